@@ -3,6 +3,7 @@ use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use ferrisetw::parser::Parser;
+use ferrisetw::provider::well_known;
 use ferrisetw::provider::Provider;
 use ferrisetw::provider::TraceFlags;
 use ferrisetw::schema::Schema;
@@ -59,7 +60,7 @@ fn parse_etw_event(schema: &Schema, record: &EventRecord) {
 fn main() {
     env_logger::init(); // this is optional. This makes the (rare) error logs of ferrisetw to be printed to stderr
 
-    let dns_provider = Provider::by_guid("1c95126e-7eea-49a9-a3fe-a378b03ddb4d") // Microsoft-Windows-DNS-Client
+    let dns_provider = Provider::by_guid(well_known::DNS_CLIENT)
         .add_callback(dns_etw_callback)
         .trace_flags(TraceFlags::EVENT_ENABLE_PROPERTY_PROCESS_START_KEY)
         .build();
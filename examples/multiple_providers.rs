@@ -1,4 +1,4 @@
-use ferrisetw::parser::{Parser, Pointer};
+use ferrisetw::parser::{Parser, RemotePtr};
 use ferrisetw::provider::*;
 use ferrisetw::schema_locator::SchemaLocator;
 use ferrisetw::trace::*;
@@ -12,7 +12,10 @@ fn registry_callback(record: &EventRecord, schema_locator: &SchemaLocator) {
             if record.event_id() == 7 {
                 let parser = Parser::create(record, &schema);
                 let pid = record.process_id();
-                let key_obj: Pointer = parser.try_parse("KeyObject").unwrap_or(Pointer::default());
+                let key_obj = parser
+                    .try_parse::<RemotePtr>("KeyObject")
+                    .map(|ptr| ptr.as_u64())
+                    .unwrap_or(0);
                 let status: u32 = parser.try_parse("Status").unwrap_or(0);
                 let value_name: String = parser.try_parse("ValueName").unwrap_or(String::from(""));
                 println!(
@@ -0,0 +1,204 @@
+//! Derive macros for [ferrisetw](https://docs.rs/ferrisetw)
+//!
+//! This crate is not meant to be used directly: enable the `derive` feature of `ferrisetw`, and use `#[derive(ferrisetw::Event)]`.
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, TokenStream as TokenStream2};
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt, LitStr};
+
+/// Implement `ferrisetw::parser::FromEvent` for a struct with named fields
+///
+/// Every field is parsed with `Parser::try_parse`, using the name of the field as the name of the property.
+///
+/// Field attributes:
+/// * `#[etw(rename = "PropertyName")]`: parse another property than the name of the field
+/// * `#[etw(optional)]`: for `Option<_>` fields, that are `None` when the event has no such property
+/// * `#[etw(skip)]`: do not parse this field, and use its `Default` value instead
+///
+/// Struct attributes, checked before parsing any field:
+/// * `#[etw(provider = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")]`: the GUID of the provider of the event
+/// * `#[etw(id = 2)]`: the ID of the event
+/// * `#[etw(version = 1)]`: the version of the event
+#[proc_macro_derive(Event, attributes(etw))]
+pub fn derive_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct StructAttributes {
+    provider: Option<u128>,
+    id: Option<u16>,
+    version: Option<u8>,
+}
+
+enum FieldKind {
+    Required,
+    Optional,
+    Skipped,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "Event can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Event can only be derived for structs",
+            ))
+        }
+    };
+
+    let attributes = struct_attributes(&input)?;
+    let mut checks = Vec::new();
+    if let Some(provider) = attributes.provider {
+        let provider = Literal::u128_suffixed(provider);
+        checks.push(quote! {
+            if record.provider_id() != ::ferrisetw::GUID::from_u128(#provider) {
+                return ::core::result::Result::Err(::ferrisetw::parser::ParserError::UnexpectedEvent);
+            }
+        });
+    }
+    if let Some(id) = attributes.id {
+        checks.push(quote! {
+            if record.event_id() != #id {
+                return ::core::result::Result::Err(::ferrisetw::parser::ParserError::UnexpectedEvent);
+            }
+        });
+    }
+    if let Some(version) = attributes.version {
+        checks.push(quote! {
+            if record.version() != #version {
+                return ::core::result::Result::Err(::ferrisetw::parser::ParserError::UnexpectedEvent);
+            }
+        });
+    }
+
+    let mut initializers = Vec::new();
+    for field in fields {
+        // Named fields always have an ident
+        let ident = field.ident.as_ref().unwrap();
+        let mut property = ident.unraw().to_string();
+        let mut kind = FieldKind::Required;
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("etw"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    property = meta.value()?.parse::<LitStr>()?.value();
+                } else if meta.path.is_ident("optional") {
+                    kind = FieldKind::Optional;
+                } else if meta.path.is_ident("skip") {
+                    kind = FieldKind::Skipped;
+                } else {
+                    return Err(meta.error("unsupported etw field attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        initializers.push(match kind {
+            FieldKind::Required => quote! { #ident: parser.try_parse(#property)? },
+            FieldKind::Optional => quote! {
+                #ident: match parser.try_parse(#property) {
+                    ::core::result::Result::Ok(value) => ::core::option::Option::Some(value),
+                    ::core::result::Result::Err(::ferrisetw::parser::ParserError::NotFound) => ::core::option::Option::None,
+                    ::core::result::Result::Err(err) => return ::core::result::Result::Err(err),
+                }
+            },
+            FieldKind::Skipped => quote! { #ident: ::core::default::Default::default() },
+        });
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::ferrisetw::parser::FromEvent for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn from_parser(
+                record: &::ferrisetw::EventRecord,
+                parser: &::ferrisetw::parser::Parser<'_, '_>,
+            ) -> ::core::result::Result<Self, ::ferrisetw::parser::ParserError> {
+                #(#checks)*
+                ::core::result::Result::Ok(Self {
+                    #(#initializers,)*
+                })
+            }
+        }
+    })
+}
+
+fn struct_attributes(input: &DeriveInput) -> syn::Result<StructAttributes> {
+    let mut attributes = StructAttributes::default();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("etw"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("provider") {
+                let guid = meta.value()?.parse::<LitStr>()?;
+                attributes.provider = Some(parse_guid(&guid)?);
+            } else if meta.path.is_ident("id") {
+                attributes.id = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+            } else if meta.path.is_ident("version") {
+                attributes.version = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+            } else {
+                return Err(meta.error("unsupported etw struct attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attributes)
+}
+
+/// Parse a GUID such as `22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716` (with or without braces)
+fn parse_guid(literal: &LitStr) -> syn::Result<u128> {
+    let value = literal.value();
+    let digits: String = value
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .chars()
+        .filter(|c| *c != '-')
+        .collect();
+    if digits.len() != 32 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(syn::Error::new(literal.span(), "invalid provider GUID"));
+    }
+    u128::from_str_radix(&digits, 16)
+        .map_err(|_| syn::Error::new(literal.span(), "invalid provider GUID"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proc_macro2::Span;
+
+    #[test]
+    fn test_parse_guid() {
+        let guid = |s: &str| parse_guid(&LitStr::new(s, Span::call_site())).ok();
+
+        assert_eq!(
+            guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716"),
+            Some(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716)
+        );
+        assert_eq!(
+            guid("{22FB2CD6-0E7B-422B-A0C7-2FAD1FD0E716}"),
+            Some(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716)
+        );
+        assert_eq!(guid("22fb2cd6-0e7b-422b-a0c7"), None);
+        assert_eq!(guid("+2fb2cd6-0e7b-422b-a0c7-2fad1fd0e716"), None);
+    }
+}
@@ -0,0 +1,118 @@
+//! What the current version of Windows supports
+//!
+//! ETW gained features over the Windows releases. [`capabilities`] reports which of them are available, so that programs that run on various versions can adapt their configuration at runtime:
+//! ```
+//! # use ferrisetw::provider::{EventFilter, Provider};
+//! let mut builder = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716");
+//! if ferrisetw::capabilities().event_filters {
+//!     builder = builder.add_filter(EventFilter::ByEventIds(vec![1, 2]));
+//! }
+//! let provider = builder.build();
+//! ```
+use once_cell::sync::Lazy;
+
+pub use crate::native::version_helper::WindowsVersion;
+
+const WIN8: WindowsVersion = WindowsVersion::new(6, 2, 0);
+const WIN8_1: WindowsVersion = WindowsVersion::new(6, 3, 0);
+const WIN10_1703: WindowsVersion = WindowsVersion::new(10, 0, 15063);
+const WIN10_1709: WindowsVersion = WindowsVersion::new(10, 0, 16299);
+const WIN10_2004: WindowsVersion = WindowsVersion::new(10, 0, 19041);
+
+/// The ETW features supported by a version of Windows, see [`capabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The version of Windows these capabilities have been computed for
+    ///
+    /// This is `None` when the current version could not be determined. The capabilities of the latest versions are then assumed.
+    pub version: Option<WindowsVersion>,
+    /// How many system logger sessions (i.e. kernel traces) can run at the same time
+    ///
+    /// This is 8 since Windows 8 (`EVENT_TRACE_SYSTEM_LOGGER_MODE`). Only the "NT Kernel Logger" is available on older versions.
+    pub system_loggers: u32,
+    /// Whether flush timers can be shorter than a second (Windows 8)
+    pub millisecond_flush_timer: bool,
+    /// Whether providers can be filtered by event IDs, process IDs and executable names (Windows 8.1)
+    ///
+    /// See [`EventFilter`](crate::provider::EventFilter)
+    pub event_filters: bool,
+    /// Whether providers can be filtered by the values of their properties (Windows 8.1)
+    ///
+    /// See [`PayloadFilter`](crate::provider::PayloadFilter)
+    pub payload_filters: bool,
+    /// Whether the events of TraceLogging providers can be filtered by their names (Windows 10 version 1709)
+    pub name_filters: bool,
+    /// Whether sessions accept `EVENT_TRACE_PROPERTIES_V2`, which session-level filters require (Windows 10 version 1703)
+    ///
+    /// See [`TraceBuilder::add_session_filter`](crate::trace::TraceBuilder::add_session_filter)
+    pub properties_v2: bool,
+    /// Whether the events declared by a provider manifest can be listed (Windows 10 version 2004)
+    ///
+    /// See [`ProviderMetadata::events`](crate::provider::metadata::ProviderMetadata::events)
+    pub manifest_events: bool,
+}
+
+impl Capabilities {
+    /// The capabilities of a given version of Windows
+    pub fn of(version: WindowsVersion) -> Self {
+        Self {
+            version: Some(version),
+            system_loggers: if version >= WIN8 { 8 } else { 1 },
+            millisecond_flush_timer: version >= WIN8,
+            event_filters: version >= WIN8_1,
+            payload_filters: version >= WIN8_1,
+            name_filters: version >= WIN10_1709,
+            properties_v2: version >= WIN10_1703,
+            manifest_events: version >= WIN10_2004,
+        }
+    }
+
+    /// Whether kernel traces can use their own names, rather than the "NT Kernel Logger" one
+    pub fn named_kernel_traces(&self) -> bool {
+        self.system_loggers > 1
+    }
+}
+
+static CAPABILITIES: Lazy<Capabilities> = Lazy::new(|| match WindowsVersion::current() {
+    Ok(version) => Capabilities::of(version),
+    Err(err) => {
+        log::warn!("Unable to get the version of Windows: {:?}", err);
+        Capabilities {
+            version: None,
+            ..Capabilities::of(WindowsVersion::new(u32::MAX, u32::MAX, u32::MAX))
+        }
+    }
+});
+
+/// The ETW features supported by the current version of Windows
+///
+/// This is computed once, and cached for the lifetime of the process.
+pub fn capabilities() -> &'static Capabilities {
+    &CAPABILITIES
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let win7 = Capabilities::of(WindowsVersion::new(6, 1, 7601));
+        assert_eq!(win7.system_loggers, 1);
+        assert!(!win7.named_kernel_traces());
+        assert!(!win7.millisecond_flush_timer);
+        assert!(!win7.event_filters);
+
+        let win10_1703 = Capabilities::of(WindowsVersion::new(10, 0, 15063));
+        assert!(win10_1703.named_kernel_traces());
+        assert!(win10_1703.payload_filters);
+        assert!(win10_1703.properties_v2);
+        assert!(!win10_1703.name_filters);
+        assert!(!win10_1703.manifest_events);
+
+        let current = capabilities();
+        assert!(current.version.is_some());
+        assert_eq!(current, &Capabilities::of(current.version.unwrap()));
+    }
+}
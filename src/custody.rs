@@ -0,0 +1,138 @@
+//! Chain-of-custody metadata for ETL dump files
+//!
+//! When enabled with [`TraceBuilder::write_chain_of_custody`](crate::trace::TraceBuilder::write_chain_of_custody), a metadata event is written into the ETL dump file of a session when it starts.
+//! It records where and how the file was produced, and can be read back using [`ChainOfCustody::from_record`], e.g. from a [`FileTrace`](crate::FileTrace) callback.
+use std::convert::TryInto;
+
+use widestring::{U16CStr, U16String};
+use windows::core::GUID;
+
+use crate::native::etw_types::event_record::EventRecord;
+use crate::native::machine_info;
+
+/// Version of the payload layout
+const PAYLOAD_VERSION: u32 = 1;
+
+/// Information about the machine and the session that produced an ETL file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainOfCustody {
+    /// Fully qualified name of the machine
+    pub machine_name: String,
+    /// OS build number (e.g. `22631.4317`)
+    pub os_build: String,
+    /// Hash of the session configuration (name, properties, dump file parameters, and providers)
+    pub session_config_hash: u64,
+    /// Version of ferrisetw that has written this event
+    pub ferrisetw_version: String,
+}
+
+impl ChainOfCustody {
+    /// GUID of the (classic) provider of chain-of-custody events
+    pub const PROVIDER_GUID: GUID = GUID::from_u128(0x6a0b3c54_93d1_4b7e_8f4e_2b1d9c7e5a10);
+
+    /// Event type (`EVENT_TRACE_TYPE_INFO`) of chain-of-custody events
+    pub const EVENT_TYPE: u8 = 0;
+
+    /// Collect information about the current machine
+    pub(crate) fn collect(session_config_hash: u64) -> Self {
+        Self {
+            machine_name: machine_info::computer_name().unwrap_or_default(),
+            os_build: machine_info::os_build().unwrap_or_default(),
+            session_config_hash,
+            ferrisetw_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Read chain-of-custody information back from an event
+    ///
+    /// This returns `None` if the record is not a chain-of-custody event (or if it is malformed)
+    pub fn from_record(record: &EventRecord) -> Option<Self> {
+        if record.provider_id() != Self::PROVIDER_GUID {
+            return None;
+        }
+        Self::from_payload(record.user_buffer())
+    }
+
+    /// Serialize as: version (u32), config hash (u64), then null-terminated UTF-16 machine name, OS build and ferrisetw version
+    pub(crate) fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&PAYLOAD_VERSION.to_le_bytes());
+        payload.extend_from_slice(&self.session_config_hash.to_le_bytes());
+        for s in [&self.machine_name, &self.os_build, &self.ferrisetw_version] {
+            for c in U16String::from_str(s).as_slice() {
+                payload.extend_from_slice(&c.to_le_bytes());
+            }
+            payload.extend_from_slice(&0u16.to_le_bytes());
+        }
+        payload
+    }
+
+    fn from_payload(payload: &[u8]) -> Option<Self> {
+        let version = u32::from_le_bytes(payload.get(0..4)?.try_into().ok()?);
+        if version != PAYLOAD_VERSION {
+            return None;
+        }
+        let session_config_hash = u64::from_le_bytes(payload.get(4..12)?.try_into().ok()?);
+
+        let wide: Vec<u16> = payload[12..]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        let mut strings = wide
+            .split_inclusive(|c| *c == 0)
+            .map(|s| U16CStr::from_slice(s).ok().map(|s| s.to_string_lossy()));
+
+        Some(Self {
+            machine_name: strings.next()??,
+            os_build: strings.next()??,
+            session_config_hash,
+            ferrisetw_version: strings.next()??,
+        })
+    }
+}
+
+/// A 64-bit FNV-1a hasher
+///
+/// Contrary to `std::collections::hash_map::DefaultHasher`, its output is stable across Rust versions, which matters for hashes that are persisted in files.
+pub(crate) struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SyntheticEvent;
+
+    #[test]
+    fn test_payload_roundtrip() {
+        let custody = ChainOfCustody {
+            machine_name: String::from("host.example.com"),
+            os_build: String::from("22631.4317"),
+            session_config_hash: 0x0123_4567_89ab_cdef,
+            ferrisetw_version: String::from("1.2.0"),
+        };
+
+        let event = SyntheticEvent::new()
+            .with_provider(ChainOfCustody::PROVIDER_GUID)
+            .with_user_data(&custody.to_payload());
+        assert_eq!(ChainOfCustody::from_record(event.record()), Some(custody));
+
+        let other = SyntheticEvent::new().with_user_data(&[0; 16]);
+        assert_eq!(ChainOfCustody::from_record(other.record()), None);
+    }
+}
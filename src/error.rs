@@ -0,0 +1,79 @@
+//! Crate-level error type
+//!
+//! Every module of this crate has its own error type. [`Error`] wraps any of them, so that a single type can be used with the `?` operator.
+use crate::etl::EtlError;
+use crate::native::{EvntraceNativeError, PlaError, SddlNativeError, TdhNativeError};
+use crate::parser::ParserError;
+use crate::provider::ProviderError;
+use crate::relogger::RelogError;
+use crate::schema_locator::SchemaError;
+use crate::trace::TraceError;
+
+/// Any error returned by this crate
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Wrapper over a [`TraceError`]
+    Trace(TraceError),
+    /// Wrapper over a [`ParserError`]
+    Parser(ParserError),
+    /// Wrapper over a [`SchemaError`]
+    Schema(SchemaError),
+    /// Wrapper over a [`ProviderError`]
+    Provider(ProviderError),
+    /// Wrapper over an [`EvntraceNativeError`]
+    EvntraceNative(EvntraceNativeError),
+    /// Wrapper over a [`TdhNativeError`]
+    TdhNative(TdhNativeError),
+    /// Wrapper over a [`PlaError`]
+    Pla(PlaError),
+    /// Wrapper over an [`SddlNativeError`]
+    SddlNative(SddlNativeError),
+    /// Wrapper over a [`RelogError`]
+    Relog(RelogError),
+    /// Wrapper over an [`EtlError`]
+    Etl(EtlError),
+}
+
+/// A `Result` whose error is a crate-level [`Error`]
+pub type Result<T> = std::result::Result<T, Error>;
+
+macro_rules! impl_from_error {
+    ($variant:ident, $err:ty) => {
+        impl From<$err> for Error {
+            fn from(err: $err) -> Self {
+                Error::$variant(err)
+            }
+        }
+    };
+}
+
+impl_from_error!(Trace, TraceError);
+impl_from_error!(Parser, ParserError);
+impl_from_error!(Schema, SchemaError);
+impl_from_error!(Provider, ProviderError);
+impl_from_error!(EvntraceNative, EvntraceNativeError);
+impl_from_error!(TdhNative, TdhNativeError);
+impl_from_error!(Pla, PlaError);
+impl_from_error!(SddlNative, SddlNativeError);
+impl_from_error!(Relog, RelogError);
+impl_from_error!(Etl, EtlError);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Trace(err) => write!(f, "trace error: {:?}", err),
+            Error::Parser(err) => write!(f, "parser error: {}", err),
+            Error::Schema(err) => write!(f, "schema error: {:?}", err),
+            Error::Provider(err) => write!(f, "provider error: {:?}", err),
+            Error::EvntraceNative(err) => write!(f, "native ETW error: {:?}", err),
+            Error::TdhNative(err) => write!(f, "native TDH error: {}", err),
+            Error::Pla(err) => write!(f, "PLA error: {:?}", err),
+            Error::SddlNative(err) => write!(f, "SDDL error: {}", err),
+            Error::Relog(err) => write!(f, "relogger error: {:?}", err),
+            Error::Etl(err) => write!(f, "ETL error: {:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,259 @@
+//! Helpers to work with ETL files
+//!
+//! * [`merge`] merges several ETL files into one. This is a thin layer over the [`relogger`](crate::relogger).
+//! * [`Writer`] creates an ETL file out of events built from scratch (e.g. to generate test fixtures, or to convert other formats into ETL).
+//!
+//! [`EtlWriter`] writes ETL files directly, without any session nor privilege. [`Writer`] is built on it.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::EVENT_DESCRIPTOR;
+
+use crate::relogger::{RelogError, RelogStats, Relogger};
+use crate::trace::TraceError;
+
+mod etl_writer;
+pub use etl_writer::{EtlWriter, DEFAULT_BUFFER_SIZE, MIN_BUFFER_SIZE};
+
+/// ETL module errors
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EtlError {
+    /// Wrapper over a [`TraceError`]
+    Trace(TraceError),
+    /// Wrapper over a [`RelogError`]
+    Relog(RelogError),
+    /// The ETL file could not be written
+    Io(std::io::Error),
+}
+
+impl From<TraceError> for EtlError {
+    fn from(err: TraceError) -> Self {
+        EtlError::Trace(err)
+    }
+}
+
+impl From<RelogError> for EtlError {
+    fn from(err: RelogError) -> Self {
+        EtlError::Relog(err)
+    }
+}
+
+impl From<std::io::Error> for EtlError {
+    fn from(err: std::io::Error) -> Self {
+        EtlError::Io(err)
+    }
+}
+
+type EtlResult<T> = Result<T, EtlError>;
+
+/// Merge several ETL files (e.g. a kernel ETL and a user ETL) into a single file, whose events are in chronological order
+///
+/// This is similar to `xperf -merge`, except that no extra metadata (e.g. image identification events) is added to the output file.<br/>
+/// `output` is overwritten in case it already exists.
+///
+/// ```no_run
+/// # use std::path::{Path, PathBuf};
+/// let inputs = [PathBuf::from("kernel.etl"), PathBuf::from("user.etl")];
+/// let stats = ferrisetw::etl::merge(&inputs, Path::new("merged.etl")).unwrap();
+/// println!("{} events merged", stats.kept);
+/// ```
+pub fn merge(inputs: &[PathBuf], output: &Path) -> Result<RelogStats, RelogError> {
+    inputs
+        .iter()
+        .fold(Relogger::new(output), |relogger, input| {
+            relogger.add_log_file(input.as_path())
+        })
+        .run()
+}
+
+/// An event to write into an ETL file, see [`Writer`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EtlEvent {
+    provider_id: GUID,
+    id: u16,
+    version: u8,
+    channel: u8,
+    level: u8,
+    opcode: u8,
+    task: u16,
+    keyword: u64,
+    process_id: u32,
+    thread_id: u32,
+    processor: u32,
+    offset: Duration,
+    payload: Vec<u8>,
+}
+
+impl EtlEvent {
+    pub fn new(provider_id: GUID, id: u16) -> Self {
+        Self {
+            provider_id,
+            id,
+            ..Default::default()
+        }
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn level(mut self, level: u8) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.opcode = opcode;
+        self
+    }
+
+    pub fn task(mut self, task: u16) -> Self {
+        self.task = task;
+        self
+    }
+
+    pub fn keyword(mut self, keyword: u64) -> Self {
+        self.keyword = keyword;
+        self
+    }
+
+    /// Set the process and thread that emitted this event
+    pub fn process(mut self, process_id: u32, thread_id: u32) -> Self {
+        self.process_id = process_id;
+        self.thread_id = thread_id;
+        self
+    }
+
+    pub fn processor(mut self, processor: u32) -> Self {
+        self.processor = processor;
+        self
+    }
+
+    /// Set when this event happened, relative to the beginning of the file
+    pub fn at(mut self, offset: Duration) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Set the user data of this event, i.e. its properties as they would have been written by its provider
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    fn descriptor(&self) -> EVENT_DESCRIPTOR {
+        EVENT_DESCRIPTOR {
+            Id: self.id,
+            Version: self.version,
+            Channel: self.channel,
+            Level: self.level,
+            Opcode: self.opcode,
+            Task: self.task,
+            Keyword: self.keyword,
+        }
+    }
+}
+
+/// Creates an ETL file out of events built from scratch
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use ferrisetw::etl::{EtlEvent, Writer};
+/// # use ferrisetw::GUID;
+/// let provider = GUID::from("9f5e1a3c-1f3a-4b8e-9a1e-2c7d5b8e6f10");
+/// let mut writer = Writer::create("fixture.etl");
+/// for i in 0..10u32 {
+///     writer.write(
+///         EtlEvent::new(provider, 1)
+///             .process(1234, 5678)
+///             .at(Duration::from_millis(i as u64 * 10))
+///             .payload(i.to_le_bytes().to_vec()),
+///     );
+/// }
+/// let written = writer.finish().unwrap();
+/// ```
+///
+/// The file is written by an [`EtlWriter`], whose start time is the time `finish` is called.
+#[derive(Debug)]
+pub struct Writer {
+    output: PathBuf,
+    events: Vec<EtlEvent>,
+}
+
+impl Writer {
+    /// Write into `output`, that is overwritten in case it already exists
+    ///
+    /// Nothing is written until [`Self::finish`] is called.
+    pub fn create<P: Into<PathBuf>>(output: P) -> Self {
+        Self {
+            output: output.into(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn write(&mut self, event: EtlEvent) {
+        self.events.push(event);
+    }
+
+    /// The number of events written so far
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Create the ETL file. Events are sorted by time (see [`EtlEvent::at`]). Returns the number of events that have been written
+    pub fn finish(self) -> EtlResult<usize> {
+        let mut events = self.events;
+        events.sort_by_key(|event| event.offset);
+
+        let mut writer = EtlWriter::create(&self.output)?;
+        for event in &events {
+            writer.write_event(event)?;
+        }
+        writer.finish()?;
+        Ok(events.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_nothing() {
+        let result = merge(&[], Path::new("ferrisetw-merge-test.etl"));
+        assert!(matches!(result, Err(RelogError::NoInput)));
+    }
+
+    #[test]
+    fn test_etl_event() {
+        let provider = GUID::from_u128(0x781);
+        let event = EtlEvent::new(provider, 7)
+            .version(2)
+            .level(4)
+            .process(10, 20)
+            .at(Duration::from_millis(5))
+            .payload(vec![1, 2, 3]);
+        assert_eq!(event.provider_id, provider);
+        assert_eq!((event.id, event.version, event.level), (7, 2, 4));
+        assert_eq!((event.process_id, event.thread_id), (10, 20));
+        assert_eq!(event.offset, Duration::from_millis(5));
+        assert_eq!(event.payload, [1, 2, 3]);
+
+        let mut writer = Writer::create("ferrisetw-writer-test.etl");
+        assert!(writer.is_empty());
+        writer.write(event);
+        assert_eq!(writer.len(), 1);
+    }
+}
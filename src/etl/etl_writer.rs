@@ -0,0 +1,544 @@
+//! Write ETL files directly, in the buffer format of ETW
+//!
+//! An ETL file is a sequence of fixed-size buffers. Each buffer starts with a `WMI_BUFFER_HEADER`, followed by events (aligned on 8 bytes).
+//! The first buffer contains a single event, whose payload is the `TRACE_LOGFILE_HEADER` of the file.<br/>
+//! Events are written with an `EVENT_HEADER`, and their timestamps use the system time clock (i.e. they are `FILETIME` quads).
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::EtlEvent;
+use crate::native::etw_types::event_record::EventRecord;
+use crate::native::machine_info;
+
+/// The default size of the buffers, in bytes
+pub const DEFAULT_BUFFER_SIZE: u32 = 64 * 1024;
+/// The minimum size of the buffers, in bytes
+///
+/// The first buffer must hold the header of the file (about 400 bytes). ETW sessions do not use smaller buffers either.
+pub const MIN_BUFFER_SIZE: u32 = 1024;
+
+/// Size of a `WMI_BUFFER_HEADER`
+const BUFFER_HEADER_SIZE: usize = 72;
+/// Size of a `SYSTEM_TRACE_HEADER`
+const SYSTEM_TRACE_HEADER_SIZE: usize = 32;
+/// Size of a `TRACE_LOGFILE_HEADER`, as written by 64-bit loggers
+const LOGFILE_HEADER_SIZE: usize = 280;
+/// Size of an `EVENT_HEADER`
+const EVENT_HEADER_SIZE: usize = 80;
+
+/// `TRACE_HEADER_FLAG | TRACE_HEADER_EVENT_TRACE`, in the high byte of the first field of every event
+const TRACE_HEADER_FLAGS: u8 = 0xC0;
+const TRACE_HEADER_TYPE_SYSTEM64: u8 = 2;
+const TRACE_HEADER_TYPE_EVENT_HEADER64: u8 = 19;
+/// `ETW_BUFFER_TYPE_GENERIC` and `ETW_BUFFER_TYPE_HEADER`
+const BUFFER_TYPE_GENERIC: u16 = 0;
+const BUFFER_TYPE_HEADER: u16 = 4;
+/// `EVENT_TRACE_CLOCK_SYSTEMTIME`
+const CLOCK_SYSTEM_TIME: u32 = 2;
+/// The frequency of the system time clock, i.e. 100-nanosecond intervals per second
+const SYSTEM_TIME_FREQUENCY: i64 = 10_000_000;
+
+const LOGGER_NAME: &str = "ferrisetw";
+
+/// A buffer being filled
+struct Buffer {
+    /// The events of this buffer, without the buffer header
+    data: Vec<u8>,
+    last_timestamp: i64,
+}
+
+/// Writes events into an ETL file, without any ETW session
+///
+/// The resulting file can be read by [`FileTrace`](crate::FileTrace)s, and by the usual tools (e.g. WPA, tracerpt), provided these know the schemas of the events.
+///
+/// ```no_run
+/// # use std::sync::{Arc, Mutex};
+/// # use ferrisetw::etl::EtlWriter;
+/// # use ferrisetw::{EventRecord, SchemaLocator, FileTrace};
+/// let writer = Arc::new(Mutex::new(EtlWriter::create("filtered.etl").unwrap()));
+/// let writer_clone = Arc::clone(&writer);
+/// let trace = FileTrace::new("huge.etl".into(), move |record: &EventRecord, _: &SchemaLocator| {
+///     if record.event_id() == 1 {
+///         writer_clone.lock().unwrap().write(record).unwrap();
+///     }
+/// })
+/// .start_and_process()
+/// .unwrap();
+/// drop(trace);
+///
+/// let writer = Arc::try_unwrap(writer).ok().unwrap().into_inner().unwrap();
+/// writer.finish().unwrap();
+/// ```
+///
+/// Events are expected to be written in chronological order (at least per processor, see [`EventRecord::processor_index`]).
+/// Like in ETW sessions, every processor has its own buffer, so that interleaved processors do not waste buffers.
+/// Their extended data items (e.g. stack traces) are not written.<br/>
+/// The file is only complete once [`Self::finish`] has been called.
+pub struct EtlWriter<W: Write + Seek> {
+    inner: W,
+    buffer_size: u32,
+    /// The buffer being filled, for every processor
+    current: BTreeMap<u16, Buffer>,
+    /// Buffers written so far, including the header buffer
+    buffers_written: u32,
+    start_time: Option<i64>,
+    end_time: i64,
+    processors: u16,
+    events_written: usize,
+}
+
+impl EtlWriter<BufWriter<File>> {
+    /// Create (or truncate) an ETL file
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write + Seek> EtlWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            current: BTreeMap::new(),
+            buffers_written: 0,
+            start_time: None,
+            end_time: 0,
+            processors: 1,
+            events_written: 0,
+        }
+    }
+
+    /// Set the size of the buffers of the file (64 KB by default). This must be called before any event is written.
+    ///
+    /// Larger buffers can hold larger events, since an event cannot span several buffers.<br/>
+    /// This fails for sizes below [`MIN_BUFFER_SIZE`], or that are not a multiple of 8 (events are 8-byte aligned).
+    pub fn with_buffer_size(mut self, buffer_size: u32) -> std::io::Result<Self> {
+        if buffer_size < MIN_BUFFER_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "buffers of {} bytes are smaller than the minimum of {} bytes",
+                    buffer_size, MIN_BUFFER_SIZE
+                ),
+            ));
+        }
+        if !buffer_size.is_multiple_of(8) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("buffers of {} bytes are not 8-byte aligned", buffer_size),
+            ));
+        }
+        self.buffer_size = buffer_size;
+        Ok(self)
+    }
+
+    /// Set the start time of the file, as a `FILETIME` quad
+    ///
+    /// This is the origin of the [`EtlEvent::at`] offsets. It defaults to the timestamp of the first event.
+    pub fn with_start_time(mut self, start_time: i64) -> Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// The number of events written so far
+    pub fn events_written(&self) -> usize {
+        self.events_written
+    }
+
+    /// Write a copy of an event, e.g. one received by a callback, or an [`OwnedEventRecord`](crate::OwnedEventRecord)
+    ///
+    /// The file uses the system time clock: the event is written with its [`EventRecord::system_timestamp`].
+    pub fn write(&mut self, record: &EventRecord) -> std::io::Result<()> {
+        let header = &record.0.EventHeader;
+        let flags = header.Flags & !(Etw::EVENT_HEADER_FLAG_EXTENDED_INFO as u16);
+        self.write_raw(
+            &header.ProviderId,
+            &header.EventDescriptor,
+            flags,
+            header.EventProperty,
+            (header.ProcessId, header.ThreadId),
+            record.processor_index(),
+            record.system_timestamp(),
+            &header.ActivityId,
+            record.user_buffer(),
+        )
+    }
+
+    /// Write a synthesized event. Its timestamp is relative to the start time of the file (see [`Self::with_start_time`])
+    pub fn write_event(&mut self, event: &EtlEvent) -> std::io::Result<()> {
+        let start_time = *self.start_time.get_or_insert_with(now);
+        let timestamp = start_time + (event.offset.as_nanos() / 100) as i64;
+        self.write_raw(
+            &event.provider_id,
+            &event.descriptor(),
+            Etw::EVENT_HEADER_FLAG_64_BIT_HEADER as u16,
+            0,
+            (event.process_id, event.thread_id),
+            event.processor as u16,
+            timestamp,
+            &GUID::zeroed(),
+            &event.payload,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_raw(
+        &mut self,
+        provider_id: &GUID,
+        descriptor: &Etw::EVENT_DESCRIPTOR,
+        flags: u16,
+        event_property: u16,
+        (process_id, thread_id): (u32, u32),
+        processor: u16,
+        timestamp: i64,
+        activity_id: &GUID,
+        user_data: &[u8],
+    ) -> std::io::Result<()> {
+        let size = EVENT_HEADER_SIZE + user_data.len();
+        // Events are padded to 8 bytes in the buffer
+        let padded_size = align_8(size);
+        if size > u16::MAX as usize || padded_size > self.buffer_size as usize - BUFFER_HEADER_SIZE
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("event of {} bytes does not fit in a buffer", size),
+            ));
+        }
+        if self.buffers_written == 0 {
+            // Reserve room for the header buffer, that is written last
+            self.inner.write_all(&vec![0; self.buffer_size as usize])?;
+            self.buffers_written = 1;
+        }
+        let buffer_size = self.buffer_size as usize;
+        if matches!(self.current.get(&processor), Some(buffer) if BUFFER_HEADER_SIZE + buffer.data.len() + padded_size > buffer_size)
+        {
+            self.flush_buffer(processor)?;
+        }
+        let buffer = self.current.entry(processor).or_insert_with(|| Buffer {
+            data: Vec::new(),
+            last_timestamp: timestamp,
+        });
+
+        let data = &mut buffer.data;
+        data.extend_from_slice(&(size as u16).to_le_bytes());
+        data.extend_from_slice(&[TRACE_HEADER_TYPE_EVENT_HEADER64, TRACE_HEADER_FLAGS]);
+        data.extend_from_slice(&flags.to_le_bytes());
+        data.extend_from_slice(&event_property.to_le_bytes());
+        data.extend_from_slice(&thread_id.to_le_bytes());
+        data.extend_from_slice(&process_id.to_le_bytes());
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data.extend_from_slice(&guid_bytes(provider_id));
+        data.extend_from_slice(&descriptor.Id.to_le_bytes());
+        data.extend_from_slice(&[
+            descriptor.Version,
+            descriptor.Channel,
+            descriptor.Level,
+            descriptor.Opcode,
+        ]);
+        data.extend_from_slice(&descriptor.Task.to_le_bytes());
+        data.extend_from_slice(&descriptor.Keyword.to_le_bytes());
+        // Kernel and user times
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&guid_bytes(activity_id));
+        data.extend_from_slice(user_data);
+        data.resize(align_8(data.len()), 0);
+        buffer.last_timestamp = buffer.last_timestamp.max(timestamp);
+
+        self.start_time.get_or_insert(timestamp);
+        self.end_time = self.end_time.max(timestamp);
+        self.processors = self.processors.max(processor.saturating_add(1));
+        self.events_written += 1;
+        Ok(())
+    }
+
+    /// Write the current buffer of a processor (if any) to the file
+    fn flush_buffer(&mut self, processor: u16) -> std::io::Result<()> {
+        if let Some(buffer) = self.current.remove(&processor) {
+            let bytes = self.buffer_bytes(
+                BUFFER_TYPE_GENERIC,
+                processor,
+                buffer.last_timestamp,
+                &buffer.data,
+            );
+            self.inner.write_all(&bytes)?;
+            self.buffers_written += 1;
+        }
+        Ok(())
+    }
+
+    /// Serialize a buffer: its header, its events, then `0xFF` up to the buffer size
+    fn buffer_bytes(
+        &self,
+        buffer_type: u16,
+        processor: u16,
+        timestamp: i64,
+        events: &[u8],
+    ) -> Vec<u8> {
+        let used = (BUFFER_HEADER_SIZE + events.len()) as u32;
+        let start_time = self.start_time.unwrap_or_default();
+        let mut bytes = Vec::with_capacity(self.buffer_size as usize);
+        bytes.extend_from_slice(&self.buffer_size.to_le_bytes());
+        // Saved and current offsets
+        bytes.extend_from_slice(&used.to_le_bytes());
+        bytes.extend_from_slice(&used.to_le_bytes());
+        // Reference count
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&timestamp.to_le_bytes());
+        // Sequence number
+        bytes.extend_from_slice(&(self.buffers_written as u64).to_le_bytes());
+        // Clock type (3 bits) and frequency
+        let clock = ((SYSTEM_TIME_FREQUENCY as u64) << 3) | CLOCK_SYSTEM_TIME as u64;
+        bytes.extend_from_slice(&clock.to_le_bytes());
+        // ETW_BUFFER_CONTEXT: processor index and logger ID
+        bytes.extend_from_slice(&processor.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        // State
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        // Offset
+        bytes.extend_from_slice(&used.to_le_bytes());
+        // Buffer flag
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&buffer_type.to_le_bytes());
+        // ETW_REF_CLOCK: start time, and the same instant in the clock of the file
+        bytes.extend_from_slice(&start_time.to_le_bytes());
+        bytes.extend_from_slice(&start_time.to_le_bytes());
+        debug_assert_eq!(bytes.len(), BUFFER_HEADER_SIZE);
+
+        bytes.extend_from_slice(events);
+        bytes.resize(self.buffer_size as usize, 0xFF);
+        bytes
+    }
+
+    /// The event of the header buffer: a `SYSTEM_TRACE_HEADER`, then a `TRACE_LOGFILE_HEADER` and the logger and file names
+    fn logfile_header_event(&self) -> Vec<u8> {
+        let start_time = self.start_time.unwrap_or_default();
+        let names: Vec<u8> = LOGGER_NAME
+            .encode_utf16()
+            .chain([0, 0])
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        let size = SYSTEM_TRACE_HEADER_SIZE + LOGFILE_HEADER_SIZE + names.len();
+
+        let mut event = Vec::with_capacity(align_8(size));
+        // SYSTEM_TRACE_HEADER: version, header type, flags, size, hook ID (EventTrace/Header), thread and process IDs, time, kernel and user times
+        event.extend_from_slice(&2u16.to_le_bytes());
+        event.extend_from_slice(&[TRACE_HEADER_TYPE_SYSTEM64, TRACE_HEADER_FLAGS]);
+        event.extend_from_slice(&(size as u16).to_le_bytes());
+        event.extend_from_slice(&0u16.to_le_bytes());
+        event.extend_from_slice(&[0; 8]);
+        event.extend_from_slice(&start_time.to_le_bytes());
+        event.extend_from_slice(&[0; 8]);
+
+        // TRACE_LOGFILE_HEADER
+        let os_build = machine_info::os_build()
+            .and_then(|build| build.split('.').next()?.parse::<u32>().ok())
+            .unwrap_or_default();
+        event.extend_from_slice(&self.buffer_size.to_le_bytes());
+        // Major, minor, sub and sub-minor versions
+        event.extend_from_slice(&[10, 0, 1, 5]);
+        event.extend_from_slice(&os_build.to_le_bytes());
+        event.extend_from_slice(&(self.processors as u32).to_le_bytes());
+        event.extend_from_slice(&self.end_time.to_le_bytes());
+        // Timer resolution (15.625 ms) and maximum file size
+        event.extend_from_slice(&156_250u32.to_le_bytes());
+        event.extend_from_slice(&0u32.to_le_bytes());
+        event.extend_from_slice(&Etw::EVENT_TRACE_FILE_MODE_SEQUENTIAL.to_le_bytes());
+        event.extend_from_slice(&self.buffers_written.to_le_bytes());
+        // Start buffers, pointer size, events lost, CPU speed
+        for value in [1u32, 8, 0, 0] {
+            event.extend_from_slice(&value.to_le_bytes());
+        }
+        // Logger and log file name pointers (meaningless in a file), then the time zone
+        event.extend_from_slice(&[0; 16]);
+        event.extend_from_slice(&[0; 172]);
+        // Padding, then boot time, performance frequency, start time, clock type and buffers lost
+        event.extend_from_slice(&[0; 4]);
+        event.extend_from_slice(&start_time.to_le_bytes());
+        event.extend_from_slice(&SYSTEM_TIME_FREQUENCY.to_le_bytes());
+        event.extend_from_slice(&start_time.to_le_bytes());
+        event.extend_from_slice(&CLOCK_SYSTEM_TIME.to_le_bytes());
+        event.extend_from_slice(&0u32.to_le_bytes());
+        debug_assert_eq!(event.len(), SYSTEM_TRACE_HEADER_SIZE + LOGFILE_HEADER_SIZE);
+
+        event.extend_from_slice(&names);
+        event.resize(align_8(size), 0);
+        event
+    }
+
+    /// Write the remaining events and the header of the file, and return the underlying writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if self.buffers_written == 0 {
+            self.inner.write_all(&vec![0; self.buffer_size as usize])?;
+            self.buffers_written = 1;
+        }
+        let processors: Vec<u16> = self.current.keys().copied().collect();
+        for processor in processors {
+            self.flush_buffer(processor)?;
+        }
+
+        let header_event = self.logfile_header_event();
+        let header_buffer = self.buffer_bytes(
+            BUFFER_TYPE_HEADER,
+            0,
+            self.start_time.unwrap_or_default(),
+            &header_event,
+        );
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header_buffer)?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> std::fmt::Debug for EtlWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EtlWriter")
+            .field("buffer_size", &self.buffer_size)
+            .field("buffers_written", &self.buffers_written)
+            .field("events_written", &self.events_written)
+            .field("start_time", &self.start_time)
+            .field("end_time", &self.end_time)
+            .finish()
+    }
+}
+
+/// The current time, as a `FILETIME` quad
+fn now() -> i64 {
+    /// 100-nanosecond intervals between 1601-01-01 and 1970-01-01
+    const UNIX_EPOCH_QUAD: i64 = 116_444_736_000_000_000;
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    UNIX_EPOCH_QUAD + (since_epoch.as_nanos() / 100) as i64
+}
+
+fn align_8(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+fn guid_bytes(guid: &GUID) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    bytes[0..4].copy_from_slice(&guid.data1.to_le_bytes());
+    bytes[4..6].copy_from_slice(&guid.data2.to_le_bytes());
+    bytes[6..8].copy_from_slice(&guid.data3.to_le_bytes());
+    bytes[8..16].copy_from_slice(&guid.data4);
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_buffer_layout() {
+        let provider = GUID::from_u128(0x781);
+        let event = SyntheticEvent::new()
+            .with_provider(provider)
+            .with_opcode(3)
+            .with_timestamp(1_000)
+            .with_processor(1)
+            .with_user_data(&[1, 2, 3]);
+
+        let mut writer = EtlWriter::new(Cursor::new(Vec::new()))
+            .with_buffer_size(4096)
+            .unwrap();
+        writer.write(event.record()).unwrap();
+        writer
+            .write_event(&EtlEvent::new(provider, 2).processor(1))
+            .unwrap();
+        assert_eq!(writer.events_written(), 2);
+        let file = writer.finish().unwrap().into_inner();
+
+        // A header buffer, and a buffer for the events of processor 1
+        assert_eq!(file.len(), 2 * 4096);
+        let (header, events) = file.split_at(4096);
+        assert_eq!(read_u32(header, 0), 4096);
+        assert_eq!(
+            u16::from_le_bytes([header[54], header[55]]),
+            BUFFER_TYPE_HEADER
+        );
+        let logfile_header = &header[BUFFER_HEADER_SIZE + SYSTEM_TRACE_HEADER_SIZE..];
+        // Number of processors, then buffers written
+        assert_eq!(read_u32(logfile_header, 12), 2);
+        assert_eq!(read_u32(logfile_header, 36), 2);
+
+        assert_eq!(u16::from_le_bytes([events[40], events[41]]), 1);
+        let first = &events[BUFFER_HEADER_SIZE..];
+        assert_eq!(
+            u16::from_le_bytes([first[0], first[1]]) as usize,
+            EVENT_HEADER_SIZE + 3
+        );
+        assert_eq!(first[2], TRACE_HEADER_TYPE_EVENT_HEADER64);
+        assert_eq!(&first[24..40], &guid_bytes(&provider));
+        assert_eq!(first[45], 3);
+        assert_eq!(&first[80..83], &[1, 2, 3]);
+        // The second event starts on the next 8-byte boundary, and is timestamped relatively to the first one
+        let second = &first[align_8(EVENT_HEADER_SIZE + 3)..];
+        assert_eq!(u16::from_le_bytes([second[40], second[41]]), 2);
+        assert_eq!(
+            i64::from_le_bytes(second[16..24].try_into().unwrap()),
+            1_000
+        );
+    }
+
+    #[test]
+    fn test_event_too_large() {
+        let mut writer = EtlWriter::new(Cursor::new(Vec::new()))
+            .with_buffer_size(MIN_BUFFER_SIZE)
+            .unwrap();
+        let event = EtlEvent::new(GUID::zeroed(), 1).payload(vec![0; 1024]);
+        assert!(writer.write_event(&event).is_err());
+        let room = MIN_BUFFER_SIZE as usize - BUFFER_HEADER_SIZE - EVENT_HEADER_SIZE;
+        let event = EtlEvent::new(GUID::zeroed(), 1).payload(vec![0; room + 1]);
+        assert!(writer.write_event(&event).is_err());
+        let event = EtlEvent::new(GUID::zeroed(), 1).payload(vec![0; room]);
+        assert!(writer.write_event(&event).is_ok());
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        assert!(EtlWriter::new(Cursor::new(Vec::new()))
+            .with_buffer_size(MIN_BUFFER_SIZE - 1)
+            .is_err());
+        assert!(EtlWriter::new(Cursor::new(Vec::new()))
+            .with_buffer_size(MIN_BUFFER_SIZE + 4)
+            .is_err());
+        // Smaller buffers would not hold the header of the file
+        let writer = EtlWriter::new(Cursor::new(Vec::new()));
+        assert!(
+            BUFFER_HEADER_SIZE + writer.logfile_header_event().len() <= MIN_BUFFER_SIZE as usize
+        );
+    }
+
+    #[test]
+    fn test_buffer_per_processor() {
+        let provider = GUID::from_u128(0x781);
+        let mut writer = EtlWriter::new(Cursor::new(Vec::new()))
+            .with_buffer_size(4096)
+            .unwrap();
+        for index in 0..10u32 {
+            writer
+                .write_event(&EtlEvent::new(provider, 1).processor(index % 2))
+                .unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+
+        // A header buffer, then a single buffer per processor
+        assert_eq!(file.len(), 3 * 4096);
+        assert_eq!(u16::from_le_bytes([file[4096 + 40], file[4096 + 41]]), 0);
+        assert_eq!(u16::from_le_bytes([file[8192 + 40], file[8192 + 41]]), 1);
+    }
+}
@@ -0,0 +1,422 @@
+//! Metadata events embedded by `KernelTraceControl` into merged ETL files
+//!
+//! When merging a trace (e.g. with WPR or `xperf -merge`), `KernelTraceControl.dll` appends classic events that describe the recording machine:
+//! the identity of the loaded images, the mapping between NT volumes and drive letters, etc.<br/>
+//! These events are needed to make sense of the trace (e.g. to symbolize it) on another machine.
+//!
+//! These events have no schema that TDH could use, [`KernelTraceControlEvent::from_record`] decodes them.
+//!
+//! ```
+//! # use ferrisetw::EventRecord;
+//! # use ferrisetw::kernel_trace_control::KernelTraceControlEvent;
+//! # use ferrisetw::native::device_path::DevicePathTranslator;
+//! fn on_event(record: &EventRecord, translator: &DevicePathTranslator) {
+//!     if let Some(KernelTraceControlEvent::VolumeMapping(mapping)) = KernelTraceControlEvent::from_record(record) {
+//!         mapping.apply(translator);
+//!     }
+//! }
+//! ```
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use windows::core::GUID;
+
+use crate::native::device_path::DevicePathTranslator;
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::RemotePtr;
+
+/// GUID of the `ImageId` classic events
+pub const IMAGE_ID_GUID: GUID = GUID::from_u128(0xb3e675d7_2554_4f18_830b_2762732560de);
+/// GUID of the `SysConfigEx` classic events
+pub const SYS_CONFIG_EX_GUID: GUID = GUID::from_u128(0x9b79ee91_b5fd_41c0_a243_4248e266e9d0);
+
+/// Opcode of `ImageId` events, describing a loaded image
+pub const IMAGE_ID_OPCODE: u8 = 0;
+/// Opcode of `DbgID_RSDS` events, describing the PDB of a loaded image
+pub const DBG_ID_RSDS_OPCODE: u8 = 36;
+/// Opcode of `SysConfigEx` events, describing the drive letter of a volume
+pub const VOLUME_MAPPING_OPCODE: u8 = 35;
+
+/// Identity of an image that was loaded during the trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageId {
+    /// Base address the image was loaded at
+    pub image_base: RemotePtr,
+    pub image_size: u64,
+    pub process_id: u32,
+    /// `TimeDateStamp` from the PE header of the image
+    pub time_date_stamp: u32,
+    /// File name of the image, as set in its version resources
+    pub original_file_name: String,
+}
+
+/// The PDB matching an image that was loaded during the trace (from its `RSDS` CodeView debug directory)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbgIdRsds {
+    /// Base address the image was loaded at
+    pub image_base: RemotePtr,
+    pub process_id: u32,
+    /// Signature of the PDB
+    pub guid_sig: GUID,
+    /// Age of the PDB
+    pub age: u32,
+    /// Path of the PDB, as it was when the image was built
+    pub pdb_file_name: String,
+}
+
+impl DbgIdRsds {
+    /// The file name of the PDB, without its build-time directory
+    pub fn pdb_name(&self) -> &str {
+        self.pdb_file_name
+            .rsplit(['\\', '/'])
+            .next()
+            .unwrap_or(&self.pdb_file_name)
+    }
+
+    /// The relative path of the PDB on a symbol server (e.g. `ntdll.pdb/1EB9FACB04EA273BB4BA52C3ECEF1E481/ntdll.pdb`)
+    pub fn symbol_server_path(&self) -> String {
+        let guid = &self.guid_sig;
+        let mut key = format!("{:08X}{:04X}{:04X}", guid.data1, guid.data2, guid.data3);
+        for byte in guid.data4 {
+            key.push_str(&format!("{:02X}", byte));
+        }
+        format!(
+            "{}/{}{:X}/{}",
+            self.pdb_name(),
+            key,
+            self.age,
+            self.pdb_name()
+        )
+    }
+}
+
+/// The drive letter of a volume, on the machine that recorded the trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VolumeMapping {
+    /// e.g. `\Device\HarddiskVolume3`
+    pub nt_path: String,
+    /// e.g. `C:`
+    pub dos_path: String,
+}
+
+impl VolumeMapping {
+    /// Make `translator` aware of this mapping
+    ///
+    /// For traces recorded on another machine, you probably want to start from an [empty](DevicePathTranslator::empty) translator.
+    pub fn apply(&self, translator: &DevicePathTranslator) {
+        translator.add_mapping(&self.nt_path, &self.dos_path);
+    }
+}
+
+/// A decoded `KernelTraceControl` metadata event
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KernelTraceControlEvent {
+    ImageId(ImageId),
+    DbgIdRsds(DbgIdRsds),
+    VolumeMapping(VolumeMapping),
+}
+
+impl KernelTraceControlEvent {
+    /// Decode a `KernelTraceControl` event
+    ///
+    /// This returns `None` for any other event (and for malformed ones)
+    pub fn from_record(record: &EventRecord) -> Option<Self> {
+        let mut payload = RawPayload::new(record);
+        match (record.provider_id(), record.opcode()) {
+            (IMAGE_ID_GUID, IMAGE_ID_OPCODE) => Some(Self::ImageId(ImageId {
+                image_base: payload.pointer()?,
+                image_size: payload.pointer()?.as_u64(),
+                process_id: payload.u32()?,
+                time_date_stamp: payload.u32()?,
+                original_file_name: payload.wide_string()?,
+            })),
+            (IMAGE_ID_GUID, DBG_ID_RSDS_OPCODE) => Some(Self::DbgIdRsds(DbgIdRsds {
+                image_base: payload.pointer()?,
+                process_id: payload.u32()?,
+                guid_sig: payload.guid()?,
+                age: payload.u32()?,
+                pdb_file_name: payload.ansi_string()?,
+            })),
+            (SYS_CONFIG_EX_GUID, VOLUME_MAPPING_OPCODE) => {
+                Some(Self::VolumeMapping(VolumeMapping {
+                    nt_path: payload.wide_string()?,
+                    dos_path: payload.wide_string()?,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// An image that was loaded during the trace, along with its PDB (if known)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleRecord {
+    pub image_base: u64,
+    pub image_size: u64,
+    pub time_date_stamp: u32,
+    pub original_file_name: String,
+    pub pdb: Option<DbgIdRsds>,
+}
+
+/// Collects `ImageId` and `DbgID_RSDS` events, so that addresses (e.g. from stack traces) can be mapped to their module and PDB
+///
+/// This can be shared between callbacks, and used for external symbolization (e.g. against a symbol server).
+///
+/// ```
+/// # use ferrisetw::EventRecord;
+/// # use ferrisetw::kernel_trace_control::ModuleRecords;
+/// fn on_event(record: &EventRecord, modules: &ModuleRecords) {
+///     if modules.on_event(record) {
+///         return;
+///     }
+///     // ...then, for every address of a stack trace
+///     # let address = 0;
+///     if let Some(module) = modules.find(address) {
+///         if let Some(pdb) = module.pdb {
+///             println!("{address:#x} is in {}, see {}", module.original_file_name, pdb.symbol_server_path());
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct ModuleRecords {
+    /// Modules, by image base
+    modules: RwLock<HashMap<u64, ModuleRecord>>,
+}
+
+impl ModuleRecords {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the module information from `record`
+    ///
+    /// Returns whether `record` was an `ImageId` or `DbgID_RSDS` event
+    pub fn on_event(&self, record: &EventRecord) -> bool {
+        let event = match KernelTraceControlEvent::from_record(record) {
+            Some(event) => event,
+            None => return false,
+        };
+        let mut modules = match self.modules.write() {
+            Ok(modules) => modules,
+            Err(_) => return false,
+        };
+
+        match event {
+            KernelTraceControlEvent::ImageId(image_id) => {
+                let base = image_id.image_base.as_u64();
+                let module = modules.entry(base).or_insert_with(|| ModuleRecord {
+                    image_base: base,
+                    ..Default::default()
+                });
+                module.image_size = image_id.image_size;
+                module.time_date_stamp = image_id.time_date_stamp;
+                module.original_file_name = image_id.original_file_name;
+                true
+            }
+            KernelTraceControlEvent::DbgIdRsds(dbg_id) => {
+                let base = dbg_id.image_base.as_u64();
+                let module = modules.entry(base).or_insert_with(|| ModuleRecord {
+                    image_base: base,
+                    ..Default::default()
+                });
+                module.pdb = Some(dbg_id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The module that contains `address`, if any
+    pub fn find(&self, address: u64) -> Option<ModuleRecord> {
+        let modules = self.modules.read().ok()?;
+        modules
+            .values()
+            .find(|module| {
+                address >= module.image_base && address - module.image_base < module.image_size
+            })
+            .cloned()
+    }
+
+    /// Every module recorded so far
+    pub fn modules(&self) -> Vec<ModuleRecord> {
+        match self.modules.read() {
+            Ok(modules) => modules.values().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// A cursor over the user data of a classic event, whose layout is known in advance
+pub(crate) struct RawPayload<'a> {
+    data: &'a [u8],
+    pointer_size: usize,
+}
+
+impl<'a> RawPayload<'a> {
+    pub fn new(record: &'a EventRecord) -> Self {
+        Self {
+            data: record.user_buffer(),
+            pointer_size: record.pointer_size(),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (taken, remainder) = (self.data.get(..len)?, self.data.get(len..)?);
+        self.data = remainder;
+        Some(taken)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    pub fn pointer(&mut self) -> Option<RemotePtr> {
+        let value = match self.pointer_size {
+            4 => self.u32()? as u64,
+            _ => self.u64()?,
+        };
+        Some(RemotePtr::new(value, self.pointer_size))
+    }
+
+    pub fn guid(&mut self) -> Option<GUID> {
+        let data1 = self.u32()?;
+        let mut rest = [0; 12];
+        rest.copy_from_slice(self.take(12)?);
+        let mut data4 = [0; 8];
+        data4.copy_from_slice(&rest[4..]);
+        Some(GUID::from_values(
+            data1,
+            u16::from_le_bytes([rest[0], rest[1]]),
+            u16::from_le_bytes([rest[2], rest[3]]),
+            data4,
+        ))
+    }
+
+    /// A null-terminated 8-bit string
+    pub fn ansi_string(&mut self) -> Option<String> {
+        let len = self.data.iter().take_while(|c| **c != 0).count();
+        let string = String::from_utf8_lossy(self.take(len)?).into_owned();
+        // Skip the null terminator (which may be missing at the end of the buffer)
+        let _ = self.take(1);
+        Some(string)
+    }
+
+    /// A null-terminated UTF-16 string
+    pub fn wide_string(&mut self) -> Option<String> {
+        let chars: Vec<u16> = self
+            .data
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        // Skip the string and its null terminator (which may be missing at the end of the buffer)
+        let consumed = ((chars.len() + 1) * 2).min(self.data.len());
+        self.take(consumed)?;
+        Some(String::from_utf16_lossy(&chars))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SyntheticEvent;
+
+    fn wide(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain(std::iter::once(0))
+            .flat_map(|c| c.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_image_id_32_bit() {
+        let event = SyntheticEvent::new()
+            .with_32_bit_header()
+            .with_provider(IMAGE_ID_GUID)
+            .with_pointer(0x0040_0000)
+            .with_pointer(0x2000)
+            .with_user_data(&1234u32.to_le_bytes())
+            .with_user_data(&0x5f5e_0000u32.to_le_bytes())
+            .with_user_data(&wide("notepad.exe"));
+
+        let decoded = KernelTraceControlEvent::from_record(event.record());
+        assert_eq!(
+            decoded,
+            Some(KernelTraceControlEvent::ImageId(ImageId {
+                image_base: RemotePtr::new(0x0040_0000, 4),
+                image_size: 0x2000,
+                process_id: 1234,
+                time_date_stamp: 0x5f5e_0000,
+                original_file_name: String::from("notepad.exe"),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_module_records() {
+        let guid = GUID::from_u128(0x1eb9facb_04ea_273b_b4ba_52c3ecef1e48);
+        let image_id = SyntheticEvent::new()
+            .with_provider(IMAGE_ID_GUID)
+            .with_pointer(0x7ff8_0000_0000)
+            .with_pointer(0x1000)
+            .with_user_data(&0u32.to_le_bytes())
+            .with_user_data(&0u32.to_le_bytes())
+            .with_user_data(&wide("ntdll.dll"));
+        let dbg_id = SyntheticEvent::new()
+            .with_provider(IMAGE_ID_GUID)
+            .with_opcode(DBG_ID_RSDS_OPCODE)
+            .with_pointer(0x7ff8_0000_0000)
+            .with_user_data(&4u32.to_le_bytes())
+            .with_user_data(&guid.data1.to_le_bytes())
+            .with_user_data(&guid.data2.to_le_bytes())
+            .with_user_data(&guid.data3.to_le_bytes())
+            .with_user_data(&guid.data4)
+            .with_user_data(&1u32.to_le_bytes())
+            .with_user_data(b"d:\\build\\ntdll.pdb\0");
+
+        let modules = ModuleRecords::new();
+        assert!(modules.on_event(image_id.record()));
+        assert!(modules.on_event(dbg_id.record()));
+
+        let module = modules.find(0x7ff8_0000_0800).unwrap();
+        assert_eq!(module.original_file_name, "ntdll.dll");
+        let pdb = module.pdb.unwrap();
+        assert_eq!(pdb.guid_sig, guid);
+        assert_eq!(
+            pdb.symbol_server_path(),
+            "ntdll.pdb/1EB9FACB04EA273BB4BA52C3ECEF1E481/ntdll.pdb"
+        );
+        assert!(modules.find(0x7ff8_0000_1000).is_none());
+    }
+
+    #[test]
+    fn test_decode_volume_mapping() {
+        let event = SyntheticEvent::new()
+            .with_provider(SYS_CONFIG_EX_GUID)
+            .with_opcode(VOLUME_MAPPING_OPCODE)
+            .with_user_data(&wide(r"\Device\HarddiskVolume3"))
+            .with_user_data(&wide("C:"));
+
+        let translator = DevicePathTranslator::empty();
+        match KernelTraceControlEvent::from_record(event.record()) {
+            Some(KernelTraceControlEvent::VolumeMapping(mapping)) => mapping.apply(&translator),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            translator.to_dos_path(std::path::Path::new(
+                r"\Device\HarddiskVolume3\Windows\notepad.exe"
+            )),
+            Some(std::path::PathBuf::from(r"C:\Windows\notepad.exe"))
+        );
+    }
+}
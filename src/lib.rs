@@ -98,10 +98,22 @@
 //! [KrabsETW]: https://github.com/microsoft/krabsetw/
 //! [Source]: https://docs.microsoft.com/en-us/windows/win32/etw/about-event-tracing
 //!
+//! # Self-telemetry
+//! ferrisetw can also report its own diagnostics (sessions started, providers enabled, lost events, decoding errors) as ETW events.
+//! See [`self_telemetry`].
+//!
 //! # Log messages
 //! ferrisetw may (very) occasionally write error log messages using the [`log`](https://docs.rs/log/latest/log/) crate.<br/>
 //! In case you want them to be printed to the console, your binary should use one of the various logger implementations. [`env_logger`](https://docs.rs/env_logger/latest/env_logger/) is one of them.<br/>
 //! You can have a look at how to use it in the `examples/` folder in the GitHub repository.
+//!
+//! # Profiling
+//! With the `tracing-internal` feature, ferrisetw enters [`tracing`](https://docs.rs/tracing/latest/tracing/) spans (at the `TRACE` level) around schema cache misses, TDH calls, property parsing and serialization.<br/>
+//! Any `tracing` subscriber (e.g. a flame graph or a Tracy layer) then shows how much of the time spent per event is spent in ferrisetw.
+
+// So that the code generated by `ferrisetw_derive` (that refers to `::ferrisetw`) can be used in our own tests
+#[cfg(test)]
+extern crate self as ferrisetw;
 
 #[macro_use]
 extern crate memoffset;
@@ -113,14 +125,30 @@ extern crate bitflags;
 extern crate num_derive;
 extern crate num_traits;
 
+pub mod capabilities;
+pub mod custody;
+mod error;
+pub mod etl;
+pub mod kernel_trace_control;
+pub mod metrics;
 pub mod native;
 pub mod parser;
+pub mod predicate;
 mod property;
 pub mod provider;
 pub mod query;
+pub mod relogger;
 pub mod schema;
 pub mod schema_locator;
+pub mod self_telemetry;
 pub mod ser;
+pub mod sink;
+#[cfg(feature = "kernel")]
+pub mod stack_walk;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(test)]
+mod test_utils;
 pub mod trace;
 mod traits;
 mod utils;
@@ -128,13 +156,19 @@ mod utils;
 pub(crate) type EtwCallback = Box<dyn FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static>;
 
 // Convenience re-exports.
+pub use crate::capabilities::capabilities;
+pub use crate::error::{Error, Result};
 pub use crate::native::etw_types::event_record::EventRecord;
+pub use crate::native::etw_types::event_record::OwnedEventRecord;
 pub use crate::schema_locator::SchemaLocator;
 #[cfg(feature = "serde")]
 pub use crate::ser::{EventSerializer, EventSerializerOptions};
 pub use crate::trace::FileTrace;
+#[cfg(feature = "kernel")]
 pub use crate::trace::KernelTrace;
 pub use crate::trace::UserTrace;
+#[cfg(feature = "derive")]
+pub use ferrisetw_derive::Event;
 
 // These types are returned by some public APIs of this crate.
 // They must be re-exported, so that users of the crate have a way to avoid version conflicts
@@ -0,0 +1,338 @@
+//! Turn the properties of events into metrics, published at fixed intervals
+//!
+//! [`EventMetrics`] extracts numeric properties from the events of a provider, aggregates them into counters and gauges,
+//! and periodically hands them over to a user callback. This callback can forward them to any metrics backend (the `metrics` crate, Prometheus, Windows performance counters...).
+//!
+//! For instance, per-process network throughput can be derived from `Microsoft-Windows-Kernel-Network` events:
+//! ```no_run
+//! # use std::time::Duration;
+//! # use ferrisetw::metrics::{EventMetrics, Metric};
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::UserTrace;
+//! let metrics = EventMetrics::new(vec![
+//!     // TcpIp/Send (IPv4)
+//!     Metric::counter("tcp_bytes_sent", 10, "size").per_process_from("PID"),
+//!     // TcpIp/Recv (IPv4)
+//!     Metric::counter("tcp_bytes_received", 11, "size").per_process_from("PID"),
+//! ]);
+//! let kernel_network = Provider::by_guid("7dd42a49-5329-4832-8dfd-43d979153a88")
+//!     .add_callback(metrics.callback())
+//!     .build();
+//! let _trace = UserTrace::new().enable(kernel_network).start_and_process().unwrap();
+//!
+//! let _publisher = metrics.publish_every(Duration::from_secs(1), |samples| {
+//!     for sample in samples {
+//!         println!("{} (pid {:?}): {} bytes/s", sample.name, sample.process_id, sample.value);
+//!     }
+//! });
+//! ```
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use crate::native::tdh_types::TdhInType;
+use crate::parser::Parser;
+use crate::{EventRecord, SchemaLocator};
+
+/// How the values of a [`Metric`] are aggregated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// The sum of the values received during each publication interval (e.g. bytes sent). It is reset after each publication
+    Counter,
+    /// The last value received (e.g. a queue length). It is kept until a new value is received
+    Gauge,
+}
+
+/// Where the process a value is attributed to comes from
+#[derive(Debug, Clone)]
+enum ProcessSource {
+    Header,
+    Property(String),
+}
+
+/// A metric derived from the events of a provider
+#[derive(Debug, Clone)]
+pub struct Metric {
+    name: String,
+    kind: MetricKind,
+    event_id: u16,
+    /// `None` to count the events
+    property: Option<String>,
+    per_process: Option<ProcessSource>,
+}
+
+impl Metric {
+    /// Sum the values of `property`, for every event with ID `event_id`
+    pub fn counter(name: &str, event_id: u16, property: &str) -> Self {
+        Self::new(name, MetricKind::Counter, event_id, Some(property))
+    }
+
+    /// Count the events with ID `event_id`
+    pub fn event_count(name: &str, event_id: u16) -> Self {
+        Self::new(name, MetricKind::Counter, event_id, None)
+    }
+
+    /// Keep the last value of `property`, for events with ID `event_id`
+    pub fn gauge(name: &str, event_id: u16, property: &str) -> Self {
+        Self::new(name, MetricKind::Gauge, event_id, Some(property))
+    }
+
+    fn new(name: &str, kind: MetricKind, event_id: u16, property: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            event_id,
+            property: property.map(str::to_string),
+            per_process: None,
+        }
+    }
+
+    /// Keep a distinct value for every process, as reported by the header of the events
+    pub fn per_process(mut self) -> Self {
+        self.per_process = Some(ProcessSource::Header);
+        self
+    }
+
+    /// Keep a distinct value for every process, whose ID is read from a property of the events
+    ///
+    /// This is useful for events that are not emitted in the context of the process they relate to (e.g. most kernel events).
+    pub fn per_process_from(mut self, property: &str) -> Self {
+        self.per_process = Some(ProcessSource::Property(property.to_string()));
+        self
+    }
+}
+
+/// The value of a [`Metric`], as published by [`EventMetrics`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSample {
+    pub name: String,
+    pub kind: MetricKind,
+    /// `None` unless the metric is per-process
+    pub process_id: Option<u32>,
+    pub value: f64,
+}
+
+/// The current values of every metric, indexed by the position of their metric and by process
+type Values = HashMap<(usize, Option<u32>), f64>;
+
+/// Aggregates the properties of events into metrics, see the [module-level documentation](crate::metrics)
+#[derive(Debug, Clone)]
+pub struct EventMetrics {
+    metrics: Arc<Vec<Metric>>,
+    values: Arc<Mutex<Values>>,
+}
+
+impl EventMetrics {
+    pub fn new(metrics: Vec<Metric>) -> Self {
+        Self {
+            metrics: Arc::new(metrics),
+            values: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The callback that feeds these metrics, to be given to [`ProviderBuilder::add_callback`](crate::provider::ProviderBuilder::add_callback)
+    ///
+    /// Event IDs are only compared within the provider this callback is added to.
+    pub fn callback(&self) -> impl FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static {
+        let this = self.clone();
+        move |record: &EventRecord, schema_locator: &SchemaLocator| {
+            this.on_event(record, schema_locator)
+        }
+    }
+
+    fn on_event(&self, record: &EventRecord, schema_locator: &SchemaLocator) {
+        let event_id = record.event_id();
+        if !self
+            .metrics
+            .iter()
+            .any(|metric| metric.event_id == event_id)
+        {
+            return;
+        }
+        let schema = match schema_locator.event_schema(record) {
+            Ok(schema) => schema,
+            Err(err) => {
+                log::debug!("Unable to get the schema of a metric event: {:?}", err);
+                return;
+            }
+        };
+        let parser = Parser::create(record, &schema);
+
+        for (index, metric) in self.metrics.iter().enumerate() {
+            if metric.event_id != event_id {
+                continue;
+            }
+            let value = match &metric.property {
+                None => Some(1.0),
+                Some(property) => numeric_property(&parser, property),
+            };
+            let process_id = match &metric.per_process {
+                None => Some(None),
+                Some(ProcessSource::Header) => Some(Some(record.process_id())),
+                Some(ProcessSource::Property(property)) => {
+                    numeric_property(&parser, property).map(|pid| Some(pid as u32))
+                }
+            };
+            match (value, process_id) {
+                (Some(value), Some(process_id)) => self.record(index, process_id, value),
+                _ => log::debug!(
+                    "Unable to get the value of metric {} from event {}",
+                    metric.name,
+                    event_id
+                ),
+            }
+        }
+    }
+
+    fn record(&self, index: usize, process_id: Option<u32>, value: f64) {
+        let mut values = self.values.lock().unwrap();
+        let current = values.entry((index, process_id)).or_insert(0.0);
+        match self.metrics[index].kind {
+            MetricKind::Counter => *current += value,
+            MetricKind::Gauge => *current = value,
+        }
+    }
+
+    /// The current value of every metric
+    ///
+    /// Counters are reset by this call, so that each snapshot contains the sum of the values received since the previous one.
+    pub fn snapshot(&self) -> Vec<MetricSample> {
+        let mut values = self.values.lock().unwrap();
+        let mut samples: Vec<MetricSample> = values
+            .iter()
+            .map(|((index, process_id), value)| {
+                let metric = &self.metrics[*index];
+                MetricSample {
+                    name: metric.name.clone(),
+                    kind: metric.kind,
+                    process_id: *process_id,
+                    value: *value,
+                }
+            })
+            .collect();
+        values.retain(|(index, _), _| self.metrics[*index].kind == MetricKind::Gauge);
+        samples.sort_by(|a, b| (&a.name, a.process_id).cmp(&(&b.name, b.process_id)));
+        samples
+    }
+
+    /// Call `publish` with a [`snapshot`](Self::snapshot) of the metrics every `interval`, from a background thread
+    ///
+    /// The thread stops when the returned [`MetricsPublisher`] is dropped.
+    pub fn publish_every<F>(&self, interval: Duration, mut publish: F) -> MetricsPublisher
+    where
+        F: FnMut(&[MetricSample]) + Send + 'static,
+    {
+        let this = self.clone();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                publish(&this.snapshot());
+            }
+        });
+        MetricsPublisher {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// A background thread that periodically publishes metrics, see [`EventMetrics::publish_every`]
+///
+/// The thread exits when this is dropped.
+#[derive(Debug)]
+pub struct MetricsPublisher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for MetricsPublisher {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn numeric_property(parser: &Parser, name: &str) -> Option<f64> {
+    let (bytes, in_type, _) = parser.raw_property(name).ok()?;
+    numeric_value(bytes, in_type)
+}
+
+/// Convert the bytes of a property into a number, according to its declared type
+fn numeric_value(bytes: &[u8], in_type: TdhInType) -> Option<f64> {
+    let value = match (in_type, bytes.len()) {
+        (TdhInType::InTypeInt8, 1) => bytes[0] as i8 as f64,
+        (TdhInType::InTypeUInt8, 1) => bytes[0] as f64,
+        (TdhInType::InTypeInt16, 2) => i16::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeUInt16, 2) => u16::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeInt32, 4) => i32::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeUInt32, 4)
+        | (TdhInType::InTypeHexInt32, 4)
+        | (TdhInType::InTypeBoolean, 4)
+        | (TdhInType::InTypePointer, 4) => u32::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeInt64, 8) => i64::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeUInt64, 8)
+        | (TdhInType::InTypeHexInt64, 8)
+        | (TdhInType::InTypePointer, 8) => u64::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeFloat, 4) => f32::from_ne_bytes(bytes.try_into().ok()?) as f64,
+        (TdhInType::InTypeDouble, 8) => f64::from_ne_bytes(bytes.try_into().ok()?),
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_numeric_value() {
+        assert_eq!(numeric_value(&[0xff], TdhInType::InTypeInt8), Some(-1.0));
+        assert_eq!(
+            numeric_value(&1500u32.to_ne_bytes(), TdhInType::InTypeUInt32),
+            Some(1500.0)
+        );
+        assert_eq!(
+            numeric_value(&2.5f64.to_ne_bytes(), TdhInType::InTypeDouble),
+            Some(2.5)
+        );
+        assert_eq!(numeric_value(&[1, 2], TdhInType::InTypeUInt32), None);
+        assert_eq!(numeric_value(b"abc\0", TdhInType::InTypeAnsiString), None);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let metrics = EventMetrics::new(vec![
+            Metric::counter("bytes", 10, "size").per_process(),
+            Metric::gauge("queue", 12, "length"),
+        ]);
+        metrics.record(0, Some(4), 100.0);
+        metrics.record(0, Some(4), 50.0);
+        metrics.record(0, Some(8), 10.0);
+        metrics.record(1, None, 3.0);
+        metrics.record(1, None, 7.0);
+
+        let sample = |name: &str, kind, process_id, value| MetricSample {
+            name: name.to_string(),
+            kind,
+            process_id,
+            value,
+        };
+        assert_eq!(
+            metrics.snapshot(),
+            vec![
+                sample("bytes", MetricKind::Counter, Some(4), 150.0),
+                sample("bytes", MetricKind::Counter, Some(8), 10.0),
+                sample("queue", MetricKind::Gauge, None, 7.0),
+            ]
+        );
+        // Counters are reset, gauges are kept
+        assert_eq!(
+            metrics.snapshot(),
+            vec![sample("queue", MetricKind::Gauge, None, 7.0)]
+        );
+    }
+}
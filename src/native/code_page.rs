@@ -0,0 +1,34 @@
+//! Native API - Code page conversions
+use windows::Win32::Globalization::{MultiByteToWideChar, MB_ERR_INVALID_CHARS};
+
+/// The system default Windows ANSI code page
+pub use windows::Win32::Globalization::CP_ACP;
+
+/// Decode a string encoded with the given code page
+///
+/// Returns an `Err` in case `bytes` contains sequences that are invalid in this code page
+pub fn multi_byte_to_string(code_page: u32, bytes: &[u8]) -> std::io::Result<String> {
+    if bytes.is_empty() {
+        return Ok(String::new());
+    }
+
+    let len = unsafe {
+        // Safety: a `None` output buffer makes this only compute the required length
+        MultiByteToWideChar(code_page, MB_ERR_INVALID_CHARS, bytes, None)
+    };
+    if len <= 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut wide = vec![0u16; len as usize];
+    let len = unsafe {
+        // Safety: `wide` has the size that has just been computed
+        MultiByteToWideChar(code_page, MB_ERR_INVALID_CHARS, bytes, Some(&mut wide))
+    };
+    if len <= 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    wide.truncate(len as usize);
+
+    Ok(String::from_utf16_lossy(&wide))
+}
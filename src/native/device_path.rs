@@ -0,0 +1,221 @@
+//! Translation of NT device paths into DOS paths
+//!
+//! Kernel events (e.g. file or image events) report paths such as `\Device\HarddiskVolume3\Windows\explorer.exe`.
+//! This module turns them into their more human-friendly counterparts, e.g. `C:\Windows\explorer.exe`.
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use widestring::{U16CString, U16Str, U16String};
+use windows::core::PCWSTR;
+use windows::Win32::Storage::FileSystem::{GetLogicalDrives, QueryDosDeviceW};
+
+/// Large enough for any `\Device\...` target of a drive letter
+const MAX_DEVICE_NAME_CHARS: usize = 1024;
+
+/// A drive letter (e.g. `C:`), along with the NT device it points to (e.g. `\Device\HarddiskVolume3`)
+#[derive(Debug, Clone)]
+struct DriveMapping {
+    device: Vec<u16>,
+    drive: Vec<u16>,
+}
+
+/// A cached translator from NT device paths to DOS paths
+///
+/// The mapping between drive letters and devices is queried (using [`QueryDosDeviceW`](https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-querydosdevicew)) when this instance is created.
+/// In case volumes are mounted or unmounted afterwards, call [`Self::refresh`].
+///
+/// # Example
+/// ```
+/// # use std::path::Path;
+/// # use ferrisetw::native::device_path::DevicePathTranslator;
+/// let translator = DevicePathTranslator::new();
+/// let dos_path = translator.to_dos_path(Path::new(r"\Device\HarddiskVolume3\Windows\explorer.exe"));
+/// // On most systems, this is Some(r"C:\Windows\explorer.exe")
+/// ```
+#[derive(Debug)]
+pub struct DevicePathTranslator {
+    mappings: RwLock<Vec<DriveMapping>>,
+}
+
+impl Default for DevicePathTranslator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DevicePathTranslator {
+    /// Create a translator, populated with the current drive letters
+    pub fn new() -> Self {
+        Self {
+            mappings: RwLock::new(query_drive_mappings()),
+        }
+    }
+
+    /// Create a translator without any mapping
+    ///
+    /// This is useful to translate paths from a trace recorded on another machine, whose mappings are added using [`Self::add_mapping`].
+    pub fn empty() -> Self {
+        Self {
+            mappings: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Add (or replace) the mapping between an NT device (e.g. `\Device\HarddiskVolume3`) and a DOS drive (e.g. `C:`)
+    ///
+    /// Note that [`Self::refresh`] discards the mappings added this way.
+    pub fn add_mapping(&self, device: &str, drive: &str) {
+        let device: Vec<u16> = device.trim_end_matches('\\').encode_utf16().collect();
+        let drive: Vec<u16> = drive.trim_end_matches('\\').encode_utf16().collect();
+        if device.is_empty() || drive.is_empty() {
+            return;
+        }
+
+        if let Ok(mut guard) = self.mappings.write() {
+            guard.retain(|mapping| !eq_ignore_ascii_case(&mapping.device, &device));
+            guard.push(DriveMapping { device, drive });
+        }
+    }
+
+    /// Query the drive letters again
+    pub fn refresh(&self) {
+        let mappings = query_drive_mappings();
+        if let Ok(mut guard) = self.mappings.write() {
+            *guard = mappings;
+        }
+    }
+
+    /// Translate `\Device\HarddiskVolumeX\...` into `X:\...`
+    ///
+    /// Returns `None` in case `path` does not start with a device that is mounted as a drive letter.
+    pub fn to_dos_path(&self, path: &Path) -> Option<PathBuf> {
+        let wide_path = U16String::from_os_str(path.as_os_str());
+        let mappings = self.mappings.read().ok()?;
+        translate(&mappings, wide_path.as_slice())
+            .map(|translated| PathBuf::from(U16Str::from_slice(&translated).to_os_string()))
+    }
+
+    /// Same as [`Self::to_dos_path`], but returns `path` unchanged in case it cannot be translated
+    pub fn to_dos_path_or_original(&self, path: &Path) -> PathBuf {
+        self.to_dos_path(path).unwrap_or_else(|| path.to_path_buf())
+    }
+}
+
+fn query_drive_mappings() -> Vec<DriveMapping> {
+    let drives_bitmask = unsafe {
+        // Safety: this function has no precondition
+        GetLogicalDrives()
+    };
+
+    let mut mappings = Vec::new();
+    for (index, letter) in (b'A'..=b'Z').enumerate() {
+        if drives_bitmask & (1 << index) == 0 {
+            continue;
+        }
+
+        let drive: Vec<u16> = vec![letter as u16, b':' as u16];
+        let wide_drive = U16CString::from_vec_truncate(drive.clone());
+        let mut target = vec![0u16; MAX_DEVICE_NAME_CHARS];
+        let written = unsafe {
+            // Safety:
+            //  * the device name is a valid, null-terminated widestring
+            //  * the output buffer is valid, and its size is passed along with it by windows-rs
+            QueryDosDeviceW(PCWSTR::from_raw(wide_drive.as_ptr()), Some(&mut target))
+        };
+        if written == 0 {
+            log::warn!(
+                "Unable to query the device for drive {}: {}",
+                letter as char,
+                std::io::Error::last_os_error()
+            );
+            continue;
+        }
+
+        // The output is a list of null-terminated strings. The first one is the current mapping.
+        let device: Vec<u16> = target.iter().copied().take_while(|c| *c != 0).collect();
+        if !device.is_empty() {
+            mappings.push(DriveMapping { device, drive });
+        }
+    }
+
+    mappings
+}
+
+fn translate(mappings: &[DriveMapping], path: &[u16]) -> Option<Vec<u16>> {
+    const BACKSLASH: u16 = b'\\' as u16;
+
+    mappings.iter().find_map(|mapping| {
+        let prefix = path.get(..mapping.device.len())?;
+        if !eq_ignore_ascii_case(prefix, &mapping.device) {
+            return None;
+        }
+
+        // Make sure `\Device\HarddiskVolume1` does not match `\Device\HarddiskVolume10\...`
+        let remainder = &path[mapping.device.len()..];
+        match remainder.first() {
+            None | Some(&BACKSLASH) => {
+                let mut translated = mapping.drive.clone();
+                translated.extend_from_slice(remainder);
+                Some(translated)
+            }
+            Some(_) => None,
+        }
+    })
+}
+
+fn eq_ignore_ascii_case(a: &[u16], b: &[u16]) -> bool {
+    fn to_ascii_lowercase(c: u16) -> u16 {
+        if (b'A' as u16..=b'Z' as u16).contains(&c) {
+            c + (b'a' - b'A') as u16
+        } else {
+            c
+        }
+    }
+
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(x, y)| to_ascii_lowercase(*x) == to_ascii_lowercase(*y))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn test_translate() {
+        let mappings = vec![
+            DriveMapping {
+                device: wide(r"\Device\HarddiskVolume1"),
+                drive: wide("D:"),
+            },
+            DriveMapping {
+                device: wide(r"\Device\HarddiskVolume10"),
+                drive: wide("C:"),
+            },
+        ];
+
+        assert_eq!(
+            translate(
+                &mappings,
+                &wide(r"\Device\HarddiskVolume10\Windows\explorer.exe")
+            ),
+            Some(wide(r"C:\Windows\explorer.exe"))
+        );
+        assert_eq!(
+            translate(&mappings, &wide(r"\device\harddiskvolume1\file.txt")),
+            Some(wide(r"D:\file.txt"))
+        );
+        assert_eq!(
+            translate(&mappings, &wide(r"\Device\HarddiskVolume1")),
+            Some(wide(r"D:"))
+        );
+        assert_eq!(
+            translate(&mappings, &wide(r"\Device\Mup\server\share\file.txt")),
+            None
+        );
+    }
+}
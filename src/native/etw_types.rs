@@ -8,6 +8,7 @@
 //! needed by using the functions exposed by the modules at the crate level
 #![allow(clippy::bad_bit_mask)]
 
+use crate::capabilities::capabilities;
 use crate::provider::event_filter::EventFilterDescriptor;
 use crate::provider::TraceFlags;
 use crate::trace::callback_data::CallbackData;
@@ -16,6 +17,7 @@ use std::ffi::{c_void, OsString};
 use std::fmt::Formatter;
 use std::marker::PhantomData;
 use std::sync::Arc;
+use std::time::Duration;
 
 use widestring::{U16CStr, U16CString};
 use windows::core::GUID;
@@ -27,6 +29,18 @@ pub(crate) mod event_record;
 pub(crate) mod extended_data;
 
 pub const TRACE_NAME_MAX_CHARS: usize = 200; // Microsoft documentation says the limit is 1024, but do not trust us. Experience shows that traces with names longer than ~240 character silently fail.
+/// The longest log file path, as documented for `QueryAllTracesW` (which fails in case a session logs to a longer path than the space it is given)
+pub const LOG_FILE_PATH_MAX_CHARS: usize = 1024;
+
+/// Makes `FlushTimer` a number of milliseconds instead of seconds (Windows 8+)
+///
+/// Not defined in windows-rs (yet?), see <https://learn.microsoft.com/en-us/windows/win32/etw/logging-mode-constants>
+const EVENT_TRACE_USE_MS_FLUSH_TIMER: u32 = 0x00000010;
+
+/// The `VersionNumber` bitfield of `EVENT_TRACE_PROPERTIES_V2::V2Control`
+///
+/// See <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties_v2>
+const EVENT_TRACE_PROPERTIES_V2_VERSION: u32 = 2;
 
 /// This enum is <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ne-evntrace-trace_query_info_class>
 ///
@@ -174,6 +188,43 @@ impl std::default::Default for DumpFileLoggingMode {
     }
 }
 
+/// The clock a session uses to timestamp its events (see `Wnode.ClientContext` in [EVENT_TRACE_PROPERTIES])
+///
+/// Unless a trace is started with [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps), Windows converts every timestamp to system time,
+/// so that the clock only changes the resolution and the cost of the timestamps.
+///
+/// [EVENT_TRACE_PROPERTIES]: https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ClockType {
+    /// The high-resolution performance counter (`QueryPerformanceCounter`)
+    #[default]
+    QueryPerformanceCounter,
+    /// The system time, with a resolution of the system timer (~15 ms)
+    SystemTime,
+    /// The CPU cycle counter. This is the cheapest clock, but it may drift when the frequency of the processor changes
+    CpuCycle,
+}
+
+impl ClockType {
+    /// The clock of a session, given the `Wnode.ClientContext` (or `TRACE_LOGFILE_HEADER::ReservedFlags`) value it has been started with
+    pub fn from_client_context(context: u32) -> Option<Self> {
+        match context {
+            1 => Some(Self::QueryPerformanceCounter),
+            2 => Some(Self::SystemTime),
+            3 => Some(Self::CpuCycle),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn client_context(self) -> u32 {
+        match self {
+            Self::QueryPerformanceCounter => 1,
+            Self::SystemTime => 2,
+            Self::CpuCycle => 3,
+        }
+    }
+}
+
 /// The data source the trace is subscribed to
 #[derive(Clone, Debug)]
 pub enum SubscriptionSource {
@@ -183,21 +234,38 @@ pub enum SubscriptionSource {
     FromFile(U16CString),
 }
 
-/// Wrapper over an [EVENT_TRACE_PROPERTIES](https://docs.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties), and its allocated companion members
+/// Settings of a session that are set with [`TraceBuilder`](crate::trace::TraceBuilder) methods, rather than with [`TraceProperties`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub(crate) struct SessionSettings {
+    /// See [`TraceBuilder::clock_type`](crate::trace::TraceBuilder::clock_type)
+    pub clock_type: ClockType,
+    /// See [`TraceBuilder::flush_threshold`](crate::trace::TraceBuilder::flush_threshold)
+    pub flush_threshold: u32,
+}
+
+/// Wrapper over an [EVENT_TRACE_PROPERTIES_V2](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties_v2), and its allocated companion members
 ///
 /// The [EventTraceProperties] struct contains the information about a tracing session, this struct
 /// also needs two buffers right after it to hold the log file name and the session name. This struct
 /// provides the full definition of the properties plus the the allocation for both names
+///
+/// `EVENT_TRACE_PROPERTIES_V2` starts with the same members as `EVENT_TRACE_PROPERTIES`.
+/// Unless [`Self::use_v2`] has been called, Windows will only consider these V1 members.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct EventTraceProperties {
-    etw_trace_properties: Etw::EVENT_TRACE_PROPERTIES,
+    etw_trace_properties: Etw::EVENT_TRACE_PROPERTIES_V2,
     /// The trace name to subscribe to
     wide_trace_name: [u16; TRACE_NAME_MAX_CHARS + 1], // The +1 leaves space for the final null widechar.
     /// The file name (if any) we store our events to
-    wide_etl_dump_file_path: [u16; TRACE_NAME_MAX_CHARS + 1], // The +1 leaves space for the final null widechar.
+    wide_etl_dump_file_path: [u16; LOG_FILE_PATH_MAX_CHARS + 1], // The +1 leaves space for the final null widechar.
 }
 
+// Safety: the only pointer in `EVENT_TRACE_PROPERTIES_V2` is `FilterDesc`, which is only set (by `use_v2`) for the duration of a `StartTraceW` call, and is reset to null right afterwards (by `clear_session_filters`)
+unsafe impl Send for EventTraceProperties {}
+// Safety: see above
+unsafe impl Sync for EventTraceProperties {}
+
 impl std::fmt::Debug for EventTraceProperties {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         let name = U16CString::from_vec_truncate(self.wide_trace_name).to_string_lossy();
@@ -212,7 +280,7 @@ impl EventTraceProperties {
     ///
     /// # Notes
     /// `trace_name` is limited to 200 characters.<br/>
-    /// The path to the dump file is limited to 200 characters.
+    /// The path to the dump file is limited to 1024 characters.
     pub(crate) fn new<T>(
         trace_name: &U16CStr,
         etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
@@ -222,19 +290,15 @@ impl EventTraceProperties {
     where
         T: RealTimeTraceTrait,
     {
-        let mut etw_trace_properties = Etw::EVENT_TRACE_PROPERTIES::default();
+        let mut etw_trace_properties = Etw::EVENT_TRACE_PROPERTIES_V2::default();
 
         etw_trace_properties.Wnode.BufferSize = std::mem::size_of::<EventTraceProperties>() as u32;
         etw_trace_properties.Wnode.Guid = T::trace_guid();
         etw_trace_properties.Wnode.Flags = Etw::WNODE_FLAG_TRACED_GUID;
-        etw_trace_properties.Wnode.ClientContext = 1; // QPC clock resolution
+        etw_trace_properties.Wnode.ClientContext = ClockType::default().client_context();
         etw_trace_properties.BufferSize = trace_properties.buffer_size;
         etw_trace_properties.MinimumBuffers = trace_properties.min_buffer;
         etw_trace_properties.MaximumBuffers = trace_properties.max_buffer;
-        etw_trace_properties.FlushTimer = trace_properties
-            .flush_timer
-            .as_secs()
-            .clamp(1, u32::MAX as u64) as u32; // See https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties
 
         if !trace_properties.log_file_mode.is_empty() {
             etw_trace_properties.LogFileMode = trace_properties.log_file_mode.bits();
@@ -244,13 +308,29 @@ impl EventTraceProperties {
                 .bits()
         }
 
+        let flush_timer = FlushTimer::from_duration(
+            trace_properties.flush_timer,
+            capabilities().millisecond_flush_timer,
+        );
+        if flush_timer.clamped {
+            log::warn!(
+                "Requested flush timer ({:?}) is not supported, {:?} will be used instead",
+                trace_properties.flush_timer,
+                flush_timer.effective_duration()
+            );
+        }
+        etw_trace_properties.FlushTimer = flush_timer.value;
+        if flush_timer.in_milliseconds {
+            etw_trace_properties.LogFileMode |= EVENT_TRACE_USE_MS_FLUSH_TIMER;
+        }
+
         etw_trace_properties.LogFileMode |= T::augmented_file_mode();
         etw_trace_properties.EnableFlags = enable_flags;
 
         let mut s = Self {
             etw_trace_properties,
             wide_trace_name: [0u16; TRACE_NAME_MAX_CHARS + 1],
-            wide_etl_dump_file_path: [0u16; TRACE_NAME_MAX_CHARS + 1],
+            wide_etl_dump_file_path: [0u16; LOG_FILE_PATH_MAX_CHARS + 1],
         };
 
         // https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties#remarks
@@ -272,7 +352,7 @@ impl EventTraceProperties {
             }
             Some((path, file_mode, max_size)) => {
                 // Set the file path, and set the dump-file-related flags
-                let path_len = path.len().min(TRACE_NAME_MAX_CHARS);
+                let path_len = path.len().min(LOG_FILE_PATH_MAX_CHARS);
                 s.wide_etl_dump_file_path[..path_len].copy_from_slice(&path.as_slice()[..path_len]);
                 s.etw_trace_properties.LogFileNameOffset =
                     offset_of!(EventTraceProperties, wide_etl_dump_file_path) as u32;
@@ -287,6 +367,22 @@ impl EventTraceProperties {
         s
     }
 
+    /// Create an instance with no settings, that is suitable to be filled by Windows (e.g. by `QueryAllTracesW`)
+    pub(crate) fn empty() -> Self {
+        let mut etw_trace_properties = Etw::EVENT_TRACE_PROPERTIES_V2::default();
+        etw_trace_properties.Wnode.BufferSize = std::mem::size_of::<EventTraceProperties>() as u32;
+        etw_trace_properties.LoggerNameOffset =
+            offset_of!(EventTraceProperties, wide_trace_name) as u32;
+        etw_trace_properties.LogFileNameOffset =
+            offset_of!(EventTraceProperties, wide_etl_dump_file_path) as u32;
+
+        Self {
+            etw_trace_properties,
+            wide_trace_name: [0u16; TRACE_NAME_MAX_CHARS + 1],
+            wide_etl_dump_file_path: [0u16; LOG_FILE_PATH_MAX_CHARS + 1],
+        }
+    }
+
     /// Gets a pointer to the wrapped [Etw::EVENT_TRACE_PROPERTIES]
     ///
     /// # Safety
@@ -296,7 +392,83 @@ impl EventTraceProperties {
     ///
     /// Note that `OpenTraceA` **will** modify its content on output.
     pub unsafe fn as_mut_ptr(&mut self) -> *mut Etw::EVENT_TRACE_PROPERTIES {
-        &mut self.etw_trace_properties as *mut Etw::EVENT_TRACE_PROPERTIES
+        // EVENT_TRACE_PROPERTIES_V2 begins with the very same members as EVENT_TRACE_PROPERTIES
+        &mut self.etw_trace_properties as *mut Etw::EVENT_TRACE_PROPERTIES_V2
+            as *mut Etw::EVENT_TRACE_PROPERTIES
+    }
+
+    /// Mark these properties as `EVENT_TRACE_PROPERTIES_V2`, and attach session-level filters to them
+    ///
+    /// `filters` is referenced by raw pointer, so it must outlive the next call to `StartTraceW`.
+    /// Call [`Self::clear_session_filters`] as soon as this call has returned.
+    pub(crate) fn use_v2(&mut self, filters: &mut [EVENT_FILTER_DESCRIPTOR]) {
+        self.etw_trace_properties.Wnode.Flags |= Etw::WNODE_FLAG_VERSIONED_PROPERTIES;
+        self.etw_trace_properties.Anonymous2.V2Control = EVENT_TRACE_PROPERTIES_V2_VERSION;
+        self.etw_trace_properties.FilterDescCount = filters.len() as u32; // (let's assume we won't try to fit more than 4 billion filters)
+        if filters.is_empty() {
+            self.etw_trace_properties.FilterDesc = std::ptr::null_mut();
+        } else {
+            self.etw_trace_properties.FilterDesc = filters.as_mut_ptr();
+        }
+    }
+
+    /// Apply the settings that are not part of [`TraceProperties`]
+    pub(crate) fn apply_settings(&mut self, settings: &SessionSettings) {
+        self.etw_trace_properties.Wnode.ClientContext = settings.clock_type.client_context();
+        self.etw_trace_properties.Anonymous1.FlushThreshold = settings.flush_threshold as i32;
+    }
+
+    /// Override the GUID of the session (private logger sessions use the GUID of their provider)
+    pub(crate) fn set_session_guid(&mut self, guid: GUID) {
+        self.etw_trace_properties.Wnode.Guid = guid;
+    }
+
+    /// Revert to plain `EVENT_TRACE_PROPERTIES` (e.g. on Windows versions that do not support V2)
+    pub(crate) fn use_v1(&mut self) {
+        self.etw_trace_properties.Wnode.Flags &= !Etw::WNODE_FLAG_VERSIONED_PROPERTIES;
+        self.etw_trace_properties.Anonymous2.V2Control = 0;
+        self.clear_session_filters();
+    }
+
+    /// Whether these properties are currently marked as `EVENT_TRACE_PROPERTIES_V2`
+    pub(crate) fn is_v2(&self) -> bool {
+        self.etw_trace_properties.Wnode.Flags & Etw::WNODE_FLAG_VERSIONED_PROPERTIES != 0
+    }
+
+    /// Forget about the session-level filters set by [`Self::use_v2`], so that no dangling pointer is kept around
+    pub(crate) fn clear_session_filters(&mut self) {
+        self.etw_trace_properties.FilterDescCount = 0;
+        self.etw_trace_properties.FilterDesc = std::ptr::null_mut();
+    }
+
+    /// The wrapped native properties, e.g. to read the counters updated by `ControlTraceW(EVENT_TRACE_CONTROL_QUERY)`
+    pub(crate) fn native(&self) -> &Etw::EVENT_TRACE_PROPERTIES_V2 {
+        &self.etw_trace_properties
+    }
+
+    /// Set or clear `EVENT_TRACE_REAL_TIME_MODE`, in preparation for a `ControlTraceW(EVENT_TRACE_CONTROL_UPDATE)`
+    ///
+    /// The log file name is cleared, because an update with a log file name would switch the session to another file.
+    pub(crate) fn set_real_time_mode(&mut self, enabled: bool) {
+        // Work on the raw bits, that may contain flags `LoggingMode` does not know about
+        let real_time = LoggingMode::EVENT_TRACE_REAL_TIME_MODE.bits();
+        if enabled {
+            self.etw_trace_properties.LogFileMode |= real_time;
+        } else {
+            self.etw_trace_properties.LogFileMode &= !real_time;
+        }
+        self.etw_trace_properties.LogFileNameOffset = 0;
+    }
+
+    /// The path of the file the session logs to, if any
+    pub(crate) fn log_file_name(&self) -> Option<OsString> {
+        if self.etw_trace_properties.LogFileNameOffset == 0 {
+            return None;
+        }
+        widestring::U16CStr::from_slice_truncate(&self.wide_etl_dump_file_path)
+            .ok()
+            .filter(|ws| !ws.is_empty())
+            .map(|ws| ws.to_os_string())
     }
 
     pub fn trace_name_array(&self) -> &[u16] {
@@ -309,6 +481,75 @@ impl EventTraceProperties {
     }
 }
 
+/// The `FlushTimer` member of an `EVENT_TRACE_PROPERTIES`, along with the unit it is expressed in
+///
+/// See <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FlushTimer {
+    /// The value to write into `FlushTimer`
+    pub value: u32,
+    /// Whether `value` is expressed in milliseconds (and thus requires `EVENT_TRACE_USE_MS_FLUSH_TIMER`) rather than in seconds
+    pub in_milliseconds: bool,
+    /// Whether the requested duration could not be honored exactly
+    pub clamped: bool,
+}
+
+impl FlushTimer {
+    /// Convert a requested flush interval into a `FlushTimer`
+    ///
+    /// Whole seconds are always expressed in seconds.<br/>
+    /// Sub-second intervals are expressed in milliseconds when `ms_supported` is true (i.e. on Windows 8 and later), and are rounded up to the next second otherwise.<br/>
+    /// ETW interprets a zero `FlushTimer` as "no periodic flush", so the smallest value we produce is 1 (second or millisecond).
+    /// A zero `requested` interval is mapped to one second, as it always has been.
+    pub fn from_duration(requested: Duration, ms_supported: bool) -> Self {
+        let max = u32::MAX as u128;
+
+        if requested.is_zero() {
+            return Self {
+                value: 1,
+                in_milliseconds: false,
+                clamped: true,
+            };
+        }
+
+        if requested.subsec_nanos() == 0 && requested.as_secs() > 0 {
+            let secs = requested.as_secs() as u128;
+            return Self {
+                value: secs.min(max) as u32,
+                in_milliseconds: false,
+                clamped: secs > max,
+            };
+        }
+
+        if ms_supported {
+            let millis = requested.as_millis();
+            let value = millis.clamp(1, max);
+            return Self {
+                value: value as u32,
+                in_milliseconds: true,
+                clamped: value * 1_000_000 != requested.as_nanos(),
+            };
+        }
+
+        // Round up, so that we never flush more often than requested (and at least once per second)
+        let secs = requested.as_secs() as u128 + 1;
+        Self {
+            value: secs.min(max) as u32,
+            in_milliseconds: false,
+            clamped: true,
+        }
+    }
+
+    /// The flush interval that ETW will actually use
+    pub fn effective_duration(&self) -> Duration {
+        if self.in_milliseconds {
+            Duration::from_millis(self.value as u64)
+        } else {
+            Duration::from_secs(self.value as u64)
+        }
+    }
+}
+
 /// Newtype wrapper over an [EVENT_TRACE_LOGFILEW]
 ///
 /// Its lifetime is tied a to [`CallbackData`] because it contains raw pointers to it.
@@ -329,6 +570,7 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
         callback_data: &'callbackdata Box<Arc<CallbackData>>,
         subscription_source: SubscriptionSource,
         callback: unsafe extern "system" fn(*mut Etw::EVENT_RECORD),
+        buffer_callback: unsafe extern "system" fn(*mut Etw::EVENT_TRACE_LOGFILEW) -> u32,
     ) -> Self {
         let not_really_mut_ptr =
             callback_data.as_ref() as *const Arc<CallbackData> as *const c_void as *mut c_void; // That's kind-of fine because the user context is _not supposed_ to be changed by Windows APIs
@@ -337,6 +579,7 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
             Anonymous2: Etw::EVENT_TRACE_LOGFILEW_1 {
                 EventRecordCallback: Some(callback),
             },
+            BufferCallback: Some(buffer_callback),
             Context: not_really_mut_ptr,
             ..Default::default()
         };
@@ -352,9 +595,14 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
             SubscriptionSource::RealTimeSession(wide_logger_name) => {
                 log_file.native.LoggerName = PWSTR(wide_logger_name.as_mut_ptr());
 
+                let mut mode =
+                    Etw::PROCESS_TRACE_MODE_REAL_TIME | Etw::PROCESS_TRACE_MODE_EVENT_RECORD;
+                if callback_data.raw_timestamps() {
+                    // See EventRecord::system_timestamp() for how these are converted back
+                    mode |= Etw::PROCESS_TRACE_MODE_RAW_TIMESTAMP;
+                }
                 log_file.native.Anonymous1 = Etw::EVENT_TRACE_LOGFILEW_0 {
-                    ProcessTraceMode: Etw::PROCESS_TRACE_MODE_REAL_TIME
-                        | Etw::PROCESS_TRACE_MODE_EVENT_RECORD, // In case you really want to use PROCESS_TRACE_MODE_RAW_TIMESTAMP, please review EventRecord::timestamp(), which could not be valid anymore
+                    ProcessTraceMode: mode,
                 };
             }
             SubscriptionSource::FromFile(wide_file_name) => {
@@ -379,9 +627,81 @@ impl<'callbackdata> EventTraceLogfile<'callbackdata> {
         &mut self.native as *mut Etw::EVENT_TRACE_LOGFILEW
     }
 
-    /// The current Context pointer.
-    pub fn context_ptr(&self) -> *const std::ffi::c_void {
-        self.native.Context
+    /// The header that `OpenTraceW` has filled
+    pub(crate) fn header(&self) -> LogFileHeader {
+        LogFileHeader::from(&self.native.LogfileHeader)
+    }
+}
+
+/// Information about the environment a trace has been captured in, as read from its [TRACE_LOGFILE_HEADER]
+///
+/// This is filled when the trace is opened, before any event is processed. See [`crate::FileTrace::log_file_header`].<br/>
+/// Timestamps are `FILETIME` quads, i.e. on the same clock as [`crate::EventRecord::raw_timestamp`].
+///
+/// [TRACE_LOGFILE_HEADER]: https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-trace_logfile_header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct LogFileHeader {
+    /// Size of the buffers of the session, in bytes
+    pub buffer_size: u32,
+    pub os_major_version: u8,
+    pub os_minor_version: u8,
+    /// Build number of the OS
+    pub os_build: u32,
+    pub number_of_processors: u32,
+    pub cpu_speed_mhz: u32,
+    /// Size of a pointer on the capturing machine, in bytes
+    pub pointer_size: u32,
+    /// Frequency of the high-resolution performance counter, in counts per second
+    pub perf_freq: i64,
+    /// Resolution of the hardware timer, in 100-nanosecond units
+    pub timer_resolution: u32,
+    /// The clock used for the timestamps of the session (see `Wnode.ClientContext` in `EVENT_TRACE_PROPERTIES`, and [`Self::clock`])
+    pub clock_type: u32,
+    pub boot_time: i64,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub buffers_written: u32,
+    pub buffers_lost: u32,
+    pub events_lost: u32,
+    /// See [`LoggingMode`]
+    pub log_file_mode: u32,
+    /// Maximum size of the file, in MB
+    pub maximum_file_size: u32,
+}
+
+impl LogFileHeader {
+    /// The clock used for the timestamps of the session, in case it is known
+    pub fn clock(&self) -> Option<ClockType> {
+        ClockType::from_client_context(self.clock_type)
+    }
+}
+
+impl From<&Etw::TRACE_LOGFILE_HEADER> for LogFileHeader {
+    fn from(header: &Etw::TRACE_LOGFILE_HEADER) -> Self {
+        // Safety: the members of these unions are plain integers (or GUIDs), any bit pattern is valid
+        let (version, sizes) =
+            unsafe { (header.Anonymous1.VersionDetail, header.Anonymous2.Anonymous) };
+        Self {
+            buffer_size: header.BufferSize,
+            os_major_version: version.MajorVersion,
+            os_minor_version: version.MinorVersion,
+            os_build: header.ProviderVersion,
+            number_of_processors: header.NumberOfProcessors,
+            cpu_speed_mhz: sizes.CpuSpeedInMHz,
+            pointer_size: sizes.PointerSize,
+            perf_freq: header.PerfFreq,
+            timer_resolution: header.TimerResolution,
+            clock_type: header.ReservedFlags,
+            boot_time: header.BootTime,
+            start_time: header.StartTime,
+            end_time: header.EndTime,
+            buffers_written: header.BuffersWritten,
+            buffers_lost: header.BuffersLost,
+            events_lost: sizes.EventsLost,
+            log_file_mode: header.LogFileMode,
+            maximum_file_size: header.MaximumFileSize,
+        }
     }
 }
 
@@ -440,7 +760,7 @@ impl<'filters> EnableTraceParameters<'filters> {
 /// Wrapper over the [DECODING_SOURCE] type
 ///
 /// [DECODING_SOURCE]: https://learn.microsoft.com/en-us/windows/win32/api/tdh/ne-tdh-decoding_source
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DecodingSource {
     DecodingSourceXMLFile,
     DecodingSourceWbem,
@@ -464,3 +784,128 @@ impl From<Etw::DECODING_SOURCE> for DecodingSource {
 // Safe cast (EVENT_HEADER_FLAG_32_BIT_HEADER = 32)
 #[doc(hidden)]
 pub const EVENT_HEADER_FLAG_32_BIT_HEADER: u16 = Etw::EVENT_HEADER_FLAG_32_BIT_HEADER as u16;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_type() {
+        for clock in [
+            ClockType::QueryPerformanceCounter,
+            ClockType::SystemTime,
+            ClockType::CpuCycle,
+        ] {
+            assert_eq!(
+                ClockType::from_client_context(clock.client_context()),
+                Some(clock)
+            );
+        }
+        assert_eq!(ClockType::from_client_context(0), None);
+        assert_eq!(
+            SessionSettings::default().clock_type.client_context(),
+            1,
+            "sessions use the QPC clock by default"
+        );
+        let header = LogFileHeader {
+            clock_type: 3,
+            ..Default::default()
+        };
+        assert_eq!(header.clock(), Some(ClockType::CpuCycle));
+    }
+
+    #[test]
+    fn test_flush_timer_whole_seconds() {
+        let ft = FlushTimer::from_duration(Duration::from_secs(3), true);
+        assert_eq!(ft.value, 3);
+        assert!(!ft.in_milliseconds);
+        assert!(!ft.clamped);
+    }
+
+    #[test]
+    fn test_flush_timer_sub_second() {
+        let ft = FlushTimer::from_duration(Duration::from_millis(250), true);
+        assert_eq!(ft.value, 250);
+        assert!(ft.in_milliseconds);
+        assert!(!ft.clamped);
+
+        let ft = FlushTimer::from_duration(Duration::from_millis(250), false);
+        assert_eq!(ft.value, 1);
+        assert!(!ft.in_milliseconds);
+        assert!(ft.clamped);
+    }
+
+    #[test]
+    fn test_flush_timer_zero() {
+        let ft = FlushTimer::from_duration(Duration::ZERO, true);
+        assert_eq!(ft.effective_duration(), Duration::from_secs(1));
+        assert!(ft.clamped);
+
+        let ft = FlushTimer::from_duration(Duration::from_micros(10), true);
+        assert_eq!(ft.effective_duration(), Duration::from_millis(1));
+        assert!(ft.clamped);
+
+        let ft = FlushTimer::from_duration(Duration::ZERO, false);
+        assert_eq!(ft.effective_duration(), Duration::from_secs(1));
+        assert!(ft.clamped);
+    }
+
+    #[test]
+    fn test_set_real_time_mode() {
+        let name = U16CString::from_str("test-session").unwrap();
+        let path = U16CString::from_str("C:\\trace.etl").unwrap();
+        let mut properties = EventTraceProperties::new::<crate::UserTrace>(
+            &name,
+            Some((&path, DumpFileLoggingMode::default(), None)),
+            &TraceProperties::default(),
+            Etw::EVENT_TRACE_FLAG::default(),
+        );
+        assert!(properties.log_file_name().is_some());
+        let real_time = LoggingMode::EVENT_TRACE_REAL_TIME_MODE.bits();
+        let other_modes = properties.native().LogFileMode & !real_time;
+
+        properties.set_real_time_mode(false);
+        assert_eq!(properties.native().LogFileMode, other_modes);
+        assert!(properties.log_file_name().is_none());
+
+        properties.set_real_time_mode(true);
+        assert_eq!(properties.native().LogFileMode, other_modes | real_time);
+    }
+
+    #[test]
+    fn test_long_log_file_path() {
+        let name = U16CString::from_str("test-session").unwrap();
+        let long_path = format!("C:\\{}.etl", "a".repeat(600));
+        let path = U16CString::from_str(&long_path).unwrap();
+        let properties = EventTraceProperties::new::<crate::UserTrace>(
+            &name,
+            Some((&path, DumpFileLoggingMode::default(), None)),
+            &TraceProperties::default(),
+            Etw::EVENT_TRACE_FLAG::default(),
+        );
+        assert_eq!(properties.log_file_name(), Some(long_path.into()));
+    }
+
+    #[test]
+    fn test_log_file_header() {
+        let mut native = Etw::TRACE_LOGFILE_HEADER {
+            BufferSize: 65536,
+            ProviderVersion: 19045,
+            NumberOfProcessors: 8,
+            BuffersLost: 2,
+            ..Default::default()
+        };
+        native.Anonymous1.VersionDetail.MajorVersion = 10;
+        native.Anonymous2.Anonymous.PointerSize = 8;
+        native.Anonymous2.Anonymous.EventsLost = 3;
+
+        let header = LogFileHeader::from(&native);
+        assert_eq!(header.buffer_size, 65536);
+        assert_eq!(header.os_major_version, 10);
+        assert_eq!(header.os_build, 19045);
+        assert_eq!(header.number_of_processors, 8);
+        assert_eq!(header.pointer_size, 8);
+        assert_eq!(header.events_lost, 3);
+        assert_eq!(header.buffers_lost, 2);
+    }
+}
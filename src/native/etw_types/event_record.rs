@@ -1,10 +1,11 @@
 //! Safe wrappers over the EVENT_RECORD type
 
 use windows::core::GUID;
-use windows::Win32::System::Diagnostics::Etw::EVENT_RECORD;
+use windows::Win32::System::Diagnostics::Etw::{EVENT_HEADER_EXTENDED_DATA_ITEM, EVENT_RECORD};
 
 use crate::native::etw_types::extended_data::EventHeaderExtendedDataItem;
-use crate::native::ExtendedDataItem;
+use crate::native::{ExtendedDataItem, StackTraceItem};
+use crate::provider::metadata::ProviderMetadata;
 
 use super::EVENT_HEADER_FLAG_32_BIT_HEADER;
 
@@ -56,6 +57,14 @@ impl EventRecord {
         self.0.EventHeader.EventDescriptor.Opcode
     }
 
+    /// The class and opcode of this event, if it is a classic kernel event
+    ///
+    /// This makes it possible to match kernel events without hardcoding the GUIDs of their classes.
+    #[cfg(feature = "kernel")]
+    pub fn kernel_event_kind(&self) -> Option<crate::provider::kernel_providers::KernelEventKind> {
+        crate::provider::kernel_providers::KernelEventKind::new(self.provider_id(), self.opcode())
+    }
+
     /// The `Version` field from the wrapped `EVENT_RECORD`
     pub fn version(&self) -> u8 {
         self.0.EventHeader.EventDescriptor.Version
@@ -71,6 +80,38 @@ impl EventRecord {
         self.0.EventHeader.EventDescriptor.Keyword
     }
 
+    /// Whether this event matches a level and keywords, given by their names in the provider manifest
+    ///
+    /// This returns `true` when the event comes from the provider described by `metadata`, its level is at most as verbose as `level` (e.g. an `"Error"` event matches `"Warning"`),
+    /// and it has at least one of the `keywords` (an empty `keywords` slice does not filter on keywords).<br/>
+    /// Unknown level or keyword names never match.
+    ///
+    /// ```
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::provider::metadata::ProviderMetadata;
+    /// fn is_network_error(record: &EventRecord, metadata: &ProviderMetadata) -> bool {
+    ///     record.matches(metadata, "Error", &["Network"])
+    /// }
+    /// ```
+    pub fn matches(&self, metadata: &ProviderMetadata, level: &str, keywords: &[&str]) -> bool {
+        if self.provider_id() != metadata.guid() {
+            return false;
+        }
+
+        match metadata.level(level) {
+            Some(max_level) if self.level() <= max_level => (),
+            _ => return false,
+        }
+
+        if keywords.is_empty() {
+            return true;
+        }
+        match metadata.keywords_mask(keywords) {
+            Some(mask) => self.keyword() & mask != 0,
+            None => false,
+        }
+    }
+
     /// The `Flags` field from the wrapped `EVENT_RECORD`
     pub fn event_flags(&self) -> u16 {
         self.0.EventHeader.Flags
@@ -91,6 +132,14 @@ impl EventRecord {
         self.0.EventHeader.ActivityId
     }
 
+    /// The index of the processor the event has been logged on, from the `BufferContext` of the wrapped `EVENT_RECORD`
+    pub fn processor_index(&self) -> u16 {
+        unsafe {
+            // Safety: both members of this union are plain integers. The index is always set, since traces are opened with `PROCESS_TRACE_MODE_EVENT_RECORD`
+            self.0.BufferContext.Anonymous.ProcessorIndex
+        }
+    }
+
     /// The `TimeStamp` field from the wrapped `EVENT_RECORD`
     ///
     /// As per [Microsoft's documentation](https://docs.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_header):
@@ -100,15 +149,29 @@ impl EventRecord {
     /// > on the value of the `Wnode.ClientContext` member of `EVENT_TRACE_PROPERTIES` at the time
     /// > the controller created the session.
     ///
+    /// This is thus in system time, unless the trace has been started with [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps).
+    ///
     /// Note: the `time_rs` Cargo feature enables to convert this into strongly-typed values
     pub fn raw_timestamp(&self) -> i64 {
         self.0.EventHeader.TimeStamp
     }
 
-    /// The `TimeStamp` field from the wrapped `EVENT_RECORD`, as a strongly-typed `time::OffsetDateTime`
+    /// The time the event occurred, as a `FILETIME` quad (i.e. the number of 100-nanosecond intervals since January 1, 1601)
+    ///
+    /// This is the same as [`Self::raw_timestamp`], except for traces that have been started with [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps),
+    /// whose timestamps are converted from the clock of the session (see [`ClockType`](crate::trace::ClockType)).
+    pub fn system_timestamp(&self) -> i64 {
+        let raw = self.raw_timestamp();
+        match crate::native::evntrace::timestamp_converter(self) {
+            Some(converter) => converter.to_file_time(raw),
+            None => raw,
+        }
+    }
+
+    /// The time the event occurred (see [`Self::system_timestamp`]), as a strongly-typed `time::OffsetDateTime`
     #[cfg(feature = "time_rs")]
     pub fn timestamp(&self) -> time::OffsetDateTime {
-        crate::native::time::FileTime::from_quad(self.0.EventHeader.TimeStamp).into()
+        crate::native::time::FileTime::from_quad(self.system_timestamp()).into()
     }
 
     pub(crate) fn user_buffer(&self) -> &[u8] {
@@ -161,6 +224,39 @@ impl EventRecord {
         }
     }
 
+    /// Deep-copy this event (its header, user data and extended data), so that it can outlive the callback
+    ///
+    /// The resulting [`OwnedEventRecord`] can be sent to other threads, and parsed later (e.g. with a [`Parser`](crate::parser::Parser)).
+    pub fn to_owned(&self) -> OwnedEventRecord {
+        OwnedEventRecord::new(self)
+    }
+
+    /// Returns the call stack attached to this event, if any
+    ///
+    /// Stacks are only captured when requested, e.g. with [`TraceFlags::EVENT_ENABLE_PROPERTY_STACK_TRACE`](crate::provider::TraceFlags::EVENT_ENABLE_PROPERTY_STACK_TRACE).<br/>
+    /// Stacks captured on 32-bit computers are widened to 64-bit addresses.
+    pub fn stack_trace(&self) -> Option<StackTraceItem<u64>> {
+        self.extended_data()
+            .iter()
+            .find_map(|ext_data| match ext_data.to_extended_data_item() {
+                ExtendedDataItem::StackTrace64(stack) => Some(stack),
+                ExtendedDataItem::StackTrace32(stack) => Some(stack.into()),
+                _ => None,
+            })
+    }
+
+    /// Returns the start key of the process that has emitted this event, if any
+    ///
+    /// Unlike process IDs, start keys are not reused (until the next boot). They are only attached when requested with [`TraceFlags::EVENT_ENABLE_PROPERTY_PROCESS_START_KEY`](crate::provider::TraceFlags::EVENT_ENABLE_PROPERTY_PROCESS_START_KEY).
+    pub fn process_start_key(&self) -> Option<u64> {
+        self.extended_data()
+            .iter()
+            .find_map(|ext_data| match ext_data.to_extended_data_item() {
+                ExtendedDataItem::ProcessStartKey(key) => Some(key),
+                _ => None,
+            })
+    }
+
     /// Returns the `eventName` for manifest-free events
     pub fn event_name(&self) -> String {
         if self.event_id() != 0 {
@@ -179,3 +275,136 @@ impl EventRecord {
         }
     }
 }
+
+/// An [`EventRecord`] that owns its data, see [`EventRecord::to_owned`]
+///
+/// This dereferences to an [`EventRecord`], and can thus be used wherever an `&EventRecord` is expected.
+pub struct OwnedEventRecord {
+    record: EventRecord,
+    /// `record.UserData` points to this buffer.
+    /// `u64`s are used so that the data is at least as aligned as it is in ETW buffers
+    #[allow(dead_code)] // only kept alive for `record`
+    user_data: Vec<u64>,
+    /// `record.ExtendedData` points to this array
+    extended_data: Vec<EVENT_HEADER_EXTENDED_DATA_ITEM>,
+    /// The `DataPtr` of every item of `extended_data` points to one of these buffers
+    #[allow(dead_code)] // only kept alive for `extended_data`
+    extended_data_buffers: Vec<Vec<u64>>,
+}
+
+// Safety: the only pointers in the wrapped `EVENT_RECORD` point to heap buffers that are owned by this instance, and never mutated
+unsafe impl Send for OwnedEventRecord {}
+// Safety: see above
+unsafe impl Sync for OwnedEventRecord {}
+
+/// Copy `data` into a buffer that is suitably aligned for any ETW data
+fn aligned_copy(data: &[u8]) -> Vec<u64> {
+    let mut buffer = vec![0u64; data.len().div_ceil(std::mem::size_of::<u64>())];
+    unsafe {
+        // Safety: `buffer` is at least `data.len()` bytes long, and does not overlap `data`
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buffer.as_mut_ptr().cast::<u8>(), data.len());
+    }
+    buffer
+}
+
+impl OwnedEventRecord {
+    fn new(source: &EventRecord) -> Self {
+        let user_data = aligned_copy(source.user_buffer());
+
+        let mut extended_data = Vec::with_capacity(source.extended_data().len());
+        let mut extended_data_buffers = Vec::with_capacity(source.extended_data().len());
+        for item in source.extended_data() {
+            let raw = item.as_raw();
+            let data = if raw.DataPtr == 0 || raw.DataSize == 0 {
+                &[][..]
+            } else {
+                unsafe {
+                    // Safety: Windows guarantees `DataPtr` points to `DataSize` bytes, that live as long as `source`
+                    std::slice::from_raw_parts(raw.DataPtr as *const u8, raw.DataSize as usize)
+                }
+            };
+            let buffer = aligned_copy(data);
+            let mut copy = *raw;
+            copy.DataPtr = buffer.as_ptr() as u64;
+            extended_data.push(copy);
+            extended_data_buffers.push(buffer);
+        }
+
+        let mut native = source.0;
+        // This points to the `CallbackData` of the trace, which this copy may outlive
+        native.UserContext = std::ptr::null_mut();
+        native.UserData = if user_data.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            user_data.as_ptr() as *mut _
+        };
+        native.ExtendedDataCount = extended_data.len() as u16;
+        native.ExtendedData = if extended_data.is_empty() {
+            std::ptr::null_mut()
+        } else {
+            extended_data.as_ptr() as *mut _
+        };
+
+        Self {
+            record: EventRecord(native),
+            user_data,
+            extended_data,
+            extended_data_buffers,
+        }
+    }
+
+    /// The borrowed view over this event
+    pub fn as_record(&self) -> &EventRecord {
+        &self.record
+    }
+}
+
+impl std::ops::Deref for OwnedEventRecord {
+    type Target = EventRecord;
+
+    fn deref(&self) -> &EventRecord {
+        &self.record
+    }
+}
+
+impl Clone for OwnedEventRecord {
+    fn clone(&self) -> Self {
+        Self::new(&self.record)
+    }
+}
+
+impl std::fmt::Debug for OwnedEventRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedEventRecord")
+            .field("provider_id", &self.provider_id())
+            .field("event_id", &self.event_id())
+            .field("user_data_len", &self.user_buffer().len())
+            .field("extended_data_count", &self.extended_data.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SyntheticEvent;
+
+    #[test]
+    fn test_owned_record_outlives_source() {
+        let provider = GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716);
+        let source = SyntheticEvent::new()
+            .with_provider(provider)
+            .with_opcode(3)
+            .with_user_data(&[1, 2, 3, 4, 5]);
+
+        let owned = source.record().to_owned();
+        drop(source);
+
+        let moved = std::thread::spawn(move || owned).join().unwrap();
+        assert_eq!(moved.provider_id(), provider);
+        assert_eq!(moved.opcode(), 3);
+        assert_eq!(moved.user_buffer(), &[1, 2, 3, 4, 5]);
+        assert!(moved.extended_data().is_empty());
+        assert_eq!(moved.clone().user_buffer(), &[1, 2, 3, 4, 5]);
+    }
+}
@@ -52,6 +52,27 @@ where
         self.addresses.as_ref()
     }
 
+    /// Resolve the addresses of this stack into symbols
+    ///
+    /// `symbolizer` receives the ID of the process the stack belongs to, and every address of the stack.
+    /// It must return one (optional) symbol per address, and is typically backed by a symbol engine (e.g. DbgHelp) that tracks the modules loaded by each process.
+    /// Missing symbols are reported as `None`.
+    pub fn resolve<F>(&self, process_id: u32, symbolizer: F) -> Vec<StackFrame>
+    where
+        Address: Into<u64>,
+        F: FnOnce(u32, &[u64]) -> Vec<Option<String>>,
+    {
+        let addresses: Vec<u64> = self.addresses.iter().map(|addr| (*addr).into()).collect();
+        let mut symbols = symbolizer(process_id, &addresses).into_iter();
+        addresses
+            .into_iter()
+            .map(|address| StackFrame {
+                address,
+                symbol: symbols.next().flatten(),
+            })
+            .collect()
+    }
+
     unsafe fn from_raw(
         match_id: u64,
         first_address: *const Address,
@@ -69,6 +90,22 @@ where
     }
 }
 
+impl From<StackTraceItem<u32>> for StackTraceItem<u64> {
+    fn from(item: StackTraceItem<u32>) -> Self {
+        StackTraceItem {
+            match_id: item.match_id,
+            addresses: item.addresses.iter().map(|addr| u64::from(*addr)).collect(),
+        }
+    }
+}
+
+/// A frame of a call stack, see [`StackTraceItem::resolve`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub address: u64,
+    pub symbol: Option<String>,
+}
+
 /// A wrapper over [`windows::Win32::System::Diagnostics::Etw::EVENT_HEADER_EXTENDED_DATA_ITEM`]
 #[repr(transparent)]
 pub struct EventHeaderExtendedDataItem(EVENT_HEADER_EXTENDED_DATA_ITEM);
@@ -103,6 +140,11 @@ pub enum ExtendedDataItem {
 }
 
 impl EventHeaderExtendedDataItem {
+    /// The wrapped `EVENT_HEADER_EXTENDED_DATA_ITEM`
+    pub(crate) fn as_raw(&self) -> &EVENT_HEADER_EXTENDED_DATA_ITEM {
+        &self.0
+    }
+
     /// Returns the `ExtType` of this extended data.
     ///
     /// See <https://docs.microsoft.com/en-us/windows/win32/api/relogger/ns-relogger-event_header_extended_data_item> for possible values
@@ -253,3 +295,29 @@ impl EventHeaderExtendedDataItem {
         ))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stack_trace_item() {
+        // MatchId, followed by the addresses
+        let raw: [u64; 4] = [7, 0x1000, 0x2000, 0x3000];
+        let item = unsafe {
+            StackTraceItem::<u64>::from_raw(raw[0], &raw[1], std::mem::size_of_val(&raw))
+        };
+        assert_eq!(item.match_id(), 7);
+        assert_eq!(item.addresses(), &[0x1000, 0x2000, 0x3000]);
+
+        let frames = item.resolve(42, |pid, addresses| {
+            assert_eq!(pid, 42);
+            assert_eq!(addresses.len(), 3);
+            vec![Some(String::from("ntdll!NtClose")), None]
+        });
+        assert_eq!(frames[0].symbol.as_deref(), Some("ntdll!NtClose"));
+        assert_eq!(frames[1].symbol, None);
+        assert_eq!(frames[2].address, 0x3000);
+        assert_eq!(frames[2].symbol, None);
+    }
+}
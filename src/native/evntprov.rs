@@ -0,0 +1,91 @@
+//! Native API - Event provider (evntprov) functions
+//!
+//! These are used to write events, rather than to consume them
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::evntrace::{EvntraceNativeError, EvntraceNativeResult};
+
+/// A handle returned by `EventRegister`
+pub type RegistrationHandle = u64;
+
+/// Register an event provider
+pub fn event_register(provider: &GUID) -> EvntraceNativeResult<RegistrationHandle> {
+    let mut handle = 0;
+    let status = unsafe {
+        // Safety: no enable callback is given, so there is no context pointer that should outlive this call
+        Etw::EventRegister(provider, None, None, &mut handle)
+    };
+    if status != 0 {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status as i32),
+        ));
+    }
+    Ok(handle)
+}
+
+/// Unregister an event provider
+///
+/// `handle` must not be used afterwards
+pub fn event_unregister(handle: RegistrationHandle) {
+    let status = unsafe { Etw::EventUnregister(handle) };
+    if status != 0 {
+        log::warn!("Unable to unregister event provider: error {}", status);
+    }
+}
+
+/// Whether any session listens to events of this level and keyword
+pub fn event_provider_enabled(handle: RegistrationHandle, level: u8, keyword: u64) -> bool {
+    unsafe { Etw::EventProviderEnabled(handle, level, keyword) }.as_bool()
+}
+
+/// Write a TraceLogging event
+///
+/// `provider_metadata` and `event_metadata` must be TraceLogging-encoded, and describe the `fields` that follow.
+pub fn event_write_tracelogging(
+    handle: RegistrationHandle,
+    descriptor: &Etw::EVENT_DESCRIPTOR,
+    provider_metadata: &[u8],
+    event_metadata: &[u8],
+    fields: &[&[u8]],
+) -> EvntraceNativeResult<()> {
+    fn data_descriptor(data: &[u8], ty: u32) -> Etw::EVENT_DATA_DESCRIPTOR {
+        Etw::EVENT_DATA_DESCRIPTOR {
+            Ptr: data.as_ptr() as u64,
+            Size: data.len() as u32,
+            Anonymous: Etw::EVENT_DATA_DESCRIPTOR_0 {
+                Anonymous: Etw::EVENT_DATA_DESCRIPTOR_0_0 {
+                    Type: ty as u8,
+                    Reserved1: 0,
+                    Reserved2: 0,
+                },
+            },
+        }
+    }
+
+    let mut descriptors = Vec::with_capacity(fields.len() + 2);
+    descriptors.push(data_descriptor(
+        provider_metadata,
+        Etw::EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA,
+    ));
+    descriptors.push(data_descriptor(
+        event_metadata,
+        Etw::EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA,
+    ));
+    descriptors.extend(
+        fields
+            .iter()
+            .map(|field| data_descriptor(field, Etw::EVENT_DATA_DESCRIPTOR_TYPE_NONE)),
+    );
+
+    let status = unsafe {
+        // Safety: every descriptor points to a slice that outlives this call
+        Etw::EventWriteTransfer(handle, descriptor, None, None, Some(&descriptors))
+    };
+    if status != 0 {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status as i32),
+        ));
+    }
+    Ok(())
+}
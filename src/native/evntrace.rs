@@ -2,30 +2,46 @@
 //!
 //! This module makes sure the calls are safe memory-wise, but does not attempt to ensure they are called in the right order.<br/>
 //! Thus, you should prefer using `UserTrace`s, `KernelTrace`s and `TraceBuilder`s, that will ensure these API are correctly used.
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ffi::c_void;
 use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
 
 use widestring::U16CStr;
 use windows::core::GUID;
 use windows::core::PCWSTR;
+use windows::Win32::Foundation::ERROR_ACCESS_DENIED;
 use windows::Win32::Foundation::ERROR_ALREADY_EXISTS;
+use windows::Win32::Foundation::ERROR_BUSY;
 use windows::Win32::Foundation::ERROR_CTX_CLOSE_PENDING;
+use windows::Win32::Foundation::ERROR_INSUFFICIENT_BUFFER;
+use windows::Win32::Foundation::ERROR_INVALID_PARAMETER;
+use windows::Win32::Foundation::ERROR_MORE_DATA;
 use windows::Win32::Foundation::ERROR_SUCCESS;
+use windows::Win32::Foundation::ERROR_WMI_GUID_NOT_FOUND;
+use windows::Win32::Foundation::ERROR_WMI_INSTANCE_NOT_FOUND;
 use windows::Win32::Foundation::FILETIME;
+use windows::Win32::Foundation::WIN32_ERROR;
 use windows::Win32::System::Diagnostics::Etw;
+use windows::Win32::System::Diagnostics::Etw::EVENT_CONTROL_CODE_DISABLE_PROVIDER;
 use windows::Win32::System::Diagnostics::Etw::EVENT_CONTROL_CODE_ENABLE_PROVIDER;
+use windows::Win32::System::Diagnostics::Etw::EVENT_FILTER_DESCRIPTOR;
 use windows::Win32::System::Diagnostics::Etw::TRACE_QUERY_INFO_CLASS;
 
 use super::etw_types::*;
 use crate::native::etw_types::event_record::EventRecord;
+use crate::native::time::TimestampConverter;
 use crate::provider::event_filter::EventFilterDescriptor;
+use crate::provider::protected::AccessDeniedReason;
 use crate::provider::Provider;
-use crate::trace::callback_data::CallbackData;
+use crate::trace::callback_data::{BufferStats, CallbackData};
 use crate::trace::{RealTimeTraceTrait, TraceProperties};
 
 pub type TraceHandle = Etw::PROCESSTRACE_HANDLE;
@@ -33,6 +49,7 @@ pub type ControlHandle = Etw::CONTROLTRACE_HANDLE;
 
 /// Evntrace native module errors
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum EvntraceNativeError {
     /// Represents an Invalid Handle Error
     InvalidHandle,
@@ -40,49 +57,146 @@ pub enum EvntraceNativeError {
     AlreadyExist,
     /// Represents an standard IO Error
     IoError(std::io::Error),
+    /// A native call did not return within the configured timeout (see [`TraceBuilder::native_call_timeout`](crate::trace::TraceBuilder::native_call_timeout))
+    Timeout {
+        /// The native call that timed out
+        call: String,
+        timeout: Duration,
+    },
+    /// The current process is not allowed to enable this provider (see [`provider::protected`](crate::provider::protected))
+    ProviderAccessDenied {
+        guid: GUID,
+        reason: AccessDeniedReason,
+    },
 }
 
 pub(crate) type EvntraceNativeResult<T> = Result<T, EvntraceNativeError>;
 
+/// Errors that are likely to go away by themselves, e.g. right after a previous session has been torn down
+const TRANSIENT_ERRORS: [WIN32_ERROR; 3] = [
+    ERROR_WMI_INSTANCE_NOT_FOUND,
+    ERROR_CTX_CLOSE_PENDING,
+    ERROR_BUSY,
+];
+
+impl EvntraceNativeError {
+    /// Whether this error may go away by itself, so that retrying the call makes sense
+    ///
+    /// This is typically the case when a session name has not been released yet by a previous session that is being torn down.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::AlreadyExist => true,
+            Self::IoError(e) => match e.raw_os_error() {
+                None => false,
+                // Depending on the API, errors are reported as plain Win32 codes or as HRESULTs
+                Some(code) => TRANSIENT_ERRORS
+                    .iter()
+                    .any(|err| code == err.0 as i32 || code == err.to_hresult().0),
+            },
+            _ => false,
+        }
+    }
+}
+
+/// The contexts (i.e. `CallbackData`) of the traces that are currently open
+///
+/// The context pointer given to ETW points to a `Box<Arc<CallbackData>>` that is owned by this registry, so that it is never freed while ETW may still use it.<br/>
 /// When a trace is closing, it is possible that every past events have not been processed yet.
 /// These events will still be fed to the callback, **after** the trace has been closed
-/// (see `ERROR_CTX_CLOSE_PENDING` in https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-closetrace#remarks)
-/// Also, there is no way to tell which callback invocation is the last one.
-///
-/// But, we would like to free memory used by the callbacks when we're done!
-/// Since that is not possible, let's discard every callback run after we've called `CloseTrace`.
-/// That's the purpose of this set.
+/// (see `ERROR_CTX_CLOSE_PENDING` in https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-closetrace#remarks).
+/// Hence, once `ProcessTrace` has been called on a trace, its context is kept until `ProcessTrace` has returned, which means the last buffered event has been delivered,
+/// even in case `CloseTrace` has failed.
+/// This way, no trailing event is dropped, and the `CallbackData` is freed as soon as it can no longer be used.
 ///
-/// TODO: it _might_ be possible to know whether we've processed the last buffered event, as
-///       ControlTraceW(EVENT_TRACE_CONTROL_QUERY) _might_ tell us if the buffers are empty or not.
-///       In case the trace is in ERROR_CTX_CLOSE_PENDING state, we could call this after every
-///       callback so that we know when to actually free memory used by the (now useless) callback.
-///       Maybe also setting the BufferCallback in EVENT_TRACE_LOGFILEW may help us.
-///       That's <https://github.com/n4r1b/ferrisetw/issues/62>
-static UNIQUE_VALID_CONTEXTS: UniqueValidContexts = UniqueValidContexts::new();
-struct UniqueValidContexts(Lazy<Mutex<HashSet<u64>>>);
-enum ContextError {
-    AlreadyExist,
+/// See <https://github.com/n4r1b/ferrisetw/issues/62>
+static OPEN_CONTEXTS: OpenContexts = OpenContexts::new();
+struct OpenContexts(Lazy<Mutex<HashMap<u64, OpenContext>>>);
+
+#[allow(clippy::redundant_allocation)] // The Box is what makes the context pointer stable
+struct OpenContext {
+    context: Box<Arc<CallbackData>>,
+    trace_handle: u64,
+    /// `CloseTrace` has been called
+    closed: bool,
+    /// `ProcessTrace` has been called
+    processing_started: bool,
+    /// `ProcessTrace` has returned
+    processing_ended: bool,
 }
 
-impl UniqueValidContexts {
+impl OpenContexts {
     pub const fn new() -> Self {
-        Self(Lazy::new(|| Mutex::new(HashSet::new())))
+        Self(Lazy::new(|| Mutex::new(HashMap::new())))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, OpenContext>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether this `CallbackData` is already used by an open trace
+    fn contains(&self, callback_data: &Arc<CallbackData>) -> bool {
+        self.lock()
+            .values()
+            .any(|open| Arc::ptr_eq(&open.context, callback_data))
     }
-    /// Insert if it did not exist previously
-    fn insert(&self, ctx_ptr: *const c_void) -> Result<(), ContextError> {
-        match self.0.lock().unwrap().insert(ctx_ptr as u64) {
-            true => Ok(()),
-            false => Err(ContextError::AlreadyExist),
+
+    #[allow(clippy::redundant_allocation)]
+    fn insert(&self, context: Box<Arc<CallbackData>>, trace_handle: TraceHandle) {
+        let ctx_ptr = context.as_ref() as *const Arc<CallbackData> as u64;
+        self.lock().insert(
+            ctx_ptr,
+            OpenContext {
+                context,
+                trace_handle: trace_handle.Value,
+                closed: false,
+                processing_started: false,
+                processing_ended: false,
+            },
+        );
+    }
+
+    fn get(&self, ctx_ptr: *const c_void) -> Option<Arc<CallbackData>> {
+        self.lock()
+            .get(&(ctx_ptr as u64))
+            .map(|open| Arc::clone(&open.context))
+    }
+
+    fn get_by_handle(&self, trace_handle: TraceHandle) -> Option<Arc<CallbackData>> {
+        self.lock()
+            .values()
+            .find(|open| open.trace_handle == trace_handle.Value)
+            .map(|open| Arc::clone(&open.context))
+    }
+
+    /// Update the context of a trace, and drop it in case ETW will no longer use it
+    fn update<F: FnOnce(&mut OpenContext)>(&self, trace_handle: TraceHandle, f: F) {
+        let mut contexts = self.lock();
+        let ctx_ptr = contexts
+            .iter()
+            .find(|(_, open)| open.trace_handle == trace_handle.Value)
+            .map(|(ctx_ptr, _)| *ctx_ptr);
+        if let Some(ctx_ptr) = ctx_ptr {
+            let open = contexts.get_mut(&ctx_ptr).unwrap(); // we've just found it
+            f(open);
+            if open.closed && (open.processing_ended || !open.processing_started) {
+                contexts.remove(&ctx_ptr);
+            }
         }
     }
 
-    fn remove(&self, ctx_ptr: *const c_void) {
-        self.0.lock().unwrap().remove(&(ctx_ptr as u64));
+    /// The trace has been closed. Its context is dropped now, unless `ProcessTrace` is still running
+    fn closed(&self, trace_handle: TraceHandle) {
+        self.update(trace_handle, |open| open.closed = true);
+    }
+
+    /// `ProcessTrace` is about to be called. From now on, the context is only dropped once it has returned
+    fn processing_started(&self, trace_handle: TraceHandle) {
+        self.update(trace_handle, |open| open.processing_started = true);
     }
 
-    pub fn is_valid(&self, ctx_ptr: *const c_void) -> bool {
-        self.0.lock().unwrap().contains(&(ctx_ptr as u64))
+    /// `ProcessTrace` has returned. The context is dropped now in case the trace has been closed already
+    fn processing_ended(&self, trace_handle: TraceHandle) {
+        self.update(trace_handle, |open| open.processing_ended = true);
     }
 }
 
@@ -95,37 +209,160 @@ extern "system" fn trace_callback_thunk(p_record: *mut Etw::EVENT_RECORD) {
         };
 
         if let Some(event_record) = record_from_ptr {
-            let p_user_context = event_record.user_context();
-            if !UNIQUE_VALID_CONTEXTS.is_valid(p_user_context) {
-                return;
-            }
-            let p_callback_data = p_user_context.cast::<Arc<CallbackData>>();
-            let callback_data = unsafe {
-                // Safety:
-                //  * the API of this create guarantees this points to a `CallbackData` already allocated and created
-                //  * we've just checked using UNIQUE_VALID_CONTEXTS that this `CallbackData` has not been dropped
-                //  * the API of this crate guarantees this `CallbackData` is not mutated from another thread during the trace:
-                //      * we're the only one to change CallbackData::events_handled (and that's an atomic, so it's fine)
-                //      * the list of Providers is a constant (may change in the future with #54)
-                //      * the schema_locator only has interior mutability
-                p_callback_data.as_ref()
-            };
-            if let Some(callback_data) = callback_data {
-                // The UserContext is owned by the `Trace` object. When it is dropped, so will the UserContext.
-                // We clone it now, so that the original Arc can be safely dropped at all times, but the callback data (including the closure captured context) will still be alive until the callback ends.
-                let cloned_arc = Arc::clone(callback_data);
-                cloned_arc.on_event(event_record);
+            // The context may be dropped from another thread at any time (e.g. once ProcessTrace has returned).
+            // We clone the Arc, so that the callback data (including the closure captured context) is still alive until the callback ends.
+            // Contexts that are not found belong to traces that have been closed. That is not supposed to happen, since they are kept until ProcessTrace returns.
+            if let Some(callback_data) = OPEN_CONTEXTS.get(event_record.user_context()) {
+                callback_data.on_event(event_record);
             }
         }
     })) {
         Ok(_) => {}
         Err(e) => {
-            log::error!("UNIMPLEMENTED PANIC: {e:?}");
+            // Panics of the user callbacks are handled according to the `PanicPolicy` of the trace. Anything reaching here is a bug in this crate.
+            log::error!("Unexpected panic while dispatching an event: {e:?}");
             std::process::exit(1);
         }
     }
 }
 
+/// Whether a trace that delivers raw timestamps has been opened
+///
+/// Until then, [`timestamp_converter`] does not need to look up the trace of the record (and to lock `OPEN_CONTEXTS`).
+static RAW_TIMESTAMPS_USED: AtomicBool = AtomicBool::new(false);
+
+/// Converts the raw timestamps of the trace this record belongs to, in case it delivers some (see [`crate::trace::TraceBuilder::raw_timestamps`])
+pub(crate) fn timestamp_converter(record: &EventRecord) -> Option<TimestampConverter> {
+    if !RAW_TIMESTAMPS_USED.load(Ordering::Relaxed) || record.user_context().is_null() {
+        return None;
+    }
+    OPEN_CONTEXTS
+        .get(record.user_context())
+        .and_then(|callback_data| callback_data.timestamp_converter())
+}
+
+/// This will be called by the ETW framework after every buffer has been delivered
+///
+/// Returning `FALSE` makes `ProcessTrace` return. This is how [`crate::trace::PanicPolicy::StopTrace`] and [`crate::trace::TraceBuilder::set_buffer_callback`] are implemented.
+extern "system" fn buffer_callback_thunk(p_log_file: *mut Etw::EVENT_TRACE_LOGFILEW) -> u32 {
+    std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let log_file = match unsafe { p_log_file.as_ref() } {
+            None => return true,
+            Some(log_file) => log_file,
+        };
+        match OPEN_CONTEXTS.get(log_file.Context as *const c_void) {
+            Some(callback_data) => {
+                let stats = BufferStats {
+                    buffers_read: log_file.BuffersRead,
+                    buffer_size: log_file.BufferSize,
+                    filled: log_file.Filled,
+                    events_lost: unsafe {
+                        // Safety: every member of this union is plain old data, reading any of them is fine
+                        log_file.LogfileHeader.Anonymous2.Anonymous.EventsLost
+                    },
+                    buffers_lost: log_file.LogfileHeader.BuffersLost,
+                    events_handled: callback_data.events_handled(),
+                };
+                callback_data.on_buffer(&stats)
+            }
+            None => true,
+        }
+    }))
+    // Panics of the buffer callback are handled by the panic policy of the trace. Nothing else is expected to panic, but unwinding into ETW must be avoided anyway
+    .unwrap_or_else(|_| {
+        log::error!("Unexpected panic after a buffer has been delivered");
+        true
+    }) as u32
+}
+
+/// Run a potentially blocking native call, giving up after `timeout` (if any)
+///
+/// Some calls (e.g. `EnableTraceEx2`, which synchronously invokes the enable callback of the provider) may hang for a long time.
+/// When a timeout is set, the call is run on a helper thread, which is detached (and keeps running) in case it times out.
+pub(crate) fn run_with_timeout<R, F>(
+    call: String,
+    timeout: Option<Duration>,
+    f: F,
+) -> EvntraceNativeResult<R>
+where
+    F: FnOnce() -> EvntraceNativeResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    run_with_timeout_or_else(call, timeout, f, drop)
+}
+
+/// Same as [`run_with_timeout`], but `on_late` is given the result of the call in case it returns after we have given up on it
+///
+/// This is meant to undo calls with side effects, e.g. to stop a session that `StartTraceW` has eventually started.<br/>
+/// `on_late` runs on the helper thread.
+pub(crate) fn run_with_timeout_or_else<R, F, L>(
+    call: String,
+    timeout: Option<Duration>,
+    f: F,
+    on_late: L,
+) -> EvntraceNativeResult<R>
+where
+    F: FnOnce() -> EvntraceNativeResult<R> + Send + 'static,
+    L: FnOnce(EvntraceNativeResult<R>) + Send + 'static,
+    R: Send + 'static,
+{
+    let start = Instant::now();
+
+    let result = match timeout {
+        None => f(),
+        Some(timeout) => {
+            let (tx, rx) = mpsc::channel();
+            // Whether we have given up on the call. This is checked and set under the lock, so that a result is either received, or given to `on_late`
+            let given_up = Arc::new(Mutex::new(false));
+            let thread_given_up = Arc::clone(&given_up);
+            let thread = std::thread::spawn(move || {
+                let result = f();
+                let given_up = thread_given_up
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if *given_up {
+                    drop(given_up);
+                    on_late(result);
+                } else {
+                    // The receiver is still waiting for this result
+                    let _ = tx.send(result);
+                }
+            });
+
+            let received = match rx.recv_timeout(timeout) {
+                Ok(result) => Ok(result),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    let mut given_up = given_up.lock().unwrap_or_else(PoisonError::into_inner);
+                    // The call may have returned in the meantime
+                    match rx.try_recv() {
+                        Ok(result) => Ok(result),
+                        Err(mpsc::TryRecvError::Empty) => {
+                            *given_up = true;
+                            log::warn!("{call} did not return after {timeout:?}. Giving up, the call is left running on a detached thread");
+                            return Err(EvntraceNativeError::Timeout { call, timeout });
+                        }
+                        Err(mpsc::TryRecvError::Disconnected) => Err(()),
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(()),
+            };
+            match received {
+                Ok(result) => result,
+                Err(()) => {
+                    // The helper thread has panicked without sending anything. Let's propagate the panic
+                    match thread.join() {
+                        Err(panic) => std::panic::resume_unwind(panic),
+                        Ok(()) => unreachable!("the helper thread always sends its result"),
+                    }
+                }
+            }
+        }
+    };
+
+    log::debug!("{call} returned after {:?}", start.elapsed());
+    result
+}
+
 fn filter_invalid_trace_handles(h: TraceHandle) -> Option<TraceHandle> {
     // See https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-opentracew#return-value
     // We're conservative and we always filter out u32::MAX, although it could be valid on 64-bit setups.
@@ -150,18 +387,77 @@ fn filter_invalid_control_handle(h: ControlHandle) -> Option<ControlHandle> {
 /// Create a new session.
 ///
 /// This builds an `EventTraceProperties`, calls `StartTraceW` and returns the built `EventTraceProperties` as well as the trace ControlHandle
+///
+/// The session is first started using `EVENT_TRACE_PROPERTIES_V2`. In case the OS does not support it, this falls back to `EVENT_TRACE_PROPERTIES` (and `session_filters` are ignored).
 pub(crate) fn start_trace<T>(
     trace_name: &U16CStr,
     etl_dump_file: Option<(&U16CStr, DumpFileLoggingMode, Option<u32>)>,
     trace_properties: &TraceProperties,
+    settings: &SessionSettings,
     enable_flags: Etw::EVENT_TRACE_FLAG,
+    session_filters: &[EventFilterDescriptor],
 ) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)>
 where
     T: RealTimeTraceTrait,
 {
     let mut properties =
         EventTraceProperties::new::<T>(trace_name, etl_dump_file, trace_properties, enable_flags);
+    properties.apply_settings(settings);
+
+    let mut filter_descs: Vec<EVENT_FILTER_DESCRIPTOR> = session_filters
+        .iter()
+        .map(|efd| efd.as_event_filter_descriptor())
+        .collect();
+    properties.use_v2(&mut filter_descs);
 
+    let mut status = start_trace_with_properties(&mut properties);
+    let v2_rejected = match &status {
+        Err(EvntraceNativeError::IoError(e)) => {
+            e.raw_os_error() == Some(ERROR_INVALID_PARAMETER.to_hresult().0)
+        }
+        _ => false,
+    };
+    if properties.is_v2() && v2_rejected {
+        // Older systems do not know about WNODE_FLAG_VERSIONED_PROPERTIES
+        if !session_filters.is_empty() {
+            log::warn!(
+                "EVENT_TRACE_PROPERTIES_V2 is not supported, session-level filters will be ignored"
+            );
+        }
+        properties.use_v1();
+        status = start_trace_with_properties(&mut properties);
+    }
+    // `filter_descs` is about to be dropped
+    properties.clear_session_filters();
+
+    let control_handle = status?;
+    Ok((properties, control_handle))
+}
+
+/// Start a private logger session (`EVENT_TRACE_PRIVATE_LOGGER_MODE`), whose `Wnode.Guid` is `session_guid`
+///
+/// Private sessions do not support `EVENT_TRACE_PROPERTIES_V2`, nor real-time delivery.
+pub(crate) fn start_private_trace(
+    trace_name: &U16CStr,
+    etl_dump_file: (&U16CStr, DumpFileLoggingMode, Option<u32>),
+    trace_properties: &TraceProperties,
+    session_guid: GUID,
+) -> EvntraceNativeResult<(EventTraceProperties, ControlHandle)> {
+    let mut properties = EventTraceProperties::new::<crate::UserTrace>(
+        trace_name,
+        Some(etl_dump_file),
+        trace_properties,
+        Etw::EVENT_TRACE_FLAG::default(),
+    );
+    properties.use_v1();
+    properties.set_session_guid(session_guid);
+    let control_handle = start_trace_with_properties(&mut properties)?;
+    Ok((properties, control_handle))
+}
+
+fn start_trace_with_properties(
+    properties: &mut EventTraceProperties,
+) -> EvntraceNativeResult<ControlHandle> {
     let mut control_handle = ControlHandle::default();
     let status = unsafe {
         // Safety:
@@ -189,29 +485,39 @@ where
         }
     }
 
-    match filter_invalid_control_handle(control_handle) {
-        None => Err(EvntraceNativeError::InvalidHandle),
-        Some(handle) => Ok((properties, handle)),
-    }
+    filter_invalid_control_handle(control_handle).ok_or(EvntraceNativeError::InvalidHandle)
 }
 
 /// Subscribe to a started trace
 ///
 /// Microsoft calls this "opening" the trace (and this calls `OpenTraceW`)
-#[allow(clippy::borrowed_box)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer
+///
+/// This also returns the header of the trace, as filled by `OpenTraceW`.
+#[allow(clippy::borrowed_box)]
+// Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer
 pub(crate) fn open_trace(
     subscription_source: SubscriptionSource,
     callback_data: &Box<Arc<CallbackData>>,
-) -> EvntraceNativeResult<TraceHandle> {
-    let mut log_file =
-        EventTraceLogfile::create(callback_data, subscription_source, trace_callback_thunk);
-
-    if let Err(ContextError::AlreadyExist) = UNIQUE_VALID_CONTEXTS.insert(log_file.context_ptr()) {
+) -> EvntraceNativeResult<(TraceHandle, LogFileHeader)> {
+    if OPEN_CONTEXTS.contains(callback_data) {
         // That's probably possible to get multiple handles to the same trace, by opening them multiple times.
         // But that's left as a future TODO. Making things right and safe is difficult enough with a single opening of the trace already.
         return Err(EvntraceNativeError::AlreadyExist);
     }
 
+    if callback_data.raw_timestamps() {
+        RAW_TIMESTAMPS_USED.store(true, Ordering::Relaxed);
+    }
+
+    // This context is owned by OPEN_CONTEXTS, see its documentation
+    let context = Box::new(Arc::clone(callback_data.as_ref()));
+    let mut log_file = EventTraceLogfile::create(
+        &context,
+        subscription_source,
+        trace_callback_thunk,
+        buffer_callback_thunk,
+    );
+
     let trace_handle = unsafe {
         // This function modifies the data pointed to by log_file.
         // This is fine because there is currently no other ref `self` (the current function takes a `&mut self`, and `self` is not used anywhere else in the current function)
@@ -224,7 +530,11 @@ pub(crate) fn open_trace(
     if filter_invalid_trace_handles(trace_handle).is_none() {
         Err(EvntraceNativeError::IoError(std::io::Error::last_os_error()))
     } else {
-        Ok(trace_handle)
+        // No event can be delivered before ProcessTrace is called with this handle, it is fine to register the context only now
+        let header = log_file.header();
+        drop(log_file);
+        OPEN_CONTEXTS.insert(context, trace_handle);
+        Ok((trace_handle, header))
     }
 }
 
@@ -236,10 +546,17 @@ pub(crate) fn enable_provider(
     match filter_invalid_control_handle(control_handle) {
         None => Err(EvntraceNativeError::InvalidHandle),
         Some(handle) => {
+            crate::provider::lint::check(provider);
+
             let owned_event_filter_descriptors: Vec<EventFilterDescriptor> = provider
                 .filters()
                 .iter()
-                .filter_map(|filter| filter.to_event_filter_descriptor().ok()) // Silently ignoring invalid filters (basically, empty ones)
+                .filter_map(|filter| {
+                    filter
+                        .to_event_filter_descriptor_for(&provider.guid())
+                        .map_err(|err| log::warn!("Ignoring invalid filter {:?}: {}", filter, err))
+                        .ok()
+                })
                 .collect();
 
             let parameters = EnableTraceParameters::create(
@@ -262,6 +579,43 @@ pub(crate) fn enable_provider(
             }
             .ok();
 
+            res.map_err(|err| {
+                if err.code() == ERROR_ACCESS_DENIED.to_hresult() {
+                    let guid = provider.guid();
+                    EvntraceNativeError::ProviderAccessDenied {
+                        guid,
+                        reason: AccessDeniedReason::diagnose(guid),
+                    }
+                } else {
+                    EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
+                }
+            })
+        }
+    }
+}
+
+/// Detach a provider from a trace
+pub(crate) fn disable_provider(
+    control_handle: ControlHandle,
+    provider_guid: GUID,
+) -> EvntraceNativeResult<()> {
+    match filter_invalid_control_handle(control_handle) {
+        None => Err(EvntraceNativeError::InvalidHandle),
+        Some(handle) => {
+            let res = unsafe {
+                Etw::EnableTraceEx2(
+                    handle,
+                    &provider_guid as *const GUID,
+                    EVENT_CONTROL_CODE_DISABLE_PROVIDER.0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    None,
+                )
+            }
+            .ok();
+
             res.map_err(|err| {
                 EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
             })
@@ -269,21 +623,104 @@ pub(crate) fn enable_provider(
     }
 }
 
+/// Write a classic (MOF) event into a session, using `TraceEvent`
+///
+/// The event is written to every consumer of the session, including its ETL dump file (if any).
+pub(crate) fn trace_event(
+    control_handle: ControlHandle,
+    guid: GUID,
+    event_type: u8,
+    payload: &[u8],
+) -> EvntraceNativeResult<()> {
+    let handle =
+        filter_invalid_control_handle(control_handle).ok_or(EvntraceNativeError::InvalidHandle)?;
+
+    let header_size = std::mem::size_of::<Etw::EVENT_TRACE_HEADER>();
+    let total_size = u16::try_from(header_size + payload.len()).map_err(|_| {
+        EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(
+            ERROR_INVALID_PARAMETER.0 as i32,
+        ))
+    })?;
+
+    // The payload immediately follows the header. A buffer of u64 is suitably aligned for an EVENT_TRACE_HEADER
+    let mut buffer = vec![0u64; (total_size as usize).div_ceil(std::mem::size_of::<u64>())];
+    let mut header = Etw::EVENT_TRACE_HEADER {
+        Size: total_size,
+        ..Default::default()
+    };
+    header.Anonymous2.Class.Type = event_type;
+    header.Anonymous3.Guid = guid;
+    header.Anonymous4.Anonymous2.Flags = Etw::WNODE_FLAG_TRACED_GUID;
+    unsafe {
+        // Safety: the buffer is large enough and correctly aligned for the header, followed by the payload
+        let p_header = buffer.as_mut_ptr().cast::<Etw::EVENT_TRACE_HEADER>();
+        p_header.write(header);
+        std::ptr::copy_nonoverlapping(
+            payload.as_ptr(),
+            p_header.cast::<u8>().add(header_size),
+            payload.len(),
+        );
+    }
+
+    let status = unsafe {
+        // Safety: the header is valid, and `Size` correctly describes the whole buffer
+        Etw::TraceEvent(handle.Value, buffer.as_ptr().cast())
+    };
+    if status != ERROR_SUCCESS {
+        return Err(EvntraceNativeError::IoError(
+            std::io::Error::from_raw_os_error(status.0 as i32),
+        ));
+    }
+    Ok(())
+}
+
+/// The callback data of a trace that has been opened (and is not fully closed yet)
+pub(crate) fn callback_data(trace_handle: TraceHandle) -> Option<Arc<CallbackData>> {
+    OPEN_CONTEXTS.get_by_handle(trace_handle)
+}
+
 /// Start processing a trace (this call is blocking until the trace is stopped)
 ///
 /// You probably want to spawn a thread that will block on this call.
 pub(crate) fn process_trace(trace_handle: TraceHandle) -> EvntraceNativeResult<()> {
-    if filter_invalid_trace_handles(trace_handle).is_none() {
+    process_traces(&[trace_handle])
+}
+
+/// Process several traces with a single call to `ProcessTrace`, that delivers their events in chronological order
+///
+/// Windows supports up to 64 handles, that must either be all real-time traces, or all file traces.
+pub(crate) fn process_traces(trace_handles: &[TraceHandle]) -> EvntraceNativeResult<()> {
+    if trace_handles.is_empty()
+        || trace_handles
+            .iter()
+            .any(|handle| filter_invalid_trace_handles(*handle).is_none())
+    {
         Err(EvntraceNativeError::InvalidHandle)
     } else {
+        // Unless a time range has been set, we want to start processing events as soon as January 1601.
+        // * for ETL file traces, this is fine, this means "process everything from the file"
+        // * for real-time traces, this means we might process a few events already waiting in the buffers when the processing is starting. This is fine, I suppose.
+        let (start, end) = time_window(trace_handles);
+        let start = filetime_from_quad(start);
+        let end = end.map(filetime_from_quad);
+        for trace_handle in trace_handles {
+            OPEN_CONTEXTS.processing_started(*trace_handle);
+        }
         let result = unsafe {
-            // We want to start processing events as soon as January 1601.
-            // * for ETL file traces, this is fine, this means "process everything from the file"
-            // * for real-time traces, this means we might process a few events already waiting in the buffers when the processing is starting. This is fine, I suppose.
-            let mut start = FILETIME::default();
-            Etw::ProcessTrace(&[trace_handle], Some(&mut start as *mut FILETIME), None)
+            Etw::ProcessTrace(
+                trace_handles,
+                Some(&start as *const FILETIME),
+                end.as_ref().map(|end| end as *const FILETIME),
+            )
         }
         .ok();
+        // Every buffered event has been delivered
+        for trace_handle in trace_handles {
+            if let Some(callback_data) = OPEN_CONTEXTS.get_by_handle(*trace_handle) {
+                callback_data.flush_pending();
+            }
+            OPEN_CONTEXTS.processing_ended(*trace_handle);
+        }
 
         result.map_err(|err| {
             EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
@@ -291,6 +728,39 @@ pub(crate) fn process_trace(trace_handle: TraceHandle) -> EvntraceNativeResult<(
     }
 }
 
+/// The time window to process, i.e. the union of the time ranges of these traces (see [`crate::trace::FileTraceBuilder::between`])
+///
+/// Timestamps are expressed as `FILETIME` quads.
+fn time_window(trace_handles: &[TraceHandle]) -> (i64, Option<i64>) {
+    let ranges: Vec<Option<(i64, i64)>> = trace_handles
+        .iter()
+        .map(|handle| {
+            OPEN_CONTEXTS
+                .get_by_handle(*handle)
+                .and_then(|callback_data| callback_data.time_range())
+        })
+        .collect();
+    let start = ranges
+        .iter()
+        .map(|range| range.map_or(0, |(start, _)| start))
+        .min()
+        .unwrap_or(0);
+    // A single trace without a range means the window is not bounded
+    let end = ranges
+        .iter()
+        .map(|range| range.map(|(_, end)| end))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|ends| ends.into_iter().max());
+    (start, end)
+}
+
+fn filetime_from_quad(quad: i64) -> FILETIME {
+    FILETIME {
+        dwLowDateTime: (quad & 0xffffffff) as u32,
+        dwHighDateTime: (quad >> 32) as u32,
+    }
+}
+
 /// Call `ControlTraceW` on the trace
 ///
 /// # Notes
@@ -326,6 +796,19 @@ pub(crate) fn control_trace(
     }
 }
 
+/// Similar to [`control_trace`], but on a copy of the properties, that is returned once updated
+///
+/// This leaves the properties of the session untouched, so that they can still be used to stop it.
+pub(crate) fn control_trace_copy(
+    properties: &EventTraceProperties,
+    control_handle: ControlHandle,
+    control_code: Etw::EVENT_TRACE_CONTROL,
+) -> EvntraceNativeResult<EventTraceProperties> {
+    let mut copy = *properties;
+    control_trace(&mut copy, control_handle, control_code)?;
+    Ok(copy)
+}
+
 /// Similar to [`control_trace`], but using a trace name instead of a handle
 pub(crate) fn control_trace_by_name(
     properties: &mut EventTraceProperties,
@@ -349,36 +832,130 @@ pub(crate) fn control_trace_by_name(
     })
 }
 
+/// Query the properties of every running session (see [QueryAllTracesW](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-queryalltracesw))
+pub(crate) fn query_all_traces() -> EvntraceNativeResult<Vec<EventTraceProperties>> {
+    // This is the historical limit of concurrent sessions. Recent Windows versions may have more, in which case we'll retry with a larger array.
+    let mut capacity = 64;
+    loop {
+        let mut properties = vec![EventTraceProperties::empty(); capacity];
+        let mut pointers: Vec<*mut Etw::EVENT_TRACE_PROPERTIES> = properties
+            .iter_mut()
+            .map(|p| unsafe {
+                // Safety: `properties` outlives the call to QueryAllTracesW, and is not accessed until it returns
+                p.as_mut_ptr()
+            })
+            .collect();
+        let mut count = 0;
+        let status = unsafe { Etw::QueryAllTracesW(&mut pointers, &mut count) };
+
+        if status == ERROR_MORE_DATA && count as usize > capacity {
+            capacity = count as usize;
+            continue;
+        }
+        if status != ERROR_SUCCESS {
+            return Err(EvntraceNativeError::IoError(
+                std::io::Error::from_raw_os_error(status.0 as i32),
+            ));
+        }
+        properties.truncate(count as usize);
+        return Ok(properties);
+    }
+}
+
 /// Close the trace
 ///
 /// It is suggested to stop the trace immediately after `close`ing it (that's what it done in the `impl Drop`), because I'm not sure how sensible it is to call other methods (apart from `stop`) afterwards
 ///
-/// In case ETW reports there are still events in the queue that are still to trigger callbacks, this returns Ok(true).<br/>
+/// In case ETW reports there are still events in the queue that are still to trigger callbacks, this returns Ok(true).
+/// These events are still delivered to the callbacks, until `ProcessTrace` returns.<br/>
 /// If no further event callback will be invoked, this returns Ok(false)<br/>
 /// On error, this returns an `Err`
-#[allow(clippy::borrowed_box)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer
-pub(crate) fn close_trace(
-    trace_handle: TraceHandle,
-    callback_data: &Box<Arc<CallbackData>>,
-) -> EvntraceNativeResult<bool> {
+pub(crate) fn close_trace(trace_handle: TraceHandle) -> EvntraceNativeResult<bool> {
     match filter_invalid_trace_handles(trace_handle) {
         None => Err(EvntraceNativeError::InvalidHandle),
         Some(handle) => {
-            // By contruction, only one Provider used this context in its callback. It is safe to remove it, it won't be used by anyone else.
-            UNIQUE_VALID_CONTEXTS
-                .remove(callback_data.as_ref() as *const Arc<CallbackData> as *const c_void);
-
             let status = unsafe { Etw::CloseTrace(handle) }.ok();
 
             match status {
-                Ok(()) => Ok(false),
-                Err(err) if err.code() == ERROR_CTX_CLOSE_PENDING.to_hresult() => Ok(true),
-                Err(err) => Err(EvntraceNativeError::IoError(
-                    std::io::Error::from_raw_os_error(err.code().0),
-                )),
+                Ok(()) => {
+                    OPEN_CONTEXTS.closed(trace_handle);
+                    Ok(false)
+                }
+                Err(err) if err.code() == ERROR_CTX_CLOSE_PENDING.to_hresult() => {
+                    // Buffered events are still being delivered. The context is dropped once they all are
+                    OPEN_CONTEXTS.closed(trace_handle);
+                    Ok(true)
+                }
+                Err(err) => {
+                    // `ProcessTrace` may still be delivering events: the context is left to `process_traces` to drop
+                    Err(EvntraceNativeError::IoError(
+                        std::io::Error::from_raw_os_error(err.code().0),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// The IDs of the processes that currently register a provider
+///
+/// See [TraceGuidQueryInfo](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ne-evntrace-trace_query_info_class)
+pub(crate) fn provider_instances(guid: &GUID) -> EvntraceNativeResult<Vec<u32>> {
+    // A buffer of u32 is suitably aligned for TRACE_GUID_INFO and TRACE_PROVIDER_INSTANCE_INFO
+    let mut buffer: Vec<u32> = Vec::new();
+    loop {
+        let mut returned = 0u32;
+        let status = unsafe {
+            // Safety: the input is a GUID, and the output buffer is `buffer.len()` u32s long
+            Etw::EnumerateTraceGuidsEx(
+                TRACE_QUERY_INFO_CLASS(TraceInformation::TraceGuidQueryInfo as i32),
+                Some((guid as *const GUID).cast()),
+                std::mem::size_of::<GUID>() as u32,
+                Some(buffer.as_mut_ptr().cast()),
+                std::mem::size_of_val(buffer.as_slice()) as u32,
+                &mut returned,
+            )
+        };
+        match status {
+            ERROR_SUCCESS => break,
+            ERROR_INSUFFICIENT_BUFFER => {
+                buffer = vec![0; (returned as usize).div_ceil(std::mem::size_of::<u32>())];
+            }
+            // The provider is not registered at all
+            ERROR_WMI_GUID_NOT_FOUND => return Ok(Vec::new()),
+            other => {
+                return Err(EvntraceNativeError::IoError(
+                    std::io::Error::from_raw_os_error(other.0 as i32),
+                ))
             }
         }
     }
+
+    let bytes: Vec<u8> = buffer
+        .iter()
+        .flat_map(|value| value.to_ne_bytes())
+        .collect();
+    let read_u32 = |offset: usize| {
+        bytes
+            .get(offset..offset + 4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let instance_count = read_u32(0).unwrap_or(0);
+    let mut pids = Vec::with_capacity(instance_count as usize);
+    // TRACE_GUID_INFO is followed by TRACE_PROVIDER_INSTANCE_INFOs, each of them followed by its enabling sessions
+    let mut offset = std::mem::size_of::<Etw::TRACE_GUID_INFO>();
+    for _ in 0..instance_count {
+        let (next_offset, pid) = match (read_u32(offset), read_u32(offset + 8)) {
+            (Some(next_offset), Some(pid)) => (next_offset, pid),
+            _ => break,
+        };
+        pids.push(pid);
+        if next_offset == 0 {
+            break;
+        }
+        offset += next_offset as usize;
+    }
+    Ok(pids)
 }
 
 /// Queries the system for system-wide ETW information (that does not require an active session).
@@ -398,3 +975,130 @@ pub(crate) fn query_info(class: TraceInformation, buf: &mut [u8]) -> EvntraceNat
         EvntraceNativeError::IoError(std::io::Error::from_raw_os_error(err.code().0))
     })
 }
+
+/// Request stack walks for the given classic kernel events (see [TraceStackTracingInfo](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ne-evntrace-trace_query_info_class))
+pub(crate) fn set_stack_tracing(
+    control_handle: ControlHandle,
+    events: &[Etw::CLASSIC_EVENT_ID],
+) -> EvntraceNativeResult<()> {
+    match filter_invalid_control_handle(control_handle) {
+        None => Err(EvntraceNativeError::InvalidHandle),
+        Some(handle) => {
+            let status = unsafe {
+                // Safety: the pointer and length describe `events`, that outlives this call
+                Etw::TraceSetInformation(
+                    handle,
+                    TRACE_QUERY_INFO_CLASS(TraceInformation::TraceStackTracingInfo as i32),
+                    events.as_ptr().cast(),
+                    std::mem::size_of_val(events) as u32,
+                )
+            };
+            if status != ERROR_SUCCESS {
+                return Err(EvntraceNativeError::IoError(
+                    std::io::Error::from_raw_os_error(status.0 as i32),
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Enable kernel events using a PERFINFO_GROUPMASK (see [TraceSystemTraceEnableFlagsInfo](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ne-evntrace-trace_query_info_class))
+///
+/// This replaces the `EnableFlags` of the session, that must thus be part of `group_mask`.
+pub(crate) fn set_group_mask(
+    control_handle: ControlHandle,
+    group_mask: &[u32; 8],
+) -> EvntraceNativeResult<()> {
+    match filter_invalid_control_handle(control_handle) {
+        None => Err(EvntraceNativeError::InvalidHandle),
+        Some(handle) => {
+            let status = unsafe {
+                // Safety: the pointer and length describe `group_mask`, that outlives this call
+                Etw::TraceSetInformation(
+                    handle,
+                    TRACE_QUERY_INFO_CLASS(
+                        TraceInformation::TraceSystemTraceEnableFlagsInfo as i32,
+                    ),
+                    group_mask.as_ptr().cast(),
+                    std::mem::size_of_val(group_mask) as u32,
+                )
+            };
+            if status != ERROR_SUCCESS {
+                return Err(EvntraceNativeError::IoError(
+                    std::io::Error::from_raw_os_error(status.0 as i32),
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_with_timeout() {
+        let fast = run_with_timeout(String::from("fast"), Some(Duration::from_secs(10)), || {
+            Ok(42)
+        });
+        assert_eq!(fast.unwrap(), 42);
+
+        // The slow call only returns once we have given up on it
+        let (release, released) = mpsc::channel::<()>();
+        let (late_tx, late_rx) = mpsc::channel();
+        let slow = run_with_timeout_or_else(
+            String::from("slow"),
+            Some(Duration::from_millis(10)),
+            move || {
+                let _ = released.recv();
+                Ok(42)
+            },
+            move |late| late_tx.send(late).unwrap(),
+        );
+        assert!(matches!(slow, Err(EvntraceNativeError::Timeout { call, .. }) if call == "slow"));
+        release.send(()).unwrap();
+        assert_eq!(late_rx.recv().unwrap().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_deferred_context_cleanup() {
+        let callback_data = Arc::new(CallbackData::RealTime(
+            crate::trace::callback_data::RealTimeCallbackData::new(),
+        ));
+        let contexts = OpenContexts::new();
+        let open = |handle: u64| {
+            let context = Box::new(Arc::clone(&callback_data));
+            let ctx_ptr = context.as_ref() as *const Arc<CallbackData> as *const c_void;
+            contexts.insert(context, TraceHandle { Value: handle });
+            ctx_ptr
+        };
+
+        // Closed while ProcessTrace is running: the context lives until ProcessTrace returns
+        let pending = open(1);
+        assert!(contexts.contains(&callback_data));
+        contexts.processing_started(TraceHandle { Value: 1 });
+        contexts.closed(TraceHandle { Value: 1 });
+        assert!(contexts.get(pending).is_some());
+        // e.g. a second close, that would have failed
+        contexts.closed(TraceHandle { Value: 1 });
+        assert!(contexts.get(pending).is_some());
+        contexts.processing_ended(TraceHandle { Value: 1 });
+        assert!(contexts.get(pending).is_none());
+
+        // ProcessTrace returned first (e.g. the session has been stopped)
+        let ended = open(2);
+        contexts.processing_started(TraceHandle { Value: 2 });
+        contexts.processing_ended(TraceHandle { Value: 2 });
+        assert!(contexts.get(ended).is_some());
+        contexts.closed(TraceHandle { Value: 2 });
+        assert!(contexts.get(ended).is_none());
+
+        // Closed before it has ever been processed
+        let closed = open(3);
+        contexts.closed(TraceHandle { Value: 3 });
+        assert!(contexts.get(closed).is_none());
+        assert!(!contexts.contains(&callback_data));
+    }
+}
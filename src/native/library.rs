@@ -0,0 +1,27 @@
+//! Native API - Library Loader
+//!
+//! Some functions only exist on recent versions of Windows. Importing them statically would prevent this crate from loading on older versions,
+//! so that this module resolves them at runtime instead.
+use windows::core::{PCSTR, PCWSTR};
+use windows::Win32::System::LibraryLoader::{
+    GetProcAddress, LoadLibraryExW, LOAD_LIBRARY_SEARCH_SYSTEM32,
+};
+
+/// Resolve a function exported by a system DLL
+///
+/// This returns `None` in case the DLL or the function does not exist on this version of Windows.<br/>
+/// The DLL is never unloaded, so that the returned function remains valid for the lifetime of the process.
+///
+/// # Safety
+/// `F` must be the `unsafe extern "system" fn` type that matches the signature of the exported function.
+pub(crate) unsafe fn system_function<F: Copy>(dll: PCWSTR, name: PCSTR) -> Option<F> {
+    assert_eq!(
+        std::mem::size_of::<F>(),
+        std::mem::size_of::<unsafe extern "system" fn() -> isize>(),
+        "F must be a function pointer"
+    );
+    let module = LoadLibraryExW(dll, None, LOAD_LIBRARY_SEARCH_SYSTEM32).ok()?;
+    let function = GetProcAddress(module, name)?;
+    // Safety: F is a function pointer with the same size, and the caller guarantees it has the right signature
+    Some(std::mem::transmute_copy(&function))
+}
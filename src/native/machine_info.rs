@@ -0,0 +1,113 @@
+//! Native API - Information about the current machine
+use widestring::{U16CStr, U16CString};
+use windows::core::{PCWSTR, PWSTR};
+use windows::Win32::System::Registry::{
+    RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD, RRF_RT_REG_SZ,
+};
+use windows::Win32::System::SystemInformation::{
+    ComputerNameDnsFullyQualified, GetComputerNameExW,
+};
+
+const CURRENT_VERSION_KEY: &str = r"SOFTWARE\Microsoft\Windows NT\CurrentVersion";
+
+/// The fully qualified DNS name of this machine
+pub fn computer_name() -> Option<String> {
+    let mut size = 0;
+    // This first call is expected to fail, and to give us the required buffer size
+    let _ = unsafe { GetComputerNameExW(ComputerNameDnsFullyQualified, PWSTR::null(), &mut size) };
+    if size == 0 {
+        return None;
+    }
+
+    let mut buffer = vec![0u16; size as usize];
+    unsafe {
+        // Safety: `buffer` is `size` wide chars long
+        GetComputerNameExW(
+            ComputerNameDnsFullyQualified,
+            PWSTR::from_raw(buffer.as_mut_ptr()),
+            &mut size,
+        )
+    }
+    .ok()?;
+
+    U16CStr::from_slice_truncate(&buffer)
+        .ok()
+        .map(|name| name.to_string_lossy())
+}
+
+/// The OS build number, including the update revision (e.g. `22631.4317`)
+///
+/// Contrary to `GetVersionEx`, this is not affected by the application manifest
+pub fn os_build() -> Option<String> {
+    let build = read_current_version_string("CurrentBuild")?;
+    match read_current_version_dword("UBR") {
+        Some(ubr) => Some(format!("{}.{}", build, ubr)),
+        None => Some(build),
+    }
+}
+
+fn read_current_version_string(value: &str) -> Option<String> {
+    let key = U16CString::from_str_truncate(CURRENT_VERSION_KEY);
+    let value = U16CString::from_str_truncate(value);
+
+    let mut size = 0u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            PCWSTR::from_raw(key.as_ptr()),
+            PCWSTR::from_raw(value.as_ptr()),
+            RRF_RT_REG_SZ,
+            None,
+            None,
+            Some(&mut size),
+        )
+    };
+    if status.is_err() || size == 0 {
+        return None;
+    }
+
+    let mut buffer = vec![0u16; (size as usize).div_ceil(std::mem::size_of::<u16>())];
+    let status = unsafe {
+        // Safety: `buffer` is at least `size` bytes long
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            PCWSTR::from_raw(key.as_ptr()),
+            PCWSTR::from_raw(value.as_ptr()),
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr().cast()),
+            Some(&mut size),
+        )
+    };
+    if status.is_err() {
+        return None;
+    }
+
+    U16CStr::from_slice_truncate(&buffer)
+        .ok()
+        .map(|s| s.to_string_lossy())
+}
+
+fn read_current_version_dword(value: &str) -> Option<u32> {
+    let key = U16CString::from_str_truncate(CURRENT_VERSION_KEY);
+    let value = U16CString::from_str_truncate(value);
+
+    let mut data = 0u32;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    let status = unsafe {
+        // Safety: `data` is a valid DWORD
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            PCWSTR::from_raw(key.as_ptr()),
+            PCWSTR::from_raw(value.as_ptr()),
+            RRF_RT_REG_DWORD,
+            None,
+            Some((&mut data as *mut u32).cast()),
+            Some(&mut size),
+        )
+    };
+    if status.is_err() {
+        return None;
+    }
+    Some(data)
+}
@@ -1,10 +1,18 @@
 //! Abstraction layer for Native functions and types
 //!
 //! This module interacts with the Windows native functions and should abstract all `unsafe` calls
+pub(crate) mod code_page;
+pub mod device_path;
 pub(crate) mod etw_types;
+pub(crate) mod evntprov;
 pub(crate) mod evntrace;
+pub(crate) mod library;
+pub(crate) mod machine_info;
 pub(crate) mod pla;
+pub(crate) mod privileges;
+pub(crate) mod process;
 pub(crate) mod sddl;
+pub(crate) mod security;
 pub(crate) mod tdh;
 pub(crate) mod tdh_types;
 pub mod time;
@@ -19,9 +27,11 @@ pub use tdh::TdhNativeError;
 // These are returned by some of our public APIs
 pub use etw_types::extended_data::EventHeaderExtendedDataItem;
 pub use etw_types::extended_data::ExtendedDataItem;
+pub use etw_types::extended_data::{StackFrame, StackTraceItem};
 pub use etw_types::DecodingSource;
 pub use evntrace::ControlHandle;
 pub use evntrace::TraceHandle;
+pub use tdh_types::{PropertyError, TdhInType, TdhOutType};
 pub use windows::Win32::System::Diagnostics::Etw::{
     EVENT_EXTENDED_ITEM_INSTANCE, EVENT_EXTENDED_ITEM_STACK_TRACE32,
     EVENT_EXTENDED_ITEM_STACK_TRACE64,
@@ -15,6 +15,7 @@ use windows::{
 
 /// Pla native module errors
 #[derive(Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum PlaError {
     /// Represents a Provider not found Error
     NotFound,
@@ -0,0 +1,80 @@
+//! Native API - Privileges of the current process
+use once_cell::sync::Lazy;
+use windows::core::{s, w};
+use windows::Win32::Foundation::{BOOL, HANDLE, PSID};
+use windows::Win32::Security::{
+    CheckTokenMembership, CreateWellKnownSid, WinBuiltinAdministratorsSid,
+    WinBuiltinPerfLoggingUsersSid, WELL_KNOWN_SID_TYPE,
+};
+use windows::Win32::System::Threading::{
+    GetCurrentProcess, ProcessProtectionLevelInfo, PROCESS_INFORMATION_CLASS,
+    PROCESS_PROTECTION_LEVEL_INFORMATION,
+};
+
+use crate::native::library::system_function;
+
+type GetProcessInformationFn = unsafe extern "system" fn(
+    HANDLE,
+    PROCESS_INFORMATION_CLASS,
+    *mut std::ffi::c_void,
+    u32,
+) -> BOOL;
+
+/// `GetProcessInformation` is only available on Windows 8 and later
+static GET_PROCESS_INFORMATION: Lazy<Option<GetProcessInformationFn>> =
+    Lazy::new(|| unsafe { system_function(w!("kernel32.dll"), s!("GetProcessInformation")) });
+
+/// See `SECURITY_MAX_SID_SIZE` in winnt.h
+const SECURITY_MAX_SID_SIZE: u32 = 68;
+
+/// Whether the current thread belongs to a well-known group
+///
+/// Groups that are "deny-only" (e.g. Administrators in a non-elevated process) are not considered.<br/>
+/// This returns `None` in case this cannot be determined.
+fn is_member_of(sid_type: WELL_KNOWN_SID_TYPE) -> Option<bool> {
+    // A buffer of u64 is suitably aligned for a SID
+    let mut buffer = [0u64; (SECURITY_MAX_SID_SIZE as usize).div_ceil(std::mem::size_of::<u64>())];
+    let sid = PSID(buffer.as_mut_ptr().cast());
+    let mut sid_size = SECURITY_MAX_SID_SIZE;
+    unsafe {
+        // Safety: `buffer` is at least `sid_size` bytes long
+        CreateWellKnownSid(sid_type, PSID::default(), sid, &mut sid_size)
+    }
+    .ok()?;
+
+    let mut is_member = BOOL::default();
+    unsafe {
+        // Safety: `sid` has been initialized by CreateWellKnownSid
+        CheckTokenMembership(HANDLE::default(), sid, &mut is_member)
+    }
+    .ok()?;
+    Some(is_member.as_bool())
+}
+
+/// Whether the current process runs elevated, as a member of the Administrators group
+pub fn is_elevated_administrator() -> Option<bool> {
+    is_member_of(WinBuiltinAdministratorsSid)
+}
+
+/// Whether the current process is a member of the "Performance Log Users" group, that is allowed to control user-mode ETW sessions
+pub fn is_performance_log_user() -> Option<bool> {
+    is_member_of(WinBuiltinPerfLoggingUsersSid)
+}
+
+/// The protection level of the current process (a `PROTECTION_LEVEL_*` value)
+///
+/// This returns `None` in case this cannot be determined (e.g. before Windows 8).
+pub fn protection_level() -> Option<u32> {
+    let get_process_information = (*GET_PROCESS_INFORMATION)?;
+    let mut info = PROCESS_PROTECTION_LEVEL_INFORMATION::default();
+    let succeeded = unsafe {
+        // Safety: `info` is a PROCESS_PROTECTION_LEVEL_INFORMATION, as expected for this information class
+        get_process_information(
+            GetCurrentProcess(),
+            ProcessProtectionLevelInfo,
+            &mut info as *mut PROCESS_PROTECTION_LEVEL_INFORMATION as *mut std::ffi::c_void,
+            std::mem::size_of::<PROCESS_PROTECTION_LEVEL_INFORMATION>() as u32,
+        )
+    };
+    succeeded.as_bool().then_some(info.ProtectionLevel.0)
+}
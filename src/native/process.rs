@@ -0,0 +1,33 @@
+//! Native API - Other processes running on this machine
+use windows::Win32::Foundation::{CloseHandle, ERROR_INVALID_PARAMETER, STILL_ACTIVE};
+use windows::Win32::System::Threading::{
+    GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+};
+
+/// Whether a process with this PID is currently running
+///
+/// In case this cannot be determined (e.g. the process belongs to another user and cannot be opened), the process is considered running.
+pub fn is_running(pid: u32) -> bool {
+    let handle = match unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid) } {
+        Ok(handle) => handle,
+        // There is no process with this PID
+        Err(err) if err.code() == ERROR_INVALID_PARAMETER.to_hresult() => return false,
+        // E.g. ERROR_ACCESS_DENIED: the process exists, we're just not allowed to query it
+        Err(_) => return true,
+    };
+
+    let mut exit_code = 0u32;
+    let result = unsafe {
+        // Safety: `handle` is valid, and has been opened with the right access
+        GetExitCodeProcess(handle, &mut exit_code)
+    };
+    unsafe {
+        // Safety: `handle` is valid, and is not used afterwards
+        let _ = CloseHandle(handle);
+    }
+    match result {
+        // A process that has exited with code 259 is mistaken for a running one, which errs on the safe side
+        Ok(()) => exit_code == STILL_ACTIVE.0 as u32,
+        Err(_) => true,
+    }
+}
@@ -6,6 +6,7 @@ use windows::Win32::Security::Authorization::ConvertSidToStringSidA;
 
 /// SDDL native error
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SddlNativeError {
     /// Represents an error parsing the SID into a String
     SidParseError(Utf8Error),
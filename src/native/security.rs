@@ -0,0 +1,132 @@
+//! Native API - Security descriptors of ETW providers and sessions
+use windows::core::{GUID, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{BOOL, ERROR_INSUFFICIENT_BUFFER, ERROR_MORE_DATA, PSID};
+use windows::Win32::Security::{
+    GetAce, GetSecurityDescriptorDacl, LookupAccountSidW, ACCESS_ALLOWED_ACE, ACL,
+    PSECURITY_DESCRIPTOR, SID_NAME_USE,
+};
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::sddl;
+
+/// `ACCESS_ALLOWED_ACE_TYPE`, as defined in winnt.h
+const ACCESS_ALLOWED_ACE_TYPE: u8 = 0;
+/// `ACCESS_DENIED_ACE_TYPE`, as defined in winnt.h
+const ACCESS_DENIED_ACE_TYPE: u8 = 1;
+/// Account and domain names are at most 256 characters long
+const MAX_NAME_LEN: usize = 257;
+
+/// An access-allowed or access-denied entry of a DACL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawAce {
+    pub allowed: bool,
+    pub mask: u32,
+    pub sid: String,
+    /// `DOMAIN\name`, in case the SID could be resolved
+    pub account: Option<String>,
+}
+
+/// Read the security descriptor of a provider or session (as would `EventAccessQuery`), and return the entries of its DACL
+///
+/// Entries of other types (e.g. audit or object entries) are ignored.<br/>
+/// This returns `None` in case there is no DACL, which grants full access to everyone.
+pub fn query_access(guid: GUID) -> std::io::Result<Option<Vec<RawAce>>> {
+    let descriptor = query_descriptor(guid)?;
+    if descriptor.is_empty() {
+        return Ok(None);
+    }
+    let sd = PSECURITY_DESCRIPTOR(descriptor.as_ptr() as *mut _);
+
+    let mut present = BOOL::default();
+    let mut defaulted = BOOL::default();
+    let mut dacl: *mut ACL = std::ptr::null_mut();
+    unsafe {
+        // Safety: `descriptor` is a valid self-relative security descriptor, that outlives `dacl`
+        GetSecurityDescriptorDacl(sd, &mut present, &mut dacl, &mut defaulted)
+    }?;
+    if !present.as_bool() || dacl.is_null() {
+        return Ok(None);
+    }
+
+    let ace_count = unsafe { (*dacl).AceCount };
+    let mut entries = Vec::with_capacity(ace_count as usize);
+    for index in 0..ace_count {
+        let mut ace: *mut std::ffi::c_void = std::ptr::null_mut();
+        unsafe {
+            // Safety: `index` is within the bounds of the ACL
+            GetAce(dacl, index as u32, &mut ace)
+        }?;
+        // Safety: allowed and denied ACEs share the same layout, and their header is read first
+        let ace = unsafe { &*(ace as *const ACCESS_ALLOWED_ACE) };
+        let allowed = match ace.Header.AceType {
+            ACCESS_ALLOWED_ACE_TYPE => true,
+            ACCESS_DENIED_ACE_TYPE => false,
+            _ => continue,
+        };
+        let sid = PSID(&ace.SidStart as *const u32 as *mut _);
+        let sid_string = sddl::convert_sid_to_string(sid.0).map_err(|err| match err {
+            sddl::SddlNativeError::IoError(e) => e,
+            other => std::io::Error::new(std::io::ErrorKind::InvalidData, other.to_string()),
+        })?;
+        entries.push(RawAce {
+            allowed,
+            mask: ace.Mask,
+            sid: sid_string,
+            account: lookup_account(sid),
+        });
+    }
+    Ok(Some(entries))
+}
+
+fn query_descriptor(guid: GUID) -> std::io::Result<Vec<u64>> {
+    let mut size = 0u32;
+    // A buffer of u64 is suitably aligned for a security descriptor
+    let mut buffer: Vec<u64> = Vec::new();
+    loop {
+        let status = unsafe {
+            // Safety: `buffer` is at least `size` bytes long
+            Etw::EventAccessQuery(
+                &guid,
+                PSECURITY_DESCRIPTOR(buffer.as_mut_ptr().cast()),
+                &mut size,
+            )
+        };
+        match status {
+            // The buffer is still empty in case there is no security descriptor at all
+            0 => return Ok(buffer),
+            code if code == ERROR_MORE_DATA.0 || code == ERROR_INSUFFICIENT_BUFFER.0 => {
+                buffer = vec![0u64; (size as usize).div_ceil(std::mem::size_of::<u64>())];
+            }
+            code => return Err(std::io::Error::from_raw_os_error(code as i32)),
+        }
+    }
+}
+
+fn lookup_account(sid: PSID) -> Option<String> {
+    let mut name = [0u16; MAX_NAME_LEN];
+    let mut domain = [0u16; MAX_NAME_LEN];
+    let mut name_len = name.len() as u32;
+    let mut domain_len = domain.len() as u32;
+    let mut name_use = SID_NAME_USE::default();
+    unsafe {
+        // Safety: lengths match the sizes of the buffers
+        LookupAccountSidW(
+            PCWSTR::null(),
+            sid,
+            PWSTR(name.as_mut_ptr()),
+            &mut name_len,
+            PWSTR(domain.as_mut_ptr()),
+            &mut domain_len,
+            &mut name_use,
+        )
+    }
+    .ok()?;
+
+    let name = String::from_utf16_lossy(&name[..name_len as usize]);
+    let domain = String::from_utf16_lossy(&domain[..domain_len as usize]);
+    if domain.is_empty() {
+        Some(name)
+    } else {
+        Some(format!("{}\\{}", domain, name))
+    }
+}
@@ -9,15 +9,25 @@ use std::alloc::Layout;
 
 use super::etw_types::*;
 use crate::native::etw_types::event_record::EventRecord;
-use crate::native::tdh_types::Property;
+use crate::native::library::system_function;
+use crate::native::tdh_types::{EventMap, EventMapKind, Property, PropertyFlags};
 use crate::traits::*;
-use widestring::U16CStr;
-use windows::core::GUID;
-use windows::Win32::Foundation::ERROR_INSUFFICIENT_BUFFER;
-use windows::Win32::System::Diagnostics::Etw::{self, EVENT_PROPERTY_INFO, TRACE_EVENT_INFO};
+use crate::utils::internal_span;
+use once_cell::sync::Lazy;
+use widestring::{U16CStr, U16CString};
+use windows::core::{s, w, GUID, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{
+    BOOLEAN, ERROR_INSUFFICIENT_BUFFER, ERROR_NOT_FOUND, ERROR_NOT_SUPPORTED,
+};
+use windows::Win32::System::Diagnostics::Etw::{
+    self, EVENT_FILTER_DESCRIPTOR, EVENT_MAP_ENTRY, EVENT_MAP_INFO, EVENT_PROPERTY_INFO,
+    PAYLOAD_FILTER_PREDICATE, PROVIDER_ENUMERATION_INFO, PROVIDER_EVENT_INFO, PROVIDER_FIELD_INFO,
+    PROVIDER_FIELD_INFOARRAY, TRACE_EVENT_INFO, TRACE_PROVIDER_INFO,
+};
 
 /// Tdh native module errors
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum TdhNativeError {
     /// Represents an allocation error
     AllocationError,
@@ -76,6 +86,7 @@ macro_rules! extract_utf16_string {
 impl TraceEventInfo {
     /// Create a instance of `Self` suitable for the given event
     pub fn build_from_event(event: &EventRecord) -> TdhNativeResult<Self> {
+        let _span = internal_span!("TdhGetEventInformation");
         let mut buffer_size = 0;
         let status = unsafe {
             // Safety:
@@ -191,7 +202,8 @@ pub struct PropertyIterator<'info> {
 
 impl<'info> PropertyIterator<'info> {
     fn new(te_info: &'info TraceEventInfo) -> Self {
-        let count = te_info.as_raw().PropertyCount;
+        // Members of structures are listed after the top-level properties. They are not part of the sequence of properties of the event.
+        let count = te_info.as_raw().TopLevelPropertyCount;
         Self {
             next_index: 0,
             count,
@@ -248,12 +260,34 @@ impl<'info> Iterator for PropertyIterator<'info> {
         };
         let property_name = property_name.to_string_lossy();
 
+        let flags = PropertyFlags::from(curr_prop.Flags);
+        let map_name_offset = if flags
+            .intersects(PropertyFlags::PROPERTY_STRUCT | PropertyFlags::PROPERTY_HAS_CUSTOM_SCHEMA)
+        {
+            0
+        } else {
+            // The property is a non-struct type. It makes sense to access this field of the union
+            unsafe { curr_prop.Anonymous1.nonStructType.MapNameOffset }
+        };
+        let map_name = if map_name_offset == 0 {
+            None
+        } else {
+            let map_name = unsafe {
+                // Safety:
+                //  * the offset comes from a Microsoft API, and points to a null-terminated string within the same buffer
+                //  * we will copy into a String before the buffer gets invalid
+                U16CStr::from_ptr_str(te_info_data.offset(map_name_offset as isize) as *const u16)
+            };
+            Some(map_name.to_string_lossy())
+        };
+
         self.next_index += 1;
-        Some(Property::new(property_name, curr_prop))
+        Some(Property::new(property_name, map_name, curr_prop))
     }
 }
 
 pub fn property_size(event: &EventRecord, name: &str) -> TdhNativeResult<u32> {
+    let _span = internal_span!("TdhGetPropertySize", property = name);
     let mut property_size = 0;
 
     let name = name.into_utf16();
@@ -274,3 +308,395 @@ pub fn property_size(event: &EventRecord, name: &str) -> TdhNativeResult<u32> {
 
     Ok(property_size)
 }
+
+/// The value map or bitmap named `map_name`, as defined by the provider of `event`
+///
+/// See [TdhGetEventMapInformation](https://learn.microsoft.com/en-us/windows/win32/api/tdh/nf-tdh-tdhgeteventmapinformation)
+pub fn event_map_information(event: &EventRecord, map_name: &str) -> TdhNativeResult<EventMap> {
+    let _span = internal_span!("TdhGetEventMapInformation", map = map_name);
+    let map_name = map_name.into_utf16();
+    let mut buffer_size = 0;
+    let status = unsafe {
+        // Safety:
+        //  * the `EVENT_RECORD` was passed by Microsoft and has not been modified: it is thus valid and correctly aligned
+        //  * `map_name` is null-terminated
+        Etw::TdhGetEventMapInformation(
+            event.as_raw_ptr(),
+            PCWSTR::from_raw(map_name.as_ptr()),
+            None,
+            &mut buffer_size,
+        )
+    };
+    if status != ERROR_INSUFFICIENT_BUFFER.0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+
+    // A buffer of u64 is suitably aligned for an EVENT_MAP_INFO
+    let mut buffer = vec![0u64; (buffer_size as usize).div_ceil(std::mem::size_of::<u64>())];
+    let status = unsafe {
+        // Safety: `buffer` is at least `buffer_size` bytes long, and correctly aligned
+        Etw::TdhGetEventMapInformation(
+            event.as_raw_ptr(),
+            PCWSTR::from_raw(map_name.as_ptr()),
+            Some(buffer.as_mut_ptr().cast::<EVENT_MAP_INFO>()),
+            &mut buffer_size,
+        )
+    };
+    if status != 0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+
+    let base = buffer.as_ptr().cast::<u8>();
+    let info = buffer.as_ptr().cast::<EVENT_MAP_INFO>();
+    let (flags, entries) = unsafe {
+        // Safety: TDH has filled the buffer with an EVENT_MAP_INFO, which is followed by `EntryCount` entries
+        (
+            (*info).Flag,
+            std::slice::from_raw_parts(
+                std::ptr::addr_of!((*info).MapEntryArray).cast::<EVENT_MAP_ENTRY>(),
+                (*info).EntryCount as usize,
+            ),
+        )
+    };
+
+    let has_flag = |flag: Etw::MAP_FLAGS| flags.0 & flag.0 != 0;
+    let kind = if has_flag(Etw::EVENTMAP_INFO_FLAG_MANIFEST_BITMAP)
+        || has_flag(Etw::EVENTMAP_INFO_FLAG_WBEM_BITMAP)
+    {
+        EventMapKind::Bitmap
+    } else {
+        EventMapKind::ValueMap
+    };
+    // WBEM maps without explicit values are indexed by their position (or by bit position, for bitmaps)
+    let implicit_values = has_flag(Etw::EVENTMAP_INFO_FLAG_WBEM_NO_MAP);
+
+    Ok(EventMap {
+        kind,
+        entries: entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let value = match (implicit_values, kind) {
+                    (true, EventMapKind::ValueMap) => index as u32,
+                    (true, EventMapKind::Bitmap) => 1u32.checked_shl(index as u32).unwrap_or(0),
+                    (false, _) => unsafe {
+                        // Safety: value maps that are not WBEM_NO_MAP have ULONG values
+                        entry.Anonymous.Value
+                    },
+                };
+                let name = if entry.OutputOffset == 0 {
+                    String::new()
+                } else {
+                    unsafe {
+                        // Safety:
+                        //  * we trust Microsoft for providing correctly aligned, null-terminated strings within the buffer
+                        //  * we copy into a String before the buffer gets invalid
+                        U16CStr::from_ptr_str(base.add(entry.OutputOffset as usize).cast::<u16>())
+                    }
+                    .to_string_lossy()
+                };
+                // Manifest strings usually come with a trailing space
+                (value, name.trim_end().to_string())
+            })
+            .collect(),
+    })
+}
+
+/// Names and values of the fields (e.g. levels or keywords) a provider defines in its manifest
+pub fn provider_field_information(
+    provider: &GUID,
+    field_type: Etw::EVENT_FIELD_TYPE,
+) -> TdhNativeResult<Vec<(String, u64)>> {
+    let mut buffer_size = 0;
+    let status = unsafe {
+        Etw::TdhEnumerateProviderFieldInformation(provider, field_type, None, &mut buffer_size)
+    };
+    if status == ERROR_NOT_FOUND.0 {
+        // The provider does not define any field of this type
+        return Ok(Vec::new());
+    }
+    if status != ERROR_INSUFFICIENT_BUFFER.0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+
+    // A buffer of u64 is suitably aligned for a PROVIDER_FIELD_INFOARRAY
+    let mut buffer = vec![0u64; (buffer_size as usize).div_ceil(std::mem::size_of::<u64>())];
+    let status = unsafe {
+        // Safety: `buffer` is at least `buffer_size` bytes long, and correctly aligned
+        Etw::TdhEnumerateProviderFieldInformation(
+            provider,
+            field_type,
+            Some(buffer.as_mut_ptr().cast::<PROVIDER_FIELD_INFOARRAY>()),
+            &mut buffer_size,
+        )
+    };
+    if status != 0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+
+    let base = buffer.as_ptr().cast::<u8>();
+    let array = buffer.as_ptr().cast::<PROVIDER_FIELD_INFOARRAY>();
+    let infos = unsafe {
+        // Safety: TDH has filled the buffer with a PROVIDER_FIELD_INFOARRAY, which is followed by `NumberOfElements` items
+        std::slice::from_raw_parts(
+            std::ptr::addr_of!((*array).FieldInfoArray).cast::<PROVIDER_FIELD_INFO>(),
+            (*array).NumberOfElements as usize,
+        )
+    };
+
+    Ok(infos
+        .iter()
+        .map(|info| {
+            let name = if info.NameOffset == 0 {
+                String::new()
+            } else {
+                unsafe {
+                    // Safety:
+                    //  * we trust Microsoft for providing correctly aligned, null-terminated strings within the buffer
+                    //  * we copy into a String before the buffer gets invalid
+                    U16CStr::from_ptr_str(base.add(info.NameOffset as usize).cast::<u16>())
+                }
+                .to_string_lossy()
+            };
+            (name, info.Value)
+        })
+        .collect())
+}
+
+/// List the providers whose schema is registered on this machine (name, GUID and schema source), using [TdhEnumerateProviders](https://learn.microsoft.com/en-us/windows/win32/api/tdh/nf-tdh-tdhenumerateproviders)
+pub fn enumerate_providers() -> TdhNativeResult<Vec<(String, GUID, u32)>> {
+    let mut buffer_size = 0;
+    let mut buffer: Vec<u64> = Vec::new();
+    loop {
+        let status = unsafe {
+            // Safety: `buffer` is at least `buffer_size` bytes long, and correctly aligned
+            Etw::TdhEnumerateProviders(
+                if buffer.is_empty() {
+                    None
+                } else {
+                    Some(buffer.as_mut_ptr().cast::<PROVIDER_ENUMERATION_INFO>())
+                },
+                &mut buffer_size,
+            )
+        };
+        if status == 0 && !buffer.is_empty() {
+            break;
+        }
+        // Providers may have been registered in the meantime, so this may happen several times
+        if status != 0 && status != ERROR_INSUFFICIENT_BUFFER.0 {
+            return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+                status as i32,
+            )));
+        }
+        buffer = vec![
+            0u64;
+            (buffer_size as usize)
+                .div_ceil(std::mem::size_of::<u64>())
+                .max(1)
+        ];
+    }
+
+    let base = buffer.as_ptr().cast::<u8>();
+    let array = buffer.as_ptr().cast::<PROVIDER_ENUMERATION_INFO>();
+    let infos = unsafe {
+        // Safety: TDH has filled the buffer with a PROVIDER_ENUMERATION_INFO, which is followed by `NumberOfProviders` items
+        std::slice::from_raw_parts(
+            std::ptr::addr_of!((*array).TraceProviderInfoArray).cast::<TRACE_PROVIDER_INFO>(),
+            (*array).NumberOfProviders as usize,
+        )
+    };
+
+    Ok(infos
+        .iter()
+        .map(|info| {
+            let name = unsafe {
+                // Safety:
+                //  * we trust Microsoft for providing correctly aligned, null-terminated strings within the buffer
+                //  * we copy into a String before the buffer gets invalid
+                U16CStr::from_ptr_str(base.add(info.ProviderNameOffset as usize).cast::<u16>())
+            }
+            .to_string_lossy();
+            (name, info.ProviderGuid, info.SchemaSource)
+        })
+        .collect())
+}
+
+type TdhEnumerateManifestProviderEventsFn =
+    unsafe extern "system" fn(*const GUID, *mut PROVIDER_EVENT_INFO, *mut u32) -> u32;
+
+/// `TdhEnumerateManifestProviderEvents` is only available on Windows 10 version 2004 and later
+static TDH_ENUMERATE_MANIFEST_PROVIDER_EVENTS: Lazy<Option<TdhEnumerateManifestProviderEventsFn>> =
+    Lazy::new(|| unsafe {
+        system_function(w!("tdh.dll"), s!("TdhEnumerateManifestProviderEvents"))
+    });
+
+/// Descriptors of every event a manifest-based provider defines
+///
+/// This requires Windows 10 version 2004 (or later), and fails with `ERROR_NOT_SUPPORTED` on older versions
+pub fn manifest_provider_events(provider: &GUID) -> TdhNativeResult<Vec<Etw::EVENT_DESCRIPTOR>> {
+    let enumerate_events = (*TDH_ENUMERATE_MANIFEST_PROVIDER_EVENTS).ok_or_else(|| {
+        TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            ERROR_NOT_SUPPORTED.0 as i32,
+        ))
+    })?;
+
+    let mut buffer_size = 0;
+    let status = unsafe { enumerate_events(provider, std::ptr::null_mut(), &mut buffer_size) };
+    if status == ERROR_NOT_FOUND.0 {
+        // The provider has no manifest (e.g. TraceLogging or MOF providers)
+        return Ok(Vec::new());
+    }
+    if status != ERROR_INSUFFICIENT_BUFFER.0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+
+    // A buffer of u64 is suitably aligned for a PROVIDER_EVENT_INFO
+    let mut buffer = vec![0u64; (buffer_size as usize).div_ceil(std::mem::size_of::<u64>())];
+    let status = unsafe {
+        // Safety: `buffer` is at least `buffer_size` bytes long, and correctly aligned
+        enumerate_events(
+            provider,
+            buffer.as_mut_ptr().cast::<PROVIDER_EVENT_INFO>(),
+            &mut buffer_size,
+        )
+    };
+    if status != 0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+
+    let array = buffer.as_ptr().cast::<PROVIDER_EVENT_INFO>();
+    let descriptors = unsafe {
+        // Safety: TDH has filled the buffer with a PROVIDER_EVENT_INFO, which is followed by `NumberOfEvents` items
+        std::slice::from_raw_parts(
+            std::ptr::addr_of!((*array).EventDescriptorsArray).cast::<Etw::EVENT_DESCRIPTOR>(),
+            (*array).NumberOfEvents as usize,
+        )
+    };
+    Ok(descriptors.to_vec())
+}
+
+/// A payload filter, as created by [TdhCreatePayloadFilter](https://learn.microsoft.com/en-us/windows/win32/api/tdh/nf-tdh-tdhcreatepayloadfilter)
+///
+/// It is deleted when dropped
+pub struct PayloadFilterHandle(*mut std::ffi::c_void);
+
+impl Drop for PayloadFilterHandle {
+    fn drop(&mut self) {
+        let status = unsafe {
+            // Safety: this pointer has been allocated by TdhCreatePayloadFilter, and is not used anymore
+            Etw::TdhDeletePayloadFilter(&mut self.0)
+        };
+        if status != 0 {
+            log::warn!("Unable to delete payload filter: error {}", status);
+        }
+    }
+}
+
+/// Create a filter on the properties of an event
+///
+/// Each predicate is made of a property name, a `PAYLOAD_OPERATOR` and a value.
+/// The provider must have a manifest, that defines this event.
+pub fn create_payload_filter(
+    provider: &GUID,
+    event_id: u16,
+    version: u8,
+    match_any: bool,
+    predicates: &[(&str, u16, &str)],
+) -> TdhNativeResult<PayloadFilterHandle> {
+    let wide_strings: Vec<(U16CString, U16CString)> = predicates
+        .iter()
+        .map(|(name, _, value)| {
+            (
+                U16CString::from_str_truncate(name),
+                U16CString::from_str_truncate(value),
+            )
+        })
+        .collect();
+    let native_predicates: Vec<PAYLOAD_FILTER_PREDICATE> = predicates
+        .iter()
+        .zip(wide_strings.iter())
+        .map(
+            |((_, operator, _), (name, value))| PAYLOAD_FILTER_PREDICATE {
+                // TDH does not write to these strings
+                FieldName: PWSTR(name.as_ptr() as *mut u16),
+                CompareOp: *operator,
+                Value: PWSTR(value.as_ptr() as *mut u16),
+            },
+        )
+        .collect();
+    let descriptor = Etw::EVENT_DESCRIPTOR {
+        Id: event_id,
+        Version: version,
+        ..Default::default()
+    };
+
+    let mut filter = std::ptr::null_mut();
+    let status = unsafe {
+        // Safety: the predicate strings are alive until the end of this function, TDH copies them into the filter
+        Etw::TdhCreatePayloadFilter(
+            provider,
+            &descriptor,
+            BOOLEAN::from(match_any),
+            &native_predicates,
+            &mut filter,
+        )
+    };
+    if status != 0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+    Ok(PayloadFilterHandle(filter))
+}
+
+/// Combine payload filters into a single `EVENT_FILTER_DESCRIPTOR`
+///
+/// An event is kept if it matches any of the filters for its event ID.<br/>
+/// The returned descriptor must be freed with [`cleanup_payload_filter_descriptor`].
+pub fn aggregate_payload_filters(
+    filters: &[PayloadFilterHandle],
+) -> TdhNativeResult<EVENT_FILTER_DESCRIPTOR> {
+    let pointers: Vec<*const std::ffi::c_void> = filters.iter().map(|f| f.0 as *const _).collect();
+    let match_all_flags = vec![BOOLEAN(0); filters.len()];
+
+    let mut descriptor = EVENT_FILTER_DESCRIPTOR::default();
+    let status = unsafe {
+        // Safety: both arrays have one item per filter
+        Etw::TdhAggregatePayloadFilters(
+            pointers.len() as u32,
+            pointers.as_ptr(),
+            Some(match_all_flags.as_ptr()),
+            &mut descriptor,
+        )
+    };
+    if status != 0 {
+        return Err(TdhNativeError::IoError(std::io::Error::from_raw_os_error(
+            status as i32,
+        )));
+    }
+    Ok(descriptor)
+}
+
+/// Free a descriptor created by [`aggregate_payload_filters`]
+///
+/// # Safety
+///
+/// `descriptor` must come from [`aggregate_payload_filters`], and must not be used afterwards
+pub unsafe fn cleanup_payload_filter_descriptor(descriptor: &mut EVENT_FILTER_DESCRIPTOR) {
+    let status = Etw::TdhCleanupPayloadEventFilterDescriptor(descriptor);
+    if status != 0 {
+        log::warn!("Unable to free payload filter descriptor: error {}", status);
+    }
+}
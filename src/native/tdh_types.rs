@@ -14,6 +14,7 @@ use num_traits::FromPrimitive;
 use windows::Win32::System::Diagnostics::Etw;
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum PropertyError {
     /// Parsing complex types in properties is not supported in this crate
     /// (yet? See <https://github.com/n4r1b/ferrisetw/issues/76>)
@@ -75,6 +76,13 @@ pub enum PropertyInfo {
         /// Number of elements.
         count: PropertyCount,
     },
+    /// A property this crate is not able to parse (e.g. a structure)
+    ///
+    /// It is still listed, so that the offsets of the properties that follow it remain correct.
+    Unsupported {
+        /// Why this property cannot be parsed
+        error: PropertyError,
+    },
 }
 
 impl Default for PropertyInfo {
@@ -96,17 +104,32 @@ pub struct Property {
     pub flags: PropertyFlags,
     /// Information about the property.
     pub info: PropertyInfo,
+    /// Name of the value map or bitmap that gives names to the values of this property, if any
+    pub map_name: Option<String>,
 }
 
 #[doc(hidden)]
 impl Property {
-    pub fn new(name: String, property: &Etw::EVENT_PROPERTY_INFO) -> Result<Self, PropertyError> {
+    pub fn new(
+        name: String,
+        map_name: Option<String>,
+        property: &Etw::EVENT_PROPERTY_INFO,
+    ) -> Result<Self, PropertyError> {
         let flags = PropertyFlags::from(property.Flags);
 
+        let unsupported = |error| Property {
+            name: name.clone(),
+            flags,
+            info: PropertyInfo::Unsupported { error },
+            map_name: None,
+        };
+
         if flags.contains(PropertyFlags::PROPERTY_STRUCT) {
-            Err(PropertyError::UnimplementedType("structure"))
+            Ok(unsupported(PropertyError::UnimplementedType("structure")))
         } else if flags.contains(PropertyFlags::PROPERTY_HAS_CUSTOM_SCHEMA) {
-            Err(PropertyError::UnimplementedType("has custom schema"))
+            Ok(unsupported(PropertyError::UnimplementedType(
+                "has custom schema",
+            )))
         } else {
             // The property is a non-struct type. It makes sense to access these fields of the unions
             let ot = unsafe { property.Anonymous1.nonStructType.OutType };
@@ -152,6 +175,7 @@ impl Property {
                         length,
                         count: c,
                     },
+                    map_name,
                 }),
                 None => Ok(Property {
                     name,
@@ -161,6 +185,7 @@ impl Property {
                         out_type,
                         length,
                     },
+                    map_name,
                 }),
             }
         }
@@ -195,7 +220,10 @@ pub enum TdhInType {
     InTypeSid,        // Field size determined by the first few bytes of the field
     InTypeHexInt32,
     InTypeHexInt64,
-    InTypeCountedString = 300,
+    InTypeCountedString = 300, // UTF-16 string, preceded by its length in bytes (2 bytes, little-endian)
+    InTypeCountedAnsiString, // ANSI string, preceded by its length in bytes (2 bytes, little-endian)
+    InTypeReversedCountedString, // UTF-16 string, preceded by its length in bytes (2 bytes, big-endian)
+    InTypeReversedCountedAnsiString, // ANSI string, preceded by its length in bytes (2 bytes, big-endian)
 }
 
 /// Represent a TDH_OUT_TYPE
@@ -228,6 +256,7 @@ pub enum TdhOutType {
     OutTypePort,
     OutTypeIpv4,
     OutTypeIpv6,
+    OutTypeSocketAddress, // SOCKADDR_IN or SOCKADDR_IN6
     OutTypeWin32Error = 30,
     OutTypeNtStatus = 31,
     OutTypeHResult = 32,
@@ -262,3 +291,102 @@ impl From<Etw::PROPERTY_FLAGS> for PropertyFlags {
         PropertyFlags::from_bits_truncate(flags as u32)
     }
 }
+
+/// Whether an [`EventMap`] names whole values, or individual bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventMapKind {
+    ValueMap,
+    Bitmap,
+}
+
+/// Names that a provider gives to the values of a property (see the `map` attribute of manifest properties)
+///
+/// See [EVENT_MAP_INFO](https://learn.microsoft.com/en-us/windows/win32/api/tdh/ns-tdh-event_map_info)
+#[derive(Debug, Clone)]
+pub struct EventMap {
+    pub kind: EventMapKind,
+    /// Values (or bit masks, for bitmaps) along with their names
+    pub entries: Vec<(u32, String)>,
+}
+
+impl EventMap {
+    /// The name of `value`
+    ///
+    /// For bitmaps, the names of every set bit are joined with `" | "`.<br/>
+    /// Values (or remaining bits) that are not part of the map are formatted in hexadecimal.
+    pub fn resolve(&self, value: u32) -> String {
+        match self.kind {
+            EventMapKind::ValueMap => self
+                .entries
+                .iter()
+                .find(|(v, _)| *v == value)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_else(|| format!("{:#x}", value)),
+            EventMapKind::Bitmap => {
+                if value == 0 {
+                    return self
+                        .entries
+                        .iter()
+                        .find(|(mask, _)| *mask == 0)
+                        .map(|(_, name)| name.clone())
+                        .unwrap_or_else(|| String::from("0x0"));
+                }
+
+                let mut names = Vec::new();
+                let mut remaining = value;
+                for (mask, name) in &self.entries {
+                    if *mask != 0 && value & mask == *mask {
+                        names.push(name.clone());
+                        remaining &= !mask;
+                    }
+                }
+                if remaining != 0 {
+                    names.push(format!("{:#x}", remaining));
+                }
+                names.join(" | ")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_event_map() {
+        let status = EventMap {
+            kind: EventMapKind::ValueMap,
+            entries: vec![
+                (0, String::from("STATUS_SUCCESS")),
+                (0xc0000022, String::from("STATUS_ACCESS_DENIED")),
+            ],
+        };
+        assert_eq!(status.resolve(0xc0000022), "STATUS_ACCESS_DENIED");
+        assert_eq!(status.resolve(0xc0000005), "0xc0000005");
+
+        let access = EventMap {
+            kind: EventMapKind::Bitmap,
+            entries: vec![(0x1, String::from("Read")), (0x2, String::from("Write"))],
+        };
+        assert_eq!(access.resolve(0x3), "Read | Write");
+        assert_eq!(access.resolve(0x5), "Read | 0x4");
+        assert_eq!(access.resolve(0), "0x0");
+    }
+
+    #[test]
+    fn test_struct_property_is_kept() {
+        let info = Etw::EVENT_PROPERTY_INFO {
+            Flags: Etw::PropertyStruct,
+            ..Default::default()
+        };
+        let property = Property::new(String::from("Struct"), None, &info).unwrap();
+        assert_eq!(property.name, "Struct");
+        assert!(matches!(
+            property.info,
+            PropertyInfo::Unsupported {
+                error: PropertyError::UnimplementedType("structure")
+            }
+        ));
+    }
+}
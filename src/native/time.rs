@@ -1,11 +1,18 @@
 //! Implements wrappers for various Windows time structures.
+use once_cell::sync::Lazy;
+use windows::core::{s, w};
 use windows::Win32::{
     Foundation::{FILETIME, SYSTEMTIME},
+    System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency},
+    System::SystemInformation::GetSystemTimeAsFileTime,
     System::Time::SystemTimeToFileTime,
 };
 
+use crate::native::etw_types::{ClockType, LogFileHeader};
+use crate::native::library::system_function;
+
 /// Wrapper for [FILETIME](https://learn.microsoft.com/en-us/windows/win32/api/minwinbase/ns-minwinbase-filetime)
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct FileTime(pub(crate) FILETIME);
 
@@ -57,6 +64,172 @@ impl FileTime {
     }
 }
 
+type GetSystemTimePreciseAsFileTimeFn = unsafe extern "system" fn(*mut FILETIME);
+
+/// `GetSystemTimePreciseAsFileTime` is only available on Windows 8 and later
+static GET_SYSTEM_TIME_PRECISE: Lazy<Option<GetSystemTimePreciseAsFileTimeFn>> =
+    Lazy::new(|| unsafe {
+        system_function(w!("kernel32.dll"), s!("GetSystemTimePreciseAsFileTime"))
+    });
+
+/// The current system time, as precise as this version of Windows allows
+///
+/// Older versions only provide the system time with the resolution of the system timer (~15 ms).
+fn system_time_now() -> FILETIME {
+    match *GET_SYSTEM_TIME_PRECISE {
+        Some(get_system_time_precise) => {
+            let mut now = FILETIME::default();
+            unsafe { get_system_time_precise(&mut now) };
+            now
+        }
+        None => unsafe { GetSystemTimeAsFileTime() },
+    }
+}
+
+/// The frequency of the performance counter (i.e. the clock of sessions that use the QPC clock resolution), in counts per second
+pub(crate) fn performance_frequency() -> Option<i64> {
+    let mut frequency = 0;
+    unsafe { QueryPerformanceFrequency(&mut frequency) }.ok()?;
+    Some(frequency)
+}
+
+/// Converts the raw timestamps of a session (see [`TraceBuilder::raw_timestamps`](crate::trace::TraceBuilder::raw_timestamps)) into system times
+///
+/// This relies on a reading of the session clock and a reading of the system time, taken at the same instant.
+/// Raw timestamps themselves are monotonic ticks of the clock of the session (see [`ClockType`]), that are best suited to measure durations (see [`Self::to_duration`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampConverter {
+    clock: ClockType,
+    ticks_per_second: i64,
+    raw_origin: i64,
+    quad_origin: i64,
+}
+
+impl TimestampConverter {
+    /// Create a converter, given the raw timestamp and the `FILETIME` quad of the same instant
+    pub fn new(clock: ClockType, ticks_per_second: i64, raw_origin: i64, quad_origin: i64) -> Self {
+        Self {
+            clock,
+            ticks_per_second,
+            raw_origin,
+            quad_origin,
+        }
+    }
+
+    /// Create a converter for the session this header has been read from
+    ///
+    /// The clock and its frequency are read from the header (`PerfFreq` for the QPC clock, the speed of the processor for the CPU cycle counter).
+    /// `raw_start` is the raw timestamp of the start of the session, i.e. of [`LogFileHeader::start_time`].<br/>
+    /// This returns `None` in case the header does not tell the frequency of its clock.
+    pub fn from_log_file_header(header: &LogFileHeader, raw_start: i64) -> Option<Self> {
+        let clock = header.clock()?;
+        let ticks_per_second = match clock {
+            ClockType::QueryPerformanceCounter => header.perf_freq,
+            ClockType::SystemTime => 10_000_000,
+            ClockType::CpuCycle => header.cpu_speed_mhz as i64 * 1_000_000,
+        };
+        if ticks_per_second <= 0 {
+            return None;
+        }
+        Some(Self::new(
+            clock,
+            ticks_per_second,
+            raw_start,
+            header.start_time,
+        ))
+    }
+
+    /// Read the clock of a session and the system time right now
+    ///
+    /// The frequency of the clock is read from the `header` of the session, or from the system in case it is missing.
+    /// This returns `None` in case the clock cannot be read.
+    pub(crate) fn now(clock: ClockType, header: &LogFileHeader) -> Option<Self> {
+        let now = system_time_now();
+        let quad_origin = ((now.dwHighDateTime as i64) << 32) | now.dwLowDateTime as i64;
+        match clock {
+            ClockType::SystemTime => Some(Self::new(clock, 10_000_000, quad_origin, quad_origin)),
+            ClockType::QueryPerformanceCounter => {
+                let mut counter = 0;
+                unsafe { QueryPerformanceCounter(&mut counter) }.ok()?;
+                let frequency = match header.perf_freq {
+                    0 => performance_frequency()?,
+                    frequency => frequency,
+                };
+                Some(Self::new(clock, frequency, counter, quad_origin))
+            }
+            ClockType::CpuCycle if header.cpu_speed_mhz != 0 => Some(Self::new(
+                clock,
+                header.cpu_speed_mhz as i64 * 1_000_000,
+                cycle_counter()?,
+                quad_origin,
+            )),
+            ClockType::CpuCycle => None,
+        }
+    }
+
+    pub fn clock(&self) -> ClockType {
+        self.clock
+    }
+
+    /// The frequency of the clock, in ticks per second
+    ///
+    /// For the CPU cycle counter, this is the nominal speed of the processor, so that conversions are approximate.
+    pub fn ticks_per_second(&self) -> i64 {
+        self.ticks_per_second
+    }
+
+    /// Convert a raw timestamp into a `FILETIME` quad (i.e. the number of 100-nanosecond intervals since January 1, 1601)
+    pub fn to_file_time(&self, raw: i64) -> i64 {
+        if self.clock == ClockType::SystemTime {
+            return raw;
+        }
+        self.quad_origin + (self.ticks_to_nanos(raw as i128 - self.raw_origin as i128) / 100) as i64
+    }
+
+    /// Convert a raw timestamp into a strongly-typed `time::OffsetDateTime`
+    #[cfg(feature = "time_rs")]
+    pub fn to_date_time(&self, raw: i64) -> time::OffsetDateTime {
+        FileTime::from_quad(self.to_file_time(raw)).into()
+    }
+
+    /// Convert a number of ticks (e.g. the difference between two raw timestamps) into a duration
+    ///
+    /// Negative numbers of ticks are converted into a zero duration.
+    pub fn to_duration(&self, ticks: i64) -> std::time::Duration {
+        let nanos = self.ticks_to_nanos(ticks.max(0) as i128);
+        std::time::Duration::from_nanos(nanos.min(u64::MAX as i128) as u64)
+    }
+
+    fn ticks_to_nanos(&self, ticks: i128) -> i128 {
+        match self.ticks_per_second {
+            0 => 0,
+            frequency => ticks * NS_IN_SECOND as i128 / frequency as i128,
+        }
+    }
+}
+
+/// The current value of the cycle counter of the processor
+#[cfg(target_arch = "x86_64")]
+fn cycle_counter() -> Option<i64> {
+    Some(unsafe { std::arch::x86_64::_rdtsc() } as i64)
+}
+
+#[cfg(target_arch = "x86")]
+fn cycle_counter() -> Option<i64> {
+    Some(unsafe { std::arch::x86::_rdtsc() } as i64)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+fn cycle_counter() -> Option<i64> {
+    None
+}
+
+/// Converts to the number of 100-nanosecond intervals since January 1, 1601 (i.e. a `FILETIME` quad, the clock of [`crate::EventRecord::raw_timestamp`])
+#[cfg(feature = "time_rs")]
+pub(crate) fn quad_from_date_time(date_time: time::OffsetDateTime) -> i64 {
+    (date_time.unix_timestamp_nanos() / 100) as i64 + SECONDS_BETWEEN_1601_AND_1970 * 10_000_000
+}
+
 #[cfg(feature = "time_rs")]
 impl From<FileTime> for time::OffsetDateTime {
     fn from(file_time: FileTime) -> Self {
@@ -84,7 +257,7 @@ impl serde::ser::Serialize for FileTime {
 }
 
 /// Wrapper for [SYSTEMTIME](https://learn.microsoft.com/en-us/windows/win32/api/minwinbase/ns-minwinbase-systemtime)
-#[derive(Copy, Clone, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct SystemTime(pub(crate) SYSTEMTIME);
 
@@ -154,3 +327,47 @@ impl serde::ser::Serialize for SystemTime {
         self.as_unix_timestamp().serialize(serializer)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timestamp_converter() {
+        // A 1 GHz cycle counter
+        let converter =
+            TimestampConverter::new(ClockType::CpuCycle, 1_000_000_000, 5_000, 1_000_000);
+        assert_eq!(converter.to_file_time(5_000), 1_000_000);
+        assert_eq!(
+            converter.to_file_time(5_000 + 1_000_000_000),
+            1_000_000 + 10_000_000
+        );
+        assert_eq!(converter.to_file_time(4_900), 1_000_000 - 1);
+        assert_eq!(converter.to_duration(1_500), Duration::from_nanos(1_500));
+        assert_eq!(converter.to_duration(-1), Duration::ZERO);
+
+        let converter = TimestampConverter::new(ClockType::SystemTime, 10_000_000, 0, 0);
+        assert_eq!(converter.to_file_time(123_456), 123_456);
+    }
+
+    #[test]
+    fn test_converter_from_header() {
+        let header = LogFileHeader {
+            clock_type: 1,
+            perf_freq: 10_000_000,
+            start_time: 133_000_000_000_000_000,
+            ..Default::default()
+        };
+        let converter = TimestampConverter::from_log_file_header(&header, 42).unwrap();
+        assert_eq!(converter.clock(), ClockType::QueryPerformanceCounter);
+        assert_eq!(converter.ticks_per_second(), 10_000_000);
+        assert_eq!(converter.to_file_time(42 + 10), header.start_time + 10);
+
+        let unknown_frequency = LogFileHeader {
+            clock_type: 3,
+            ..header
+        };
+        assert!(TimestampConverter::from_log_file_header(&unknown_frequency, 42).is_none());
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! This module act as a helper to parse the Buffer from an ETW Event
 
+use crate::native::code_page;
 use crate::native::etw_types::event_record::EventRecord;
 use crate::native::sddl;
 use crate::native::tdh;
@@ -11,14 +12,21 @@ use crate::native::tdh_types::{
 use crate::native::time::{FileTime, SystemTime};
 use crate::property::PropertySlice;
 use crate::schema::Schema;
-use std::collections::HashMap;
-use std::convert::TryInto;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::schema_locator::SchemaLocator;
+use crate::utils::internal_span;
+use std::borrow::Cow;
+use std::convert::{TryFrom, TryInto};
+use std::ffi::OsString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Mutex;
+use widestring::U16Str;
 use windows::core::GUID;
 
 /// Parser module errors
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ParserError {
     /// No property has this name
     NotFound,
@@ -28,6 +36,13 @@ pub enum ParserError {
     ParseError,
     /// Length mismatch when parsing a type
     LengthMismatch,
+    /// The size of a pointer property does not match the pointer size of its event
+    PointerSizeMismatch {
+        /// Pointer size of the event, in bytes
+        expected: usize,
+        /// Size of the property, in bytes
+        found: usize,
+    },
     PropertyError(String),
     /// An error while transforming an Utf-8 buffer into String
     Utf8Error(std::str::Utf8Error),
@@ -37,6 +52,12 @@ pub enum ParserError {
     SddlNativeError(crate::native::SddlNativeError),
     /// Represents an internal [TdhNativeError](crate::native::TdhNativeError)
     TdhNativeError(crate::native::TdhNativeError),
+    /// The property is not associated with any value map or bitmap
+    NoMap,
+    /// The property exists, but its type is not supported by this crate
+    Unsupported(crate::native::tdh_types::PropertyError),
+    /// The event is not the one a [`FromEvent`] implementation expects (e.g. another provider or event ID)
+    UnexpectedEvent,
 }
 
 impl From<crate::native::TdhNativeError> for ParserError {
@@ -70,11 +91,19 @@ impl std::fmt::Display for ParserError {
             Self::InvalidType => write!(f, "invalid type"),
             Self::ParseError => write!(f, "parse error"),
             Self::LengthMismatch => write!(f, "length mismatch"),
+            Self::PointerSizeMismatch { expected, found } => write!(
+                f,
+                "pointer size mismatch (expected {} bytes, found {})",
+                expected, found
+            ),
             Self::PropertyError(s) => write!(f, "property error {}", s),
             Self::Utf8Error(e) => write!(f, "utf-8 error {}", e),
             Self::SliceError(e) => write!(f, "slice error {}", e),
             Self::SddlNativeError(e) => write!(f, "sddl native error {}", e),
             Self::TdhNativeError(e) => write!(f, "tdh native error {}", e),
+            Self::NoMap => write!(f, "no value map"),
+            Self::Unsupported(e) => write!(f, "unsupported property: {}", e),
+            Self::UnexpectedEvent => write!(f, "unexpected event"),
         }
     }
 }
@@ -86,11 +115,48 @@ type ParserResult<T> = Result<T, ParserError>;
 ///
 /// This is useful because computing their offset can be costly
 struct CachedSlices<'schema, 'record> {
-    slices: HashMap<String, PropertySlice<'schema, 'record>>,
+    /// Properties are extracted in the order of the schema, starting after its fixed layout (see [`fixed_layout`])
+    slices: Vec<PropertySlice<'schema, 'record>>,
     /// The user buffer index we've cached up to
     last_cached_offset: usize,
 }
 
+/// How a [`Parser`] decodes `AnsiString` properties, see [`Parser::ansi_policy`]
+///
+/// Despite their name, these properties are not always UTF-8. They are often encoded with the code page of the process that emitted them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnsiStringPolicy {
+    /// Strings must be valid UTF-8, [`ParserError::Utf8Error`] is returned otherwise
+    #[default]
+    Strict,
+    /// Invalid UTF-8 sequences are replaced with `U+FFFD`
+    Lossy,
+    /// Strings are decoded with the ANSI code page of the system
+    SystemCodePage,
+    /// Strings are decoded with the given code page (e.g. `1252` for Western European)
+    CodePage(u32),
+}
+
+impl AnsiStringPolicy {
+    fn decode(self, buffer: &[u8]) -> ParserResult<String> {
+        match self {
+            AnsiStringPolicy::Strict => Ok(std::str::from_utf8(buffer)?.to_string()),
+            AnsiStringPolicy::Lossy => Ok(String::from_utf8_lossy(buffer).into_owned()),
+            AnsiStringPolicy::SystemCodePage => decode_code_page(code_page::CP_ACP, buffer),
+            AnsiStringPolicy::CodePage(cp) => decode_code_page(cp, buffer),
+        }
+    }
+}
+
+fn decode_code_page(code_page: u32, buffer: &[u8]) -> ParserResult<String> {
+    code_page::multi_byte_to_string(code_page, buffer).map_err(|err| {
+        ParserError::PropertyError(format!(
+            "invalid string for code page {}: {}",
+            code_page, err
+        ))
+    })
+}
+
 /// Represents a Parser
 ///
 /// This structure provides a way to parse an ETW event (= extract its properties).
@@ -119,8 +185,13 @@ struct CachedSlices<'schema, 'record> {
 #[allow(dead_code)]
 pub struct Parser<'schema, 'record> {
     properties: &'schema [Property],
+    /// Used to resolve value maps. This is `None` for parsers that are only built from a list of properties
+    schema: Option<&'schema Schema>,
     record: &'record EventRecord,
+    /// The location of the leading fixed-size properties, that can be extracted without walking the previous ones
+    fixed_layout: Cow<'schema, [Range<usize>]>,
     cache: Mutex<CachedSlices<'schema, 'record>>,
+    ansi_policy: AnsiStringPolicy,
 }
 
 impl<'schema, 'record> Parser<'schema, 'record> {
@@ -143,169 +214,186 @@ impl<'schema, 'record> Parser<'schema, 'record> {
         Parser {
             record: event_record,
             properties: schema.properties(),
+            schema: Some(schema),
+            fixed_layout: Cow::Borrowed(schema.fixed_layout(event_record.pointer_size())),
             cache: Mutex::new(CachedSlices::default()),
+            ansi_policy: AnsiStringPolicy::default(),
         }
     }
 
-    #[allow(clippy::len_zero)]
+    /// Set how `AnsiString` properties are decoded when parsed into a `String`
+    ///
+    /// By default, they must be valid UTF-8 ([`AnsiStringPolicy::Strict`]).
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// # use ferrisetw::parser::{AnsiStringPolicy, Parser};
+    /// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///     let schema = schema_locator.event_schema(record).unwrap();
+    ///     let parser = Parser::create(record, &schema).ansi_policy(AnsiStringPolicy::SystemCodePage);
+    ///     let command_line: Option<String> = parser.try_parse("CommandLine").ok();
+    /// };
+    /// ```
+    pub fn ansi_policy(mut self, policy: AnsiStringPolicy) -> Self {
+        self.ansi_policy = policy;
+        self
+    }
+
+    /// Create a Parser from a list of properties, rather than from a [`Schema`]
+    #[cfg(test)]
+    pub(crate) fn from_properties(
+        event_record: &'record EventRecord,
+        properties: &'schema [Property],
+    ) -> Self {
+        Parser {
+            record: event_record,
+            properties,
+            schema: None,
+            fixed_layout: Cow::Owned(fixed_layout(properties, event_record.pointer_size())),
+            cache: Mutex::new(CachedSlices::default()),
+            ansi_policy: AnsiStringPolicy::default(),
+        }
+    }
+
+    /// `walked` are the properties that follow the fixed layout and precede this one, as they have been located already.
     fn find_property_size(
         &self,
         property: &Property,
         remaining_user_buffer: &[u8],
+        walked: &[PropertySlice],
     ) -> ParserResult<usize> {
-        match property.info {
-            PropertyInfo::Value {
-                in_type, length, ..
-            } => {
-                // There are several cases
-                //  * regular case, where property.len() directly makes sense
-                //  * but EVENT_PROPERTY_INFO.length is an union, and (in its lengthPropertyIndex form) can refeer to another field
-                //    e.g.: the WinInet provider manifest has fields such as `<data name="Verb" inType="win:AnsiString" length="_VerbLength"/>`
-                //    In this case, we defer to TDH to know the right length.
-
-                // For pointer input type we can immediately infer the size based on the header flags.
-                if in_type == TdhInType::InTypePointer {
-                    return Ok(self.record.pointer_size());
-                }
-
-                let prop_len = match length {
-                    PropertyLength::Length(l) => l,
-                    PropertyLength::Index(_) => {
-                        // TODO optimize to cache the lookup, the problem is here this is called under an
-                        // exclusive mutex, so attempting to extract and cache a related property will
-                        // deadlock.
-                        return Ok(tdh::property_size(self.record, &property.name)? as usize);
-                    }
-                };
+        // EVENT_PROPERTY_INFO.length is an union, and (in its lengthPropertyIndex form) can refer to another field
+        // e.g.: the WinInet provider manifest has fields such as `<data name="Verb" inType="win:AnsiString" length="_VerbLength"/>`
+        // The count of an array can refer to another field as well. Such fields always precede the property, we have located them already.
+        let sibling = |index| self.sibling_value(index, walked);
+        if let Some(size) = known_property_size(property, self.record.pointer_size(), sibling)? {
+            return Ok(size);
+        }
 
-                if prop_len > 0 {
-                    return Ok(prop_len as usize);
-                }
+        if let PropertyInfo::Value {
+            in_type,
+            length: PropertyLength::Length(_),
+            ..
+        } = property.info
+        {
+            // Length is not set. We'll have to ask TDH for the right length.
+            // However, before doing so, there are some cases where we could determine ourselves.
+            // The following _very_ common property types can be short-circuited to prevent the expensive call.
+            // (that's taken from krabsetw)
+
+            if let Some(l) = counted_string_length(in_type, remaining_user_buffer) {
+                // Include the length prefix
+                return Ok(l + 2);
+            }
 
-                // Length is not set. We'll have to ask TDH for the right length.
-                // However, before doing so, there are some cases where we could determine ourselves.
-                // The following _very_ common property types can be short-circuited to prevent the expensive call.
-                // (that's taken from krabsetw)
-
-                match in_type {
-                    TdhInType::InTypeAnsiString => {
-                        let mut l = 0;
-                        for char in remaining_user_buffer {
-                            if char == &0 {
-                                l += 1; // include the final null byte
-                                break;
-                            }
-                            l += 1;
+            match in_type {
+                TdhInType::InTypeAnsiString => {
+                    let mut l = 0;
+                    for char in remaining_user_buffer {
+                        if char == &0 {
+                            l += 1; // include the final null byte
+                            break;
                         }
-                        return Ok(l);
+                        l += 1;
                     }
-                    TdhInType::InTypeUnicodeString => {
-                        let mut l = 0;
-                        for bytes in remaining_user_buffer.chunks_exact(2) {
-                            if bytes[0] == 0 && bytes[1] == 0 {
-                                l += 2;
-                                break;
-                            }
+                    return Ok(l);
+                }
+                TdhInType::InTypeUnicodeString => {
+                    let mut l = 0;
+                    for bytes in remaining_user_buffer.chunks_exact(2) {
+                        if bytes[0] == 0 && bytes[1] == 0 {
                             l += 2;
+                            break;
                         }
-                        return Ok(l);
+                        l += 2;
                     }
-                    _ => (),
+                    return Ok(l);
                 }
-
-                Ok(tdh::property_size(self.record, &property.name)? as usize)
+                _ => (),
             }
-            PropertyInfo::Array {
-                in_type,
-                length,
-                count,
-                ..
-            } => {
-                // For pointer input type we can immediately infer the size based on the header flags.
-                let prop_len = if in_type == TdhInType::InTypePointer {
-                    self.record.pointer_size()
-                } else {
-                    match length {
-                        PropertyLength::Length(l) => l as usize,
-                        PropertyLength::Index(_) => {
-                            // TODO optimize to cache the lookup, the problem is here this is called under an
-                            // exclusive mutex, so attempting to extract and cache a related property will
-                            // deadlock.
-                            return Ok(tdh::property_size(self.record, &property.name)? as usize);
-                        }
-                    }
-                };
+        }
 
-                let prop_count = match count {
-                    PropertyCount::Count(c) => c as usize,
-                    PropertyCount::Index(_) => {
-                        // TODO optimize to cache the lookup, the problem is here this is called under an
-                        // exclusive mutex, so attempting to extract and cache a related property will
-                        // deadlock.
-                        return Ok(tdh::property_size(self.record, &property.name)? as usize);
-                    }
-                };
+        // We cannot parse it, but TDH can tell where it ends
+        Ok(tdh::property_size(self.record, &property.name)? as usize)
+    }
 
-                if prop_len > 0 {
-                    return Ok(prop_len * prop_count);
-                }
+    /// The value of an integer property, that another property refers to as its length or count
+    ///
+    /// This is `None` in case the property has not been located, or is not an integer.
+    fn sibling_value(&self, index: u16, walked: &[PropertySlice]) -> Option<usize> {
+        let index = usize::from(index);
+        let buffer = match self.fixed_layout.get(index) {
+            Some(range) => self.record.user_buffer().get(range.clone())?,
+            None => walked.get(index - self.fixed_layout.len())?.buffer,
+        };
+        let value = match buffer.len() {
+            1 => u64::from(buffer[0]),
+            2 => u64::from(u16::from_ne_bytes(buffer.try_into().ok()?)),
+            4 => u64::from(u32::from_ne_bytes(buffer.try_into().ok()?)),
+            8 => u64::from_ne_bytes(buffer.try_into().ok()?),
+            _ => return None,
+        };
+        usize::try_from(value).ok()
+    }
 
-                Ok(tdh::property_size(self.record, &property.name)? as usize)
-            }
+    fn find_property(&self, key: PropertyKey) -> ParserResult<PropertySlice<'schema, 'record>> {
+        let index = match key {
+            PropertyKey::Index(index) => index,
+            PropertyKey::Name(name) => self
+                .properties
+                .iter()
+                .position(|property| property.name == name)
+                .ok_or(ParserError::NotFound)?,
+        };
+        if index >= self.properties.len() {
+            return Err(ParserError::NotFound);
         }
-    }
 
-    fn find_property(&self, name: &str) -> ParserResult<PropertySlice<'schema, 'record>> {
+        // Leading fixed-size properties are at the same offset in every event of this schema
+        if let Some(range) = self.fixed_layout.get(index) {
+            let buffer = self
+                .record
+                .user_buffer()
+                .get(range.clone())
+                .ok_or_else(|| {
+                    ParserError::PropertyError("Property length out of buffer bounds".to_owned())
+                })?;
+            return supported(PropertySlice {
+                property: &self.properties[index],
+                buffer,
+            });
+        }
+        let first_walked_property = self.fixed_layout.len();
+        let cache_index = index - first_walked_property;
+
         let mut cache = self.cache.lock().unwrap();
 
         // We may have extracted this property already
-        if let Some(p) = cache.slices.get(name) {
-            return Ok(*p);
+        if let Some(p) = cache.slices.get(cache_index) {
+            return supported(*p);
         }
 
-        let last_cached_property = cache.slices.len();
-        let properties_not_parsed_yet = match self.properties.get(last_cached_property..) {
-            Some(s) => s,
-            // If we've parsed every property already, that means no property matches this name
-            None => return Err(ParserError::NotFound),
-        };
-
-        for property in properties_not_parsed_yet {
-            let remaining_user_buffer =
-                match self.record.user_buffer().get(cache.last_cached_offset..) {
-                    None => {
-                        return Err(ParserError::PropertyError(
-                            "Invalid buffer bounds".to_owned(),
-                        ))
-                    }
-                    Some(s) => s,
-                };
-
-            let prop_size = self.find_property_size(property, remaining_user_buffer)?;
-            let property_buffer = match remaining_user_buffer.get(..prop_size) {
-                None => {
-                    return Err(ParserError::PropertyError(
-                        "Property length out of buffer bounds".to_owned(),
-                    ))
-                }
-                Some(s) => s,
-            };
-
-            let prop_slice = PropertySlice {
+        let _span = internal_span!(
+            "parse_properties",
+            property = self.properties[index].name.as_str()
+        );
+        if cache.slices.is_empty() {
+            cache.last_cached_offset = self.fixed_layout.last().map_or(0, |range| range.end);
+        }
+        let last_cached_property = first_walked_property + cache.slices.len();
+        for property in &self.properties[last_cached_property..=index] {
+            let property_buffer =
+                self.property_buffer(property, cache.last_cached_offset, &cache.slices)?;
+            cache.slices.push(PropertySlice {
                 property,
                 buffer: property_buffer,
-            };
-            cache
-                .slices
-                .insert(String::clone(&property.name), prop_slice);
-            cache.last_cached_offset += prop_size;
-
-            if property.name == name {
-                return Ok(prop_slice);
-            }
+            });
+            cache.last_cached_offset += property_buffer.len();
         }
 
-        Err(ParserError::NotFound)
+        supported(cache.slices[cache_index])
     }
 
     /// Return a property from the event, or an error in case the parsing failed.
@@ -317,11 +405,255 @@ impl<'schema, 'record> Parser<'schema, 'record> {
         Parser<'schema, 'record>: private::TryParse<T>,
     {
         use crate::parser::private::TryParse;
-        self.try_parse_impl(name)
+        self.try_parse_impl(PropertyKey::Name(name))
+    }
+
+    /// Same as [`try_parse`](Self::try_parse), for the property at this index in the schema (see [`Schema::property_index`])
+    ///
+    /// This saves looking the property up by name, which matters when millions of events of the same kind are processed:
+    /// indexes can be resolved once per schema, and used for every event afterwards.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// # use ferrisetw::parser::Parser;
+    /// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///     let schema = schema_locator.event_schema(record).unwrap();
+    ///     // This could be cached, e.g. along with the fingerprint of the schema (see `Schema::fingerprint`)
+    ///     let process_id = schema.property_index("ProcessID").unwrap();
+    ///
+    ///     let parser = Parser::create(record, &schema);
+    ///     let process_id: u32 = parser.try_parse_at(process_id).unwrap();
+    /// };
+    /// ```
+    pub fn try_parse_at<T>(&self, index: usize) -> ParserResult<T>
+    where
+        Parser<'schema, 'record>: private::TryParse<T>,
+    {
+        use crate::parser::private::TryParse;
+        self.try_parse_impl(PropertyKey::Index(index))
+    }
+
+    /// Return the raw bytes of a property, along with the types its manifest declares
+    ///
+    /// This is useful for properties that need a custom decoding (e.g. vendor-specific binary blobs), as the crate does not try to interpret them.<br/>
+    /// The returned slice borrows the user buffer of the event, and is thus not aligned in any particular way.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// # use ferrisetw::parser::Parser;
+    /// # use ferrisetw::native::TdhInType;
+    /// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///     let schema = schema_locator.event_schema(record).unwrap();
+    ///     let parser = Parser::create(record, &schema);
+    ///     let (bytes, in_type, _out_type) = parser.raw_property("Blob").unwrap();
+    ///     if in_type == TdhInType::InTypeBinary {
+    ///         println!("{} bytes", bytes.len());
+    ///     }
+    /// };
+    /// ```
+    pub fn raw_property(&self, name: &str) -> ParserResult<(&'record [u8], TdhInType, TdhOutType)> {
+        let prop_slice = self.find_property(PropertyKey::Name(name))?;
+        match prop_slice.property.info {
+            PropertyInfo::Value {
+                in_type, out_type, ..
+            }
+            | PropertyInfo::Array {
+                in_type, out_type, ..
+            } => Ok((prop_slice.buffer, in_type, out_type)),
+            PropertyInfo::Unsupported { ref error } => Err(ParserError::Unsupported(error.clone())),
+        }
+    }
+
+    /// Return the name the provider gives to the value of a property, using the `map` this property refers to
+    ///
+    /// This gives e.g. `"STATUS_ACCESS_DENIED"` rather than `0xc0000022`.<br/>
+    /// For bitmaps, the names of every set bit are joined with `" | "`. Values that are not part of the map are formatted in hexadecimal.<br/>
+    /// Maps are retrieved from TDH on first use, and cached in the [Schema].
+    ///
+    /// [`ParserError::NoMap`] is returned in case the property does not refer to any map.
+    pub fn try_parse_map(&self, name: &str) -> ParserResult<String> {
+        let prop_slice = self.find_property(PropertyKey::Name(name))?;
+        let map_name = prop_slice
+            .property
+            .map_name
+            .as_deref()
+            .ok_or(ParserError::NoMap)?;
+
+        let value = match prop_slice.property.info {
+            PropertyInfo::Value { .. } => match prop_slice.buffer.len() {
+                1 => prop_slice.buffer[0] as u32,
+                2 => u16::from_ne_bytes(prop_slice.buffer.try_into()?) as u32,
+                4 => u32::from_ne_bytes(prop_slice.buffer.try_into()?),
+                _ => return Err(ParserError::LengthMismatch),
+            },
+            _ => return Err(ParserError::InvalidType),
+        };
+
+        let schema = self.schema.ok_or(ParserError::NoMap)?;
+        let map = schema.event_map(self.record, map_name)?;
+        Ok(map.resolve(value))
+    }
+
+    /// Decode every property of the event, in the order of the schema
+    ///
+    /// This walks the user buffer once, without looking properties up by name, and is thus cheaper than calling
+    /// [`try_parse`](Self::try_parse) for every property when all of them are needed (e.g. to log or forward whole events).<br/>
+    /// It does not populate the cache used by `try_parse`.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// # use ferrisetw::parser::Parser;
+    /// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///     let schema = schema_locator.event_schema(record).unwrap();
+    ///     let parser = Parser::create(record, &schema);
+    ///     for (name, value) in parser.parse_all().unwrap() {
+    ///         println!("{}: {:?}", name, value);
+    ///     }
+    /// };
+    /// ```
+    pub fn parse_all(&self) -> ParserResult<Vec<(&'schema str, PropertyValue)>> {
+        self.iter_properties().collect()
+    }
+
+    /// Iterate over the properties of the event, decoded as [`PropertyValue`]s, in the order of the schema
+    ///
+    /// This is the lazy counterpart of [`parse_all`](Self::parse_all), for generic consumers (loggers, exporters...) that handle events they do not know the layout of.
+    /// A property that cannot be decoded yields an `Err`, and iteration goes on with the next property.
+    /// Iteration stops after an `Err` in case the size of a property cannot be determined, as the next properties cannot be located.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::EventRecord;
+    /// # use ferrisetw::schema_locator::SchemaLocator;
+    /// # use ferrisetw::parser::{Parser, PropertyValue};
+    /// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+    ///     let schema = schema_locator.event_schema(record).unwrap();
+    ///     let parser = Parser::create(record, &schema);
+    ///     for (name, value) in parser.iter_properties().flatten() {
+    ///         if let PropertyValue::String(value) = value {
+    ///             println!("{}: {}", name, value);
+    ///         }
+    ///     }
+    /// };
+    /// ```
+    pub fn iter_properties(&self) -> PropertyValues<'_, 'schema, 'record> {
+        PropertyValues {
+            parser: self,
+            properties: self.properties.iter(),
+            offset: 0,
+            walked: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// The buffer of a property, that starts at `offset` in the user buffer
+    fn property_buffer(
+        &self,
+        property: &Property,
+        offset: usize,
+        walked: &[PropertySlice],
+    ) -> ParserResult<&'record [u8]> {
+        let remaining_user_buffer = self
+            .record
+            .user_buffer()
+            .get(offset..)
+            .ok_or_else(|| ParserError::PropertyError("Invalid buffer bounds".to_owned()))?;
+        let prop_size = self.find_property_size(property, remaining_user_buffer, walked)?;
+        remaining_user_buffer.get(..prop_size).ok_or_else(|| {
+            ParserError::PropertyError("Property length out of buffer bounds".to_owned())
+        })
+    }
+}
+
+/// An iterator over the decoded properties of an event, see [`Parser::iter_properties`]
+pub struct PropertyValues<'parser, 'schema, 'record> {
+    parser: &'parser Parser<'schema, 'record>,
+    properties: std::slice::Iter<'schema, Property>,
+    offset: usize,
+    /// The properties that follow the fixed layout, as far as they have been iterated over
+    walked: Vec<PropertySlice<'schema, 'record>>,
+    /// Set once a property could not be located, since the next ones cannot be either
+    done: bool,
+}
+
+impl<'parser, 'schema, 'record> Iterator for PropertyValues<'parser, 'schema, 'record> {
+    type Item = ParserResult<(&'schema str, PropertyValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let index = self.parser.properties.len() - self.properties.len();
+        let property = self.properties.next()?;
+        let buffer = match self
+            .parser
+            .property_buffer(property, self.offset, &self.walked)
+        {
+            Ok(buffer) => buffer,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err));
+            }
+        };
+        self.offset += buffer.len();
+        let slice = PropertySlice { property, buffer };
+        if index >= self.parser.fixed_layout.len() {
+            self.walked.push(slice);
+        }
+
+        let value = self.parser.decode_value(slice);
+        Some(value.map(|value| (property.name.as_str(), value)))
     }
 }
 
-mod private {
+/// A type that can be built from the properties of an event
+///
+/// This is usually derived with `#[derive(ferrisetw::Event)]`, which requires the `derive` feature.
+/// Each field is then parsed with [`Parser::try_parse`], see the documentation of the derive macro for the supported attributes.
+///
+/// ```
+/// # #[cfg(feature = "derive")] {
+/// use ferrisetw::parser::FromEvent;
+/// # use ferrisetw::EventRecord;
+/// # use ferrisetw::schema_locator::SchemaLocator;
+///
+/// #[derive(ferrisetw::Event)]
+/// #[etw(provider = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716", id = 2)]
+/// struct ProcessStop {
+///     #[etw(rename = "ProcessID")]
+///     process_id: u32,
+///     #[etw(rename = "ExitCode")]
+///     exit_code: u32,
+///     #[etw(rename = "ImageName")]
+///     image_name: String,
+/// }
+///
+/// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+///     if let Ok(stop) = ProcessStop::from_event(record, schema_locator) {
+///         println!("{} ({}) exited with {}", stop.image_name, stop.process_id, stop.exit_code);
+///     }
+/// };
+/// # }
+/// ```
+pub trait FromEvent: Sized {
+    /// Build `Self` from an event, whose properties are read from `parser`
+    fn from_parser(record: &EventRecord, parser: &Parser<'_, '_>) -> ParserResult<Self>;
+
+    /// Build `Self` from an event, after locating its schema
+    fn from_event(record: &EventRecord, schema_locator: &SchemaLocator) -> crate::Result<Self> {
+        let schema = schema_locator.event_schema(record)?;
+        let parser = Parser::create(record, &schema);
+        Ok(Self::from_parser(record, &parser)?)
+    }
+}
+
+pub(crate) mod private {
     use super::*;
 
     /// Trait to try and parse a type
@@ -336,16 +668,26 @@ mod private {
         /// return an Error in case the type `T` can't be parsed
         ///
         /// # Arguments
-        /// * `name` - Name of the property to be found in the Schema
-        fn try_parse_impl(&self, name: &str) -> Result<T, ParserError>;
+        /// * `key` - Name (or index) of the property to be found in the Schema
+        fn try_parse_impl(&self, key: PropertyKey<'_>) -> Result<T, ParserError>;
+    }
+
+    /// How a property is looked up, see [`Parser::try_parse`] and [`Parser::try_parse_at`]
+    #[derive(Debug, Clone, Copy)]
+    pub enum PropertyKey<'a> {
+        Name(&'a str),
+        /// The index of the property in the schema
+        Index(usize),
     }
 }
 
+use private::PropertyKey;
+
 macro_rules! impl_try_parse_primitive {
     ($T:ident) => {
         impl private::TryParse<$T> for Parser<'_, '_> {
-            fn try_parse_impl(&self, name: &str) -> ParserResult<$T> {
-                let prop_slice = self.find_property(name)?;
+            fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<$T> {
+                let prop_slice = self.find_property(key)?;
 
                 match prop_slice.property.info {
                     PropertyInfo::Value { .. } => {
@@ -365,8 +707,8 @@ macro_rules! impl_try_parse_primitive {
 macro_rules! impl_try_parse_primitive_array {
     ($T:ident) => {
         impl<'schema, 'record> private::TryParse<&'record [$T]> for Parser<'schema, 'record> {
-            fn try_parse_impl(&self, name: &str) -> ParserResult<&'record [$T]> {
-                let prop_slice = self.find_property(name)?;
+            fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<&'record [$T]> {
+                let prop_slice = self.find_property(key)?;
 
                 match prop_slice.property.info {
                     PropertyInfo::Array { .. } => {
@@ -433,7 +775,7 @@ impl_try_parse_primitive_array!(i64);
 ///
 /// * InTypeUnicodeString
 /// * InTypeAnsiString
-/// * InTypeCountedString
+/// * InTypeCountedString (and its ANSI and reversed variants)
 /// * InTypeGuid
 ///
 /// On success a `String` with the with the data from the `name` property will be returned
@@ -455,60 +797,382 @@ impl_try_parse_primitive_array!(i64);
 ///
 /// [TdhInTypes]: TdhInType
 impl private::TryParse<String> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> ParserResult<String> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<String> {
+        let prop_slice = self.find_property(key)?;
 
         match prop_slice.property.info {
-            PropertyInfo::Value { in_type, .. } => match in_type {
-                TdhInType::InTypeUnicodeString => {
-                    if prop_slice.buffer.len() % 2 != 0 {
-                        return Err(ParserError::PropertyError(
-                            "odd length in bytes for a wide string".into(),
-                        ));
-                    }
+            PropertyInfo::Value { in_type, .. } => {
+                self.decode_string(prop_slice.property, in_type, prop_slice.buffer)
+            }
+            _ => Err(ParserError::InvalidType),
+        }
+    }
+}
 
-                    // std::slice::from_raw_parts requires a pointer to be aligned, but we can't
-                    // guarantee that the buffer is aligned. In testing, I found that the buffer
-                    // is in fact never aligned appropriately, so a cheap workaround is to copy
-                    // the buffer into a new Vec<u16> and use that as the source for the slice
-                    // until we can find a better solution.
-                    let mut aligned_buffer = Vec::with_capacity(prop_slice.buffer.len() / 2);
-                    for chunk in prop_slice.buffer.chunks_exact(2) {
-                        let part = u16::from_ne_bytes([chunk[0], chunk[1]]);
-                        aligned_buffer.push(part);
-                    }
+/// Returns an `Err` in case this property cannot be parsed
+fn supported<'schema, 'record>(
+    prop_slice: PropertySlice<'schema, 'record>,
+) -> ParserResult<PropertySlice<'schema, 'record>> {
+    match &prop_slice.property.info {
+        PropertyInfo::Unsupported { error } => Err(ParserError::Unsupported(error.clone())),
+        _ => Ok(prop_slice),
+    }
+}
 
-                    let mut wide = aligned_buffer.as_slice();
+/// Whether the manifest declares a fixed length for this property (rather than a null-terminated or parametrized one)
+fn has_fixed_length(property: &Property) -> bool {
+    matches!(
+        property.info,
+        PropertyInfo::Value {
+            length: PropertyLength::Length(l),
+            ..
+        } if l > 0
+    )
+}
 
-                    match wide.last() {
-                        // remove the null terminator from the slice
-                        Some(c) if c == &0 => wide = &wide[..wide.len() - 1],
-                        _ => (),
-                    }
+/// The size of a property, in case it is declared by the schema, or by the properties it refers to
+///
+/// `sibling` returns the value of the property at a given index, for lengths and counts that refer to another property.
+/// These values come from the event itself: in case they make the size overflow, this returns a [`ParserError::LengthMismatch`].
+fn known_property_size(
+    property: &Property,
+    pointer_size: usize,
+    sibling: impl Fn(u16) -> Option<usize>,
+) -> ParserResult<Option<usize>> {
+    let size = match property.info {
+        // For pointer input type we can immediately infer the size based on the header flags.
+        PropertyInfo::Value {
+            in_type: TdhInType::InTypePointer,
+            ..
+        } => Some(pointer_size),
+        PropertyInfo::Value {
+            in_type, length, ..
+        } => {
+            let length = match length {
+                PropertyLength::Length(l) if l > 0 => usize::from(l),
+                // Length is not set, the size depends on the content of the property
+                PropertyLength::Length(_) => return Ok(None),
+                PropertyLength::Index(index) => match sibling(index) {
+                    Some(length) => length,
+                    None => return Ok(None),
+                },
+            };
+            // Sized strings (e.g. char arrays) have their length expressed in characters, not in bytes
+            if in_type == TdhInType::InTypeUnicodeString {
+                length.checked_mul(2)
+            } else {
+                Some(length)
+            }
+        }
+        PropertyInfo::Array {
+            in_type,
+            length,
+            count,
+            ..
+        } => {
+            let element_size = match (in_type, length) {
+                (TdhInType::InTypePointer, _) => pointer_size,
+                (_, PropertyLength::Length(l)) if l > 0 => usize::from(l),
+                (_, PropertyLength::Length(_)) => return Ok(None),
+                (_, PropertyLength::Index(index)) => match sibling(index) {
+                    Some(length) => length,
+                    None => return Ok(None),
+                },
+            };
+            let count = match count {
+                PropertyCount::Count(c) => usize::from(c),
+                PropertyCount::Index(index) => match sibling(index) {
+                    Some(count) => count,
+                    None => return Ok(None),
+                },
+            };
+            // Same as above, for arrays of sized strings
+            if in_type == TdhInType::InTypeUnicodeString {
+                element_size.checked_mul(2)
+            } else {
+                Some(element_size)
+            }
+            .and_then(|element_size| element_size.checked_mul(count))
+        }
+        PropertyInfo::Unsupported { .. } => return Ok(None),
+    };
+    size.map(Some).ok_or(ParserError::LengthMismatch)
+}
 
-                    // Decode UTF-16 to String
-                    Ok(widestring::decode_utf16_lossy(wide.iter().copied()).collect::<String>())
-                }
-                TdhInType::InTypeAnsiString => {
-                    let string = std::str::from_utf8(prop_slice.buffer)?;
-                    Ok(string.trim_matches(char::default()).to_string())
-                }
-                TdhInType::InTypeSid => {
-                    let string =
-                        sddl::convert_sid_to_string(prop_slice.buffer.as_ptr() as *const _)?;
-                    Ok(string)
+/// Where the leading properties of an event are, in case their sizes are fixed
+///
+/// This lists the ranges (in the user buffer) of the properties that are only preceded by fixed-size properties, and are fixed-size themselves.
+/// They are the same for every event of a given schema and bitness, so that [`Schema`] caches them.
+pub(crate) fn fixed_layout(properties: &[Property], pointer_size: usize) -> Vec<Range<usize>> {
+    let mut offset = 0;
+    properties
+        .iter()
+        .map_while(|property| {
+            let size = known_property_size(property, pointer_size, |_| None).ok()??;
+            let range = offset..offset + size;
+            offset += size;
+            Some(range)
+        })
+        .collect()
+}
+
+/// The length (in bytes, without the prefix itself) of a counted string, as read from its 2-byte prefix
+///
+/// This returns `None` for other types, or in case the buffer is too short to contain the prefix.
+fn counted_string_length(in_type: TdhInType, buffer: &[u8]) -> Option<usize> {
+    let prefix: [u8; 2] = buffer.get(..2)?.try_into().ok()?;
+    let length = match in_type {
+        TdhInType::InTypeCountedString | TdhInType::InTypeCountedAnsiString => {
+            u16::from_le_bytes(prefix)
+        }
+        TdhInType::InTypeReversedCountedString | TdhInType::InTypeReversedCountedAnsiString => {
+            u16::from_be_bytes(prefix)
+        }
+        _ => return None,
+    };
+    Some(length as usize)
+}
+
+/// The characters of a counted string property, without their length prefix
+fn counted_string(in_type: TdhInType, buffer: &[u8]) -> ParserResult<&[u8]> {
+    let length = counted_string_length(in_type, buffer).ok_or(ParserError::LengthMismatch)?;
+    buffer.get(2..2 + length).ok_or(ParserError::LengthMismatch)
+}
+
+/// Copy a wide string property into a (correctly aligned) `Vec<u16>`, without its final null terminator (if any)
+fn aligned_wide_string(buffer: &[u8]) -> ParserResult<Vec<u16>> {
+    if buffer.len() % 2 == 1 {
+        return Err(ParserError::PropertyError(
+            "odd length in bytes for a wide string".into(),
+        ));
+    }
+
+    // std::slice::from_raw_parts requires a pointer to be aligned, but we can't
+    // guarantee that the buffer is aligned. In testing, I found that the buffer
+    // is in fact never aligned appropriately, so a cheap workaround is to copy
+    // the buffer into a new Vec<u16> and use that as the source for the slice
+    // until we can find a better solution.
+    let mut aligned_buffer = Vec::with_capacity(buffer.len() / 2);
+    for chunk in buffer.chunks_exact(2) {
+        let part = u16::from_ne_bytes([chunk[0], chunk[1]]);
+        aligned_buffer.push(part);
+    }
+
+    // remove the null terminator
+    if aligned_buffer.last() == Some(&0) {
+        aligned_buffer.pop();
+    }
+
+    Ok(aligned_buffer)
+}
+
+/// The `OsString` impl of the `TryParse` trait should be used to retrieve `InTypeUnicodeString` properties
+/// that must be kept as-is, e.g. because they may contain invalid UTF-16 that the `String` impl would lossily replace.
+///
+/// This is typically useful for file paths, that can then be fed back into Win32 APIs.
+impl private::TryParse<OsString> for Parser<'_, '_> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<OsString> {
+        let prop_slice = self.find_property(key)?;
+
+        match prop_slice.property.info {
+            PropertyInfo::Value {
+                in_type: TdhInType::InTypeUnicodeString,
+                ..
+            } => {
+                let wide = aligned_wide_string(prop_slice.buffer)?;
+                Ok(widestring::U16Str::from_slice(&wide).to_os_string())
+            }
+            _ => Err(ParserError::InvalidType),
+        }
+    }
+}
+
+/// The `PathBuf` impl of the `TryParse` trait can be used for file-name properties.
+///
+/// See the `OsString` impl for more info.
+///
+/// # Example
+/// ```
+/// # use std::path::PathBuf;
+/// # use ferrisetw::EventRecord;
+/// # use ferrisetw::schema_locator::SchemaLocator;
+/// # use ferrisetw::parser::Parser;
+/// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+///     let schema = schema_locator.event_schema(record).unwrap();
+///     let parser = Parser::create(record, &schema);
+///     let file_name: PathBuf = parser.try_parse("FileName").unwrap();
+/// };
+/// ```
+impl private::TryParse<PathBuf> for Parser<'_, '_> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<PathBuf> {
+        private::TryParse::<OsString>::try_parse_impl(self, key).map(PathBuf::from)
+    }
+}
+
+/// The characters of an ANSI string property, without its length prefix nor its null terminator(s)
+fn ansi_string_bytes<'a>(
+    property: &Property,
+    in_type: TdhInType,
+    buffer: &'a [u8],
+) -> ParserResult<&'a [u8]> {
+    let mut bytes = match in_type {
+        TdhInType::InTypeAnsiString => buffer,
+        TdhInType::InTypeCountedAnsiString | TdhInType::InTypeReversedCountedAnsiString => {
+            counted_string(in_type, buffer)?
+        }
+        _ => return Err(ParserError::InvalidType),
+    };
+    if in_type == TdhInType::InTypeAnsiString && has_fixed_length(property) {
+        // Fixed-size buffers are padded with nulls (or garbage) after the string
+        let end = bytes.iter().position(|c| *c == 0).unwrap_or(bytes.len());
+        bytes = &bytes[..end];
+    }
+    // Same as the `String` impl, that trims null characters on both ends
+    while let [0, rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., 0] = bytes {
+        bytes = rest;
+    }
+    Ok(bytes)
+}
+
+/// The `&str` impl of the `TryParse` trait borrows `InTypeAnsiString` properties (and their counted variants) from the record, without allocating.
+///
+/// The string must be valid UTF-8, whatever the [`AnsiStringPolicy`] of the parser: [`ParserError::Utf8Error`] is returned otherwise.
+/// See the `Cow<str>` impl to borrow whenever possible, and decode otherwise.
+///
+/// # Example
+/// ```
+/// # use ferrisetw::EventRecord;
+/// # use ferrisetw::schema_locator::SchemaLocator;
+/// # use ferrisetw::parser::Parser;
+/// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+///     let schema = schema_locator.event_schema(record).unwrap();
+///     let parser = Parser::create(record, &schema);
+///     if parser.try_parse::<&str>("QueryName").ok() == Some("example.com") {
+///         println!("example.com has been queried");
+///     }
+/// };
+/// ```
+impl<'schema, 'record> private::TryParse<&'record str> for Parser<'schema, 'record> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<&'record str> {
+        let prop_slice = self.find_property(key)?;
+
+        match prop_slice.property.info {
+            PropertyInfo::Value { in_type, .. } => {
+                let bytes = ansi_string_bytes(prop_slice.property, in_type, prop_slice.buffer)?;
+                Ok(std::str::from_utf8(bytes)?)
+            }
+            _ => Err(ParserError::InvalidType),
+        }
+    }
+}
+
+/// The `Cow<str>` impl of the `TryParse` trait accepts the same properties as the `String` impl.
+///
+/// ANSI strings are borrowed from the record when they are valid UTF-8 (and the [`AnsiStringPolicy`] is [`AnsiStringPolicy::Strict`] or [`AnsiStringPolicy::Lossy`]).
+/// Other strings (including every UTF-16 string) are decoded into an owned `String`.
+impl<'schema, 'record> private::TryParse<Cow<'record, str>> for Parser<'schema, 'record> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<Cow<'record, str>> {
+        let prop_slice = self.find_property(key)?;
+
+        match prop_slice.property.info {
+            PropertyInfo::Value { in_type, .. } => {
+                match ansi_string_bytes(prop_slice.property, in_type, prop_slice.buffer) {
+                    Ok(bytes) => match self.ansi_policy {
+                        AnsiStringPolicy::Strict => Ok(Cow::Borrowed(std::str::from_utf8(bytes)?)),
+                        AnsiStringPolicy::Lossy => Ok(String::from_utf8_lossy(bytes)),
+                        policy => policy.decode(bytes).map(Cow::Owned),
+                    },
+                    Err(_) => self
+                        .decode_string(prop_slice.property, in_type, prop_slice.buffer)
+                        .map(Cow::Owned),
                 }
-                TdhInType::InTypeCountedString => unimplemented!(),
-                _ => Err(ParserError::InvalidType),
-            },
+            }
             _ => Err(ParserError::InvalidType),
         }
     }
 }
 
+/// The `Cow<U16Str>` impl of the `TryParse` trait returns the UTF-16 code units of `InTypeUnicodeString` properties (and their counted variants), without their null terminator.
+///
+/// They are borrowed from the record when it is suitably aligned for `u16`s, and copied otherwise.
+/// Unlike the `String` impl, invalid UTF-16 is kept as-is.
+///
+/// # Example
+/// ```
+/// # use std::borrow::Cow;
+/// # use widestring::U16Str;
+/// # use ferrisetw::EventRecord;
+/// # use ferrisetw::schema_locator::SchemaLocator;
+/// # use ferrisetw::parser::Parser;
+/// let my_callback = |record: &EventRecord, schema_locator: &SchemaLocator| {
+///     let schema = schema_locator.event_schema(record).unwrap();
+///     let parser = Parser::create(record, &schema);
+///     let image_name: Cow<U16Str> = parser.try_parse("ImageName").unwrap();
+///     if image_name.to_string_lossy().ends_with("notepad.exe") {
+///         println!("notepad.exe has started");
+///     }
+/// };
+/// ```
+impl<'schema, 'record> private::TryParse<Cow<'record, U16Str>> for Parser<'schema, 'record> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<Cow<'record, U16Str>> {
+        let prop_slice = self.find_property(key)?;
+
+        let (bytes, fixed_length) = match prop_slice.property.info {
+            PropertyInfo::Value {
+                in_type: TdhInType::InTypeUnicodeString,
+                ..
+            } => (prop_slice.buffer, has_fixed_length(prop_slice.property)),
+            PropertyInfo::Value {
+                in_type:
+                    in_type @ (TdhInType::InTypeCountedString | TdhInType::InTypeReversedCountedString),
+                ..
+            } => (counted_string(in_type, prop_slice.buffer)?, false),
+            _ => return Err(ParserError::InvalidType),
+        };
+
+        if bytes.len() % 2 == 1 {
+            return Err(ParserError::PropertyError(
+                "odd length in bytes for a wide string".into(),
+            ));
+        }
+        let wide: Cow<'record, [u16]> =
+            if bytes.as_ptr().align_offset(std::mem::align_of::<u16>()) == 0 {
+                // Safety: the buffer is aligned, and contains `bytes.len() / 2` u16s
+                Cow::Borrowed(unsafe {
+                    std::slice::from_raw_parts(bytes.as_ptr().cast::<u16>(), bytes.len() / 2)
+                })
+            } else {
+                Cow::Owned(
+                    bytes
+                        .chunks_exact(2)
+                        .map(|chunk| u16::from_ne_bytes([chunk[0], chunk[1]]))
+                        .collect(),
+                )
+            };
+
+        let end = if fixed_length {
+            // Fixed-size buffers are padded with nulls (or garbage) after the string
+            wide.iter().position(|c| *c == 0).unwrap_or(wide.len())
+        } else {
+            // remove the null terminator
+            wide.len() - usize::from(wide.last() == Some(&0))
+        };
+        Ok(match wide {
+            Cow::Borrowed(wide) => Cow::Borrowed(U16Str::from_slice(&wide[..end])),
+            Cow::Owned(mut wide) => {
+                wide.truncate(end);
+                Cow::Owned(widestring::U16String::from_vec(wide))
+            }
+        })
+    }
+}
+
 impl private::TryParse<GUID> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> Result<GUID, ParserError> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> Result<GUID, ParserError> {
+        let prop_slice = self.find_property(key)?;
 
         match prop_slice.property.info {
             PropertyInfo::Value { in_type, .. } => {
@@ -516,25 +1180,29 @@ impl private::TryParse<GUID> for Parser<'_, '_> {
                     return Err(ParserError::InvalidType);
                 }
 
-                if prop_slice.buffer.len() != 16 {
-                    return Err(ParserError::LengthMismatch);
-                }
-
-                Ok(GUID {
-                    data1: u32::from_ne_bytes(prop_slice.buffer[0..4].try_into()?),
-                    data2: u16::from_ne_bytes(prop_slice.buffer[4..6].try_into()?),
-                    data3: u16::from_be_bytes(prop_slice.buffer[6..8].try_into()?),
-                    data4: prop_slice.buffer[8..].try_into()?,
-                })
+                guid_from_bytes(prop_slice.buffer)
             }
             _ => Err(ParserError::InvalidType),
         }
     }
 }
 
+fn guid_from_bytes(buffer: &[u8]) -> ParserResult<GUID> {
+    if buffer.len() != 16 {
+        return Err(ParserError::LengthMismatch);
+    }
+
+    Ok(GUID {
+        data1: u32::from_ne_bytes(buffer[0..4].try_into()?),
+        data2: u16::from_ne_bytes(buffer[4..6].try_into()?),
+        data3: u16::from_be_bytes(buffer[6..8].try_into()?),
+        data4: buffer[8..].try_into()?,
+    })
+}
+
 impl private::TryParse<IpAddr> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> ParserResult<IpAddr> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<IpAddr> {
+        let prop_slice = self.find_property(key)?;
 
         match prop_slice.property.info {
             PropertyInfo::Value { out_type, .. } => {
@@ -542,29 +1210,91 @@ impl private::TryParse<IpAddr> for Parser<'_, '_> {
                     return Err(ParserError::InvalidType);
                 }
 
-                // Hardcoded values for now
-                let res = match prop_slice.buffer.len() {
-                    16 => {
-                        let tmp: [u8; 16] = prop_slice.buffer.try_into()?;
-                        IpAddr::V6(Ipv6Addr::from(tmp))
-                    }
-                    4 => {
-                        let tmp: [u8; 4] = prop_slice.buffer.try_into()?;
-                        IpAddr::V4(Ipv4Addr::from(tmp))
-                    }
-                    _ => return Err(ParserError::LengthMismatch),
-                };
+                ip_from_bytes(prop_slice.buffer)
+            }
+            _ => Err(ParserError::InvalidType),
+        }
+    }
+}
+
+fn ip_from_bytes(buffer: &[u8]) -> ParserResult<IpAddr> {
+    // Hardcoded values for now
+    match buffer.len() {
+        16 => {
+            let tmp: [u8; 16] = buffer.try_into()?;
+            Ok(IpAddr::V6(Ipv6Addr::from(tmp)))
+        }
+        4 => {
+            let tmp: [u8; 4] = buffer.try_into()?;
+            Ok(IpAddr::V4(Ipv4Addr::from(tmp)))
+        }
+        _ => Err(ParserError::LengthMismatch),
+    }
+}
+
+/// `AF_INET`, as defined in ws2def.h
+const AF_INET: u16 = 2;
+/// `AF_INET6`, as defined in ws2def.h
+const AF_INET6: u16 = 23;
+
+impl private::TryParse<SocketAddr> for Parser<'_, '_> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<SocketAddr> {
+        let prop_slice = self.find_property(key)?;
 
-                Ok(res)
+        match prop_slice.property.info {
+            PropertyInfo::Value { out_type, .. } => {
+                if out_type != TdhOutType::OutTypeSocketAddress {
+                    return Err(ParserError::InvalidType);
+                }
+
+                socket_addr_from_bytes(prop_slice.buffer)
             }
             _ => Err(ParserError::InvalidType),
         }
     }
 }
 
+fn socket_addr_from_bytes(buffer: &[u8]) -> ParserResult<SocketAddr> {
+    // The address family is in host byte order, while the port, address and flow info are in network byte order
+    let family = u16::from_ne_bytes(
+        buffer
+            .get(0..2)
+            .ok_or(ParserError::LengthMismatch)?
+            .try_into()?,
+    );
+    match family {
+        AF_INET => {
+            // SOCKADDR_IN (the trailing `sin_zero` may be omitted)
+            if buffer.len() < 8 {
+                return Err(ParserError::LengthMismatch);
+            }
+            let port = u16::from_be_bytes(buffer[2..4].try_into()?);
+            let ip: [u8; 4] = buffer[4..8].try_into()?;
+            Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port)))
+        }
+        AF_INET6 => {
+            // SOCKADDR_IN6
+            if buffer.len() < 28 {
+                return Err(ParserError::LengthMismatch);
+            }
+            let port = u16::from_be_bytes(buffer[2..4].try_into()?);
+            let flow_info = u32::from_be_bytes(buffer[4..8].try_into()?);
+            let ip: [u8; 16] = buffer[8..24].try_into()?;
+            let scope_id = u32::from_ne_bytes(buffer[24..28].try_into()?);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(ip),
+                port,
+                flow_info,
+                scope_id,
+            )))
+        }
+        _ => Err(ParserError::ParseError),
+    }
+}
+
 impl private::TryParse<bool> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> ParserResult<bool> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<bool> {
+        let prop_slice = self.find_property(key)?;
 
         match prop_slice.property.info {
             PropertyInfo::Value { in_type, .. } => {
@@ -572,21 +1302,25 @@ impl private::TryParse<bool> for Parser<'_, '_> {
                     return Err(ParserError::InvalidType);
                 }
 
-                match prop_slice.buffer.len() {
-                    1 => Ok(prop_slice.buffer[0] != 0),
-                    4 => Ok(u32::from_ne_bytes(prop_slice.buffer.try_into()?) != 0),
-                    8 => Ok(u64::from_ne_bytes(prop_slice.buffer.try_into()?) != 0),
-                    _ => Err(ParserError::LengthMismatch),
-                }
+                bool_from_bytes(prop_slice.buffer)
             }
             _ => Err(ParserError::InvalidType),
         }
     }
 }
 
+fn bool_from_bytes(buffer: &[u8]) -> ParserResult<bool> {
+    match buffer.len() {
+        1 => Ok(buffer[0] != 0),
+        4 => Ok(u32::from_ne_bytes(buffer.try_into()?) != 0),
+        8 => Ok(u64::from_ne_bytes(buffer.try_into()?) != 0),
+        _ => Err(ParserError::LengthMismatch),
+    }
+}
+
 impl private::TryParse<FileTime> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> ParserResult<FileTime> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<FileTime> {
+        let prop_slice = self.find_property(key)?;
 
         match prop_slice.property.info {
             PropertyInfo::Value { in_type, .. } => {
@@ -602,8 +1336,8 @@ impl private::TryParse<FileTime> for Parser<'_, '_> {
 }
 
 impl private::TryParse<SystemTime> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> ParserResult<SystemTime> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<SystemTime> {
+        let prop_slice = self.find_property(key)?;
 
         match prop_slice.property.info {
             PropertyInfo::Value { in_type, .. } => {
@@ -618,68 +1352,877 @@ impl private::TryParse<SystemTime> for Parser<'_, '_> {
     }
 }
 
-#[derive(Clone, Default, Debug)]
-pub struct Pointer(usize);
+/// A pointer value read from an event
+///
+/// The pointed address belongs to the process that emitted the event (or to the kernel), so it cannot be dereferenced.<br/>
+/// Its size depends on the bitness of the event (see `EVENT_HEADER_FLAG_32_BIT_HEADER`), not on the bitness of the current process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RemotePtr {
+    value: u64,
+    /// Size of the pointer in the event, in bytes (either 4 or 8)
+    size: usize,
+}
 
-impl std::ops::Deref for Pointer {
-    type Target = usize;
+impl RemotePtr {
+    pub(crate) fn new(value: u64, size: usize) -> Self {
+        Self { value, size }
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+    /// The pointer value, zero-extended to 64 bits
+    pub fn as_u64(&self) -> u64 {
+        self.value
     }
-}
 
-impl std::ops::DerefMut for Pointer {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    /// Size of the pointer in the event, in bytes (either 4 or 8)
+    pub fn pointer_size(&self) -> usize {
+        self.size
     }
-}
 
-impl std::fmt::LowerHex for Pointer {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let val = self.0;
+    /// Whether this pointer comes from an event with a 32-bit header
+    pub fn is_32_bit(&self) -> bool {
+        self.size == 4
+    }
 
-        std::fmt::LowerHex::fmt(&val, f) // delegate to u32/u64 implementation
+    /// Whether this is a null pointer
+    pub fn is_null(&self) -> bool {
+        self.value == 0
     }
 }
 
-impl std::fmt::UpperHex for Pointer {
+impl std::fmt::LowerHex for RemotePtr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let val = self.0;
-
-        std::fmt::UpperHex::fmt(&val, f) // delegate to u32/u64 implementation
+        std::fmt::LowerHex::fmt(&self.value, f) // delegate to u64 implementation
     }
 }
 
-impl std::fmt::Display for Pointer {
+impl std::fmt::UpperHex for RemotePtr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let val = self.0;
+        std::fmt::UpperHex::fmt(&self.value, f) // delegate to u64 implementation
+    }
+}
 
-        std::fmt::Display::fmt(&val, f) // delegate to u32/u64 implementation
+/// Formats as a `0x`-prefixed hex number, padded to the pointer size (e.g. `0x0000abcd` for a 32-bit pointer)
+impl std::fmt::Display for RemotePtr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#0width$x}", self.value, width = 2 + 2 * self.size)
     }
 }
 
-impl private::TryParse<Pointer> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> ParserResult<Pointer> {
-        let prop_slice = self.find_property(name)?;
+impl private::TryParse<RemotePtr> for Parser<'_, '_> {
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> ParserResult<RemotePtr> {
+        let prop_slice = self.find_property(key)?;
 
-        let mut res = Pointer::default();
-        if prop_slice.buffer.len() == std::mem::size_of::<u32>() {
-            res.0 = private::TryParse::<u32>::try_parse_impl(self, name)? as usize;
-        } else {
-            res.0 = private::TryParse::<u64>::try_parse_impl(self, name)? as usize;
-        }
+        match prop_slice.property.info {
+            PropertyInfo::Value { .. } => {
+                let expected = self.record.pointer_size();
+                let found = prop_slice.buffer.len();
+                let value = match (expected, found) {
+                    (4, 4) => u32::from_ne_bytes(prop_slice.buffer.try_into()?) as u64,
+                    (8, 8) => u64::from_ne_bytes(prop_slice.buffer.try_into()?),
+                    _ => return Err(ParserError::PointerSizeMismatch { expected, found }),
+                };
 
-        Ok(res)
+                Ok(RemotePtr {
+                    value,
+                    size: expected,
+                })
+            }
+            _ => Err(ParserError::InvalidType),
+        }
     }
 }
 
 impl private::TryParse<Vec<u8>> for Parser<'_, '_> {
-    fn try_parse_impl(&self, name: &str) -> Result<Vec<u8>, ParserError> {
-        let prop_slice = self.find_property(name)?;
+    fn try_parse_impl(&self, key: PropertyKey<'_>) -> Result<Vec<u8>, ParserError> {
+        let prop_slice = self.find_property(key)?;
         Ok(prop_slice.buffer.to_vec())
     }
 }
 
-// TODO: Implement SocketAddress
-// TODO: Study if we can use primitive types for HexInt64, HexInt32 and Pointer
+/// A decoded property value, see [`Parser::iter_properties`] and [`Parser::parse_all`]
+///
+/// Variants follow the TDH in-type of the property (or its out-type, for IP and socket addresses).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum PropertyValue {
+    Null,
+    Bool(bool),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+    String(String),
+    Guid(GUID),
+    Pointer(RemotePtr),
+    FileTime(FileTime),
+    SystemTime(SystemTime),
+    IpAddr(IpAddr),
+    SocketAddr(SocketAddr),
+    /// Binary blobs, as well as properties whose type is not supported by this crate
+    Binary(Vec<u8>),
+    Array(Vec<PropertyValue>),
+}
+
+/// Size of a single value of this in-type, in case it does not depend on the event
+fn fixed_size(in_type: TdhInType) -> Option<usize> {
+    match in_type {
+        TdhInType::InTypeInt8 | TdhInType::InTypeUInt8 => Some(1),
+        TdhInType::InTypeInt16 | TdhInType::InTypeUInt16 => Some(2),
+        TdhInType::InTypeInt32
+        | TdhInType::InTypeUInt32
+        | TdhInType::InTypeHexInt32
+        | TdhInType::InTypeFloat
+        | TdhInType::InTypeBoolean => Some(4),
+        TdhInType::InTypeInt64
+        | TdhInType::InTypeUInt64
+        | TdhInType::InTypeHexInt64
+        | TdhInType::InTypeDouble
+        | TdhInType::InTypeFileTime => Some(8),
+        TdhInType::InTypeGuid | TdhInType::InTypeSystemTime => Some(16),
+        _ => None,
+    }
+}
+
+impl Parser<'_, '_> {
+    fn decode_value(&self, prop_slice: PropertySlice) -> ParserResult<PropertyValue> {
+        match prop_slice.property.info {
+            PropertyInfo::Value {
+                in_type, out_type, ..
+            } => self.decode_element(prop_slice.property, in_type, out_type, prop_slice.buffer),
+            PropertyInfo::Array {
+                in_type, out_type, ..
+            } => {
+                let element_size = match in_type {
+                    TdhInType::InTypePointer => self.record.pointer_size(),
+                    _ => match fixed_size(in_type) {
+                        Some(size) => size,
+                        None => return Ok(PropertyValue::Binary(prop_slice.buffer.to_vec())),
+                    },
+                };
+                let elements = prop_slice.buffer.chunks_exact(element_size);
+                if !elements.remainder().is_empty() {
+                    return Err(ParserError::LengthMismatch);
+                }
+
+                elements
+                    .map(|element| {
+                        self.decode_element(prop_slice.property, in_type, out_type, element)
+                    })
+                    .collect::<ParserResult<Vec<_>>>()
+                    .map(PropertyValue::Array)
+            }
+            PropertyInfo::Unsupported { .. } => {
+                Ok(PropertyValue::Binary(prop_slice.buffer.to_vec()))
+            }
+        }
+    }
+
+    fn decode_element(
+        &self,
+        property: &Property,
+        in_type: TdhInType,
+        out_type: TdhOutType,
+        buffer: &[u8],
+    ) -> ParserResult<PropertyValue> {
+        // give the output type precedence if there is one, otherwise use the input type (same as the serializer)
+        match out_type {
+            TdhOutType::OutTypeIpv4 | TdhOutType::OutTypeIpv6 => {
+                return ip_from_bytes(buffer).map(PropertyValue::IpAddr)
+            }
+            TdhOutType::OutTypeSocketAddress => {
+                return socket_addr_from_bytes(buffer).map(PropertyValue::SocketAddr)
+            }
+            _ => (),
+        }
+
+        if let Some(size) = fixed_size(in_type) {
+            if buffer.len() != size {
+                return Err(ParserError::LengthMismatch);
+            }
+        }
+
+        let value = match in_type {
+            TdhInType::InTypeNull => PropertyValue::Null,
+            TdhInType::InTypeBoolean => PropertyValue::Bool(bool_from_bytes(buffer)?),
+            TdhInType::InTypeInt8 => PropertyValue::I8(i8::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeUInt8 => PropertyValue::U8(u8::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeInt16 => PropertyValue::I16(i16::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeUInt16 => PropertyValue::U16(u16::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeInt32 => PropertyValue::I32(i32::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeUInt32 | TdhInType::InTypeHexInt32 => {
+                PropertyValue::U32(u32::from_ne_bytes(buffer.try_into()?))
+            }
+            TdhInType::InTypeInt64 => PropertyValue::I64(i64::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeUInt64 | TdhInType::InTypeHexInt64 => {
+                PropertyValue::U64(u64::from_ne_bytes(buffer.try_into()?))
+            }
+            TdhInType::InTypeFloat => PropertyValue::F32(f32::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeDouble => PropertyValue::F64(f64::from_ne_bytes(buffer.try_into()?)),
+            TdhInType::InTypeGuid => PropertyValue::Guid(guid_from_bytes(buffer)?),
+            TdhInType::InTypeFileTime => {
+                PropertyValue::FileTime(FileTime::from_slice(buffer.try_into()?))
+            }
+            TdhInType::InTypeSystemTime => {
+                PropertyValue::SystemTime(SystemTime::from_slice(buffer.try_into()?))
+            }
+            TdhInType::InTypePointer => {
+                let expected = self.record.pointer_size();
+                let found = buffer.len();
+                let value = match (expected, found) {
+                    (4, 4) => u32::from_ne_bytes(buffer.try_into()?) as u64,
+                    (8, 8) => u64::from_ne_bytes(buffer.try_into()?),
+                    _ => return Err(ParserError::PointerSizeMismatch { expected, found }),
+                };
+                PropertyValue::Pointer(RemotePtr::new(value, expected))
+            }
+            TdhInType::InTypeUnicodeString
+            | TdhInType::InTypeAnsiString
+            | TdhInType::InTypeSid
+            | TdhInType::InTypeCountedString
+            | TdhInType::InTypeCountedAnsiString
+            | TdhInType::InTypeReversedCountedString
+            | TdhInType::InTypeReversedCountedAnsiString => {
+                PropertyValue::String(self.decode_string(property, in_type, buffer)?)
+            }
+            TdhInType::InTypeBinary => PropertyValue::Binary(buffer.to_vec()),
+        };
+        Ok(value)
+    }
+
+    fn decode_string(
+        &self,
+        property: &Property,
+        in_type: TdhInType,
+        buffer: &[u8],
+    ) -> ParserResult<String> {
+        match in_type {
+            TdhInType::InTypeUnicodeString => {
+                let mut wide = aligned_wide_string(buffer)?;
+                if has_fixed_length(property) {
+                    // Fixed-size buffers are padded with nulls (or garbage) after the string
+                    if let Some(end) = wide.iter().position(|c| *c == 0) {
+                        wide.truncate(end);
+                    }
+                }
+
+                // Decode UTF-16 to String
+                Ok(widestring::decode_utf16_lossy(wide.iter().copied()).collect::<String>())
+            }
+            TdhInType::InTypeAnsiString => {
+                let mut buffer = buffer;
+                if has_fixed_length(property) {
+                    // Fixed-size buffers are padded with nulls (or garbage) after the string
+                    let end = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+                    buffer = &buffer[..end];
+                }
+                let string = self.ansi_policy.decode(buffer)?;
+                Ok(string.trim_matches(char::default()).to_string())
+            }
+            TdhInType::InTypeSid => {
+                let string = sddl::convert_sid_to_string(buffer.as_ptr() as *const _)?;
+                Ok(string)
+            }
+            TdhInType::InTypeCountedString | TdhInType::InTypeReversedCountedString => {
+                let wide = aligned_wide_string(counted_string(in_type, buffer)?)?;
+                Ok(widestring::decode_utf16_lossy(wide.iter().copied()).collect::<String>())
+            }
+            TdhInType::InTypeCountedAnsiString | TdhInType::InTypeReversedCountedAnsiString => {
+                let string = self.ansi_policy.decode(counted_string(in_type, buffer)?)?;
+                Ok(string.trim_matches(char::default()).to_string())
+            }
+            _ => Err(ParserError::InvalidType),
+        }
+    }
+}
+
+// TODO: Study if we can use primitive types for HexInt64 and HexInt32
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::tdh_types::PropertyFlags;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_remote_ptr_display() {
+        let ptr32 = RemotePtr {
+            value: 0xabcd,
+            size: 4,
+        };
+        let ptr64 = RemotePtr {
+            value: 0xdeadbeef,
+            size: 8,
+        };
+
+        assert_eq!(ptr32.to_string(), "0x0000abcd");
+        assert_eq!(ptr64.to_string(), "0x00000000deadbeef");
+        assert_eq!(format!("{:X}", ptr64), "DEADBEEF");
+    }
+
+    #[test]
+    fn test_parse_pointers_32_bit_header() {
+        let event = SyntheticEvent::new()
+            .with_32_bit_header()
+            .with_pointer(0x1234)
+            .with_pointer(0xabcd)
+            .with_user_data(&42u32.to_ne_bytes());
+        let properties = [
+            pointer_property("First"),
+            pointer_property("Second"),
+            value_property("Value", TdhInType::InTypeUInt32, 4),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        let second: RemotePtr = parser.try_parse("Second").unwrap();
+        assert!(second.is_32_bit());
+        assert_eq!(second.as_u64(), 0xabcd);
+        assert_eq!(parser.try_parse::<u32>("Value").unwrap(), 42);
+    }
+
+    #[test]
+    fn test_parse_pointers_64_bit_header() {
+        let event = SyntheticEvent::new()
+            .with_pointer(0xffff_8000_0000_1234)
+            .with_pointer(0);
+        let properties = [pointer_property("First"), pointer_property("Second")];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        let first: RemotePtr = parser.try_parse("First").unwrap();
+        assert!(!first.is_32_bit());
+        assert_eq!(first.as_u64(), 0xffff_8000_0000_1234);
+        assert!(parser.try_parse::<RemotePtr>("Second").unwrap().is_null());
+    }
+
+    #[test]
+    fn test_parse_pointer_array_32_bit_header() {
+        let event = SyntheticEvent::new()
+            .with_32_bit_header()
+            .with_pointer(1)
+            .with_pointer(2)
+            .with_user_data(&7u16.to_ne_bytes());
+        let properties = [
+            pointer_array_property("Pointers", 2),
+            value_property("Value", TdhInType::InTypeUInt16, 2),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        assert_eq!(parser.try_parse::<u16>("Value").unwrap(), 7);
+    }
+
+    #[test]
+    fn test_parse_fixed_length_strings() {
+        let mut data = b"abc\0\xff\xff\0\0".to_vec();
+        data.extend(
+            "de".encode_utf16()
+                .chain([0, 0])
+                .flat_map(|c| c.to_ne_bytes()),
+        );
+        data.extend(5u16.to_ne_bytes());
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let properties = [
+            value_property("Ansi", TdhInType::InTypeAnsiString, 8),
+            // 4 characters, i.e. 8 bytes
+            value_property("Unicode", TdhInType::InTypeUnicodeString, 4),
+            value_property("Value", TdhInType::InTypeUInt16, 2),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        assert_eq!(parser.try_parse::<String>("Ansi").unwrap(), "abc");
+        assert_eq!(parser.try_parse::<String>("Unicode").unwrap(), "de");
+        assert_eq!(parser.try_parse::<u16>("Value").unwrap(), 5);
+    }
+
+    #[test]
+    fn test_fixed_length_string_arrays() {
+        let mut data: Vec<u8> = "ab\0cd\0\0"
+            .encode_utf16()
+            .flat_map(u16::to_ne_bytes)
+            .collect();
+        data.extend(5u16.to_ne_bytes());
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let properties = [
+            Property {
+                name: "Names".to_string(),
+                flags: PropertyFlags::empty(),
+                info: PropertyInfo::Array {
+                    in_type: TdhInType::InTypeUnicodeString,
+                    out_type: TdhOutType::OutTypeNull,
+                    // 2 strings of 3 characters, i.e. 12 bytes
+                    length: PropertyLength::Length(3),
+                    count: PropertyCount::Count(2),
+                },
+                map_name: None,
+            },
+            value_property("Value", TdhInType::InTypeUInt16, 2),
+        ];
+        assert_eq!(fixed_layout(&properties, 8), vec![0..12, 12..14]);
+
+        let parser = Parser::from_properties(event.record(), &properties);
+        assert_eq!(parser.try_parse::<u16>("Value").unwrap(), 5);
+    }
+
+    #[test]
+    fn test_parse_counted_strings() {
+        let wide: Vec<u8> = "héllo".encode_utf16().flat_map(u16::to_ne_bytes).collect();
+        let mut data = (wide.len() as u16).to_le_bytes().to_vec();
+        data.extend(&wide);
+        data.extend(3u16.to_le_bytes());
+        data.extend(b"abc");
+        data.extend(2u16.to_be_bytes());
+        data.extend(b"de");
+        data.extend(7u16.to_ne_bytes());
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let properties = [
+            value_property("Unicode", TdhInType::InTypeCountedString, 0),
+            value_property("Ansi", TdhInType::InTypeCountedAnsiString, 0),
+            value_property("Reversed", TdhInType::InTypeReversedCountedAnsiString, 0),
+            value_property("Value", TdhInType::InTypeUInt16, 2),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        assert_eq!(parser.try_parse::<String>("Unicode").unwrap(), "héllo");
+        assert_eq!(parser.try_parse::<String>("Ansi").unwrap(), "abc");
+        assert_eq!(parser.try_parse::<String>("Reversed").unwrap(), "de");
+        assert_eq!(parser.try_parse::<u16>("Value").unwrap(), 7);
+    }
+
+    #[test]
+    fn test_parse_borrowed_strings() {
+        let utf16 = |s: &str| -> Vec<u8> {
+            s.encode_utf16()
+                .chain([0])
+                .flat_map(u16::to_ne_bytes)
+                .collect()
+        };
+        let mut data = b"abc\0".to_vec();
+        data.extend(utf16("dé"));
+        // Shifts the next string to an odd offset
+        data.extend(b"ab\0");
+        data.extend(utf16("fg"));
+        data.extend(b"caf\xe9\0");
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let properties = [
+            value_property("Ansi", TdhInType::InTypeAnsiString, 0),
+            value_property("Aligned", TdhInType::InTypeUnicodeString, 0),
+            value_property("Odd", TdhInType::InTypeAnsiString, 0),
+            value_property("Unaligned", TdhInType::InTypeUnicodeString, 0),
+            value_property("Latin", TdhInType::InTypeAnsiString, 0),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        assert_eq!(parser.try_parse::<&str>("Ansi").unwrap(), "abc");
+        assert!(matches!(
+            parser.try_parse::<Cow<str>>("Ansi").unwrap(),
+            Cow::Borrowed("abc")
+        ));
+        assert!(matches!(
+            parser.try_parse::<&str>("Aligned"),
+            Err(ParserError::InvalidType)
+        ));
+        assert!(matches!(
+            parser.try_parse::<Cow<str>>("Aligned").unwrap(),
+            Cow::Owned(s) if s == "dé"
+        ));
+
+        let aligned = parser.try_parse::<Cow<U16Str>>("Aligned").unwrap();
+        assert!(matches!(aligned, Cow::Borrowed(_)));
+        assert_eq!(aligned.to_string_lossy(), "dé");
+        let unaligned = parser.try_parse::<Cow<U16Str>>("Unaligned").unwrap();
+        assert!(matches!(unaligned, Cow::Owned(_)));
+        assert_eq!(unaligned.to_string_lossy(), "fg");
+
+        assert!(matches!(
+            parser.try_parse::<&str>("Latin"),
+            Err(ParserError::Utf8Error(_))
+        ));
+        let parser = Parser::from_properties(event.record(), &properties)
+            .ansi_policy(AnsiStringPolicy::Lossy);
+        assert_eq!(
+            parser.try_parse::<Cow<str>>("Latin").unwrap(),
+            "caf\u{fffd}"
+        );
+    }
+
+    #[test]
+    fn test_raw_property() {
+        let event = SyntheticEvent::new().with_user_data(&[1, 2, 3, 4, 5, 6]);
+        let properties = [
+            value_property("Value", TdhInType::InTypeUInt16, 2),
+            value_property("Blob", TdhInType::InTypeBinary, 4),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        let (bytes, in_type, out_type) = parser.raw_property("Blob").unwrap();
+        assert_eq!(bytes, &[3, 4, 5, 6]);
+        assert_eq!(in_type, TdhInType::InTypeBinary);
+        assert_eq!(out_type, TdhOutType::OutTypeNull);
+        assert!(matches!(
+            parser.raw_property("Missing"),
+            Err(ParserError::NotFound)
+        ));
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive_event() {
+        #[derive(Debug, PartialEq, ferrisetw::Event)]
+        #[etw(provider = "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")]
+        struct Typed {
+            #[etw(rename = "Value")]
+            value: u16,
+            #[etw(optional)]
+            missing: Option<u32>,
+            #[etw(skip)]
+            skipped: Vec<u8>,
+        }
+
+        let provider = GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716);
+        let event = SyntheticEvent::new()
+            .with_provider(provider)
+            .with_user_data(&[5, 0]);
+        let properties = [value_property("Value", TdhInType::InTypeUInt16, 2)];
+        let parser = Parser::from_properties(event.record(), &properties);
+        assert_eq!(
+            Typed::from_parser(event.record(), &parser).unwrap(),
+            Typed {
+                value: 5,
+                missing: None,
+                skipped: Vec::new(),
+            }
+        );
+
+        let other_event = SyntheticEvent::new().with_user_data(&[5, 0]);
+        let parser = Parser::from_properties(other_event.record(), &properties);
+        assert!(matches!(
+            Typed::from_parser(other_event.record(), &parser),
+            Err(ParserError::UnexpectedEvent)
+        ));
+    }
+
+    #[test]
+    fn test_ansi_string_policies() {
+        let event = SyntheticEvent::new().with_user_data(b"caf\xe9\0");
+        let properties = [value_property("Ansi", TdhInType::InTypeAnsiString, 0)];
+
+        let parser = Parser::from_properties(event.record(), &properties);
+        assert!(matches!(
+            parser.try_parse::<String>("Ansi"),
+            Err(ParserError::Utf8Error(_))
+        ));
+
+        let parser = Parser::from_properties(event.record(), &properties)
+            .ansi_policy(AnsiStringPolicy::Lossy);
+        assert_eq!(parser.try_parse::<String>("Ansi").unwrap(), "caf\u{fffd}");
+
+        let parser = Parser::from_properties(event.record(), &properties)
+            .ansi_policy(AnsiStringPolicy::CodePage(1252));
+        assert_eq!(parser.try_parse::<String>("Ansi").unwrap(), "caf\u{e9}");
+    }
+
+    fn socket_address_property(name: &str, length: u16) -> Property {
+        let mut property = value_property(name, TdhInType::InTypeBinary, length);
+        if let PropertyInfo::Value { out_type, .. } = &mut property.info {
+            *out_type = TdhOutType::OutTypeSocketAddress;
+        }
+        property
+    }
+
+    #[test]
+    fn test_parse_socket_addresses() {
+        let mut v4 = 2u16.to_ne_bytes().to_vec();
+        v4.extend(8080u16.to_be_bytes());
+        v4.extend([192, 168, 1, 10]);
+        v4.extend([0; 8]);
+
+        let mut v6 = 23u16.to_ne_bytes().to_vec();
+        v6.extend(443u16.to_be_bytes());
+        v6.extend(0u32.to_be_bytes());
+        v6.extend(Ipv6Addr::LOCALHOST.octets());
+        v6.extend(3u32.to_ne_bytes());
+
+        let event = SyntheticEvent::new()
+            .with_user_data(&v4)
+            .with_user_data(&v6)
+            .with_user_data(&[0; 16]);
+        let properties = [
+            socket_address_property("Local", 16),
+            socket_address_property("Remote", 28),
+            socket_address_property("Unknown", 16),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        assert_eq!(
+            parser.try_parse::<SocketAddr>("Local").unwrap(),
+            "192.168.1.10:8080".parse().unwrap()
+        );
+        let remote = parser.try_parse::<SocketAddr>("Remote").unwrap();
+        assert_eq!(remote, "[::1%3]:443".parse().unwrap());
+        assert!(matches!(
+            parser.try_parse::<SocketAddr>("Unknown"),
+            Err(ParserError::ParseError)
+        ));
+        assert!(matches!(
+            parser.try_parse::<IpAddr>("Local"),
+            Err(ParserError::InvalidType)
+        ));
+    }
+
+    #[test]
+    fn test_parse_pointer_size_mismatch() {
+        let event = SyntheticEvent::new().with_user_data(&1u32.to_ne_bytes());
+        let properties = [value_property("NotAPointer", TdhInType::InTypeHexInt32, 4)];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        assert!(matches!(
+            parser.try_parse::<RemotePtr>("NotAPointer"),
+            Err(ParserError::PointerSizeMismatch {
+                expected: 8,
+                found: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_iter_properties() {
+        let mut data = b"caf\xe9\0".to_vec();
+        data.extend(42u32.to_ne_bytes());
+        data.extend(b"abc");
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let properties = [
+            value_property("Latin", TdhInType::InTypeAnsiString, 0),
+            value_property("Count", TdhInType::InTypeUInt32, 4),
+            value_property("Truncated", TdhInType::InTypeGuid, 16),
+            value_property("Unreachable", TdhInType::InTypeUInt32, 4),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        let mut values = parser.iter_properties();
+        assert!(matches!(
+            values.next(),
+            Some(Err(ParserError::Utf8Error(_)))
+        ));
+        assert_eq!(
+            values.next().unwrap().unwrap(),
+            ("Count", PropertyValue::U32(42))
+        );
+        assert!(matches!(values.next(), Some(Err(_))));
+        assert!(values.next().is_none());
+        assert!(parser.parse_all().is_err());
+    }
+
+    #[test]
+    fn test_fixed_layout() {
+        let properties = [
+            value_property("Id", TdhInType::InTypeUInt32, 4),
+            pointer_property("Object"),
+            pointer_array_property("Stack", 2),
+            value_property("Label", TdhInType::InTypeUnicodeString, 3),
+            value_property("Name", TdhInType::InTypeAnsiString, 0),
+            value_property("Flags", TdhInType::InTypeUInt16, 2),
+        ];
+        assert_eq!(
+            fixed_layout(&properties, 8),
+            vec![0..4, 4..12, 12..28, 28..34]
+        );
+        assert_eq!(
+            fixed_layout(&properties, 4),
+            vec![0..4, 4..8, 8..16, 16..22]
+        );
+        assert!(fixed_layout(&properties[4..], 8).is_empty());
+
+        let mut data = 7u32.to_ne_bytes().to_vec();
+        data.extend([0u8; 24]);
+        data.extend([b'a', 0, b'b', 0, b'c', 0]);
+        data.extend(b"name\0");
+        data.extend(3u16.to_ne_bytes());
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let parser = Parser::from_properties(event.record(), &properties);
+        // Properties that follow a variable-size one are still found by walking the previous ones
+        assert_eq!(parser.try_parse::<u16>("Flags").unwrap(), 3);
+        assert_eq!(parser.try_parse::<String>("Name").unwrap(), "name");
+        assert_eq!(parser.try_parse::<String>("Label").unwrap(), "abc");
+        assert_eq!(parser.try_parse::<u32>("Id").unwrap(), 7);
+
+        let truncated = SyntheticEvent::new().with_user_data(&data[..10]);
+        let parser = Parser::from_properties(truncated.record(), &properties);
+        assert_eq!(parser.try_parse::<u32>("Id").unwrap(), 7);
+        assert!(parser.try_parse::<u64>("Object").is_err());
+    }
+
+    #[test]
+    fn test_length_and_count_from_other_properties() {
+        let sized = |name: &str, in_type, length| Property {
+            name: name.to_string(),
+            flags: PropertyFlags::empty(),
+            info: PropertyInfo::Value {
+                in_type,
+                out_type: TdhOutType::OutTypeNull,
+                length,
+            },
+            map_name: None,
+        };
+        let properties = [
+            value_property("VerbLength", TdhInType::InTypeUInt16, 2),
+            sized(
+                "Verb",
+                TdhInType::InTypeAnsiString,
+                PropertyLength::Index(0),
+            ),
+            value_property("Count", TdhInType::InTypeUInt8, 1),
+            Property {
+                name: "Values".to_string(),
+                flags: PropertyFlags::empty(),
+                info: PropertyInfo::Array {
+                    in_type: TdhInType::InTypeUInt32,
+                    out_type: TdhOutType::OutTypeNull,
+                    length: PropertyLength::Length(4),
+                    count: PropertyCount::Index(2),
+                },
+                map_name: None,
+            },
+            sized(
+                "Label",
+                TdhInType::InTypeUnicodeString,
+                PropertyLength::Index(2),
+            ),
+            value_property("Trailer", TdhInType::InTypeUInt16, 2),
+        ];
+
+        let mut data = 3u16.to_ne_bytes().to_vec();
+        // Not null-terminated: only the length tells where it ends
+        data.extend(b"GET");
+        data.push(2);
+        data.extend([10u32, 20].iter().flat_map(|v| v.to_ne_bytes()));
+        data.extend([b'o', 0, b'k', 0]);
+        data.extend(0xabcdu16.to_ne_bytes());
+        let event = SyntheticEvent::new().with_user_data(&data);
+
+        // These do not need TDH, that would fail for this event
+        let parser = Parser::from_properties(event.record(), &properties);
+        assert_eq!(parser.try_parse::<u16>("Trailer").unwrap(), 0xabcd);
+        assert_eq!(parser.try_parse::<String>("Verb").unwrap(), "GET");
+        assert_eq!(parser.try_parse::<String>("Label").unwrap(), "ok");
+
+        let parser = Parser::from_properties(event.record(), &properties);
+        let values: Vec<_> = parser
+            .iter_properties()
+            .map(|value| value.unwrap().0)
+            .collect();
+        assert_eq!(
+            values,
+            vec!["VerbLength", "Verb", "Count", "Values", "Label", "Trailer"]
+        );
+    }
+
+    #[test]
+    fn test_huge_count_from_other_property() {
+        let properties = [
+            value_property("Count", TdhInType::InTypeUInt64, 8),
+            Property {
+                name: "Values".to_string(),
+                flags: PropertyFlags::empty(),
+                info: PropertyInfo::Array {
+                    in_type: TdhInType::InTypeUnicodeString,
+                    out_type: TdhOutType::OutTypeNull,
+                    length: PropertyLength::Length(4),
+                    count: PropertyCount::Index(0),
+                },
+                map_name: None,
+            },
+            value_property("Trailer", TdhInType::InTypeUInt16, 2),
+        ];
+        let mut data = u64::MAX.to_ne_bytes().to_vec();
+        data.extend([0u8; 8]);
+        let event = SyntheticEvent::new().with_user_data(&data);
+
+        let parser = Parser::from_properties(event.record(), &properties);
+        assert_eq!(parser.try_parse::<u64>("Count").unwrap(), u64::MAX);
+        assert!(matches!(
+            parser.try_parse::<u16>("Trailer"),
+            Err(ParserError::LengthMismatch)
+        ));
+        assert!(matches!(
+            known_property_size(&properties[1], 8, |_| Some(usize::MAX / 4)),
+            Err(ParserError::LengthMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_parse_by_index() {
+        let mut data = 7u32.to_ne_bytes().to_vec();
+        data.extend(42u64.to_ne_bytes());
+        data.extend(b"abc\0");
+        let event = SyntheticEvent::new().with_user_data(&data);
+        let properties = [
+            value_property("Small", TdhInType::InTypeUInt32, 4),
+            value_property("Large", TdhInType::InTypeUInt64, 8),
+            value_property("Name", TdhInType::InTypeAnsiString, 0),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        // Out of order, so that the lookup of an index parses the properties that come before it
+        assert_eq!(parser.try_parse_at::<String>(2).unwrap(), "abc");
+        assert_eq!(parser.try_parse_at::<u32>(0).unwrap(), 7);
+        assert_eq!(parser.try_parse_at::<u64>(1).unwrap(), 42);
+        assert_eq!(parser.try_parse::<u64>("Large").unwrap(), 42);
+        assert!(matches!(
+            parser.try_parse_at::<u32>(3),
+            Err(ParserError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let mut data = b"abc\0".to_vec();
+        data.extend(42u32.to_ne_bytes());
+        data.extend(1u32.to_ne_bytes());
+        data.extend([1u16, 2, 3].iter().flat_map(|v| v.to_ne_bytes()));
+        let event = SyntheticEvent::new()
+            .with_user_data(&data)
+            .with_pointer(0xabcd);
+        let array = Property {
+            name: "Values".to_string(),
+            flags: PropertyFlags::empty(),
+            info: PropertyInfo::Array {
+                in_type: TdhInType::InTypeUInt16,
+                out_type: TdhOutType::OutTypeNull,
+                length: PropertyLength::Length(2),
+                count: PropertyCount::Count(3),
+            },
+            map_name: None,
+        };
+        let properties = [
+            value_property("Name", TdhInType::InTypeAnsiString, 0),
+            value_property("Count", TdhInType::InTypeUInt32, 4),
+            value_property("Enabled", TdhInType::InTypeBoolean, 4),
+            array,
+            pointer_property("Address"),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+
+        let values = parser.parse_all().unwrap();
+        assert_eq!(
+            values,
+            vec![
+                ("Name", PropertyValue::String("abc".to_string())),
+                ("Count", PropertyValue::U32(42)),
+                ("Enabled", PropertyValue::Bool(true)),
+                (
+                    "Values",
+                    PropertyValue::Array(vec![
+                        PropertyValue::U16(1),
+                        PropertyValue::U16(2),
+                        PropertyValue::U16(3)
+                    ])
+                ),
+                ("Address", PropertyValue::Pointer(RemotePtr::new(0xabcd, 8))),
+            ]
+        );
+    }
+}
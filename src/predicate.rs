@@ -0,0 +1,255 @@
+//! Composable predicates on events, evaluated in software
+//!
+//! Kernel-side filters (see [`EventFilter`](crate::provider::EventFilter)) are cheap, but they can only filter on a few criteria (event IDs, PIDs...).<br/>
+//! [`Pred`]s can test any header field or property of an event. They are evaluated by ferrisetw before your callbacks are invoked,
+//! and properties are only parsed when a predicate actually needs them.
+//!
+//! The same predicates can be used on real-time traces (see [`ProviderBuilder::filter_with`](crate::provider::ProviderBuilder::filter_with))
+//! and on file traces (see [`FileTraceBuilder::filter_with`](crate::trace::FileTraceBuilder::filter_with)).
+//!
+//! # Example
+//! ```
+//! # use ferrisetw::predicate::Pred;
+//! # use ferrisetw::provider::Provider;
+//! let dns_queries = Pred::id(3008).and(Pred::prop_eq("QueryName", "example.com"));
+//!
+//! let provider = Provider::by_guid("1c95126e-7eea-49a9-a3fe-a378b03ddb4d") // Microsoft-Windows-DNS-Client
+//!     .filter_with(dns_queries)
+//!     .add_callback(|record, schema_locator| {
+//!         // Only called for queries to example.com
+//!     })
+//!     .build();
+//! ```
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use once_cell::unsync::OnceCell;
+use windows::core::GUID;
+
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::{Parser, RemotePtr};
+use crate::schema::Schema;
+use crate::schema_locator::SchemaLocator;
+
+/// The event being evaluated. The schema and parser are only built when a predicate needs them, and shared by all predicates.
+struct Evaluation<'a> {
+    record: &'a EventRecord,
+    locator: &'a SchemaLocator,
+    schema: &'a OnceCell<Option<Arc<Schema>>>,
+    parser: &'a OnceCell<Option<Parser<'a, 'a>>>,
+}
+
+impl<'a> Evaluation<'a> {
+    fn parser(&self) -> Option<&Parser<'a, 'a>> {
+        let schema = self.schema;
+        let (record, locator) = (self.record, self.locator);
+        self.parser
+            .get_or_init(|| {
+                schema
+                    .get_or_init(|| locator.event_schema(record).ok())
+                    .as_deref()
+                    .map(|schema| Parser::create(record, schema))
+            })
+            .as_ref()
+    }
+}
+
+type PredFn = dyn Fn(&Evaluation) -> bool + Send + Sync + 'static;
+
+/// A predicate on events
+///
+/// Predicates are built from the constructors of this type, and combined with [`Pred::and`], [`Pred::or`] and `!`.
+pub struct Pred(Box<PredFn>);
+
+impl Pred {
+    fn new<F>(f: F) -> Self
+    where
+        F: Fn(&Evaluation) -> bool + Send + Sync + 'static,
+    {
+        Self(Box::new(f))
+    }
+
+    /// A predicate on the header of the event
+    pub fn record<F>(f: F) -> Self
+    where
+        F: Fn(&EventRecord) -> bool + Send + Sync + 'static,
+    {
+        Self::new(move |eval| f(eval.record))
+    }
+
+    /// A predicate on the properties of the event
+    ///
+    /// This is `false` for events whose schema cannot be found.
+    ///
+    /// ```
+    /// # use ferrisetw::predicate::Pred;
+    /// let slow_queries = Pred::with_parser(|parser| {
+    ///     parser.try_parse::<u32>("QueryDuration").map(|d| d > 1000).unwrap_or(false)
+    /// });
+    /// ```
+    pub fn with_parser<F>(f: F) -> Self
+    where
+        F: Fn(&Parser) -> bool + Send + Sync + 'static,
+    {
+        Self::new(move |eval| eval.parser().map(&f).unwrap_or(false))
+    }
+
+    /// Matches every event
+    pub fn always() -> Self {
+        Self::new(|_| true)
+    }
+
+    /// Matches events with this event ID
+    pub fn id(id: u16) -> Self {
+        Self::record(move |record| record.event_id() == id)
+    }
+
+    /// Matches events with any of these event IDs
+    pub fn ids(ids: &[u16]) -> Self {
+        let ids = ids.to_vec();
+        Self::record(move |record| ids.contains(&record.event_id()))
+    }
+
+    /// Matches events with this opcode
+    pub fn opcode(opcode: u8) -> Self {
+        Self::record(move |record| record.opcode() == opcode)
+    }
+
+    /// Matches events from this provider
+    pub fn provider(guid: GUID) -> Self {
+        Self::record(move |record| record.provider_id() == guid)
+    }
+
+    /// Matches events emitted by this process
+    pub fn process_id(pid: u32) -> Self {
+        Self::record(move |record| record.process_id() == pid)
+    }
+
+    /// Matches events whose level is at most `level` (e.g. `2` matches `Critical` and `Error` events)
+    pub fn max_level(level: u8) -> Self {
+        Self::record(move |record| record.level() <= level)
+    }
+
+    /// Matches events that have any of the bits of `mask` in their keyword
+    pub fn keyword_any(mask: u64) -> Self {
+        Self::record(move |record| record.keyword() & mask != 0)
+    }
+
+    /// Matches events whose property `name` is equal to `value`
+    ///
+    /// This is `false` when the property is missing, or cannot be parsed as the same type as `value`.
+    pub fn prop_eq<V: PredicateValue>(name: &str, value: V) -> Self {
+        let name = name.to_string();
+        Self::with_parser(move |parser| value.eq_property(parser, &name))
+    }
+
+    /// Matches events that match both `self` and `other`
+    ///
+    /// `other` is not evaluated in case `self` does not match.
+    pub fn and(self, other: Pred) -> Self {
+        Self::new(move |eval| (self.0)(eval) && (other.0)(eval))
+    }
+
+    /// Matches events that match either `self` or `other`
+    ///
+    /// `other` is not evaluated in case `self` matches.
+    pub fn or(self, other: Pred) -> Self {
+        Self::new(move |eval| (self.0)(eval) || (other.0)(eval))
+    }
+
+    /// Evaluate this predicate on an event
+    pub fn matches(&self, record: &EventRecord, locator: &SchemaLocator) -> bool {
+        let schema = OnceCell::new();
+        let parser = OnceCell::new();
+        let eval = Evaluation {
+            record,
+            locator,
+            schema: &schema,
+            parser: &parser,
+        };
+        (self.0)(&eval)
+    }
+}
+
+impl std::ops::Not for Pred {
+    type Output = Pred;
+
+    fn not(self) -> Self::Output {
+        Self::new(move |eval| !(self.0)(eval))
+    }
+}
+
+impl std::fmt::Debug for Pred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pred").finish_non_exhaustive()
+    }
+}
+
+/// Values that properties can be compared to, see [`Pred::prop_eq`]
+pub trait PredicateValue: Send + Sync + 'static {
+    /// Whether the property `name` is equal to `self`
+    fn eq_property(&self, parser: &Parser, name: &str) -> bool;
+}
+
+macro_rules! impl_predicate_value {
+    ($T:ty) => {
+        impl PredicateValue for $T {
+            fn eq_property(&self, parser: &Parser, name: &str) -> bool {
+                parser
+                    .try_parse::<$T>(name)
+                    .map(|value| &value == self)
+                    .unwrap_or(false)
+            }
+        }
+    };
+}
+
+impl_predicate_value!(u8);
+impl_predicate_value!(i8);
+impl_predicate_value!(u16);
+impl_predicate_value!(i16);
+impl_predicate_value!(u32);
+impl_predicate_value!(i32);
+impl_predicate_value!(u64);
+impl_predicate_value!(i64);
+impl_predicate_value!(f32);
+impl_predicate_value!(f64);
+impl_predicate_value!(bool);
+impl_predicate_value!(String);
+impl_predicate_value!(GUID);
+impl_predicate_value!(IpAddr);
+impl_predicate_value!(RemotePtr);
+
+impl PredicateValue for &'static str {
+    fn eq_property(&self, parser: &Parser, name: &str) -> bool {
+        parser
+            .try_parse::<String>(name)
+            .map(|value| value == *self)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SyntheticEvent;
+
+    #[test]
+    fn test_combinators() {
+        let event = SyntheticEvent::new().with_level(2).with_keyword(0x10);
+        let record = event.record();
+        let locator = SchemaLocator::new();
+
+        assert!(Pred::max_level(3)
+            .and(Pred::keyword_any(0x30))
+            .matches(record, &locator));
+        assert!(!Pred::max_level(1)
+            .and(Pred::always())
+            .matches(record, &locator));
+        assert!(Pred::max_level(1)
+            .or(Pred::keyword_any(0x10))
+            .matches(record, &locator));
+        assert!((!Pred::opcode(1)).matches(record, &locator));
+        assert!(Pred::ids(&[0, 42]).matches(record, &locator));
+    }
+}
@@ -2,24 +2,47 @@
 //!
 //! Provides an abstraction over an [ETW Provider](https://docs.microsoft.com/en-us/windows/win32/etw/about-event-tracing#providers)
 use crate::native::etw_types::event_record::EventRecord;
-use crate::native::pla;
-use crate::schema_locator::SchemaLocator;
+use crate::native::{pla, tdh, DecodingSource, TdhNativeError};
+use crate::predicate::Pred;
+use crate::schema_locator::{SchemaError, SchemaLocator};
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::{self, MAX_EVENT_FILTERS_COUNT};
 
 pub(crate) mod event_filter;
-pub use event_filter::EventFilter;
+pub use event_filter::{EventFilter, PayloadFilter, PayloadOperator, PayloadPredicate};
 
+#[cfg(feature = "kernel")]
 pub mod kernel_providers;
+pub mod lint;
+pub mod metadata;
+pub mod protected;
 mod trace_flags;
+pub mod well_known;
 pub use trace_flags::TraceFlags;
 
 /// Provider module errors
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ProviderError {
     /// Wrapper over an internal [PlaError](crate::native::PlaError)
     ComProvider(crate::native::PlaError),
+    /// Some kernel providers cannot be enabled together (see [`kernel_providers::KernelProviderSet`])
+    #[cfg(feature = "kernel")]
+    KernelConflicts(Vec<kernel_providers::KernelConflict>),
+    /// The metadata of the provider (e.g. its keywords) could not be queried, see [`metadata::ProviderMetadata::query`]
+    Metadata(TdhNativeError),
+    /// The provider does not define a keyword with this name
+    UnknownKeyword(String),
+    /// The provider does not define a level with this name
+    UnknownLevel(String),
+    /// This filter cannot be built, see [`ProviderBuilder::try_add_filter`]
+    InvalidFilter(String),
+    /// The provider already has as many filters as Windows supports
+    TooManyFilters,
+    /// This string is not a GUID, see [`Provider::try_by_guid`]
+    InvalidGuid(String),
 }
 
 impl From<crate::native::PlaError> for ProviderError {
@@ -44,12 +67,21 @@ pub struct Provider {
     trace_flags: TraceFlags,
     /// Provider kernel flags, only apply to KernelProvider
     kernel_flags: u32,
+    /// Provider PERFINFO_GROUPMASK value, only apply to KernelProvider
+    kernel_group_mask: u32,
     /// Provider filters
     filters: Vec<EventFilter>,
+    /// Software filters, evaluated before invoking the callbacks
+    predicates: Vec<Pred>,
     /// Callbacks that will receive events from this Provider
     callbacks: Arc<RwLock<Vec<crate::EtwCallback>>>,
+    /// Receives the events whose schema cannot be retrieved, see [`ProviderBuilder::on_unparseable_event`]
+    unparseable_hook: Option<Mutex<UnparseableEventHook>>,
 }
 
+/// A closure that receives the events whose schema cannot be retrieved, see [`ProviderBuilder::on_unparseable_event`]
+pub type UnparseableEventHook = Box<dyn FnMut(&EventRecord, &SchemaError) + Send + 'static>;
+
 /// A Builder for a `Provider`
 ///
 /// See [`Provider`] for various functions that create `ProviderBuilder`s.
@@ -60,8 +92,11 @@ pub struct ProviderBuilder {
     level: u8,
     trace_flags: TraceFlags,
     kernel_flags: u32,
+    kernel_group_mask: u32,
     filters: Vec<EventFilter>,
+    predicates: Vec<Pred>,
     callbacks: Arc<RwLock<Vec<crate::EtwCallback>>>,
+    unparseable_hook: Option<UnparseableEventHook>,
 }
 
 impl std::fmt::Debug for ProviderBuilder {
@@ -73,8 +108,11 @@ impl std::fmt::Debug for ProviderBuilder {
             .field("level", &self.level)
             .field("trace_flags", &self.trace_flags)
             .field("kernel_flags", &self.kernel_flags)
+            .field("kernel_group_mask", &self.kernel_group_mask)
             .field("filters", &self.filters)
+            .field("predicates", &self.predicates.len())
             .field("n_callbacks", &self.callbacks.read().unwrap().len())
+            .field("unparseable_hook", &self.unparseable_hook.is_some())
             .finish()
     }
 }
@@ -85,6 +123,9 @@ impl Provider {
     ///
     /// Many types [implement `Into<GUID>`](https://microsoft.github.io/windows-docs-rs/doc/windows/core/struct.GUID.html#trait-implementations)
     /// and are acceptable as argument: `GUID` themselves, but also `&str`, etc.
+    ///
+    /// # Panics
+    /// Converting an invalid string into a `GUID` panics. See [`Provider::try_by_guid`] for GUIDs that are not known in advance.
     pub fn by_guid<G: Into<GUID>>(guid: G) -> ProviderBuilder {
         ProviderBuilder {
             guid: guid.into(),
@@ -93,27 +134,55 @@ impl Provider {
             level: 5,
             trace_flags: TraceFlags::empty(),
             kernel_flags: 0,
+            kernel_group_mask: 0,
             filters: Vec::new(),
+            predicates: Vec::new(),
             callbacks: Arc::new(RwLock::new(Vec::new())),
+            unparseable_hook: None,
+        }
+    }
+
+    /// Same as [`Self::by_guid`], but fails instead of panicking in case `guid` is not a valid GUID string
+    ///
+    /// The GUID may be enclosed in braces, e.g. `{22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716}`.
+    pub fn try_by_guid(guid: &str) -> Result<ProviderBuilder, ProviderError> {
+        let invalid = || ProviderError::InvalidGuid(guid.to_string());
+        let text = match guid.strip_prefix('{') {
+            Some(braced) => braced.strip_suffix('}').ok_or_else(invalid)?,
+            None => guid,
+        };
+        let well_formed = text.len() == 36
+            && text.char_indices().all(|(i, c)| match i {
+                8 | 13 | 18 | 23 => c == '-',
+                _ => c.is_ascii_hexdigit(),
+            });
+        if !well_formed {
+            return Err(invalid());
         }
+        let value = u128::from_str_radix(&text.replace('-', ""), 16).map_err(|_| invalid())?;
+        Ok(Self::by_guid(GUID::from_u128(value)))
     }
 
     /// Create a Kernel Provider
     ///
     /// You can pass either a KernelProvider you have created yourself, or one of the standard providers from [`crate::provider::kernel_providers`].
+    /// To enable several kernel providers that emit events of the same class, see [`kernel_providers::KernelProviderSet`].
+    #[cfg(feature = "kernel")]
     pub fn kernel(kernel_provider: &kernel_providers::KernelProvider) -> ProviderBuilder {
         let mut builder = Self::by_guid(kernel_provider.guid);
         builder.kernel_flags = kernel_provider.flags;
+        builder.kernel_group_mask = kernel_provider.group_mask;
         builder
     }
 
     /// Create a Provider defined by its name.
     ///
-    /// This function will look for the Provider GUID by means of the [ITraceDataProviderCollection](https://docs.microsoft.com/en-us/windows/win32/api/pla/nn-pla-itracedataprovidercollection)
+    /// This function looks for the Provider GUID among the providers registered on this machine (see [`enumerate_registered_providers`]).
+    /// Providers that are not found there are then looked for by means of the [ITraceDataProviderCollection](https://docs.microsoft.com/en-us/windows/win32/api/pla/nn-pla-itracedataprovidercollection)
     /// interface.
     ///
     /// # Remark
-    /// This function is considerably slow, prefer using the `by_guid` function when possible
+    /// This function is slow (and considerably slower for providers that are not registered), prefer using the `by_guid` function when possible
     ///
     /// # Example
     /// ```
@@ -121,11 +190,47 @@ impl Provider {
     /// let my_provider = Provider::by_name("Microsoft-Windows-WinINet").unwrap().build();
     /// ```
     pub fn by_name(name: &str) -> Result<ProviderBuilder, crate::native::PlaError> {
-        let guid = unsafe { pla::get_provider_guid(name) }?;
+        let registered = enumerate_registered_providers()
+            .map_err(|err| log::debug!("Unable to enumerate providers: {:?}", err))
+            .unwrap_or_default()
+            .into_iter()
+            .find(|provider| provider.name.eq_ignore_ascii_case(name));
+
+        let guid = match registered {
+            Some(provider) => provider.guid,
+            None => unsafe { pla::get_provider_guid(name) }?,
+        };
         Ok(Self::by_guid(guid))
     }
 }
 
+/// A provider whose schema is registered on this machine, see [`enumerate_registered_providers`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RegisteredProvider {
+    pub name: String,
+    pub guid: GUID,
+    /// Where the schema of the events of this provider comes from (usually an instrumentation manifest, or a MOF class)
+    pub schema_source: DecodingSource,
+}
+
+/// List the providers whose schema is registered on this machine
+///
+/// This uses [TdhEnumerateProviders](https://learn.microsoft.com/en-us/windows/win32/api/tdh/nf-tdh-tdhenumerateproviders), and is much faster than querying providers one by one with [`Provider::by_name`].<br/>
+/// Note that providers that do not register their schema (e.g. TraceLogging providers) are not part of this list.
+pub fn enumerate_registered_providers() -> Result<Vec<RegisteredProvider>, TdhNativeError> {
+    Ok(tdh::enumerate_providers()?
+        .into_iter()
+        .map(|(name, guid, schema_source)| RegisteredProvider {
+            name,
+            guid,
+            schema_source: DecodingSource::from(
+                windows::Win32::System::Diagnostics::Etw::DECODING_SOURCE(schema_source as i32),
+            ),
+        })
+        .collect())
+}
+
 // Actually use the Provider
 impl Provider {
     pub fn guid(&self) -> GUID {
@@ -146,14 +251,84 @@ impl Provider {
     pub fn kernel_flags(&self) -> u32 {
         self.kernel_flags
     }
+    pub fn kernel_group_mask(&self) -> u32 {
+        self.kernel_group_mask
+    }
     pub fn filters(&self) -> &[EventFilter] {
         &self.filters
     }
+    /// The software filters added with [`ProviderBuilder::filter_with`]
+    pub fn predicates(&self) -> &[Pred] {
+        &self.predicates
+    }
+    /// How many callbacks are registered on this provider
+    pub fn callback_count(&self) -> usize {
+        self.callbacks.read().map(|cbs| cbs.len()).unwrap_or(0)
+    }
 
-    pub(crate) fn on_event(&self, record: &EventRecord, locator: &SchemaLocator) {
-        if let Ok(mut callbacks) = self.callbacks.write() {
-            callbacks.iter_mut().for_each(|cb| cb(record, locator))
+    /// Whether ETW would have delivered this event to this provider alone, given its level and keywords
+    ///
+    /// This matters when several providers with the same GUID are enabled on a trace, see [`Self::merge`].
+    pub(crate) fn accepts(&self, record: &EventRecord) -> bool {
+        let level = record.level();
+        let keyword = record.keyword();
+        // Level 0 and keyword 0 mean "no filtering", both on the provider and on the event sides
+        let level_ok = self.level == 0 || level == 0 || level <= self.level;
+        let keyword_ok = keyword == 0
+            || ((self.any == 0 || keyword & self.any != 0) && keyword & self.all == self.all);
+        level_ok && keyword_ok
+    }
+
+    /// Combine the settings of providers that share the same GUID, so that it is enabled once on a trace
+    ///
+    /// ETW only keeps one set of settings per provider and per session: enabling the same GUID twice would override the first settings.
+    /// Instead, the merged provider has the union of their keywords, the highest of their levels and every trace flag.
+    /// Each provider then only receives the events it would have received on its own (see [`Self::accepts`]).<br/>
+    /// The merged provider has no callback. This returns `None` in case any of these providers has kernel-side filters, that cannot be merged.
+    pub(crate) fn merge(providers: &[Arc<Provider>]) -> Option<Provider> {
+        let first = providers.first()?;
+        if providers.iter().any(|prov| !prov.filters.is_empty()) {
+            return None;
+        }
+        let mut merged = Provider::by_guid(first.guid).build();
+        merged.any = if providers.iter().any(|prov| prov.any == 0) {
+            0
+        } else {
+            providers.iter().fold(0, |acc, prov| acc | prov.any)
         };
+        merged.all = providers.iter().fold(u64::MAX, |acc, prov| acc & prov.all);
+        merged.level = providers.iter().map(|prov| prov.level).max().unwrap_or(0);
+        if providers.iter().any(|prov| prov.level == 0) {
+            merged.level = 0;
+        }
+        merged.trace_flags = providers
+            .iter()
+            .fold(TraceFlags::empty(), |acc, prov| acc | prov.trace_flags);
+        Some(merged)
+    }
+
+    pub(crate) fn on_event(&self, record: &EventRecord, locator: &SchemaLocator) {
+        if let Some(hook) = &self.unparseable_hook {
+            // The schema is cached by the locator, so that the callbacks will not look it up again
+            if let Err(err) = locator.event_schema(record) {
+                let mut hook = hook.lock().unwrap_or_else(PoisonError::into_inner);
+                hook(record, &err);
+                return;
+            }
+        }
+        if !self
+            .predicates
+            .iter()
+            .all(|pred| pred.matches(record, locator))
+        {
+            return;
+        }
+        // A callback that panicked (see `PanicPolicy::LogAndContinue`) has poisoned the lock, but the callbacks are still usable
+        let mut callbacks = self
+            .callbacks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        callbacks.iter_mut().for_each(|cb| cb(record, locator));
     }
 }
 
@@ -166,8 +341,11 @@ impl std::fmt::Debug for Provider {
             .field("level", &self.level)
             .field("trace_flags", &self.trace_flags)
             .field("kernel_flags", &self.kernel_flags)
+            .field("kernel_group_mask", &self.kernel_group_mask)
             .field("filters", &self.filters)
+            .field("predicates", &self.predicates.len())
             .field("callbacks", &self.callbacks.read().unwrap().len())
+            .field("unparseable_hook", &self.unparseable_hook.is_some())
             .finish()
     }
 }
@@ -176,6 +354,9 @@ impl ProviderBuilder {
     /// Set the `any` flag in the Provider instance
     /// [More info](https://docs.microsoft.com/en-us/message-analyzer/system-etw-provider-event-keyword-level-settings#filtering-with-system-etw-provider-event-keywords-and-levels)
     ///
+    /// Note: `0` (the default) is left to the interpretation of each provider. Most of them consider it means "every keyword", but some only emit their events that have no keyword at all.
+    /// See [`ProviderBuilder::all_events`] to remove any ambiguity.
+    ///
     /// # Example
     /// ```
     /// # use ferrisetw::provider::Provider;
@@ -217,6 +398,25 @@ impl ProviderBuilder {
         self
     }
 
+    /// Enable every event of this provider, regardless of its level and keywords
+    ///
+    /// This sets the level to `0xFF` and `any` to `0xFFFFFFFFFFFFFFFF` (and resets `all`).<br/>
+    /// This is convenient when exploring what a provider emits, but beware that:
+    /// * some providers are very verbose, and may cause events to be lost when every keyword is enabled
+    /// * some keywords have side effects (e.g. rundown or debug keywords make the provider emit extra events, or log more expensive data)
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// let my_provider = Provider::by_guid("1EDEEE53-0AFE-4609-B846-D8C0B2075B1F").all_events().build();
+    /// ```
+    pub fn all_events(mut self) -> Self {
+        self.level = 0xFF;
+        self.any = u64::MAX;
+        self.all = 0;
+        self
+    }
+
     /// Set the `trace_flags` flag in the Provider instance
     /// [More info](https://docs.microsoft.com/en-us/windows-hardware/drivers/devtest/trace-flags)
     ///
@@ -230,6 +430,73 @@ impl ProviderBuilder {
         self
     }
 
+    /// Set the `any` flag from the names of keywords, as defined by the provider
+    ///
+    /// Names are case-insensitive, and are resolved right away, using the keywords TDH reports for the provider (see also [`metadata`]).
+    /// This fails in case the keywords cannot be queried (e.g. for providers that are not registered on this machine), or if any name is unknown.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use ferrisetw::provider::Provider;
+    /// let my_provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716") // Microsoft-Windows-Kernel-Process
+    ///     .with_keywords_any(&["WINEVENT_KEYWORD_PROCESS", "WINEVENT_KEYWORD_IMAGE"])
+    ///     .unwrap()
+    ///     .build();
+    /// ```
+    pub fn with_keywords_any(self, names: &[&str]) -> Result<Self, ProviderError> {
+        let any = keywords_mask(
+            &self.field_information(Etw::EventKeywordInformation)?,
+            names,
+        )?;
+        Ok(self.any(any))
+    }
+
+    /// Set the `all` flag from the names of keywords, as defined by the provider
+    ///
+    /// See [`Self::with_keywords_any`]
+    pub fn with_keywords_all(self, names: &[&str]) -> Result<Self, ProviderError> {
+        let all = keywords_mask(
+            &self.field_information(Etw::EventKeywordInformation)?,
+            names,
+        )?;
+        Ok(self.all(all))
+    }
+
+    /// Set the `level` flag from the name of a level
+    ///
+    /// The levels defined by the provider are looked up first (case-insensitively). The standard levels (`Critical`, `Error`, `Warning`, `Informational` and `Verbose`, optionally prefixed with `win:`)
+    /// are always accepted, even when the metadata of the provider cannot be queried.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// let my_provider = Provider::by_guid("1EDEEE53-0AFE-4609-B846-D8C0B2075B1F").with_level("win:Warning").unwrap().build();
+    /// ```
+    pub fn with_level(self, name: &str) -> Result<Self, ProviderError> {
+        let defined = match self.field_information(Etw::EventLevelInformation) {
+            Ok(levels) => levels
+                .into_iter()
+                .find(|(level_name, _)| level_name.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value as u8),
+            Err(err) => {
+                log::debug!("Unable to query the levels of a provider: {:?}", err);
+                None
+            }
+        };
+        let level = defined
+            .or_else(|| standard_level(name))
+            .ok_or_else(|| ProviderError::UnknownLevel(name.to_string()))?;
+        Ok(self.level(level))
+    }
+
+    /// The names and values of the levels, keywords or channels defined by the provider
+    fn field_information(
+        &self,
+        field_type: Etw::EVENT_FIELD_TYPE,
+    ) -> Result<Vec<(String, u64)>, ProviderError> {
+        tdh::provider_field_information(&self.guid, field_type).map_err(ProviderError::Metadata)
+    }
+
     /// Add a callback function that will be called when the Provider generates an Event
     ///
     /// # Notes
@@ -259,6 +526,18 @@ impl ProviderBuilder {
         self
     }
 
+    /// Set a closure that receives the events of this provider whose schema cannot be retrieved, along with the error
+    ///
+    /// These events are then not passed to the predicates and callbacks of this provider, which can thus assume [`SchemaLocator::event_schema`] succeeds.<br/>
+    /// This is useful to count such events, dump their raw user buffers, forward them elsewhere, etc.
+    pub fn on_unparseable_event<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&EventRecord, &SchemaError) + Send + 'static,
+    {
+        self.unparseable_hook = Some(Box::new(hook));
+        self
+    }
+
     /// Add a filter to this Provider.
     ///
     /// Adding multiple filters will bind them with an `AND` relationship.<br/>
@@ -280,6 +559,39 @@ impl ProviderBuilder {
         self
     }
 
+    /// Same as [`Self::add_filter`], but checks the filter right away
+    ///
+    /// [`Self::add_filter`] accepts any filter, and filters that cannot be built are silently ignored when the provider is enabled.
+    /// Instead, this fails in case the filter is invalid (e.g. empty, or too large), or in case the provider already has `MAX_EVENT_FILTERS_COUNT` filters.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::{EventFilter, Provider, ProviderError};
+    /// let result = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716").try_add_filter(EventFilter::ByEventIds(Vec::new()));
+    /// assert!(matches!(result, Err(ProviderError::InvalidFilter(_))));
+    /// ```
+    pub fn try_add_filter(mut self, filter: EventFilter) -> Result<Self, ProviderError> {
+        if self.filters.len() >= MAX_EVENT_FILTERS_COUNT as usize {
+            return Err(ProviderError::TooManyFilters);
+        }
+        filter
+            .to_event_filter_descriptor_for(&self.guid)
+            .map_err(|err| ProviderError::InvalidFilter(err.to_string()))?;
+        self.filters.push(filter);
+        Ok(self)
+    }
+
+    /// Only invoke the callbacks for events that match `pred`
+    ///
+    /// Contrary to [`Self::add_filter`], this is evaluated in software, on events that have already been delivered by ETW. It can thus test any field or property of the events.<br/>
+    /// Adding multiple predicates will bind them with an `AND` relationship.
+    ///
+    /// See [`crate::predicate`] for more info.
+    pub fn filter_with(mut self, pred: Pred) -> Self {
+        self.predicates.push(pred);
+        self
+    }
+
     /// Build the provider
     ///
     /// # Example
@@ -301,8 +613,199 @@ impl ProviderBuilder {
             level: self.level,
             trace_flags: self.trace_flags,
             kernel_flags: self.kernel_flags,
+            kernel_group_mask: self.kernel_group_mask,
             filters: self.filters,
+            predicates: self.predicates,
             callbacks: self.callbacks,
+            unparseable_hook: self.unparseable_hook.map(Mutex::new),
         }
     }
 }
+
+/// The combined mask of keywords, given their names
+fn keywords_mask(keywords: &[(String, u64)], names: &[&str]) -> Result<u64, ProviderError> {
+    names.iter().try_fold(0, |mask, name| {
+        match keywords
+            .iter()
+            .find(|(keyword_name, _)| keyword_name.eq_ignore_ascii_case(name))
+        {
+            Some((_, keyword)) => Ok(mask | keyword),
+            None => Err(ProviderError::UnknownKeyword(name.to_string())),
+        }
+    })
+}
+
+/// The value of the levels defined by Windows (see `winmeta.xml`)
+fn standard_level(name: &str) -> Option<u8> {
+    let name = name.to_ascii_lowercase();
+    match name.strip_prefix("win:").unwrap_or(&name) {
+        "logalways" => Some(0),
+        "critical" => Some(1),
+        "error" => Some(2),
+        "warning" => Some(3),
+        "informational" | "information" => Some(4),
+        "verbose" => Some(5),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_try_by_guid() {
+        let expected = GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716");
+        for valid in [
+            "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716",
+            "{22FB2CD6-0E7B-422B-A0C7-2FAD1FD0E716}",
+        ] {
+            assert_eq!(Provider::try_by_guid(valid).unwrap().guid, expected);
+        }
+        for invalid in [
+            "",
+            "not a guid",
+            "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e71",
+            "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e71g",
+            "22fb2cd60e7b-422b-a0c7-2fad1fd0e7160",
+            "{22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716",
+            "+2fb2cd6-0e7b-422b-a0c7-2fad1fd0e716",
+        ] {
+            assert!(
+                matches!(Provider::try_by_guid(invalid), Err(ProviderError::InvalidGuid(s)) if s == invalid),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_try_add_filter() {
+        let builder = Provider::by_guid(GUID::from_u128(0x789))
+            .try_add_filter(EventFilter::ByEventIds(vec![1, 2]))
+            .unwrap();
+        assert_eq!(builder.filters.len(), 1);
+        assert!(matches!(
+            builder.try_add_filter(EventFilter::ByEventIds(Vec::new())),
+            Err(ProviderError::InvalidFilter(_))
+        ));
+
+        let builder = (0..MAX_EVENT_FILTERS_COUNT)
+            .fold(Provider::by_guid(GUID::from_u128(0x789)), |builder, pid| {
+                builder.add_filter(EventFilter::ByPids(vec![pid as u16]))
+            });
+        assert!(matches!(
+            builder.try_add_filter(EventFilter::ByEventIds(vec![1])),
+            Err(ProviderError::TooManyFilters)
+        ));
+    }
+
+    #[test]
+    fn test_named_keywords_and_levels() {
+        let metadata = metadata::ProviderMetadata::from_fields(
+            GUID::from_u128(0x788),
+            vec![(String::from("Debug"), 16)],
+            vec![
+                (String::from("Network"), 0x40),
+                (String::from("Errors"), 0x8000_0000_0000_0000),
+            ],
+        );
+        assert_eq!(
+            keywords_mask(metadata.keywords(), &["network", "Errors"]).unwrap(),
+            0x8000_0000_0000_0040
+        );
+        assert_eq!(keywords_mask(metadata.keywords(), &[]).unwrap(), 0);
+        assert!(matches!(
+            keywords_mask(metadata.keywords(), &["Network", "Disk"]),
+            Err(ProviderError::UnknownKeyword(name)) if name == "Disk"
+        ));
+
+        assert_eq!(metadata.level("debug"), Some(16));
+        assert_eq!(standard_level("win:Informational"), Some(4));
+        assert_eq!(standard_level("ERROR"), Some(2));
+        assert_eq!(standard_level("Debug"), None);
+
+        // This provider is not registered, only the standard levels can be used
+        let builder = Provider::by_guid(GUID::from_u128(0x788));
+        assert_eq!(builder.with_level("Verbose").unwrap().level, 5);
+        assert!(matches!(
+            Provider::by_guid(GUID::from_u128(0x788)).with_keywords_any(&["Network"]),
+            Err(ProviderError::Metadata(_))
+        ));
+    }
+
+    #[test]
+    fn test_unparseable_event() {
+        let unparseable = Arc::new(Mutex::new(Vec::new()));
+        let unparseable_clone = Arc::clone(&unparseable);
+        let provider_guid = GUID::from_u128(0x6d1ea1a5_54a4_4d2b_9a4c_000000000775);
+        let provider = Provider::by_guid(provider_guid)
+            .add_callback(|_, _| panic!("this event has no schema"))
+            .on_unparseable_event(move |record, _err| {
+                unparseable_clone.lock().unwrap().push(record.event_id())
+            })
+            .build();
+
+        // No manifest is registered for this provider, TDH can not find its schema
+        let event = SyntheticEvent::new().with_provider(provider_guid);
+        provider.on_event(event.record(), &SchemaLocator::new());
+        assert_eq!(
+            *unparseable.lock().unwrap(),
+            vec![event.record().event_id()]
+        );
+    }
+
+    #[test]
+    fn test_merge_providers() {
+        let guid = GUID::from_u128(0x784);
+        let errors = Arc::new(Provider::by_guid(guid).level(2).any(0x1).build());
+        let verbose = Arc::new(
+            Provider::by_guid(guid)
+                .level(5)
+                .any(0x6)
+                .all(0x2)
+                .trace_flags(TraceFlags::EVENT_ENABLE_PROPERTY_SID)
+                .build(),
+        );
+        let merged = Provider::merge(&[Arc::clone(&errors), Arc::clone(&verbose)]).unwrap();
+        assert_eq!(merged.guid(), guid);
+        assert_eq!(merged.level(), 5);
+        assert_eq!(merged.any(), 0x7);
+        assert_eq!(merged.all(), 0);
+        assert_eq!(merged.trace_flags(), TraceFlags::EVENT_ENABLE_PROPERTY_SID);
+
+        let everything = Arc::new(Provider::by_guid(guid).level(0).build());
+        let merged = Provider::merge(&[errors, Arc::clone(&everything)]).unwrap();
+        assert_eq!((merged.level(), merged.any()), (0, 0));
+
+        let filtered = Arc::new(
+            Provider::by_guid(guid)
+                .add_filter(EventFilter::ByEventIds(vec![1]))
+                .build(),
+        );
+        assert!(Provider::merge(&[everything, filtered]).is_none());
+    }
+
+    #[test]
+    fn test_provider_accepts() {
+        let provider = Provider::by_guid(GUID::from_u128(0x784))
+            .level(3)
+            .any(0x3)
+            .all(0x1)
+            .build();
+        let event = |level, keyword| {
+            SyntheticEvent::new()
+                .with_level(level)
+                .with_keyword(keyword)
+        };
+        assert!(provider.accepts(event(2, 0x1).record()));
+        assert!(provider.accepts(event(0, 0).record()));
+        // Level too verbose
+        assert!(!provider.accepts(event(4, 0x1).record()));
+        // None of the `any` keywords
+        assert!(!provider.accepts(event(2, 0x4).record()));
+        // Missing one of the `all` keywords
+        assert!(!provider.accepts(event(2, 0x2).record()));
+    }
+}
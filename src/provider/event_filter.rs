@@ -1,15 +1,18 @@
 use std::alloc::Layout;
 use std::error::Error;
 
+use windows::core::GUID;
 use windows::Win32::Foundation::BOOLEAN;
 use windows::Win32::System::Diagnostics::Etw::{
-    EVENT_FILTER_DESCRIPTOR, EVENT_FILTER_EVENT_ID, EVENT_FILTER_TYPE_EVENT_ID,
-    EVENT_FILTER_TYPE_PID,
+    self, EVENT_FILTER_DESCRIPTOR, EVENT_FILTER_EVENT_ID, EVENT_FILTER_TYPE_EVENT_ID,
+    EVENT_FILTER_TYPE_EXECUTABLE_NAME, EVENT_FILTER_TYPE_PID,
 };
 use windows::Win32::System::Diagnostics::Etw::{
-    MAX_EVENT_FILTER_EVENT_ID_COUNT, MAX_EVENT_FILTER_PID_COUNT,
+    MAX_EVENT_FILTER_EVENT_ID_COUNT, MAX_EVENT_FILTER_PID_COUNT, MAX_PAYLOAD_PREDICATES,
 };
 
+use crate::native::tdh;
+
 /// Specifies how this provider will filter its events
 ///
 /// Some filters are not effective prior to Windows 8.1 ([source](https://learn.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor#remarks))
@@ -23,6 +26,14 @@ pub enum EventFilter {
     ByPids(Vec<u16>),
     /// Filter by ETW Event ID.
     ByEventIds(Vec<u16>),
+    /// Filter by the executable name of the process that emits the events (e.g. `"chrome.exe"`).
+    /// Names are file names, without their directory. This is only effective from Windows 8.1.
+    ByExecutableNames(Vec<String>),
+    /// Filter by the values of the properties of events.
+    /// This is evaluated by the kernel, and requires the provider to have a manifest.
+    ///
+    /// An event is kept if it matches any of the filters that target its event ID.
+    ByPayload(Vec<PayloadFilter>),
     // TODO: see https://docs.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_filter_descriptor
     //       and https://docs.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-enabletraceex2#remarks
     //       other filter types are possible
@@ -31,14 +42,187 @@ pub enum EventFilter {
 
 impl EventFilter {
     /// Builds an EventFilterDescriptor (which can in turn generate an EVENT_FILTER_DESCRIPTOR)
+    ///
+    /// This fails for [`EventFilter::ByPayload`], that needs to know its provider (see [`Self::to_event_filter_descriptor_for`])
     pub fn to_event_filter_descriptor(&self) -> Result<EventFilterDescriptor, Box<dyn Error>> {
         match self {
             EventFilter::ByPids(pids) => EventFilterDescriptor::try_new_by_process_ids(pids),
             EventFilter::ByEventIds(ids) => EventFilterDescriptor::try_new_by_event_ids(ids),
+            EventFilter::ByExecutableNames(names) => {
+                EventFilterDescriptor::try_new_by_executable_names(names)
+            }
+            EventFilter::ByPayload(_) => Err("Payload filters require a provider GUID".into()),
+        }
+    }
+
+    /// Builds an EventFilterDescriptor for a filter of the given provider
+    pub fn to_event_filter_descriptor_for(
+        &self,
+        provider: &GUID,
+    ) -> Result<EventFilterDescriptor, Box<dyn Error>> {
+        match self {
+            EventFilter::ByPayload(filters) => {
+                EventFilterDescriptor::try_new_by_payload(provider, filters)
+            }
+            _ => self.to_event_filter_descriptor(),
+        }
+    }
+}
+
+/// A comparison operator, used by [`PayloadPredicate`]
+///
+/// See [PAYLOAD_OPERATOR](https://learn.microsoft.com/en-us/windows/win32/api/tdh/ne-tdh-payload_operator)
+///
+/// When deserialized, operators are written in snake case (e.g. `"not_between"`), or as their usual symbols for comparisons (e.g. `"<="`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum PayloadOperator {
+    #[cfg_attr(feature = "serde", serde(alias = "=="))]
+    Eq,
+    #[cfg_attr(feature = "serde", serde(alias = "!="))]
+    Ne,
+    #[cfg_attr(feature = "serde", serde(alias = "<="))]
+    Le,
+    #[cfg_attr(feature = "serde", serde(alias = ">"))]
+    Gt,
+    #[cfg_attr(feature = "serde", serde(alias = "<"))]
+    Lt,
+    #[cfg_attr(feature = "serde", serde(alias = ">="))]
+    Ge,
+    /// The value must be two numbers separated by a comma, e.g. `"10,20"`
+    Between,
+    /// The value must be two numbers separated by a comma, e.g. `"10,20"`
+    NotBetween,
+    Modulo,
+    /// The property contains the value as a substring
+    Contains,
+    /// The property does not contain the value as a substring
+    DoesntContain,
+    /// The property is equal to the value, as strings
+    Is,
+    /// The property is not equal to the value, as strings
+    IsNot,
+}
+
+impl PayloadOperator {
+    fn to_native(self) -> u16 {
+        let op = match self {
+            PayloadOperator::Eq => Etw::PAYLOADFIELD_EQ,
+            PayloadOperator::Ne => Etw::PAYLOADFIELD_NE,
+            PayloadOperator::Le => Etw::PAYLOADFIELD_LE,
+            PayloadOperator::Gt => Etw::PAYLOADFIELD_GT,
+            PayloadOperator::Lt => Etw::PAYLOADFIELD_LT,
+            PayloadOperator::Ge => Etw::PAYLOADFIELD_GE,
+            PayloadOperator::Between => Etw::PAYLOADFIELD_BETWEEN,
+            PayloadOperator::NotBetween => Etw::PAYLOADFIELD_NOTBETWEEN,
+            PayloadOperator::Modulo => Etw::PAYLOADFIELD_MODULO,
+            PayloadOperator::Contains => Etw::PAYLOADFIELD_CONTAINS,
+            PayloadOperator::DoesntContain => Etw::PAYLOADFIELD_DOESNTCONTAIN,
+            PayloadOperator::Is => Etw::PAYLOADFIELD_IS,
+            PayloadOperator::IsNot => Etw::PAYLOADFIELD_ISNOT,
+        };
+        op.0 as u16
+    }
+}
+
+/// A condition on a property of an event, e.g. `QueryName IS "example.com"`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PayloadPredicate {
+    pub property: String,
+    pub operator: PayloadOperator,
+    /// The value to compare to, formatted as a string (even for numeric properties)
+    pub value: String,
+}
+
+impl PayloadPredicate {
+    pub fn new(property: &str, operator: PayloadOperator, value: &str) -> Self {
+        Self {
+            property: property.to_string(),
+            operator,
+            value: value.to_string(),
         }
     }
 }
 
+/// Conditions on the properties of a given event, see [`EventFilter::ByPayload`]
+///
+/// # Example
+/// ```
+/// # use ferrisetw::provider::{EventFilter, PayloadFilter, PayloadOperator, PayloadPredicate, Provider};
+/// let example_com = PayloadFilter::new(3006, 0)
+///     .predicate(PayloadPredicate::new("QueryName", PayloadOperator::Is, "example.com"));
+///
+/// Provider::by_guid("1c95126e-7eea-49a9-a3fe-a378b03ddb4d")
+///     .add_filter(EventFilter::ByPayload(vec![example_com]))
+///     .build();
+/// ```
+///
+/// With the `serde` feature, filters can also be deserialized, so that they can be described in a configuration file (e.g. JSON or TOML) rather than in code.
+/// They are compiled into `PAYLOAD_FILTER_PREDICATE`s when the provider is enabled.
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # {
+/// # use ferrisetw::provider::{EventFilter, PayloadFilter, Provider};
+/// let description = r#"[
+///     {
+///         "event_id": 3006,
+///         "predicates": [
+///             { "property": "QueryName", "operator": "is", "value": "example.com" },
+///             { "property": "QueryType", "operator": "==", "value": "28" }
+///         ],
+///         "match_any": true
+///     }
+/// ]"#;
+/// let filters: Vec<PayloadFilter> = serde_json::from_str(description).unwrap();
+///
+/// Provider::by_guid("1c95126e-7eea-49a9-a3fe-a378b03ddb4d")
+///     .add_filter(EventFilter::ByPayload(filters))
+///     .build();
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PayloadFilter {
+    pub event_id: u16,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub event_version: u8,
+    pub predicates: Vec<PayloadPredicate>,
+    /// Whether an event is kept when any predicate is true (rather than when all predicates are true)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub match_any: bool,
+}
+
+impl PayloadFilter {
+    /// A filter on the given event, that keeps events that match every predicate
+    pub fn new(event_id: u16, event_version: u8) -> Self {
+        Self {
+            event_id,
+            event_version,
+            predicates: Vec::new(),
+            match_any: false,
+        }
+    }
+
+    /// Add a predicate to this filter
+    ///
+    /// At most 8 predicates are supported
+    pub fn predicate(mut self, predicate: PayloadPredicate) -> Self {
+        self.predicates.push(predicate);
+        self
+    }
+
+    /// Keep events that match any predicate, rather than every predicate
+    pub fn match_any(mut self, match_any: bool) -> Self {
+        self.match_any = match_any;
+        self
+    }
+}
+
 /// Similar to windows' `EVENT_FILTER_DESCRIPTOR`, but with owned data
 ///
 /// See [`Self::as_event_filter_descriptor`] to get a Windows-rs-compatible type
@@ -47,8 +231,15 @@ pub struct EventFilterDescriptor {
     data: *mut u8,
     layout: Layout,
     ty: u32,
+    /// The data has been allocated by TDH (payload filters), rather than by us
+    tdh_allocated: bool,
 }
 
+// Safety: the data is owned by this instance, and is never shared with any other instance
+unsafe impl Send for EventFilterDescriptor {}
+// Safety: the data is never mutated after construction
+unsafe impl Sync for EventFilterDescriptor {}
+
 impl EventFilterDescriptor {
     /// Allocates a new instance, where the included data is `data_size` bytes, and is suitably aligned for type `T`
     fn try_new<T>(data_size: usize) -> Result<Self, Box<dyn Error>> {
@@ -73,6 +264,7 @@ impl EventFilterDescriptor {
             data,
             layout,
             ty: 0,
+            tdh_allocated: false,
         })
     }
 
@@ -151,6 +343,84 @@ impl EventFilterDescriptor {
         Ok(s)
     }
 
+    /// Build a new instance that will filter by executable names.
+    ///
+    /// Returns an `Err` in case the allocation failed, if no names were given, or if they exceed the size limit of a filter
+    pub fn try_new_by_executable_names(names: &[String]) -> Result<Self, Box<dyn Error>> {
+        if names.iter().any(|name| name.contains(';')) {
+            return Err("Executable names must not contain a ';'".into());
+        }
+        if names.is_empty() {
+            return Err("Filter must not be empty".into());
+        }
+
+        // A single null-terminated UTF-16 string, where names are separated by semicolons
+        let wide: Vec<u16> = names
+            .join(";")
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .collect();
+
+        let mut s = Self::try_new::<u16>(std::mem::size_of_val(wide.as_slice()))?;
+        s.ty = EVENT_FILTER_TYPE_EXECUTABLE_NAME;
+        unsafe {
+            // Safety: `s.data` has been allocated with the size of `wide`, and is suitably aligned for u16
+            std::ptr::copy_nonoverlapping(wide.as_ptr(), s.data.cast::<u16>(), wide.len());
+        }
+        Ok(s)
+    }
+
+    /// Build a new instance that will filter by the properties of the events of `provider`.
+    ///
+    /// Returns an `Err` in case TDH rejects the filters (e.g. the provider has no manifest, or a property does not exist), or if either zero or too many predicates were given
+    pub fn try_new_by_payload(
+        provider: &GUID,
+        filters: &[PayloadFilter],
+    ) -> Result<Self, Box<dyn Error>> {
+        if filters.is_empty() {
+            return Err("Filter must not be empty".into());
+        }
+
+        let mut handles = Vec::with_capacity(filters.len());
+        for filter in filters {
+            if filter.predicates.is_empty() {
+                return Err("Filter must not be empty".into());
+            }
+            if filter.predicates.len() > MAX_PAYLOAD_PREDICATES as usize {
+                return Err("Too many payload predicates".into());
+            }
+            let predicates: Vec<(&str, u16, &str)> = filter
+                .predicates
+                .iter()
+                .map(|p| {
+                    (
+                        p.property.as_str(),
+                        p.operator.to_native(),
+                        p.value.as_str(),
+                    )
+                })
+                .collect();
+            let handle = tdh::create_payload_filter(
+                provider,
+                filter.event_id,
+                filter.event_version,
+                filter.match_any,
+                &predicates,
+            )
+            .map_err(|err| format!("Invalid payload filter: {}", err))?;
+            handles.push(handle);
+        }
+
+        let descriptor = tdh::aggregate_payload_filters(&handles)
+            .map_err(|err| format!("Unable to aggregate payload filters: {}", err))?;
+        Ok(Self {
+            data: descriptor.Ptr as *mut u8,
+            layout: Layout::from_size_align(descriptor.Size as usize, 1)?,
+            ty: descriptor.Type,
+            tdh_allocated: true,
+        })
+    }
+
     /// Returns the EVENT_FILTER_DESCRIPTOR from this [`EventFilterDescriptor`]
     ///
     /// # Safety
@@ -168,6 +438,15 @@ impl EventFilterDescriptor {
 
 impl Drop for EventFilterDescriptor {
     fn drop(&mut self) {
+        if self.tdh_allocated {
+            let mut descriptor = self.as_event_filter_descriptor();
+            unsafe {
+                // Safety: this descriptor is the one returned by TdhAggregatePayloadFilters
+                tdh::cleanup_payload_filter_descriptor(&mut descriptor);
+            }
+            return;
+        }
+
         unsafe {
             // Safety:
             // * ptr is a block of memory currently allocated via alloc::alloc
@@ -176,3 +455,64 @@ impl Drop for EventFilterDescriptor {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_executable_names_filter() {
+        let names = vec!["chrome.exe".to_string(), "a.exe".to_string()];
+        let descriptor = EventFilterDescriptor::try_new_by_executable_names(&names).unwrap();
+        let native = descriptor.as_event_filter_descriptor();
+        assert_eq!(native.Type, EVENT_FILTER_TYPE_EXECUTABLE_NAME);
+        assert_eq!(native.Size as usize, ("chrome.exe;a.exe".len() + 1) * 2);
+
+        let data = unsafe {
+            std::slice::from_raw_parts(native.Ptr as *const u16, native.Size as usize / 2)
+        };
+        assert_eq!(String::from_utf16_lossy(data), "chrome.exe;a.exe\0");
+
+        assert!(EventFilterDescriptor::try_new_by_executable_names(&[]).is_err());
+        assert!(EventFilterDescriptor::try_new_by_executable_names(&["a;b".to_string()]).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize_payload_filters() {
+        let description = r#"[
+            { "event_id": 1, "predicates": [{ "property": "Pid", "operator": ">=", "value": "4" }] },
+            {
+                "event_id": 2,
+                "event_version": 1,
+                "predicates": [
+                    { "property": "Size", "operator": "not_between", "value": "10,20" },
+                    { "property": "Name", "operator": "doesnt_contain", "value": "svc" }
+                ],
+                "match_any": true
+            }
+        ]"#;
+        let filters: Vec<PayloadFilter> = serde_json::from_str(description).unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!((filters[0].event_id, filters[0].event_version), (1, 0));
+        assert!(!filters[0].match_any);
+        assert_eq!(filters[0].predicates[0].operator, PayloadOperator::Ge);
+        assert_eq!(
+            filters[0].predicates[0].operator.to_native(),
+            Etw::PAYLOADFIELD_GE.0 as u16
+        );
+        assert!(filters[1].match_any);
+        assert_eq!(
+            filters[1].predicates[0].operator,
+            PayloadOperator::NotBetween
+        );
+        assert_eq!(
+            filters[1].predicates[1].operator,
+            PayloadOperator::DoesntContain
+        );
+        assert_eq!(filters[1].predicates[1].value, "svc");
+
+        let unknown = r#"{ "property": "Pid", "operator": "like", "value": "4" }"#;
+        assert!(serde_json::from_str::<PayloadPredicate>(unknown).is_err());
+    }
+}
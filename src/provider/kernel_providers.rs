@@ -8,6 +8,17 @@
 
 use super::GUID;
 
+mod classes;
+pub use classes::{KernelEventClass, KernelEventKind};
+pub mod events;
+pub mod file_io;
+pub mod handles;
+pub mod network;
+pub mod processes;
+pub mod registry;
+mod set;
+pub use set::{KernelConflict, KernelProviderSet};
+
 /// List of Kernel Providers GUIDs
 ///
 /// Credits: [KrabsETW::kernel_guids](https://github.com/microsoft/krabsetw/blob/master/krabs/krabs/kernel_guids.hpp)
@@ -184,6 +195,36 @@ mod kernel_flags {
     pub const EVENT_TRACE_FLAG_FILE_IO_INIT: u32 = 0x04000000;
 }
 
+/// List of PERFINFO_GROUPMASK values, for kernel events that have no `EnableFlags` bit
+///
+/// The three upper bits are the index of the mask in the `PERFINFO_GROUPMASK`, the other bits are the flags within that mask.<br/>
+/// Credits: [KrabsETW::perfinfo_groupmask](https://github.com/microsoft/krabsetw/blob/master/krabs/krabs/perfinfo_groupmask.hpp)
+mod group_masks {
+    pub const PERF_MASK_INDEX: u32 = 0xe0000000;
+    pub const PERF_MASK_GROUP: u32 = !PERF_MASK_INDEX;
+    pub const PERF_NUM_MASKS: usize = 8;
+
+    pub const PERF_POOL: u32 = 0x20000040;
+    pub const PERF_OB_HANDLE: u32 = 0x80000040;
+    pub const PERF_OB_OBJECT: u32 = 0x80000080;
+}
+
+/// The `PERFINFO_GROUPMASK` that enables both these `EnableFlags` and group masks
+///
+/// The first mask of a `PERFINFO_GROUPMASK` holds the `EnableFlags`, because setting a group mask overrides them.
+pub(crate) fn perfinfo_group_mask(
+    enable_flags: u32,
+    group_masks: impl IntoIterator<Item = u32>,
+) -> [u32; group_masks::PERF_NUM_MASKS] {
+    let mut masks = [0; group_masks::PERF_NUM_MASKS];
+    masks[0] = enable_flags;
+    for group_mask in group_masks {
+        let index = ((group_mask & group_masks::PERF_MASK_INDEX) >> 29) as usize;
+        masks[index] |= group_mask & group_masks::PERF_MASK_GROUP;
+    }
+    masks
+}
+
 /// Contains kernel provider identifiers.
 ///
 /// You'll need to use it with [`crate::provider::Provider::kernel`]
@@ -193,12 +234,29 @@ pub struct KernelProvider {
     pub guid: GUID,
     /// Kernel Provider Flags
     pub flags: u32,
+    /// Kernel Provider PERFINFO_GROUPMASK value, for providers that cannot be enabled with `flags` (0 otherwise)
+    pub group_mask: u32,
 }
 
 impl KernelProvider {
     /// Use the `new` function to create a Kernel Provider which can be then tied into a Provider
     pub const fn new(guid: GUID, flags: u32) -> KernelProvider {
-        KernelProvider { guid, flags }
+        KernelProvider {
+            guid,
+            flags,
+            group_mask: 0,
+        }
+    }
+
+    /// Create a Kernel Provider that is enabled by a PERFINFO_GROUPMASK value rather than by `EnableFlags`
+    ///
+    /// Such providers are enabled with [TraceSetInformation](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-tracesetinformation), which requires Windows 8 or later.
+    pub const fn with_group_mask(guid: GUID, group_mask: u32) -> KernelProvider {
+        KernelProvider {
+            guid,
+            flags: 0,
+            group_mask,
+        }
     }
 }
 
@@ -325,6 +383,15 @@ pub static CONTEXT_SWITCH_PROVIDER: KernelProvider = KernelProvider::new(
 /// Represents the ALPC Kernel Provider
 pub static ALPC_PROVIDER: KernelProvider =
     KernelProvider::new(kernel_guids::ALPC_GUID, kernel_flags::EVENT_TRACE_FLAG_ALPC);
+/// Represents the Object Manager (handles) Kernel Provider
+pub static OBJECT_HANDLE_PROVIDER: KernelProvider =
+    KernelProvider::with_group_mask(kernel_guids::OB_TRACE_GUID, group_masks::PERF_OB_HANDLE);
+/// Represents the Object Manager (objects) Kernel Provider
+pub static OBJECT_PROVIDER: KernelProvider =
+    KernelProvider::with_group_mask(kernel_guids::OB_TRACE_GUID, group_masks::PERF_OB_OBJECT);
+/// Represents the Pool Kernel Provider
+pub static POOL_PROVIDER: KernelProvider =
+    KernelProvider::with_group_mask(kernel_guids::POOL_TRACE_GUID, group_masks::PERF_POOL);
 
 #[cfg(test)]
 mod test {
@@ -354,6 +421,23 @@ mod test {
         assert_eq!(IMAGE_LOAD_GUID, kernel_provider.guid());
     }
 
+    #[test]
+    fn test_perfinfo_group_mask() {
+        let provider = Provider::kernel(&OBJECT_HANDLE_PROVIDER).build();
+        assert_eq!(provider.kernel_flags(), 0);
+        assert_eq!(provider.kernel_group_mask(), group_masks::PERF_OB_HANDLE);
+
+        let masks = perfinfo_group_mask(
+            EVENT_TRACE_FLAG_PROCESS,
+            [
+                group_masks::PERF_OB_HANDLE,
+                group_masks::PERF_OB_OBJECT,
+                group_masks::PERF_POOL,
+            ],
+        );
+        assert_eq!(masks, [EVENT_TRACE_FLAG_PROCESS, 0x40, 0, 0, 0xc0, 0, 0, 0]);
+    }
+
     #[test]
     fn test_kernel_provider_guids_correct() {
         assert_eq!(
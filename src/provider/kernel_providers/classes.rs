@@ -0,0 +1,367 @@
+//! Friendly names for classic kernel events
+//!
+//! Classic kernel events do not have an event ID: they are identified by the GUID of their (MOF) class, and by their opcode.<br/>
+//! See [`EventRecord::kernel_event_kind`](crate::EventRecord::kernel_event_kind).
+//!
+//! Credits: [the MOF classes of the NT Kernel Logger](https://learn.microsoft.com/en-us/windows/win32/etw/msnt-systemtrace)
+use windows::core::GUID;
+
+use super::kernel_guids;
+
+/// The class of a classic kernel event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum KernelEventClass {
+    Alpc,
+    DebugPrint,
+    DiskIo,
+    EventTrace,
+    FileIo,
+    Image,
+    LostEvent,
+    ObTrace,
+    PageFault,
+    PerfInfo,
+    Pool,
+    Process,
+    Registry,
+    SplitIo,
+    StackWalk,
+    TcpIp,
+    Thread,
+    UdpIp,
+}
+
+/// A class, its GUID, its name and the names of its opcodes
+type ClassEntry = (
+    KernelEventClass,
+    GUID,
+    &'static str,
+    &'static [(u8, &'static str)],
+);
+
+static CLASSES: &[ClassEntry] = &[
+    (
+        KernelEventClass::Alpc,
+        kernel_guids::ALPC_GUID,
+        "ALPC",
+        &[
+            (33, "SendMessage"),
+            (34, "ReceiveMessage"),
+            (35, "WaitForReply"),
+            (36, "WaitForNewMessage"),
+            (37, "UnwaitThread"),
+        ],
+    ),
+    (
+        KernelEventClass::DebugPrint,
+        kernel_guids::DEBUG_GUID,
+        "DbgPrint",
+        &[(32, "DebugPrint")],
+    ),
+    (
+        KernelEventClass::DiskIo,
+        kernel_guids::DISK_IO_GUID,
+        "DiskIo",
+        &[
+            (10, "Read"),
+            (11, "Write"),
+            (12, "ReadInit"),
+            (13, "WriteInit"),
+            (14, "FlushBuffers"),
+            (15, "FlushInit"),
+        ],
+    ),
+    (
+        KernelEventClass::EventTrace,
+        kernel_guids::EVENT_TRACE_GUID,
+        "EventTrace",
+        &[(0, "Header"), (8, "RDComplete")],
+    ),
+    (
+        KernelEventClass::FileIo,
+        kernel_guids::FILE_IO_GUID,
+        "FileIo",
+        &[
+            (0, "Name"),
+            (32, "FileCreate"),
+            (35, "FileDelete"),
+            (36, "FileRundown"),
+            (64, "Create"),
+            (65, "Cleanup"),
+            (66, "Close"),
+            (67, "Read"),
+            (68, "Write"),
+            (69, "SetInfo"),
+            (70, "Delete"),
+            (71, "Rename"),
+            (72, "DirEnum"),
+            (73, "Flush"),
+            (74, "QueryInfo"),
+            (75, "FSControl"),
+            (76, "OperationEnd"),
+            (77, "DirNotify"),
+        ],
+    ),
+    (
+        KernelEventClass::Image,
+        kernel_guids::IMAGE_LOAD_GUID,
+        "Image",
+        &[(2, "Unload"), (3, "DCStart"), (4, "DCEnd"), (10, "Load")],
+    ),
+    (
+        KernelEventClass::LostEvent,
+        kernel_guids::LOST_EVENT_GUID,
+        "RT_LostEvent",
+        &[
+            (32, "RTLostEvent"),
+            (33, "RTLostBuffer"),
+            (34, "RTLostFile"),
+        ],
+    ),
+    (
+        KernelEventClass::ObTrace,
+        kernel_guids::OB_TRACE_GUID,
+        "ObTrace",
+        &[
+            (32, "CreateHandle"),
+            (33, "CloseHandle"),
+            (34, "DuplicateHandle"),
+            (36, "TypeDCStart"),
+            (37, "TypeDCEnd"),
+        ],
+    ),
+    (
+        KernelEventClass::PageFault,
+        kernel_guids::PAGE_FAULT_GUID,
+        "PageFault",
+        &[
+            (10, "TransitionFault"),
+            (11, "DemandZeroFault"),
+            (12, "CopyOnWrite"),
+            (13, "GuardPageFault"),
+            (14, "HardPageFault"),
+            (15, "AccessViolation"),
+            (32, "HardFault"),
+            (98, "VirtualAlloc"),
+            (99, "VirtualFree"),
+        ],
+    ),
+    (
+        KernelEventClass::PerfInfo,
+        kernel_guids::PERF_INFO_GUID,
+        "PerfInfo",
+        &[
+            (46, "SampleProf"),
+            (51, "SysClEnter"),
+            (52, "SysClExit"),
+            (66, "ThreadedDPC"),
+            (67, "ISR"),
+            (68, "DPC"),
+            (69, "TimerDPC"),
+        ],
+    ),
+    (
+        KernelEventClass::Pool,
+        kernel_guids::POOL_TRACE_GUID,
+        "Pool",
+        &[],
+    ),
+    (
+        KernelEventClass::Process,
+        kernel_guids::PROCESS_GUID,
+        "Process",
+        &[
+            (1, "Start"),
+            (2, "End"),
+            (3, "DCStart"),
+            (4, "DCEnd"),
+            (11, "Terminate"),
+            (32, "PerfCtr"),
+            (33, "PerfCtrRundown"),
+            (39, "Defunct"),
+        ],
+    ),
+    (
+        KernelEventClass::Registry,
+        kernel_guids::REGISTRY_GUID,
+        "Registry",
+        &[
+            (10, "Create"),
+            (11, "Open"),
+            (12, "Delete"),
+            (13, "Query"),
+            (14, "SetValue"),
+            (15, "DeleteValue"),
+            (16, "QueryValue"),
+            (17, "EnumerateKey"),
+            (18, "EnumerateValueKey"),
+            (19, "QueryMultipleValue"),
+            (20, "SetInformation"),
+            (21, "Flush"),
+            (22, "KCBCreate"),
+            (23, "KCBDelete"),
+            (24, "KCBRundownBegin"),
+            (25, "KCBRundownEnd"),
+            (26, "Virtualize"),
+            (27, "Close"),
+        ],
+    ),
+    (
+        KernelEventClass::SplitIo,
+        kernel_guids::SPLIT_IO_GUID,
+        "SplitIo",
+        &[(32, "VolMgr")],
+    ),
+    (
+        KernelEventClass::StackWalk,
+        kernel_guids::STACK_WALK_GUID,
+        "StackWalk",
+        &[(32, "Stack")],
+    ),
+    (
+        KernelEventClass::TcpIp,
+        kernel_guids::TCP_IP_GUID,
+        "TcpIp",
+        &[
+            (10, "Send"),
+            (11, "Recv"),
+            (12, "Connect"),
+            (13, "Disconnect"),
+            (14, "Retransmit"),
+            (15, "Accept"),
+            (16, "Reconnect"),
+            (17, "Fail"),
+            (18, "TCPCopy"),
+            (26, "SendIPV6"),
+            (27, "RecvIPV6"),
+            (28, "ConnectIPV6"),
+            (29, "DisconnectIPV6"),
+            (30, "RetransmitIPV6"),
+            (31, "AcceptIPV6"),
+            (32, "ReconnectIPV6"),
+            (34, "TCPCopyIPV6"),
+        ],
+    ),
+    (
+        KernelEventClass::Thread,
+        kernel_guids::THREAD_GUID,
+        "Thread",
+        &[
+            (1, "Start"),
+            (2, "End"),
+            (3, "DCStart"),
+            (4, "DCEnd"),
+            (36, "CSwitch"),
+            (50, "ReadyThread"),
+            (72, "SetName"),
+        ],
+    ),
+    (
+        KernelEventClass::UdpIp,
+        kernel_guids::UDP_IP_GUID,
+        "UdpIp",
+        &[
+            (10, "Send"),
+            (11, "Recv"),
+            (17, "Fail"),
+            (26, "SendIPV6"),
+            (27, "RecvIPV6"),
+        ],
+    ),
+];
+
+impl KernelEventClass {
+    /// The class of the events whose provider ID is `guid`, if this is a known kernel class
+    pub fn from_guid(guid: GUID) -> Option<Self> {
+        CLASSES
+            .iter()
+            .find(|(_, class_guid, _, _)| *class_guid == guid)
+            .map(|(class, _, _, _)| *class)
+    }
+
+    fn entry(&self) -> &'static ClassEntry {
+        // Every variant has an entry in CLASSES
+        CLASSES
+            .iter()
+            .find(|(class, _, _, _)| class == self)
+            .unwrap()
+    }
+
+    /// The GUID of this class, i.e. the provider ID of its events
+    pub fn guid(&self) -> GUID {
+        self.entry().1
+    }
+
+    /// The name of this class, as in its MOF definition (e.g. `"Process"`)
+    pub fn name(&self) -> &'static str {
+        self.entry().2
+    }
+
+    /// The name of an opcode of this class (e.g. `"Start"`), if known
+    pub fn opcode_name(&self, opcode: u8) -> Option<&'static str> {
+        self.entry()
+            .3
+            .iter()
+            .find(|(known, _)| *known == opcode)
+            .map(|(_, name)| *name)
+    }
+}
+
+/// The class and opcode of a classic kernel event, see [`EventRecord::kernel_event_kind`](crate::EventRecord::kernel_event_kind)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KernelEventKind {
+    pub class: KernelEventClass,
+    pub opcode: u8,
+}
+
+impl KernelEventKind {
+    pub fn new(guid: GUID, opcode: u8) -> Option<Self> {
+        KernelEventClass::from_guid(guid).map(|class| Self { class, opcode })
+    }
+
+    /// The name of the opcode (e.g. `"Start"`), if known
+    pub fn opcode_name(&self) -> Option<&'static str> {
+        self.class.opcode_name(self.opcode)
+    }
+}
+
+impl std::fmt::Display for KernelEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.opcode_name() {
+            Some(opcode) => write!(f, "{}/{}", self.class.name(), opcode),
+            None => write!(f, "{}/{}", self.class.name(), self.opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_kernel_event_kind() {
+        let event = SyntheticEvent::new()
+            .with_provider(GUID::from("3d6fa8d0-fe05-11d0-9dda-00c04fd7ba7c"))
+            .with_opcode(1);
+        let kind = event.record().kernel_event_kind().unwrap();
+        assert_eq!(kind.class, KernelEventClass::Process);
+        assert_eq!(kind.opcode_name(), Some("Start"));
+        assert_eq!(kind.to_string(), "Process/Start");
+
+        let unknown_opcode = KernelEventKind::new(kernel_guids::TCP_IP_GUID, 200).unwrap();
+        assert_eq!(unknown_opcode.to_string(), "TcpIp/200");
+
+        let user_event = SyntheticEvent::new().with_provider(GUID::from_u128(0x774));
+        assert_eq!(user_event.record().kernel_event_kind(), None);
+    }
+
+    #[test]
+    fn test_every_class_has_an_entry() {
+        for (class, guid, _, _) in CLASSES {
+            assert_eq!(KernelEventClass::from_guid(*guid), Some(*class));
+            assert_eq!(class.guid(), *guid);
+        }
+    }
+}
@@ -0,0 +1,640 @@
+//! Typed accessors for common classic kernel events
+//!
+//! Classic kernel events are described by MOF classes (their decoding source is `DecodingSourceWbem`).
+//! Their layouts depend on the version of the event, and on the bitness of the kernel that emitted them, which TDH does not always handle well.<br/>
+//! The types of this module parse these events directly from their user buffers, without querying TDH (and without any [`Schema`](crate::schema::Schema)).
+//!
+//! ```
+//! # use ferrisetw::EventRecord;
+//! # use ferrisetw::schema_locator::SchemaLocator;
+//! use ferrisetw::provider::kernel_providers::events::{ProcessEvent, ProcessEventKind};
+//!
+//! let process_callback = |record: &EventRecord, _locator: &SchemaLocator| {
+//!     if let Ok(event) = ProcessEvent::parse(record) {
+//!         if event.kind == ProcessEventKind::Start {
+//!             println!("{} started {}", event.parent_id, event.image_file_name);
+//!         }
+//!     }
+//! };
+//! ```
+//!
+//! Every `parse` function returns [`ParserError::UnexpectedEvent`] for events of another class, opcode or (unsupported) version, and [`ParserError::LengthMismatch`] in case the user buffer is too short.
+//! Fields that were added in later versions of an event are `None` for older versions.
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::kernel_guids;
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::ParserError;
+use windows::core::GUID;
+
+/// Reads the fields of a user buffer, one after the other
+pub(super) struct Reader<'a> {
+    buffer: &'a [u8],
+    pointer_size: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn new(record: &'a EventRecord) -> Self {
+        Self {
+            buffer: record.user_buffer(),
+            pointer_size: record.pointer_size(),
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParserError> {
+        if self.buffer.len() < len {
+            return Err(ParserError::LengthMismatch);
+        }
+        let (bytes, remaining) = self.buffer.split_at(len);
+        self.buffer = remaining;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ParserError> {
+        // `bytes` has checked the length already
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, ParserError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(super) fn u16(&mut self) -> Result<u16, ParserError> {
+        self.array().map(u16::from_ne_bytes)
+    }
+
+    pub(super) fn u32(&mut self) -> Result<u32, ParserError> {
+        self.array().map(u32::from_ne_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, ParserError> {
+        self.array().map(i32::from_ne_bytes)
+    }
+
+    pub(super) fn i64(&mut self) -> Result<i64, ParserError> {
+        self.array().map(i64::from_ne_bytes)
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64, ParserError> {
+        self.array().map(u64::from_ne_bytes)
+    }
+
+    /// A pointer-sized value, with the size of a pointer of the kernel that emitted the event
+    pub(super) fn pointer(&mut self) -> Result<u64, ParserError> {
+        if self.pointer_size == 4 {
+            self.u32().map(u64::from)
+        } else {
+            self.u64()
+        }
+    }
+
+    /// A port, in network byte order
+    pub(super) fn port(&mut self) -> Result<u16, ParserError> {
+        self.array().map(u16::from_be_bytes)
+    }
+
+    pub(super) fn ipv4(&mut self) -> Result<Ipv4Addr, ParserError> {
+        self.array::<4>().map(Ipv4Addr::from)
+    }
+
+    pub(super) fn ipv6(&mut self) -> Result<Ipv6Addr, ParserError> {
+        self.array::<16>().map(Ipv6Addr::from)
+    }
+
+    /// A null-terminated ANSI string. A missing terminator is tolerated at the end of the buffer.
+    fn ansi_string(&mut self) -> Result<String, ParserError> {
+        let len = self
+            .buffer
+            .iter()
+            .position(|c| *c == 0)
+            .map(|pos| pos + 1)
+            .unwrap_or(self.buffer.len());
+        let bytes = self.bytes(len)?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// A null-terminated UTF-16 string. A missing terminator is tolerated at the end of the buffer.
+    pub(super) fn utf16_string(&mut self) -> Result<String, ParserError> {
+        let mut units = Vec::new();
+        while self.buffer.len() >= 2 {
+            match self.u16()? {
+                0 => break,
+                unit => units.push(unit),
+            }
+        }
+        String::from_utf16(&units).map_err(|_| ParserError::ParseError)
+    }
+
+    /// A `TDH_INTYPE_WBEMSID`, i.e. a `TOKEN_USER` followed by a SID, or a null `u32` when there is no SID
+    fn wbem_sid(&mut self) -> Result<Option<String>, ParserError> {
+        if self.buffer.get(..4) == Some(&[0, 0, 0, 0]) {
+            self.bytes(4)?;
+            return Ok(None);
+        }
+        // TOKEN_USER contains a pointer and a (padded) u32
+        self.bytes(2 * self.pointer_size)?;
+        self.sid().map(Some)
+    }
+
+    /// A `SID`, formatted as e.g. `S-1-5-18`
+    pub(super) fn sid(&mut self) -> Result<String, ParserError> {
+        let revision = self.u8()?;
+        let sub_authority_count = self.u8()?;
+        let mut authority = [0u8; 8];
+        authority[2..].copy_from_slice(self.bytes(6)?);
+        let authority = u64::from_be_bytes(authority);
+
+        let mut sid = if authority < (1 << 32) {
+            format!("S-{}-{}", revision, authority)
+        } else {
+            format!("S-{}-0x{:012X}", revision, authority)
+        };
+        for _ in 0..sub_authority_count {
+            sid.push_str(&format!("-{}", self.u32()?));
+        }
+        Ok(sid)
+    }
+}
+
+/// Checks the class and the version of an event, and returns its opcode
+pub(super) fn check_event(
+    record: &EventRecord,
+    class: GUID,
+    min_version: u8,
+) -> Result<u8, ParserError> {
+    if record.provider_id() != class || record.version() < min_version {
+        return Err(ParserError::UnexpectedEvent);
+    }
+    Ok(record.opcode())
+}
+
+/// The opcode of a [`ProcessEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessEventKind {
+    Start,
+    End,
+    /// A process that was already running when the trace started
+    DcStart,
+    /// A process that was still running when the trace ended
+    DcEnd,
+    Defunct,
+}
+
+/// An event of the [`PROCESS_PROVIDER`](super::PROCESS_PROVIDER) (`Process_TypeGroup1` MOF class, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ProcessEvent {
+    pub kind: ProcessEventKind,
+    /// The address of the `EPROCESS` of the process
+    pub unique_process_key: u64,
+    pub process_id: u32,
+    pub parent_id: u32,
+    pub session_id: u32,
+    pub exit_status: i32,
+    /// Since version 3
+    pub directory_table_base: Option<u64>,
+    /// Since version 4
+    pub flags: Option<u32>,
+    /// The SID of the user of the process, e.g. `S-1-5-18`
+    pub user_sid: Option<String>,
+    pub image_file_name: String,
+    pub command_line: String,
+    /// Since version 4
+    pub package_full_name: Option<String>,
+    /// Since version 4
+    pub application_id: Option<String>,
+}
+
+impl ProcessEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::PROCESS_GUID, 2)? {
+            1 => ProcessEventKind::Start,
+            2 => ProcessEventKind::End,
+            3 => ProcessEventKind::DcStart,
+            4 => ProcessEventKind::DcEnd,
+            39 => ProcessEventKind::Defunct,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let version = record.version();
+        let mut reader = Reader::new(record);
+        Ok(Self {
+            kind,
+            unique_process_key: reader.pointer()?,
+            process_id: reader.u32()?,
+            parent_id: reader.u32()?,
+            session_id: reader.u32()?,
+            exit_status: reader.i32()?,
+            directory_table_base: match version {
+                2 => None,
+                _ => Some(reader.pointer()?),
+            },
+            flags: match version {
+                2 | 3 => None,
+                _ => Some(reader.u32()?),
+            },
+            user_sid: reader.wbem_sid()?,
+            image_file_name: reader.ansi_string()?,
+            command_line: reader.utf16_string()?,
+            package_full_name: match version {
+                2 | 3 => None,
+                _ => Some(reader.utf16_string()?),
+            },
+            application_id: match version {
+                2 | 3 => None,
+                _ => Some(reader.utf16_string()?),
+            },
+        })
+    }
+}
+
+/// The opcode of a [`ThreadEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadEventKind {
+    Start,
+    End,
+    /// A thread that was already running when the trace started
+    DcStart,
+    /// A thread that was still running when the trace ended
+    DcEnd,
+}
+
+/// An event of the [`THREAD_PROVIDER`](super::THREAD_PROVIDER) (`Thread_TypeGroup1` MOF class, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ThreadEvent {
+    pub kind: ThreadEventKind,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub stack_base: u64,
+    pub stack_limit: u64,
+    pub user_stack_base: u64,
+    pub user_stack_limit: u64,
+    pub win32_start_address: u64,
+    pub teb_base: u64,
+    pub sub_process_tag: u32,
+    /// Since version 3
+    pub base_priority: Option<u8>,
+    /// Since version 3
+    pub page_priority: Option<u8>,
+    /// Since version 3
+    pub io_priority: Option<u8>,
+}
+
+impl ThreadEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::THREAD_GUID, 2)? {
+            1 => ThreadEventKind::Start,
+            2 => ThreadEventKind::End,
+            3 => ThreadEventKind::DcStart,
+            4 => ThreadEventKind::DcEnd,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let has_priorities = record.version() >= 3;
+        let mut reader = Reader::new(record);
+        let process_id = reader.u32()?;
+        let thread_id = reader.u32()?;
+        let stack_base = reader.pointer()?;
+        let stack_limit = reader.pointer()?;
+        let user_stack_base = reader.pointer()?;
+        let user_stack_limit = reader.pointer()?;
+        // `StartAddr` in version 2, `Affinity` since version 3
+        reader.pointer()?;
+        let win32_start_address = reader.pointer()?;
+        let teb_base = reader.pointer()?;
+        let sub_process_tag = reader.u32()?;
+        let (base_priority, page_priority, io_priority) = if has_priorities {
+            (Some(reader.u8()?), Some(reader.u8()?), Some(reader.u8()?))
+        } else {
+            (None, None, None)
+        };
+        Ok(Self {
+            kind,
+            process_id,
+            thread_id,
+            stack_base,
+            stack_limit,
+            user_stack_base,
+            user_stack_limit,
+            win32_start_address,
+            teb_base,
+            sub_process_tag,
+            base_priority,
+            page_priority,
+            io_priority,
+        })
+    }
+}
+
+/// The opcode of an [`ImageEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageEventKind {
+    Load,
+    Unload,
+    /// An image that was already loaded when the trace started
+    DcStart,
+    /// An image that was still loaded when the trace ended
+    DcEnd,
+}
+
+/// An event of the [`IMAGE_LOAD_PROVIDER`](super::IMAGE_LOAD_PROVIDER) (`Image_Load` MOF class, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ImageEvent {
+    pub kind: ImageEventKind,
+    pub image_base: u64,
+    pub image_size: u64,
+    pub process_id: u32,
+    pub image_checksum: u32,
+    pub time_date_stamp: u32,
+    pub default_base: u64,
+    /// The NT path of the image, e.g. `\Device\HarddiskVolume3\Windows\System32\ntdll.dll`
+    pub file_name: String,
+}
+
+impl ImageEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::IMAGE_LOAD_GUID, 2)? {
+            10 => ImageEventKind::Load,
+            2 => ImageEventKind::Unload,
+            3 => ImageEventKind::DcStart,
+            4 => ImageEventKind::DcEnd,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let image_base = reader.pointer()?;
+        let image_size = reader.pointer()?;
+        let process_id = reader.u32()?;
+        let image_checksum = reader.u32()?;
+        let time_date_stamp = reader.u32()?;
+        // Reserved0 (or SignatureLevel and friends, in later versions)
+        reader.u32()?;
+        let default_base = reader.pointer()?;
+        // Reserved1 to Reserved4
+        reader.bytes(4 * 4)?;
+        Ok(Self {
+            kind,
+            image_base,
+            image_size,
+            process_id,
+            image_checksum,
+            time_date_stamp,
+            default_base,
+            file_name: reader.utf16_string()?,
+        })
+    }
+}
+
+/// The opcode of a [`DiskIoEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskIoEventKind {
+    Read,
+    Write,
+}
+
+/// A completed disk I/O, from the [`DISK_IO_PROVIDER`](super::DISK_IO_PROVIDER) (`DiskIo_TypeGroup1` MOF class, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DiskIoEvent {
+    pub kind: DiskIoEventKind,
+    pub disk_number: u32,
+    pub irp_flags: u32,
+    pub transfer_size: u32,
+    pub byte_offset: u64,
+    pub file_object: u64,
+    pub irp: u64,
+    /// The duration of the I/O, in performance counter ticks
+    pub high_res_response_time: u64,
+    /// Since version 3
+    pub issuing_thread_id: Option<u32>,
+}
+
+impl DiskIoEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::DISK_IO_GUID, 2)? {
+            10 => DiskIoEventKind::Read,
+            11 => DiskIoEventKind::Write,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let has_thread_id = record.version() >= 3;
+        let mut reader = Reader::new(record);
+        let disk_number = reader.u32()?;
+        let irp_flags = reader.u32()?;
+        let transfer_size = reader.u32()?;
+        // Reserved
+        reader.u32()?;
+        Ok(Self {
+            kind,
+            disk_number,
+            irp_flags,
+            transfer_size,
+            byte_offset: reader.u64()?,
+            file_object: reader.pointer()?,
+            irp: reader.pointer()?,
+            high_res_response_time: reader.u64()?,
+            issuing_thread_id: match has_thread_id {
+                true => Some(reader.u32()?),
+                false => None,
+            },
+        })
+    }
+}
+
+/// The opcode of a [`TcpIpEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpIpEventKind {
+    Send,
+    Receive,
+    Connect,
+    Disconnect,
+    Retransmit,
+    Accept,
+    Reconnect,
+    Copy,
+}
+
+/// A TCP event from the [`TCP_IP_PROVIDER`](super::TCP_IP_PROVIDER) (`TcpIp_TypeGroup1` and `TcpIp_TypeGroup2` MOF classes, for IPv4 and IPv6, versions 2 and later)
+///
+/// Only the fields that are common to every opcode are parsed.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TcpIpEvent {
+    pub kind: TcpIpEventKind,
+    pub process_id: u32,
+    /// The size of the packet, in bytes
+    pub size: u32,
+    pub destination: SocketAddr,
+    pub source: SocketAddr,
+}
+
+impl TcpIpEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let opcode = check_event(record, kernel_guids::TCP_IP_GUID, 2)?;
+        // IPv6 opcodes are the IPv4 ones, plus 16
+        let (is_ipv6, opcode) = match opcode {
+            26..=34 => (true, opcode - 16),
+            _ => (false, opcode),
+        };
+        let kind = match opcode {
+            10 => TcpIpEventKind::Send,
+            11 => TcpIpEventKind::Receive,
+            12 => TcpIpEventKind::Connect,
+            13 => TcpIpEventKind::Disconnect,
+            14 => TcpIpEventKind::Retransmit,
+            15 => TcpIpEventKind::Accept,
+            16 => TcpIpEventKind::Reconnect,
+            18 => TcpIpEventKind::Copy,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let process_id = reader.u32()?;
+        let size = reader.u32()?;
+        let (destination, source): (IpAddr, IpAddr) = if is_ipv6 {
+            (reader.ipv6()?.into(), reader.ipv6()?.into())
+        } else {
+            (reader.ipv4()?.into(), reader.ipv4()?.into())
+        };
+        let destination_port = reader.port()?;
+        let source_port = reader.port()?;
+        Ok(Self {
+            kind,
+            process_id,
+            size,
+            destination: SocketAddr::new(destination, destination_port),
+            source: SocketAddr::new(source, source_port),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn process_start(version: u8, is_32_bit: bool) -> SyntheticEvent {
+        let mut event = SyntheticEvent::new();
+        if is_32_bit {
+            event = event.with_32_bit_header();
+        }
+        event = event
+            .with_provider(kernel_guids::PROCESS_GUID)
+            .with_opcode(1)
+            .with_version(version)
+            .with_pointer(0xffff_8000_1234_0000)
+            .with_user_data(&1234u32.to_ne_bytes())
+            .with_user_data(&567u32.to_ne_bytes())
+            .with_user_data(&1u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes());
+        if version >= 3 {
+            event = event.with_pointer(0x1ab000);
+        }
+        if version >= 4 {
+            event = event.with_user_data(&0u32.to_ne_bytes());
+        }
+        // TOKEN_USER, then S-1-5-18
+        event = event
+            .with_pointer(0xdead)
+            .with_pointer(0)
+            .with_user_data(&[1, 1, 0, 0, 0, 0, 0, 5])
+            .with_user_data(&18u32.to_ne_bytes())
+            .with_user_data(b"notepad.exe\0")
+            .with_utf16_string("notepad.exe foo.txt");
+        if version >= 4 {
+            event = event.with_utf16_string("").with_utf16_string("");
+        }
+        event
+    }
+
+    #[test]
+    fn test_process_event() {
+        for is_32_bit in [false, true] {
+            for version in [2, 3, 4] {
+                let event = process_start(version, is_32_bit);
+                let process = ProcessEvent::parse(event.record()).unwrap();
+                assert_eq!(process.kind, ProcessEventKind::Start);
+                assert_eq!(process.process_id, 1234);
+                assert_eq!(process.parent_id, 567);
+                assert_eq!(process.user_sid.as_deref(), Some("S-1-5-18"));
+                assert_eq!(process.image_file_name, "notepad.exe");
+                assert_eq!(process.command_line, "notepad.exe foo.txt");
+                assert_eq!(process.directory_table_base.is_some(), version >= 3);
+                assert_eq!(process.package_full_name.is_some(), version >= 4);
+            }
+        }
+
+        let unexpected = process_start(4, false).with_opcode(42);
+        assert!(matches!(
+            ProcessEvent::parse(unexpected.record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+        let truncated = SyntheticEvent::new()
+            .with_provider(kernel_guids::PROCESS_GUID)
+            .with_opcode(2)
+            .with_version(4)
+            .with_pointer(0);
+        assert!(matches!(
+            ProcessEvent::parse(truncated.record()),
+            Err(ParserError::LengthMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_tcpip_event() {
+        let event = SyntheticEvent::new()
+            .with_provider(kernel_guids::TCP_IP_GUID)
+            .with_opcode(12)
+            .with_version(2)
+            .with_user_data(&4u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            .with_user_data(&[93, 184, 216, 34])
+            .with_user_data(&[192, 168, 1, 10])
+            .with_user_data(&443u16.to_be_bytes())
+            .with_user_data(&50000u16.to_be_bytes());
+        let tcp = TcpIpEvent::parse(event.record()).unwrap();
+        assert_eq!(tcp.kind, TcpIpEventKind::Connect);
+        assert_eq!(tcp.destination, "93.184.216.34:443".parse().unwrap());
+        assert_eq!(tcp.source, "192.168.1.10:50000".parse().unwrap());
+
+        let mut data = Vec::new();
+        data.extend(Ipv6Addr::LOCALHOST.octets());
+        data.extend(Ipv6Addr::LOCALHOST.octets());
+        data.extend(80u16.to_be_bytes());
+        data.extend(1234u16.to_be_bytes());
+        let event = SyntheticEvent::new()
+            .with_provider(kernel_guids::TCP_IP_GUID)
+            .with_opcode(26)
+            .with_version(2)
+            .with_user_data(&4u32.to_ne_bytes())
+            .with_user_data(&100u32.to_ne_bytes())
+            .with_user_data(&data);
+        let tcp = TcpIpEvent::parse(event.record()).unwrap();
+        assert_eq!(tcp.kind, TcpIpEventKind::Send);
+        assert_eq!(tcp.size, 100);
+        assert_eq!(tcp.destination, "[::1]:80".parse().unwrap());
+    }
+
+    #[test]
+    fn test_disk_io_event() {
+        let event = SyntheticEvent::new()
+            .with_32_bit_header()
+            .with_provider(kernel_guids::DISK_IO_GUID)
+            .with_opcode(11)
+            .with_version(3)
+            .with_user_data(&0u32.to_ne_bytes())
+            .with_user_data(&0x43u32.to_ne_bytes())
+            .with_user_data(&4096u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            .with_user_data(&0x10000u64.to_ne_bytes())
+            .with_pointer(0x1000)
+            .with_pointer(0x2000)
+            .with_user_data(&250u64.to_ne_bytes())
+            .with_user_data(&88u32.to_ne_bytes());
+        let io = DiskIoEvent::parse(event.record()).unwrap();
+        assert_eq!(io.kind, DiskIoEventKind::Write);
+        assert_eq!(io.transfer_size, 4096);
+        assert_eq!(io.byte_offset, 0x10000);
+        assert_eq!(io.irp, 0x2000);
+        assert_eq!(io.issuing_thread_id, Some(88));
+    }
+}
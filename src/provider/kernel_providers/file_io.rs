@@ -0,0 +1,764 @@
+//! File I/O, correlated into per-file summaries
+//!
+//! The `FileIo` kernel events report every operation twice: when it starts (with the file object it applies to), and when it ends (with its status, but only the address of its IRP).
+//! A [`FileIoCorrelator`] matches them by IRP, and accumulates the operations of every file object, from its creation to its cleanup (i.e. when its last handle is closed).
+//! It then yields a [`FileSummary`] with the path of the file, and the byte counts and latencies of its reads and writes.
+//!
+//! The end of an operation may never be seen (e.g. in case the session has lost it). Operations are thus given up on once they have been pending for [`PENDING_OPERATION_TIMEOUT`],
+//! whenever more than [`MAX_PENDING_OPERATIONS`] are pending.
+//! Likewise, the cleanup of a file object, or the deletion of a file, may never be seen: at most [`MAX_OPEN_FILES`] file objects and [`MAX_FILE_NAMES`] file names are kept,
+//! the ones that have not been used for the longest time are forgotten first.
+//!
+//! ```no_run
+//! # use std::sync::Mutex;
+//! # use ferrisetw::{EventRecord, SchemaLocator};
+//! # use ferrisetw::trace::KernelTrace;
+//! use ferrisetw::provider::kernel_providers::file_io::{self, FileIoCorrelator};
+//!
+//! let correlator = Mutex::new(FileIoCorrelator::new());
+//! let provider = file_io::provider()
+//!     .add_callback(move |record: &EventRecord, _locator: &SchemaLocator| {
+//!         if let Some(summary) = correlator.lock().unwrap().update(record) {
+//!             println!(
+//!                 "{:?}: {} bytes read in {:?}",
+//!                 summary.path, summary.reads.bytes, summary.reads.latency
+//!             );
+//!         }
+//!     })
+//!     .build();
+//! let (_trace, _handle) = KernelTrace::new().enable(provider).start().unwrap();
+//! ```
+//!
+//! The `parse` functions fail like those of [`events`](super::events).
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use super::events::{check_event, Reader};
+use super::{kernel_flags, kernel_guids, KernelProvider};
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::ParserError;
+use crate::provider::{Provider, ProviderBuilder};
+
+/// How many operations can be pending before the oldest ones are given up on, see [`FileIoCorrelator`]
+pub const MAX_PENDING_OPERATIONS: usize = 64 * 1024;
+/// How many file objects can be open before the ones that have not been used for the longest time are forgotten, see [`FileIoCorrelator`]
+pub const MAX_OPEN_FILES: usize = 64 * 1024;
+/// How many file names can be known before the ones that have not been used for the longest time are forgotten, see [`FileIoCorrelator`]
+pub const MAX_FILE_NAMES: usize = 256 * 1024;
+/// How long an operation can be pending before it may be given up on, see [`FileIoCorrelator`]
+pub const PENDING_OPERATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The opcode of `FileDelete` events, after which a file key is no longer used
+const FILE_DELETE_OPCODE: u8 = 35;
+
+/// Both the starts (`EVENT_TRACE_FLAG_FILE_IO_INIT`) and the ends (`EVENT_TRACE_FLAG_FILE_IO`) of file operations
+static FILE_IO_CORRELATION_PROVIDER: KernelProvider = KernelProvider::new(
+    kernel_guids::FILE_IO_GUID,
+    kernel_flags::EVENT_TRACE_FLAG_FILE_IO | kernel_flags::EVENT_TRACE_FLAG_FILE_IO_INIT,
+);
+
+/// A builder for a provider that receives every event a [`FileIoCorrelator`] needs, to be enabled on a `KernelTrace`
+///
+/// This is the union of the [`FILE_IO_PROVIDER`](super::FILE_IO_PROVIDER) and of the [`FILE_INIT_IO_PROVIDER`](super::FILE_INIT_IO_PROVIDER), that cannot be enabled together since they share their class.
+pub fn provider() -> ProviderBuilder {
+    Provider::kernel(&FILE_IO_CORRELATION_PROVIDER)
+}
+
+/// A file creation (or opening), `FileIo_Create` MOF class, versions 2 and later
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileCreateEvent {
+    pub irp: u64,
+    pub file_object: u64,
+    pub thread_id: u32,
+    pub create_options: u32,
+    pub file_attributes: u32,
+    pub share_access: u32,
+    pub open_path: String,
+}
+
+impl FileCreateEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        if check_event(record, kernel_guids::FILE_IO_GUID, 2)? != 64 {
+            return Err(ParserError::UnexpectedEvent);
+        }
+        let mut reader = Reader::new(record);
+        let irp = reader.pointer()?;
+        let (file_object, thread_id) = file_object_and_thread(record, &mut reader)?;
+        Ok(Self {
+            irp,
+            file_object,
+            thread_id,
+            create_options: reader.u32()?,
+            file_attributes: reader.u32()?,
+            share_access: reader.u32()?,
+            open_path: reader.utf16_string()?,
+        })
+    }
+}
+
+/// Reads the `FileObject` and `TTID` fields, that come after `IrpPtr`
+///
+/// Version 2 has a pointer-sized `TTID` before `FileObject`. Since version 3, it is a 32-bit `IssuingThreadId` after it.
+fn file_object_and_thread(
+    record: &EventRecord,
+    reader: &mut Reader,
+) -> Result<(u64, u32), ParserError> {
+    if record.version() == 2 {
+        let thread_id = reader.pointer()? as u32;
+        Ok((reader.pointer()?, thread_id))
+    } else {
+        Ok((reader.pointer()?, reader.u32()?))
+    }
+}
+
+/// Same as [`file_object_and_thread`], for events that also have a `FileKey` after `FileObject`
+fn file_object_key_and_thread(
+    record: &EventRecord,
+    reader: &mut Reader,
+) -> Result<(u64, u64, u32), ParserError> {
+    if record.version() == 2 {
+        let thread_id = reader.pointer()? as u32;
+        Ok((reader.pointer()?, reader.pointer()?, thread_id))
+    } else {
+        Ok((reader.pointer()?, reader.pointer()?, reader.u32()?))
+    }
+}
+
+/// The opcode of a [`FileReadWriteEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileReadWriteKind {
+    Read,
+    Write,
+}
+
+/// The start of a read or a write, `FileIo_ReadWrite` MOF class, versions 2 and later
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileReadWriteEvent {
+    pub kind: FileReadWriteKind,
+    pub offset: u64,
+    pub irp: u64,
+    pub file_object: u64,
+    /// Identifies the file, as in [`FileNameEvent::file_key`]
+    pub file_key: u64,
+    pub thread_id: u32,
+    /// The requested size, in bytes
+    pub io_size: u32,
+    pub io_flags: u32,
+}
+
+impl FileReadWriteEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::FILE_IO_GUID, 2)? {
+            67 => FileReadWriteKind::Read,
+            68 => FileReadWriteKind::Write,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let offset = reader.u64()?;
+        let irp = reader.pointer()?;
+        let (file_object, file_key, thread_id) = file_object_key_and_thread(record, &mut reader)?;
+        Ok(Self {
+            kind,
+            offset,
+            irp,
+            file_object,
+            file_key,
+            thread_id,
+            io_size: reader.u32()?,
+            io_flags: reader.u32()?,
+        })
+    }
+}
+
+/// The opcode of a [`FileSimpleOpEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSimpleOpKind {
+    /// The last handle to the file object has been closed
+    Cleanup,
+    /// The file object has been released
+    Close,
+    Flush,
+}
+
+/// An operation without parameters, `FileIo_SimpleOp` MOF class, versions 2 and later
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileSimpleOpEvent {
+    pub kind: FileSimpleOpKind,
+    pub irp: u64,
+    pub file_object: u64,
+    pub file_key: u64,
+    pub thread_id: u32,
+}
+
+impl FileSimpleOpEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::FILE_IO_GUID, 2)? {
+            65 => FileSimpleOpKind::Cleanup,
+            66 => FileSimpleOpKind::Close,
+            73 => FileSimpleOpKind::Flush,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let irp = reader.pointer()?;
+        let (file_object, file_key, thread_id) = file_object_key_and_thread(record, &mut reader)?;
+        Ok(Self {
+            kind,
+            irp,
+            file_object,
+            file_key,
+            thread_id,
+        })
+    }
+}
+
+/// The end of an operation, `FileIo_OpEnd` MOF class, versions 2 and later
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileOperationEndEvent {
+    /// The IRP of the operation, as in the event that has started it
+    pub irp: u64,
+    /// Depends on the operation, e.g. the number of bytes transferred for reads and writes
+    pub extra_info: u64,
+    /// The `NTSTATUS` of the operation
+    pub status: u32,
+}
+
+impl FileOperationEndEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        if check_event(record, kernel_guids::FILE_IO_GUID, 2)? != 76 {
+            return Err(ParserError::UnexpectedEvent);
+        }
+        let mut reader = Reader::new(record);
+        Ok(Self {
+            irp: reader.pointer()?,
+            extra_info: reader.pointer()?,
+            status: reader.u32()?,
+        })
+    }
+}
+
+/// The name of a file, `FileIo_Name` MOF class (`Name`, `FileCreate`, `FileDelete` and `FileRundown` opcodes), versions 2 and later
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileNameEvent {
+    pub file_key: u64,
+    pub file_name: String,
+}
+
+impl FileNameEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        match check_event(record, kernel_guids::FILE_IO_GUID, 2)? {
+            0 | 32 | 35 | 36 => (),
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        Ok(Self {
+            file_key: reader.pointer()?,
+            file_name: reader.utf16_string()?,
+        })
+    }
+}
+
+/// Counters of the reads (or of the writes) of a file, see [`FileSummary`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoStats {
+    /// How many operations have started
+    pub count: u64,
+    /// The requested sizes, in bytes
+    pub bytes: u64,
+    /// How many operations have ended, and are thus accounted in the latencies
+    pub completed: u64,
+    /// The total latency of the completed operations
+    pub latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl IoStats {
+    /// The average latency of the completed operations
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let completed = u32::try_from(self.completed).ok().filter(|c| *c > 0)?;
+        Some(self.latency / completed)
+    }
+
+    fn complete(&mut self, latency: Duration) {
+        self.completed += 1;
+        self.latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+}
+
+/// What has been done with a file object, from its creation to its cleanup
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FileSummary {
+    pub file_object: u64,
+    /// The path of the file, in case it is known
+    pub path: Option<String>,
+    /// The process that has created the file object (or that has first used it, in case it was created before the trace started)
+    pub process_id: u32,
+    /// The `FILETIME` the file object was created, in case the trace has seen it
+    pub created_at: Option<i64>,
+    /// The `FILETIME` of the cleanup
+    pub cleaned_up_at: i64,
+    /// The `NTSTATUS` of the creation, in case the trace has seen it
+    pub create_status: Option<u32>,
+    pub reads: IoStats,
+    pub writes: IoStats,
+    pub flushes: u64,
+}
+
+/// An operation that has started, and whose end is expected
+#[derive(Debug)]
+struct PendingOperation {
+    file_object: u64,
+    /// `None` for creations
+    kind: Option<FileReadWriteKind>,
+    started_at: i64,
+}
+
+/// A file object that has not been cleaned up yet
+#[derive(Debug)]
+struct OpenFile {
+    summary: FileSummary,
+    /// The `FILETIME` of the latest event about this file object
+    used_at: i64,
+}
+
+/// A file name, by file key
+#[derive(Debug)]
+struct FileName {
+    name: String,
+    /// The `FILETIME` of the latest event about this file key
+    used_at: i64,
+}
+
+/// Correlates `FileIo` events into [`FileSummary`]s
+///
+/// See [the module documentation](self).
+#[derive(Debug, Default)]
+pub struct FileIoCorrelator {
+    /// Paths, by file key
+    names: HashMap<u64, FileName>,
+    /// Files that have not been cleaned up yet, by file object
+    files: HashMap<u64, OpenFile>,
+    /// Operations that have not ended yet, by IRP
+    pending: HashMap<u64, PendingOperation>,
+    /// How many pending operations have been given up on
+    abandoned: u64,
+}
+
+impl FileIoCorrelator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the correlator with a `FileIo` event. Other events are ignored.
+    ///
+    /// Returns the summary of a file, once it has been cleaned up.
+    pub fn update(&mut self, record: &EventRecord) -> Option<FileSummary> {
+        let timestamp = record.system_timestamp();
+        if let Ok(end) = FileOperationEndEvent::parse(record) {
+            self.end_operation(&end, timestamp);
+        } else if let Ok(create) = FileCreateEvent::parse(record) {
+            let file = self.file(create.file_object, 0, record.process_id(), timestamp);
+            file.created_at = Some(timestamp);
+            file.path = Some(create.open_path);
+            self.start_operation(
+                create.irp,
+                PendingOperation {
+                    file_object: create.file_object,
+                    kind: None,
+                    started_at: timestamp,
+                },
+            );
+        } else if let Ok(io) = FileReadWriteEvent::parse(record) {
+            let file = self.file(io.file_object, io.file_key, record.process_id(), timestamp);
+            let stats = match io.kind {
+                FileReadWriteKind::Read => &mut file.reads,
+                FileReadWriteKind::Write => &mut file.writes,
+            };
+            stats.count += 1;
+            stats.bytes += u64::from(io.io_size);
+            self.start_operation(
+                io.irp,
+                PendingOperation {
+                    file_object: io.file_object,
+                    kind: Some(io.kind),
+                    started_at: timestamp,
+                },
+            );
+        } else if let Ok(op) = FileSimpleOpEvent::parse(record) {
+            match op.kind {
+                FileSimpleOpKind::Flush => {
+                    self.file(op.file_object, op.file_key, record.process_id(), timestamp)
+                        .flushes += 1;
+                }
+                FileSimpleOpKind::Cleanup => {
+                    let mut summary = match self.files.remove(&op.file_object) {
+                        Some(file) => file.summary,
+                        None => new_summary(
+                            op.file_object,
+                            self.names.get(&op.file_key).map(|name| &name.name),
+                            record.process_id(),
+                        ),
+                    };
+                    summary.cleaned_up_at = timestamp;
+                    return Some(summary);
+                }
+                FileSimpleOpKind::Close => (),
+            }
+        } else if let Ok(name) = FileNameEvent::parse(record) {
+            if record.opcode() == FILE_DELETE_OPCODE {
+                self.names.remove(&name.file_key);
+            } else {
+                if self.names.len() >= MAX_FILE_NAMES && !self.names.contains_key(&name.file_key) {
+                    evict_oldest_quarter(&mut self.names, |name| name.used_at);
+                }
+                self.names.insert(
+                    name.file_key,
+                    FileName {
+                        name: name.file_name,
+                        used_at: timestamp,
+                    },
+                );
+            }
+        }
+        None
+    }
+
+    /// How many file objects have been seen, and not cleaned up yet
+    pub fn open_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// How many operations have started, and not ended yet
+    pub fn pending_operations(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// How many operations have been given up on, because they were pending for too long (see [the module documentation](self))
+    pub fn abandoned_operations(&self) -> u64 {
+        self.abandoned
+    }
+
+    fn start_operation(&mut self, irp: u64, operation: PendingOperation) {
+        if self.pending.len() >= MAX_PENDING_OPERATIONS {
+            let before = self.pending.len();
+            // FILETIMEs are in 100-nanosecond intervals
+            let cutoff = operation.started_at - (PENDING_OPERATION_TIMEOUT.as_nanos() / 100) as i64;
+            self.pending
+                .retain(|_, pending| pending.started_at >= cutoff);
+            if self.pending.len() >= MAX_PENDING_OPERATIONS {
+                // Even recent operations pile up
+                evict_oldest_quarter(&mut self.pending, |pending| pending.started_at);
+            }
+            self.abandoned += (before - self.pending.len()) as u64;
+        }
+        self.pending.insert(irp, operation);
+    }
+
+    fn file(
+        &mut self,
+        file_object: u64,
+        file_key: u64,
+        process_id: u32,
+        timestamp: i64,
+    ) -> &mut FileSummary {
+        if self.files.len() >= MAX_OPEN_FILES && !self.files.contains_key(&file_object) {
+            evict_oldest_quarter(&mut self.files, |file| file.used_at);
+        }
+        let name = self.names.get_mut(&file_key).map(|name| {
+            name.used_at = timestamp;
+            &name.name
+        });
+        let file = self.files.entry(file_object).or_insert_with(|| OpenFile {
+            summary: new_summary(file_object, name, process_id),
+            used_at: timestamp,
+        });
+        file.used_at = timestamp;
+        &mut file.summary
+    }
+
+    fn end_operation(&mut self, end: &FileOperationEndEvent, timestamp: i64) {
+        let pending = match self.pending.remove(&end.irp) {
+            Some(pending) => pending,
+            None => return,
+        };
+        // The file may have been cleaned up already
+        let file = match self.files.get_mut(&pending.file_object) {
+            Some(file) => &mut file.summary,
+            None => return,
+        };
+        // FILETIMEs are in 100-nanosecond intervals
+        let latency = Duration::from_nanos(
+            u64::try_from(timestamp - pending.started_at).unwrap_or_default() * 100,
+        );
+        match pending.kind {
+            None => file.create_status = Some(end.status),
+            Some(FileReadWriteKind::Read) => file.reads.complete(latency),
+            Some(FileReadWriteKind::Write) => file.writes.complete(latency),
+        }
+    }
+}
+
+/// Remove the quarter of `map` whose `used_at` are the oldest, so that this is not done for every insertion
+///
+/// Returns how many entries have been removed.
+fn evict_oldest_quarter<V>(map: &mut HashMap<u64, V>, used_at: impl Fn(&V) -> i64) -> usize {
+    let mut by_age: Vec<(i64, u64)> = map
+        .iter()
+        .map(|(key, value)| (used_at(value), *key))
+        .collect();
+    by_age.sort_unstable();
+    let evicted = map.len() / 4;
+    for (_, key) in &by_age[..evicted] {
+        map.remove(key);
+    }
+    evicted
+}
+
+fn new_summary(file_object: u64, path: Option<&String>, process_id: u32) -> FileSummary {
+    FileSummary {
+        file_object,
+        path: path.cloned(),
+        process_id,
+        created_at: None,
+        cleaned_up_at: 0,
+        create_status: None,
+        reads: IoStats::default(),
+        writes: IoStats::default(),
+        flushes: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    const FILE_OBJECT: u64 = 0xffff_8000_0000_f000;
+
+    fn file_io_event(opcode: u8, timestamp: i64) -> SyntheticEvent {
+        SyntheticEvent::new()
+            .with_provider(kernel_guids::FILE_IO_GUID)
+            .with_opcode(opcode)
+            .with_version(3)
+            .with_process_id(1234)
+            .with_timestamp(timestamp)
+    }
+
+    fn create(irp: u64, timestamp: i64) -> SyntheticEvent {
+        file_io_event(64, timestamp)
+            .with_pointer(irp)
+            .with_pointer(FILE_OBJECT)
+            .with_user_data(&88u32.to_ne_bytes())
+            .with_user_data(&[0; 12])
+            .with_utf16_string("\\Device\\HarddiskVolume3\\data.bin")
+    }
+
+    fn read_write(opcode: u8, irp: u64, size: u32, timestamp: i64) -> SyntheticEvent {
+        file_io_event(opcode, timestamp)
+            .with_user_data(&0u64.to_ne_bytes())
+            .with_pointer(irp)
+            .with_pointer(FILE_OBJECT)
+            .with_pointer(0xabc)
+            .with_user_data(&88u32.to_ne_bytes())
+            .with_user_data(&size.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+    }
+
+    fn simple_op(opcode: u8, timestamp: i64) -> SyntheticEvent {
+        file_io_event(opcode, timestamp)
+            .with_pointer(0x99)
+            .with_pointer(FILE_OBJECT)
+            .with_pointer(0xabc)
+            .with_user_data(&88u32.to_ne_bytes())
+    }
+
+    fn operation_end(irp: u64, extra_info: u64, timestamp: i64) -> SyntheticEvent {
+        file_io_event(76, timestamp)
+            .with_pointer(irp)
+            .with_pointer(extra_info)
+            .with_user_data(&0u32.to_ne_bytes())
+    }
+
+    #[test]
+    fn test_file_io_events() {
+        let event = read_write(68, 0x10, 4096, 0);
+        let write = FileReadWriteEvent::parse(event.record()).unwrap();
+        assert_eq!(write.kind, FileReadWriteKind::Write);
+        assert_eq!((write.irp, write.file_object), (0x10, FILE_OBJECT));
+        assert_eq!((write.file_key, write.io_size), (0xabc, 4096));
+
+        let create = FileCreateEvent::parse(create(0x20, 0).record()).unwrap();
+        assert_eq!(create.open_path, "\\Device\\HarddiskVolume3\\data.bin");
+        assert_eq!(create.thread_id, 88);
+
+        assert!(matches!(
+            FileCreateEvent::parse(event.record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+        let truncated = file_io_event(76, 0).with_pointer(0x10);
+        assert!(matches!(
+            FileOperationEndEvent::parse(truncated.record()),
+            Err(ParserError::LengthMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_file_io_correlator() {
+        let mut correlator = FileIoCorrelator::new();
+        let events = [
+            create(0x1, 1_000),
+            operation_end(0x1, 0, 1_500),
+            read_write(67, 0x2, 4096, 2_000),
+            read_write(67, 0x3, 512, 2_100),
+            operation_end(0x2, 4096, 12_000),
+            read_write(68, 0x4, 100, 13_000),
+            operation_end(0x3, 512, 14_100),
+            simple_op(73, 15_000),
+        ];
+        for event in &events {
+            assert!(correlator.update(event.record()).is_none());
+        }
+        assert_eq!(correlator.open_files(), 1);
+        assert_eq!(correlator.pending_operations(), 1);
+
+        let summary = correlator.update(simple_op(65, 20_000).record()).unwrap();
+        assert_eq!(
+            summary.path.as_deref(),
+            Some("\\Device\\HarddiskVolume3\\data.bin")
+        );
+        assert_eq!(summary.process_id, 1234);
+        assert_eq!(
+            (summary.created_at, summary.cleaned_up_at),
+            (Some(1_000), 20_000)
+        );
+        assert_eq!(summary.create_status, Some(0));
+        assert_eq!((summary.reads.count, summary.reads.bytes), (2, 4608));
+        assert_eq!(summary.reads.completed, 2);
+        assert_eq!(summary.reads.latency, Duration::from_micros(2_200));
+        assert_eq!(summary.reads.max_latency, Duration::from_micros(1_200));
+        assert_eq!(
+            summary.reads.mean_latency(),
+            Some(Duration::from_micros(1_100))
+        );
+        assert_eq!((summary.writes.count, summary.writes.completed), (1, 0));
+        assert_eq!(summary.writes.mean_latency(), None);
+        assert_eq!(summary.flushes, 1);
+        assert_eq!(correlator.open_files(), 0);
+
+        // The write ends after the cleanup
+        assert!(correlator
+            .update(operation_end(0x4, 100, 21_000).record())
+            .is_none());
+        assert_eq!(correlator.pending_operations(), 0);
+    }
+
+    #[test]
+    fn test_file_opened_before_the_trace() {
+        let mut correlator = FileIoCorrelator::new();
+        let name = file_io_event(36, 0)
+            .with_pointer(0xabc)
+            .with_utf16_string("\\Device\\HarddiskVolume3\\old.log");
+        correlator.update(name.record());
+        correlator.update(read_write(68, 0x5, 10, 100).record());
+
+        let summary = correlator.update(simple_op(65, 200).record()).unwrap();
+        assert_eq!(
+            summary.path.as_deref(),
+            Some("\\Device\\HarddiskVolume3\\old.log")
+        );
+        assert_eq!(summary.created_at, None);
+        assert_eq!(summary.writes.bytes, 10);
+
+        // The file key is released, then reused for another file
+        let delete = file_io_event(35, 300)
+            .with_pointer(0xabc)
+            .with_utf16_string("\\Device\\HarddiskVolume3\\old.log");
+        correlator.update(delete.record());
+        correlator.update(read_write(68, 0x6, 10, 400).record());
+        let summary = correlator.update(simple_op(65, 500).record()).unwrap();
+        assert_eq!(summary.path, None);
+    }
+
+    #[test]
+    fn test_abandoned_operations() {
+        let mut correlator = FileIoCorrelator::new();
+        let timeout = (PENDING_OPERATION_TIMEOUT.as_nanos() / 100) as i64;
+        // These ends are never seen
+        for irp in 0..MAX_PENDING_OPERATIONS as u64 {
+            correlator.update(read_write(67, irp, 1, 0).record());
+        }
+        assert_eq!(correlator.pending_operations(), MAX_PENDING_OPERATIONS);
+        assert_eq!(correlator.abandoned_operations(), 0);
+
+        let late = MAX_PENDING_OPERATIONS as u64;
+        correlator.update(read_write(67, late, 1, timeout + 1).record());
+        assert_eq!(correlator.pending_operations(), 1);
+        assert_eq!(
+            correlator.abandoned_operations(),
+            MAX_PENDING_OPERATIONS as u64
+        );
+    }
+
+    #[test]
+    fn test_evict_equal_timestamps() {
+        let mut correlator = FileIoCorrelator::new();
+        for irp in 0..MAX_PENDING_OPERATIONS as u64 {
+            correlator.update(read_write(67, irp, 1, 0).record());
+        }
+        // None of them has timed out, and they all started at the same time: only a quarter is given up on
+        let late = MAX_PENDING_OPERATIONS as u64;
+        correlator.update(read_write(67, late, 1, 1).record());
+        let evicted = MAX_PENDING_OPERATIONS / 4;
+        assert_eq!(
+            correlator.pending_operations(),
+            MAX_PENDING_OPERATIONS - evicted + 1
+        );
+        assert_eq!(correlator.abandoned_operations(), evicted as u64);
+    }
+
+    #[test]
+    fn test_version_2_layout() {
+        // Version 2 has a pointer-sized TTID right after IrpPtr
+        let event = SyntheticEvent::new()
+            .with_provider(kernel_guids::FILE_IO_GUID)
+            .with_opcode(68)
+            .with_version(2)
+            .with_user_data(&16u64.to_ne_bytes())
+            .with_pointer(0x1000)
+            .with_pointer(88)
+            .with_pointer(FILE_OBJECT)
+            .with_pointer(0xabc)
+            .with_user_data(&512u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes());
+        let io = FileReadWriteEvent::parse(event.record()).unwrap();
+        assert_eq!(io.kind, FileReadWriteKind::Write);
+        assert_eq!(io.offset, 16);
+        assert_eq!(io.irp, 0x1000);
+        assert_eq!(io.thread_id, 88);
+        assert_eq!(io.file_object, FILE_OBJECT);
+        assert_eq!(io.file_key, 0xabc);
+        assert_eq!(io.io_size, 512);
+
+        let event = SyntheticEvent::new()
+            .with_provider(kernel_guids::FILE_IO_GUID)
+            .with_opcode(64)
+            .with_version(2)
+            .with_32_bit_header()
+            .with_pointer(0x1000)
+            .with_pointer(88)
+            .with_pointer(FILE_OBJECT)
+            .with_user_data(&[0; 12])
+            .with_utf16_string("C:\\data.bin");
+        let create = FileCreateEvent::parse(event.record()).unwrap();
+        assert_eq!(create.thread_id, 88);
+        assert_eq!(create.file_object, FILE_OBJECT as u32 as u64);
+        assert_eq!(create.open_path, "C:\\data.bin");
+    }
+}
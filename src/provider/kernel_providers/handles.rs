@@ -0,0 +1,288 @@
+//! Handle events of the Object Manager
+//!
+//! The [`OBJECT_HANDLE_PROVIDER`](super::OBJECT_HANDLE_PROVIDER) reports every handle that is created, closed or duplicated, along with the type of its object.
+//! Object types are reported as indexes. Their names are given by the type rundown events, that the kernel emits when the trace ends.<br/>
+//! A [`HandleTracker`] keeps track of the handles that are still open, which is usually what handle-leak investigations are after.
+//!
+//! ```no_run
+//! # use std::sync::{Arc, Mutex};
+//! # use ferrisetw::{EventRecord, SchemaLocator};
+//! # use ferrisetw::trace::{KernelTrace, TraceTrait};
+//! use ferrisetw::provider::kernel_providers::handles::{self, HandleTracker};
+//!
+//! let tracker = Arc::new(Mutex::new(HandleTracker::new()));
+//! let tracker_ = Arc::clone(&tracker);
+//! let provider = handles::provider()
+//!     .add_callback(move |record: &EventRecord, _locator: &SchemaLocator| {
+//!         tracker_.lock().unwrap().update(record);
+//!     })
+//!     .build();
+//! let (trace, _handle) = KernelTrace::new().enable(provider).start().unwrap();
+//! // ...
+//! trace.stop().unwrap();
+//!
+//! let tracker = tracker.lock().unwrap();
+//! for handle in tracker.open_handles().filter(|handle| handle.process_id == 1234) {
+//!     println!("{:#x} ({})", handle.handle, tracker.type_name(handle.object_type).unwrap_or("?"));
+//! }
+//! ```
+//!
+//! The `parse` functions fail like those of [`events`](super::events).
+use std::collections::HashMap;
+
+use super::events::{check_event, Reader};
+use super::{kernel_guids, OBJECT_HANDLE_PROVIDER};
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::ParserError;
+use crate::provider::{Provider, ProviderBuilder};
+
+/// A builder for the [`OBJECT_HANDLE_PROVIDER`](super::OBJECT_HANDLE_PROVIDER), to be enabled on a `KernelTrace`
+pub fn provider() -> ProviderBuilder {
+    Provider::kernel(&OBJECT_HANDLE_PROVIDER)
+}
+
+/// The opcode of a [`HandleEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleEventKind {
+    Create,
+    Close,
+    Duplicate,
+}
+
+/// A handle event of the [`OBJECT_HANDLE_PROVIDER`](super::OBJECT_HANDLE_PROVIDER) (`ObHandleEvent` and `ObDuplicateHandleEvent` MOF classes, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct HandleEvent {
+    pub kind: HandleEventKind,
+    /// The address of the kernel object
+    pub object: u64,
+    /// The process that owns the handle: the process that has emitted the event, or the target process of a duplicated handle
+    pub process_id: u32,
+    pub handle: u32,
+    /// The index of the type of the object, see [`ObjectTypeEvent`]
+    pub object_type: u16,
+    /// The name of the object (empty for unnamed objects, and for duplicated handles)
+    pub object_name: String,
+    /// The process and the handle a duplicated handle has been copied from
+    pub source: Option<(u32, u32)>,
+}
+
+impl HandleEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::OB_TRACE_GUID, 2)? {
+            32 => HandleEventKind::Create,
+            33 => HandleEventKind::Close,
+            34 => HandleEventKind::Duplicate,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let object = reader.pointer()?;
+        if kind == HandleEventKind::Duplicate {
+            let source_handle = reader.u32()?;
+            let handle = reader.u32()?;
+            let process_id = reader.u32()?;
+            let object_type = reader.u16()?;
+            let source_process_id = reader.u32()?;
+            return Ok(Self {
+                kind,
+                object,
+                process_id,
+                handle,
+                object_type,
+                object_name: String::new(),
+                source: Some((source_process_id, source_handle)),
+            });
+        }
+        Ok(Self {
+            kind,
+            object,
+            process_id: record.process_id(),
+            handle: reader.u32()?,
+            object_type: reader.u16()?,
+            object_name: reader.utf16_string()?,
+            source: None,
+        })
+    }
+}
+
+/// The name of an object type (`ObTypeEvent` MOF class, versions 2 and later)
+///
+/// The kernel reports every object type when the trace starts (`TypeDCStart`) or ends (`TypeDCEnd`).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ObjectTypeEvent {
+    /// The index of the type, as found in [`HandleEvent::object_type`]
+    pub index: u16,
+    /// The name of the type, e.g. `File`, `Key` or `Event`
+    pub name: String,
+}
+
+impl ObjectTypeEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        match check_event(record, kernel_guids::OB_TRACE_GUID, 2)? {
+            36 | 37 => (),
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let index = reader.u16()?;
+        // Reserved
+        reader.u16()?;
+        Ok(Self {
+            index,
+            name: reader.utf16_string()?,
+        })
+    }
+}
+
+/// The handles that are open, according to the handle events it has seen
+///
+/// Handles that were opened before the trace started are not known, and closing them is ignored.
+#[derive(Debug, Default)]
+pub struct HandleTracker {
+    types: HashMap<u16, String>,
+    /// Indexed by process ID and handle
+    open: HashMap<(u32, u32), HandleEvent>,
+}
+
+impl HandleTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the tracker with an event of the [`OBJECT_HANDLE_PROVIDER`](super::OBJECT_HANDLE_PROVIDER). Other events are ignored.
+    ///
+    /// Returns the handle event, in case this is one.
+    pub fn update(&mut self, record: &EventRecord) -> Option<HandleEvent> {
+        if let Ok(object_type) = ObjectTypeEvent::parse(record) {
+            self.types.insert(object_type.index, object_type.name);
+            return None;
+        }
+        let event = HandleEvent::parse(record).ok()?;
+        let key = (event.process_id, event.handle);
+        match event.kind {
+            HandleEventKind::Create | HandleEventKind::Duplicate => {
+                self.open.insert(key, event.clone());
+            }
+            HandleEventKind::Close => {
+                self.open.remove(&key);
+            }
+        }
+        Some(event)
+    }
+
+    /// The name of an object type, in case its rundown event has been seen
+    pub fn type_name(&self, object_type: u16) -> Option<&str> {
+        self.types.get(&object_type).map(String::as_str)
+    }
+
+    /// The handles that have been created (or duplicated) and not closed yet
+    pub fn open_handles(&self) -> impl Iterator<Item = &HandleEvent> {
+        self.open.values()
+    }
+
+    /// How many handles are open, per process
+    pub fn open_handles_per_process(&self) -> HashMap<u32, usize> {
+        let mut counts = HashMap::new();
+        for (process_id, _) in self.open.keys() {
+            *counts.entry(*process_id).or_default() += 1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn handle_event(opcode: u8, handle: u32, object_type: u16, name: &str) -> SyntheticEvent {
+        SyntheticEvent::new()
+            .with_provider(kernel_guids::OB_TRACE_GUID)
+            .with_opcode(opcode)
+            .with_version(2)
+            .with_process_id(1234)
+            .with_pointer(0xffff_8000_0000_1000)
+            .with_user_data(&handle.to_ne_bytes())
+            .with_user_data(&object_type.to_ne_bytes())
+            .with_utf16_string(name)
+    }
+
+    #[test]
+    fn test_handle_events() {
+        let event = handle_event(32, 0x40, 37, "\\BaseNamedObjects\\foo");
+        let create = HandleEvent::parse(event.record()).unwrap();
+        assert_eq!(create.kind, HandleEventKind::Create);
+        assert_eq!(create.object, 0xffff_8000_0000_1000);
+        assert_eq!(create.process_id, 1234);
+        assert_eq!(create.handle, 0x40);
+        assert_eq!(create.object_type, 37);
+        assert_eq!(create.object_name, "\\BaseNamedObjects\\foo");
+        assert!(create.source.is_none());
+
+        let duplicate = SyntheticEvent::new()
+            .with_32_bit_header()
+            .with_provider(kernel_guids::OB_TRACE_GUID)
+            .with_opcode(34)
+            .with_version(2)
+            .with_pointer(0x8000_1000)
+            .with_user_data(&0x40u32.to_ne_bytes())
+            .with_user_data(&0x88u32.to_ne_bytes())
+            .with_user_data(&5678u32.to_ne_bytes())
+            .with_user_data(&37u16.to_ne_bytes())
+            .with_user_data(&1234u32.to_ne_bytes());
+        let duplicate = HandleEvent::parse(duplicate.record()).unwrap();
+        assert_eq!(duplicate.kind, HandleEventKind::Duplicate);
+        assert_eq!((duplicate.process_id, duplicate.handle), (5678, 0x88));
+        assert_eq!(duplicate.source, Some((1234, 0x40)));
+
+        let object_type = SyntheticEvent::new()
+            .with_provider(kernel_guids::OB_TRACE_GUID)
+            .with_opcode(37)
+            .with_version(2)
+            .with_user_data(&37u16.to_ne_bytes())
+            .with_user_data(&0u16.to_ne_bytes())
+            .with_utf16_string("Event");
+        let object_type = ObjectTypeEvent::parse(object_type.record()).unwrap();
+        assert_eq!(
+            (object_type.index, object_type.name.as_str()),
+            (37, "Event")
+        );
+
+        assert!(matches!(
+            ObjectTypeEvent::parse(event.record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+        let truncated = SyntheticEvent::new()
+            .with_provider(kernel_guids::OB_TRACE_GUID)
+            .with_opcode(33)
+            .with_version(2)
+            .with_pointer(0);
+        assert!(matches!(
+            HandleEvent::parse(truncated.record()),
+            Err(ParserError::LengthMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_handle_tracker() {
+        let mut tracker = HandleTracker::new();
+        for (opcode, handle) in [(32, 0x40), (32, 0x44), (32, 0x48), (33, 0x44), (33, 0x99)] {
+            tracker.update(handle_event(opcode, handle, 37, "").record());
+        }
+        let type_rundown = SyntheticEvent::new()
+            .with_provider(kernel_guids::OB_TRACE_GUID)
+            .with_opcode(37)
+            .with_version(2)
+            .with_user_data(&37u16.to_ne_bytes())
+            .with_user_data(&0u16.to_ne_bytes())
+            .with_utf16_string("Event");
+        assert!(tracker.update(type_rundown.record()).is_none());
+
+        let mut open: Vec<_> = tracker.open_handles().map(|handle| handle.handle).collect();
+        open.sort_unstable();
+        assert_eq!(open, vec![0x40, 0x48]);
+        assert_eq!(tracker.open_handles_per_process()[&1234], 2);
+        assert_eq!(tracker.type_name(37), Some("Event"));
+        assert_eq!(tracker.type_name(38), None);
+    }
+}
@@ -0,0 +1,457 @@
+//! Network flows, reconstructed from TCP and UDP kernel events
+//!
+//! The [`TCP_IP_PROVIDER`](super::TCP_IP_PROVIDER) reports every send and receive of every socket, for both TCP and UDP.
+//! A [`FlowTracker`] aggregates them into flows (identified by their protocol, local and remote addresses), and yields a [`FlowRecord`]
+//! with the byte and packet counts, the duration and the owning process of each flow:
+//! * when a TCP connection is disconnected,
+//! * when a flow has been idle for too long (UDP flows have no end, and the disconnection of a TCP connection may be missed),
+//! * and optionally at regular intervals for long-lived flows, see [`FlowTracker::report_interval`].
+//!
+//! Timeouts and intervals are measured with the timestamps of the events, that [`FlowTracker::expire`] should be called with regularly.
+//!
+//! ```no_run
+//! # use std::sync::Mutex;
+//! # use std::time::Duration;
+//! # use ferrisetw::{EventRecord, SchemaLocator};
+//! # use ferrisetw::trace::{KernelTrace, TraceTrait};
+//! use ferrisetw::provider::kernel_providers::network::{self, FlowTracker};
+//!
+//! let tracker = Mutex::new(FlowTracker::new().idle_timeout(Duration::from_secs(30)));
+//! let provider = network::provider()
+//!     .add_callback(move |record: &EventRecord, _locator: &SchemaLocator| {
+//!         let mut tracker = tracker.lock().unwrap();
+//!         let closed = tracker.update(record);
+//!         for flow in closed.into_iter().chain(tracker.expire(record.system_timestamp())) {
+//!             println!(
+//!                 "{:?} {} -> {} (pid {}): {} bytes sent, {} bytes received in {:?}",
+//!                 flow.protocol,
+//!                 flow.local,
+//!                 flow.remote,
+//!                 flow.process_id,
+//!                 flow.bytes_sent,
+//!                 flow.bytes_received,
+//!                 flow.duration()
+//!             );
+//!         }
+//!     })
+//!     .build();
+//! let (_trace, _handle) = KernelTrace::new().enable(provider).start().unwrap();
+//! ```
+//!
+//! The `parse` functions fail like those of [`events`](super::events).
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::events::{check_event, Reader, TcpIpEvent, TcpIpEventKind};
+use super::{kernel_guids, TCP_IP_PROVIDER};
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::ParserError;
+use crate::provider::{Provider, ProviderBuilder};
+
+/// A builder for the [`TCP_IP_PROVIDER`](super::TCP_IP_PROVIDER), to be enabled on a `KernelTrace`
+///
+/// Its flag enables both the `TcpIp` and the `UdpIp` events.
+pub fn provider() -> ProviderBuilder {
+    Provider::kernel(&TCP_IP_PROVIDER)
+}
+
+/// The opcode of a [`UdpIpEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpIpEventKind {
+    Send,
+    Receive,
+}
+
+/// A UDP event from the [`TCP_IP_PROVIDER`](super::TCP_IP_PROVIDER) (`UdpIp_TypeGroup1` and `UdpIp_TypeGroup2` MOF classes, for IPv4 and IPv6, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct UdpIpEvent {
+    pub kind: UdpIpEventKind,
+    pub process_id: u32,
+    /// The size of the datagram, in bytes
+    pub size: u32,
+    pub destination: SocketAddr,
+    pub source: SocketAddr,
+}
+
+impl UdpIpEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let (is_ipv6, kind) = match check_event(record, kernel_guids::UDP_IP_GUID, 2)? {
+            10 => (false, UdpIpEventKind::Send),
+            11 => (false, UdpIpEventKind::Receive),
+            26 => (true, UdpIpEventKind::Send),
+            27 => (true, UdpIpEventKind::Receive),
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let process_id = reader.u32()?;
+        let size = reader.u32()?;
+        let (destination, source): (IpAddr, IpAddr) = if is_ipv6 {
+            (reader.ipv6()?.into(), reader.ipv6()?.into())
+        } else {
+            (reader.ipv4()?.into(), reader.ipv4()?.into())
+        };
+        let destination_port = reader.port()?;
+        let source_port = reader.port()?;
+        Ok(Self {
+            kind,
+            process_id,
+            size,
+            destination: SocketAddr::new(destination, destination_port),
+            source: SocketAddr::new(source, source_port),
+        })
+    }
+}
+
+/// The transport protocol of a [`FlowRecord`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Why a [`FlowRecord`] has been yielded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowEnd {
+    /// The TCP connection has been disconnected
+    Disconnected,
+    /// No event has been seen for the flow during the idle timeout
+    Idle,
+    /// The flow is still active, this is a periodic report (see [`FlowTracker::report_interval`])
+    Active,
+    /// The flow was still active when [`FlowTracker::drain`] was called
+    Drained,
+}
+
+/// A network flow, and what has been exchanged over it
+///
+/// Counters are totals since the flow has been seen for the first time, including in periodic reports.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FlowRecord {
+    pub protocol: Protocol,
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    /// The process that owns the socket
+    pub process_id: u32,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// How many send events have been seen (a single send may span several packets on the wire)
+    pub packets_sent: u64,
+    /// How many receive events have been seen
+    pub packets_received: u64,
+    /// How many TCP retransmissions have been seen
+    pub retransmits: u64,
+    /// The `FILETIME` of the first event of the flow
+    pub first_seen: i64,
+    /// The `FILETIME` of the latest event of the flow
+    pub last_seen: i64,
+    pub end: FlowEnd,
+}
+
+impl FlowRecord {
+    fn new(
+        protocol: Protocol,
+        local: SocketAddr,
+        remote: SocketAddr,
+        process_id: u32,
+        timestamp: i64,
+    ) -> Self {
+        Self {
+            protocol,
+            local,
+            remote,
+            process_id,
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
+            retransmits: 0,
+            first_seen: timestamp,
+            last_seen: timestamp,
+            end: FlowEnd::Active,
+        }
+    }
+
+    /// The time elapsed between the first and the latest events of the flow
+    pub fn duration(&self) -> Duration {
+        elapsed(self.first_seen, self.last_seen)
+    }
+}
+
+/// The time elapsed between two `FILETIME`s
+fn elapsed(from: i64, to: i64) -> Duration {
+    Duration::from_nanos(u64::try_from(to - from).unwrap_or_default() * 100)
+}
+
+/// Identifies a flow: the protocol, then the local and remote addresses
+type FlowKey = (Protocol, SocketAddr, SocketAddr);
+
+#[derive(Debug)]
+struct Flow {
+    record: FlowRecord,
+    /// The `FILETIME` of the latest periodic report (or of the first event)
+    last_report: i64,
+}
+
+/// Aggregates TCP and UDP events into [`FlowRecord`]s
+///
+/// See [the module documentation](self).
+#[derive(Debug)]
+pub struct FlowTracker {
+    flows: HashMap<FlowKey, Flow>,
+    idle_timeout: Duration,
+    report_interval: Option<Duration>,
+}
+
+impl Default for FlowTracker {
+    fn default() -> Self {
+        Self {
+            flows: HashMap::new(),
+            idle_timeout: Duration::from_secs(120),
+            report_interval: None,
+        }
+    }
+}
+
+impl FlowTracker {
+    /// A tracker that ends flows after 2 minutes of inactivity, and does not report active flows
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how long a flow can be idle before [`expire`](Self::expire) ends it
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Make [`expire`](Self::expire) report the flows that are still active, at most once per `interval`
+    pub fn report_interval(mut self, interval: Duration) -> Self {
+        self.report_interval = Some(interval);
+        self
+    }
+
+    /// Update the tracker with a `TcpIp` or `UdpIp` event. Other events are ignored.
+    ///
+    /// Returns the record of a TCP flow, once it has been disconnected.
+    pub fn update(&mut self, record: &EventRecord) -> Option<FlowRecord> {
+        let timestamp = record.system_timestamp();
+        if let Ok(udp) = UdpIpEvent::parse(record) {
+            let flow = self.flow(
+                (Protocol::Udp, udp.source, udp.destination),
+                udp.process_id,
+                timestamp,
+            );
+            match udp.kind {
+                UdpIpEventKind::Send => {
+                    flow.bytes_sent += u64::from(udp.size);
+                    flow.packets_sent += 1;
+                }
+                UdpIpEventKind::Receive => {
+                    flow.bytes_received += u64::from(udp.size);
+                    flow.packets_received += 1;
+                }
+            }
+            return None;
+        }
+
+        let tcp = TcpIpEvent::parse(record).ok()?;
+        let key = (Protocol::Tcp, tcp.source, tcp.destination);
+        if tcp.kind == TcpIpEventKind::Disconnect {
+            // Connections that have not been used during the trace are not reported
+            let mut flow = self.flows.remove(&key)?.record;
+            flow.last_seen = flow.last_seen.max(timestamp);
+            flow.end = FlowEnd::Disconnected;
+            return Some(flow);
+        }
+        let flow = self.flow(key, tcp.process_id, timestamp);
+        match tcp.kind {
+            TcpIpEventKind::Send => {
+                flow.bytes_sent += u64::from(tcp.size);
+                flow.packets_sent += 1;
+            }
+            TcpIpEventKind::Receive | TcpIpEventKind::Copy => {
+                flow.bytes_received += u64::from(tcp.size);
+                flow.packets_received += 1;
+            }
+            TcpIpEventKind::Retransmit => flow.retransmits += 1,
+            TcpIpEventKind::Connect
+            | TcpIpEventKind::Accept
+            | TcpIpEventKind::Reconnect
+            | TcpIpEventKind::Disconnect => (),
+        }
+        None
+    }
+
+    /// End the flows that have been idle for longer than the idle timeout, and report the active ones in case a report interval is set
+    ///
+    /// `now` is a `FILETIME`, usually the [`system_timestamp`](crate::EventRecord::system_timestamp) of the latest event.
+    /// This walks every flow, it should thus be called regularly (e.g. once per second of trace time) rather than after every event.
+    pub fn expire(&mut self, now: i64) -> Vec<FlowRecord> {
+        let idle_timeout = self.idle_timeout;
+        let report_interval = self.report_interval;
+        let mut records = Vec::new();
+        self.flows.retain(|_, flow| {
+            if elapsed(flow.record.last_seen, now) >= idle_timeout {
+                let mut record = flow.record.clone();
+                record.end = FlowEnd::Idle;
+                records.push(record);
+                return false;
+            }
+            if let Some(interval) = report_interval {
+                if elapsed(flow.last_report, now) >= interval {
+                    flow.last_report = now;
+                    records.push(flow.record.clone());
+                }
+            }
+            true
+        });
+        records
+    }
+
+    /// End every flow, e.g. once the trace has stopped
+    pub fn drain(&mut self) -> Vec<FlowRecord> {
+        self.flows
+            .drain()
+            .map(|(_, flow)| {
+                let mut record = flow.record;
+                record.end = FlowEnd::Drained;
+                record
+            })
+            .collect()
+    }
+
+    /// How many flows are being tracked
+    pub fn active_flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// The flow of this key, created in case it is not known yet
+    fn flow(&mut self, key: FlowKey, process_id: u32, timestamp: i64) -> &mut FlowRecord {
+        let flow = self.flows.entry(key).or_insert_with(|| Flow {
+            record: FlowRecord::new(key.0, key.1, key.2, process_id, timestamp),
+            last_report: timestamp,
+        });
+        flow.record.last_seen = flow.record.last_seen.max(timestamp);
+        &mut flow.record
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    const LOCAL: [u8; 4] = [192, 168, 1, 10];
+    const REMOTE: [u8; 4] = [93, 184, 216, 34];
+
+    fn ip_event(
+        guid: windows::core::GUID,
+        opcode: u8,
+        size: u32,
+        timestamp: i64,
+    ) -> SyntheticEvent {
+        SyntheticEvent::new()
+            .with_provider(guid)
+            .with_opcode(opcode)
+            .with_version(2)
+            .with_timestamp(timestamp)
+            .with_user_data(&1234u32.to_ne_bytes())
+            .with_user_data(&size.to_ne_bytes())
+            .with_user_data(&REMOTE)
+            .with_user_data(&LOCAL)
+            .with_user_data(&443u16.to_be_bytes())
+            .with_user_data(&50000u16.to_be_bytes())
+    }
+
+    fn tcp(opcode: u8, size: u32, timestamp: i64) -> SyntheticEvent {
+        ip_event(kernel_guids::TCP_IP_GUID, opcode, size, timestamp)
+    }
+
+    fn udp(opcode: u8, size: u32, timestamp: i64) -> SyntheticEvent {
+        ip_event(kernel_guids::UDP_IP_GUID, opcode, size, timestamp)
+    }
+
+    #[test]
+    fn test_udp_event() {
+        let event = udp(11, 512, 0);
+        let udp_event = UdpIpEvent::parse(event.record()).unwrap();
+        assert_eq!(udp_event.kind, UdpIpEventKind::Receive);
+        assert_eq!(udp_event.process_id, 1234);
+        assert_eq!(udp_event.size, 512);
+        assert_eq!(udp_event.destination, "93.184.216.34:443".parse().unwrap());
+        assert_eq!(udp_event.source, "192.168.1.10:50000".parse().unwrap());
+
+        assert!(matches!(
+            UdpIpEvent::parse(tcp(11, 512, 0).record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+        assert!(matches!(
+            UdpIpEvent::parse(udp(17, 0, 0).record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+    }
+
+    #[test]
+    fn test_flow_tracker() {
+        // 10 ms, in FILETIME units
+        const MS_10: i64 = 100_000;
+        let mut tracker = FlowTracker::new().idle_timeout(Duration::from_secs(1));
+
+        assert!(tracker.update(tcp(12, 0, 0).record()).is_none());
+        assert!(tracker.update(tcp(10, 100, MS_10).record()).is_none());
+        assert!(tracker.update(tcp(11, 1000, 2 * MS_10).record()).is_none());
+        assert!(tracker.update(tcp(14, 100, 3 * MS_10).record()).is_none());
+        assert!(tracker.update(udp(10, 50, 3 * MS_10).record()).is_none());
+        assert_eq!(tracker.active_flows(), 2);
+
+        let flow = tracker.update(tcp(13, 0, 4 * MS_10).record()).unwrap();
+        assert_eq!(flow.protocol, Protocol::Tcp);
+        assert_eq!(flow.local, "192.168.1.10:50000".parse().unwrap());
+        assert_eq!(flow.remote, "93.184.216.34:443".parse().unwrap());
+        assert_eq!(flow.process_id, 1234);
+        assert_eq!((flow.bytes_sent, flow.bytes_received), (100, 1000));
+        assert_eq!((flow.packets_sent, flow.packets_received), (1, 1));
+        assert_eq!(flow.retransmits, 1);
+        assert_eq!(flow.duration(), Duration::from_millis(40));
+        assert_eq!(flow.end, FlowEnd::Disconnected);
+        assert_eq!(tracker.active_flows(), 1);
+        assert!(tracker.update(tcp(13, 0, 5 * MS_10).record()).is_none());
+
+        // The UDP flow has been idle for less than a second
+        assert!(tracker.expire(100 * MS_10).is_empty());
+        let flows = tracker.expire(103 * MS_10);
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].protocol, Protocol::Udp);
+        assert_eq!(flows[0].bytes_sent, 50);
+        assert_eq!(flows[0].end, FlowEnd::Idle);
+        assert_eq!(tracker.active_flows(), 0);
+    }
+
+    #[test]
+    fn test_flow_reports() {
+        const SECOND: i64 = 10_000_000;
+        let mut tracker = FlowTracker::new().report_interval(Duration::from_secs(5));
+
+        for second in 0..12 {
+            tracker.update(udp(10, 10, second * SECOND).record());
+        }
+        let reports = tracker.expire(5 * SECOND);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].end, FlowEnd::Active);
+        // Counters are totals, that include the events that have been seen after `now`
+        assert_eq!(reports[0].packets_sent, 12);
+        assert!(tracker.expire(9 * SECOND).is_empty());
+        assert_eq!(tracker.expire(10 * SECOND).len(), 1);
+
+        let flows = tracker.drain();
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].end, FlowEnd::Drained);
+        assert_eq!(flows[0].bytes_sent, 120);
+        assert_eq!(flows[0].duration(), Duration::from_secs(11));
+        assert_eq!(tracker.active_flows(), 0);
+    }
+}
@@ -0,0 +1,617 @@
+//! A process tree, maintained from kernel process events
+//!
+//! When the [`PROCESS_PROVIDER`](super::PROCESS_PROVIDER) is enabled, the kernel reports every process that is running when the trace starts (`DCStart` rundown events),
+//! then every process that starts or ends.
+//! A [`ProcessTree`] assembles them into the processes that are running, with their parents, images, sessions and users.<br/>
+//! Unlike a toolhelp snapshot taken aside from the trace, this tree is consistent with the events the trace delivers: a callback can query it for the process of the event it is handling.
+//!
+//! Classic kernel events do not carry the start keys of processes. These are reported by the `ProcessStart`, `ProcessRundown` and `ProcessStop` events of Microsoft-Windows-Kernel-Process
+//! (see [`kernel_process_provider`], that is enabled on a `UserTrace`), as their `ProcessSequenceNumber`.
+//! The tree can be updated with both providers: it merges what they report about the same processes, and tells apart processes that have reused an ID by their start keys.
+//!
+//! [`ProcessTree::snapshot`] makes a point-in-time copy of the tree. With the `serde` feature, it can be serialized (e.g. to JSON with `serde_json`).
+//!
+//! ```no_run
+//! # use std::sync::{Arc, Mutex};
+//! # use ferrisetw::{EventRecord, SchemaLocator};
+//! # use ferrisetw::trace::{KernelTrace, TraceTrait, UserTrace};
+//! use ferrisetw::provider::kernel_providers::processes::{self, ProcessTree};
+//!
+//! let tree = Arc::new(Mutex::new(ProcessTree::new()));
+//! let tree_ = Arc::clone(&tree);
+//! let provider = processes::provider()
+//!     .add_callback(move |record: &EventRecord, _locator: &SchemaLocator| {
+//!         tree_.lock().unwrap().update(record);
+//!     })
+//!     .build();
+//! let (kernel_trace, _handle) = KernelTrace::new().enable(provider).start().unwrap();
+//! let tree_ = Arc::clone(&tree);
+//! let start_keys = processes::kernel_process_provider()
+//!     .add_callback(move |record: &EventRecord, _locator: &SchemaLocator| {
+//!         tree_.lock().unwrap().update(record);
+//!     })
+//!     .build();
+//! let (user_trace, _handle) = UserTrace::new().enable(start_keys).start().unwrap();
+//! // ...
+//! let snapshot = tree.lock().unwrap().snapshot();
+//! for process in snapshot.ancestors(1234) {
+//!     println!("{} ({}, {:?})", process.image_file_name, process.process_id, process.start_key);
+//! }
+//! kernel_trace.stop().unwrap();
+//! user_trace.stop().unwrap();
+//! ```
+use std::collections::{BTreeMap, HashMap};
+
+use super::events::{check_event, ProcessEvent, ProcessEventKind, Reader};
+use super::PROCESS_PROVIDER;
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::ParserError;
+use crate::provider::well_known::{keywords, KERNEL_PROCESS};
+use crate::provider::{Provider, ProviderBuilder};
+
+/// A builder for the [`PROCESS_PROVIDER`](super::PROCESS_PROVIDER), to be enabled on a `KernelTrace`
+pub fn provider() -> ProviderBuilder {
+    Provider::kernel(&PROCESS_PROVIDER)
+}
+
+/// A builder for the process events of Microsoft-Windows-Kernel-Process, to be enabled on a `UserTrace`
+///
+/// These events carry the start keys of processes, see [the module documentation](self).
+pub fn kernel_process_provider() -> ProviderBuilder {
+    Provider::by_guid(KERNEL_PROCESS).any(keywords::kernel_process::PROCESS)
+}
+
+/// The event ID of a [`KernelProcessEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelProcessEventKind {
+    Start,
+    Stop,
+    /// A process that was already running
+    Rundown,
+}
+
+/// A `ProcessStart`, `ProcessStop` or `ProcessRundown` event of Microsoft-Windows-Kernel-Process
+///
+/// Unlike the classic kernel events of [`events`](super::events), these are described by a manifest. Their layouts still depend on their versions,
+/// and are parsed the same way (see [the `events` module](super::events) for the errors `parse` returns).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct KernelProcessEvent {
+    pub kind: KernelProcessEventKind,
+    pub process_id: u32,
+    /// The `ProcessSequenceNumber` of the process, since version 2 of `ProcessStart` and `ProcessStop`
+    pub start_key: Option<u64>,
+    /// The `FILETIME` the process has been created
+    pub create_time: i64,
+    /// `None` for `ProcessStop` events
+    pub parent_id: Option<u32>,
+    /// The `ParentProcessSequenceNumber` of the process, since version 2 of `ProcessStart`
+    pub parent_start_key: Option<u64>,
+    /// `None` for `ProcessStop` events
+    pub session_id: Option<u32>,
+    /// The NT path of the image of the process, e.g. `\Device\HarddiskVolume3\Windows\System32\cmd.exe`. `None` for `ProcessStop` events
+    pub image_name: Option<String>,
+}
+
+impl KernelProcessEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        check_event(record, KERNEL_PROCESS.guid, 0)?;
+        let version = record.version();
+        let (kind, has_start_keys) = match record.event_id() {
+            1 => (KernelProcessEventKind::Start, version >= 2),
+            2 => (KernelProcessEventKind::Stop, version >= 2),
+            15 => (KernelProcessEventKind::Rundown, true),
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        let process_id = reader.u32()?;
+        let start_key = if has_start_keys {
+            Some(reader.u64()?)
+        } else {
+            None
+        };
+        let create_time = reader.i64()?;
+        if kind == KernelProcessEventKind::Stop {
+            return Ok(Self {
+                kind,
+                process_id,
+                start_key,
+                create_time,
+                parent_id: None,
+                parent_start_key: None,
+                session_id: None,
+                image_name: None,
+            });
+        }
+
+        let parent_id = reader.u32()?;
+        let parent_start_key = if has_start_keys {
+            Some(reader.u64()?)
+        } else {
+            None
+        };
+        let session_id = reader.u32()?;
+        // `Flags`, since version 1
+        if version >= 1 || kind == KernelProcessEventKind::Rundown {
+            reader.u32()?;
+        }
+        // `ProcessTokenElevationType`, `ProcessTokenIsElevated` and `MandatoryLabel`, since version 3
+        if version >= 3 || kind == KernelProcessEventKind::Rundown {
+            reader.u32()?;
+            reader.u32()?;
+            reader.sid()?;
+        }
+        Ok(Self {
+            kind,
+            process_id,
+            start_key,
+            create_time,
+            parent_id: Some(parent_id),
+            parent_start_key,
+            session_id: Some(session_id),
+            image_name: Some(reader.utf16_string()?),
+        })
+    }
+}
+
+/// A process of a [`ProcessTree`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct ProcessInfo {
+    pub process_id: u32,
+    /// The process that has created this one. It may have ended since (or, in case it was still running when the trace started, its ID may have been reused)
+    pub parent_id: u32,
+    pub image_file_name: String,
+    /// Empty for processes that have only been reported by Microsoft-Windows-Kernel-Process
+    pub command_line: String,
+    pub session_id: u32,
+    /// The SID of the user of the process, e.g. `S-1-5-18`. Microsoft-Windows-Kernel-Process does not report it
+    pub user_sid: Option<String>,
+    /// The address of the `EPROCESS` of the process, or 0 for processes that have only been reported by Microsoft-Windows-Kernel-Process
+    pub unique_process_key: u64,
+    /// The start key of the process (see [`EventRecord::process_start_key`]), as reported by Microsoft-Windows-Kernel-Process
+    ///
+    /// Unlike process IDs, start keys are not reused until the next boot. This is `None` for processes that have only been reported by classic kernel events.
+    pub start_key: Option<u64>,
+    /// The start key of the parent process, in case it is known
+    pub parent_start_key: Option<u64>,
+    /// The `FILETIME` the process started, or `None` for processes that were already running when the trace started
+    pub started_at: Option<i64>,
+}
+
+impl ProcessInfo {
+    fn new(event: ProcessEvent, started_at: Option<i64>) -> Self {
+        Self {
+            process_id: event.process_id,
+            parent_id: event.parent_id,
+            image_file_name: event.image_file_name,
+            command_line: event.command_line,
+            session_id: event.session_id,
+            user_sid: event.user_sid,
+            unique_process_key: event.unique_process_key,
+            start_key: None,
+            parent_start_key: None,
+            started_at,
+        }
+    }
+
+    fn from_kernel_process(event: KernelProcessEvent, started_at: Option<i64>) -> Self {
+        let image_name = event.image_name.unwrap_or_default();
+        Self {
+            process_id: event.process_id,
+            parent_id: event.parent_id.unwrap_or_default(),
+            // Classic kernel events only report the file name of the image
+            image_file_name: image_name
+                .rsplit('\\')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+            command_line: String::new(),
+            session_id: event.session_id.unwrap_or_default(),
+            user_sid: None,
+            unique_process_key: 0,
+            start_key: event.start_key,
+            parent_start_key: event.parent_start_key,
+            started_at,
+        }
+    }
+
+    /// Add what a classic kernel event reports about this process, which Microsoft-Windows-Kernel-Process does not
+    fn merge_classic(&mut self, event: ProcessEvent) {
+        self.image_file_name = event.image_file_name;
+        self.command_line = event.command_line;
+        self.user_sid = event.user_sid;
+        self.unique_process_key = event.unique_process_key;
+    }
+
+    /// Whether a classic kernel event about process `process_id` (created by `parent_id`) describes this process
+    ///
+    /// This is the case unless this process is already known from classic events, or has another parent.
+    fn matches_classic(&self, parent_id: u32) -> bool {
+        self.unique_process_key == 0 && self.parent_id == parent_id
+    }
+
+    /// Whether a Microsoft-Windows-Kernel-Process event describes this process
+    fn matches_kernel_process(&self, event: &KernelProcessEvent) -> bool {
+        match (self.start_key, event.start_key) {
+            (Some(start_key), Some(event_key)) => start_key == event_key,
+            _ => event
+                .parent_id
+                .is_none_or(|parent_id| parent_id == self.parent_id),
+        }
+    }
+
+    /// Whether `parent` is the process that has created this one, rather than a process that has reused its ID later
+    fn is_child_of(&self, parent: &ProcessInfo) -> bool {
+        if parent.process_id != self.parent_id || parent.process_id == self.process_id {
+            return false;
+        }
+        // Start keys are never reused, they tell for sure
+        if let (Some(parent_key), Some(key)) = (parent.start_key, self.parent_start_key) {
+            return parent_key == key;
+        }
+        match (parent.started_at, self.started_at) {
+            (Some(parent_start), Some(start)) => parent_start <= start,
+            // A process that has started during the trace cannot be the parent of a process that was running before
+            (Some(_), None) => false,
+            _ => true,
+        }
+    }
+}
+
+/// The processes that are running, according to the process events it has seen
+///
+/// See [the module documentation](self).
+#[derive(Debug, Default)]
+pub struct ProcessTree {
+    processes: HashMap<u32, ProcessInfo>,
+    /// The `FILETIME` of the latest process event
+    updated_at: Option<i64>,
+}
+
+impl ProcessTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the tree with an event of the [`PROCESS_PROVIDER`](super::PROCESS_PROVIDER), or a process event of Microsoft-Windows-Kernel-Process (see [`kernel_process_provider`])
+    ///
+    /// Other events are ignored. Returns the classic process event, in case this is one.
+    pub fn update(&mut self, record: &EventRecord) -> Option<ProcessEvent> {
+        if let Ok(event) = KernelProcessEvent::parse(record) {
+            self.update_kernel_process(event, record.system_timestamp());
+            return None;
+        }
+        let event = ProcessEvent::parse(record).ok()?;
+        let timestamp = record.system_timestamp();
+        self.updated_at = Some(timestamp);
+        match event.kind {
+            ProcessEventKind::Start => match self.processes.get_mut(&event.process_id) {
+                // Microsoft-Windows-Kernel-Process has reported this start already
+                Some(process)
+                    if process.started_at.is_some() && process.matches_classic(event.parent_id) =>
+                {
+                    process.merge_classic(event.clone())
+                }
+                // In case its ID is reused, the previous process has ended (even if its end has not been seen)
+                _ => {
+                    self.processes.insert(
+                        event.process_id,
+                        ProcessInfo::new(event.clone(), Some(timestamp)),
+                    );
+                }
+            },
+            ProcessEventKind::DcStart | ProcessEventKind::DcEnd => {
+                // Rundowns describe processes that have been seen already, unless the trace has missed their start
+                match self.processes.get_mut(&event.process_id) {
+                    Some(process) => {
+                        if process.matches_classic(event.parent_id) {
+                            process.merge_classic(event.clone());
+                        }
+                    }
+                    None => {
+                        self.processes
+                            .insert(event.process_id, ProcessInfo::new(event.clone(), None));
+                    }
+                }
+            }
+            ProcessEventKind::End | ProcessEventKind::Defunct => {
+                self.processes.remove(&event.process_id);
+            }
+        }
+        Some(event)
+    }
+
+    fn update_kernel_process(&mut self, event: KernelProcessEvent, timestamp: i64) {
+        self.updated_at = Some(timestamp);
+        let known = self.processes.get_mut(&event.process_id);
+        match event.kind {
+            KernelProcessEventKind::Stop => {
+                if known.is_some_and(|process| process.matches_kernel_process(&event)) {
+                    self.processes.remove(&event.process_id);
+                }
+            }
+            KernelProcessEventKind::Start | KernelProcessEventKind::Rundown => match known {
+                Some(process) if process.matches_kernel_process(&event) => {
+                    // The classic kernel events have reported this process already
+                    process.start_key = process.start_key.or(event.start_key);
+                    process.parent_start_key = process.parent_start_key.or(event.parent_start_key);
+                }
+                // Rundowns do not replace processes that have been seen already
+                Some(_) if event.kind == KernelProcessEventKind::Rundown => (),
+                // Either a new process, or a process that has reused the ID of another one
+                _ => {
+                    let started_at =
+                        (event.kind == KernelProcessEventKind::Start).then_some(timestamp);
+                    self.processes.insert(
+                        event.process_id,
+                        ProcessInfo::from_kernel_process(event, started_at),
+                    );
+                }
+            },
+        }
+    }
+
+    /// A process that is running
+    pub fn get(&self, process_id: u32) -> Option<&ProcessInfo> {
+        self.processes.get(&process_id)
+    }
+
+    /// How many processes are running
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+
+    /// A copy of the tree, as it is now
+    pub fn snapshot(&self) -> ProcessSnapshot {
+        ProcessSnapshot {
+            processes: self
+                .processes
+                .iter()
+                .map(|(process_id, process)| (*process_id, process.clone()))
+                .collect(),
+            taken_at: self.updated_at,
+        }
+    }
+}
+
+/// A point-in-time copy of a [`ProcessTree`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessSnapshot {
+    /// The running processes, by process ID
+    pub processes: BTreeMap<u32, ProcessInfo>,
+    /// The `FILETIME` of the latest process event the tree had seen, or `None` in case it had not seen any
+    pub taken_at: Option<i64>,
+}
+
+impl ProcessSnapshot {
+    pub fn get(&self, process_id: u32) -> Option<&ProcessInfo> {
+        self.processes.get(&process_id)
+    }
+
+    /// The parent of a process, in case it is still running
+    pub fn parent(&self, process_id: u32) -> Option<&ProcessInfo> {
+        let process = self.get(process_id)?;
+        self.get(process.parent_id)
+            .filter(|parent| process.is_child_of(parent))
+    }
+
+    /// The processes that have been created by a process
+    pub fn children(&self, process_id: u32) -> impl Iterator<Item = &ProcessInfo> {
+        let parent = self.get(process_id);
+        self.processes
+            .values()
+            .filter(move |process| matches!(parent, Some(parent) if process.is_child_of(parent)))
+    }
+
+    /// The parent of a process, then the parent of its parent, and so on, as far as they are running
+    pub fn ancestors(&self, process_id: u32) -> impl Iterator<Item = &ProcessInfo> {
+        let mut current = process_id;
+        // Guards against cycles, which corrupted (or forged) parent IDs could create
+        let mut remaining = self.processes.len();
+        std::iter::from_fn(move || {
+            remaining = remaining.checked_sub(1)?;
+            let parent = self.parent(current)?;
+            current = parent.process_id;
+            Some(parent)
+        })
+    }
+
+    /// The processes whose parent is not running (or is unknown)
+    pub fn roots(&self) -> impl Iterator<Item = &ProcessInfo> {
+        self.processes
+            .values()
+            .filter(move |process| self.parent(process.process_id).is_none())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::kernel_guids;
+    use super::*;
+    use crate::test_utils::*;
+
+    fn process_event(
+        opcode: u8,
+        process_id: u32,
+        parent_id: u32,
+        timestamp: i64,
+    ) -> SyntheticEvent {
+        SyntheticEvent::new()
+            .with_provider(kernel_guids::PROCESS_GUID)
+            .with_opcode(opcode)
+            .with_version(2)
+            .with_timestamp(timestamp)
+            .with_pointer(0xffff_8000_0000_0000 | u64::from(process_id))
+            .with_user_data(&process_id.to_ne_bytes())
+            .with_user_data(&parent_id.to_ne_bytes())
+            .with_user_data(&1u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            // TOKEN_USER, then S-1-5-18
+            .with_pointer(0xdead)
+            .with_pointer(0)
+            .with_user_data(&[1, 1, 0, 0, 0, 0, 0, 5])
+            .with_user_data(&18u32.to_ne_bytes())
+            .with_user_data(format!("{}.exe\0", process_id).as_bytes())
+            .with_utf16_string("")
+    }
+
+    fn kernel_process_event(
+        event_id: u16,
+        process_id: u32,
+        start_key: u64,
+        parent: (u32, u64),
+        timestamp: i64,
+    ) -> SyntheticEvent {
+        let event = SyntheticEvent::new()
+            .with_provider(KERNEL_PROCESS.guid)
+            .with_event_id(event_id)
+            .with_version(if event_id == 15 { 1 } else { 3 })
+            .with_timestamp(timestamp)
+            .with_user_data(&process_id.to_ne_bytes())
+            .with_user_data(&start_key.to_ne_bytes())
+            .with_user_data(&timestamp.to_ne_bytes());
+        if event_id == 2 {
+            return event;
+        }
+        event
+            .with_user_data(&parent.0.to_ne_bytes())
+            .with_user_data(&parent.1.to_ne_bytes())
+            .with_user_data(&1u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            .with_user_data(&1u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            // S-1-16-12288 (high mandatory level)
+            .with_user_data(&[1, 1, 0, 0, 0, 0, 0, 16])
+            .with_user_data(&12288u32.to_ne_bytes())
+            .with_utf16_string(&format!(
+                "\\Device\\HarddiskVolume3\\Windows\\{}.exe",
+                process_id
+            ))
+    }
+
+    #[test]
+    fn test_kernel_process_event() {
+        let event = KernelProcessEvent::parse(
+            kernel_process_event(1, 900, 0x1234, (700, 0x1000), 20).record(),
+        )
+        .unwrap();
+        assert_eq!(event.kind, KernelProcessEventKind::Start);
+        assert_eq!(event.process_id, 900);
+        assert_eq!(event.start_key, Some(0x1234));
+        assert_eq!(event.create_time, 20);
+        assert_eq!(event.parent_id, Some(700));
+        assert_eq!(event.parent_start_key, Some(0x1000));
+        assert_eq!(event.session_id, Some(1));
+        assert_eq!(
+            event.image_name.as_deref(),
+            Some("\\Device\\HarddiskVolume3\\Windows\\900.exe")
+        );
+
+        let stop =
+            KernelProcessEvent::parse(kernel_process_event(2, 900, 0x1234, (0, 0), 30).record())
+                .unwrap();
+        assert_eq!(stop.kind, KernelProcessEventKind::Stop);
+        assert_eq!(stop.start_key, Some(0x1234));
+        assert_eq!(stop.image_name, None);
+
+        // Classic kernel events are not Kernel-Process events
+        assert!(matches!(
+            KernelProcessEvent::parse(process_event(1, 900, 700, 20).record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+    }
+
+    #[test]
+    fn test_start_keys() {
+        let mut tree = ProcessTree::new();
+        tree.update(process_event(3, 500, 4, 10).record());
+        tree.update(kernel_process_event(15, 500, 0x500, (4, 0x4), 10).record());
+        // Both providers report the same start, in any order
+        tree.update(kernel_process_event(1, 600, 0x600, (500, 0x500), 20).record());
+        tree.update(process_event(1, 600, 500, 20).record());
+        tree.update(process_event(1, 700, 500, 25).record());
+        tree.update(kernel_process_event(1, 700, 0x700, (500, 0x500), 25).record());
+
+        let process = tree.get(600).unwrap();
+        assert_eq!(process.start_key, Some(0x600));
+        assert_eq!(process.parent_start_key, Some(0x500));
+        assert_eq!(process.image_file_name, "600.exe");
+        assert_eq!(process.user_sid.as_deref(), Some("S-1-5-18"));
+        assert_ne!(process.unique_process_key, 0);
+        assert_eq!(tree.get(700).unwrap().start_key, Some(0x700));
+        assert_eq!(tree.get(500).unwrap().start_key, Some(0x500));
+        assert_eq!(tree.len(), 3);
+
+        // 500 ends, and its ID is reused by a process started by 700: start keys tell them apart
+        tree.update(kernel_process_event(2, 500, 0x500, (0, 0), 30).record());
+        assert!(tree.get(500).is_none());
+        tree.update(kernel_process_event(1, 500, 0x800, (700, 0x700), 40).record());
+        // A late stop of the previous process does not remove the new one
+        tree.update(kernel_process_event(2, 500, 0x500, (0, 0), 50).record());
+        assert_eq!(tree.get(500).unwrap().start_key, Some(0x800));
+
+        let snapshot = tree.snapshot();
+        assert!(snapshot.parent(600).is_none());
+        let ancestors: Vec<_> = snapshot.ancestors(500).map(|p| p.process_id).collect();
+        assert_eq!(ancestors, vec![700]);
+    }
+
+    #[test]
+    fn test_process_tree() {
+        let mut tree = ProcessTree::new();
+        // Rundown of the processes that were running before the trace started
+        for (process_id, parent_id) in [(4, 0), (600, 4), (700, 600), (800, 999)] {
+            tree.update(process_event(3, process_id, parent_id, 10).record());
+        }
+        tree.update(process_event(1, 900, 700, 20).record());
+        tree.update(process_event(1, 901, 900, 30).record());
+        tree.update(process_event(1, 902, 900, 30).record());
+        tree.update(process_event(2, 902, 900, 40).record());
+        // Already known
+        tree.update(process_event(3, 900, 12345, 50).record());
+        assert_eq!(tree.len(), 6);
+        assert_eq!(tree.get(900).unwrap().parent_id, 700);
+        assert_eq!(tree.get(900).unwrap().started_at, Some(20));
+        assert_eq!(tree.get(600).unwrap().started_at, None);
+        assert_eq!(tree.get(600).unwrap().image_file_name, "600.exe");
+        assert_eq!(tree.get(600).unwrap().session_id, 1);
+        assert_eq!(tree.get(600).unwrap().user_sid.as_deref(), Some("S-1-5-18"));
+        assert!(tree.get(902).is_none());
+
+        let snapshot = tree.snapshot();
+        assert_eq!(snapshot.taken_at, Some(50));
+        let ancestors: Vec<_> = snapshot.ancestors(901).map(|p| p.process_id).collect();
+        assert_eq!(ancestors, vec![900, 700, 600, 4]);
+        let children: Vec<_> = snapshot.children(900).map(|p| p.process_id).collect();
+        assert_eq!(children, vec![901]);
+        let roots: Vec<_> = snapshot.roots().map(|p| p.process_id).collect();
+        assert_eq!(roots, vec![4, 800]);
+
+        // The snapshot does not change with the tree
+        tree.update(process_event(2, 901, 900, 60).record());
+        assert!(tree.get(901).is_none());
+        assert!(snapshot.get(901).is_some());
+    }
+
+    #[test]
+    fn test_reused_parent_id() {
+        let mut tree = ProcessTree::new();
+        tree.update(process_event(3, 500, 4, 10).record());
+        tree.update(process_event(1, 600, 500, 20).record());
+        tree.update(process_event(2, 500, 4, 30).record());
+        tree.update(process_event(1, 500, 4, 40).record());
+
+        // 600 has been created by the previous process 500, that has ended
+        let snapshot = tree.snapshot();
+        assert!(snapshot.parent(600).is_none());
+        assert_eq!(snapshot.children(500).count(), 0);
+        assert_eq!(snapshot.get(500).unwrap().started_at, Some(40));
+    }
+}
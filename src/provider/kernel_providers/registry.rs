@@ -0,0 +1,351 @@
+//! Registry operations, with full key paths
+//!
+//! Events of the [`REGISTRY_PROVIDER`](super::REGISTRY_PROVIDER) do not always carry the path of their key.
+//! Most of them identify it by the address of its Key Control Block (KCB), and only give a name relative to it (or the name of a value).
+//! The paths of the KCBs are reported by separate events: when a KCB is created, and as a rundown of the existing KCBs, when the trace starts and ends.<br/>
+//! A [`RegistryTracker`] keeps track of the KCBs, and turns registry events into [`RegistryOperation`]s, attributed to the process that has performed them.
+//!
+//! ```no_run
+//! # use std::sync::Mutex;
+//! # use ferrisetw::{EventRecord, SchemaLocator};
+//! # use ferrisetw::trace::KernelTrace;
+//! use ferrisetw::provider::kernel_providers::registry::{self, RegistryEventKind, RegistryTracker};
+//!
+//! let tracker = Mutex::new(RegistryTracker::new());
+//! let provider = registry::provider()
+//!     .add_callback(move |record: &EventRecord, _locator: &SchemaLocator| {
+//!         let operation = match tracker.lock().unwrap().update(record) {
+//!             Some(operation) => operation,
+//!             None => return,
+//!         };
+//!         let key_path = operation.friendly_key_path().unwrap_or_default();
+//!         if operation.kind == RegistryEventKind::SetValue
+//!             && key_path.ends_with("\\CurrentVersion\\Run")
+//!         {
+//!             println!("Process {} has set {:?} in {}", operation.process_id, operation.value_name, key_path);
+//!         }
+//!     })
+//!     .build();
+//! let (_trace, _handle) = KernelTrace::new().enable(provider).start().unwrap();
+//! ```
+//!
+//! [`RegistryEvent::parse`] fails like the `parse` functions of [`events`](super::events).
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use super::events::{check_event, Reader};
+use super::{kernel_guids, REGISTRY_PROVIDER};
+use crate::native::etw_types::event_record::EventRecord;
+use crate::parser::ParserError;
+use crate::provider::{Provider, ProviderBuilder};
+
+/// A builder for the [`REGISTRY_PROVIDER`](super::REGISTRY_PROVIDER), to be enabled on a `KernelTrace`
+pub fn provider() -> ProviderBuilder {
+    Provider::kernel(&REGISTRY_PROVIDER)
+}
+
+/// The opcode of a [`RegistryEvent`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RegistryEventKind {
+    CreateKey,
+    OpenKey,
+    DeleteKey,
+    QueryKey,
+    SetValue,
+    DeleteValue,
+    QueryValue,
+    EnumerateKey,
+    EnumerateValue,
+    QueryMultipleValue,
+    SetInformation,
+    Flush,
+    KcbCreate,
+    KcbDelete,
+    /// A KCB that existed when the trace started
+    KcbRundownBegin,
+    /// A KCB that still existed when the trace ended
+    KcbRundownEnd,
+    Virtualize,
+    Close,
+}
+
+impl RegistryEventKind {
+    /// Whether the event describes a KCB, rather than an operation on a key
+    pub fn is_kcb_event(self) -> bool {
+        matches!(
+            self,
+            Self::KcbCreate | Self::KcbDelete | Self::KcbRundownBegin | Self::KcbRundownEnd
+        )
+    }
+
+    /// Whether the name of the event is the name of a value of the key, rather than (a part of) the path of the key
+    pub fn is_value_operation(self) -> bool {
+        matches!(self, Self::SetValue | Self::DeleteValue | Self::QueryValue)
+    }
+}
+
+/// An event of the [`REGISTRY_PROVIDER`](super::REGISTRY_PROVIDER) (`Registry_TypeGroup1` MOF class, versions 2 and later)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RegistryEvent {
+    pub kind: RegistryEventKind,
+    /// When the operation started, as a raw timestamp
+    pub initial_time: i64,
+    /// The `NTSTATUS` of the operation
+    pub status: u32,
+    /// The index of the subkey or value, for enumerations
+    pub index: u32,
+    /// The address of the KCB of the key (0 in case `key_name` is an absolute path)
+    pub key_handle: u64,
+    /// The path of the KCB for KCB events, the name of the value for value operations, and a path relative to `key_handle` otherwise
+    pub key_name: String,
+}
+
+impl RegistryEvent {
+    pub fn parse(record: &EventRecord) -> Result<Self, ParserError> {
+        let kind = match check_event(record, kernel_guids::REGISTRY_GUID, 2)? {
+            10 => RegistryEventKind::CreateKey,
+            11 => RegistryEventKind::OpenKey,
+            12 => RegistryEventKind::DeleteKey,
+            13 => RegistryEventKind::QueryKey,
+            14 => RegistryEventKind::SetValue,
+            15 => RegistryEventKind::DeleteValue,
+            16 => RegistryEventKind::QueryValue,
+            17 => RegistryEventKind::EnumerateKey,
+            18 => RegistryEventKind::EnumerateValue,
+            19 => RegistryEventKind::QueryMultipleValue,
+            20 => RegistryEventKind::SetInformation,
+            21 => RegistryEventKind::Flush,
+            22 => RegistryEventKind::KcbCreate,
+            23 => RegistryEventKind::KcbDelete,
+            24 => RegistryEventKind::KcbRundownBegin,
+            25 => RegistryEventKind::KcbRundownEnd,
+            26 => RegistryEventKind::Virtualize,
+            27 => RegistryEventKind::Close,
+            _ => return Err(ParserError::UnexpectedEvent),
+        };
+        let mut reader = Reader::new(record);
+        Ok(Self {
+            kind,
+            initial_time: reader.i64()?,
+            status: reader.u32()?,
+            index: reader.u32()?,
+            key_handle: reader.pointer()?,
+            key_name: reader.utf16_string()?,
+        })
+    }
+}
+
+/// An operation on a registry key, see [`RegistryTracker`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RegistryOperation {
+    pub kind: RegistryEventKind,
+    pub process_id: u32,
+    pub thread_id: u32,
+    /// The raw timestamp of the event
+    pub timestamp: i64,
+    /// The `NTSTATUS` of the operation
+    pub status: u32,
+    /// The full path of the key, e.g. `\REGISTRY\MACHINE\SOFTWARE\Microsoft`
+    ///
+    /// This is `None` in case the path of the KCB of the key is not known (e.g. because the KCB was created before the trace started, and has not been reported yet).
+    pub key_path: Option<String>,
+    /// The name of the value, for value operations
+    pub value_name: Option<String>,
+}
+
+impl RegistryOperation {
+    /// The path of the key, with its root key abbreviated (see [`friendly_path`])
+    pub fn friendly_key_path(&self) -> Option<Cow<'_, str>> {
+        self.key_path.as_deref().map(friendly_path)
+    }
+}
+
+/// The root keys, as the kernel names them and as they are usually abbreviated
+const ROOT_KEYS: &[(&str, &str)] = &[("\\REGISTRY\\MACHINE", "HKLM"), ("\\REGISTRY\\USER", "HKU")];
+
+/// Abbreviate the root key of a registry path, e.g. `\REGISTRY\MACHINE\SOFTWARE` becomes `HKLM\SOFTWARE`
+///
+/// Other paths are returned as-is.
+pub fn friendly_path(path: &str) -> Cow<'_, str> {
+    for (native, friendly) in ROOT_KEYS {
+        let prefix = match path.get(..native.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(native) => prefix,
+            _ => continue,
+        };
+        let rest = &path[prefix.len()..];
+        if rest.is_empty() || rest.starts_with('\\') {
+            return Cow::Owned(format!("{}{}", friendly, rest));
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+/// Reconstructs the paths of the keys that registry events refer to
+///
+/// See [the module documentation](self).
+#[derive(Debug, Default)]
+pub struct RegistryTracker {
+    /// The paths of the known KCBs, by address
+    kcbs: HashMap<u64, String>,
+}
+
+impl RegistryTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the tracker with an event of the [`REGISTRY_PROVIDER`](super::REGISTRY_PROVIDER). Other events are ignored.
+    ///
+    /// Returns the operation, in case the event describes one (rather than a KCB).
+    pub fn update(&mut self, record: &EventRecord) -> Option<RegistryOperation> {
+        let event = RegistryEvent::parse(record).ok()?;
+        match event.kind {
+            RegistryEventKind::KcbCreate
+            | RegistryEventKind::KcbRundownBegin
+            | RegistryEventKind::KcbRundownEnd => {
+                self.kcbs.insert(event.key_handle, event.key_name);
+                return None;
+            }
+            RegistryEventKind::KcbDelete => {
+                self.kcbs.remove(&event.key_handle);
+                return None;
+            }
+            _ => (),
+        }
+
+        let (key_path, value_name) = if event.kind.is_value_operation() {
+            (self.key_path(event.key_handle), Some(event.key_name))
+        } else {
+            (self.resolve(event.key_handle, &event.key_name), None)
+        };
+        Some(RegistryOperation {
+            kind: event.kind,
+            process_id: record.process_id(),
+            thread_id: record.thread_id(),
+            timestamp: record.raw_timestamp(),
+            status: event.status,
+            key_path,
+            value_name,
+        })
+    }
+
+    /// The path of a KCB, in case it is known
+    pub fn key_path(&self, kcb: u64) -> Option<String> {
+        self.kcbs.get(&kcb).cloned()
+    }
+
+    /// How many KCBs are known
+    pub fn known_keys(&self) -> usize {
+        self.kcbs.len()
+    }
+
+    /// The full path of `name`, relative to a KCB
+    fn resolve(&self, kcb: u64, name: &str) -> Option<String> {
+        if kcb == 0 {
+            return Some(name.to_string());
+        }
+        let base = self.kcbs.get(&kcb)?;
+        Some(match name.trim_start_matches('\\') {
+            "" => base.clone(),
+            name => format!("{}\\{}", base, name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn registry_event(opcode: u8, kcb: u64, name: &str) -> SyntheticEvent {
+        SyntheticEvent::new()
+            .with_provider(kernel_guids::REGISTRY_GUID)
+            .with_opcode(opcode)
+            .with_version(2)
+            .with_process_id(1234)
+            .with_user_data(&100i64.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            .with_user_data(&0u32.to_ne_bytes())
+            .with_pointer(kcb)
+            .with_utf16_string(name)
+    }
+
+    #[test]
+    fn test_registry_event() {
+        let event = registry_event(14, 0xffff_8000_0000_2000, "Updater");
+        let set_value = RegistryEvent::parse(event.record()).unwrap();
+        assert_eq!(set_value.kind, RegistryEventKind::SetValue);
+        assert_eq!(set_value.initial_time, 100);
+        assert_eq!(set_value.key_handle, 0xffff_8000_0000_2000);
+        assert_eq!(set_value.key_name, "Updater");
+
+        let unexpected = registry_event(42, 0, "");
+        assert!(matches!(
+            RegistryEvent::parse(unexpected.record()),
+            Err(ParserError::UnexpectedEvent)
+        ));
+        let truncated = SyntheticEvent::new()
+            .with_provider(kernel_guids::REGISTRY_GUID)
+            .with_opcode(14)
+            .with_version(2)
+            .with_user_data(&100i64.to_ne_bytes());
+        assert!(matches!(
+            RegistryEvent::parse(truncated.record()),
+            Err(ParserError::LengthMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_registry_tracker() {
+        let run = "\\REGISTRY\\MACHINE\\SOFTWARE\\Microsoft\\Windows\\CurrentVersion";
+        let mut tracker = RegistryTracker::new();
+        assert!(tracker
+            .update(registry_event(24, 0x1000, run).record())
+            .is_none());
+        assert!(tracker
+            .update(registry_event(22, 0x2000, &format!("{}\\Run", run)).record())
+            .is_none());
+        assert_eq!(tracker.known_keys(), 2);
+
+        let open = tracker
+            .update(registry_event(11, 0x1000, "\\Run").record())
+            .unwrap();
+        assert_eq!(open.kind, RegistryEventKind::OpenKey);
+        assert_eq!(open.key_path.as_deref(), Some(&*format!("{}\\Run", run)));
+        assert!(open.value_name.is_none());
+
+        let set_value = tracker
+            .update(registry_event(14, 0x2000, "Updater").record())
+            .unwrap();
+        assert_eq!(set_value.process_id, 1234);
+        assert_eq!(set_value.value_name.as_deref(), Some("Updater"));
+        assert_eq!(
+            set_value.friendly_key_path().unwrap(),
+            "HKLM\\SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Run"
+        );
+
+        tracker.update(registry_event(23, 0x2000, "").record());
+        let unknown = tracker
+            .update(registry_event(14, 0x2000, "Updater").record())
+            .unwrap();
+        assert!(unknown.key_path.is_none());
+
+        let absolute = tracker
+            .update(registry_event(10, 0, "\\REGISTRY\\USER\\S-1-5-18").record())
+            .unwrap();
+        assert_eq!(absolute.friendly_key_path().unwrap(), "HKU\\S-1-5-18");
+    }
+
+    #[test]
+    fn test_friendly_path() {
+        assert_eq!(friendly_path("\\Registry\\Machine\\SYSTEM"), "HKLM\\SYSTEM");
+        assert_eq!(friendly_path("\\REGISTRY\\USER"), "HKU");
+        assert_eq!(
+            friendly_path("\\REGISTRY\\MACHINEX"),
+            "\\REGISTRY\\MACHINEX"
+        );
+        assert_eq!(friendly_path("Run"), "Run");
+    }
+}
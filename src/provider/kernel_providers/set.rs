@@ -0,0 +1,241 @@
+//! Several kernel providers, enabled together on a single `KernelTrace`
+//!
+//! Windows has a single kernel session, whose `EnableFlags` are the union of the flags of every kernel provider.<br/>
+//! Several providers emit events of the same class (e.g. [`PROCESS_PROVIDER`](super::PROCESS_PROVIDER) and [`PROCESS_COUNTER_PROVIDER`](super::PROCESS_COUNTER_PROVIDER)),
+//! and events are routed to providers by class GUID, so that each of them would receive the events of the other.
+//! A [`KernelProviderSet`] makes it possible to restrict a provider to some opcodes, and detects such conflicts before the trace is started.
+use windows::core::GUID;
+
+use super::KernelProvider;
+use crate::native::etw_types::event_record::EventRecord;
+use crate::predicate::Pred;
+use crate::provider::{Provider, ProviderBuilder, ProviderError};
+use crate::schema_locator::SchemaLocator;
+
+/// A reason why the providers of a [`KernelProviderSet`] cannot be enabled together
+///
+/// Providers are referred to by their index in the set, in the order they have been added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelConflict {
+    /// Both providers receive events of this class, and their opcodes overlap: each one would receive the events of the other
+    AmbiguousClass {
+        guid: GUID,
+        first: usize,
+        second: usize,
+    },
+    /// Both providers are enabled by the same flags (or group mask), but expect events of different classes
+    SharedFlags { first: usize, second: usize },
+}
+
+struct Entry {
+    guid: GUID,
+    flags: u32,
+    group_mask: u32,
+    /// `None` means every opcode
+    opcodes: Option<Vec<u8>>,
+    builder: ProviderBuilder,
+}
+
+impl Entry {
+    fn opcodes_overlap(&self, other: &Entry) -> bool {
+        match (&self.opcodes, &other.opcodes) {
+            (Some(ours), Some(theirs)) => ours.iter().any(|opcode| theirs.contains(opcode)),
+            _ => true,
+        }
+    }
+
+    fn flags_overlap(&self, other: &Entry) -> bool {
+        self.flags & other.flags != 0
+            || (self.group_mask != 0 && self.group_mask == other.group_mask)
+    }
+}
+
+/// Kernel providers (each with its own callback) to enable together on a `KernelTrace`
+///
+/// # Example
+/// ```
+/// # use ferrisetw::provider::kernel_providers::{KernelProviderSet, PROCESS_PROVIDER, PROCESS_COUNTER_PROVIDER};
+/// # use ferrisetw::trace::KernelTrace;
+/// # let on_process = |_: &ferrisetw::EventRecord, _: &ferrisetw::schema_locator::SchemaLocator| {};
+/// # let on_counters = |_: &ferrisetw::EventRecord, _: &ferrisetw::schema_locator::SchemaLocator| {};
+/// let providers = KernelProviderSet::new()
+///     .add_for_opcodes(&PROCESS_PROVIDER, &[1, 2, 3, 4], on_process)
+///     .add_for_opcodes(&PROCESS_COUNTER_PROVIDER, &[32, 33], on_counters)
+///     .build()
+///     .unwrap();
+/// let builder = providers
+///     .into_iter()
+///     .fold(KernelTrace::new(), |builder, provider| builder.enable(provider));
+/// ```
+#[derive(Default)]
+pub struct KernelProviderSet {
+    entries: Vec<Entry>,
+}
+
+impl KernelProviderSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a provider, whose `callback` receives every event of its class
+    pub fn add<F>(self, provider: &KernelProvider, callback: F) -> Self
+    where
+        F: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.push(provider, None, callback)
+    }
+
+    /// Add a provider, whose `callback` only receives the events of its class that have one of these `opcodes`
+    pub fn add_for_opcodes<F>(self, provider: &KernelProvider, opcodes: &[u8], callback: F) -> Self
+    where
+        F: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.push(provider, Some(opcodes.to_vec()), callback)
+    }
+
+    fn push<F>(mut self, provider: &KernelProvider, opcodes: Option<Vec<u8>>, callback: F) -> Self
+    where
+        F: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        self.entries.push(Entry {
+            guid: provider.guid,
+            flags: provider.flags,
+            group_mask: provider.group_mask,
+            opcodes,
+            builder: Provider::kernel(provider).add_callback(callback),
+        });
+        self
+    }
+
+    /// The `EnableFlags` of the kernel session, once every provider of this set is enabled
+    pub fn enable_flags(&self) -> u32 {
+        self.entries.iter().fold(0, |acc, entry| acc | entry.flags)
+    }
+
+    /// Every pair of providers of this set that cannot be enabled together
+    pub fn conflicts(&self) -> Vec<KernelConflict> {
+        let mut conflicts = Vec::new();
+        for (first, ours) in self.entries.iter().enumerate() {
+            for (second, theirs) in self.entries.iter().enumerate().skip(first + 1) {
+                if ours.guid == theirs.guid {
+                    if ours.opcodes_overlap(theirs) {
+                        conflicts.push(KernelConflict::AmbiguousClass {
+                            guid: ours.guid,
+                            first,
+                            second,
+                        });
+                    }
+                } else if ours.flags_overlap(theirs) {
+                    conflicts.push(KernelConflict::SharedFlags { first, second });
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Build one [`Provider`] per entry of this set, to be enabled on the same `KernelTrace`
+    ///
+    /// This fails in case of [`conflicts`](Self::conflicts).
+    pub fn build(self) -> Result<Vec<Provider>, ProviderError> {
+        let conflicts = self.conflicts();
+        if !conflicts.is_empty() {
+            return Err(ProviderError::KernelConflicts(conflicts));
+        }
+
+        Ok(self
+            .entries
+            .into_iter()
+            .map(|entry| {
+                let opcode_pred = entry
+                    .opcodes
+                    .and_then(|opcodes| opcodes.into_iter().map(Pred::opcode).reduce(Pred::or));
+                match opcode_pred {
+                    Some(pred) => entry.builder.filter_with(pred).build(),
+                    None => entry.builder.build(),
+                }
+            })
+            .collect())
+    }
+}
+
+impl std::fmt::Debug for KernelProviderSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KernelProviderSet")
+            .field("len", &self.entries.len())
+            .field("enable_flags", &self.enable_flags())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use crate::test_utils::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_conflicts() {
+        let set = KernelProviderSet::new()
+            .add(&PROCESS_PROVIDER, |_, _| {})
+            .add(&PROCESS_COUNTER_PROVIDER, |_, _| {})
+            .add(&IMAGE_LOAD_PROVIDER, |_, _| {});
+        assert_eq!(
+            set.conflicts(),
+            vec![KernelConflict::AmbiguousClass {
+                guid: PROCESS_PROVIDER.guid,
+                first: 0,
+                second: 1
+            }]
+        );
+        assert!(set.build().is_err());
+
+        let set = KernelProviderSet::new()
+            .add(&DISK_IO_PROVIDER, |_, _| {})
+            .add(
+                &KernelProvider::new(GUID::from_u128(0x773), DISK_IO_PROVIDER.flags),
+                |_, _| {},
+            );
+        assert_eq!(
+            set.conflicts(),
+            vec![KernelConflict::SharedFlags {
+                first: 0,
+                second: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_opcode_routing() {
+        let process_events = Arc::new(AtomicUsize::new(0));
+        let counter_events = Arc::new(AtomicUsize::new(0));
+        let (p, c) = (Arc::clone(&process_events), Arc::clone(&counter_events));
+
+        let set = KernelProviderSet::new()
+            .add_for_opcodes(&PROCESS_PROVIDER, &[1, 2], move |_, _| {
+                p.fetch_add(1, Ordering::Relaxed);
+            })
+            .add_for_opcodes(&PROCESS_COUNTER_PROVIDER, &[32], move |_, _| {
+                c.fetch_add(1, Ordering::Relaxed);
+            });
+        assert!(set.conflicts().is_empty());
+        assert_eq!(
+            set.enable_flags(),
+            PROCESS_PROVIDER.flags | PROCESS_COUNTER_PROVIDER.flags
+        );
+
+        let providers = set.build().unwrap();
+        let locator = SchemaLocator::new();
+        for opcode in [1, 2, 32, 39] {
+            let event = SyntheticEvent::new()
+                .with_provider(PROCESS_PROVIDER.guid)
+                .with_opcode(opcode);
+            for provider in &providers {
+                provider.on_event(event.record(), &locator);
+            }
+        }
+        assert_eq!(process_events.load(Ordering::Relaxed), 2);
+        assert_eq!(counter_events.load(Ordering::Relaxed), 1);
+    }
+}
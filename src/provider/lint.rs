@@ -0,0 +1,126 @@
+//! Warnings about providers that are enabled with keywords that cannot match any event
+//!
+//! A typo in a keyword mask usually goes unnoticed: the trace starts fine, but receives nothing.<br/>
+//! These checks compare the keywords of a provider with the ones it actually defines (see [`ProviderMetadata`]).
+//! They are opt-in (see [`set_hook`]), because they query the metadata of every provider that is enabled.
+use std::sync::{Arc, PoisonError, RwLock};
+
+use once_cell::sync::Lazy;
+use windows::core::GUID;
+
+use super::metadata::ProviderMetadata;
+use super::Provider;
+
+/// A suspicious keyword configuration, see [`lint_keywords`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KeywordLint {
+    /// `any` does not contain any keyword defined by the provider. Only events that have no keyword will be received
+    NoDefinedKeywordInAny { any: u64, defined: u64 },
+    /// `all` contains keywords that the provider does not define. Only events that have no keyword will be received
+    UndefinedKeywordsInAll { undefined: u64 },
+}
+
+type Hook = Arc<dyn Fn(GUID, &KeywordLint) + Send + Sync>;
+
+static HOOK: Lazy<RwLock<Option<Hook>>> = Lazy::new(|| RwLock::new(None));
+
+/// Check the `any` and `all` keywords of a provider against the keywords it defines
+///
+/// A provider that defines no keyword is not reported: it will not filter its events by keyword anyway.
+pub fn lint_keywords(any: u64, all: u64, metadata: &ProviderMetadata) -> Vec<KeywordLint> {
+    let defined = metadata.defined_keywords();
+    let mut lints = Vec::new();
+    if defined == 0 {
+        return lints;
+    }
+    if any != 0 && any & defined == 0 {
+        lints.push(KeywordLint::NoDefinedKeywordInAny { any, defined });
+    }
+    if all & !defined != 0 {
+        lints.push(KeywordLint::UndefinedKeywordsInAll {
+            undefined: all & !defined,
+        });
+    }
+    lints
+}
+
+/// Run `hook` for every suspicious keyword configuration, whenever a provider is enabled on a trace
+///
+/// This replaces any previous hook.
+pub fn set_hook<F>(hook: F)
+where
+    F: Fn(GUID, &KeywordLint) + Send + Sync + 'static,
+{
+    *HOOK.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(hook));
+}
+
+/// Log every suspicious keyword configuration as a warning, whenever a provider is enabled on a trace
+///
+/// This is a shortcut for [`set_hook`].
+pub fn log_warnings() {
+    set_hook(|guid, lint| log::warn!("Provider {:?} may not receive any event: {:?}", guid, lint));
+}
+
+/// Stop checking the keywords of providers
+pub fn clear_hook() {
+    *HOOK.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// Run the hook (if any) on the keywords of `provider`
+pub(crate) fn check(provider: &Provider) {
+    // The lock is not held while querying the metadata and running the hook, that may itself set another hook
+    let hook = match HOOK.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        None => return,
+        Some(hook) => Arc::clone(hook),
+    };
+
+    let metadata = match ProviderMetadata::query(provider.guid()) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            log::debug!(
+                "Unable to query the keywords of provider {:?}: {:?}",
+                provider.guid(),
+                err
+            );
+            return;
+        }
+    };
+    for lint in lint_keywords(provider.any(), provider.all(), &metadata) {
+        hook(provider.guid(), &lint);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lint_keywords() {
+        let metadata = ProviderMetadata::from_fields(
+            GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716),
+            Vec::new(),
+            vec![
+                (String::from("Network"), 0x10),
+                (String::from("Disk"), 0x20),
+            ],
+        );
+
+        assert!(lint_keywords(0, 0, &metadata).is_empty());
+        assert!(lint_keywords(u64::MAX, 0x10, &metadata).is_empty());
+        assert_eq!(
+            lint_keywords(0x1, 0, &metadata),
+            vec![KeywordLint::NoDefinedKeywordInAny {
+                any: 0x1,
+                defined: 0x30
+            }]
+        );
+        assert_eq!(
+            lint_keywords(0x10, 0x50, &metadata),
+            vec![KeywordLint::UndefinedKeywordsInAll { undefined: 0x40 }]
+        );
+
+        let no_keywords = ProviderMetadata::from_fields(GUID::zeroed(), Vec::new(), Vec::new());
+        assert!(lint_keywords(0x1, 0x1, &no_keywords).is_empty());
+    }
+}
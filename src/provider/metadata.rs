@@ -0,0 +1,226 @@
+//! Names of the levels, keywords and channels defined by a provider, and the events it declares
+//!
+//! These make it possible to filter events with the names used in the provider manifest, rather than with magic values and masks.
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use crate::native::tdh;
+use crate::native::TdhNativeError;
+
+/// Levels, keywords, channels and events defined by a provider, as reported by TDH
+///
+/// See [`EventRecord::matches`](crate::EventRecord::matches)
+#[derive(Debug, Clone)]
+pub struct ProviderMetadata {
+    guid: GUID,
+    levels: Vec<(String, u8)>,
+    keywords: Vec<(String, u64)>,
+    channels: Vec<(String, u8)>,
+    /// `None` in case the events could not be enumerated
+    events: Option<Vec<EventDescriptor>>,
+}
+
+/// The descriptor of an event, as declared in the manifest of its provider
+///
+/// See [EVENT_DESCRIPTOR](https://learn.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_descriptor)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventDescriptor {
+    pub id: u16,
+    pub version: u8,
+    pub channel: u8,
+    pub level: u8,
+    pub opcode: u8,
+    pub task: u16,
+    pub keyword: u64,
+}
+
+impl From<Etw::EVENT_DESCRIPTOR> for EventDescriptor {
+    fn from(descriptor: Etw::EVENT_DESCRIPTOR) -> Self {
+        Self {
+            id: descriptor.Id,
+            version: descriptor.Version,
+            channel: descriptor.Channel,
+            level: descriptor.Level,
+            opcode: descriptor.Opcode,
+            task: descriptor.Task,
+            keyword: descriptor.Keyword,
+        }
+    }
+}
+
+impl ProviderMetadata {
+    /// Query the levels, keywords, channels and events of a provider
+    ///
+    /// This only works for providers whose metadata is known to TDH (e.g. manifest-based providers registered on this machine).<br/>
+    /// Enumerating the events requires Windows 10 version 2004 (or later). In case they cannot be enumerated, the levels, keywords and channels are still returned (see [`Self::events`]).
+    pub fn query(provider: GUID) -> Result<Self, TdhNativeError> {
+        let levels = tdh::provider_field_information(&provider, Etw::EventLevelInformation)?
+            .into_iter()
+            .map(|(name, value)| (name, value as u8))
+            .collect();
+        let keywords = tdh::provider_field_information(&provider, Etw::EventKeywordInformation)?;
+        let channels = tdh::provider_field_information(&provider, Etw::EventChannelInformation)?
+            .into_iter()
+            .map(|(name, value)| (name, value as u8))
+            .collect();
+        let events = match tdh::manifest_provider_events(&provider) {
+            Ok(events) => Some(events.into_iter().map(EventDescriptor::from).collect()),
+            Err(err) => {
+                log::debug!(
+                    "Unable to enumerate the events of provider {:?}: {}",
+                    provider,
+                    err
+                );
+                None
+            }
+        };
+
+        Ok(Self {
+            guid: provider,
+            levels,
+            keywords,
+            channels,
+            events,
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_fields(
+        guid: GUID,
+        levels: Vec<(String, u8)>,
+        keywords: Vec<(String, u64)>,
+    ) -> Self {
+        Self {
+            guid,
+            levels,
+            keywords,
+            channels: Vec::new(),
+            events: None,
+        }
+    }
+
+    /// The GUID of the provider
+    pub fn guid(&self) -> GUID {
+        self.guid
+    }
+
+    /// The names and values of the levels defined by the provider
+    pub fn levels(&self) -> &[(String, u8)] {
+        &self.levels
+    }
+
+    /// The names and masks of the keywords defined by the provider
+    pub fn keywords(&self) -> &[(String, u64)] {
+        &self.keywords
+    }
+
+    /// The names and values of the channels defined by the provider
+    pub fn channels(&self) -> &[(String, u8)] {
+        &self.channels
+    }
+
+    /// The descriptors of the events declared in the manifest of the provider
+    ///
+    /// This is `None` in case they could not be enumerated (e.g. before Windows 10 version 2004).
+    pub fn events(&self) -> Option<&[EventDescriptor]> {
+        self.events.as_deref()
+    }
+
+    /// The value of a level, given its (case-insensitive) name
+    pub fn level(&self, name: &str) -> Option<u8> {
+        self.levels
+            .iter()
+            .find(|(level_name, _)| level_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// The mask of a keyword, given its (case-insensitive) name
+    pub fn keyword(&self, name: &str) -> Option<u64> {
+        self.keywords
+            .iter()
+            .find(|(keyword_name, _)| keyword_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// The value of a channel, given its (case-insensitive) name
+    pub fn channel(&self, name: &str) -> Option<u8> {
+        self.channels
+            .iter()
+            .find(|(channel_name, _)| channel_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// The combined mask of several keywords
+    ///
+    /// This returns `None` in case any of them is unknown
+    pub fn keywords_mask(&self, names: &[&str]) -> Option<u64> {
+        names
+            .iter()
+            .try_fold(0, |mask, name| Some(mask | self.keyword(name)?))
+    }
+
+    /// The combined mask of every keyword defined by the provider
+    pub fn defined_keywords(&self) -> u64 {
+        self.keywords
+            .iter()
+            .fold(0, |mask, (_, value)| mask | value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SyntheticEvent;
+
+    const PROVIDER: GUID = GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716);
+
+    #[test]
+    fn test_record_matches() {
+        let metadata = ProviderMetadata::from_fields(
+            PROVIDER,
+            vec![(String::from("Error"), 2), (String::from("Warning"), 3)],
+            vec![
+                (String::from("Network"), 0x10),
+                (String::from("Disk"), 0x20),
+            ],
+        );
+        let event = SyntheticEvent::new()
+            .with_provider(PROVIDER)
+            .with_level(2)
+            .with_keyword(0x10);
+        let record = event.record();
+
+        assert!(record.matches(&metadata, "Error", &["Network"]));
+        assert!(record.matches(&metadata, "warning", &[]));
+        assert!(record.matches(&metadata, "Warning", &["Disk", "Network"]));
+        assert!(!record.matches(&metadata, "Error", &["Disk"]));
+        assert!(!record.matches(&metadata, "Verbose", &[]));
+        assert!(!record.matches(&metadata, "Error", &["Unknown"]));
+    }
+
+    #[test]
+    fn test_event_descriptor() {
+        let native = Etw::EVENT_DESCRIPTOR {
+            Id: 12,
+            Version: 1,
+            Channel: 16,
+            Level: 4,
+            Opcode: 2,
+            Task: 7,
+            Keyword: 0x8000_0000_0000_0010,
+        };
+
+        assert_eq!(
+            EventDescriptor::from(native),
+            EventDescriptor {
+                id: 12,
+                version: 1,
+                channel: 16,
+                level: 4,
+                opcode: 2,
+                task: 7,
+                keyword: 0x8000_0000_0000_0010,
+            }
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Providers that only protected processes are allowed to enable
+//!
+//! A few providers, most notably `Microsoft-Windows-Threat-Intelligence`, can only be enabled by processes that run as
+//! [Protected Process Light](https://learn.microsoft.com/en-us/windows/win32/services/protecting-anti-malware-services-)
+//! with (at least) the anti-malware signer. Being an elevated administrator is not enough.<br/>
+//! Any other process gets an `ERROR_ACCESS_DENIED`, that ferrisetw reports as an
+//! [`EvntraceNativeError::ProviderAccessDenied`](crate::native::EvntraceNativeError::ProviderAccessDenied).
+//!
+//! To consume these providers:
+//! 1. the binary must be signed with a certificate registered by an Early Launch Anti-Malware driver, and be started as a protected service
+//!    (`SERVICE_LAUNCH_PROTECTED_ANTIMALWARE_LIGHT`);
+//! 2. [`can_enable_protected_providers`] tells whether the current process qualifies;
+//! 3. the provider is then enabled like any other provider. Its events are only emitted for the keywords that are requested,
+//!    so `any` should be set explicitly to the keywords of interest.
+//!
+//! ```no_run
+//! # use ferrisetw::provider::{protected, Provider};
+//! # use ferrisetw::UserTrace;
+//! if protected::can_enable_protected_providers() != Some(true) {
+//!     panic!("Threat-Intelligence events require a PPL anti-malware process");
+//! }
+//! let provider = Provider::by_guid(protected::THREAT_INTELLIGENCE_GUID)
+//!     // Remote memory allocations and writes
+//!     .any(0x4 | 0x80000)
+//!     .build();
+//! let _trace = UserTrace::new().enable(provider).start_and_process().unwrap();
+//! ```
+//!
+//! Access to regular providers may also be restricted by their security descriptor, see [`EventAccess`](crate::query::EventAccess).
+use windows::core::GUID;
+
+use crate::native::privileges;
+
+/// `Microsoft-Windows-Threat-Intelligence`
+pub const THREAT_INTELLIGENCE_GUID: GUID = GUID::from_values(
+    0xf4e1897c,
+    0xbb5d,
+    0x5668,
+    [0xf1, 0xd8, 0x04, 0x0f, 0x4d, 0x8d, 0xd3, 0x44],
+);
+
+/// The known providers that can only be enabled by protected processes
+pub static PROTECTED_PROVIDERS: &[GUID] = &[THREAT_INTELLIGENCE_GUID];
+
+/// Whether this provider is known to only accept protected processes
+pub fn is_protected(guid: GUID) -> bool {
+    PROTECTED_PROVIDERS.contains(&guid)
+}
+
+/// The protection level of a process
+///
+/// See [`PROCESS_PROTECTION_LEVEL_INFORMATION`](https://learn.microsoft.com/en-us/windows/win32/api/processthreadsapi/ns-processthreadsapi-process_protection_level_information)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ProtectionLevel {
+    /// The process is not protected
+    None,
+    WinTcbLight,
+    Windows,
+    WindowsLight,
+    AntimalwareLight,
+    LsaLight,
+    WinTcb,
+    CodeGenLight,
+    Authenticode,
+    PplApp,
+    /// A level this version of ferrisetw does not know about
+    Other(u32),
+}
+
+impl From<u32> for ProtectionLevel {
+    fn from(level: u32) -> Self {
+        match level {
+            0 => Self::WinTcbLight,
+            1 => Self::Windows,
+            2 => Self::WindowsLight,
+            3 => Self::AntimalwareLight,
+            4 => Self::LsaLight,
+            5 => Self::WinTcb,
+            6 => Self::CodeGenLight,
+            7 => Self::Authenticode,
+            8 => Self::PplApp,
+            0xFFFF_FFFE => Self::None,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl ProtectionLevel {
+    /// The protection level of the current process, or `None` in case it cannot be determined
+    pub fn current() -> Option<Self> {
+        privileges::protection_level().map(Self::from)
+    }
+
+    /// Whether this level is high enough to enable [protected providers](PROTECTED_PROVIDERS)
+    ///
+    /// This requires a signer at least as trusted as the anti-malware one.
+    pub fn allows_protected_providers(&self) -> bool {
+        matches!(
+            self,
+            Self::AntimalwareLight
+                | Self::LsaLight
+                | Self::WindowsLight
+                | Self::Windows
+                | Self::WinTcbLight
+                | Self::WinTcb
+        )
+    }
+}
+
+/// Whether the current process is allowed to enable [protected providers](PROTECTED_PROVIDERS)
+///
+/// This returns `None` in case this cannot be determined.
+pub fn can_enable_protected_providers() -> Option<bool> {
+    ProtectionLevel::current().map(|level| level.allows_protected_providers())
+}
+
+/// Why enabling a provider has been denied, as far as ferrisetw can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AccessDeniedReason {
+    /// This is a [protected provider](PROTECTED_PROVIDERS), and the current process does not run with a high enough protection level
+    ///
+    /// `level` is `None` in case the protection level of the current process cannot be determined.
+    NotProtected { level: Option<ProtectionLevel> },
+    /// The current process is not an elevated administrator
+    NotElevated,
+    /// The security descriptor of this provider does not grant the current user the right to enable it (see [`EventAccess`](crate::query::EventAccess))
+    Restricted,
+}
+
+impl AccessDeniedReason {
+    /// Guess why enabling this provider failed with `ERROR_ACCESS_DENIED`
+    pub(crate) fn diagnose(guid: GUID) -> Self {
+        if is_protected(guid) {
+            let level = ProtectionLevel::current();
+            if !matches!(level, Some(level) if level.allows_protected_providers()) {
+                return Self::NotProtected { level };
+            }
+        }
+        if privileges::is_elevated_administrator() == Some(false) {
+            return Self::NotElevated;
+        }
+        Self::Restricted
+    }
+}
+
+impl std::fmt::Display for AccessDeniedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotProtected { level: Some(level) } => write!(
+                f,
+                "this provider requires a protected anti-malware process, current protection level is {:?}",
+                level
+            ),
+            Self::NotProtected { level: None } => write!(
+                f,
+                "this provider requires a protected anti-malware process"
+            ),
+            Self::NotElevated => write!(f, "the current process is not elevated"),
+            Self::Restricted => write!(
+                f,
+                "the security descriptor of this provider does not allow the current user to enable it"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_protection_levels() {
+        assert_eq!(ProtectionLevel::from(0xFFFF_FFFE), ProtectionLevel::None);
+        assert_eq!(ProtectionLevel::from(3), ProtectionLevel::AntimalwareLight);
+        assert_eq!(ProtectionLevel::from(42), ProtectionLevel::Other(42));
+
+        assert!(ProtectionLevel::AntimalwareLight.allows_protected_providers());
+        assert!(ProtectionLevel::WinTcb.allows_protected_providers());
+        assert!(!ProtectionLevel::None.allows_protected_providers());
+        assert!(!ProtectionLevel::Authenticode.allows_protected_providers());
+        assert!(!ProtectionLevel::PplApp.allows_protected_providers());
+    }
+
+    #[test]
+    fn test_protected_providers() {
+        assert!(is_protected(GUID::from(
+            "f4e1897c-bb5d-5668-f1d8-040f4d8dd344"
+        )));
+        assert!(!is_protected(GUID::from(
+            "22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716"
+        )));
+        // Test processes never run as PPL
+        assert_eq!(
+            AccessDeniedReason::diagnose(THREAT_INTELLIGENCE_GUID),
+            AccessDeniedReason::NotProtected {
+                level: ProtectionLevel::current()
+            }
+        );
+    }
+}
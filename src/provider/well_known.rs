@@ -0,0 +1,240 @@
+//! GUIDs and keywords of commonly used providers
+//!
+//! These save looking up (and copy-pasting) GUID strings:
+//! ```
+//! # use ferrisetw::provider::{well_known, Provider};
+//! let provider = Provider::by_guid(well_known::KERNEL_PROCESS)
+//!     .any(well_known::keywords::kernel_process::PROCESS | well_known::keywords::kernel_process::IMAGE)
+//!     .build();
+//! assert_eq!(provider.guid(), well_known::KERNEL_PROCESS.guid);
+//! ```
+//!
+//! Keywords are only listed for the providers whose manifest defines stable ones. [`metadata`](super::metadata) lists the keywords of any registered provider.
+use windows::core::GUID;
+
+use super::protected::THREAT_INTELLIGENCE_GUID;
+
+/// A provider that ships with Windows (or with a widespread product)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WellKnownProvider {
+    /// The name of the provider, as registered in its manifest
+    pub name: &'static str,
+    pub guid: GUID,
+}
+
+impl WellKnownProvider {
+    const fn new(name: &'static str, guid: u128) -> Self {
+        Self {
+            name,
+            guid: GUID::from_u128(guid),
+        }
+    }
+}
+
+impl From<WellKnownProvider> for GUID {
+    fn from(provider: WellKnownProvider) -> Self {
+        provider.guid
+    }
+}
+
+pub const KERNEL_PROCESS: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-Kernel-Process",
+    0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716,
+);
+pub const KERNEL_FILE: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-Kernel-File",
+    0xedd08927_9cc4_4e65_b970_c2560fb5c289,
+);
+pub const KERNEL_NETWORK: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-Kernel-Network",
+    0x7dd42a49_5329_4832_8dfd_43d979153a88,
+);
+pub const KERNEL_REGISTRY: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-Kernel-Registry",
+    0x70eb4f03_c1de_4f73_a051_33d13d5413bd,
+);
+pub const KERNEL_AUDIT_API_CALLS: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-Kernel-Audit-API-Calls",
+    0xe02a841c_75a3_4fa7_afc8_ae09cf9b7f23,
+);
+pub const DNS_CLIENT: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-DNS-Client",
+    0x1c95126e_7eea_49a9_a3fe_a378b03ddb4d,
+);
+pub const TCPIP: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-TCPIP",
+    0x2f07e2ee_15db_40f1_90ef_9d7ba282188a,
+);
+pub const SMB_CLIENT: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-SMBClient",
+    0x988c59c5_0a1c_45b6_a555_0c62276e327d,
+);
+pub const RPC: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-RPC",
+    0x6ad52b32_d609_4be9_ae07_ce8dae937e39,
+);
+pub const POWERSHELL: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-PowerShell",
+    0xa0c1853b_5c40_4b15_8766_3cf1c58f985a,
+);
+pub const WMI_ACTIVITY: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-WMI-Activity",
+    0x1418ef04_b0b4_4623_bf7e_d74ab47bbdaa,
+);
+pub const SECURITY_AUDITING: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-Security-Auditing",
+    0x54849625_5478_4994_a5ba_3e3b0328c30d,
+);
+/// `Microsoft-Antimalware-Scan-Interface` (AMSI)
+pub const AMSI: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Antimalware-Scan-Interface",
+    0x2a576b87_09a7_520e_c21a_4942f0271d67,
+);
+pub const DOTNET_RUNTIME: WellKnownProvider = WellKnownProvider::new(
+    "Microsoft-Windows-DotNETRuntime",
+    0xe13c0d23_ccbc_4e12_931b_d9cc2eee27e4,
+);
+/// This provider can only be enabled by protected processes, see [`protected`](super::protected)
+pub const THREAT_INTELLIGENCE: WellKnownProvider = WellKnownProvider {
+    name: "Microsoft-Windows-Threat-Intelligence",
+    guid: THREAT_INTELLIGENCE_GUID,
+};
+
+/// Every provider of this module
+pub static WELL_KNOWN_PROVIDERS: &[WellKnownProvider] = &[
+    KERNEL_PROCESS,
+    KERNEL_FILE,
+    KERNEL_NETWORK,
+    KERNEL_REGISTRY,
+    KERNEL_AUDIT_API_CALLS,
+    DNS_CLIENT,
+    TCPIP,
+    SMB_CLIENT,
+    RPC,
+    POWERSHELL,
+    WMI_ACTIVITY,
+    SECURITY_AUDITING,
+    AMSI,
+    DOTNET_RUNTIME,
+    THREAT_INTELLIGENCE,
+];
+
+/// Find a provider of this module by its name (case-insensitive)
+pub fn by_name(name: &str) -> Option<WellKnownProvider> {
+    WELL_KNOWN_PROVIDERS
+        .iter()
+        .find(|provider| provider.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+/// Find a provider of this module by its GUID
+pub fn by_guid(guid: GUID) -> Option<WellKnownProvider> {
+    WELL_KNOWN_PROVIDERS
+        .iter()
+        .find(|provider| provider.guid == guid)
+        .copied()
+}
+
+/// Keyword masks of the providers of this module, to be used with [`ProviderBuilder::any`](super::ProviderBuilder::any) and [`ProviderBuilder::all`](super::ProviderBuilder::all)
+pub mod keywords {
+    /// See [`KERNEL_PROCESS`](super::KERNEL_PROCESS)
+    pub mod kernel_process {
+        pub const PROCESS: u64 = 0x10;
+        pub const THREAD: u64 = 0x20;
+        pub const IMAGE: u64 = 0x40;
+        pub const CPU_PRIORITY: u64 = 0x80;
+        pub const OTHER_PRIORITY: u64 = 0x100;
+        pub const PROCESS_FREEZE: u64 = 0x200;
+        pub const JOB: u64 = 0x400;
+        pub const ENABLE_PROCESS_TRACING_CALLBACKS: u64 = 0x800;
+        pub const JOB_IO: u64 = 0x1000;
+        pub const WORK_ON_BEHALF: u64 = 0x2000;
+        pub const JOB_SILO: u64 = 0x4000;
+    }
+
+    /// See [`KERNEL_FILE`](super::KERNEL_FILE)
+    pub mod kernel_file {
+        pub const FILENAME: u64 = 0x10;
+        pub const FILEIO: u64 = 0x20;
+        pub const OP_END: u64 = 0x40;
+        pub const CREATE: u64 = 0x80;
+        pub const READ: u64 = 0x100;
+        pub const WRITE: u64 = 0x200;
+        pub const DELETE_PATH: u64 = 0x400;
+        pub const RENAME_SETLINK_PATH: u64 = 0x800;
+        pub const CREATE_NEW_FILE: u64 = 0x1000;
+    }
+
+    /// See [`KERNEL_NETWORK`](super::KERNEL_NETWORK)
+    pub mod kernel_network {
+        pub const IPV4: u64 = 0x10;
+        pub const IPV6: u64 = 0x20;
+    }
+
+    /// See [`POWERSHELL`](super::POWERSHELL)
+    pub mod powershell {
+        pub const RUNSPACE: u64 = 0x1;
+        pub const PIPELINE: u64 = 0x2;
+        pub const PROTOCOL: u64 = 0x4;
+        pub const TRANSPORT: u64 = 0x8;
+        pub const HOST: u64 = 0x10;
+        pub const CMDLETS: u64 = 0x20;
+        pub const SERIALIZER: u64 = 0x40;
+        pub const SESSION: u64 = 0x80;
+        pub const MANAGED_PLUGIN: u64 = 0x100;
+    }
+
+    /// See [`DOTNET_RUNTIME`](super::DOTNET_RUNTIME)
+    pub mod dotnet_runtime {
+        pub const GC: u64 = 0x1;
+        pub const GC_HANDLE: u64 = 0x2;
+        pub const LOADER: u64 = 0x8;
+        pub const JIT: u64 = 0x10;
+        pub const NGEN: u64 = 0x20;
+        pub const START_ENUMERATION: u64 = 0x40;
+        pub const END_ENUMERATION: u64 = 0x80;
+        pub const SECURITY: u64 = 0x400;
+        pub const APP_DOMAIN_RESOURCE_MANAGEMENT: u64 = 0x800;
+        pub const JIT_TRACING: u64 = 0x1000;
+        pub const INTEROP: u64 = 0x2000;
+        pub const CONTENTION: u64 = 0x4000;
+        pub const EXCEPTION: u64 = 0x8000;
+        pub const THREADING: u64 = 0x10000;
+        pub const JITTED_METHOD_IL_TO_NATIVE_MAP: u64 = 0x20000;
+        pub const TYPE: u64 = 0x80000;
+        pub const GC_HEAP_DUMP: u64 = 0x100000;
+        /// Adds a stack trace to the events of the other keywords
+        pub const STACK: u64 = 0x40000000;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_well_known_providers() {
+        assert_eq!(
+            KERNEL_PROCESS.guid,
+            GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+        );
+        assert_eq!(
+            DNS_CLIENT.guid,
+            GUID::from("1c95126e-7eea-49a9-a3fe-a378b03ddb4d")
+        );
+        assert_eq!(
+            GUID::from(THREAT_INTELLIGENCE),
+            GUID::from("f4e1897c-bb5d-5668-f1d8-040f4d8dd344")
+        );
+
+        let names: HashSet<_> = WELL_KNOWN_PROVIDERS.iter().map(|p| p.name).collect();
+        let guids: HashSet<_> = WELL_KNOWN_PROVIDERS.iter().map(|p| p.guid).collect();
+        assert_eq!(names.len(), WELL_KNOWN_PROVIDERS.len());
+        assert_eq!(guids.len(), WELL_KNOWN_PROVIDERS.len());
+
+        assert_eq!(by_name("microsoft-windows-tcpip"), Some(TCPIP));
+        assert_eq!(by_guid(POWERSHELL.guid), Some(POWERSHELL));
+        assert_eq!(by_name("Microsoft-Windows-Not-A-Provider"), None);
+    }
+}
@@ -1,10 +1,13 @@
 //! ETW information classes wrapper
 
+use bitflags::bitflags;
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
 use windows::Win32::System::Diagnostics::Etw::TRACE_PROFILE_INTERVAL;
 use zerocopy::AsBytes;
 
 use crate::{
-    native::{etw_types::TraceInformation, evntrace},
+    native::{etw_types::TraceInformation, evntrace, security, EvntraceNativeError},
     trace::TraceError,
 };
 
@@ -49,3 +52,194 @@ impl SessionlessInfo {
         Ok(max_pmc)
     }
 }
+
+bitflags! {
+    /// Access rights on an ETW provider or session
+    ///
+    /// See the `WMIGUID_*` and `TRACELOG_*` constants in evntrace.h
+    pub struct EventAccessRights: u32 {
+        const QUERY =                Etw::WMIGUID_QUERY;
+        const SET =                  Etw::WMIGUID_SET;
+        const NOTIFICATION =         Etw::WMIGUID_NOTIFICATION;
+        const READ_DESCRIPTION =     Etw::WMIGUID_READ_DESCRIPTION;
+        const EXECUTE =              Etw::WMIGUID_EXECUTE;
+        const CREATE_REALTIME =      Etw::TRACELOG_CREATE_REALTIME;
+        const CREATE_ONDISK =        Etw::TRACELOG_CREATE_ONDISK;
+        const GUID_ENABLE =          Etw::TRACELOG_GUID_ENABLE;
+        const ACCESS_KERNEL_LOGGER = Etw::TRACELOG_ACCESS_KERNEL_LOGGER;
+        /// Also known as `TRACELOG_CREATE_INPROC`
+        const LOG_EVENT =            Etw::TRACELOG_LOG_EVENT;
+        const ACCESS_REALTIME =      Etw::TRACELOG_ACCESS_REALTIME;
+        const REGISTER_GUIDS =       Etw::TRACELOG_REGISTER_GUIDS;
+        const JOIN_GROUP =           Etw::TRACELOG_JOIN_GROUP;
+    }
+}
+
+/// An entry of the security descriptor of a provider or session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessEntry {
+    /// Whether these rights are granted (or denied)
+    pub allowed: bool,
+    pub rights: EventAccessRights,
+    /// The account, as a string SID (e.g. `S-1-5-18`)
+    pub sid: String,
+    /// The account, as `DOMAIN\name` (e.g. `NT AUTHORITY\SYSTEM`), in case the SID could be resolved
+    pub account: Option<String>,
+}
+
+impl AccessEntry {
+    fn display_name(&self) -> &str {
+        self.account.as_deref().unwrap_or(&self.sid)
+    }
+}
+
+/// Who can enable, log to, or consume an ETW provider or session
+///
+/// This is useful to understand why enabling a provider fails with `ERROR_ACCESS_DENIED`.
+/// Some providers (e.g. `Microsoft-Windows-Threat-Intelligence`) can only be enabled by protected processes, regardless of the privileges of the caller.
+///
+/// ```no_run
+/// # use ferrisetw::query::EventAccess;
+/// # use ferrisetw::GUID;
+/// let threat_intelligence = GUID::from("f4e1897c-bb5d-5668-f1d8-040f4d8dd344");
+/// let access = EventAccess::query(threat_intelligence).unwrap();
+/// for entry in access.who_can_enable() {
+///     println!("{:?} can enable this provider", entry.account);
+/// }
+/// println!("{}", access);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventAccess {
+    guid: GUID,
+    /// `None` in case there is no DACL, i.e. everyone has full access
+    entries: Option<Vec<AccessEntry>>,
+}
+
+impl EventAccess {
+    /// Read the security descriptor of a provider (or of a session, given its GUID), with `EventAccessQuery`
+    ///
+    /// Providers without a specific security descriptor use the default one, i.e. the one of `EventTraceGuid` (`68fdd900-4a3e-11d1-84f4-0000f80464e3`).
+    pub fn query(guid: GUID) -> TraceResult<Self> {
+        let entries = security::query_access(guid).map_err(EvntraceNativeError::IoError)?;
+        Ok(Self {
+            guid,
+            entries: entries.map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|ace| AccessEntry {
+                        allowed: ace.allowed,
+                        rights: EventAccessRights::from_bits_truncate(ace.mask),
+                        sid: ace.sid,
+                        account: ace.account,
+                    })
+                    .collect()
+            }),
+        })
+    }
+
+    pub fn guid(&self) -> GUID {
+        self.guid
+    }
+
+    /// Whether there is no DACL at all, which grants every right to everyone
+    pub fn is_unrestricted(&self) -> bool {
+        self.entries.is_none()
+    }
+
+    /// The entries of the DACL, in order
+    pub fn entries(&self) -> &[AccessEntry] {
+        self.entries.as_deref().unwrap_or(&[])
+    }
+
+    /// The entries that grant every right of `rights`, unless another entry denies one of them to the same account
+    pub fn who_can(&self, rights: EventAccessRights) -> Vec<&AccessEntry> {
+        let entries = self.entries();
+        entries
+            .iter()
+            .filter(|entry| entry.allowed && entry.rights.contains(rights))
+            .filter(|entry| {
+                !entries.iter().any(|denied| {
+                    !denied.allowed && denied.sid == entry.sid && denied.rights.intersects(rights)
+                })
+            })
+            .collect()
+    }
+
+    /// The accounts that can enable the provider (i.e. attach it to a session)
+    pub fn who_can_enable(&self) -> Vec<&AccessEntry> {
+        self.who_can(EventAccessRights::GUID_ENABLE)
+    }
+
+    /// The accounts that can log events for this provider
+    pub fn who_can_log(&self) -> Vec<&AccessEntry> {
+        self.who_can(EventAccessRights::LOG_EVENT)
+    }
+
+    /// The accounts that can consume the events of the session in real time
+    pub fn who_can_consume(&self) -> Vec<&AccessEntry> {
+        self.who_can(EventAccessRights::ACCESS_REALTIME)
+    }
+}
+
+impl std::fmt::Display for EventAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Security descriptor of {:?}", self.guid)?;
+        if self.is_unrestricted() {
+            return writeln!(f, "  (no DACL: full access to everyone)");
+        }
+        for entry in self.entries() {
+            writeln!(
+                f,
+                "  {} {}: {:?}",
+                if entry.allowed { "allow" } else { "deny " },
+                entry.display_name(),
+                entry.rights
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(allowed: bool, rights: EventAccessRights, sid: &str) -> AccessEntry {
+        AccessEntry {
+            allowed,
+            rights,
+            sid: sid.to_string(),
+            account: None,
+        }
+    }
+
+    #[test]
+    fn test_who_can() {
+        let access = EventAccess {
+            guid: GUID::zeroed(),
+            entries: Some(vec![
+                entry(true, EventAccessRights::all(), "S-1-5-18"),
+                entry(
+                    true,
+                    EventAccessRights::GUID_ENABLE | EventAccessRights::ACCESS_REALTIME,
+                    "S-1-5-32-559",
+                ),
+                entry(true, EventAccessRights::LOG_EVENT, "S-1-1-0"),
+                entry(false, EventAccessRights::GUID_ENABLE, "S-1-5-32-559"),
+            ]),
+        };
+
+        let sids = |entries: Vec<&AccessEntry>| {
+            entries
+                .iter()
+                .map(|entry| entry.sid.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sids(access.who_can_enable()), ["S-1-5-18"]);
+        assert_eq!(sids(access.who_can_log()), ["S-1-5-18", "S-1-1-0"]);
+        assert_eq!(sids(access.who_can_consume()), ["S-1-5-18", "S-1-5-32-559"]);
+        assert!(access
+            .to_string()
+            .contains("deny  S-1-5-32-559: GUID_ENABLE"));
+    }
+}
@@ -0,0 +1,464 @@
+//! Rewrite ETL files (or real-time sessions) into a new ETL file
+//!
+//! A [`Relogger`] reads events from one or more inputs, hands every event to a callback, and writes the events it keeps into a new ETL file.
+//! The callback can drop events (e.g. to trim a huge capture to a few providers), modify them (e.g. to scrub personal data before sharing a file),
+//! or inject additional events (see [`Relogger::on_start`] and [`RelogEvent::inject`]).
+//!
+//! This wraps the [`ITraceRelogger`](https://learn.microsoft.com/en-us/windows/win32/api/relogger/nn-relogger-itracerelogger) COM API.
+//!
+//! ```no_run
+//! # use ferrisetw::relogger::{RelogAction, Relogger};
+//! # use windows::core::GUID;
+//! let noisy_provider = GUID::from("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716");
+//! let stats = Relogger::new("trimmed.etl")
+//!     .add_log_file("huge.etl")
+//!     .on_event(move |event, _locator| {
+//!         if event.record().provider_id() == noisy_provider {
+//!             RelogAction::Drop
+//!         } else {
+//!             RelogAction::Keep
+//!         }
+//!     })
+//!     .run()
+//!     .unwrap();
+//! println!("{} events kept out of {}", stats.kept, stats.events);
+//! ```
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use windows::core::{implement, BSTR, GUID};
+use windows::Win32::Foundation::{BOOLEAN, E_POINTER};
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_INPROC_SERVER, COINIT_MULTITHREADED,
+};
+use windows::Win32::System::Diagnostics::Etw::{
+    CLSID_TraceRelogger, ITraceEvent, ITraceEventCallback, ITraceEventCallback_Impl,
+    ITraceRelogger, EVENT_DESCRIPTOR, RELOGSTREAM_HANDLE,
+};
+
+use crate::native::etw_types::event_record::EventRecord;
+use crate::schema_locator::SchemaLocator;
+use crate::trace::callback_data::panic_message;
+
+/// Relogger module errors
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RelogError {
+    /// Neither a log file nor a real-time session has been added
+    NoInput,
+    /// A callback panicked. Relogging has been cancelled.
+    CallbackPanicked(String),
+    /// Represents an HRESULT common error
+    ComError(windows::core::Error),
+}
+
+impl From<windows::core::Error> for RelogError {
+    fn from(err: windows::core::Error) -> Self {
+        RelogError::ComError(err)
+    }
+}
+
+type RelogResult<T> = Result<T, RelogError>;
+
+/// What to do with an event, once the callback has seen it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RelogAction {
+    /// Write the event (as possibly modified by the callback) to the output file
+    #[default]
+    Keep,
+    /// Do not write the event
+    Drop,
+}
+
+/// Counters of a [`Relogger::run`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelogStats {
+    /// How many events have been read from the inputs
+    pub events: usize,
+    /// How many of them have been written
+    pub kept: usize,
+    /// How many of them have been dropped
+    pub dropped: usize,
+    /// How many additional events the callback has injected
+    pub injected: usize,
+}
+
+type RelogCallback = Box<dyn FnMut(&mut RelogEvent, &SchemaLocator) -> RelogAction + Send>;
+type StartCallback = Box<dyn FnOnce(&mut RelogStart) -> RelogResult<()> + Send>;
+
+/// An event being relogged
+///
+/// Changes made to this event (e.g. with [`Self::set_payload`]) are written to the output file, in case the callback returns [`RelogAction::Keep`].
+pub struct RelogEvent<'a> {
+    event: ITraceEvent,
+    relogger: &'a ITraceRelogger,
+    injected: usize,
+}
+
+impl<'a> RelogEvent<'a> {
+    /// The current content of this event
+    pub fn record(&self) -> &EventRecord {
+        unsafe {
+            // Safety: the record is owned by `self.event`, that outlives the returned reference
+            let record = self
+                .event
+                .GetEventRecord()
+                .expect("an ITraceEvent always has an event record");
+            EventRecord::from_ptr(record).expect("an ITraceEvent always has an event record")
+        }
+    }
+
+    /// Replace the user data of this event
+    ///
+    /// Note that the new payload must still match the schema of the event for consumers to be able to decode it.
+    pub fn set_payload(&mut self, payload: &[u8]) -> RelogResult<()> {
+        unsafe { self.event.SetPayload(payload) }?;
+        Ok(())
+    }
+
+    pub fn set_process_id(&mut self, process_id: u32) -> RelogResult<()> {
+        unsafe { self.event.SetProcessId(process_id) }?;
+        Ok(())
+    }
+
+    pub fn set_thread_id(&mut self, thread_id: u32) -> RelogResult<()> {
+        unsafe { self.event.SetThreadId(thread_id) }?;
+        Ok(())
+    }
+
+    pub fn set_processor_index(&mut self, processor_index: u32) -> RelogResult<()> {
+        unsafe { self.event.SetProcessorIndex(processor_index) }?;
+        Ok(())
+    }
+
+    /// Set the timestamp of this event, in the clock of its input (see [`EventRecord::raw_timestamp`])
+    pub fn set_timestamp(&mut self, timestamp: i64) -> RelogResult<()> {
+        unsafe { self.event.SetTimeStamp(&timestamp) }?;
+        Ok(())
+    }
+
+    pub fn set_provider_id(&mut self, provider_id: GUID) -> RelogResult<()> {
+        unsafe { self.event.SetProviderId(&provider_id) }?;
+        Ok(())
+    }
+
+    pub fn set_activity_id(&mut self, activity_id: GUID) -> RelogResult<()> {
+        unsafe { self.event.SetActivityId(&activity_id) }?;
+        Ok(())
+    }
+
+    /// Set the ID, version, level, opcode, task and keyword of this event
+    pub fn set_event_descriptor(&mut self, descriptor: &EVENT_DESCRIPTOR) -> RelogResult<()> {
+        unsafe { self.event.SetEventDescriptor(descriptor) }?;
+        Ok(())
+    }
+
+    /// A copy of this event, that can be modified then [injected](Self::inject)
+    pub fn duplicate(&self) -> RelogResult<RelogEvent<'a>> {
+        Ok(RelogEvent {
+            event: unsafe { self.event.Clone() }?,
+            relogger: self.relogger,
+            injected: 0,
+        })
+    }
+
+    /// Write an additional event to the output file, right away (i.e. before this event, in case it is kept)
+    pub fn inject(&mut self, event: &RelogEvent) -> RelogResult<()> {
+        unsafe { self.relogger.Inject(&event.event) }?;
+        self.injected += 1;
+        Ok(())
+    }
+}
+
+/// The beginning of the output file, see [`Relogger::on_start`]
+pub struct RelogStart<'a> {
+    relogger: &'a ITraceRelogger,
+    /// The stream of the first input
+    stream: RELOGSTREAM_HANDLE,
+    header_timestamp: Option<i64>,
+    injected: usize,
+}
+
+impl<'a> RelogStart<'a> {
+    /// The timestamp of the header of the first input, i.e. roughly when it started, in its clock (see [`EventRecord::raw_timestamp`])
+    pub fn header_timestamp(&self) -> Option<i64> {
+        self.header_timestamp
+    }
+
+    /// A blank event, in the format of the first input, that can be filled then [injected](Self::inject)
+    pub fn new_event(&self) -> RelogResult<RelogEvent<'a>> {
+        Ok(RelogEvent {
+            event: unsafe { self.relogger.CreateEventInstance(self.stream, 0) }?,
+            relogger: self.relogger,
+            injected: 0,
+        })
+    }
+
+    /// Write an event to the output file, before any event of the inputs
+    pub fn inject(&mut self, event: &RelogEvent) -> RelogResult<()> {
+        unsafe { self.relogger.Inject(&event.event) }?;
+        self.injected += 1;
+        Ok(())
+    }
+}
+
+struct Shared {
+    callback: RelogCallback,
+    on_start: Option<StartCallback>,
+    first_stream: RELOGSTREAM_HANDLE,
+    schema_locator: SchemaLocator,
+    stats: RelogStats,
+    /// Why relogging has been cancelled, if it has
+    failure: Option<RelogError>,
+}
+
+#[implement(ITraceEventCallback)]
+struct EventCallback {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl ITraceEventCallback_Impl for EventCallback {
+    fn OnBeginProcessTrace(
+        &self,
+        header_event: Option<&ITraceEvent>,
+        relogger: Option<&ITraceRelogger>,
+    ) -> windows::core::Result<()> {
+        let relogger = relogger.ok_or_else(|| windows::core::Error::from(E_POINTER))?;
+        let mut shared = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        let on_start = match shared.on_start.take() {
+            None => return Ok(()),
+            Some(on_start) => on_start,
+        };
+        let header_timestamp = header_event
+            .and_then(|header| unsafe { header.GetEventRecord() }.ok())
+            .and_then(|record| unsafe { EventRecord::from_ptr(record) })
+            .map(EventRecord::raw_timestamp);
+
+        let mut start = RelogStart {
+            relogger,
+            stream: shared.first_stream,
+            header_timestamp,
+            injected: 0,
+        };
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| on_start(&mut start)));
+        shared.stats.injected += start.injected;
+
+        let failure = match result {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(err)) => err,
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                log::error!("Relogger callback panicked: {}", message);
+                RelogError::CallbackPanicked(message)
+            }
+        };
+        shared.failure = Some(failure);
+        unsafe { relogger.Cancel() }?;
+        Ok(())
+    }
+
+    fn OnFinalizeProcessTrace(
+        &self,
+        _relogger: Option<&ITraceRelogger>,
+    ) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnEvent(
+        &self,
+        event: Option<&ITraceEvent>,
+        relogger: Option<&ITraceRelogger>,
+    ) -> windows::core::Result<()> {
+        let (event, relogger) = match (event, relogger) {
+            (Some(event), Some(relogger)) => (event, relogger),
+            _ => return Err(E_POINTER.into()),
+        };
+        let mut shared = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        let shared = &mut *shared;
+        if shared.failure.is_some() {
+            // Relogging is being cancelled
+            return Ok(());
+        }
+        shared.stats.events += 1;
+
+        let mut relog_event = RelogEvent {
+            event: event.clone(),
+            relogger,
+            injected: 0,
+        };
+        let callback = &mut shared.callback;
+        let schema_locator = &shared.schema_locator;
+        let action = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            callback(&mut relog_event, schema_locator)
+        }));
+        shared.stats.injected += relog_event.injected;
+
+        match action {
+            Ok(RelogAction::Keep) => {
+                unsafe { relogger.Inject(&relog_event.event) }?;
+                shared.stats.kept += 1;
+            }
+            Ok(RelogAction::Drop) => shared.stats.dropped += 1,
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                log::error!("Relogger callback panicked: {}", message);
+                shared.failure = Some(RelogError::CallbackPanicked(message));
+                unsafe { relogger.Cancel() }?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads events from ETL files and real-time sessions, and writes them into a new ETL file
+///
+/// See [the module-level documentation](self).
+pub struct Relogger {
+    output: PathBuf,
+    log_files: Vec<PathBuf>,
+    sessions: Vec<String>,
+    compress: bool,
+    callback: Option<RelogCallback>,
+    on_start: Option<StartCallback>,
+}
+
+impl Relogger {
+    /// Write the relogged events into `output`, that is overwritten in case it already exists
+    pub fn new<P: Into<PathBuf>>(output: P) -> Self {
+        Self {
+            output: output.into(),
+            log_files: Vec::new(),
+            sessions: Vec::new(),
+            compress: false,
+            callback: None,
+            on_start: None,
+        }
+    }
+
+    /// Read the events of an ETL file
+    pub fn add_log_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.log_files.push(path.into());
+        self
+    }
+
+    /// Read the events of a running real-time session
+    ///
+    /// Relogging then only ends when the session is stopped.
+    pub fn add_realtime_session<S: Into<String>>(mut self, name: S) -> Self {
+        self.sessions.push(name.into());
+        self
+    }
+
+    /// Compress the output file (Windows 8 and later)
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Set the closure that decides what happens to every event
+    ///
+    /// By default, every event is kept, which merges the inputs into a single file.
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&mut RelogEvent, &SchemaLocator) -> RelogAction + Send + 'static,
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Set a closure that is run once, before the events of the inputs are relogged
+    ///
+    /// It can create and inject events of its own (see [`RelogStart::new_event`]). Returning an error cancels relogging, and makes [`Self::run`] return it.
+    pub fn on_start<F>(mut self, callback: F) -> Self
+    where
+        F: FnOnce(&mut RelogStart) -> RelogResult<()> + Send + 'static,
+    {
+        self.on_start = Some(Box::new(callback));
+        self
+    }
+
+    /// Relog every event of the inputs. This is blocking, and returns once every input has been read.
+    pub fn run(self) -> RelogResult<RelogStats> {
+        if self.log_files.is_empty() && self.sessions.is_empty() {
+            return Err(RelogError::NoInput);
+        }
+
+        // This fails in case COM has already been initialized (differently) on this thread, which is fine
+        let com_initialized = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.is_ok();
+        let result = self.relog();
+        if com_initialized {
+            unsafe { CoUninitialize() };
+        }
+        result
+    }
+
+    fn relog(self) -> RelogResult<RelogStats> {
+        let relogger: ITraceRelogger =
+            unsafe { CoCreateInstance(&CLSID_TraceRelogger, None, CLSCTX_INPROC_SERVER) }?;
+
+        let mut streams = Vec::new();
+        for path in &self.log_files {
+            let path = BSTR::from(path.to_string_lossy().as_ref());
+            streams.push(unsafe { relogger.AddLogfileTraceStream(&path, std::ptr::null()) }?);
+        }
+        for name in &self.sessions {
+            let name = BSTR::from(name.as_str());
+            streams.push(unsafe { relogger.AddRealtimeTraceStream(&name, std::ptr::null()) }?);
+        }
+        let output = BSTR::from(self.output.to_string_lossy().as_ref());
+        unsafe { relogger.SetOutputFilename(&output) }?;
+        if self.compress {
+            unsafe { relogger.SetCompressionMode(BOOLEAN(1)) }?;
+        }
+
+        let shared = Arc::new(Mutex::new(Shared {
+            callback: self
+                .callback
+                .unwrap_or_else(|| Box::new(|_, _| RelogAction::Keep)),
+            on_start: self.on_start,
+            first_stream: streams[0],
+            schema_locator: SchemaLocator::new(),
+            stats: RelogStats::default(),
+            failure: None,
+        }));
+        let callback: ITraceEventCallback = EventCallback {
+            shared: Arc::clone(&shared),
+        }
+        .into();
+        unsafe { relogger.RegisterCallback(&callback) }?;
+
+        let processed = unsafe { relogger.ProcessTrace() };
+
+        let mut shared = shared.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(failure) = shared.failure.take() {
+            return Err(failure);
+        }
+        processed?;
+        Ok(shared.stats)
+    }
+}
+
+impl std::fmt::Debug for Relogger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Relogger")
+            .field("output", &self.output)
+            .field("log_files", &self.log_files)
+            .field("sessions", &self.sessions)
+            .field("compress", &self.compress)
+            .field("custom_callback", &self.callback.is_some())
+            .field("on_start", &self.on_start.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_relog_without_input() {
+        let result = Relogger::new("ferrisetw-relog-test.etl").run();
+        assert!(matches!(result, Err(RelogError::NoInput)));
+    }
+}
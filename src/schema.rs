@@ -1,9 +1,21 @@
 //! ETW Event Schema and handler
 //!
 //! This module contains the means needed to interact with the Schema of an ETW event
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use windows::core::GUID;
+
+use crate::custody::StableHasher;
+use crate::native::etw_types::event_record::EventRecord;
 use crate::native::etw_types::DecodingSource;
-use crate::native::tdh::TraceEventInfo;
-use crate::native::tdh_types::{Property, PropertyError};
+use crate::native::tdh::{self, TdhNativeResult, TraceEventInfo};
+use crate::native::tdh_types::{
+    EventMap, Property, PropertyCount, PropertyError, PropertyFlags, PropertyInfo, PropertyLength,
+    TdhInType, TdhOutType,
+};
+use crate::parser;
 use once_cell::sync::OnceCell;
 
 /// A schema suitable for parsing a given kind of event.
@@ -11,17 +23,152 @@ use once_cell::sync::OnceCell;
 /// It is usually retrieved from [`crate::schema_locator::SchemaLocator::event_schema`].
 ///
 /// This structure is basically a wrapper over a [TraceEventInfo](https://docs.microsoft.com/en-us/windows/win32/api/tdh/ns-tdh-trace_event_info),
-/// with a few info parsed (and cached) out of it
+/// with a few info parsed (and cached) out of it.<br/>
+/// It can also be built from a [`StaticSchema`], for events whose layout is known in advance.
 pub struct Schema {
-    te_info: TraceEventInfo,
+    source: SchemaSource,
     cached_properties: OnceCell<Result<Vec<Property>, PropertyError>>,
+    cached_fingerprint: OnceCell<u64>,
+    /// For 32-bit and 64-bit events
+    cached_layouts: [OnceCell<Vec<Range<usize>>>; 2],
+    cached_maps: Mutex<HashMap<String, Arc<EventMap>>>,
+}
+
+enum SchemaSource {
+    Tdh(TraceEventInfo),
+    Static {
+        provider: GUID,
+        id: u16,
+        version: u8,
+        schema: StaticSchema,
+    },
+}
+
+/// The description of an event, used to parse it without querying TDH
+///
+/// See [`register_static_schema`](crate::schema_locator::register_static_schema).
+/// Properties must be listed in the order they appear in the event, their offsets are computed from the sizes of the properties that precede them.
+#[derive(Debug, Clone)]
+pub struct StaticSchema {
+    provider_name: String,
+    task_name: String,
+    opcode_name: String,
+    decoding_source: DecodingSource,
+    properties: Vec<Property>,
+}
+
+impl StaticSchema {
+    pub fn new(provider_name: &str) -> Self {
+        Self {
+            provider_name: provider_name.to_string(),
+            task_name: String::new(),
+            opcode_name: String::new(),
+            decoding_source: DecodingSource::DecodingSourceXMLFile,
+            properties: Vec::new(),
+        }
+    }
+
+    pub fn task_name(mut self, task_name: &str) -> Self {
+        self.task_name = task_name.to_string();
+        self
+    }
+
+    pub fn opcode_name(mut self, opcode_name: &str) -> Self {
+        self.opcode_name = opcode_name.to_string();
+        self
+    }
+
+    /// The decoding source reported by [`Schema::decoding_source`]. This defaults to `DecodingSourceXMLFile`
+    pub fn decoding_source(mut self, decoding_source: DecodingSource) -> Self {
+        self.decoding_source = decoding_source;
+        self
+    }
+
+    /// Add a property
+    ///
+    /// `length` is its size in bytes. It should be `0` for properties whose size is determined when parsing them (e.g. null-terminated strings, pointers, SIDs).
+    pub fn property(
+        mut self,
+        name: &str,
+        in_type: TdhInType,
+        out_type: TdhOutType,
+        length: u16,
+    ) -> Self {
+        self.properties.push(Property {
+            name: name.to_string(),
+            flags: PropertyFlags::empty(),
+            info: PropertyInfo::Value {
+                in_type,
+                out_type,
+                length: PropertyLength::Length(length),
+            },
+            map_name: None,
+        });
+        self
+    }
+
+    /// Add an array property, made of `count` items of `length` bytes each
+    pub fn array(
+        mut self,
+        name: &str,
+        in_type: TdhInType,
+        out_type: TdhOutType,
+        length: u16,
+        count: u16,
+    ) -> Self {
+        self.properties.push(Property {
+            name: name.to_string(),
+            flags: PropertyFlags::empty(),
+            info: PropertyInfo::Array {
+                in_type,
+                out_type,
+                length: PropertyLength::Length(length),
+                count: PropertyCount::Count(count),
+            },
+            map_name: None,
+        });
+        self
+    }
 }
 
 impl Schema {
     pub(crate) fn new(te_info: TraceEventInfo) -> Self {
+        Self::with_source(SchemaSource::Tdh(te_info))
+    }
+
+    pub(crate) fn from_static(provider: GUID, id: u16, version: u8, schema: StaticSchema) -> Self {
+        Self::with_source(SchemaSource::Static {
+            provider,
+            id,
+            version,
+            schema,
+        })
+    }
+
+    fn with_source(source: SchemaSource) -> Self {
         Schema {
-            te_info,
+            source,
             cached_properties: OnceCell::new(),
+            cached_fingerprint: OnceCell::new(),
+            cached_layouts: Default::default(),
+            cached_maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The provider GUID, event ID and version this schema describes
+    fn identity(&self) -> (GUID, u16, u8) {
+        match &self.source {
+            SchemaSource::Tdh(te_info) => (
+                te_info.provider_guid(),
+                te_info.event_id(),
+                te_info.event_version(),
+            ),
+            SchemaSource::Static {
+                provider,
+                id,
+                version,
+                ..
+            } => (*provider, *id, *version),
         }
     }
 
@@ -41,7 +188,10 @@ impl Schema {
     /// };
     /// ```
     pub fn decoding_source(&self) -> DecodingSource {
-        self.te_info.decoding_source()
+        match &self.source {
+            SchemaSource::Tdh(te_info) => te_info.decoding_source(),
+            SchemaSource::Static { schema, .. } => schema.decoding_source,
+        }
     }
 
     /// Use the `provider_name` function to obtain the Provider name from the `TRACE_EVENT_INFO`
@@ -57,7 +207,10 @@ impl Schema {
     /// ```
     /// [TraceEventInfo]: crate::native::tdh::TraceEventInfo
     pub fn provider_name(&self) -> String {
-        self.te_info.provider_name()
+        match &self.source {
+            SchemaSource::Tdh(te_info) => te_info.provider_name(),
+            SchemaSource::Static { schema, .. } => schema.provider_name.clone(),
+        }
     }
 
     /// Use the `task_name` function to obtain the Task name from the `TRACE_EVENT_INFO`
@@ -74,7 +227,10 @@ impl Schema {
     /// ```
     /// [TraceEventInfo]: crate::native::tdh::TraceEventInfo
     pub fn task_name(&self) -> String {
-        self.te_info.task_name()
+        match &self.source {
+            SchemaSource::Tdh(te_info) => te_info.task_name(),
+            SchemaSource::Static { schema, .. } => schema.task_name.clone(),
+        }
     }
 
     /// Use the `opcode_name` function to obtain the Opcode name from the `TRACE_EVENT_INFO`
@@ -91,7 +247,19 @@ impl Schema {
     /// ```
     /// [TraceEventInfo]: crate::native::tdh::TraceEventInfo
     pub fn opcode_name(&self) -> String {
-        self.te_info.opcode_name()
+        match &self.source {
+            SchemaSource::Tdh(te_info) => te_info.opcode_name(),
+            SchemaSource::Static { schema, .. } => schema.opcode_name.clone(),
+        }
+    }
+
+    /// The index of a property, to be used with [`Parser::try_parse_at`](crate::parser::Parser::try_parse_at)
+    ///
+    /// This is `None` in case the event has no property with this name.
+    pub fn property_index(&self, name: &str) -> Option<usize> {
+        self.properties()
+            .iter()
+            .position(|property| property.name == name)
     }
 
     /// Parses the list of properties of the wrapped `TRACE_EVENT_INFO`
@@ -108,9 +276,13 @@ impl Schema {
     }
 
     pub(crate) fn try_properties(&self) -> Result<&[Property], PropertyError> {
+        let te_info = match &self.source {
+            SchemaSource::Tdh(te_info) => te_info,
+            SchemaSource::Static { schema, .. } => return Ok(&schema.properties),
+        };
         let cache = self.cached_properties.get_or_init(|| {
             let mut cache = Vec::new();
-            for property in self.te_info.properties() {
+            for property in te_info.properties() {
                 cache.push(property?)
             }
             Ok(cache)
@@ -121,14 +293,169 @@ impl Schema {
             Ok(cache) => Ok(cache.as_slice()),
         }
     }
+
+    /// The ranges of the leading fixed-size properties, for events with pointers of this size
+    ///
+    /// This is computed on first call, and cached for later use
+    pub(crate) fn fixed_layout(&self, pointer_size: usize) -> &[Range<usize>] {
+        let cache = &self.cached_layouts[usize::from(pointer_size == 8)];
+        cache.get_or_init(|| parser::fixed_layout(self.properties(), pointer_size))
+    }
+
+    /// A hash of the layout of the properties (their names, types, lengths and counts, in order)
+    ///
+    /// This is stable across processes and machines, so that it can be stored, and compared to the fingerprint of the same event later (or elsewhere).
+    /// A different fingerprint for the same provider, event ID and version means that the shape of the event changed (e.g. after an OS update),
+    /// and that the code that parses it should be reviewed.
+    ///
+    /// The provider, event ID and version are not part of the fingerprint, neither are the names of the value maps.
+    pub fn fingerprint(&self) -> u64 {
+        *self.cached_fingerprint.get_or_init(|| {
+            let mut hasher = StableHasher::new();
+            match self.try_properties() {
+                Ok(properties) => {
+                    for property in properties {
+                        hash_property(&mut hasher, property);
+                    }
+                }
+                Err(err) => hasher.write(err.to_string().as_bytes()),
+            }
+            hasher.finish()
+        })
+    }
+
+    /// Retrieves the value map or bitmap named `map_name` (see [`Property::map_name`])
+    ///
+    /// This is queried on first call (using `record`, which must be an event this schema describes), and cached for later use
+    pub(crate) fn event_map(
+        &self,
+        record: &EventRecord,
+        map_name: &str,
+    ) -> TdhNativeResult<Arc<EventMap>> {
+        let mut maps = self.cached_maps.lock().unwrap();
+        match maps.get(map_name) {
+            Some(map) => Ok(Arc::clone(map)),
+            None => {
+                let map = Arc::new(tdh::event_map_information(record, map_name)?);
+                maps.insert(map_name.to_string(), Arc::clone(&map));
+                Ok(map)
+            }
+        }
+    }
+}
+
+fn hash_property(hasher: &mut StableHasher, property: &Property) {
+    // Names are nul-terminated, so that consecutive names cannot be confused
+    hasher.write(property.name.as_bytes());
+    hasher.write(&[0]);
+    hasher.write(&property.flags.bits().to_le_bytes());
+    match &property.info {
+        PropertyInfo::Value {
+            in_type,
+            out_type,
+            length,
+        } => {
+            hasher.write(&[0]);
+            hash_types(hasher, *in_type, *out_type, *length);
+        }
+        PropertyInfo::Array {
+            in_type,
+            out_type,
+            length,
+            count,
+        } => {
+            hasher.write(&[1]);
+            hash_types(hasher, *in_type, *out_type, *length);
+            let (tag, count) = match count {
+                PropertyCount::Count(count) => (0, count),
+                PropertyCount::Index(index) => (1, index),
+            };
+            hasher.write(&[tag]);
+            hasher.write(&count.to_le_bytes());
+        }
+        PropertyInfo::Unsupported { error } => {
+            hasher.write(&[2]);
+            hasher.write(error.to_string().as_bytes());
+        }
+    }
+}
+
+fn hash_types(
+    hasher: &mut StableHasher,
+    in_type: TdhInType,
+    out_type: TdhOutType,
+    length: PropertyLength,
+) {
+    hasher.write(&(in_type as u16).to_le_bytes());
+    hasher.write(&(out_type as u16).to_le_bytes());
+    let (tag, length) = match length {
+        PropertyLength::Length(length) => (0, length),
+        PropertyLength::Index(index) => (1, index),
+    };
+    hasher.write(&[tag]);
+    hasher.write(&length.to_le_bytes());
 }
 
 impl PartialEq for Schema {
     fn eq(&self, other: &Self) -> bool {
-        self.te_info.event_id() == other.te_info.event_id()
-            && self.te_info.provider_guid() == other.te_info.provider_guid()
-            && self.te_info.event_version() == other.te_info.event_version()
+        self.identity() == other.identity()
     }
 }
 
 impl Eq for Schema {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn schema(id: u16, static_schema: StaticSchema) -> Schema {
+        Schema::from_static(GUID::from_u128(0x787), id, 0, static_schema)
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let v1 = StaticSchema::new("Fingerprint")
+            .property(
+                "ProcessId",
+                TdhInType::InTypeUInt32,
+                TdhOutType::OutTypeUInt32,
+                4,
+            )
+            .property(
+                "ImageName",
+                TdhInType::InTypeUnicodeString,
+                TdhOutType::OutTypeString,
+                0,
+            );
+        let fingerprint = schema(1, v1.clone()).fingerprint();
+        // Only the layout matters
+        assert_eq!(
+            schema(2, v1.clone().task_name("Other")).fingerprint(),
+            fingerprint
+        );
+
+        let widened = StaticSchema::new("Fingerprint")
+            .property(
+                "ProcessId",
+                TdhInType::InTypeUInt64,
+                TdhOutType::OutTypeUInt64,
+                8,
+            )
+            .property(
+                "ImageName",
+                TdhInType::InTypeUnicodeString,
+                TdhOutType::OutTypeString,
+                0,
+            );
+        assert_ne!(schema(1, widened).fingerprint(), fingerprint);
+
+        let appended = v1.array(
+            "Flags",
+            TdhInType::InTypeUInt16,
+            TdhOutType::OutTypeUInt16,
+            2,
+            4,
+        );
+        assert_ne!(schema(1, appended).fingerprint(), fingerprint);
+    }
+}
@@ -1,17 +1,64 @@
 //! A way to cache and retrieve Schemas
 
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
+use once_cell::sync::Lazy;
 use windows::core::GUID;
 
 use crate::native::etw_types::event_record::EventRecord;
 use crate::native::tdh;
 use crate::native::tdh::TraceEventInfo;
-use crate::schema::Schema;
+use crate::schema::{Schema, StaticSchema};
+use crate::trace::callback_data::{ErrorCallback, EventError};
+use crate::utils::internal_span;
+
+/// Provider GUID, event ID and event version
+type StaticSchemaKey = (GUID, u16, u8);
+
+/// Schemas registered with [`register_static_schema`]
+static STATIC_SCHEMAS: Lazy<RwLock<HashMap<StaticSchemaKey, Arc<Schema>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Register the schema of an event, so that [`SchemaLocator::event_schema`] never queries TDH for it
+///
+/// Every event of this `provider` with this `id` and `version` will be parsed with `schema`, in every trace of this process.
+/// This is useful for events that are received at a high rate, and whose layout is known in advance.<br/>
+/// Note that classic (MOF) events usually all have an ID of `0`, and they can not be told apart this way.
+///
+/// This replaces any schema that was previously registered for this event.
+pub fn register_static_schema(provider: GUID, id: u16, version: u8, schema: StaticSchema) {
+    let schema = Arc::new(Schema::from_static(provider, id, version, schema));
+    STATIC_SCHEMAS
+        .write()
+        .unwrap()
+        .insert((provider, id, version), schema);
+}
+
+/// Remove a schema registered with [`register_static_schema`]
+///
+/// This returns `false` in case no schema was registered for this event.
+pub fn unregister_static_schema(provider: GUID, id: u16, version: u8) -> bool {
+    STATIC_SCHEMAS
+        .write()
+        .unwrap()
+        .remove(&(provider, id, version))
+        .is_some()
+}
+
+fn static_schema(event: &EventRecord) -> Option<Arc<Schema>> {
+    let schemas = STATIC_SCHEMAS.read().unwrap();
+    if schemas.is_empty() {
+        return None;
+    }
+    schemas
+        .get(&(event.provider_id(), event.event_id(), event.version()))
+        .map(Arc::clone)
+}
 
 /// Schema module errors
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SchemaError {
     /// Represents an internal [TdhNativeError]
     ///
@@ -87,12 +134,15 @@ impl SchemaKey {
 #[derive(Default)]
 pub struct SchemaLocator {
     schemas: Mutex<HashMap<SchemaKey, Arc<Schema>>>,
+    /// Notified of the internal errors of the trace this locator belongs to
+    error_callback: Option<ErrorCallback>,
 }
 
 impl std::fmt::Debug for SchemaLocator {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SchemaLocator")
             .field("len", &self.schemas.try_lock().map(|guard| guard.len()))
+            .field("error_callback", &self.error_callback.is_some())
             .finish()
     }
 }
@@ -101,11 +151,30 @@ impl SchemaLocator {
     pub(crate) fn new() -> Self {
         SchemaLocator {
             schemas: Mutex::new(HashMap::new()),
+            error_callback: None,
+        }
+    }
+
+    pub(crate) fn set_error_callback(&mut self, callback: ErrorCallback) {
+        self.error_callback = Some(callback);
+    }
+
+    /// Notify the error callback of the trace (if any)
+    pub(crate) fn report_error(&self, error: EventError) {
+        if let Some(callback) = &self.error_callback {
+            callback(&error);
         }
     }
 
+    /// The number of schemas currently cached
+    pub(crate) fn cached_schemas(&self) -> usize {
+        self.schemas.lock().map(|guard| guard.len()).unwrap_or(0)
+    }
+
     /// Retrieve the Schema of an ETW Event
     ///
+    /// Schemas registered with [`register_static_schema`] take precedence over the ones TDH would return.
+    ///
     /// # Arguments
     /// * `event` - The [EventRecord] that's passed to the callback
     ///
@@ -118,13 +187,34 @@ impl SchemaLocator {
     /// };
     /// ```
     pub fn event_schema(&self, event: &EventRecord) -> SchemaResult<Arc<Schema>> {
+        if let Some(schema) = static_schema(event) {
+            return Ok(schema);
+        }
+
         let key = SchemaKey::new(event);
 
         let mut schemas = self.schemas.lock().unwrap();
         match schemas.get(&key) {
             Some(s) => Ok(Arc::clone(s)),
             None => {
-                let tei = TraceEventInfo::build_from_event(event)?;
+                let _span = internal_span!(
+                    "schema_cache_miss",
+                    provider_id = ?event.provider_id(),
+                    event_id = event.event_id(),
+                    version = event.version()
+                );
+                let tei = TraceEventInfo::build_from_event(event).inspect_err(|err| {
+                    crate::self_telemetry::decode_error(
+                        event.provider_id(),
+                        event.event_id(),
+                        &err.to_string(),
+                    );
+                    self.report_error(EventError::Schema {
+                        provider_id: event.provider_id(),
+                        event_id: event.event_id(),
+                        message: err.to_string(),
+                    });
+                })?;
                 let new_schema = Arc::from(Schema::new(tei));
                 schemas.insert(key, Arc::clone(&new_schema));
                 Ok(new_schema)
@@ -132,3 +222,49 @@ impl SchemaLocator {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::native::{TdhInType, TdhOutType};
+    use crate::parser::Parser;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_static_schema() {
+        let provider = GUID::from_u128(0x6d1ea1a5_54a4_4d2b_9a4c_000000000768);
+        let mut data = 1234u32.to_ne_bytes().to_vec();
+        data.extend("abc\0".encode_utf16().flat_map(u16::to_ne_bytes));
+        let event = SyntheticEvent::new()
+            .with_provider(provider)
+            .with_user_data(&data);
+
+        register_static_schema(
+            provider,
+            0,
+            0,
+            StaticSchema::new("My-Provider")
+                .task_name("Task")
+                .property("Pid", TdhInType::InTypeUInt32, TdhOutType::OutTypeUInt32, 4)
+                .property(
+                    "Name",
+                    TdhInType::InTypeUnicodeString,
+                    TdhOutType::OutTypeString,
+                    0,
+                ),
+        );
+
+        let locator = SchemaLocator::new();
+        let schema = locator.event_schema(event.record()).unwrap();
+        assert_eq!(schema.provider_name(), "My-Provider");
+        assert_eq!(schema.task_name(), "Task");
+        assert_eq!(locator.cached_schemas(), 0);
+
+        let parser = Parser::create(event.record(), &schema);
+        assert_eq!(parser.try_parse::<u32>("Pid").unwrap(), 1234);
+        assert_eq!(parser.try_parse::<String>("Name").unwrap(), "abc");
+
+        assert!(unregister_static_schema(provider, 0, 0));
+        assert!(!unregister_static_schema(provider, 0, 0));
+    }
+}
@@ -0,0 +1,229 @@
+//! Diagnostics about ferrisetw itself, emitted as ETW events
+//!
+//! When enabled (see [`enable`]), ferrisetw registers its own [TraceLogging](https://learn.microsoft.com/en-us/windows/win32/tracelogging/trace-logging-about) provider,
+//! and writes events when something noteworthy happens:
+//! * `SessionStarted`: a real-time session has been started
+//! * `ProviderEnabled`: a provider has been enabled on a session
+//! * `EventsLost`: a stopped session has lost some events or buffers
+//! * `DecodeError`: the schema of an event could not be retrieved
+//!
+//! This way, a collector built on ferrisetw can be monitored with the same tooling as any other provider.<br/>
+//! This is disabled by default, and costs (almost) nothing as long as it is disabled, or no session listens to [`PROVIDER_GUID`].
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use crate::native::evntprov::{
+    event_provider_enabled, event_register, event_unregister, event_write_tracelogging,
+    RegistrationHandle,
+};
+use crate::native::EvntraceNativeError;
+
+/// The name of the ferrisetw provider
+pub const PROVIDER_NAME: &str = "Ferrisetw";
+/// The GUID of the ferrisetw provider
+pub const PROVIDER_GUID: GUID = GUID::from_u128(0x3c6f8f0e_5a9b_4d1e_9c2a_7f4b1e6d8a21);
+
+// See https://learn.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_descriptor
+const LEVEL_WARNING: u8 = 3;
+const LEVEL_INFORMATION: u8 = 4;
+/// TraceLogging events must use this channel to be decoded on every Windows version
+const CHANNEL_TRACELOGGING: u8 = 11;
+
+// TraceLogging input types (see TraceLoggingProvider.h)
+const IN_TYPE_UNICODE_STRING: u8 = 1;
+const IN_TYPE_UINT16: u8 = 6;
+const IN_TYPE_UINT32: u8 = 8;
+const IN_TYPE_GUID: u8 = 15;
+
+/// The handle of our provider, or 0 when it is not registered
+static REGISTRATION: Lazy<RwLock<RegistrationHandle>> = Lazy::new(|| RwLock::new(0));
+
+static PROVIDER_METADATA: Lazy<Vec<u8>> = Lazy::new(|| {
+    let mut metadata = vec![0, 0];
+    metadata.extend_from_slice(PROVIDER_NAME.as_bytes());
+    metadata.push(0);
+    let size = metadata.len() as u16;
+    metadata[..2].copy_from_slice(&size.to_le_bytes());
+    metadata
+});
+
+/// Register the ferrisetw provider, so that its events can be collected
+///
+/// Calling this when the provider is already registered has no effect.
+pub fn enable() -> Result<(), EvntraceNativeError> {
+    let mut registration = REGISTRATION.write().unwrap();
+    if *registration == 0 {
+        *registration = event_register(&PROVIDER_GUID)?;
+    }
+    Ok(())
+}
+
+/// Unregister the ferrisetw provider
+pub fn disable() {
+    let mut registration = REGISTRATION.write().unwrap();
+    if *registration != 0 {
+        event_unregister(*registration);
+        *registration = 0;
+    }
+}
+
+/// Whether the ferrisetw provider is registered
+pub fn is_enabled() -> bool {
+    *REGISTRATION.read().unwrap() != 0
+}
+
+/// A TraceLogging event, along with its metadata
+struct TlgEvent {
+    name: &'static str,
+    metadata: Vec<u8>,
+    fields: Vec<Vec<u8>>,
+}
+
+impl TlgEvent {
+    fn new(name: &'static str) -> Self {
+        // Size (filled in later), then an empty tag byte, then the event name
+        let mut metadata = vec![0, 0, 0];
+        metadata.extend_from_slice(name.as_bytes());
+        metadata.push(0);
+        Self {
+            name,
+            metadata,
+            fields: Vec::new(),
+        }
+    }
+
+    fn field(mut self, name: &str, in_type: u8, data: Vec<u8>) -> Self {
+        self.metadata.extend_from_slice(name.as_bytes());
+        self.metadata.push(0);
+        self.metadata.push(in_type);
+        self.fields.push(data);
+        self
+    }
+
+    fn string(self, name: &str, value: &str) -> Self {
+        let data = value
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        self.field(name, IN_TYPE_UNICODE_STRING, data)
+    }
+
+    fn u16(self, name: &str, value: u16) -> Self {
+        self.field(name, IN_TYPE_UINT16, value.to_le_bytes().to_vec())
+    }
+
+    fn u32(self, name: &str, value: u32) -> Self {
+        self.field(name, IN_TYPE_UINT32, value.to_le_bytes().to_vec())
+    }
+
+    fn guid(self, name: &str, value: GUID) -> Self {
+        let mut data = Vec::with_capacity(16);
+        data.extend_from_slice(&value.data1.to_le_bytes());
+        data.extend_from_slice(&value.data2.to_le_bytes());
+        data.extend_from_slice(&value.data3.to_le_bytes());
+        data.extend_from_slice(&value.data4);
+        self.field(name, IN_TYPE_GUID, data)
+    }
+
+    /// The metadata, prefixed with its own size
+    fn encoded_metadata(&self) -> Vec<u8> {
+        let mut metadata = self.metadata.clone();
+        let size = metadata.len() as u16;
+        metadata[..2].copy_from_slice(&size.to_le_bytes());
+        metadata
+    }
+}
+
+/// Run `build` and write the resulting event, unless nobody would receive it
+fn write_event<F: FnOnce() -> TlgEvent>(level: u8, build: F) {
+    let registration = REGISTRATION.read().unwrap();
+    if *registration == 0 || !event_provider_enabled(*registration, level, 0) {
+        return;
+    }
+
+    let event = build();
+    let descriptor = Etw::EVENT_DESCRIPTOR {
+        Channel: CHANNEL_TRACELOGGING,
+        Level: level,
+        ..Default::default()
+    };
+    let fields: Vec<&[u8]> = event.fields.iter().map(|f| f.as_slice()).collect();
+    if let Err(err) = event_write_tracelogging(
+        *registration,
+        &descriptor,
+        &PROVIDER_METADATA,
+        &event.encoded_metadata(),
+        &fields,
+    ) {
+        log::debug!(
+            "Unable to write self-telemetry event {}: {:?}",
+            event.name,
+            err
+        );
+    }
+}
+
+pub(crate) fn session_started(session: &str) {
+    write_event(LEVEL_INFORMATION, || {
+        TlgEvent::new("SessionStarted").string("Session", session)
+    });
+}
+
+pub(crate) fn provider_enabled(session: &str, provider: GUID) {
+    write_event(LEVEL_INFORMATION, || {
+        TlgEvent::new("ProviderEnabled")
+            .string("Session", session)
+            .guid("Provider", provider)
+    });
+}
+
+pub(crate) fn events_lost(session: &str, events_lost: u32, real_time_buffers_lost: u32) {
+    write_event(LEVEL_WARNING, || {
+        TlgEvent::new("EventsLost")
+            .string("Session", session)
+            .u32("EventsLost", events_lost)
+            .u32("RealTimeBuffersLost", real_time_buffers_lost)
+    });
+}
+
+pub(crate) fn decode_error(provider: GUID, event_id: u16, error: &str) {
+    write_event(LEVEL_WARNING, || {
+        TlgEvent::new("DecodeError")
+            .guid("Provider", provider)
+            .u16("EventId", event_id)
+            .string("Error", error)
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_metadata() {
+        let event = TlgEvent::new("Ev").u16("Id", 7).string("S", "a");
+
+        assert_eq!(
+            event.encoded_metadata(),
+            [
+                &[13, 0, 0][..],
+                b"Ev\0Id\0",
+                &[IN_TYPE_UINT16],
+                b"S\0",
+                &[IN_TYPE_UNICODE_STRING],
+            ]
+            .concat()
+        );
+        assert_eq!(event.fields, vec![vec![7, 0], vec![b'a', 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_provider_metadata() {
+        assert_eq!(PROVIDER_METADATA[..2], [12, 0]);
+        assert_eq!(&PROVIDER_METADATA[2..], b"Ferrisetw\0");
+    }
+}
@@ -26,6 +26,8 @@
 //!     }
 //! }
 //! ```
+//!
+//! Sensitive properties can be scrubbed before they are serialized, see [`Redactor`].
 #![cfg(feature = "serde")]
 
 use crate::native::etw_types::event_record::EventRecord;
@@ -33,11 +35,15 @@ use crate::native::tdh_types::{Property, PropertyInfo, TdhInType, TdhOutType};
 use crate::native::time::{FileTime, SystemTime};
 use crate::parser::Parser;
 use crate::schema::Schema;
+use crate::utils::internal_span;
 use crate::GUID;
 use serde::ser::{SerializeMap, SerializeStruct};
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use windows::Win32::System::Diagnostics::Etw::{EVENT_DESCRIPTOR, EVENT_HEADER};
 
+mod redact;
+pub use redact::{RedactFn, Redaction, Redactor};
+
 /// Serialization options for EventSerializer
 #[derive(Clone, Copy)]
 pub struct EventSerializerOptions {
@@ -68,6 +74,7 @@ pub struct EventSerializer<'a> {
     pub(crate) schema: &'a Schema,
     pub(crate) parser: Parser<'a, 'a>,
     pub(crate) options: EventSerializerOptions,
+    pub(crate) redactor: Option<&'a Redactor>,
 }
 
 impl<'a> EventSerializer<'a> {
@@ -82,8 +89,15 @@ impl<'a> EventSerializer<'a> {
             schema,
             parser: Parser::create(record, schema),
             options,
+            redactor: None,
         }
     }
+
+    /// Scrub the properties of the event with this redactor, before they are serialized
+    pub fn with_redactor(mut self, redactor: &'a Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
 }
 
 impl serde::ser::Serialize for EventSerializer<'_> {
@@ -91,6 +105,7 @@ impl serde::ser::Serialize for EventSerializer<'_> {
     where
         S: serde::ser::Serializer,
     {
+        let _span = internal_span!("serialize_event", event_id = self.record.event_id());
         let mut state = serializer.serialize_struct("Record", 4)?;
 
         if self.options.include_schema {
@@ -101,7 +116,7 @@ impl serde::ser::Serialize for EventSerializer<'_> {
         }
 
         if self.options.include_header {
-            let header = HeaderSer::new(&self.record.0.EventHeader);
+            let header = HeaderSer::new(&self.record.0.EventHeader, self.record.system_timestamp());
             state.serialize_field("Header", &header)?;
         } else {
             state.skip_field("Header")?;
@@ -116,7 +131,13 @@ impl serde::ser::Serialize for EventSerializer<'_> {
             state.skip_field("Extended")?;
         }
 
-        let event = EventSer::new(self.record, self.schema, &self.parser, &self.options);
+        let event = EventSer::new(
+            self.record,
+            self.schema,
+            &self.parser,
+            &self.options,
+            self.redactor,
+        );
         state.serialize_field("Event", &event)?;
 
         state.end()
@@ -163,11 +184,13 @@ impl serde::ser::Serialize for SchemaSer<'_> {
 
 struct HeaderSer<'a> {
     header: &'a EVENT_HEADER,
+    /// See [`EventRecord::system_timestamp`](crate::EventRecord::system_timestamp)
+    timestamp: i64,
 }
 
 impl<'a> HeaderSer<'a> {
-    fn new(header: &'a EVENT_HEADER) -> Self {
-        Self { header }
+    fn new(header: &'a EVENT_HEADER, timestamp: i64) -> Self {
+        Self { header, timestamp }
     }
 }
 
@@ -183,7 +206,7 @@ impl serde::ser::Serialize for HeaderSer<'_> {
         state.serialize_field("EventProperty", &self.header.Flags)?;
         state.serialize_field("ThreadId", &self.header.ThreadId)?;
         state.serialize_field("ProcessId", &self.header.ProcessId)?;
-        state.serialize_field("TimeStamp", &FileTime::from_quad(self.header.TimeStamp))?;
+        state.serialize_field("TimeStamp", &FileTime::from_quad(self.timestamp))?;
         state.serialize_field("ProviderId", &GUIDExt(self.header.ProviderId))?;
         state.serialize_field("ActivityId", &GUIDExt(self.header.ActivityId))?;
         let descriptor = DescriptorSer::new(&self.header.EventDescriptor);
@@ -224,6 +247,7 @@ struct EventSer<'a, 'b> {
     schema: &'a Schema,
     parser: &'a Parser<'b, 'b>,
     options: &'a EventSerializerOptions,
+    redactor: Option<&'a Redactor>,
 }
 
 impl<'a, 'b> EventSer<'a, 'b> {
@@ -232,12 +256,30 @@ impl<'a, 'b> EventSer<'a, 'b> {
         schema: &'a Schema,
         parser: &'a Parser<'b, 'b>,
         options: &'a EventSerializerOptions,
+        redactor: Option<&'a Redactor>,
     ) -> Self {
         Self {
             record,
             schema,
             parser,
             options,
+            redactor,
+        }
+    }
+
+    /// The redaction of a property, if any
+    fn redaction(&self, prop: &Property) -> Option<&'a Redaction> {
+        self.redactor.and_then(|redactor| redactor.rule(&prop.name))
+    }
+
+    /// Whether a property is still serialized once redacted
+    ///
+    /// Redactions other than [`Redaction::Drop`] only make sense for strings, properties of other types they match are dropped.
+    fn is_kept(&self, prop: &Property, handler: &PropHandler) -> bool {
+        match self.redaction(prop) {
+            None => true,
+            Some(Redaction::Drop) => false,
+            Some(_) => matches!(handler, PropHandler::String),
         }
     }
 }
@@ -259,8 +301,10 @@ impl serde::ser::Serialize for EventSer<'_, '_> {
         };
 
         for prop in props {
-            if prop.get_parser().is_some() {
-                len += 1;
+            if let Some(s) = prop.get_parser() {
+                if self.is_kept(prop, &s.0) {
+                    len += 1;
+                }
             } else if self.options.fail_unimplemented {
                 match prop.info {
                     PropertyInfo::Value {
@@ -282,14 +326,33 @@ impl serde::ser::Serialize for EventSer<'_, '_> {
                             prop.name, in_type, out_type, count
                         )));
                     }
+                    PropertyInfo::Unsupported { ref error } => {
+                        return Err(serde::ser::Error::custom(format!(
+                            "not implemented {}: {}",
+                            prop.name, error
+                        )));
+                    }
                 }
             }
         }
 
         let mut state = serializer.serialize_map(Some(len))?;
         for prop in props {
-            if let Some(s) = prop.get_parser() {
-                s.0.ser::<S>(&mut state, prop, self.parser, self.record)?;
+            let s = match prop.get_parser() {
+                Some(s) if self.is_kept(prop, &s.0) => s,
+                _ => continue,
+            };
+            match (self.redactor, self.redaction(prop)) {
+                (Some(redactor), Some(redaction)) => {
+                    let value = self
+                        .parser
+                        .try_parse::<String>(&prop.name)
+                        .map_err(serde::ser::Error::custom)?;
+                    // `is_kept` ruled out `Redaction::Drop`
+                    let value = redactor.redacted(redaction, &value).unwrap_or_default();
+                    state.serialize_entry(&prop.name, &value)?;
+                }
+                _ => s.0.ser::<S>(&mut state, prop, self.parser, self.record)?,
             }
         }
         state.end()
@@ -322,6 +385,7 @@ enum PropHandler {
     Guid,
     Binary,
     IpAddr,
+    SocketAddr,
     ArrayInt16,
     ArrayUInt16,
     ArrayInt32,
@@ -366,6 +430,7 @@ impl PropHandler {
             PropHandler::String => prop_ser_type!(String, map, prop, parser),
             PropHandler::Binary => prop_ser_type!(Vec<u8>, map, prop, parser),
             PropHandler::IpAddr => prop_ser_type!(IpAddr, map, prop, parser),
+            PropHandler::SocketAddr => prop_ser_type!(SocketAddr, map, prop, parser),
             PropHandler::FileTime => prop_ser_type!(FileTime, map, prop, parser),
             PropHandler::SystemTime => prop_ser_type!(SystemTime, map, prop, parser),
             PropHandler::ArrayInt16 => prop_ser_type!(&[i16], map, prop, parser),
@@ -408,37 +473,41 @@ impl PropSerable for PropertyInfo {
         match self {
             PropertyInfo::Value {
                 in_type, out_type, ..
-            } => {
-                match out_type {
-                    TdhOutType::OutTypeIpv4 => Some(PropSer(PropHandler::IpAddr)),
-                    TdhOutType::OutTypeIpv6 => Some(PropSer(PropHandler::IpAddr)),
-                    _ => match in_type {
-                        TdhInType::InTypeNull => Some(PropSer(PropHandler::Null)),
-                        TdhInType::InTypeUnicodeString => Some(PropSer(PropHandler::String)),
-                        TdhInType::InTypeAnsiString => Some(PropSer(PropHandler::String)),
-                        TdhInType::InTypeInt8 => Some(PropSer(PropHandler::Int8)),
-                        TdhInType::InTypeUInt8 => Some(PropSer(PropHandler::UInt8)),
-                        TdhInType::InTypeInt16 => Some(PropSer(PropHandler::Int16)),
-                        TdhInType::InTypeUInt16 => Some(PropSer(PropHandler::UInt16)),
-                        TdhInType::InTypeInt32 => Some(PropSer(PropHandler::Int32)),
-                        TdhInType::InTypeUInt32 => Some(PropSer(PropHandler::UInt32)),
-                        TdhInType::InTypeInt64 => Some(PropSer(PropHandler::Int64)),
-                        TdhInType::InTypeUInt64 => Some(PropSer(PropHandler::UInt64)),
-                        TdhInType::InTypeFloat => Some(PropSer(PropHandler::Float)),
-                        TdhInType::InTypeDouble => Some(PropSer(PropHandler::Double)),
-                        TdhInType::InTypeBoolean => Some(PropSer(PropHandler::Bool)),
-                        TdhInType::InTypeBinary => Some(PropSer(PropHandler::Binary)),
-                        TdhInType::InTypeGuid => Some(PropSer(PropHandler::Guid)),
-                        TdhInType::InTypePointer => Some(PropSer(PropHandler::Pointer)),
-                        TdhInType::InTypeFileTime => Some(PropSer(PropHandler::FileTime)),
-                        TdhInType::InTypeSystemTime => Some(PropSer(PropHandler::SystemTime)),
-                        TdhInType::InTypeSid => Some(PropSer(PropHandler::String)),
-                        TdhInType::InTypeHexInt32 => Some(PropSer(PropHandler::Int32)),
-                        TdhInType::InTypeHexInt64 => Some(PropSer(PropHandler::Int64)),
-                        TdhInType::InTypeCountedString => None, // TODO
-                    },
-                }
-            }
+            } => match out_type {
+                TdhOutType::OutTypeIpv4 => Some(PropSer(PropHandler::IpAddr)),
+                TdhOutType::OutTypeIpv6 => Some(PropSer(PropHandler::IpAddr)),
+                TdhOutType::OutTypeSocketAddress => Some(PropSer(PropHandler::SocketAddr)),
+                _ => match in_type {
+                    TdhInType::InTypeNull => Some(PropSer(PropHandler::Null)),
+                    TdhInType::InTypeUnicodeString => Some(PropSer(PropHandler::String)),
+                    TdhInType::InTypeAnsiString => Some(PropSer(PropHandler::String)),
+                    TdhInType::InTypeInt8 => Some(PropSer(PropHandler::Int8)),
+                    TdhInType::InTypeUInt8 => Some(PropSer(PropHandler::UInt8)),
+                    TdhInType::InTypeInt16 => Some(PropSer(PropHandler::Int16)),
+                    TdhInType::InTypeUInt16 => Some(PropSer(PropHandler::UInt16)),
+                    TdhInType::InTypeInt32 => Some(PropSer(PropHandler::Int32)),
+                    TdhInType::InTypeUInt32 => Some(PropSer(PropHandler::UInt32)),
+                    TdhInType::InTypeInt64 => Some(PropSer(PropHandler::Int64)),
+                    TdhInType::InTypeUInt64 => Some(PropSer(PropHandler::UInt64)),
+                    TdhInType::InTypeFloat => Some(PropSer(PropHandler::Float)),
+                    TdhInType::InTypeDouble => Some(PropSer(PropHandler::Double)),
+                    TdhInType::InTypeBoolean => Some(PropSer(PropHandler::Bool)),
+                    TdhInType::InTypeBinary => Some(PropSer(PropHandler::Binary)),
+                    TdhInType::InTypeGuid => Some(PropSer(PropHandler::Guid)),
+                    TdhInType::InTypePointer => Some(PropSer(PropHandler::Pointer)),
+                    TdhInType::InTypeFileTime => Some(PropSer(PropHandler::FileTime)),
+                    TdhInType::InTypeSystemTime => Some(PropSer(PropHandler::SystemTime)),
+                    TdhInType::InTypeSid => Some(PropSer(PropHandler::String)),
+                    TdhInType::InTypeHexInt32 => Some(PropSer(PropHandler::Int32)),
+                    TdhInType::InTypeHexInt64 => Some(PropSer(PropHandler::Int64)),
+                    TdhInType::InTypeCountedString
+                    | TdhInType::InTypeCountedAnsiString
+                    | TdhInType::InTypeReversedCountedString
+                    | TdhInType::InTypeReversedCountedAnsiString => {
+                        Some(PropSer(PropHandler::String))
+                    }
+                },
+            },
             PropertyInfo::Array { in_type, .. } => {
                 match in_type {
                     TdhInType::InTypeInt16 => Some(PropSer(PropHandler::ArrayInt16)),
@@ -451,6 +520,7 @@ impl PropSerable for PropertyInfo {
                     _ => None, // TODO
                 }
             }
+            PropertyInfo::Unsupported { .. } => None,
         }
     }
 }
@@ -460,3 +530,70 @@ impl PropSerable for Property {
         self.info.get_parser()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    /// Serializes a list of properties the same way `EventSer` does, without requiring a `Schema`
+    struct PropertiesSer<'a> {
+        properties: &'a [Property],
+        parser: &'a Parser<'a, 'a>,
+        record: &'a EventRecord,
+    }
+
+    impl serde::ser::Serialize for PropertiesSer<'_> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            let mut state = serializer.serialize_map(Some(self.properties.len()))?;
+            for prop in self.properties {
+                if let Some(s) = prop.get_parser() {
+                    s.0.ser::<S>(&mut state, prop, self.parser, self.record)?;
+                }
+            }
+            state.end()
+        }
+    }
+
+    fn serialize_pointers(event: &SyntheticEvent) -> serde_json::Value {
+        let properties = [
+            pointer_property("Pointer"),
+            pointer_array_property("Pointers", 2),
+        ];
+        let parser = Parser::from_properties(event.record(), &properties);
+        serde_json::to_value(PropertiesSer {
+            properties: &properties,
+            parser: &parser,
+            record: event.record(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_serialize_pointers_32_bit_header() {
+        let event = SyntheticEvent::new()
+            .with_32_bit_header()
+            .with_pointer(0x1000)
+            .with_pointer(0x2000)
+            .with_pointer(0x3000);
+
+        let json = serialize_pointers(&event);
+        assert_eq!(json["Pointer"], 0x1000);
+        assert_eq!(json["Pointers"], serde_json::json!([0x2000, 0x3000]));
+    }
+
+    #[test]
+    fn test_serialize_pointers_64_bit_header() {
+        let event = SyntheticEvent::new()
+            .with_pointer(0xffff_8000_0000_1000)
+            .with_pointer(0x2000)
+            .with_pointer(0x3000);
+
+        let json = serialize_pointers(&event);
+        assert_eq!(json["Pointer"], 0xffff_8000_0000_1000u64);
+        assert_eq!(json["Pointers"], serde_json::json!([0x2000, 0x3000]));
+    }
+}
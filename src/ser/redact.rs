@@ -0,0 +1,163 @@
+//! Scrubbing of sensitive properties, before events leave the process
+//!
+//! See [`Redactor`]
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use crate::custody::StableHasher;
+
+/// A closure that computes the redacted value of a string property
+pub type RedactFn = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// What a [`Redactor`] does to the value of a property
+#[derive(Clone)]
+pub enum Redaction {
+    /// Remove the property from the output
+    Drop,
+    /// Replace the value with a hash of it, so that equal values can still be correlated
+    ///
+    /// This is a 64-bit FNV-1a hash, not a cryptographic one: values from a small set (e.g. user names) can be recovered by hashing every candidate.
+    /// See [`Redactor::salt`] to make this harder.
+    Hash,
+    /// Keep at most this many characters of the value
+    Truncate(usize),
+    /// Replace the value with a fixed string
+    Replace(String),
+    /// Replace the value with the result of a closure
+    Custom(RedactFn),
+}
+
+impl std::fmt::Debug for Redaction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Drop => write!(f, "Drop"),
+            Self::Hash => write!(f, "Hash"),
+            Self::Truncate(len) => f.debug_tuple("Truncate").field(len).finish(),
+            Self::Replace(value) => f.debug_tuple("Replace").field(value).finish(),
+            Self::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Property-name-based scrubbers, that an [`EventSerializer`](super::EventSerializer) applies before serializing an event
+///
+/// This sanitizes personal data (command lines, URLs, user names, etc.) in a single place, whatever the sink the events are sent to.
+///
+/// ```
+/// # use ferrisetw::ser::{Redaction, Redactor};
+/// let redactor = Redactor::new()
+///     .redact("CommandLine", Redaction::Hash)
+///     .redact("URL", Redaction::Truncate(32))
+///     .redact("UserName", Redaction::Drop)
+///     .salt(b"per-deployment secret");
+/// assert_eq!(redactor.apply("url", "https://example.com/?token=1234567890abcdef"), Some(String::from("https://example.com/?token=12345")));
+/// assert_eq!(redactor.apply("UserName", "alice"), None);
+/// assert_eq!(redactor.apply("ProcessId", "1234"), Some(String::from("1234")));
+/// ```
+///
+/// Property names are matched case-insensitively. [`Redaction::Drop`] applies to properties of any type.
+/// The other redactions only apply to string properties: properties of other types they match are dropped, so that nothing is leaked by mistake.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    rules: Vec<(String, Redaction)>,
+    salt: Vec<u8>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redact the properties named `property`. A later rule for the same name replaces the earlier one
+    pub fn redact(mut self, property: &str, redaction: Redaction) -> Self {
+        self.rules
+            .retain(|(name, _)| !name.eq_ignore_ascii_case(property));
+        self.rules.push((property.to_string(), redaction));
+        self
+    }
+
+    /// Mix a secret into the hashes computed by [`Redaction::Hash`]
+    pub fn salt(mut self, salt: &[u8]) -> Self {
+        self.salt = salt.to_vec();
+        self
+    }
+
+    /// The redaction of this property, if any
+    pub fn rule(&self, property: &str) -> Option<&Redaction> {
+        self.rules
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(property))
+            .map(|(_, redaction)| redaction)
+    }
+
+    /// The redacted value of a string property, or `None` in case it must be dropped
+    pub fn apply(&self, property: &str, value: &str) -> Option<String> {
+        match self.rule(property) {
+            None => Some(value.to_string()),
+            Some(redaction) => self.redacted(redaction, value),
+        }
+    }
+
+    pub(crate) fn redacted(&self, redaction: &Redaction, value: &str) -> Option<String> {
+        match redaction {
+            Redaction::Drop => None,
+            Redaction::Hash => {
+                let mut hasher = StableHasher::new();
+                hasher.write(&self.salt);
+                hasher.write(value.as_bytes());
+                Some(format!("{:016x}", hasher.finish()))
+            }
+            Redaction::Truncate(len) => Some(value.chars().take(*len).collect()),
+            Redaction::Replace(replacement) => Some(replacement.clone()),
+            Redaction::Custom(redact) => Some(redact(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redactions() {
+        let redactor = Redactor::new()
+            .redact("CommandLine", Redaction::Hash)
+            .redact("ImageName", Redaction::Replace(String::from("<redacted>")))
+            .redact("Url", Redaction::Truncate(4))
+            .redact("Url", Redaction::Truncate(8))
+            .redact(
+                "Query",
+                Redaction::Custom(Arc::new(|value: &str| value.to_uppercase())),
+            );
+
+        let hashed = redactor.apply("commandline", "cmd.exe /c secret").unwrap();
+        assert_eq!(hashed.len(), 16);
+        assert_eq!(
+            redactor.apply("CommandLine", "cmd.exe /c secret").unwrap(),
+            hashed
+        );
+        assert_ne!(
+            redactor
+                .clone()
+                .salt(b"salt")
+                .apply("CommandLine", "cmd.exe /c secret")
+                .unwrap(),
+            hashed
+        );
+
+        assert_eq!(
+            redactor.apply("ImageName", "C:\\Users\\alice\\evil.exe"),
+            Some(String::from("<redacted>"))
+        );
+        assert_eq!(
+            redactor.apply("Url", "https://example.com"),
+            Some(String::from("https://"))
+        );
+        assert_eq!(
+            redactor.apply("Query", "example.com"),
+            Some(String::from("EXAMPLE.COM"))
+        );
+        assert_eq!(redactor.apply("Other", "kept"), Some(String::from("kept")));
+        assert_eq!(redactor.rules.len(), 4);
+    }
+}
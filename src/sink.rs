@@ -0,0 +1,468 @@
+//! Send the events of a trace to a pluggable [`EventSink`]
+//!
+//! Callbacks receive borrowed events, that only live until they return. An [`EventSink`] receives owned copies of the events instead, which makes it possible to
+//! forward them to channels, files or serializers, and to test consumers without running any trace.
+//!
+//! ```no_run
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::UserTrace;
+//! use ferrisetw::sink::{SinkHandle, SinkMessage};
+//!
+//! let (sender, receiver) = std::sync::mpsc::channel::<SinkMessage>();
+//! let sink = SinkHandle::new(sender);
+//!
+//! let provider = Provider::by_guid("1EDEEE53-0AFE-4609-B846-D8C0B2075B1F")
+//!     .add_callback(sink.callback())
+//!     .build();
+//! let trace = UserTrace::new()
+//!     .enable(provider)
+//!     .set_error_callback(sink.error_callback())
+//!     .on_processing_end(sink.end_hook())
+//!     .start_and_process();
+//!
+//! for message in receiver {
+//!     match message {
+//!         SinkMessage::Event(event) => println!("event {}", event.event_id()),
+//!         SinkMessage::Lost(kind) => println!("lost {:?}", kind),
+//!         SinkMessage::End => break,
+//!     }
+//! }
+//! ```
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use windows::core::GUID;
+
+use crate::native::etw_types::event_record::{EventRecord, OwnedEventRecord};
+use crate::schema_locator::SchemaLocator;
+use crate::trace::{EventError, FileTrace, TraceError, TraceTrait};
+
+/// The class of the events ETW emits when a real-time session loses events (`RT_LostEvent`)
+const LOST_EVENT_GUID: GUID = GUID::from_u128(0x6a399ae0_4bc6_4de9_870b_3657f8947e7e);
+
+/// What has been lost, as reported by an `RT_LostEvent` event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LostEventKind {
+    /// Events have been lost by the session
+    Events,
+    /// Buffers have been lost by the session
+    Buffers,
+    /// The backing file of the session could not be written
+    File,
+}
+
+impl LostEventKind {
+    /// What `record` reports as lost, if it is an `RT_LostEvent` event
+    pub(crate) fn from_record(record: &EventRecord) -> Option<Self> {
+        if record.provider_id() != LOST_EVENT_GUID {
+            return None;
+        }
+        match record.opcode() {
+            32 => Some(Self::Events),
+            33 => Some(Self::Buffers),
+            34 => Some(Self::File),
+            _ => None,
+        }
+    }
+}
+
+/// A destination for the events of a trace
+pub trait EventSink: Send {
+    /// An event has been received
+    fn on_event(&mut self, event: OwnedEventRecord);
+
+    /// The session has reported that something has been lost
+    ///
+    /// This does nothing by default.
+    fn on_lost(&mut self, _kind: LostEventKind) {}
+
+    /// The trace has stopped being processed. No more events will be received.
+    ///
+    /// This does nothing by default.
+    fn on_end(&mut self) {}
+}
+
+impl<S: EventSink + ?Sized> EventSink for Box<S> {
+    fn on_event(&mut self, event: OwnedEventRecord) {
+        (**self).on_event(event)
+    }
+
+    fn on_lost(&mut self, kind: LostEventKind) {
+        (**self).on_lost(kind)
+    }
+
+    fn on_end(&mut self) {
+        (**self).on_end()
+    }
+}
+
+/// A sink that can be shared by the callbacks of several providers
+///
+/// Use [`SinkHandle::callback`] as a provider (or [`FileTrace`]) callback, [`SinkHandle::error_callback`] as the error callback of the trace, and [`SinkHandle::end_hook`] as an `on_processing_end` hook.
+pub struct SinkHandle<S> {
+    sink: Arc<Mutex<S>>,
+}
+
+impl<S: EventSink + 'static> SinkHandle<S> {
+    pub fn new(sink: S) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(sink)),
+        }
+    }
+
+    /// A callback that copies every event it receives to the sink
+    ///
+    /// `RT_LostEvent` events are not copied, in case a callback receives some (e.g. a [`Consumer`](crate::trace::Consumer) callback):
+    /// losses are reported to [`EventSink::on_lost`] by [`SinkHandle::error_callback`].
+    pub fn callback(&self) -> impl FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static {
+        let sink = Arc::clone(&self.sink);
+        move |record: &EventRecord, _schema_locator: &SchemaLocator| {
+            if LostEventKind::from_record(record).is_none() {
+                lock(&sink).on_event(record.to_owned());
+            }
+        }
+    }
+
+    /// An error callback that calls [`EventSink::on_lost`] whenever the session reports a loss
+    ///
+    /// See [`TraceBuilder::set_error_callback`](crate::trace::TraceBuilder::set_error_callback). Other errors are ignored.
+    pub fn error_callback(&self) -> impl Fn(&EventError) + Send + Sync + 'static {
+        let sink = Arc::clone(&self.sink);
+        move |error: &EventError| {
+            if let EventError::Lost(kind) = error {
+                lock(&sink).on_lost(*kind);
+            }
+        }
+    }
+
+    /// A hook that calls [`EventSink::on_end`]
+    ///
+    /// See [`TraceBuilder::on_processing_end`](crate::trace::TraceBuilder::on_processing_end) for when it is run.
+    pub fn end_hook(&self) -> impl Fn() + Send + Sync + 'static {
+        let sink = Arc::clone(&self.sink);
+        move || lock(&sink).on_end()
+    }
+
+    /// Access the sink, e.g. to inspect its state once the trace has been stopped
+    pub fn lock(&self) -> MutexGuard<'_, S> {
+        lock(&self.sink)
+    }
+}
+
+impl<S> Clone for SinkHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sink: Arc::clone(&self.sink),
+        }
+    }
+}
+
+impl<S> std::fmt::Debug for SinkHandle<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SinkHandle").finish_non_exhaustive()
+    }
+}
+
+/// A sink that panicked in a callback is still usable
+fn lock<S>(sink: &Mutex<S>) -> MutexGuard<'_, S> {
+    sink.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Replay the events of an ETL file into a sink, on the current thread
+///
+/// This blocks until the end of the file, then calls [`EventSink::on_end`].
+pub fn replay<S: EventSink + 'static>(
+    path: PathBuf,
+    sink: &SinkHandle<S>,
+) -> Result<(), TraceError> {
+    let (mut trace, _handle) = FileTrace::new(path, sink.callback())
+        .set_error_callback(sink.error_callback())
+        .on_processing_end(sink.end_hook())
+        .start()?;
+    trace.process()
+}
+
+/// What a channel sink sends
+#[derive(Debug)]
+pub enum SinkMessage {
+    Event(OwnedEventRecord),
+    Lost(LostEventKind),
+    End,
+}
+
+/// Messages are silently dropped once the receiver is gone
+impl EventSink for Sender<SinkMessage> {
+    fn on_event(&mut self, event: OwnedEventRecord) {
+        let _receiver_gone = self.send(SinkMessage::Event(event));
+    }
+
+    fn on_lost(&mut self, kind: LostEventKind) {
+        let _receiver_gone = self.send(SinkMessage::Lost(kind));
+    }
+
+    fn on_end(&mut self) {
+        let _receiver_gone = self.send(SinkMessage::End);
+    }
+}
+
+/// Sending blocks the processing thread while the channel is full, which may make the session lose events.<br/>
+/// Messages are silently dropped once the receiver is gone.
+impl EventSink for SyncSender<SinkMessage> {
+    fn on_event(&mut self, event: OwnedEventRecord) {
+        let _receiver_gone = self.send(SinkMessage::Event(event));
+    }
+
+    fn on_lost(&mut self, kind: LostEventKind) {
+        let _receiver_gone = self.send(SinkMessage::Lost(kind));
+    }
+
+    fn on_end(&mut self) {
+        let _receiver_gone = self.send(SinkMessage::End);
+    }
+}
+
+/// A sink that writes events to a file (or any [`Write`]), in a format given by a closure
+///
+/// ```no_run
+/// # use ferrisetw::sink::WriterSink;
+/// use std::io::Write;
+///
+/// let file = std::fs::File::create("events.txt").unwrap();
+/// let sink = WriterSink::new(std::io::BufWriter::new(file), |writer, event| {
+///     writeln!(writer, "{:?} {}", event.provider_id(), event.event_id())
+/// });
+/// ```
+///
+/// Once writing has failed, the following events are dropped. The writer is flushed at the end of the trace.
+pub struct WriterSink<W, F> {
+    writer: W,
+    format: F,
+    error: Option<std::io::Error>,
+}
+
+impl<W, F> WriterSink<W, F>
+where
+    W: Write + Send,
+    F: FnMut(&mut W, &OwnedEventRecord) -> std::io::Result<()> + Send,
+{
+    pub fn new(writer: W, format: F) -> Self {
+        Self {
+            writer,
+            format,
+            error: None,
+        }
+    }
+
+    /// The error that has stopped this sink, if any
+    pub fn error(&self) -> Option<&std::io::Error> {
+        self.error.as_ref()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W, F> EventSink for WriterSink<W, F>
+where
+    W: Write + Send,
+    F: FnMut(&mut W, &OwnedEventRecord) -> std::io::Result<()> + Send,
+{
+    fn on_event(&mut self, event: OwnedEventRecord) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = (self.format)(&mut self.writer, &event) {
+            log::warn!("Unable to write an event: {}", err);
+            self.error = Some(err);
+        }
+    }
+
+    fn on_end(&mut self) {
+        if self.error.is_none() {
+            if let Err(err) = self.writer.flush() {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// A sink that hands an [`EventSerializer`](crate::EventSerializer) of every event to a closure
+///
+/// Requires the `serde` feature.
+///
+/// ```
+/// # use ferrisetw::sink::SerializerSink;
+/// extern crate serde_json;
+///
+/// let sink = SerializerSink::new(Default::default(), |serializer| {
+///     if let Ok(json) = serde_json::to_string(&serializer) {
+///         println!("{}", json);
+///     }
+/// });
+/// ```
+///
+/// Events whose schema cannot be found are skipped.
+/// Sensitive properties can be scrubbed with [`Self::with_redactor`].
+#[cfg(feature = "serde")]
+pub struct SerializerSink<F> {
+    schema_locator: SchemaLocator,
+    options: crate::EventSerializerOptions,
+    redactor: Option<crate::ser::Redactor>,
+    serialize: F,
+    skipped: usize,
+}
+
+#[cfg(feature = "serde")]
+impl<F> SerializerSink<F>
+where
+    F: FnMut(crate::EventSerializer<'_>) + Send,
+{
+    pub fn new(options: crate::EventSerializerOptions, serialize: F) -> Self {
+        Self {
+            schema_locator: SchemaLocator::new(),
+            options,
+            redactor: None,
+            serialize,
+            skipped: 0,
+        }
+    }
+
+    /// Scrub the properties of every event with this redactor, before they are handed to the closure
+    pub fn with_redactor(mut self, redactor: crate::ser::Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    /// The number of events that have been skipped because their schema could not be found
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<F> EventSink for SerializerSink<F>
+where
+    F: FnMut(crate::EventSerializer<'_>) + Send,
+{
+    fn on_event(&mut self, event: OwnedEventRecord) {
+        match self.schema_locator.event_schema(&event) {
+            Ok(schema) => {
+                let mut serializer = crate::EventSerializer::new(&event, &schema, self.options);
+                if let Some(redactor) = &self.redactor {
+                    serializer = serializer.with_redactor(redactor);
+                }
+                (self.serialize)(serializer)
+            }
+            Err(_) => self.skipped += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::trace::callback_data::{CallbackData, RealTimeCallbackData};
+
+    #[derive(Default)]
+    struct Recorder {
+        event_ids: Vec<u16>,
+        lost: Vec<LostEventKind>,
+        ended: bool,
+    }
+
+    impl EventSink for Recorder {
+        fn on_event(&mut self, event: OwnedEventRecord) {
+            self.event_ids.push(event.event_id());
+        }
+
+        fn on_lost(&mut self, kind: LostEventKind) {
+            self.lost.push(kind);
+        }
+
+        fn on_end(&mut self) {
+            self.ended = true;
+        }
+    }
+
+    #[test]
+    fn test_sink_handle() {
+        let sink = SinkHandle::new(Recorder::default());
+        let mut callback = sink.callback();
+        let locator = SchemaLocator::new();
+
+        let event = SyntheticEvent::new().with_user_data(&[1, 2, 3]);
+        callback(event.record(), &locator);
+        let lost = SyntheticEvent::new()
+            .with_provider(LOST_EVENT_GUID)
+            .with_opcode(33);
+        callback(lost.record(), &locator);
+        sink.end_hook()();
+
+        let recorder = sink.lock();
+        assert_eq!(recorder.event_ids, vec![0]);
+        assert!(recorder.lost.is_empty());
+        assert!(recorder.ended);
+    }
+
+    #[test]
+    fn test_sink_lost_events() {
+        let sink = SinkHandle::new(Recorder::default());
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_error_callback(Arc::new(sink.error_callback()));
+        rt_cb.add_trace_callback(Box::new(sink.callback()));
+        let callback_data = CallbackData::RealTime(rt_cb);
+
+        callback_data.on_event(SyntheticEvent::new().record());
+        for opcode in [32, 33] {
+            let lost = SyntheticEvent::new()
+                .with_provider(LOST_EVENT_GUID)
+                .with_opcode(opcode);
+            callback_data.on_event(lost.record());
+        }
+
+        let recorder = sink.lock();
+        assert_eq!(recorder.event_ids, vec![0]);
+        assert_eq!(
+            recorder.lost,
+            vec![LostEventKind::Events, LostEventKind::Buffers]
+        );
+    }
+
+    #[test]
+    fn test_channel_sink() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let sink = SinkHandle::new(sender);
+        let event = SyntheticEvent::new().with_user_data(&[1, 2, 3]);
+        sink.callback()(event.record(), &SchemaLocator::new());
+        sink.end_hook()();
+
+        match receiver.recv().unwrap() {
+            SinkMessage::Event(event) => assert_eq!(event.user_buffer(), &[1, 2, 3]),
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(matches!(receiver.recv().unwrap(), SinkMessage::End));
+    }
+
+    #[test]
+    fn test_writer_sink() {
+        let mut sink = WriterSink::new(Vec::new(), |writer: &mut Vec<u8>, event| {
+            writeln!(writer, "{} {}", event.opcode(), event.user_buffer().len())
+        });
+        sink.on_event(SyntheticEvent::new().with_opcode(1).record().to_owned());
+        sink.on_event(
+            SyntheticEvent::new()
+                .with_opcode(2)
+                .with_user_data(&[0; 4])
+                .record()
+                .to_owned(),
+        );
+        sink.on_end();
+
+        assert!(sink.error().is_none());
+        assert_eq!(sink.into_inner(), b"1 0\n2 4\n");
+    }
+}
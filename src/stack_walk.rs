@@ -0,0 +1,113 @@
+//! Stack walks of kernel events
+//!
+//! A [`KernelTrace`](crate::KernelTrace) can be asked to capture the call stack of some of its events (see [`TraceBuilder::enable_stack_tracing`](crate::trace::TraceBuilder::enable_stack_tracing)).
+//! Windows then emits a separate `StackWalk` event right after each of them, that [`StackWalk::from_record`] decodes.
+//!
+//! ```
+//! # use ferrisetw::EventRecord;
+//! # use ferrisetw::stack_walk::StackWalk;
+//! fn on_event(record: &EventRecord) {
+//!     if let Some(stack) = StackWalk::from_record(record) {
+//!         println!("thread {}: {:x?}", stack.thread_id, stack.addresses);
+//!     }
+//! }
+//! ```
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use crate::kernel_trace_control::RawPayload;
+use crate::native::etw_types::event_record::EventRecord;
+
+/// GUID of the `StackWalk` classic events
+pub const STACK_WALK_GUID: GUID = GUID::from_u128(0xdef2fe46_7bd6_4b80_bd94_f57fe20d0ce3);
+/// Opcode of `StackWalk` events that carry a call stack
+pub const STACK_WALK_OPCODE: u8 = 32;
+
+/// Identifies a classic kernel event, e.g. a process creation is `(PROCESS_GUID, 1)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassicEventId {
+    /// The GUID of the event class (e.g. the GUID of a [`KernelProvider`](crate::provider::kernel_providers::KernelProvider))
+    pub guid: GUID,
+    /// The event type, i.e. its opcode
+    pub opcode: u8,
+}
+
+impl ClassicEventId {
+    pub const fn new(guid: GUID, opcode: u8) -> Self {
+        Self { guid, opcode }
+    }
+
+    pub(crate) fn to_native(self) -> Etw::CLASSIC_EVENT_ID {
+        Etw::CLASSIC_EVENT_ID {
+            EventGuid: self.guid,
+            Type: self.opcode,
+            Reserved: [0; 7],
+        }
+    }
+}
+
+/// The call stack of a kernel event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackWalk {
+    /// Timestamp of the event this stack belongs to (same clock as [`EventRecord::raw_timestamp`])
+    pub event_timestamp: i64,
+    pub process_id: u32,
+    pub thread_id: u32,
+    /// Return addresses, innermost frame first
+    pub addresses: Vec<u64>,
+}
+
+impl StackWalk {
+    /// Decode a `StackWalk` event
+    ///
+    /// This returns `None` for any other event (and for malformed ones)
+    pub fn from_record(record: &EventRecord) -> Option<Self> {
+        if record.provider_id() != STACK_WALK_GUID || record.opcode() != STACK_WALK_OPCODE {
+            return None;
+        }
+
+        let mut payload = RawPayload::new(record);
+        let event_timestamp = payload.u64()? as i64;
+        let process_id = payload.u32()?;
+        let thread_id = payload.u32()?;
+        let mut addresses = Vec::new();
+        while let Some(address) = payload.pointer() {
+            addresses.push(address.as_u64());
+        }
+        Some(Self {
+            event_timestamp,
+            process_id,
+            thread_id,
+            addresses,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::SyntheticEvent;
+
+    #[test]
+    fn test_decode_stack_walk() {
+        let mut data = Vec::new();
+        data.extend(1234u64.to_le_bytes());
+        data.extend(4u32.to_le_bytes());
+        data.extend(8u32.to_le_bytes());
+        data.extend(0xfffff800_00001000u64.to_le_bytes());
+        data.extend(0x7ff6_0000_2000u64.to_le_bytes());
+        let event = SyntheticEvent::new()
+            .with_provider(STACK_WALK_GUID)
+            .with_opcode(STACK_WALK_OPCODE)
+            .with_user_data(&data);
+
+        let stack = StackWalk::from_record(event.record()).unwrap();
+        assert_eq!(stack.event_timestamp, 1234);
+        assert_eq!(stack.process_id, 4);
+        assert_eq!(stack.thread_id, 8);
+        assert_eq!(stack.addresses, vec![0xfffff800_00001000, 0x7ff6_0000_2000]);
+
+        let other = SyntheticEvent::new().with_user_data(&data);
+        assert!(StackWalk::from_record(other.record()).is_none());
+    }
+}
@@ -0,0 +1,236 @@
+//! Consume traces as asynchronous streams of events
+//!
+//! This requires the `async` feature.
+//!
+//! [`UserTrace::into_stream`](crate::UserTrace::into_stream) and [`KernelTrace::into_stream`](crate::KernelTrace::into_stream) spawn the thread that processes the trace,
+//! and return an [`EventStream`], that implements [`futures_core::Stream`]. It can thus be consumed by any async runtime (tokio, async-std...).
+//!
+//! ```
+//! # use ferrisetw::provider::Provider;
+//! # use ferrisetw::trace::UserTrace;
+//! # async fn example() {
+//! let provider = Provider::by_guid("1EDEEE53-0AFE-4609-B846-D8C0B2075B1F").build();
+//! let (trace, _handle) = UserTrace::new().enable(provider).start().unwrap();
+//! let mut stream = trace.into_stream(1024);
+//! // e.g. using `futures::StreamExt::next`:
+//! // while let Some(event) = stream.next().await { ... }
+//! # }
+//! ```
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use windows::core::GUID;
+
+use crate::native::evntrace::{process_trace, TraceHandle};
+use crate::trace::callback_data::CallbackData;
+use crate::{EtwCallback, EventRecord, SchemaLocator};
+
+/// An event that has been copied out of the ETW buffers, so that it can be sent to another thread
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ParsedEvent {
+    pub provider_id: GUID,
+    pub event_id: u16,
+    pub opcode: u8,
+    pub version: u8,
+    pub level: u8,
+    pub keyword: u64,
+    pub process_id: u32,
+    pub thread_id: u32,
+    /// See [`EventRecord::raw_timestamp`]
+    pub raw_timestamp: i64,
+    /// These are `None` in case the schema of this event could not be found
+    pub provider_name: Option<String>,
+    pub task_name: Option<String>,
+    pub opcode_name: Option<String>,
+    /// The raw user data of the event
+    pub user_buffer: Vec<u8>,
+}
+
+impl ParsedEvent {
+    fn new(record: &EventRecord, schema_locator: &SchemaLocator) -> Self {
+        let schema = schema_locator.event_schema(record).ok();
+        Self {
+            provider_id: record.provider_id(),
+            event_id: record.event_id(),
+            opcode: record.opcode(),
+            version: record.version(),
+            level: record.level(),
+            keyword: record.keyword(),
+            process_id: record.process_id(),
+            thread_id: record.thread_id(),
+            raw_timestamp: record.raw_timestamp(),
+            provider_name: schema.as_ref().map(|s| s.provider_name()),
+            task_name: schema.as_ref().map(|s| s.task_name()),
+            opcode_name: schema.as_ref().map(|s| s.opcode_name()),
+            user_buffer: record.user_buffer().to_vec(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<ParsedEvent>,
+    waker: Option<Waker>,
+    /// The stream has been dropped
+    closed: bool,
+    /// The processing thread has returned
+    finished: bool,
+}
+
+/// The bounded channel between the processing thread and the stream
+struct Shared {
+    state: Mutex<State>,
+    not_full: Condvar,
+    capacity: usize,
+}
+
+impl Shared {
+    /// Queue an event, blocking the processing thread while the queue is full
+    fn push(&self, event: ParsedEvent) {
+        let mut state = self.state.lock().unwrap();
+        while state.queue.len() >= self.capacity && !state.closed {
+            state = self.not_full.wait(state).unwrap();
+        }
+        if state.closed {
+            return;
+        }
+        state.queue.push_back(event);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A stream of the events of a trace, see the [module-level documentation](crate::stream)
+///
+/// The stream ends when the trace stops being processed.<br/>
+/// Dropping this stream stops the trace.
+pub struct EventStream {
+    shared: Arc<Shared>,
+    /// Keeps the trace alive (and stops it when dropped)
+    trace: Option<Box<dyn Send>>,
+}
+
+impl EventStream {
+    /// Create a stream, and the callback that feeds it
+    pub(crate) fn channel(capacity: usize) -> (Self, EtwCallback) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+        });
+        let sink = Arc::clone(&shared);
+        let callback: EtwCallback = Box::new(move |record, schema_locator| {
+            sink.push(ParsedEvent::new(record, schema_locator))
+        });
+        (
+            Self {
+                shared,
+                trace: None,
+            },
+            callback,
+        )
+    }
+
+    /// Spawn the thread that processes the trace, and bind the lifetime of the trace to this stream
+    pub(crate) fn run<T: Send + 'static>(
+        mut self,
+        trace: T,
+        handle: TraceHandle,
+        callback_data: Arc<CallbackData>,
+    ) -> Self {
+        let shared = Arc::clone(&self.shared);
+        std::thread::spawn(move || {
+            let result = callback_data
+                .processing_hooks()
+                .run_around(|| process_trace(handle));
+            if let Err(err) = result {
+                log::warn!("Processing of a streamed trace failed: {:?}", err);
+            }
+            shared.finish();
+        });
+        self.trace = Some(Box::new(trace));
+        self
+    }
+}
+
+impl futures_core::Stream for EventStream {
+    type Item = ParsedEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(event) = state.queue.pop_front() {
+            self.shared.not_full.notify_one();
+            return Poll::Ready(Some(event));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        // Unblock the processing thread first, so that stopping the trace does not wait for it
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.closed = true;
+            state.queue.clear();
+        }
+        self.shared.not_full.notify_all();
+        self.trace.take();
+    }
+}
+
+impl std::fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.shared.state.try_lock();
+        f.debug_struct("EventStream")
+            .field("capacity", &self.shared.capacity)
+            .field("queued", &state.as_ref().map(|s| s.queue.len()).ok())
+            .field("finished", &state.as_ref().map(|s| s.finished).ok())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_core::Stream;
+    use std::task::{RawWaker, RawWakerVTable};
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn test_stream_ends_when_finished() {
+        let (mut stream, _callback) = EventStream::channel(4);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        stream.shared.finish();
+        assert!(matches!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+}
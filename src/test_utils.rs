@@ -0,0 +1,149 @@
+//! Utilities to fabricate events in unit tests
+//!
+//! Real events can only be obtained from a running trace (or an ETL file), and their bitness is the one of the machine that emitted them.
+//! This module makes it possible to build events (e.g. with a 32-bit header) on any CI machine.
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::EVENT_RECORD;
+
+use crate::native::etw_types::event_record::EventRecord;
+use crate::native::etw_types::EVENT_HEADER_FLAG_32_BIT_HEADER;
+use crate::native::tdh_types::{
+    Property, PropertyCount, PropertyFlags, PropertyInfo, PropertyLength, TdhInType, TdhOutType,
+};
+
+/// An owned, fabricated event
+pub(crate) struct SyntheticEvent {
+    record: EventRecord,
+    /// `record.UserData` points to this buffer
+    user_data: Vec<u8>,
+}
+
+impl SyntheticEvent {
+    /// An event with a 64-bit header and no user data
+    pub fn new() -> Self {
+        Self {
+            record: EventRecord(EVENT_RECORD::default()),
+            user_data: Vec::new(),
+        }
+    }
+
+    /// Set `EVENT_HEADER_FLAG_32_BIT_HEADER`, as if the event was emitted by a 32-bit process
+    pub fn with_32_bit_header(mut self) -> Self {
+        self.record.0.EventHeader.Flags |= EVENT_HEADER_FLAG_32_BIT_HEADER;
+        self
+    }
+
+    pub fn with_provider(mut self, provider: GUID) -> Self {
+        self.record.0.EventHeader.ProviderId = provider;
+        self
+    }
+
+    #[cfg(feature = "kernel")]
+    pub fn with_event_id(mut self, event_id: u16) -> Self {
+        self.record.0.EventHeader.EventDescriptor.Id = event_id;
+        self
+    }
+
+    pub fn with_opcode(mut self, opcode: u8) -> Self {
+        self.record.0.EventHeader.EventDescriptor.Opcode = opcode;
+        self
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.record.0.EventHeader.EventDescriptor.Version = version;
+        self
+    }
+
+    pub fn with_level(mut self, level: u8) -> Self {
+        self.record.0.EventHeader.EventDescriptor.Level = level;
+        self
+    }
+
+    pub fn with_keyword(mut self, keyword: u64) -> Self {
+        self.record.0.EventHeader.EventDescriptor.Keyword = keyword;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.record.0.EventHeader.TimeStamp = timestamp;
+        self
+    }
+
+    #[cfg(feature = "kernel")]
+    pub fn with_process_id(mut self, process_id: u32) -> Self {
+        self.record.0.EventHeader.ProcessId = process_id;
+        self
+    }
+
+    pub fn with_processor(mut self, processor: u16) -> Self {
+        self.record.0.BufferContext.Anonymous.ProcessorIndex = processor;
+        self
+    }
+
+    /// Append data to the user buffer
+    pub fn with_user_data(mut self, data: &[u8]) -> Self {
+        self.user_data.extend_from_slice(data);
+        // The heap buffer may have been reallocated
+        self.record.0.UserData = self.user_data.as_mut_ptr().cast();
+        self.record.0.UserDataLength = self.user_data.len() as u16;
+        self
+    }
+
+    /// Append a null-terminated UTF-16 string to the user buffer
+    #[cfg(feature = "kernel")]
+    pub fn with_utf16_string(self, s: &str) -> Self {
+        let data: Vec<u8> = s
+            .encode_utf16()
+            .chain(std::iter::once(0))
+            .flat_map(u16::to_ne_bytes)
+            .collect();
+        self.with_user_data(&data)
+    }
+
+    /// Append a pointer to the user buffer, with the size matching the event bitness
+    pub fn with_pointer(self, value: u64) -> Self {
+        if self.record.pointer_size() == 4 {
+            self.with_user_data(&(value as u32).to_ne_bytes())
+        } else {
+            self.with_user_data(&value.to_ne_bytes())
+        }
+    }
+
+    pub fn record(&self) -> &EventRecord {
+        &self.record
+    }
+}
+
+/// A property, as a schema would describe it
+pub(crate) fn value_property(name: &str, in_type: TdhInType, length: u16) -> Property {
+    Property {
+        name: name.to_string(),
+        flags: PropertyFlags::empty(),
+        info: PropertyInfo::Value {
+            in_type,
+            out_type: TdhOutType::OutTypeNull,
+            length: PropertyLength::Length(length),
+        },
+        map_name: None,
+    }
+}
+
+/// A pointer property (whose size depends on the event bitness)
+pub(crate) fn pointer_property(name: &str) -> Property {
+    value_property(name, TdhInType::InTypePointer, 0)
+}
+
+/// An array of `count` pointers
+pub(crate) fn pointer_array_property(name: &str, count: u16) -> Property {
+    Property {
+        name: name.to_string(),
+        flags: PropertyFlags::empty(),
+        info: PropertyInfo::Array {
+            in_type: TdhInType::InTypePointer,
+            out_type: TdhOutType::OutTypeNull,
+            length: PropertyLength::Length(0),
+            count: PropertyCount::Count(count),
+        },
+        map_name: None,
+    }
+}
@@ -3,45 +3,124 @@
 //! Provides both a Kernel and User trace that allows to start an ETW session
 use std::ffi::OsString;
 use std::marker::PhantomData;
+use std::ops::ControlFlow;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError, RwLock};
 use std::time::Duration;
 
+use once_cell::sync::Lazy;
 use widestring::U16CString;
 use windows::core::GUID;
 use windows::Win32::System::Diagnostics::Etw;
 
 use self::private::{PrivateRealTimeTraceTrait, PrivateTraceTrait};
 
-use crate::native::etw_types::{EventTraceProperties, SubscriptionSource};
+use crate::capabilities::capabilities;
+use crate::custody::{ChainOfCustody, StableHasher};
+use crate::native::etw_types::{EventTraceProperties, SessionSettings, SubscriptionSource};
 use crate::native::evntrace::{
-    close_trace, control_trace, control_trace_by_name, enable_provider, open_trace, process_trace,
-    start_trace, ControlHandle, TraceHandle,
+    close_trace, control_trace, control_trace_by_name, control_trace_copy, enable_provider,
+    open_trace, process_trace, run_with_timeout, run_with_timeout_or_else, set_group_mask,
+    set_stack_tracing, start_trace, trace_event, ControlHandle, TraceHandle,
 };
-use crate::native::version_helper;
-use crate::provider::Provider;
+use crate::parser::private::TryParse;
+use crate::parser::Parser;
+use crate::predicate::Pred;
+use crate::provider::event_filter::EventFilterDescriptor;
+use crate::provider::{EventFilter, Provider, ProviderBuilder};
+use crate::self_telemetry;
+#[cfg(feature = "kernel")]
+use crate::stack_walk::ClassicEventId;
 use crate::utils;
 use crate::EventRecord;
 use crate::SchemaLocator;
 
+pub use crate::native::etw_types::ClockType;
 pub use crate::native::etw_types::DumpFileLoggingMode;
+pub use crate::native::etw_types::LogFileHeader;
 pub use crate::native::etw_types::LoggingMode;
+pub use crate::native::time::TimestampConverter;
 
 pub(crate) mod callback_data;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod consumer;
+mod controller;
+pub mod diagnostics;
+mod dispatch;
+mod ordering;
+mod pool;
+mod private_trace;
+mod sessions;
+mod set;
+mod validation;
+use callback_data::BufferCallback;
+pub use callback_data::BufferStats;
 use callback_data::CallbackData;
 use callback_data::CallbackDataFromFile;
+pub use callback_data::CallbackPanic;
+use callback_data::ErrorCallback;
+pub use callback_data::EventError;
+use callback_data::PanicHandler;
+pub use callback_data::PanicPolicy;
+use callback_data::ProcessingHooks;
 use callback_data::RealTimeCallbackData;
+#[cfg(feature = "chaos")]
+pub use chaos::{Chaos, ChaosStats};
+pub use consumer::Consumer;
+pub use controller::SessionController;
+use diagnostics::{ProviderDump, SessionDump, TraceDump};
+pub use dispatch::{Dispatch, Overflow};
+use ordering::OrderingCheck;
+pub use ordering::{CallbackOrdering, OrderingPolicy, OrderingStats, TimestampSource};
+pub use pool::{ProcessingOutcome, ProcessingPool};
+pub use private_trace::{PrivateTrace, PrivateTraceBuilder};
+pub use sessions::{cleanup_orphaned, query_all_traces, SessionInfo, SessionStats};
+pub use set::{TraceSet, MAX_TRACES};
+pub use validation::{Severity, ValidationIssue};
 
 const KERNEL_LOGGER_NAME: &str = "NT Kernel Logger";
+/// The prefix of the names of sessions that have not been explicitly named, unless changed with [`set_default_name_prefix`]
+///
+/// See [`cleanup_orphaned`]
+pub const DEFAULT_NAME_PREFIX: &str = "n4r1b-trace";
+/// The shortest interval between two flushes, see [`TraceBuilder::flush_every`]
+pub const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+static NAME_PREFIX: Lazy<RwLock<String>> =
+    Lazy::new(|| RwLock::new(String::from(DEFAULT_NAME_PREFIX)));
+#[cfg(feature = "kernel")]
 const SYSTEM_TRACE_CONTROL_GUID: &str = "9e814aad-3204-11d2-9a82-006008a86939";
+#[cfg(feature = "kernel")]
 const EVENT_TRACE_SYSTEM_LOGGER_MODE: u32 = 0x02000000;
 
 /// Trace module errors
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum TraceError {
     InvalidTraceName,
     /// Wrapper over an internal [EvntraceNativeError](crate::native::EvntraceNativeError)
     EtwNativeError(crate::native::EvntraceNativeError),
+    /// Some providers could not be enabled
+    ///
+    /// Every provider is attempted (see [`TraceBuilder::enable_providers_concurrently`]). This contains the GUID of every provider that failed, along with its error
+    EnableProviders(Vec<(GUID, crate::native::EvntraceNativeError)>),
+    /// The file logging mode of the ETL dump file is not supported by this trace (see [`TraceBuilder::set_etl_dump_file`])
+    InvalidDumpFileMode(DumpFileLoggingMode),
+    /// A [`TraceSet`] cannot contain more than [`MAX_TRACES`] traces
+    TooManyTraces,
+    /// A [`TraceSet`] cannot contain both real-time and file traces
+    MixedTraceKinds,
+    /// A [`PrivateTrace`] cannot start with this configuration (see [`PrivateTraceBuilder::validate`])
+    InvalidPrivateTrace(Vec<ValidationIssue>),
+    /// Several providers with this GUID are enabled on the same trace, and some of them have kernel-side filters, that cannot be merged
+    ///
+    /// Providers that share a GUID are otherwise enabled once, with the union of their settings.
+    ConflictingProviders(GUID),
+    /// The configuration of the trace is invalid, and would be refused by Windows (or would not behave as expected)
+    ///
+    /// [`TraceBuilder::start`] checks this before calling `StartTraceW`, see [`TraceBuilder::validate`] for the complete list of checks.
+    InvalidConfiguration(ValidationIssue),
 }
 
 impl From<crate::native::EvntraceNativeError> for TraceError {
@@ -67,7 +146,10 @@ pub struct TraceProperties {
     pub max_buffer: u32,
     /// Represents the ETW Session flush interval.
     ///
-    /// This duration will be rounded to the closest second (and 0 will be translated as 1 second)
+    /// On Windows 8 and later, sub-second intervals are honored with a millisecond granularity.
+    /// On older versions, they are rounded up to the next second.<br/>
+    /// A zero duration is translated into 1 second.<br/>
+    /// A warning is logged whenever the requested duration cannot be honored exactly.
     pub flush_timer: Duration,
     /// Represents the ETW Session [Logging Mode](https://docs.microsoft.com/en-us/windows/win32/etw/logging-mode-constants)
     pub log_file_mode: LoggingMode,
@@ -101,17 +183,96 @@ pub trait TraceTrait: private::PrivateTraceTrait + Sized {
     ///
     /// Because this call is blocking, you probably want to call this from a background thread.<br/>
     /// See [`TraceBuilder::start`] for alternative and more convenient ways to start a trace.
+    ///
+    /// The hooks set with [`TraceBuilder::on_processing_start`] and [`TraceBuilder::on_processing_end`] are run on the current thread, around the blocking call.
     fn process(&mut self) -> TraceResult<()> {
-        process_trace(self.trace_handle()).map_err(|e| e.into())
+        let handle = self.trace_handle();
+        self.callback_data()
+            .processing_hooks()
+            .run_around(|| process_trace(handle))
+            .map_err(|e| e.into())
     }
 
     /// Process a trace given its handle.
     ///
     /// See [`TraceBuilder::start`] for alternative and more convenient ways to start a trace.
+    ///
+    /// Note: because only the handle is known, the processing hooks (see [`TraceBuilder::on_processing_start`]) are not run by this function.
     fn process_from_handle(handle: TraceHandle) -> TraceResult<()> {
         process_trace(handle).map_err(|e| e.into())
     }
 
+    /// Flush the buffers of the session, so that the events they contain are delivered right away
+    ///
+    /// Otherwise, events are delivered when a buffer is full, or when the flush timer expires (see [`TraceProperties::flush_timer`]).
+    /// This has no effect on file traces.
+    fn flush(&self) -> TraceResult<()> {
+        match self.session() {
+            None => Ok(()),
+            Some((properties, control_handle)) => flush_session(properties, control_handle),
+        }
+    }
+
+    /// Build a structured report about the state of this trace
+    ///
+    /// This contains the session properties and counters (e.g. lost events and buffers, freshly queried from Windows for real-time traces),
+    /// the configuration of the enabled providers, the size of the schema cache, and how many events have been handled so far.<br/>
+    /// Its `Display` impl is suitable for logging when investigating why some events are missing.
+    fn debug_dump(&self) -> TraceDump {
+        let callback_data = self.callback_data();
+        TraceDump {
+            session: self.session_dump(),
+            providers: callback_data
+                .providers()
+                .iter()
+                .map(|provider| ProviderDump::from(provider.as_ref()))
+                .collect(),
+            predicates: callback_data.predicate_count(),
+            events_handled: callback_data.events_handled(),
+            cached_schemas: callback_data.cached_schemas(),
+        }
+    }
+
+    /// The counters of the timestamp order checks, in case they have been enabled (see [`TraceBuilder::check_timestamp_order`])
+    fn ordering_stats(&self) -> Option<OrderingStats> {
+        self.callback_data().ordering_stats()
+    }
+
+    /// How many events the session has lost so far
+    ///
+    /// This is updated live, from the `RT_LostEvent` notifications of the session, and from its own counters (that this call queries, for real-time traces).
+    /// A growing value means that events are produced faster than they are consumed: consider using more (or larger) buffers (see [`TraceProperties`]), or enabling fewer providers.
+    fn events_lost(&self) -> u64 {
+        refresh_loss_counters(self);
+        self.callback_data().loss_counters().events_lost()
+    }
+
+    /// How many buffers the session has lost so far (either not delivered to the consumer, or not written to the ETL dump file)
+    ///
+    /// See [`Self::events_lost`].
+    fn buffers_lost(&self) -> u64 {
+        refresh_loss_counters(self);
+        self.callback_data().loss_counters().buffers_lost()
+    }
+
+    /// How many events have been dropped because the worker queues were full (see [`Overflow::Drop`])
+    ///
+    /// This is always 0 for traces that invoke their callbacks inline (see [`TraceBuilder::dispatch`]).
+    fn events_dropped(&self) -> u64 {
+        self.callback_data().events_dropped()
+    }
+
+    /// How many events have been dropped or delayed so far, in case the trace has been started with [`TraceBuilder::chaos`]
+    #[cfg(feature = "chaos")]
+    fn chaos_stats(&self) -> Option<ChaosStats> {
+        self.callback_data().chaos_stats()
+    }
+
+    /// Converts the raw timestamps of this trace into system times, in case it has been started with [`TraceBuilder::raw_timestamps`]
+    fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        self.callback_data().timestamp_converter()
+    }
+
     /// Stops the trace
     ///
     /// This consumes the trace, that can no longer be used afterwards.
@@ -132,11 +293,11 @@ pub trait RealTimeTraceTrait: TraceTrait + private::PrivateRealTimeTraceTrait {
 
 impl TraceTrait for UserTrace {
     fn trace_handle(&self) -> TraceHandle {
-        self.trace_handle
+        self.consumer.trace_handle()
     }
 
     fn events_handled(&self) -> usize {
-        self.callback_data.events_handled()
+        self.consumer.events_handled()
     }
 }
 
@@ -146,24 +307,25 @@ impl RealTimeTraceTrait for UserTrace {
     }
 
     fn trace_name(&self) -> OsString {
-        self.properties.name()
+        self.controller.name()
     }
 }
 
-// TODO: Implement enable_provider function for providers that require call to TraceSetInformation with extended PERFINFO_GROUPMASK
+#[cfg(feature = "kernel")]
 impl TraceTrait for KernelTrace {
     fn trace_handle(&self) -> TraceHandle {
-        self.trace_handle
+        self.consumer.trace_handle()
     }
 
     fn events_handled(&self) -> usize {
-        self.callback_data.events_handled()
+        self.consumer.events_handled()
     }
 }
 
+#[cfg(feature = "kernel")]
 impl RealTimeTraceTrait for KernelTrace {
     fn trace_guid() -> GUID {
-        if version_helper::is_win8_or_greater() {
+        if capabilities().named_kernel_traces() {
             GUID::new().unwrap_or(GUID::zeroed())
         } else {
             GUID::from(SYSTEM_TRACE_CONTROL_GUID)
@@ -171,7 +333,7 @@ impl RealTimeTraceTrait for KernelTrace {
     }
 
     fn trace_name(&self) -> OsString {
-        self.properties.name()
+        self.controller.name()
     }
 }
 
@@ -187,32 +349,29 @@ impl TraceTrait for FileTrace {
 
 /// A real-time trace session to collect events from user-mode applications
 ///
+/// This is a [`SessionController`] and a [`Consumer`] of the same session, see [`UserTrace::into_parts`].<br/>
 /// To stop the session, you can drop this instance
 #[derive(Debug)]
-#[allow(clippy::redundant_allocation)] // see https://github.com/n4r1b/ferrisetw/issues/72
 pub struct UserTrace {
-    properties: EventTraceProperties,
-    control_handle: ControlHandle,
-    trace_handle: TraceHandle,
-    // CallbackData is
-    // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
-    // * `Boxed`, so that the `UserTrace` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
-    callback_data: Box<Arc<CallbackData>>,
+    // Fields are dropped in this order: the flusher stops, then the session is closed, then it is stopped
+    /// See [`TraceBuilder::flush_every`]
+    flusher: Option<private::Flusher>,
+    consumer: Consumer,
+    controller: SessionController,
 }
 
 /// A real-time trace session to collect events from kernel-mode drivers
 ///
-/// To stop the session, you can drop this instance
+/// To stop the session, you can drop this instance.<br/>
+/// This requires the `kernel` feature (enabled by default).
+#[cfg(feature = "kernel")]
 #[derive(Debug)]
-#[allow(clippy::redundant_allocation)] // see https://github.com/n4r1b/ferrisetw/issues/72
 pub struct KernelTrace {
-    properties: EventTraceProperties,
-    control_handle: ControlHandle,
-    trace_handle: TraceHandle,
-    // CallbackData is
-    // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
-    // * `Boxed`, so that the `UserTrace` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
-    callback_data: Box<Arc<CallbackData>>,
+    // Fields are dropped in this order: the flusher stops, then the session is closed, then it is stopped
+    /// See [`TraceBuilder::flush_every`]
+    flusher: Option<private::Flusher>,
+    consumer: Consumer,
+    controller: SessionController,
 }
 
 /// A trace session that reads events from an ETL file
@@ -226,6 +385,10 @@ pub struct FileTrace {
     // * `Arc`ed, so that dropping a Trace while a callback is still running is not an issue
     // * `Boxed`, so that the `UserTrace` can be moved around the stack (e.g. returned by a function) but the pointers to the `CallbackData` given to Windows ETW API stay valid
     callback_data: Box<Arc<CallbackData>>,
+    /// See [`FileTrace::log_file_header`]
+    log_file_header: LogFileHeader,
+    /// The trace handle has been closed already, and must not be closed again on drop
+    closed: bool,
 }
 
 /// Various parameters related to an ETL dump file
@@ -245,6 +408,15 @@ pub struct TraceBuilder<T: RealTimeTraceTrait> {
     name: String,
     etl_dump_file: Option<DumpFileParams>,
     properties: TraceProperties,
+    settings: SessionSettings,
+    session_filters: Vec<EventFilter>,
+    native_call_timeout: Option<Duration>,
+    retry_policy: RetryPolicy,
+    enable_parallelism: usize,
+    chain_of_custody: bool,
+    flush_interval: Option<Duration>,
+    /// See [`TraceBuilder::enable_stack_tracing`] (kernel traces only)
+    stack_tracing: Vec<Etw::CLASSIC_EVENT_ID>,
     rt_callback_data: RealTimeCallbackData,
     trace_kind: PhantomData<T>,
 }
@@ -252,21 +424,252 @@ pub struct TraceBuilder<T: RealTimeTraceTrait> {
 pub struct FileTraceBuilder {
     etl_file_path: PathBuf,
     callback: crate::EtwCallback,
+    processing_hooks: ProcessingHooks,
+    replay_speed: ReplaySpeed,
+    predicates: Vec<Pred>,
+    panic_handler: PanicHandler,
+    error_callback: Option<ErrorCallback>,
+    buffer_callback: Option<BufferCallback>,
+    /// Start and end timestamps, as `FILETIME` quads
+    time_range: Option<(i64, i64)>,
+    ordering_check: Option<OrderingCheck>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<Chaos>,
+}
+
+/// How fast events are delivered by a [`FileTrace`]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReplaySpeed {
+    /// Events are delivered as fast as they are read from the file
+    #[default]
+    Unthrottled,
+    /// Events are delivered with the same spacing as when they were recorded
+    Realtime,
+    /// Events are delivered with their recorded spacing, sped up by this factor (e.g. `2.0` replays twice as fast as real-time)
+    Factor(f64),
+}
+
+/// How [`TraceBuilder::start`] retries `StartTraceW` and `OpenTraceW` when they fail with a transient error
+///
+/// See [`EvntraceNativeError::is_transient`](crate::native::EvntraceNativeError::is_transient) for the errors that are retried.<br/>
+/// The delay between attempts starts at `initial_backoff`, and doubles after every attempt, up to `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The total number of attempts (1 means "no retry")
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Do not retry
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Make up to `max_attempts` attempts, with an exponential backoff starting at `initial_backoff` (and capped to the larger of `initial_backoff` and one second)
+    pub fn new(max_attempts: u32, initial_backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff,
+            max_backoff: initial_backoff.max(Duration::from_secs(1)),
+        }
+    }
+
+    /// The delay before the attempt that follows `attempt` (starting at 1)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Run `f` until it succeeds, returns a non-transient error, or the attempts are exhausted
+    fn run<R, F>(&self, call: &str, mut f: F) -> Result<R, crate::native::EvntraceNativeError>
+    where
+        F: FnMut() -> Result<R, crate::native::EvntraceNativeError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(err) if err.is_transient() && attempt < self.max_attempts => {
+                    let backoff = self.backoff(attempt);
+                    log::debug!(
+                        "{call} failed with a transient error ({err:?}), retrying in {backoff:?}"
+                    );
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
 }
 
 impl UserTrace {
     /// Create a UserTrace builder
     pub fn new() -> TraceBuilder<UserTrace> {
-        let name = format!("n4r1b-trace-{}", utils::rand_string());
+        let name = default_name();
         TraceBuilder {
             name,
             etl_dump_file: None,
             rt_callback_data: RealTimeCallbackData::new(),
             properties: TraceProperties::default(),
+            settings: SessionSettings::default(),
+            session_filters: Vec::new(),
+            native_call_timeout: None,
+            retry_policy: RetryPolicy::default(),
+            enable_parallelism: 1,
+            chain_of_custody: false,
+            flush_interval: None,
+            stack_tracing: Vec::new(),
             trace_kind: PhantomData,
         }
     }
 
+    /// Enable a provider on this running trace
+    ///
+    /// This can be called while the trace is being processed (e.g. from another thread than the one blocked on `process`).<br/>
+    /// Events from this provider are delivered to its callbacks as soon as this function returns.
+    /// In case a provider with the same GUID was already enabled on this trace, it is re-enabled with the union of both settings, and each provider only receives the events that match its own level and keywords.
+    pub fn enable_provider(&mut self, provider: Provider) -> TraceResult<()> {
+        let provider = Arc::new(provider);
+        let rt_callback_data = self.consumer.rt_callback_data();
+
+        // Register the callbacks first, so that no event is missed
+        rt_callback_data.add_provider(Arc::clone(&provider));
+        let same_guid: Vec<Arc<Provider>> = rt_callback_data
+            .providers()
+            .into_iter()
+            .filter(|prov| prov.guid() == provider.guid())
+            .collect();
+        let result = match same_guid.len() {
+            1 => self.controller.enable_provider(&provider),
+            _ => match Provider::merge(&same_guid) {
+                Some(merged) => self.controller.enable_provider(&merged),
+                None => Err(TraceError::ConflictingProviders(provider.guid())),
+            },
+        };
+        if let Err(err) = result {
+            rt_callback_data.remove_provider(&provider);
+            return Err(err);
+        }
+        self_telemetry::provider_enabled(&self.trace_name().to_string_lossy(), provider.guid());
+        Ok(())
+    }
+
+    /// Process this trace on a background thread, and consume its events as an async stream
+    ///
+    /// At most `capacity` events are queued: when the stream is not consumed fast enough, the processing thread blocks (and ETW may eventually drop events).<br/>
+    /// Dropping the stream stops the trace. See [`crate::stream`].
+    #[cfg(feature = "async")]
+    pub fn into_stream(self, capacity: usize) -> crate::stream::EventStream {
+        into_stream(self, capacity)
+    }
+
+    /// Disable a provider on this running trace
+    ///
+    /// Its callbacks will no longer be invoked (and are dropped).
+    pub fn disable_provider(&mut self, guid: GUID) -> TraceResult<()> {
+        self.controller.disable_provider(guid)?;
+        self.consumer.rt_callback_data().remove_providers(guid);
+        Ok(())
+    }
+
+    /// Query the current counters of this session (buffers written, events lost, etc.)
+    ///
+    /// This issues a `ControlTraceW(EVENT_TRACE_CONTROL_QUERY)` on every call.
+    pub fn query_stats(&self) -> TraceResult<SessionStats> {
+        self.controller.query_stats()
+    }
+
+    /// Watch the values of a single property, on a running trace
+    ///
+    /// This enables `provider` (filtered to `event_id`), and sends the value of `property` of every matching event to `sender`.<br/>
+    /// This is a convenient way to implement simple monitoring tasks, without writing any callback.
+    /// Events whose property cannot be parsed as a `T` are skipped. The watch stops when the trace stops.<br/>
+    /// Once the receiver has been dropped, matching events are ignored without being parsed. The provider remains enabled until the trace stops, though.
+    ///
+    /// ```
+    /// # use ferrisetw::provider::Provider;
+    /// # use ferrisetw::trace::UserTrace;
+    /// let (mut trace, _handle) = UserTrace::new().start_and_process().unwrap();
+    /// let (tx, rx) = std::sync::mpsc::channel::<String>();
+    /// let dns_client = Provider::by_guid("1c95126e-7eea-49a9-a3fe-a378b03ddb4d");
+    /// trace.watch(dns_client, 3006, "QueryName", tx).unwrap();
+    /// // for query in rx { ... }
+    /// ```
+    pub fn watch<T>(
+        &mut self,
+        provider: ProviderBuilder,
+        event_id: u16,
+        property: &str,
+        sender: std::sync::mpsc::Sender<T>,
+    ) -> TraceResult<()>
+    where
+        T: Send + 'static,
+        for<'schema, 'record> Parser<'schema, 'record>: TryParse<T>,
+    {
+        let property = property.to_string();
+        // Set to `None` once the receiver has been dropped
+        let sender = Mutex::new(Some(sender));
+        let provider = provider
+            .add_filter(EventFilter::ByEventIds(vec![event_id]))
+            .add_callback(
+                move |record: &EventRecord, schema_locator: &SchemaLocator| {
+                    // Kernel-side filters are not always effective, let's double check
+                    if record.event_id() != event_id {
+                        return;
+                    }
+                    let mut sender = sender.lock().unwrap_or_else(PoisonError::into_inner);
+                    let tx = match sender.as_ref() {
+                        Some(tx) => tx,
+                        None => return,
+                    };
+                    let schema = match schema_locator.event_schema(record) {
+                        Ok(schema) => schema,
+                        Err(err) => {
+                            log::debug!("Unable to get the schema of a watched event: {:?}", err);
+                            return;
+                        }
+                    };
+                    let parser = Parser::create(record, &schema);
+                    match parser.try_parse::<T>(&property) {
+                        Ok(value) => {
+                            if tx.send(value).is_err() {
+                                log::debug!(
+                                    "The receiver of watched property {} has been dropped",
+                                    property
+                                );
+                                *sender = None;
+                            }
+                        }
+                        Err(err) => {
+                            log::debug!("Unable to parse watched property {}: {:?}", property, err)
+                        }
+                    }
+                },
+            )
+            .build();
+        self.enable_provider(provider)
+    }
+
+    /// Split this trace into its controlling and consuming parts
+    ///
+    /// This makes it possible to e.g. stop the session from one thread, while another one is blocked processing it.<br/>
+    /// The session is stopped when the [`SessionController`] is dropped. The periodic flush (see [`TraceBuilder::flush_every`]), if any, is stopped.
+    pub fn into_parts(self) -> (SessionController, Consumer) {
+        (self.controller, self.consumer)
+    }
+
     /// Stops the trace
     ///
     /// This consumes the trace, that can no longer be used afterwards.
@@ -276,6 +679,7 @@ impl UserTrace {
     }
 }
 
+#[cfg(feature = "kernel")]
 impl KernelTrace {
     /// Create a KernelTrace builder
     pub fn new() -> TraceBuilder<KernelTrace> {
@@ -284,10 +688,40 @@ impl KernelTrace {
             etl_dump_file: None,
             rt_callback_data: RealTimeCallbackData::new(),
             properties: TraceProperties::default(),
+            settings: SessionSettings::default(),
+            session_filters: Vec::new(),
+            native_call_timeout: None,
+            retry_policy: RetryPolicy::default(),
+            enable_parallelism: 1,
+            chain_of_custody: false,
+            flush_interval: None,
+            stack_tracing: Vec::new(),
             trace_kind: PhantomData,
         };
         // Not all names are valid. Let's use the setter to check them for us
-        builder.named(format!("n4r1b-trace-{}", utils::rand_string()))
+        builder.named(default_name())
+    }
+
+    /// Query the current counters of this session
+    ///
+    /// See [`UserTrace::query_stats`].
+    pub fn query_stats(&self) -> TraceResult<SessionStats> {
+        self.controller.query_stats()
+    }
+
+    /// Process this trace on a background thread, and consume its events as an async stream
+    ///
+    /// See [`UserTrace::into_stream`].
+    #[cfg(feature = "async")]
+    pub fn into_stream(self, capacity: usize) -> crate::stream::EventStream {
+        into_stream(self, capacity)
+    }
+
+    /// Split this trace into its controlling and consuming parts
+    ///
+    /// See [`UserTrace::into_parts`].
+    pub fn into_parts(self) -> (SessionController, Consumer) {
+        (self.controller, self.consumer)
     }
 
     /// Stops the trace
@@ -299,6 +733,34 @@ impl KernelTrace {
     }
 }
 
+/// Feed the events of a real-time trace into a stream, and process it on a background thread
+#[cfg(feature = "async")]
+fn into_stream<T: TraceTrait + Send + 'static>(
+    trace: T,
+    capacity: usize,
+) -> crate::stream::EventStream {
+    let (stream, callback) = crate::stream::EventStream::channel(capacity);
+    let callback_data = Arc::clone(trace.callback_data());
+    if let CallbackData::RealTime(rt_cb) = callback_data.as_ref() {
+        rt_cb.add_trace_callback(callback);
+    }
+    let handle = trace.trace_handle();
+    stream.run(trace, handle, callback_data)
+}
+
+/// Update the loss counters of a real-time trace with the counters of its session
+fn refresh_loss_counters<T: TraceTrait>(trace: &T) {
+    if let Some((properties, control_handle)) = trace.session() {
+        match sessions::query_stats(properties, control_handle) {
+            Ok(stats) => trace
+                .callback_data()
+                .loss_counters()
+                .update_from_stats(&stats),
+            Err(err) => log::debug!("Unable to query the session counters: {:?}", err),
+        }
+    }
+}
+
 mod private {
     //! The only reason for this private module is to have a "private" trait in an otherwise publicly exported type (`TraceBuilder`)
     //!
@@ -308,131 +770,372 @@ mod private {
     #[derive(Debug, PartialEq, Eq)]
     pub enum TraceKind {
         User,
+        #[cfg_attr(not(feature = "kernel"), allow(dead_code))]
         Kernel,
     }
 
     pub trait PrivateRealTimeTraceTrait: PrivateTraceTrait {
         const TRACE_KIND: TraceKind;
-        #[allow(clippy::redundant_allocation)] // Being Boxed is really important, let's keep the Box<...> in the function signature to make the intent clearer (see https://github.com/n4r1b/ferrisetw/issues/72)
-        fn build(
-            properties: EventTraceProperties,
-            control_handle: ControlHandle,
-            trace_handle: TraceHandle,
-            callback_data: Box<Arc<CallbackData>>,
-        ) -> Self;
+        fn build(controller: SessionController, consumer: Consumer) -> Self;
         fn augmented_file_mode() -> u32;
-        fn enable_flags(_providers: &[Provider]) -> u32;
+        fn enable_flags(_providers: &[Arc<Provider>]) -> u32;
+        /// The PERFINFO_GROUPMASK to set once the session is started, if any provider requires one
+        fn group_mask(_providers: &[Arc<Provider>]) -> Option<[u32; 8]>;
+        fn set_flusher(&mut self, flusher: Flusher);
     }
 
     pub trait PrivateTraceTrait {
         // This function aims at de-deduplicating code called by `impl Drop` and `Trace::stop`.
         // It is basically [`Self::stop`], without consuming self (because the `impl Drop` only has a `&mut self`, not a `self`)
         fn non_consuming_stop(&mut self) -> TraceResult<()>;
+
+        fn callback_data(&self) -> &Arc<CallbackData>;
+
+        /// The session properties, for traces that have some
+        fn session_dump(&self) -> Option<SessionDump> {
+            None
+        }
+
+        /// The properties and control handle of the session, for real-time traces
+        fn session(&self) -> Option<(&EventTraceProperties, ControlHandle)> {
+            None
+        }
+    }
+
+    /// A background thread that periodically flushes a session (and updates its loss counters), see [`TraceBuilder::flush_every`]
+    ///
+    /// The thread exits when this is dropped.
+    #[derive(Debug)]
+    pub struct Flusher {
+        stop: Option<mpsc::Sender<()>>,
+        thread: Option<std::thread::JoinHandle<()>>,
+    }
+
+    impl Flusher {
+        pub(super) fn spawn(
+            properties: EventTraceProperties,
+            control_handle: ControlHandle,
+            interval: Duration,
+            callback_data: Arc<CallbackData>,
+        ) -> Self {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let thread = std::thread::spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(err) = flush_session(&properties, control_handle) {
+                        log::warn!("Unable to flush the session: {:?}", err);
+                    }
+                    if let Ok(stats) = sessions::query_stats(&properties, control_handle) {
+                        callback_data.loss_counters().update_from_stats(&stats);
+                    }
+                }
+            });
+            Self {
+                stop: Some(stop),
+                thread: Some(thread),
+            }
+        }
+    }
+
+    impl Drop for Flusher {
+        fn drop(&mut self) {
+            // Disconnecting the channel wakes the thread up
+            self.stop.take();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
     }
 }
 
 impl private::PrivateRealTimeTraceTrait for UserTrace {
     const TRACE_KIND: private::TraceKind = private::TraceKind::User;
 
-    fn build(
-        properties: EventTraceProperties,
-        control_handle: ControlHandle,
-        trace_handle: TraceHandle,
-        callback_data: Box<Arc<CallbackData>>,
-    ) -> Self {
+    fn build(controller: SessionController, consumer: Consumer) -> Self {
         UserTrace {
-            properties,
-            control_handle,
-            trace_handle,
-            callback_data,
+            flusher: None,
+            consumer,
+            controller,
         }
     }
 
+    fn set_flusher(&mut self, flusher: private::Flusher) {
+        self.flusher = Some(flusher);
+    }
+
     fn augmented_file_mode() -> u32 {
         0
     }
-    fn enable_flags(_providers: &[Provider]) -> u32 {
+    fn enable_flags(_providers: &[Arc<Provider>]) -> u32 {
         0
     }
+    fn group_mask(_providers: &[Arc<Provider>]) -> Option<[u32; 8]> {
+        None
+    }
 }
 
 impl private::PrivateTraceTrait for UserTrace {
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
-        control_trace(
-            &mut self.properties,
-            self.control_handle,
-            Etw::EVENT_TRACE_CONTROL_STOP,
-        )?;
-        Ok(())
+        // Make sure we won't flush a stopped session
+        self.flusher.take();
+        self.consumer.non_consuming_stop()?;
+        self.controller.stop_session()
+    }
+
+    fn callback_data(&self) -> &Arc<CallbackData> {
+        self.consumer.callback_data()
+    }
+
+    fn session_dump(&self) -> Option<SessionDump> {
+        Some(self.controller.session_dump())
+    }
+
+    fn session(&self) -> Option<(&EventTraceProperties, ControlHandle)> {
+        Some((
+            self.controller.properties(),
+            self.controller.control_handle(),
+        ))
     }
 }
 
+#[cfg(feature = "kernel")]
 impl private::PrivateRealTimeTraceTrait for KernelTrace {
     const TRACE_KIND: private::TraceKind = private::TraceKind::Kernel;
 
-    fn build(
-        properties: EventTraceProperties,
-        control_handle: ControlHandle,
-        trace_handle: TraceHandle,
-        callback_data: Box<Arc<CallbackData>>,
-    ) -> Self {
+    fn build(controller: SessionController, consumer: Consumer) -> Self {
         KernelTrace {
-            properties,
-            control_handle,
-            trace_handle,
-            callback_data,
+            flusher: None,
+            consumer,
+            controller,
         }
     }
 
+    fn set_flusher(&mut self, flusher: private::Flusher) {
+        self.flusher = Some(flusher);
+    }
+
     fn augmented_file_mode() -> u32 {
-        if version_helper::is_win8_or_greater() {
+        if capabilities().named_kernel_traces() {
             EVENT_TRACE_SYSTEM_LOGGER_MODE
         } else {
             0
         }
     }
 
-    fn enable_flags(providers: &[Provider]) -> u32 {
+    fn enable_flags(providers: &[Arc<Provider>]) -> u32 {
         providers.iter().fold(0, |acc, x| acc | x.kernel_flags())
     }
+
+    fn group_mask(providers: &[Arc<Provider>]) -> Option<[u32; 8]> {
+        if providers.iter().all(|prov| prov.kernel_group_mask() == 0) {
+            return None;
+        }
+        Some(crate::provider::kernel_providers::perfinfo_group_mask(
+            Self::enable_flags(providers),
+            providers.iter().map(|prov| prov.kernel_group_mask()),
+        ))
+    }
 }
 
+#[cfg(feature = "kernel")]
 impl private::PrivateTraceTrait for KernelTrace {
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
-        control_trace(
-            &mut self.properties,
-            self.control_handle,
-            Etw::EVENT_TRACE_CONTROL_STOP,
-        )?;
-        Ok(())
+        // Make sure we won't flush a stopped session
+        self.flusher.take();
+        self.consumer.non_consuming_stop()?;
+        self.controller.stop_session()
+    }
+
+    fn callback_data(&self) -> &Arc<CallbackData> {
+        self.consumer.callback_data()
+    }
+
+    fn session_dump(&self) -> Option<SessionDump> {
+        Some(self.controller.session_dump())
+    }
+
+    fn session(&self) -> Option<(&EventTraceProperties, ControlHandle)> {
+        Some((
+            self.controller.properties(),
+            self.controller.control_handle(),
+        ))
     }
 }
 
 impl private::PrivateTraceTrait for FileTrace {
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
-        Ok(())
+        FileTrace::non_consuming_stop(self)
+    }
+
+    fn callback_data(&self) -> &Arc<CallbackData> {
+        &self.callback_data
+    }
+}
+
+/// Report the final counters of a stopped session to [`self_telemetry`], in case something has been lost
+fn report_lost_events(properties: &EventTraceProperties) {
+    let native = properties.native();
+    if native.EventsLost != 0 || native.RealTimeBuffersLost != 0 {
+        self_telemetry::events_lost(
+            &properties.name().to_string_lossy(),
+            native.EventsLost,
+            native.RealTimeBuffersLost,
+        );
+    }
+}
+
+/// Issue a `EVENT_TRACE_CONTROL_FLUSH` on a session
+fn flush_session(
+    properties: &EventTraceProperties,
+    control_handle: ControlHandle,
+) -> TraceResult<()> {
+    control_trace_copy(properties, control_handle, Etw::EVENT_TRACE_CONTROL_FLUSH)?;
+    Ok(())
+}
+
+/// Turn the real-time delivery of a session on or off, with a `EVENT_TRACE_CONTROL_UPDATE`
+fn update_real_time_mode(
+    properties: &EventTraceProperties,
+    control_handle: ControlHandle,
+    enabled: bool,
+) -> TraceResult<()> {
+    // The session is queried first, so that the update keeps its current settings
+    let mut copy = control_trace_copy(properties, control_handle, Etw::EVENT_TRACE_CONTROL_QUERY)?;
+    copy.set_real_time_mode(enabled);
+    control_trace(&mut copy, control_handle, Etw::EVENT_TRACE_CONTROL_UPDATE)?;
+    Ok(())
+}
+
+/// Query the current counters of a session, falling back to the properties it has been started with
+fn query_session(properties: &EventTraceProperties, control_handle: ControlHandle) -> SessionDump {
+    match control_trace_copy(properties, control_handle, Etw::EVENT_TRACE_CONTROL_QUERY) {
+        Ok(queried) => SessionDump::new(&queried, true),
+        Err(err) => {
+            log::warn!("Unable to query the session properties: {:?}", err);
+            SessionDump::new(properties, false)
+        }
     }
 }
 
+/// Generate a session name that is very unlikely to collide with any other, e.g. `my-agent-k2Xo8CqfZ1`
+///
+/// This is how default session names are generated.
+pub fn unique_name(prefix: &str) -> String {
+    format!("{}-{}", prefix, utils::rand_string())
+}
+
+/// Change the prefix of the names of sessions that have not been explicitly named
+///
+/// This affects the builders that are created afterwards, e.g. `set_default_name_prefix("my-agent")` makes them use names such as `my-agent-1234-k2Xo8CqfZ1`.<br/>
+/// Passing the same prefix to [`cleanup_orphaned`] then only targets the sessions of your own program.
+/// See also [`TraceBuilder::name_prefix`] to change the prefix of a single trace.
+pub fn set_default_name_prefix(prefix: &str) {
+    *NAME_PREFIX.write().unwrap() = String::from(prefix);
+}
+
+/// The prefix of the names of sessions that have not been explicitly named (this is [`DEFAULT_NAME_PREFIX`] unless changed with [`set_default_name_prefix`])
+pub fn default_name_prefix() -> String {
+    NAME_PREFIX.read().unwrap().clone()
+}
+
+/// A session name made of `prefix`, then the ID of the current process, e.g. `my-agent-1234-k2Xo8CqfZ1`
+///
+/// Containing the PID is what makes [`cleanup_orphaned`] able to tell whether the session is still in use.
+fn prefixed_name(prefix: &str) -> String {
+    unique_name(&format!("{}-{}", prefix, std::process::id()))
+}
+
+/// The name of sessions that have not been explicitly named, e.g. `n4r1b-trace-1234-k2Xo8CqfZ1`
+fn default_name() -> String {
+    prefixed_name(&default_name_prefix())
+}
+
+/// Replace the `{pid}` and `{timestamp}` placeholders of a session name
+fn expand_name_template(name: &str) -> String {
+    let mut expanded = name.replace("{pid}", &std::process::id().to_string());
+    if expanded.contains("{timestamp}") {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        expanded = expanded.replace("{timestamp}", &timestamp.to_string());
+    }
+    expanded
+}
+
+/// Refuse the combinations of file modes that `StartTraceW` would reject (or silently mishandle)
+///
+/// `nt_kernel_logger` is set for kernel traces on Windows versions that do not support `EVENT_TRACE_SYSTEM_LOGGER_MODE`
+fn check_dump_file_mode(
+    file_mode: DumpFileLoggingMode,
+    log_file_mode: LoggingMode,
+    nt_kernel_logger: bool,
+) -> TraceResult<()> {
+    // An empty log file mode means the default one, which is real-time
+    let real_time =
+        log_file_mode.is_empty() || log_file_mode.contains(LoggingMode::EVENT_TRACE_REAL_TIME_MODE);
+
+    let exclusive = [
+        DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_SEQUENTIAL,
+        DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_CIRCULAR,
+        DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE,
+    ]
+    .iter()
+    .filter(|mode| file_mode.contains(**mode))
+    .count();
+
+    let append = file_mode.contains(DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_APPEND);
+    let invalid = exclusive > 1
+        || (append && real_time)
+        || (append
+            && !file_mode.contains(DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_SEQUENTIAL)
+            && exclusive > 0)
+        || (nt_kernel_logger
+            && file_mode.contains(DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE));
+
+    if invalid {
+        return Err(TraceError::InvalidDumpFileMode(file_mode));
+    }
+    Ok(())
+}
+
 impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
     /// Define the trace name
     ///
+    /// The name may contain the following placeholders, so that multiple instances of the same program do not collide, and remain identifiable (e.g. in `logman query`):
+    /// * `{pid}`, replaced by the ID of the current process
+    /// * `{timestamp}`, replaced by the current time, as seconds since the Unix epoch
+    ///
+    /// See also [`unique_name`].
+    ///
     /// For kernel traces on Windows Versions older than Win8, this method won't change the trace name. In those versions the trace name will be set to "NT Kernel Logger".
     ///
     /// Note: this trace name may be truncated to a few hundred characters if it is too long.
     pub fn named(mut self, name: String) -> Self {
-        if T::TRACE_KIND == private::TraceKind::Kernel && !version_helper::is_win8_or_greater() {
+        if T::TRACE_KIND == private::TraceKind::Kernel && !capabilities().named_kernel_traces() {
             self.name = String::from(KERNEL_LOGGER_NAME);
         } else {
-            self.name = name;
+            self.name = expand_name_template(&name);
         };
 
         self
     }
 
+    /// Same as [`Self::named`], but fails with a [`TraceError::InvalidConfiguration`] in case the name is empty, reserved for the kernel logger, or would be truncated
+    pub fn try_named(self, name: String) -> TraceResult<Self> {
+        let builder = self.named(name);
+        match builder.name_issues().into_iter().next() {
+            Some(issue) => Err(TraceError::InvalidConfiguration(issue)),
+            None => Ok(builder),
+        }
+    }
+
+    /// Name the trace after `prefix`, the ID of the current process and a random suffix (e.g. `my-agent-1234-k2Xo8CqfZ1`)
+    ///
+    /// This is how unnamed traces are named (with the prefix set by [`set_default_name_prefix`]), and the sessions can be cleaned up with [`cleanup_orphaned`]`(prefix)`.
+    pub fn name_prefix(self, prefix: &str) -> Self {
+        self.named(prefixed_name(prefix))
+    }
+
     /// Define several low-level properties of the trace at once.
     ///
     /// These are part of [`EVENT_TRACE_PROPERTIES`](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties)
@@ -441,6 +1144,22 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
         self
     }
 
+    /// Set the clock used to timestamp the events of the session (the performance counter by default)
+    ///
+    /// Timestamps are delivered as system times regardless of this clock, unless [`TraceBuilder::raw_timestamps`] is used.
+    pub fn clock_type(mut self, clock: ClockType) -> Self {
+        self.settings.clock_type = clock;
+        self
+    }
+
+    /// Set the `FlushThreshold` member of the [`EVENT_TRACE_PROPERTIES_V2`](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties_v2) of the session
+    ///
+    /// 0 (the default) lets Windows use its default behaviour.
+    pub fn flush_threshold(mut self, threshold: u32) -> Self {
+        self.settings.flush_threshold = threshold;
+        self
+    }
+
     /// Define a dump file for the events.
     ///
     /// If set, events will be dumped to a file on disk.<br/>
@@ -451,20 +1170,284 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
     /// If you're not sure, `params` has a safe [`default` value](`DumpFileParams::default`).
     ///
     /// Note: the file name may be truncated to a few hundred characters if it is too long.
+    ///
+    /// Some combinations of modes are refused by [`TraceBuilder::start`] with a [`TraceError::InvalidDumpFileMode`]:
+    /// * `EVENT_TRACE_FILE_MODE_APPEND` cannot be used in real-time sessions (which is the case unless the [`TraceProperties::log_file_mode`] says otherwise)
+    /// * `EVENT_TRACE_FILE_MODE_CIRCULAR`, `EVENT_TRACE_FILE_MODE_SEQUENTIAL`, `EVENT_TRACE_FILE_MODE_NEWFILE` and `EVENT_TRACE_FILE_MODE_APPEND` are mutually exclusive (except for `SEQUENTIAL | APPEND`)
+    /// * `EVENT_TRACE_FILE_MODE_NEWFILE` is not supported by the "NT Kernel Logger", i.e. by kernel traces on Windows versions older than Win8
+    ///
+    /// Other combinations are refused with a [`TraceError::InvalidConfiguration`]:
+    /// * `EVENT_TRACE_FILE_MODE_CIRCULAR`, `EVENT_TRACE_FILE_MODE_NEWFILE` and `EVENT_TRACE_FILE_MODE_PREALLOCATE` require a `max_size`
+    /// * `EVENT_TRACE_FILE_MODE_APPEND` requires the [`ClockType::SystemTime`] clock (see [`TraceBuilder::clock_type`])
+    ///
+    /// For kernel traces, Windows writes rundown events (e.g. `DCStart` events for every running process, thread and loaded image) at the beginning and the end of the file.
+    /// These are part of the file, but are not necessarily delivered to the real-time callbacks.
     pub fn set_etl_dump_file(mut self, params: DumpFileParams) -> Self {
         self.etl_dump_file = Some(params);
         self
     }
 
+    /// Add a session-level filter to this trace
+    ///
+    /// Unlike [`crate::provider::ProviderBuilder::add_filter`], this filter applies to every provider of the session.<br/>
+    /// This requires [`EVENT_TRACE_PROPERTIES_V2`](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties_v2), and is ignored (with a warning log) on Windows versions that do not support it.
+    /// Also, Windows only supports a few filter types at session-level (see the link above).
+    ///
+    /// [`TraceBuilder::start`] fails with a [`TraceError::InvalidConfiguration`] in case this filter cannot be built (e.g. because it is empty).
+    pub fn add_session_filter(mut self, filter: EventFilter) -> Self {
+        self.session_filters.push(filter);
+        self
+    }
+
     /// Enable a Provider for this trace
     ///
     /// This will invoke the provider's callback whenever an event is available
     ///
     /// # Note
-    /// Windows API seems to support removing providers, or changing its properties when the session is processing events (see <https://learn.microsoft.com/en-us/windows/win32/api/evntrace/nf-evntrace-enabletraceex2#remarks>)    /// Currently, this crate only supports defining Providers and their settings when building the trace, because it is easier to ensure memory-safety this way.
-    /// It probably would be possible to support changing Providers when the trace is processing, but this is left as a TODO (see <https://github.com/n4r1b/ferrisetw/issues/54>)
-    pub fn enable(mut self, provider: Provider) -> Self {
+    /// Providers can also be enabled or disabled once a user trace is running, see [`UserTrace::enable_provider`] and [`UserTrace::disable_provider`].
+    pub fn enable(self, provider: Provider) -> Self {
+        self.rt_callback_data.add_provider(Arc::new(provider));
+        self
+    }
+
+    /// Same as [`Self::enable`], but checks the provider right away
+    ///
+    /// This fails with a [`TraceError::InvalidConfiguration`] in case the provider has a null GUID, has invalid (or too many) filters,
+    /// is a kernel provider enabled on a user trace, or conflicts with a provider with the same GUID that is already enabled.
+    pub fn try_enable(self, provider: Provider) -> TraceResult<Self> {
+        let provider = Arc::new(provider);
+        if let Some(issue) = self.provider_issues(&provider).into_iter().next() {
+            return Err(TraceError::InvalidConfiguration(issue));
+        }
         self.rt_callback_data.add_provider(provider);
+        Ok(self)
+    }
+
+    /// Give up on native calls that take longer than `timeout` when starting the trace
+    ///
+    /// `StartTraceW` and especially `EnableTraceEx2` (which synchronously runs the enable callback of the provider) may block for a long time, which would hang [`TraceBuilder::start`].<br/>
+    /// When a timeout is set, these calls are run on a helper thread, and [`TraceBuilder::start`] returns an [`EvntraceNativeError::Timeout`](crate::native::EvntraceNativeError::Timeout) if they take too long.
+    /// Note that the timed out call cannot be cancelled, and is left running in the background. In case a timed out `StartTraceW` eventually starts the session, the session is stopped right away.
+    ///
+    /// By default, there is no timeout.
+    pub fn native_call_timeout(mut self, timeout: Duration) -> Self {
+        self.native_call_timeout = Some(timeout);
+        self
+    }
+
+    /// Retry `StartTraceW` and `OpenTraceW` when they fail with a transient error
+    ///
+    /// This typically happens when a session with the same name has just been stopped, and its name has not been released yet.<br/>
+    /// By default, these calls are not retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Flush the session every `interval`, from a background thread
+    ///
+    /// This lowers the latency of low-volume providers, whose events would otherwise wait in kernel buffers until the flush timer expires (see [`TraceProperties::flush_timer`]).<br/>
+    /// The thread also queries the counters of the session, that [`TraceTrait::events_lost`] and [`TraceTrait::buffers_lost`] report.
+    /// It stops when the trace is stopped. See also [`TraceTrait::flush`].
+    ///
+    /// Intervals shorter than [`MIN_FLUSH_INTERVAL`] are raised to it, so that the thread does not keep the session busy flushing.
+    pub fn flush_every(mut self, interval: Duration) -> Self {
+        self.flush_interval = Some(interval.max(MIN_FLUSH_INTERVAL));
+        self
+    }
+
+    /// Write a [`ChainOfCustody`] metadata event into the ETL dump file, when the session starts
+    ///
+    /// This event records the machine name, the OS build, a hash of the session configuration and the version of ferrisetw.
+    /// It can be read back using [`ChainOfCustody::from_record`].
+    ///
+    /// This has no effect if no ETL dump file is set (see [`TraceBuilder::set_etl_dump_file`]).
+    pub fn write_chain_of_custody(mut self) -> Self {
+        self.chain_of_custody = true;
+        self
+    }
+
+    /// Enable providers concurrently, using at most `max_parallelism` threads
+    ///
+    /// By default, providers are enabled one after the other, which may noticeably slow down the start of sessions with many providers.<br/>
+    /// Either way, every provider is attempted, and [`TraceBuilder::start`] reports every failure at once in a [`TraceError::EnableProviders`].
+    ///
+    /// This has no effect on kernel traces.
+    pub fn enable_providers_concurrently(mut self, max_parallelism: usize) -> Self {
+        self.enable_parallelism = max_parallelism.max(1);
+        self
+    }
+
+    /// Set a closure that is run on the processing thread, right before the blocking call to `ProcessTrace`
+    ///
+    /// This is the right place to set up thread-local state (e.g. initializing COM, entering a tracing span, changing the thread priority...) that the callbacks may need.<br/>
+    /// Hooks are run by `process()`, [`TraceBuilder::start_and_process`] and [`ProcessingPool`], but not by `process_from_handle()`.
+    pub fn on_processing_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.rt_callback_data
+            .processing_hooks_mut()
+            .set_on_start(Box::new(hook));
+        self
+    }
+
+    /// Set a closure that is run on the processing thread, right after the blocking call to `ProcessTrace` has returned (whether it succeeded or not)
+    ///
+    /// See [`TraceBuilder::on_processing_start`]
+    pub fn on_processing_end<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.rt_callback_data
+            .processing_hooks_mut()
+            .set_on_end(Box::new(hook));
+        self
+    }
+
+    /// Set what happens when a callback panics
+    ///
+    /// By default ([`PanicPolicy::Abort`]), the process exits, because a panic cannot unwind across the native ETW frames.<br/>
+    /// With [`PanicPolicy::StopTrace`], the session is not stopped, but `process()` returns and no further event is delivered.
+    pub fn on_callback_panic(mut self, policy: PanicPolicy) -> Self {
+        self.rt_callback_data.panic_handler_mut().set_policy(policy);
+        self
+    }
+
+    /// Send every panic caught in a callback to `reporter`
+    ///
+    /// This is sent before the [`PanicPolicy`] is applied (so, even when the policy is to abort).
+    pub fn report_callback_panics(mut self, reporter: mpsc::Sender<CallbackPanic>) -> Self {
+        self.rt_callback_data
+            .panic_handler_mut()
+            .set_reporter(reporter);
+        self
+    }
+
+    /// Set a closure that is notified of the errors that happen while processing events
+    ///
+    /// Without it, these errors are only visible in the logs (if at all). This is run on the processing thread, and should return quickly. It is notified when:
+    /// * the schema of an event cannot be retrieved (either by this crate, e.g. to evaluate a [`Pred`], or by a callback)
+    /// * the session reports lost events or buffers
+    /// * a callback panics (see [`TraceBuilder::on_callback_panic`])
+    ///
+    /// Events that are received after the trace has been dropped cannot be attributed to it, and are not reported.
+    pub fn set_error_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&EventError) + Send + Sync + 'static,
+    {
+        self.rt_callback_data.set_error_callback(Arc::new(callback));
+        self
+    }
+
+    /// Set a closure that is run on the processing thread every time ETW has delivered a buffer of events
+    ///
+    /// It receives statistics about the buffer and the session (e.g. to monitor how full buffers are, or how many events have been lost).<br/>
+    /// Returning [`ControlFlow::Break`] makes `process()` return. The session itself is not stopped: that is still up to the owner of the trace.
+    /// Panics of this closure are handled like panics of event callbacks (see [`PanicPolicy`]).
+    pub fn set_buffer_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&BufferStats) -> ControlFlow<()> + Send + 'static,
+    {
+        self.rt_callback_data
+            .set_buffer_callback(Box::new(callback));
+        self
+    }
+
+    /// Whether to deliver the timestamps of the events in the clock of the session (see [`TraceBuilder::clock_type`]), instead of converting them to system time
+    ///
+    /// [`EventRecord::raw_timestamp`] then returns monotonic ticks of this clock (e.g. QPC ticks or CPU cycles), which are cheaper and more accurate to measure durations with.
+    /// [`EventRecord::system_timestamp`] (and `EventRecord::timestamp`) still return system times, that ferrisetw converts from these ticks.
+    /// See [`TraceTrait::timestamp_converter`] to convert them yourself.<br/>
+    /// This opens the session with `PROCESS_TRACE_MODE_RAW_TIMESTAMP`.
+    pub fn raw_timestamps(mut self, enabled: bool) -> Self {
+        self.rt_callback_data.set_raw_timestamps(enabled);
+        self
+    }
+
+    /// Check that the events of every processor are delivered with increasing timestamps
+    ///
+    /// Out-of-order events are counted (see [`TraceTrait::ordering_stats`]) and reported to the error callback (see [`TraceBuilder::set_error_callback`]).
+    /// Depending on the `policy`, they are then either delivered as usual, or dropped.<br/>
+    /// This is useful before feeding events to a time-series store, that would otherwise choke on them.
+    pub fn check_timestamp_order(mut self, policy: OrderingPolicy) -> Self {
+        self.rt_callback_data
+            .ordering_check_mut()
+            .set_policy(policy);
+        self
+    }
+
+    /// Set how the timestamp of an event is read by the order checks, instead of [`EventRecord::raw_timestamp`]
+    ///
+    /// This is useful in case events carry their own clock (e.g. in a property). This enables the checks (see [`TraceBuilder::check_timestamp_order`]).
+    pub fn timestamp_source<F>(mut self, source: F) -> Self
+    where
+        F: Fn(&EventRecord) -> i64 + Send + Sync + 'static,
+    {
+        self.rt_callback_data
+            .ordering_check_mut()
+            .set_timestamp_source(Box::new(source));
+        self
+    }
+
+    /// Set the order in which callbacks are invoked, across providers
+    ///
+    /// By default ([`CallbackOrdering::AsDelivered`]), events are delivered as soon as ETW hands them over, which is only guaranteed to be chronological per processor.
+    /// [`CallbackOrdering::Strict`] holds events back and sorts them, at the cost of some latency and of a copy of every event.<br/>
+    /// File traces do not need this: `ProcessTrace` already delivers the events of ETL files in timestamp order.
+    pub fn callback_ordering(mut self, ordering: CallbackOrdering) -> Self {
+        self.rt_callback_data.set_callback_ordering(ordering);
+        self
+    }
+
+    /// Set where the callbacks are invoked
+    ///
+    /// By default ([`Dispatch::Inline`]), callbacks run on the thread that processes the trace, and ETW cannot hand over more events until they return.
+    /// Expensive callbacks should rather run on a pool of workers ([`Dispatch::ThreadPool`]), at the cost of a copy of every event.
+    /// Callbacks then run on several threads, and the events of different providers may be handled out of order.
+    /// This is why a thread pool cannot be combined with [`CallbackOrdering::Strict`].
+    ///
+    /// ```no_run
+    /// # use ferrisetw::trace::{Dispatch, Overflow, TraceTrait, UserTrace};
+    /// # use ferrisetw::provider::Provider;
+    /// # let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716").build();
+    /// let (trace, _handle) = UserTrace::new()
+    ///     .enable(provider)
+    ///     .dispatch(Dispatch::ThreadPool {
+    ///         workers: 4,
+    ///         queue_len: 1024,
+    ///         overflow: Overflow::Drop,
+    ///     })
+    ///     .start()
+    ///     .unwrap();
+    /// // ...
+    /// println!("{} events dropped", trace.events_dropped());
+    /// ```
+    pub fn dispatch(mut self, dispatch: Dispatch) -> Self {
+        self.rt_callback_data.set_dispatch(dispatch);
+        self
+    }
+
+    /// Randomly drop or delay events before they reach the callbacks, to test that downstream code tolerates losses
+    ///
+    /// Dropped events are counted (see [`TraceTrait::chaos_stats`]), but are not reported as lost by the session.<br/>
+    /// This requires the `chaos` feature, which should only be enabled in tests.
+    ///
+    /// ```no_run
+    /// # use ferrisetw::trace::{Chaos, TraceTrait, UserTrace};
+    /// # use ferrisetw::provider::Provider;
+    /// # let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716").build();
+    /// let (trace, _handle) = UserTrace::new()
+    ///     .enable(provider)
+    ///     .chaos(Chaos::new(42).drop_probability(0.1))
+    ///     .start()
+    ///     .unwrap();
+    /// // ...
+    /// println!("{:?}", trace.chaos_stats());
+    /// ```
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.rt_callback_data.set_chaos(chaos);
         self
     }
 
@@ -481,7 +1464,26 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
     /// * Easiest option: [`TraceBuilder::start_and_process()`].<br/>
     ///   This convenience function spawns a thread for you, call [`TraceBuilder::start`] on the trace, and returns immediately.<br/>
     ///   This option returns a `T`, so you can explicitly stop the trace, but there is no way to get the status code of the ProcessTrace API.
+    ///
+    /// Invalid configurations (see [`TraceBuilder::validate`]) are refused before calling `StartTraceW`, with a [`TraceError::InvalidConfiguration`].
+    /// In case a later step fails (e.g. enabling a provider), the session is stopped before the error is returned.
     pub fn start(self) -> TraceResult<(T, TraceHandle)> {
+        if let Some(issue) = self
+            .configuration_issues()
+            .into_iter()
+            .find(ValidationIssue::is_error)
+        {
+            return Err(match issue {
+                ValidationIssue::InvalidDumpFileMode(mode) => TraceError::InvalidDumpFileMode(mode),
+                issue => TraceError::InvalidConfiguration(issue),
+            });
+        }
+
+        let custody = match (self.chain_of_custody, &self.etl_dump_file) {
+            (true, Some(_)) => Some(ChainOfCustody::collect(self.config_hash())),
+            _ => None,
+        };
+
         // Prepare a wide version of the trace name
         let trace_wide_name = U16CString::from_str_truncate(self.name);
         let mut trace_wide_vec = trace_wide_name.into_vec();
@@ -498,7 +1500,7 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
             }) => {
                 let wide_path = U16CString::from_os_str_truncate(file_path.as_os_str());
                 let mut wide_path_vec = wide_path.into_vec();
-                wide_path_vec.truncate(crate::native::etw_types::TRACE_NAME_MAX_CHARS);
+                wide_path_vec.truncate(crate::native::etw_types::LOG_FILE_PATH_MAX_CHARS);
                 Some((
                     U16CString::from_vec_truncate(wide_path_vec),
                     file_logging_mode,
@@ -507,34 +1509,155 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
             }
         };
 
+        let owned_session_filter_descriptors: Arc<Vec<EventFilterDescriptor>> = Arc::new(
+            self.session_filters
+                .iter()
+                .map(|filter| {
+                    filter.to_event_filter_descriptor().map_err(|err| {
+                        TraceError::InvalidConfiguration(ValidationIssue::InvalidFilter {
+                            provider: None,
+                            reason: err.to_string(),
+                        })
+                    })
+                })
+                .collect::<TraceResult<_>>()?,
+        );
+
         let flags = self.rt_callback_data.provider_flags::<T>();
-        let (full_properties, control_handle) = start_trace::<T>(
-            &trace_wide_name,
-            wide_etl_dump_file
-                .as_ref()
-                .map(|(path, params, max_size)| (path.as_ucstr(), *params, *max_size)),
-            &self.properties,
-            flags,
-        )?;
+        let properties = self.properties;
+        let settings = self.settings;
+        let native_call_timeout = self.native_call_timeout;
+        let retry_policy = self.retry_policy;
+        let flush_interval = self.flush_interval;
+        let (full_properties, control_handle) = retry_policy.run("StartTraceW", || {
+            let thread_trace_name = trace_wide_name.clone();
+            let wide_etl_dump_file = wide_etl_dump_file.clone();
+            let session_filter_descriptors = Arc::clone(&owned_session_filter_descriptors);
+            run_with_timeout_or_else(
+                String::from("StartTraceW"),
+                native_call_timeout,
+                move || {
+                    start_trace::<T>(
+                        &thread_trace_name,
+                        wide_etl_dump_file
+                            .as_ref()
+                            .map(|(path, params, max_size)| (path.as_ucstr(), *params, *max_size)),
+                        &properties,
+                        &settings,
+                        flags,
+                        &session_filter_descriptors,
+                    )
+                },
+                |late| {
+                    // We have given up on this session, it must not be left running
+                    if let Ok((properties, control_handle)) = late {
+                        log::warn!("StartTraceW has returned after its timeout, stopping the session it has started");
+                        let _ = SessionController::new(properties, control_handle).stop();
+                    }
+                },
+            )
+        })?;
+        // From now on, the session is stopped in case anything fails (i.e. when this is dropped)
+        let controller = SessionController::new(full_properties, control_handle);
+
+        let session_name = trace_wide_name.to_string_lossy();
+        self_telemetry::session_started(&session_name);
+
+        let callback_data = Box::new(Arc::new(CallbackData::RealTime(self.rt_callback_data)));
+
+        if !self.stack_tracing.is_empty() {
+            set_stack_tracing(control_handle, &self.stack_tracing)?;
+        }
 
-        // TODO: For kernel traces, implement enable_provider function for providers that require call to TraceSetInformation with extended PERFINFO_GROUPMASK
+        if let Some(group_mask) = T::group_mask(&callback_data.providers()) {
+            set_group_mask(control_handle, &group_mask)?;
+        }
 
         if T::TRACE_KIND == private::TraceKind::User {
-            for prov in self.rt_callback_data.providers() {
-                enable_provider(control_handle, prov)?;
-            }
+            enable_providers(
+                control_handle,
+                &callback_data,
+                self.native_call_timeout,
+                self.enable_parallelism,
+            )?;
+        }
+        for provider in callback_data.providers() {
+            self_telemetry::provider_enabled(&session_name, provider.guid());
         }
 
-        let callback_data = Box::new(Arc::new(CallbackData::RealTime(self.rt_callback_data)));
-        let trace_handle = open_trace(
-            SubscriptionSource::RealTimeSession(trace_wide_name),
-            &callback_data,
-        )?;
+        if let Some(custody) = custody {
+            trace_event(
+                control_handle,
+                ChainOfCustody::PROVIDER_GUID,
+                ChainOfCustody::EVENT_TYPE,
+                &custody.to_payload(),
+            )?;
+        }
 
-        Ok((
-            T::build(full_properties, control_handle, trace_handle, callback_data),
-            trace_handle,
-        ))
+        let (trace_handle, header) = retry_policy.run("OpenTraceW", || {
+            open_trace(
+                SubscriptionSource::RealTimeSession(trace_wide_name.clone()),
+                &callback_data,
+            )
+        })?;
+        // Likewise, the trace is closed in case anything fails (before the session is stopped)
+        let consumer = Consumer::new(trace_handle, callback_data);
+        let callback_data = consumer.callback_data();
+        if callback_data.raw_timestamps() {
+            // No event is delivered before the trace is processed, the clock is known before the first event needs it
+            match TimestampConverter::now(settings.clock_type, &header) {
+                Some(converter) => callback_data.set_timestamp_converter(converter),
+                None => log::warn!(
+                    "Unable to read the {:?} clock, raw timestamps will not be converted to system time",
+                    settings.clock_type
+                ),
+            }
+        }
+        callback_data.start_workers().map_err(|err| {
+            TraceError::EtwNativeError(crate::native::EvntraceNativeError::IoError(err))
+        })?;
+
+        let flusher = flush_interval.map(|interval| {
+            private::Flusher::spawn(
+                *controller.properties(),
+                control_handle,
+                interval,
+                Arc::clone(callback_data),
+            )
+        });
+        let mut trace = T::build(controller, consumer);
+        if let Some(flusher) = flusher {
+            trace.set_flusher(flusher);
+        }
+        Ok((trace, trace_handle))
+    }
+
+    /// Hash of the session configuration, as recorded in [`ChainOfCustody::session_config_hash`]
+    fn config_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write(self.name.as_bytes());
+        // Hashed field by field, so that the hash does not depend on the `Debug` impl of the properties
+        let properties = &self.properties;
+        hasher.write(&properties.buffer_size.to_le_bytes());
+        hasher.write(&properties.min_buffer.to_le_bytes());
+        hasher.write(&properties.max_buffer.to_le_bytes());
+        hasher.write(&properties.flush_timer.as_millis().to_le_bytes());
+        hasher.write(&properties.log_file_mode.bits().to_le_bytes());
+        hasher.write(&self.settings.clock_type.client_context().to_le_bytes());
+        hasher.write(&self.settings.flush_threshold.to_le_bytes());
+        if let Some(dump_file) = &self.etl_dump_file {
+            hasher.write(dump_file.file_path.to_string_lossy().as_bytes());
+            hasher.write(&dump_file.file_logging_mode.bits().to_le_bytes());
+            hasher.write(&dump_file.max_size.unwrap_or(0).to_le_bytes());
+        }
+        for prov in &self.rt_callback_data.providers() {
+            hasher.write(format!("{:?}", prov.guid()).as_bytes());
+            hasher.write(&[prov.level()]);
+            hasher.write(&prov.any().to_le_bytes());
+            hasher.write(&prov.all().to_le_bytes());
+            hasher.write(&prov.trace_flags().bits().to_le_bytes());
+        }
+        hasher.finish()
     }
 
     /// Convenience method that calls [`TraceBuilder::start`] then `process`
@@ -545,12 +1668,116 @@ impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
     pub fn start_and_process(self) -> TraceResult<T> {
         let (trace, trace_handle) = self.start()?;
 
-        std::thread::spawn(move || UserTrace::process_from_handle(trace_handle));
+        let callback_data = Arc::clone(trace.callback_data());
+        std::thread::spawn(move || {
+            callback_data
+                .processing_hooks()
+                .run_around(|| UserTrace::process_from_handle(trace_handle))
+        });
 
         Ok(trace)
     }
 }
 
+#[cfg(feature = "kernel")]
+impl TraceBuilder<KernelTrace> {
+    /// Capture the call stack of the given kernel events
+    ///
+    /// Each of these events is followed by a `StackWalk` event, that can be decoded with [`StackWalk::from_record`](crate::stack_walk::StackWalk::from_record).
+    /// The providers of these events must be enabled as well. At most 256 events are supported.
+    ///
+    /// # Example
+    /// ```
+    /// # use ferrisetw::provider::{kernel_providers, Provider};
+    /// # use ferrisetw::stack_walk::ClassicEventId;
+    /// # use ferrisetw::trace::KernelTrace;
+    /// let process_provider = Provider::kernel(&kernel_providers::PROCESS_PROVIDER).build();
+    /// let process_start = ClassicEventId::new(kernel_providers::PROCESS_PROVIDER.guid, 1);
+    /// let builder = KernelTrace::new()
+    ///     .enable(process_provider)
+    ///     .enable_stack_tracing(&[process_start]);
+    /// ```
+    pub fn enable_stack_tracing(mut self, events: &[ClassicEventId]) -> Self {
+        self.stack_tracing
+            .extend(events.iter().map(|event| event.to_native()));
+        self
+    }
+}
+
+/// The providers to pass to `EnableTraceEx2`: one per GUID, providers sharing a GUID being merged (see [`Provider::merge`])
+fn providers_to_enable(providers: &[Arc<Provider>]) -> TraceResult<Vec<Arc<Provider>>> {
+    let mut groups: Vec<Vec<Arc<Provider>>> = Vec::new();
+    for provider in providers {
+        match groups
+            .iter_mut()
+            .find(|group| group[0].guid() == provider.guid())
+        {
+            Some(group) => group.push(Arc::clone(provider)),
+            None => groups.push(vec![Arc::clone(provider)]),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|group| match group.len() {
+            1 => Ok(Arc::clone(&group[0])),
+            _ => Provider::merge(&group)
+                .map(Arc::new)
+                .ok_or(TraceError::ConflictingProviders(group[0].guid())),
+        })
+        .collect()
+}
+
+/// Enable every provider of a real-time trace
+///
+/// Every provider is attempted (concurrently, unless `parallelism` is 1), and every error is reported.
+fn enable_providers(
+    control_handle: ControlHandle,
+    callback_data: &Arc<CallbackData>,
+    timeout: Option<Duration>,
+    parallelism: usize,
+) -> TraceResult<()> {
+    let providers = providers_to_enable(&callback_data.providers())?;
+    let enable_one = |provider: &Arc<Provider>| {
+        let thread_provider = Arc::clone(provider);
+        run_with_timeout(
+            format!("EnableTraceEx2({:?})", provider.guid()),
+            timeout,
+            move || enable_provider(control_handle, &thread_provider),
+        )
+    };
+
+    let errors = if parallelism <= 1 {
+        providers
+            .iter()
+            .filter_map(|provider| enable_one(provider).err().map(|err| (provider.guid(), err)))
+            .collect()
+    } else {
+        let next_index = AtomicUsize::new(0);
+        let errors = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            for _ in 0..parallelism.min(providers.len()) {
+                scope.spawn(|| loop {
+                    let index = next_index.fetch_add(1, Ordering::Relaxed);
+                    let provider = match providers.get(index) {
+                        Some(provider) => provider,
+                        None => break,
+                    };
+                    if let Err(err) = enable_one(provider) {
+                        errors.lock().unwrap().push((provider.guid(), err));
+                    }
+                });
+            }
+        });
+        errors.into_inner().unwrap()
+    };
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(TraceError::EnableProviders(errors))
+    }
+}
+
 impl FileTrace {
     /// Create a trace that will read events from a file
     #[allow(clippy::new_ret_no_self)]
@@ -561,16 +1788,175 @@ impl FileTrace {
         FileTraceBuilder {
             etl_file_path: path,
             callback: Box::new(callback),
+            processing_hooks: ProcessingHooks::default(),
+            replay_speed: ReplaySpeed::Unthrottled,
+            predicates: Vec::new(),
+            panic_handler: PanicHandler::default(),
+            error_callback: None,
+            buffer_callback: None,
+            time_range: None,
+            ordering_check: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
         }
     }
 
+    /// The header of the ETL file, with information about the machine and session it has been captured on
+    ///
+    /// This is available as soon as the trace is opened, i.e. before it is processed.
+    pub fn log_file_header(&self) -> &LogFileHeader {
+        &self.log_file_header
+    }
+
     fn non_consuming_stop(&mut self) -> TraceResult<()> {
-        close_trace(self.trace_handle, &self.callback_data)?;
+        if std::mem::replace(&mut self.closed, true) {
+            return Ok(());
+        }
+        close_trace(self.trace_handle)?;
         Ok(())
     }
 }
 
 impl FileTraceBuilder {
+    /// Only invoke the callback for events that match `pred`
+    ///
+    /// Adding multiple predicates will bind them with an `AND` relationship.<br/>
+    /// See [`crate::predicate`] for more info.
+    pub fn filter_with(mut self, pred: Pred) -> Self {
+        self.predicates.push(pred);
+        self
+    }
+
+    /// Pace the delivery of events according to their original timestamps
+    ///
+    /// This is useful to replay recorded traffic in a realistic way, e.g. when developing detection logic.<br/>
+    /// By default, events are delivered as fast as possible. Non-positive (or non-finite) factors are ignored.
+    pub fn replay_speed(mut self, speed: ReplaySpeed) -> Self {
+        match speed {
+            ReplaySpeed::Factor(factor) if !(factor.is_finite() && factor > 0.0) => {
+                log::warn!("Ignoring invalid replay speed factor {}", factor);
+            }
+            _ => self.replay_speed = speed,
+        }
+        self
+    }
+
+    /// Only process the events recorded between `start` and `end`
+    ///
+    /// This is given to `ProcessTrace`, so that events out of this window are skipped by Windows, and never reach the callback.
+    /// This makes it cheap to look for a window of interest in a large ETL file.<br/>
+    /// A window that ends before it starts is ignored.
+    ///
+    /// When several file traces are processed together (see [`TraceSet`]), the union of their windows is processed.
+    #[cfg(feature = "time_rs")]
+    pub fn between(mut self, start: time::OffsetDateTime, end: time::OffsetDateTime) -> Self {
+        if end < start {
+            log::warn!(
+                "Ignoring time range that ends ({}) before it starts ({})",
+                end,
+                start
+            );
+        } else {
+            self.time_range = Some((
+                crate::native::time::quad_from_date_time(start),
+                crate::native::time::quad_from_date_time(end),
+            ));
+        }
+        self
+    }
+
+    /// Set a closure that is run on the processing thread, right before the blocking call to `ProcessTrace`
+    ///
+    /// See [`TraceBuilder::on_processing_start`]
+    pub fn on_processing_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.processing_hooks.set_on_start(Box::new(hook));
+        self
+    }
+
+    /// Set a closure that is run on the processing thread, right after the blocking call to `ProcessTrace` has returned
+    ///
+    /// See [`TraceBuilder::on_processing_start`]
+    pub fn on_processing_end<F>(mut self, hook: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.processing_hooks.set_on_end(Box::new(hook));
+        self
+    }
+
+    /// Set what happens when the callback panics
+    ///
+    /// See [`TraceBuilder::on_callback_panic`]
+    pub fn on_callback_panic(mut self, policy: PanicPolicy) -> Self {
+        self.panic_handler.set_policy(policy);
+        self
+    }
+
+    /// Send every panic caught in the callback to `reporter`
+    ///
+    /// See [`TraceBuilder::report_callback_panics`]
+    pub fn report_callback_panics(mut self, reporter: mpsc::Sender<CallbackPanic>) -> Self {
+        self.panic_handler.set_reporter(reporter);
+        self
+    }
+
+    /// Set a closure that is notified of the errors that happen while processing events
+    ///
+    /// See [`TraceBuilder::set_error_callback`]
+    pub fn set_error_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&EventError) + Send + Sync + 'static,
+    {
+        self.error_callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Set a closure that is run every time a buffer of events has been read from the file
+    ///
+    /// See [`TraceBuilder::set_buffer_callback`]
+    pub fn set_buffer_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&BufferStats) -> ControlFlow<()> + Send + 'static,
+    {
+        self.buffer_callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Check that the events of every processor are delivered with increasing timestamps
+    ///
+    /// See [`TraceBuilder::check_timestamp_order`]
+    pub fn check_timestamp_order(mut self, policy: OrderingPolicy) -> Self {
+        self.ordering_check
+            .get_or_insert_with(OrderingCheck::default)
+            .set_policy(policy);
+        self
+    }
+
+    /// Set how the timestamp of an event is read by the order checks
+    ///
+    /// See [`TraceBuilder::timestamp_source`]
+    pub fn timestamp_source<F>(mut self, source: F) -> Self
+    where
+        F: Fn(&EventRecord) -> i64 + Send + Sync + 'static,
+    {
+        self.ordering_check
+            .get_or_insert_with(OrderingCheck::default)
+            .set_timestamp_source(Box::new(source));
+        self
+    }
+
+    /// Randomly drop or delay events before they reach the callback
+    ///
+    /// See [`TraceBuilder::chaos`]. Since files deliver the same events every time, a given seed drops the same events every time.
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
     /// Build the `FileTrace` and start the trace session
     ///
     /// See the documentation for [`TraceBuilder::start`] for more information.
@@ -578,9 +1964,29 @@ impl FileTraceBuilder {
         // Prepare a wide version of the source ETL file path
         let wide_etl_file_path = U16CString::from_os_str_truncate(self.etl_file_path.as_os_str());
 
-        let from_file_cb = CallbackDataFromFile::new(self.callback);
+        let mut from_file_cb = CallbackDataFromFile::new(
+            self.callback,
+            self.processing_hooks,
+            self.replay_speed,
+            self.predicates,
+            self.panic_handler,
+            self.error_callback,
+        );
+        if let Some(buffer_callback) = self.buffer_callback {
+            from_file_cb.set_buffer_callback(buffer_callback);
+        }
+        if let Some((start, end)) = self.time_range {
+            from_file_cb.set_time_range(start, end);
+        }
+        if let Some(ordering_check) = self.ordering_check {
+            from_file_cb.set_ordering_check(ordering_check);
+        }
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = self.chaos {
+            from_file_cb.set_chaos(chaos);
+        }
         let callback_data = Box::new(Arc::new(CallbackData::FromFile(from_file_cb)));
-        let trace_handle = open_trace(
+        let (trace_handle, log_file_header) = open_trace(
             SubscriptionSource::FromFile(wide_etl_file_path),
             &callback_data,
         )?;
@@ -589,6 +1995,8 @@ impl FileTraceBuilder {
             FileTrace {
                 trace_handle,
                 callback_data,
+                log_file_header,
+                closed: false,
             },
             trace_handle,
         ))
@@ -602,24 +2010,17 @@ impl FileTraceBuilder {
     pub fn start_and_process(self) -> TraceResult<FileTrace> {
         let (trace, trace_handle) = self.start()?;
 
-        std::thread::spawn(move || FileTrace::process_from_handle(trace_handle));
+        let callback_data = Arc::clone(trace.callback_data());
+        std::thread::spawn(move || {
+            callback_data
+                .processing_hooks()
+                .run_around(|| FileTrace::process_from_handle(trace_handle))
+        });
 
         Ok(trace)
     }
 }
 
-impl Drop for UserTrace {
-    fn drop(&mut self) {
-        let _ignored_error_in_drop = self.non_consuming_stop();
-    }
-}
-
-impl Drop for KernelTrace {
-    fn drop(&mut self) {
-        let _ignored_error_in_drop = self.non_consuming_stop();
-    }
-}
-
 impl Drop for FileTrace {
     fn drop(&mut self) {
         let _ignored_error_in_drop = self.non_consuming_stop();
@@ -661,4 +2062,109 @@ mod test {
 
         assert_eq!(trace_builder.rt_callback_data.providers().len(), 2);
     }
+
+    #[test]
+    fn test_config_hash() {
+        let hash = UserTrace::new().named(String::from("hashed")).config_hash();
+        assert_eq!(
+            UserTrace::new().named(String::from("hashed")).config_hash(),
+            hash
+        );
+        assert_ne!(
+            UserTrace::new()
+                .named(String::from("hashed"))
+                .clock_type(ClockType::SystemTime)
+                .config_hash(),
+            hash
+        );
+        assert_ne!(
+            UserTrace::new()
+                .named(String::from("hashed"))
+                .flush_threshold(10)
+                .config_hash(),
+            hash
+        );
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let builder = UserTrace::new().flush_every(Duration::ZERO);
+        assert_eq!(builder.flush_interval, Some(MIN_FLUSH_INTERVAL));
+        let builder = UserTrace::new().flush_every(Duration::from_secs(1));
+        assert_eq!(builder.flush_interval, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_name_template() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            expand_name_template("agent-{pid}"),
+            format!("agent-{}", pid)
+        );
+        assert!(!expand_name_template("agent-{timestamp}").contains('{'));
+        assert_eq!(expand_name_template("plain"), "plain");
+
+        assert!(unique_name("agent").starts_with("agent-"));
+        assert_ne!(unique_name("agent"), unique_name("agent"));
+
+        assert!(prefixed_name("my-agent").starts_with(&format!("my-agent-{}-", pid)));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        use crate::native::EvntraceNativeError;
+
+        let policy = RetryPolicy::new(3, Duration::from_millis(1));
+        assert_eq!(policy.backoff(1), Duration::from_millis(1));
+        assert_eq!(policy.backoff(3), Duration::from_millis(4));
+        assert_eq!(policy.backoff(40), Duration::from_secs(1));
+
+        let mut calls = 0;
+        let result: Result<(), _> = policy.run("test", || {
+            calls += 1;
+            Err(EvntraceNativeError::AlreadyExist)
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = policy.run("test", || {
+            calls += 1;
+            Err(EvntraceNativeError::InvalidHandle)
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_dump_file_mode() {
+        let rt = LoggingMode::EVENT_TRACE_REAL_TIME_MODE;
+        let buffering = LoggingMode::EVENT_TRACE_BUFFERING_MODE;
+
+        assert!(check_dump_file_mode(DumpFileLoggingMode::default(), rt, false).is_ok());
+        assert!(check_dump_file_mode(
+            DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_CIRCULAR,
+            LoggingMode::empty(),
+            true
+        )
+        .is_ok());
+        assert!(check_dump_file_mode(
+            DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_CIRCULAR
+                | DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_SEQUENTIAL,
+            rt,
+            false
+        )
+        .is_err());
+
+        // Appending is not possible in real-time sessions
+        let append = DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_APPEND
+            | DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_SEQUENTIAL;
+        assert!(check_dump_file_mode(append, LoggingMode::empty(), false).is_err());
+        assert!(check_dump_file_mode(append, buffering, false).is_ok());
+
+        // The NT Kernel Logger does not support new files
+        let newfile = DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE;
+        assert!(check_dump_file_mode(newfile, rt, false).is_ok());
+        assert!(check_dump_file_mode(newfile, rt, true).is_err());
+    }
 }
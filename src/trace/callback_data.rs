@@ -1,12 +1,25 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::RwLock;
+use std::any::Any;
+use std::ops::ControlFlow;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
+use once_cell::sync::OnceCell;
+use windows::core::GUID;
 use windows::Win32::System::Diagnostics::Etw;
 
 use crate::native::etw_types::event_record::EventRecord;
+use crate::native::time::TimestampConverter;
+use crate::predicate::Pred;
 use crate::provider::Provider;
 use crate::schema_locator::SchemaLocator;
-use crate::trace::RealTimeTraceTrait;
+use crate::sink::LostEventKind;
+#[cfg(feature = "chaos")]
+use crate::trace::chaos::{Chaos, ChaosState, ChaosStats};
+use crate::trace::dispatch::{Dispatch, Dispatched, WorkerPool};
+use crate::trace::ordering::{CallbackOrdering, OrderingCheck, OrderingStats, ReorderBuffer};
+use crate::trace::{RealTimeTraceTrait, ReplaySpeed, SessionStats};
 use crate::EtwCallback;
 
 /// Data used by callbacks when the trace is running
@@ -18,13 +31,35 @@ pub enum CallbackData {
     FromFile(CallbackDataFromFile),
 }
 
-#[derive(Debug)]
 pub struct RealTimeCallbackData {
     /// Represents how many events have been handled so far
     events_handled: AtomicUsize,
     schema_locator: SchemaLocator,
     /// List of Providers associated with the Trace. This also owns the callback closures and their state
-    providers: Vec<Provider>,
+    ///
+    /// Providers can be added or removed while the trace is running
+    providers: RwLock<Vec<Arc<Provider>>>,
+    /// Callbacks that receive every event of the trace, regardless of its provider
+    trace_callbacks: RwLock<Vec<EtwCallback>>,
+    processing_hooks: ProcessingHooks,
+    panic_handler: PanicHandler,
+    buffer_callback: Mutex<Option<BufferCallback>>,
+    /// See [`crate::trace::TraceBuilder::check_timestamp_order`]
+    ordering_check: Option<OrderingCheck>,
+    /// See [`crate::trace::TraceBuilder::callback_ordering`]
+    reorder_buffer: Option<ReorderBuffer>,
+    /// See [`crate::trace::TraceBuilder::raw_timestamps`]
+    raw_timestamps: bool,
+    /// Converts the raw timestamps into system times. This is set once the session is opened
+    timestamp_converter: OnceCell<TimestampConverter>,
+    loss_counters: LossCounters,
+    /// See [`crate::trace::TraceBuilder::dispatch`]
+    dispatch: Dispatch,
+    /// The workers of [`Dispatch::ThreadPool`]. They are spawned once the session is opened
+    worker_pool: OnceCell<WorkerPool>,
+    /// See [`crate::trace::TraceBuilder::chaos`]
+    #[cfg(feature = "chaos")]
+    chaos: Option<ChaosState>,
 }
 
 pub struct CallbackDataFromFile {
@@ -33,13 +68,548 @@ pub struct CallbackDataFromFile {
     schema_locator: SchemaLocator,
     /// This trace is reading from an ETL file, and has a single callback
     callback: RwLock<EtwCallback>,
+    processing_hooks: ProcessingHooks,
+    replay_pacer: ReplayPacer,
+    /// Software filters, evaluated before invoking the callback
+    predicates: Vec<Pred>,
+    panic_handler: PanicHandler,
+    buffer_callback: Mutex<Option<BufferCallback>>,
+    /// See [`crate::trace::FileTraceBuilder::between`]
+    time_range: Option<(i64, i64)>,
+    /// See [`crate::trace::FileTraceBuilder::check_timestamp_order`]
+    ordering_check: Option<OrderingCheck>,
+    loss_counters: LossCounters,
+    /// See [`crate::trace::FileTraceBuilder::chaos`]
+    #[cfg(feature = "chaos")]
+    chaos: Option<ChaosState>,
+}
+
+/// Delays the delivery of events read from a file, so that they are spaced the same way they have been recorded
+#[derive(Debug)]
+struct ReplayPacer {
+    speed: ReplaySpeed,
+    /// Timestamp of the first event, and when it has been delivered
+    origin: Mutex<Option<(i64, Instant)>>,
+}
+
+impl ReplayPacer {
+    fn new(speed: ReplaySpeed) -> Self {
+        Self {
+            speed,
+            origin: Mutex::new(None),
+        }
+    }
+
+    /// Block until it is time to deliver an event that has been recorded at `timestamp`
+    fn wait_for(&self, timestamp: i64) {
+        let factor = match self.speed {
+            ReplaySpeed::Unthrottled => return,
+            ReplaySpeed::Realtime => 1.0,
+            ReplaySpeed::Factor(factor) => factor,
+        };
+
+        let (first_timestamp, start) = match self.origin.lock() {
+            Ok(mut origin) => *origin.get_or_insert((timestamp, Instant::now())),
+            Err(_) => return,
+        };
+
+        let target = start + replay_offset(first_timestamp, timestamp, factor);
+        let now = Instant::now();
+        if target > now {
+            std::thread::sleep(target - now);
+        }
+    }
+}
+
+/// How long after the first event an event should be delivered
+///
+/// Timestamps are expressed in 100ns intervals. Events that are out of order are delivered immediately.
+fn replay_offset(first_timestamp: i64, timestamp: i64, factor: f64) -> Duration {
+    let recorded_offset = timestamp.saturating_sub(first_timestamp).max(0);
+    Duration::from_secs_f64(recorded_offset as f64 / 10_000_000.0 / factor)
+}
+
+/// What to do when a callback panics
+///
+/// Panics cannot unwind across the native ETW frames, so they are always caught before returning to Windows.<br/>
+/// See [`crate::trace::TraceBuilder::on_callback_panic`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Log the panic, drop the event being processed and keep processing the following events
+    LogAndContinue,
+    /// Log the panic, ignore every further event and make `ProcessTrace` return as soon as the current buffer is done
+    ///
+    /// The session itself is not stopped: that is still up to the owner of the trace.
+    StopTrace,
+    /// Log the panic and exit the process
+    #[default]
+    Abort,
+}
+
+/// A panic that has been caught in a callback, see [`crate::trace::TraceBuilder::report_callback_panics`]
+#[derive(Debug, Clone)]
+pub struct CallbackPanic {
+    /// The panic payload, if it was a string
+    pub message: String,
+    /// The provider of the event that was being processed, or a null GUID in case the buffer callback (see [`crate::trace::TraceBuilder::set_buffer_callback`]) has panicked
+    pub provider_id: GUID,
+    /// The ID of the event that was being processed, or 0 in case the buffer callback has panicked
+    pub event_id: u16,
+}
+
+impl std::fmt::Display for CallbackPanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.provider_id == GUID::zeroed() {
+            return write!(f, "buffer callback panicked: {}", self.message);
+        }
+        write!(
+            f,
+            "callback panicked on event {} of provider {:?}: {}",
+            self.event_id, self.provider_id, self.message
+        )
+    }
+}
+
+/// Statistics about a buffer that has just been delivered, see [`crate::trace::TraceBuilder::set_buffer_callback`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BufferStats {
+    /// How many buffers have been delivered so far
+    pub buffers_read: u32,
+    /// The size of the buffer, in bytes
+    pub buffer_size: u32,
+    /// How many bytes of the buffer contain events
+    pub filled: u32,
+    /// How many events the session has lost so far
+    pub events_lost: u32,
+    /// How many buffers the session has lost so far
+    pub buffers_lost: u32,
+    /// How many events have been handled by the trace so far
+    pub events_handled: usize,
+}
+
+/// How many events and buffers a session has lost so far, see [`crate::trace::TraceTrait::events_lost`]
+///
+/// Losses are counted from two sources, that are kept apart: the `RT_LostEvent` notifications, and the cumulative counters of the session (as reported with every buffer, or queried from Windows).
+/// They overlap (a notified loss is also counted by the session), so they cannot be added up: the larger of the two is reported.
+#[derive(Debug, Default)]
+pub struct LossCounters {
+    /// How many `RT_LostEvent` notifications have been received
+    notified_events_lost: AtomicU64,
+    notified_buffers_lost: AtomicU64,
+    /// The latest cumulative counters of the session
+    session_events_lost: AtomicU64,
+    session_buffers_lost: AtomicU64,
+}
+
+impl LossCounters {
+    /// A loss has been notified by the session
+    pub fn record(&self, kind: LostEventKind) {
+        match kind {
+            LostEventKind::Events => {
+                self.notified_events_lost.fetch_add(1, Ordering::Relaxed);
+            }
+            LostEventKind::Buffers => {
+                self.notified_buffers_lost.fetch_add(1, Ordering::Relaxed);
+            }
+            LostEventKind::File => (),
+        }
+    }
+
+    /// The session reports that it has lost (at least) this many events and buffers since it started
+    pub fn update(&self, events_lost: u64, buffers_lost: u64) {
+        // Cumulative counters only grow, but updates from different threads may arrive out of order
+        self.session_events_lost
+            .fetch_max(events_lost, Ordering::Relaxed);
+        self.session_buffers_lost
+            .fetch_max(buffers_lost, Ordering::Relaxed);
+    }
+
+    pub fn update_from_stats(&self, stats: &SessionStats) {
+        self.update(
+            stats.events_lost as u64,
+            stats.real_time_buffers_lost as u64 + stats.log_buffers_lost as u64,
+        );
+    }
+
+    pub fn events_lost(&self) -> u64 {
+        self.notified_events_lost
+            .load(Ordering::Relaxed)
+            .max(self.session_events_lost.load(Ordering::Relaxed))
+    }
+
+    pub fn buffers_lost(&self) -> u64 {
+        self.notified_buffers_lost
+            .load(Ordering::Relaxed)
+            .max(self.session_buffers_lost.load(Ordering::Relaxed))
+    }
+}
+
+impl BufferStats {
+    /// How full the buffer was, between `0.0` and `1.0`
+    pub fn fill_level(&self) -> f64 {
+        if self.buffer_size == 0 {
+            return 0.0;
+        }
+        f64::from(self.filled) / f64::from(self.buffer_size)
+    }
+}
+
+/// The callback set by [`crate::trace::TraceBuilder::set_buffer_callback`]
+pub type BufferCallback = Box<dyn FnMut(&BufferStats) -> ControlFlow<()> + Send + 'static>;
+
+/// An error that happened while processing the events of a trace, see [`crate::trace::TraceBuilder::set_error_callback`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum EventError {
+    /// The schema of an event could not be retrieved
+    Schema {
+        provider_id: GUID,
+        event_id: u16,
+        message: String,
+    },
+    /// The session has lost something, as reported by an `RT_LostEvent` event
+    Lost(LostEventKind),
+    /// A callback has panicked (see [`PanicPolicy`])
+    CallbackPanic(CallbackPanic),
+    /// An event is older than the previous event of the same processor (see [`crate::trace::TraceBuilder::check_timestamp_order`])
+    OutOfOrder {
+        processor: u16,
+        timestamp: i64,
+        /// The most recent timestamp of this processor so far
+        previous: i64,
+    },
+    /// The [`Dispatch::ThreadPool`] worker of this provider has exited, so that its event has been delivered on the thread that processes the trace instead
+    WorkerGone { provider_id: GUID },
+}
+
+/// The callback set by [`crate::trace::TraceBuilder::set_error_callback`]
+pub type ErrorCallback = Arc<dyn Fn(&EventError) + Send + Sync + 'static>;
+
+/// Applies the [`PanicPolicy`] of a trace
+#[derive(Default)]
+pub struct PanicHandler {
+    policy: PanicPolicy,
+    reporter: Option<Mutex<mpsc::Sender<CallbackPanic>>>,
+    stop_requested: AtomicBool,
+}
+
+impl PanicHandler {
+    pub fn set_policy(&mut self, policy: PanicPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_reporter(&mut self, reporter: mpsc::Sender<CallbackPanic>) {
+        self.reporter = Some(Mutex::new(reporter));
+    }
+
+    /// Whether a panic has requested the trace to stop (see [`PanicPolicy::StopTrace`])
+    pub fn stop_requested(&self) -> bool {
+        self.stop_requested.load(Ordering::Relaxed)
+    }
+
+    /// Run `callback`, and apply the policy in case it panics
+    fn run<F: FnOnce()>(&self, record: &EventRecord, locator: &SchemaLocator, callback: F) {
+        if let Err(payload) = std::panic::catch_unwind(AssertUnwindSafe(callback)) {
+            self.on_panic(
+                CallbackPanic {
+                    message: panic_message(payload.as_ref()),
+                    provider_id: record.provider_id(),
+                    event_id: record.event_id(),
+                },
+                locator,
+            );
+        }
+    }
+
+    /// Run the buffer callback, and apply the policy in case it panics. Returns whether processing should go on
+    fn run_buffer_callback<F: FnOnce() -> bool>(
+        &self,
+        locator: &SchemaLocator,
+        callback: F,
+    ) -> bool {
+        match std::panic::catch_unwind(AssertUnwindSafe(callback)) {
+            Ok(keep_processing) => keep_processing,
+            Err(payload) => {
+                self.on_panic(
+                    CallbackPanic {
+                        message: panic_message(payload.as_ref()),
+                        provider_id: GUID::zeroed(),
+                        event_id: 0,
+                    },
+                    locator,
+                );
+                !self.stop_requested()
+            }
+        }
+    }
+
+    fn on_panic(&self, panic: CallbackPanic, locator: &SchemaLocator) {
+        log::error!("{panic}");
+
+        locator.report_error(EventError::CallbackPanic(panic.clone()));
+        if let Some(reporter) = &self.reporter {
+            if let Ok(reporter) = reporter.lock() {
+                // The receiver may have been dropped. There is nothing to do about it.
+                let _ = reporter.send(panic);
+            }
+        }
+
+        match self.policy {
+            PanicPolicy::LogAndContinue => {}
+            PanicPolicy::StopTrace => self.stop_requested.store(true, Ordering::Relaxed),
+            PanicPolicy::Abort => std::process::exit(1),
+        }
+    }
+}
+
+impl std::fmt::Debug for PanicHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PanicHandler")
+            .field("policy", &self.policy)
+            .field("reporter", &self.reporter.is_some())
+            .field("stop_requested", &self.stop_requested)
+            .finish()
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
+}
+
+/// A closure run on the processing thread, see [`ProcessingHooks`]
+pub type ProcessingHook = Box<dyn Fn() + Send + Sync + 'static>;
+
+/// Closures that are run on the processing thread, right before and right after the (blocking) call to `ProcessTrace`
+///
+/// This is useful to set up thread-local state (e.g. COM initialization, thread priority, etc.) that the callbacks rely on.
+#[derive(Default)]
+pub struct ProcessingHooks {
+    on_start: Option<ProcessingHook>,
+    on_end: Option<ProcessingHook>,
+}
+
+impl ProcessingHooks {
+    pub fn set_on_start(&mut self, hook: ProcessingHook) {
+        self.on_start = Some(hook);
+    }
+
+    pub fn set_on_end(&mut self, hook: ProcessingHook) {
+        self.on_end = Some(hook);
+    }
+
+    /// Run `process` between the start and end hooks.
+    ///
+    /// The end hook is run regardless of the result of `process`.
+    pub fn run_around<R, F: FnOnce() -> R>(&self, process: F) -> R {
+        if let Some(on_start) = &self.on_start {
+            on_start();
+        }
+        let result = process();
+        if let Some(on_end) = &self.on_end {
+            on_end();
+        }
+        result
+    }
+}
+
+impl std::fmt::Debug for ProcessingHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProcessingHooks")
+            .field("on_start", &self.on_start.is_some())
+            .field("on_end", &self.on_end.is_some())
+            .finish()
+    }
 }
 
 impl CallbackData {
     pub fn on_event(&self, record: &EventRecord) {
-        match self {
+        let panic_handler = self.panic_handler();
+        if panic_handler.stop_requested() {
+            return;
+        }
+        let schema_locator = self.schema_locator();
+        if let Some(kind) = LostEventKind::from_record(record) {
+            self.loss_counters().record(kind);
+            schema_locator.report_error(EventError::Lost(kind));
+        }
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = self.chaos() {
+            if !chaos.let_through() {
+                return;
+            }
+        }
+        if let Some(ordering_check) = self.ordering_check() {
+            if !ordering_check.check(record, schema_locator) {
+                return;
+            }
+        }
+        match self.reorder_buffer() {
+            None => self.dispatch(record),
+            Some(reorder_buffer) => {
+                for event in reorder_buffer.push(record, schema_locator) {
+                    self.dispatch(&event);
+                }
+            }
+        }
+    }
+
+    /// Deliver the events that are still held back (see [`CallbackOrdering::Strict`]), and wait for the workers of [`Dispatch::ThreadPool`] to handle their queues.
+    /// This is called once processing has ended.
+    pub fn flush_pending(&self) {
+        if let Some(reorder_buffer) = self.reorder_buffer() {
+            for event in reorder_buffer.drain() {
+                self.dispatch(&event);
+            }
+        }
+        if let Some(worker_pool) = self.worker_pool() {
+            worker_pool.shutdown();
+        }
+    }
+
+    /// Hand the event over to the worker pool (see [`Dispatch::ThreadPool`]), or invoke the callbacks right away
+    fn dispatch(&self, record: &EventRecord) {
+        match self
+            .worker_pool()
+            .map(|worker_pool| worker_pool.dispatch(record))
+        {
+            Some(Dispatched::Handled) => {}
+            Some(Dispatched::WorkerGone) => {
+                self.schema_locator().report_error(EventError::WorkerGone {
+                    provider_id: record.provider_id(),
+                });
+                self.deliver(record);
+            }
+            Some(Dispatched::ShutDown) | None => self.deliver(record),
+        }
+    }
+
+    /// Invoke the callbacks
+    pub(super) fn deliver(&self, record: &EventRecord) {
+        let panic_handler = self.panic_handler();
+        if panic_handler.stop_requested() {
+            return;
+        }
+        panic_handler.run(record, self.schema_locator(), || match self {
             CallbackData::RealTime(rt_cb) => rt_cb.on_event(record),
             CallbackData::FromFile(f_cb) => f_cb.on_event(record),
+        });
+    }
+
+    /// Called after every buffer. Returns whether `ProcessTrace` should keep processing the trace
+    pub fn on_buffer(&self, stats: &BufferStats) -> bool {
+        self.loss_counters()
+            .update(stats.events_lost as u64, stats.buffers_lost as u64);
+        if let Some(reorder_buffer) = self.reorder_buffer() {
+            for event in reorder_buffer.release_expired(Instant::now()) {
+                self.dispatch(&event);
+            }
+        }
+        if self.panic_handler().stop_requested() {
+            return false;
+        }
+        let buffer_callback = match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.buffer_callback,
+            CallbackData::FromFile(f_cb) => &f_cb.buffer_callback,
+        };
+        self.panic_handler()
+            .run_buffer_callback(self.schema_locator(), || {
+                // The lock is poisoned in case the callback has panicked. It can still be used.
+                let mut buffer_callback = buffer_callback
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                match buffer_callback.as_mut() {
+                    Some(callback) => callback(stats).is_continue(),
+                    None => true,
+                }
+            })
+    }
+
+    pub fn loss_counters(&self) -> &LossCounters {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.loss_counters,
+            CallbackData::FromFile(f_cb) => &f_cb.loss_counters,
+        }
+    }
+
+    fn schema_locator(&self) -> &SchemaLocator {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.schema_locator,
+            CallbackData::FromFile(f_cb) => &f_cb.schema_locator,
+        }
+    }
+
+    fn ordering_check(&self) -> Option<&OrderingCheck> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.ordering_check.as_ref(),
+            CallbackData::FromFile(f_cb) => f_cb.ordering_check.as_ref(),
+        }
+    }
+
+    fn worker_pool(&self) -> Option<&WorkerPool> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.worker_pool.get(),
+            CallbackData::FromFile(_) => None,
+        }
+    }
+
+    /// Spawn the workers of [`Dispatch::ThreadPool`], in case the trace uses it
+    pub(crate) fn start_workers(self: &Arc<Self>) -> std::io::Result<()> {
+        if let CallbackData::RealTime(rt_cb) = self.as_ref() {
+            if let Dispatch::ThreadPool {
+                workers,
+                queue_len,
+                overflow,
+            } = rt_cb.dispatch
+            {
+                let pool = WorkerPool::spawn(workers, queue_len, overflow, Arc::downgrade(self))?;
+                let _ = rt_cb.worker_pool.set(pool);
+            }
+        }
+        Ok(())
+    }
+
+    /// How many events the workers of [`Dispatch::ThreadPool`] have dropped
+    pub fn events_dropped(&self) -> u64 {
+        self.worker_pool().map_or(0, WorkerPool::dropped)
+    }
+
+    #[cfg(feature = "chaos")]
+    fn chaos(&self) -> Option<&ChaosState> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.chaos.as_ref(),
+            CallbackData::FromFile(f_cb) => f_cb.chaos.as_ref(),
+        }
+    }
+
+    /// How many events have been dropped or delayed, in case chaos has been enabled
+    #[cfg(feature = "chaos")]
+    pub fn chaos_stats(&self) -> Option<ChaosStats> {
+        self.chaos().map(ChaosState::stats)
+    }
+
+    fn reorder_buffer(&self) -> Option<&ReorderBuffer> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.reorder_buffer.as_ref(),
+            CallbackData::FromFile(_) => None,
+        }
+    }
+
+    /// The counters of the timestamp order checks, in case they are enabled
+    pub fn ordering_stats(&self) -> Option<OrderingStats> {
+        self.ordering_check().map(OrderingCheck::stats)
+    }
+
+    pub fn panic_handler(&self) -> &PanicHandler {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.panic_handler,
+            CallbackData::FromFile(f_cb) => &f_cb.panic_handler,
         }
     }
 
@@ -49,6 +619,67 @@ impl CallbackData {
             CallbackData::FromFile(f_cb) => f_cb.events_handled(),
         }
     }
+
+    /// The time range of the events to process, as `FILETIME` quads (only file traces can have one)
+    pub fn time_range(&self) -> Option<(i64, i64)> {
+        match self {
+            CallbackData::RealTime(_) => None,
+            CallbackData::FromFile(f_cb) => f_cb.time_range,
+        }
+    }
+
+    /// The providers of real-time traces (file traces have none)
+    pub fn providers(&self) -> Vec<Arc<Provider>> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.providers(),
+            CallbackData::FromFile(_) => Vec::new(),
+        }
+    }
+
+    pub fn processing_hooks(&self) -> &ProcessingHooks {
+        match self {
+            CallbackData::RealTime(rt_cb) => &rt_cb.processing_hooks,
+            CallbackData::FromFile(f_cb) => &f_cb.processing_hooks,
+        }
+    }
+
+    /// How many schemas have been cached by the schema locator of this trace
+    pub fn cached_schemas(&self) -> usize {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.schema_locator.cached_schemas(),
+            CallbackData::FromFile(f_cb) => f_cb.schema_locator.cached_schemas(),
+        }
+    }
+
+    /// Whether the session delivers timestamps in its own clock, rather than in system time (only real-time traces can)
+    pub fn raw_timestamps(&self) -> bool {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.raw_timestamps,
+            CallbackData::FromFile(_) => false,
+        }
+    }
+
+    /// Converts the raw timestamps of the session, in case it delivers some
+    pub fn timestamp_converter(&self) -> Option<TimestampConverter> {
+        match self {
+            CallbackData::RealTime(rt_cb) => rt_cb.timestamp_converter.get().copied(),
+            CallbackData::FromFile(_) => None,
+        }
+    }
+
+    pub(crate) fn set_timestamp_converter(&self, converter: TimestampConverter) {
+        if let CallbackData::RealTime(rt_cb) = self {
+            let _ = rt_cb.timestamp_converter.set(converter);
+        }
+    }
+
+    /// How many trace-wide predicates are evaluated before the callbacks (only file traces have some)
+    pub fn predicate_count(&self) -> usize {
+        match self {
+            CallbackData::RealTime(_) => 0,
+            CallbackData::FromFile(f_cb) => f_cb.predicates.len(),
+        }
+    }
 }
 
 impl std::default::Default for RealTimeCallbackData {
@@ -56,7 +687,20 @@ impl std::default::Default for RealTimeCallbackData {
         Self {
             events_handled: AtomicUsize::new(0),
             schema_locator: SchemaLocator::new(),
-            providers: Vec::new(),
+            providers: RwLock::new(Vec::new()),
+            trace_callbacks: RwLock::new(Vec::new()),
+            processing_hooks: ProcessingHooks::default(),
+            panic_handler: PanicHandler::default(),
+            buffer_callback: Mutex::new(None),
+            ordering_check: None,
+            reorder_buffer: None,
+            raw_timestamps: false,
+            timestamp_converter: OnceCell::new(),
+            dispatch: Dispatch::default(),
+            worker_pool: OnceCell::new(),
+            loss_counters: LossCounters::default(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
         }
     }
 }
@@ -66,12 +710,93 @@ impl RealTimeCallbackData {
         Default::default()
     }
 
-    pub fn add_provider(&mut self, provider: Provider) {
-        self.providers.push(provider)
+    /// Add a provider. This can be called while the trace is running
+    pub fn add_provider(&self, provider: Arc<Provider>) {
+        if let Ok(mut providers) = self.providers.write() {
+            providers.push(provider);
+        }
+    }
+
+    /// Remove this exact provider. This can be called while the trace is running
+    pub fn remove_provider(&self, provider: &Arc<Provider>) {
+        if let Ok(mut providers) = self.providers.write() {
+            providers.retain(|prov| !Arc::ptr_eq(prov, provider));
+        }
+    }
+
+    /// Remove every provider with this GUID. This can be called while the trace is running
+    pub fn remove_providers(&self, guid: GUID) {
+        if let Ok(mut providers) = self.providers.write() {
+            providers.retain(|prov| prov.guid() != guid);
+        }
+    }
+
+    /// A snapshot of the current providers
+    pub fn providers(&self) -> Vec<Arc<Provider>> {
+        match self.providers.read() {
+            Ok(providers) => providers.clone(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Add a callback that receives every event of the trace. This can be called while the trace is running
+    pub fn add_trace_callback(&self, callback: EtwCallback) {
+        if let Ok(mut callbacks) = self.trace_callbacks.write() {
+            callbacks.push(callback);
+        }
+    }
+
+    pub fn processing_hooks_mut(&mut self) -> &mut ProcessingHooks {
+        &mut self.processing_hooks
+    }
+
+    pub fn panic_handler_mut(&mut self) -> &mut PanicHandler {
+        &mut self.panic_handler
+    }
+
+    /// The timestamp order checks, that are enabled by this call
+    pub(crate) fn ordering_check_mut(&mut self) -> &mut OrderingCheck {
+        self.ordering_check
+            .get_or_insert_with(OrderingCheck::default)
+    }
+
+    pub fn set_callback_ordering(&mut self, ordering: CallbackOrdering) {
+        self.reorder_buffer = match ordering {
+            CallbackOrdering::AsDelivered => None,
+            CallbackOrdering::Strict {
+                max_pending,
+                max_delay,
+            } => Some(ReorderBuffer::new(max_pending, max_delay)),
+        };
+    }
+
+    pub fn set_dispatch(&mut self, dispatch: Dispatch) {
+        self.dispatch = dispatch;
+    }
+
+    pub(crate) fn dispatch(&self) -> Dispatch {
+        self.dispatch
+    }
+
+    pub(crate) fn strict_ordering(&self) -> bool {
+        self.reorder_buffer.is_some()
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(ChaosState::new(chaos));
+    }
+
+    pub fn set_error_callback(&mut self, callback: ErrorCallback) {
+        self.schema_locator.set_error_callback(callback);
+    }
+
+    pub fn set_buffer_callback(&mut self, callback: BufferCallback) {
+        self.buffer_callback = Mutex::new(Some(callback));
     }
 
-    pub fn providers(&self) -> &[Provider] {
-        &self.providers
+    pub fn set_raw_timestamps(&mut self, enabled: bool) {
+        self.raw_timestamps = enabled;
     }
 
     /// How many events have been handled since this instance was created
@@ -80,29 +805,112 @@ impl RealTimeCallbackData {
     }
 
     pub fn provider_flags<T: RealTimeTraceTrait>(&self) -> Etw::EVENT_TRACE_FLAG {
-        Etw::EVENT_TRACE_FLAG(T::enable_flags(&self.providers))
+        Etw::EVENT_TRACE_FLAG(T::enable_flags(&self.providers()))
     }
 
     pub fn on_event(&self, record: &EventRecord) {
         self.events_handled.fetch_add(1, Ordering::Relaxed);
 
-        for prov in &self.providers {
-            if prov.guid() == record.provider_id() {
-                prov.on_event(record, &self.schema_locator);
+        if let Ok(providers) = self.providers.read() {
+            let matching = |prov: &&Arc<Provider>| prov.guid() == record.provider_id();
+            // Providers that share a GUID have been enabled with merged settings (see `Provider::merge`)
+            let shared = providers.iter().filter(matching).nth(1).is_some();
+            for prov in providers.iter().filter(matching) {
+                if !shared || prov.accepts(record) {
+                    prov.on_event(record, &self.schema_locator);
+                }
             }
         }
+
+        // A callback that panicked (see `PanicPolicy::LogAndContinue`) has poisoned the lock, but the callbacks are still usable
+        let mut callbacks = self
+            .trace_callbacks
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        callbacks
+            .iter_mut()
+            .for_each(|cb| cb(record, &self.schema_locator));
+    }
+}
+
+impl std::fmt::Debug for RealTimeCallbackData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("RealTimeCallbackData");
+        debug
+            .field("events_handled", &self.events_handled)
+            .field("schema_locator", &self.schema_locator)
+            .field("providers", &self.providers)
+            .field(
+                "trace_callbacks",
+                &self.trace_callbacks.read().map(|cbs| cbs.len()),
+            )
+            .field("processing_hooks", &self.processing_hooks)
+            .field("panic_handler", &self.panic_handler)
+            .field(
+                "buffer_callback",
+                &self.buffer_callback.try_lock().map(|cb| cb.is_some()).ok(),
+            )
+            .field("ordering_check", &self.ordering_check)
+            .field("reorder_buffer", &self.reorder_buffer)
+            .field("raw_timestamps", &self.raw_timestamps)
+            .field("timestamp_converter", &self.timestamp_converter.get())
+            .field("loss_counters", &self.loss_counters)
+            .field("dispatch", &self.dispatch)
+            .field("worker_pool", &self.worker_pool.get());
+        #[cfg(feature = "chaos")]
+        debug.field("chaos", &self.chaos);
+        debug.finish()
     }
 }
 
 impl CallbackDataFromFile {
-    pub fn new(callback: EtwCallback) -> Self {
+    pub fn new(
+        callback: EtwCallback,
+        processing_hooks: ProcessingHooks,
+        replay_speed: ReplaySpeed,
+        predicates: Vec<Pred>,
+        panic_handler: PanicHandler,
+        error_callback: Option<ErrorCallback>,
+    ) -> Self {
+        let mut schema_locator = SchemaLocator::new();
+        if let Some(error_callback) = error_callback {
+            schema_locator.set_error_callback(error_callback);
+        }
         Self {
             events_handled: AtomicUsize::new(0),
-            schema_locator: SchemaLocator::new(),
+            schema_locator,
             callback: RwLock::new(callback),
+            processing_hooks,
+            replay_pacer: ReplayPacer::new(replay_speed),
+            predicates,
+            panic_handler,
+            buffer_callback: Mutex::new(None),
+            time_range: None,
+            ordering_check: None,
+            loss_counters: LossCounters::default(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
         }
     }
 
+    pub fn set_buffer_callback(&mut self, callback: BufferCallback) {
+        self.buffer_callback = Mutex::new(Some(callback));
+    }
+
+    /// Set the start and end timestamps of the events to process, as `FILETIME` quads
+    pub fn set_time_range(&mut self, start: i64, end: i64) {
+        self.time_range = Some((start, end));
+    }
+
+    pub(crate) fn set_ordering_check(&mut self, check: OrderingCheck) {
+        self.ordering_check = Some(check);
+    }
+
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(ChaosState::new(chaos));
+    }
+
     /// How many events have been handled since this instance was created
     pub fn events_handled(&self) -> usize {
         self.events_handled.load(Ordering::Relaxed)
@@ -110,17 +918,396 @@ impl CallbackDataFromFile {
 
     pub fn on_event(&self, record: &EventRecord) {
         self.events_handled.fetch_add(1, Ordering::Relaxed);
-        if let Ok(mut cb) = self.callback.write() {
-            cb(record, &self.schema_locator);
+        if !self
+            .predicates
+            .iter()
+            .all(|pred| pred.matches(record, &self.schema_locator))
+        {
+            return;
         }
+        self.replay_pacer.wait_for(record.raw_timestamp());
+        // See `RealTimeCallbackData::on_event` about poisoning
+        let mut cb = self
+            .callback
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        cb(record, &self.schema_locator);
     }
 }
 
 impl std::fmt::Debug for CallbackDataFromFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("CallbackDataFromFile")
+        let mut debug = f.debug_struct("CallbackDataFromFile");
+        debug
             .field("events_handled", &self.events_handled)
             .field("schema_locator", &self.schema_locator)
-            .finish()
+            .field("processing_hooks", &self.processing_hooks)
+            .field("replay_pacer", &self.replay_pacer)
+            .field("predicates", &self.predicates.len())
+            .field("panic_handler", &self.panic_handler)
+            .field(
+                "buffer_callback",
+                &self.buffer_callback.try_lock().map(|cb| cb.is_some()).ok(),
+            )
+            .field("time_range", &self.time_range)
+            .field("ordering_check", &self.ordering_check)
+            .field("loss_counters", &self.loss_counters);
+        #[cfg(feature = "chaos")]
+        debug.field("chaos", &self.chaos);
+        debug.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use crate::trace::dispatch::Overflow;
+    use std::sync::{Arc, Mutex, Weak};
+
+    #[test]
+    fn test_panic_policy() {
+        let event = SyntheticEvent::new().with_opcode(1);
+        let record = event.record();
+
+        let locator = SchemaLocator::new();
+        let (tx, rx) = mpsc::channel();
+        let mut handler = PanicHandler::default();
+        handler.set_policy(PanicPolicy::LogAndContinue);
+        handler.set_reporter(tx);
+        handler.run(record, &locator, || panic!("boom"));
+        assert!(!handler.stop_requested());
+
+        let panic = rx.try_recv().unwrap();
+        assert_eq!(panic.message, "boom");
+        assert_eq!(panic.event_id, record.event_id());
+
+        handler.set_policy(PanicPolicy::StopTrace);
+        handler.run(record, &locator, || {});
+        assert!(!handler.stop_requested());
+        handler.run(record, &locator, || {
+            panic!("{}", String::from("boom again"))
+        });
+        assert!(handler.stop_requested());
+        assert_eq!(rx.try_recv().unwrap().message, "boom again");
+    }
+
+    #[test]
+    fn test_providers_sharing_a_guid() {
+        let guid = GUID::from_u128(0x784);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let rt_cb = RealTimeCallbackData::new();
+        for (name, any) in [("first", 0x1), ("second", 0x2)] {
+            let log = Arc::clone(&received);
+            rt_cb.add_provider(Arc::new(
+                Provider::by_guid(guid)
+                    .any(any)
+                    .add_callback(move |record, _| {
+                        log.lock().unwrap().push((name, record.keyword()))
+                    })
+                    .build(),
+            ));
+        }
+        let callback_data = CallbackData::RealTime(rt_cb);
+
+        for keyword in [0x1, 0x2, 0x3] {
+            let event = SyntheticEvent::new()
+                .with_provider(guid)
+                .with_keyword(keyword);
+            callback_data.on_event(event.record());
+        }
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![
+                ("first", 0x1),
+                ("second", 0x2),
+                ("first", 0x3),
+                ("second", 0x3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_buffer_callback() {
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_buffer_callback(Box::new(|stats: &BufferStats| {
+            if stats.buffers_read < 2 {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        }));
+        let callback_data = CallbackData::RealTime(rt_cb);
+
+        let mut stats = BufferStats {
+            buffers_read: 1,
+            buffer_size: 64 * 1024,
+            filled: 16 * 1024,
+            events_lost: 0,
+            buffers_lost: 0,
+            events_handled: 0,
+        };
+        assert_eq!(stats.fill_level(), 0.25);
+        assert!(callback_data.on_buffer(&stats));
+        stats.buffers_read = 2;
+        assert!(!callback_data.on_buffer(&stats));
+    }
+
+    #[test]
+    fn test_buffer_callback_panic() {
+        let (tx, rx) = mpsc::channel();
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_buffer_callback(Box::new(|stats: &BufferStats| {
+            if stats.buffers_read == 2 {
+                panic!("buffer boom");
+            }
+            ControlFlow::Continue(())
+        }));
+        rt_cb.panic_handler_mut().set_policy(PanicPolicy::StopTrace);
+        rt_cb.panic_handler_mut().set_reporter(tx);
+        let callback_data = CallbackData::RealTime(rt_cb);
+
+        let mut stats = BufferStats {
+            buffers_read: 1,
+            buffer_size: 64 * 1024,
+            filled: 16 * 1024,
+            events_lost: 0,
+            buffers_lost: 0,
+            events_handled: 0,
+        };
+        assert!(callback_data.on_buffer(&stats));
+        stats.buffers_read = 2;
+        assert!(!callback_data.on_buffer(&stats));
+        let panic = rx.try_recv().unwrap();
+        assert_eq!(panic.message, "buffer boom");
+        assert_eq!(panic.provider_id, GUID::zeroed());
+        assert!(callback_data.panic_handler().stop_requested());
+    }
+
+    #[test]
+    fn test_error_callback() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors_clone = Arc::clone(&errors);
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_error_callback(Arc::new(move |err: &EventError| {
+            errors_clone.lock().unwrap().push(format!("{:?}", err))
+        }));
+        rt_cb
+            .panic_handler_mut()
+            .set_policy(PanicPolicy::LogAndContinue);
+        rt_cb.add_trace_callback(Box::new(|record, _| {
+            if record.opcode() == 1 {
+                panic!("boom")
+            }
+        }));
+        let callback_data = CallbackData::RealTime(rt_cb);
+
+        // An RT_LostEvent event reporting lost buffers
+        let lost = SyntheticEvent::new()
+            .with_provider(GUID::from_u128(0x6a399ae0_4bc6_4de9_870b_3657f8947e7e))
+            .with_opcode(33);
+        callback_data.on_event(lost.record());
+        let panicking = SyntheticEvent::new().with_opcode(1);
+        callback_data.on_event(panicking.record());
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "Lost(Buffers)");
+        assert!(errors[1].starts_with("CallbackPanic"));
+        assert_eq!(callback_data.events_handled(), 2);
+    }
+
+    #[test]
+    fn test_loss_counters() {
+        let callback_data = CallbackData::RealTime(RealTimeCallbackData::new());
+        let lost_guid = GUID::from_u128(0x6a399ae0_4bc6_4de9_870b_3657f8947e7e);
+        for opcode in [32, 32, 33, 34] {
+            let lost = SyntheticEvent::new()
+                .with_provider(lost_guid)
+                .with_opcode(opcode);
+            callback_data.on_event(lost.record());
+        }
+        let counters = callback_data.loss_counters();
+        assert_eq!((counters.events_lost(), counters.buffers_lost()), (2, 1));
+
+        // The counters of the session are cumulative, and more accurate than the notifications
+        callback_data.on_buffer(&BufferStats {
+            buffers_read: 1,
+            buffer_size: 64 * 1024,
+            filled: 0,
+            events_lost: 10,
+            buffers_lost: 0,
+            events_handled: 4,
+        });
+        assert_eq!((counters.events_lost(), counters.buffers_lost()), (10, 1));
+        // This loss is already counted by the session
+        callback_data.on_event(
+            SyntheticEvent::new()
+                .with_provider(lost_guid)
+                .with_opcode(32)
+                .record(),
+        );
+        assert_eq!((counters.events_lost(), counters.buffers_lost()), (10, 1));
+        counters.update_from_stats(&SessionStats {
+            events_lost: 12,
+            real_time_buffers_lost: 2,
+            log_buffers_lost: 1,
+            ..Default::default()
+        });
+        assert_eq!((counters.events_lost(), counters.buffers_lost()), (12, 3));
+    }
+
+    #[test]
+    fn test_strict_callback_ordering() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_callback_ordering(CallbackOrdering::Strict {
+            max_pending: 3,
+            max_delay: Duration::from_secs(3600),
+        });
+        let log = Arc::clone(&delivered);
+        rt_cb.add_trace_callback(Box::new(move |record, _locator| {
+            log.lock().unwrap().push(record.raw_timestamp())
+        }));
+        let callback_data = CallbackData::RealTime(rt_cb);
+
+        // Two providers on two processors, whose buffers are delivered one after the other
+        for (provider, processor, timestamp) in [
+            (1, 0, 10),
+            (1, 0, 30),
+            (1, 0, 50),
+            (2, 1, 20),
+            (2, 1, 40),
+            (2, 1, 60),
+        ] {
+            let event = SyntheticEvent::new()
+                .with_provider(GUID::from_u128(provider))
+                .with_processor(processor)
+                .with_timestamp(timestamp);
+            callback_data.on_event(event.record());
+        }
+        assert_eq!(*delivered.lock().unwrap(), vec![10, 20, 30]);
+
+        callback_data.flush_pending();
+        assert_eq!(*delivered.lock().unwrap(), vec![10, 20, 30, 40, 50, 60]);
+        assert_eq!(callback_data.events_handled(), 6);
+    }
+
+    #[test]
+    fn test_thread_pool_dispatch() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_dispatch(Dispatch::ThreadPool {
+            workers: 2,
+            queue_len: 4,
+            overflow: Overflow::Block,
+        });
+        let log = Arc::clone(&delivered);
+        rt_cb.add_trace_callback(Box::new(move |record, _locator| {
+            log.lock().unwrap().push((
+                record.provider_id(),
+                record.raw_timestamp(),
+                std::thread::current().id(),
+            ))
+        }));
+        let callback_data = Arc::new(CallbackData::RealTime(rt_cb));
+        callback_data.start_workers().unwrap();
+
+        let processing_thread = std::thread::current().id();
+        for timestamp in 0..20 {
+            let event = SyntheticEvent::new()
+                .with_provider(GUID::from_u128(1 + timestamp as u128 % 2))
+                .with_timestamp(timestamp);
+            callback_data.on_event(event.record());
+        }
+        callback_data.flush_pending();
+
+        let delivered = delivered.lock().unwrap().clone();
+        assert_eq!(delivered.len(), 20);
+        assert!(delivered
+            .iter()
+            .all(|(_, _, thread)| *thread != processing_thread));
+        for provider in [1, 2] {
+            let timestamps: Vec<_> = delivered
+                .iter()
+                .filter(|(guid, _, _)| *guid == GUID::from_u128(provider))
+                .map(|(_, timestamp, _)| *timestamp)
+                .collect();
+            assert_eq!(timestamps.len(), 10);
+            assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        assert_eq!(callback_data.events_dropped(), 0);
+        assert_eq!(callback_data.events_handled(), 20);
+    }
+
+    #[test]
+    fn test_thread_pool_overflow() {
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = Mutex::new(blocked);
+        let mut rt_cb = RealTimeCallbackData::new();
+        rt_cb.set_dispatch(Dispatch::ThreadPool {
+            workers: 1,
+            queue_len: 1,
+            overflow: Overflow::Drop,
+        });
+        rt_cb.add_trace_callback(Box::new(move |_record, _locator| {
+            let _ = blocked.lock().unwrap().recv();
+        }));
+        let callback_data = Arc::new(CallbackData::RealTime(rt_cb));
+        callback_data.start_workers().unwrap();
+
+        // The worker is stuck on (at most) the first event, and its queue has room for a single other one
+        for _ in 0..5 {
+            callback_data.on_event(SyntheticEvent::new().record());
+        }
+        assert!(callback_data.events_dropped() >= 3);
+
+        drop(release);
+        callback_data.flush_pending();
+        assert_eq!(
+            callback_data.events_handled() as u64 + callback_data.events_dropped(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_thread_pool_worker_gone() {
+        // Without callback data, the worker exits as soon as it receives an event
+        let pool = WorkerPool::spawn(1, 0, Overflow::Block, Weak::new()).unwrap();
+        let event = SyntheticEvent::new();
+        assert_eq!(pool.dispatch(event.record()), Dispatched::Handled);
+        assert_eq!(pool.dispatch(event.record()), Dispatched::WorkerGone);
+        assert_eq!(pool.dropped(), 0);
+        pool.shutdown();
+        assert_eq!(pool.dispatch(event.record()), Dispatched::ShutDown);
+    }
+
+    #[test]
+    fn test_processing_hooks_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = ProcessingHooks::default();
+        let start_log = Arc::clone(&log);
+        hooks.set_on_start(Box::new(move || start_log.lock().unwrap().push("start")));
+        let end_log = Arc::clone(&log);
+        hooks.set_on_end(Box::new(move || end_log.lock().unwrap().push("end")));
+
+        let result: Result<(), ()> = hooks.run_around(|| {
+            log.lock().unwrap().push("process");
+            Err(())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(*log.lock().unwrap(), vec!["start", "process", "end"]);
+    }
+
+    #[test]
+    fn test_replay_offset() {
+        let one_second = 10_000_000;
+        assert_eq!(replay_offset(0, one_second, 1.0), Duration::from_secs(1));
+        assert_eq!(
+            replay_offset(0, one_second, 4.0),
+            Duration::from_millis(250)
+        );
+        assert_eq!(replay_offset(one_second, 0, 1.0), Duration::ZERO);
     }
 }
@@ -0,0 +1,171 @@
+//! Randomly drop or delay events before they reach the callbacks
+//!
+//! ETW loses events under load, and delivers them late when callbacks lag behind. Code that consumes events (e.g. correlators that match the start of an operation with its end)
+//! should tolerate this, which is hard to test against real sessions.<br/>
+//! See [`TraceBuilder::chaos`](super::TraceBuilder::chaos) to simulate it. This is only available with the `chaos` feature, which should only be enabled in tests.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// How events are dropped or delayed, see [the module documentation](self)
+///
+/// Decisions are drawn from a random generator seeded with `seed`: a trace that delivers the same events in the same order (e.g. a [`FileTrace`](super::FileTrace)) drops the same ones every time.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use ferrisetw::trace::Chaos;
+/// // Drop 1% of the events, and delay 5% of them by up to 10ms
+/// let chaos = Chaos::new(42)
+///     .drop_probability(0.01)
+///     .delay(0.05, Duration::from_millis(10));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    seed: u64,
+    drop_probability: f64,
+    delay_probability: f64,
+    max_delay: Duration,
+}
+
+impl Chaos {
+    /// A configuration that neither drops nor delays events, until told otherwise
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            drop_probability: 0.0,
+            delay_probability: 0.0,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    /// Drop every event with this probability (between 0 and 1, non-finite values count as 0)
+    pub fn drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = sanitize_probability(probability);
+        self
+    }
+
+    /// Delay every event with this probability (between 0 and 1, non-finite values count as 0), by a random duration up to `max_delay`
+    ///
+    /// The thread that delivers events sleeps meanwhile, so that the events that follow are delayed as well (and the session may lose buffers).
+    pub fn delay(mut self, probability: f64, max_delay: Duration) -> Self {
+        self.delay_probability = sanitize_probability(probability);
+        self.max_delay = max_delay;
+        self
+    }
+}
+
+/// Clamp a probability to `[0, 1]`. `f64::clamp` keeps NaN, which `Rng::gen_bool` would panic on
+fn sanitize_probability(probability: f64) -> f64 {
+    if probability.is_finite() {
+        probability.clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+/// How many events have been dropped or delayed, see [`TraceTrait::chaos_stats`](super::TraceTrait::chaos_stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ChaosStats {
+    pub dropped: u64,
+    pub delayed: u64,
+}
+
+/// Applies a [`Chaos`] configuration to the events of a trace
+#[derive(Debug)]
+pub(crate) struct ChaosState {
+    chaos: Chaos,
+    rng: Mutex<StdRng>,
+    dropped: AtomicU64,
+    delayed: AtomicU64,
+}
+
+impl ChaosState {
+    pub fn new(chaos: Chaos) -> Self {
+        Self {
+            chaos,
+            rng: Mutex::new(StdRng::seed_from_u64(chaos.seed)),
+            dropped: AtomicU64::new(0),
+            delayed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the next event should be delivered. This sleeps in case it should be delayed
+    pub fn let_through(&self) -> bool {
+        let delay = {
+            let mut rng = self.rng.lock().unwrap_or_else(PoisonError::into_inner);
+            if rng.gen_bool(self.chaos.drop_probability) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            if !rng.gen_bool(self.chaos.delay_probability) {
+                return true;
+            }
+            rng.gen_range(Duration::ZERO..=self.chaos.max_delay)
+        };
+        self.delayed.fetch_add(1, Ordering::Relaxed);
+        std::thread::sleep(delay);
+        true
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        ChaosStats {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decisions(chaos: Chaos, count: usize) -> Vec<bool> {
+        let state = ChaosState::new(chaos);
+        (0..count).map(|_| state.let_through()).collect()
+    }
+
+    #[test]
+    fn test_drop_probability() {
+        let chaos = Chaos::new(7).drop_probability(0.25);
+        let state = ChaosState::new(chaos);
+        let delivered = (0..10_000).filter(|_| state.let_through()).count();
+        let stats = state.stats();
+        assert_eq!(stats.dropped as usize, 10_000 - delivered);
+        assert!((2_000..3_000).contains(&stats.dropped), "{:?}", stats);
+        assert_eq!(stats.delayed, 0);
+
+        // The same seed drops the same events
+        assert_eq!(decisions(chaos, 1000), decisions(chaos, 1000));
+        assert_ne!(
+            decisions(chaos, 1000),
+            decisions(Chaos::new(8).drop_probability(0.25), 1000)
+        );
+
+        assert!(decisions(Chaos::new(7), 1000).iter().all(|d| *d));
+        assert!(decisions(Chaos::new(7).drop_probability(2.0), 1000)
+            .iter()
+            .all(|d| !*d));
+        assert!(decisions(Chaos::new(7).drop_probability(f64::NAN), 1000)
+            .iter()
+            .all(|d| *d));
+        assert!(decisions(
+            Chaos::new(7).delay(f64::NAN, Duration::from_micros(100)),
+            1000
+        )
+        .iter()
+        .all(|d| *d));
+    }
+
+    #[test]
+    fn test_delays() {
+        let state = ChaosState::new(Chaos::new(7).delay(0.5, Duration::from_micros(100)));
+        assert!((0..100).all(|_| state.let_through()));
+        let stats = state.stats();
+        assert_eq!(stats.dropped, 0);
+        assert!((20..80).contains(&stats.delayed), "{:?}", stats);
+    }
+}
@@ -0,0 +1,110 @@
+//! Consume the events of an ETW session, independently of whoever controls it
+use std::sync::Arc;
+
+use widestring::U16CString;
+
+use super::callback_data::{CallbackData, RealTimeCallbackData};
+use super::{private, TraceError, TraceResult, TraceTrait};
+use crate::native::etw_types::SubscriptionSource;
+use crate::native::evntrace::{close_trace, open_trace, TraceHandle};
+use crate::provider::Provider;
+use crate::{EventRecord, SchemaLocator};
+
+/// The consuming side of a real-time ETW session: receive its events and dispatch them to callbacks
+///
+/// Unlike [`UserTrace`](crate::UserTrace) and [`KernelTrace`](crate::KernelTrace), this does not control the session, that may have been started by anyone.
+/// See [`SessionController`](super::SessionController) to control a session.<br/>
+/// Several consumers may read the same session at the same time.
+///
+/// The session is closed (but not stopped) when this is dropped.
+#[derive(Debug)]
+#[allow(clippy::redundant_allocation)] // see https://github.com/n4r1b/ferrisetw/issues/72
+pub struct Consumer {
+    trace_handle: TraceHandle,
+    // CallbackData is `Arc`ed and `Boxed`, see `UserTrace`
+    callback_data: Box<Arc<CallbackData>>,
+    /// The trace handle has been closed already (e.g. by [`TraceTrait::stop`]), and must not be closed again on drop
+    closed: bool,
+}
+
+impl Consumer {
+    #[allow(clippy::redundant_allocation)]
+    pub(crate) fn new(trace_handle: TraceHandle, callback_data: Box<Arc<CallbackData>>) -> Self {
+        Self {
+            trace_handle,
+            callback_data,
+            closed: false,
+        }
+    }
+
+    /// Open a running real-time session, to consume its events
+    ///
+    /// Events are dispatched to the callbacks of `providers`, according to their GUIDs. These providers are not enabled on the session (see [`SessionController::enable_provider`](super::SessionController::enable_provider)).<br/>
+    /// Events are only delivered once [`TraceTrait::process`] (or [`TraceTrait::process_from_handle`]) is called.
+    pub fn open(session_name: &str, providers: Vec<Provider>) -> TraceResult<Self> {
+        let wide_name =
+            U16CString::from_str(session_name).map_err(|_| TraceError::InvalidTraceName)?;
+
+        let rt_callback_data = RealTimeCallbackData::new();
+        for provider in providers {
+            rt_callback_data.add_provider(Arc::new(provider));
+        }
+        let callback_data = Box::new(Arc::new(CallbackData::RealTime(rt_callback_data)));
+        let (trace_handle, _header) = open_trace(
+            SubscriptionSource::RealTimeSession(wide_name),
+            &callback_data,
+        )?;
+        Ok(Self::new(trace_handle, callback_data))
+    }
+
+    /// Add a callback that receives every event of the session, regardless of its provider
+    ///
+    /// This can be called while the session is being processed.
+    pub fn add_callback<F>(&self, callback: F)
+    where
+        F: FnMut(&EventRecord, &SchemaLocator) + Send + Sync + 'static,
+    {
+        if let CallbackData::RealTime(rt_cb) = self.callback_data.as_ref().as_ref() {
+            rt_cb.add_trace_callback(Box::new(callback));
+        }
+    }
+
+    pub(crate) fn rt_callback_data(&self) -> &RealTimeCallbackData {
+        match self.callback_data.as_ref().as_ref() {
+            CallbackData::RealTime(rt_cb) => rt_cb,
+            CallbackData::FromFile(_) => {
+                unreachable!("a Consumer always has real-time callback data")
+            }
+        }
+    }
+}
+
+impl TraceTrait for Consumer {
+    fn trace_handle(&self) -> TraceHandle {
+        self.trace_handle
+    }
+
+    fn events_handled(&self) -> usize {
+        self.callback_data.events_handled()
+    }
+}
+
+impl private::PrivateTraceTrait for Consumer {
+    fn non_consuming_stop(&mut self) -> TraceResult<()> {
+        if std::mem::replace(&mut self.closed, true) {
+            return Ok(());
+        }
+        close_trace(self.trace_handle)?;
+        Ok(())
+    }
+
+    fn callback_data(&self) -> &Arc<CallbackData> {
+        &self.callback_data
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        let _ignored_error_in_drop = private::PrivateTraceTrait::non_consuming_stop(self);
+    }
+}
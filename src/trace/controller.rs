@@ -0,0 +1,160 @@
+//! Control an ETW session, independently of whoever consumes its events
+use std::ffi::OsString;
+
+use widestring::U16CString;
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::{
+    flush_session, query_session, report_lost_events, sessions, update_real_time_mode,
+    SessionStats, TraceError, TraceProperties, TraceResult, UserTrace,
+};
+use crate::native::etw_types::EventTraceProperties;
+use crate::native::evntrace::{
+    control_trace, control_trace_by_name, control_trace_copy, disable_provider, enable_provider,
+    ControlHandle,
+};
+use crate::provider::Provider;
+use crate::trace::diagnostics::SessionDump;
+
+/// The controlling side of an ETW session: enable and disable providers, query and stop the session
+///
+/// Unlike [`UserTrace`] and [`KernelTrace`](crate::KernelTrace), this does not receive any event. See [`Consumer`](super::Consumer) for that.<br/>
+/// A `SessionController` is obtained either from a trace started by this crate (see [`UserTrace::into_parts`]), or by attaching to a running session (see [`SessionController::attach`]).
+///
+/// Sessions started by this crate are stopped when their controller is dropped. Sessions that have been attached to are left running.
+#[derive(Debug)]
+pub struct SessionController {
+    properties: EventTraceProperties,
+    control_handle: ControlHandle,
+    /// Whether the session should be stopped when this is dropped
+    owned: bool,
+}
+
+impl SessionController {
+    pub(crate) fn new(properties: EventTraceProperties, control_handle: ControlHandle) -> Self {
+        Self {
+            properties,
+            control_handle,
+            owned: true,
+        }
+    }
+
+    /// Control a session that is already running (and that has typically been started by another process)
+    ///
+    /// The session is not stopped when the returned controller is dropped, but it can be explicitly stopped with [`SessionController::stop`].
+    pub fn attach(session_name: &str) -> TraceResult<Self> {
+        let wide_name =
+            U16CString::from_str(session_name).map_err(|_| TraceError::InvalidTraceName)?;
+        let mut properties = EventTraceProperties::new::<UserTrace>(
+            &wide_name,
+            None,
+            &TraceProperties::default(),
+            Etw::EVENT_TRACE_FLAG::default(),
+        );
+        control_trace_by_name(&mut properties, &wide_name, Etw::EVENT_TRACE_CONTROL_QUERY)?;
+
+        let control_handle = ControlHandle {
+            Value: unsafe {
+                // Safety: on output, Windows stores the logger ID (that is also the session handle) in this member of the union
+                properties.native().Wnode.Anonymous1.HistoricalContext
+            },
+        };
+        Ok(Self {
+            properties,
+            control_handle,
+            owned: false,
+        })
+    }
+
+    /// The name of the session
+    pub fn name(&self) -> OsString {
+        self.properties.name()
+    }
+
+    /// The handle of the session, as used by the Windows API (e.g. `EnableTraceEx2`)
+    pub fn control_handle(&self) -> ControlHandle {
+        self.control_handle
+    }
+
+    /// Enable a provider on this session
+    ///
+    /// The callbacks of `provider` are ignored: events are delivered to whatever [`Consumer`](super::Consumer) reads this session.
+    pub fn enable_provider(&self, provider: &Provider) -> TraceResult<()> {
+        enable_provider(self.control_handle, provider)?;
+        Ok(())
+    }
+
+    /// Disable a provider on this session
+    pub fn disable_provider(&self, guid: GUID) -> TraceResult<()> {
+        disable_provider(self.control_handle, guid)?;
+        Ok(())
+    }
+
+    /// Flush the buffers of the session, see [`TraceTrait::flush`](super::TraceTrait::flush)
+    pub fn flush(&self) -> TraceResult<()> {
+        flush_session(&self.properties, self.control_handle)
+    }
+
+    /// Query the current counters of this session, see [`UserTrace::query_stats`]
+    pub fn query_stats(&self) -> TraceResult<SessionStats> {
+        sessions::query_stats(&self.properties, self.control_handle)
+    }
+
+    /// Start delivering the events of a file-logging session in real time, without restarting it
+    ///
+    /// This issues a `ControlTraceW(EVENT_TRACE_CONTROL_CONVERT_TO_REALTIME)`. This is typically used to tap into a flight-recorder session during an incident:
+    /// once converted, its events can be received with a [`Consumer`](super::Consumer).<br/>
+    /// See [`SessionController::set_real_time`] to go back to file-only logging.
+    pub fn convert_to_real_time(&self) -> TraceResult<()> {
+        control_trace_copy(
+            &self.properties,
+            self.control_handle,
+            Etw::EVENT_TRACE_CONTROL(Etw::EVENT_TRACE_CONTROL_CONVERT_TO_REALTIME),
+        )?;
+        Ok(())
+    }
+
+    /// Turn the real-time delivery of this session on or off
+    ///
+    /// This issues a `ControlTraceW(EVENT_TRACE_CONTROL_UPDATE)` that sets or clears `EVENT_TRACE_REAL_TIME_MODE`, and keeps the other settings of the session (including its log file, if any).<br/>
+    /// Consumers of a session whose real-time delivery is turned off stop receiving events.
+    pub fn set_real_time(&self, enabled: bool) -> TraceResult<()> {
+        update_real_time_mode(&self.properties, self.control_handle, enabled)
+    }
+
+    /// Stops the session
+    ///
+    /// This also stops sessions that have been attached to. Consumers of this session stop receiving events.
+    pub fn stop(mut self) -> TraceResult<()> {
+        self.stop_session()
+    }
+
+    pub(crate) fn properties(&self) -> &EventTraceProperties {
+        &self.properties
+    }
+
+    pub(crate) fn session_dump(&self) -> SessionDump {
+        query_session(&self.properties, self.control_handle)
+    }
+
+    pub(crate) fn stop_session(&mut self) -> TraceResult<()> {
+        control_trace(
+            &mut self.properties,
+            self.control_handle,
+            Etw::EVENT_TRACE_CONTROL_STOP,
+        )?;
+        // Do not stop it again when dropped
+        self.owned = false;
+        report_lost_events(&self.properties);
+        Ok(())
+    }
+}
+
+impl Drop for SessionController {
+    fn drop(&mut self) {
+        if self.owned {
+            let _ignored_error_in_drop = self.stop_session();
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Structured reports about a running trace
+//!
+//! See [`TraceTrait::debug_dump`](crate::trace::TraceTrait::debug_dump).
+//! These are meant to be logged when investigating why some events are missing.
+use std::fmt::{Display, Formatter};
+
+use windows::core::GUID;
+
+use crate::native::etw_types::EventTraceProperties;
+use crate::provider::{Provider, TraceFlags};
+
+/// A snapshot of the state of a trace, see [`TraceTrait::debug_dump`](crate::trace::TraceTrait::debug_dump)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TraceDump {
+    /// The session properties and counters (real-time traces only)
+    pub session: Option<SessionDump>,
+    /// The providers enabled on this trace (real-time traces only)
+    pub providers: Vec<ProviderDump>,
+    /// How many trace-wide predicates are evaluated before the callback (file traces only)
+    pub predicates: usize,
+    /// How many events have been received, regardless of whether they have been filtered out afterwards
+    pub events_handled: usize,
+    /// How many schemas have been cached so far
+    pub cached_schemas: usize,
+}
+
+/// The properties of an ETW session, as reported by Windows
+///
+/// See [EVENT_TRACE_PROPERTIES](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties) for the meaning of each member
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SessionDump {
+    pub name: String,
+    /// Whether these counters have been freshly queried. When `false`, these are the properties the session has been started with
+    pub queried: bool,
+    pub buffer_size: u32,
+    pub minimum_buffers: u32,
+    pub maximum_buffers: u32,
+    pub maximum_file_size: u32,
+    pub log_file_mode: u32,
+    pub flush_timer: u32,
+    pub enable_flags: u32,
+    pub number_of_buffers: u32,
+    pub free_buffers: u32,
+    pub events_lost: u32,
+    pub buffers_written: u32,
+    pub log_buffers_lost: u32,
+    pub real_time_buffers_lost: u32,
+}
+
+impl SessionDump {
+    pub(crate) fn new(properties: &EventTraceProperties, queried: bool) -> Self {
+        let native = properties.native();
+        Self {
+            name: properties.name().to_string_lossy().into_owned(),
+            queried,
+            buffer_size: native.BufferSize,
+            minimum_buffers: native.MinimumBuffers,
+            maximum_buffers: native.MaximumBuffers,
+            maximum_file_size: native.MaximumFileSize,
+            log_file_mode: native.LogFileMode,
+            flush_timer: native.FlushTimer,
+            enable_flags: native.EnableFlags.0,
+            number_of_buffers: native.NumberOfBuffers,
+            free_buffers: native.FreeBuffers,
+            events_lost: native.EventsLost,
+            buffers_written: native.BuffersWritten,
+            log_buffers_lost: native.LogBuffersLost,
+            real_time_buffers_lost: native.RealTimeBuffersLost,
+        }
+    }
+}
+
+/// The configuration of a provider enabled on a trace
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ProviderDump {
+    pub guid: GUID,
+    pub any: u64,
+    pub all: u64,
+    pub level: u8,
+    pub trace_flags: TraceFlags,
+    pub kernel_flags: u32,
+    /// The kernel-side filters, formatted with their `Debug` impl
+    pub filters: Vec<String>,
+    /// How many software filters are evaluated before the callbacks
+    pub predicates: usize,
+    pub callbacks: usize,
+}
+
+impl From<&Provider> for ProviderDump {
+    fn from(provider: &Provider) -> Self {
+        Self {
+            guid: provider.guid(),
+            any: provider.any(),
+            all: provider.all(),
+            level: provider.level(),
+            trace_flags: provider.trace_flags(),
+            kernel_flags: provider.kernel_flags(),
+            filters: provider
+                .filters()
+                .iter()
+                .map(|filter| format!("{:?}", filter))
+                .collect(),
+            predicates: provider.predicates().len(),
+            callbacks: provider.callback_count(),
+        }
+    }
+}
+
+impl Display for TraceDump {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "events handled: {}, cached schemas: {}",
+            self.events_handled, self.cached_schemas
+        )?;
+        if self.predicates > 0 {
+            writeln!(f, "trace predicates: {}", self.predicates)?;
+        }
+
+        if let Some(session) = &self.session {
+            writeln!(
+                f,
+                "session {:?} ({}):",
+                session.name,
+                if session.queried {
+                    "queried"
+                } else {
+                    "as started"
+                }
+            )?;
+            writeln!(
+                f,
+                "  buffers: size={}KB min={} max={} allocated={} free={} written={}",
+                session.buffer_size,
+                session.minimum_buffers,
+                session.maximum_buffers,
+                session.number_of_buffers,
+                session.free_buffers,
+                session.buffers_written
+            )?;
+            writeln!(
+                f,
+                "  lost: events={} log buffers={} real-time buffers={}",
+                session.events_lost, session.log_buffers_lost, session.real_time_buffers_lost
+            )?;
+            writeln!(
+                f,
+                "  log file mode={:#x} max file size={} flush timer={} enable flags={:#x}",
+                session.log_file_mode,
+                session.maximum_file_size,
+                session.flush_timer,
+                session.enable_flags
+            )?;
+        }
+
+        writeln!(f, "providers: {}", self.providers.len())?;
+        for provider in &self.providers {
+            writeln!(
+                f,
+                "  {:?}: level={} any={:#x} all={:#x} trace flags={:?} kernel flags={:#x} predicates={} callbacks={}",
+                provider.guid,
+                provider.level,
+                provider.any,
+                provider.all,
+                provider.trace_flags,
+                provider.kernel_flags,
+                provider.predicates,
+                provider.callbacks
+            )?;
+            for filter in &provider.filters {
+                writeln!(f, "    filter: {}", filter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::provider::EventFilter;
+    use crate::{EventRecord, SchemaLocator};
+
+    #[test]
+    fn test_provider_dump() {
+        let provider = Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+            .level(4)
+            .any(0x10)
+            .add_filter(EventFilter::ByEventIds(vec![1, 2]))
+            .add_callback(|_: &EventRecord, _: &SchemaLocator| {})
+            .build();
+
+        let dump = TraceDump {
+            session: None,
+            providers: vec![ProviderDump::from(&provider)],
+            predicates: 0,
+            events_handled: 3,
+            cached_schemas: 1,
+        };
+        assert_eq!(dump.providers[0].callbacks, 1);
+        assert_eq!(dump.providers[0].filters, vec!["ByEventIds([1, 2])"]);
+
+        let report = dump.to_string();
+        assert!(report.contains("events handled: 3"));
+        assert!(report.contains("level=4 any=0x10"));
+        assert!(report.contains("filter: ByEventIds([1, 2])"));
+    }
+}
@@ -0,0 +1,160 @@
+//! Invoke the callbacks of a trace on worker threads
+//!
+//! By default, callbacks run on the thread that processes the trace (see [`process`](super::TraceTrait::process)), one event after the other.
+//! While a callback runs, ETW keeps filling the buffers of the session: a callback that is too slow makes the session lose buffers.<br/>
+//! See [`TraceBuilder::dispatch`](super::TraceBuilder::dispatch) to hand the events over to worker threads instead.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Mutex, PoisonError, Weak};
+use std::thread::JoinHandle;
+
+use super::callback_data::CallbackData;
+use crate::native::etw_types::event_record::{EventRecord, OwnedEventRecord};
+
+/// Where the callbacks of a trace are invoked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dispatch {
+    /// On the thread that processes the trace
+    #[default]
+    Inline,
+    /// On a pool of `workers` threads, that receive copies of the events
+    ///
+    /// The events of a given provider are always handled by the same worker, so that each provider sees its events in the order ETW has delivered them.
+    /// Callbacks of different providers may run concurrently.<br/>
+    /// Every worker has a queue of at most `queue_len` events. What happens when it is full is set by `overflow`.
+    ThreadPool {
+        workers: usize,
+        queue_len: usize,
+        overflow: Overflow,
+    },
+}
+
+/// What happens to an event whose worker queue is full, see [`Dispatch::ThreadPool`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wait until the worker has room for the event
+    ///
+    /// This is back-pressure: events are not dropped by ferrisetw, but the session may lose buffers in case workers lag behind for too long.
+    #[default]
+    Block,
+    /// Drop the event, and count it (see [`TraceTrait::events_dropped`](super::TraceTrait::events_dropped))
+    Drop,
+}
+
+/// What [`WorkerPool::dispatch`] has done with an event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dispatched {
+    /// The event has been queued, or dropped because the queue was full
+    Handled,
+    /// The pool has been shut down, the event should be delivered inline instead
+    ShutDown,
+    /// The worker of this event has exited (e.g. it has panicked), the event should be delivered inline instead
+    WorkerGone,
+}
+
+struct Worker {
+    queue: SyncSender<OwnedEventRecord>,
+    thread: JoinHandle<()>,
+}
+
+/// The worker threads of a [`Dispatch::ThreadPool`]
+pub(crate) struct WorkerPool {
+    /// Emptied on shutdown, so that workers stop once their queue is drained
+    workers: Mutex<Vec<Worker>>,
+    overflow: Overflow,
+    dropped: AtomicU64,
+}
+
+impl WorkerPool {
+    /// Spawn the workers. They hold a weak reference to the callback data, that owns the pool
+    pub fn spawn(
+        workers: usize,
+        queue_len: usize,
+        overflow: Overflow,
+        callback_data: Weak<CallbackData>,
+    ) -> std::io::Result<Self> {
+        let workers = (0..workers.max(1))
+            .map(|index| {
+                let (queue, events) = mpsc::sync_channel(queue_len);
+                let callback_data = Weak::clone(&callback_data);
+                std::thread::Builder::new()
+                    .name(format!("ferrisetw-dispatch-{}", index))
+                    .spawn(move || run_worker(events, callback_data))
+                    .map(|thread| Worker { queue, thread })
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            workers: Mutex::new(workers),
+            overflow,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Hand a copy of the event over to the worker of its provider.
+    pub fn dispatch(&self, record: &EventRecord) -> Dispatched {
+        let queue = {
+            let workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner);
+            if workers.is_empty() {
+                return Dispatched::ShutDown;
+            }
+            let index = (record.provider_id().to_u128() % workers.len() as u128) as usize;
+            // Sending may block, which must not prevent the pool from being shut down meanwhile
+            workers[index].queue.clone()
+        };
+        let event = record.to_owned();
+        match self.overflow {
+            Overflow::Block => match queue.send(event) {
+                Ok(()) => Dispatched::Handled,
+                Err(_) => Dispatched::WorkerGone,
+            },
+            Overflow::Drop => match queue.try_send(event) {
+                Ok(()) => Dispatched::Handled,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    Dispatched::Handled
+                }
+                Err(TrySendError::Disconnected(_)) => Dispatched::WorkerGone,
+            },
+        }
+    }
+
+    /// How many events have been dropped because their worker queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the queued events to be handled, and stop the workers. This is called once processing has ended.
+    pub fn shutdown(&self) {
+        let workers =
+            std::mem::take(&mut *self.workers.lock().unwrap_or_else(PoisonError::into_inner));
+        for Worker { queue, thread } in workers {
+            drop(queue);
+            if thread.join().is_err() {
+                log::warn!("A dispatch worker has panicked");
+            }
+        }
+    }
+}
+
+fn run_worker(events: Receiver<OwnedEventRecord>, callback_data: Weak<CallbackData>) {
+    for event in events {
+        match callback_data.upgrade() {
+            Some(callback_data) => callback_data.deliver(&event),
+            None => return,
+        }
+    }
+}
+
+impl std::fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field(
+                "workers",
+                &self.workers.try_lock().map(|workers| workers.len()).ok(),
+            )
+            .field("overflow", &self.overflow)
+            .field("dropped", &self.dropped)
+            .finish()
+    }
+}
@@ -0,0 +1,403 @@
+//! Checks that the events of a trace are delivered in chronological order
+//!
+//! Real-time sessions have one buffer per processor, and events are delivered buffer by buffer.
+//! Events of the same processor are supposed to have increasing timestamps, but merging buffers (or using a custom clock) sometimes breaks this,
+//! which confuses consumers that expect monotonic time series.<br/>
+//! See [`TraceBuilder::check_timestamp_order`](super::TraceBuilder::check_timestamp_order).
+//! Callbacks can also be guaranteed to see events in strict timestamp order, see [`CallbackOrdering`].
+use std::cmp::{Ordering as CmpOrdering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::callback_data::EventError;
+use crate::native::etw_types::event_record::{EventRecord, OwnedEventRecord};
+use crate::schema_locator::SchemaLocator;
+
+/// Reads the timestamp of an event, see [`TraceBuilder::timestamp_source`](super::TraceBuilder::timestamp_source)
+pub type TimestampSource = Box<dyn Fn(&EventRecord) -> i64 + Send + Sync>;
+
+/// What happens to events whose timestamp is older than the previous event of the same processor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OrderingPolicy {
+    /// The event is delivered, but counted and reported as an [`EventError::OutOfOrder`]
+    #[default]
+    Flag,
+    /// The event is counted and reported as an [`EventError::OutOfOrder`], and is not delivered to the callbacks
+    Drop,
+}
+
+/// Counters of the timestamp order checks of a trace, see [`TraceTrait::ordering_stats`](super::TraceTrait::ordering_stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OrderingStats {
+    /// How many events have been checked
+    pub checked: usize,
+    /// How many events were older than the previous event of their processor
+    pub out_of_order: usize,
+    /// How many out-of-order events have not been delivered (see [`OrderingPolicy::Drop`])
+    pub dropped: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct OrderingCheck {
+    policy: OrderingPolicy,
+    timestamp_source: Option<TimestampSource>,
+    /// The most recent timestamp of every processor
+    latest: Mutex<HashMap<u16, i64>>,
+    checked: AtomicUsize,
+    out_of_order: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+impl OrderingCheck {
+    pub fn set_policy(&mut self, policy: OrderingPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_timestamp_source(&mut self, source: TimestampSource) {
+        self.timestamp_source = Some(source);
+    }
+
+    /// Check the timestamp of an event. Returns whether the event should be delivered
+    pub fn check(&self, record: &EventRecord, schema_locator: &SchemaLocator) -> bool {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        let timestamp = match &self.timestamp_source {
+            Some(source) => source(record),
+            None => record.raw_timestamp(),
+        };
+        let processor = record.processor_index();
+
+        let previous = {
+            let mut latest = self.latest.lock().unwrap_or_else(PoisonError::into_inner);
+            let latest = latest.entry(processor).or_insert(timestamp);
+            if timestamp >= *latest {
+                *latest = timestamp;
+                return true;
+            }
+            *latest
+        };
+
+        self.out_of_order.fetch_add(1, Ordering::Relaxed);
+        schema_locator.report_error(EventError::OutOfOrder {
+            processor,
+            timestamp,
+            previous,
+        });
+        match self.policy {
+            OrderingPolicy::Flag => true,
+            OrderingPolicy::Drop => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    pub fn stats(&self) -> OrderingStats {
+        OrderingStats {
+            checked: self.checked.load(Ordering::Relaxed),
+            out_of_order: self.out_of_order.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How the callbacks of a trace are invoked, see [`TraceBuilder::callback_ordering`](super::TraceBuilder::callback_ordering)
+///
+/// Unless the trace dispatches its events to a [`Dispatch::ThreadPool`](super::Dispatch::ThreadPool), callbacks run one at a time, on the thread that processes the trace.
+/// [`CallbackOrdering::Strict`] cannot be used with a thread pool, whose workers run concurrently (see [`ValidationIssue::StrictOrderingWithThreadPool`](super::ValidationIssue::StrictOrderingWithThreadPool)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum CallbackOrdering {
+    /// Callbacks are invoked as soon as ETW delivers an event
+    ///
+    /// Events of a given processor have increasing timestamps, but events of different processors (and thus of different providers) may be interleaved out of order.
+    #[default]
+    AsDelivered,
+    /// Callbacks are invoked in strict timestamp order, across providers and processors
+    ///
+    /// Up to `max_pending` events are held back and sorted, and the oldest one is delivered whenever this limit is exceeded.
+    /// Events that arrive too late, i.e. that are older than an event that has already been delivered, are reported as [`EventError::OutOfOrder`] and dropped,
+    /// so that callbacks never see time going backwards.<br/>
+    /// Events are not held back for much longer than `max_delay` though, so that a quiet session does not delay its last events indefinitely.
+    /// Expired events (and the older ones) are delivered whenever ETW hands a buffer over, which happens at least every flush timer (see [`TraceProperties::flush_timer`](super::TraceProperties::flush_timer)).<br/>
+    /// Events still held back are delivered once processing ends.
+    Strict {
+        max_pending: usize,
+        max_delay: Duration,
+    },
+}
+
+/// An event held back by a [`ReorderBuffer`]
+struct Pending {
+    timestamp: i64,
+    /// Breaks ties between events with the same timestamp, so that they keep their arrival order
+    sequence: u64,
+    /// When the event has been pushed
+    arrived: Instant,
+    record: OwnedEventRecord,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (self.timestamp, self.sequence).cmp(&(other.timestamp, other.sequence))
+    }
+}
+
+#[derive(Default)]
+struct ReorderState {
+    pending: BinaryHeap<Reverse<Pending>>,
+    next_sequence: u64,
+    /// The timestamp of the most recent event that has been delivered
+    last_delivered: Option<i64>,
+}
+
+impl ReorderState {
+    fn pop(&mut self) -> Option<OwnedEventRecord> {
+        let Reverse(oldest) = self.pending.pop()?;
+        self.last_delivered = Some(oldest.timestamp);
+        Some(oldest.record)
+    }
+}
+
+/// Holds events back, so that they are delivered in timestamp order, see [`CallbackOrdering::Strict`]
+pub(crate) struct ReorderBuffer {
+    max_pending: usize,
+    max_delay: Duration,
+    state: Mutex<ReorderState>,
+}
+
+impl ReorderBuffer {
+    pub fn new(max_pending: usize, max_delay: Duration) -> Self {
+        Self {
+            max_pending,
+            max_delay,
+            state: Mutex::new(ReorderState::default()),
+        }
+    }
+
+    /// Add an event. Returns the events that are ready to be delivered, in timestamp order
+    pub fn push(
+        &self,
+        record: &EventRecord,
+        schema_locator: &SchemaLocator,
+    ) -> Vec<OwnedEventRecord> {
+        self.push_at(record, schema_locator, Instant::now())
+    }
+
+    /// Same as [`Self::push`], for an event that has arrived at `arrived`
+    fn push_at(
+        &self,
+        record: &EventRecord,
+        schema_locator: &SchemaLocator,
+        arrived: Instant,
+    ) -> Vec<OwnedEventRecord> {
+        let timestamp = record.raw_timestamp();
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(previous) = state.last_delivered.filter(|last| timestamp < *last) {
+            drop(state);
+            schema_locator.report_error(EventError::OutOfOrder {
+                processor: record.processor_index(),
+                timestamp,
+                previous,
+            });
+            return Vec::new();
+        }
+
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.pending.push(Reverse(Pending {
+            timestamp,
+            sequence,
+            arrived,
+            record: record.to_owned(),
+        }));
+
+        let mut ready = Vec::new();
+        while state.pending.len() > self.max_pending {
+            ready.extend(state.pop());
+        }
+        ready
+    }
+
+    /// Remove the events that have been held back for more than `max_delay` at `now`, along with the older events (so that the timestamp order is kept)
+    pub fn release_expired(&self, now: Instant) -> Vec<OwnedEventRecord> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let newest_expired = state
+            .pending
+            .iter()
+            .filter(|Reverse(pending)| {
+                now.saturating_duration_since(pending.arrived) > self.max_delay
+            })
+            .map(|Reverse(pending)| (pending.timestamp, pending.sequence))
+            .max();
+        let newest_expired = match newest_expired {
+            None => return Vec::new(),
+            Some(key) => key,
+        };
+        let mut ready = Vec::new();
+        while matches!(state.pending.peek(), Some(Reverse(oldest)) if (oldest.timestamp, oldest.sequence) <= newest_expired)
+        {
+            ready.extend(state.pop());
+        }
+        ready
+    }
+
+    /// Remove every event that is held back, in timestamp order
+    pub fn drain(&self) -> Vec<OwnedEventRecord> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        std::iter::from_fn(|| state.pop()).collect()
+    }
+
+    fn pending(&self) -> usize {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pending
+            .len()
+    }
+}
+
+impl std::fmt::Debug for ReorderBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReorderBuffer")
+            .field("max_pending", &self.max_pending)
+            .field("max_delay", &self.max_delay)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for OrderingCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OrderingCheck")
+            .field("policy", &self.policy)
+            .field("custom_timestamp_source", &self.timestamp_source.is_some())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn test_out_of_order_events() {
+        let locator = SchemaLocator::new();
+        let mut check = OrderingCheck::default();
+        check.set_policy(OrderingPolicy::Drop);
+
+        let delivered: Vec<bool> = [(0, 10), (1, 5), (0, 20), (0, 15), (1, 6), (0, 20)]
+            .iter()
+            .map(|(processor, timestamp)| {
+                let event = SyntheticEvent::new()
+                    .with_processor(*processor)
+                    .with_timestamp(*timestamp);
+                check.check(event.record(), &locator)
+            })
+            .collect();
+        assert_eq!(delivered, [true, true, true, false, true, true]);
+        assert_eq!(
+            check.stats(),
+            OrderingStats {
+                checked: 6,
+                out_of_order: 1,
+                dropped: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_custom_timestamp_source() {
+        let locator = SchemaLocator::new();
+        let mut check = OrderingCheck::default();
+        // Use the event version as a clock
+        check.set_timestamp_source(Box::new(|record| record.version() as i64));
+
+        for version in [3, 2, 4] {
+            let event = SyntheticEvent::new().with_version(version);
+            assert!(check.check(event.record(), &locator));
+        }
+        assert_eq!(check.stats().out_of_order, 1);
+        assert_eq!(check.stats().dropped, 0);
+    }
+
+    #[test]
+    fn test_reorder_buffer() {
+        let locator = SchemaLocator::new();
+        let buffer = ReorderBuffer::new(2, Duration::from_secs(3600));
+
+        let mut delivered = Vec::new();
+        // 5 arrives once 10 has been delivered, it is dropped
+        for (processor, timestamp) in [(0, 10), (1, 30), (0, 20), (1, 40), (0, 5), (0, 40)] {
+            let event = SyntheticEvent::new()
+                .with_processor(processor)
+                .with_timestamp(timestamp);
+            delivered.extend(
+                buffer
+                    .push(event.record(), &locator)
+                    .iter()
+                    .map(|record| record.raw_timestamp()),
+            );
+        }
+        assert_eq!(delivered, [10, 20, 30]);
+        assert_eq!(buffer.pending(), 2);
+
+        let remaining: Vec<(i64, u16)> = buffer
+            .drain()
+            .iter()
+            .map(|record| (record.raw_timestamp(), record.processor_index()))
+            .collect();
+        // Ties keep their arrival order
+        assert_eq!(remaining, [(40, 1), (40, 0)]);
+        assert_eq!(buffer.pending(), 0);
+    }
+
+    #[test]
+    fn test_reorder_buffer_max_delay() {
+        let locator = SchemaLocator::new();
+        let buffer = ReorderBuffer::new(100, Duration::from_secs(1));
+        let start = Instant::now();
+        for (timestamp, arrived) in [(30, 0), (10, 0), (20, 500), (40, 500)] {
+            let event = SyntheticEvent::new().with_timestamp(timestamp);
+            let arrived = start + Duration::from_millis(arrived);
+            assert!(buffer.push_at(event.record(), &locator, arrived).is_empty());
+        }
+        let timestamps = |records: Vec<OwnedEventRecord>| -> Vec<i64> {
+            records
+                .iter()
+                .map(|record| record.raw_timestamp())
+                .collect()
+        };
+
+        assert!(buffer
+            .release_expired(start + Duration::from_secs(1))
+            .is_empty());
+        // 30 and 10 have expired. 20 is released as well, since it is older than 30
+        assert_eq!(
+            timestamps(buffer.release_expired(start + Duration::from_millis(1200))),
+            [10, 20, 30]
+        );
+        assert_eq!(buffer.pending(), 1);
+        assert_eq!(
+            timestamps(buffer.release_expired(start + Duration::from_secs(2))),
+            [40]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Process several traces at once, with one thread per trace
+//!
+//! `ProcessTrace` blocks until its trace is stopped, and a handle must not be processed by more than one thread at a time.
+//! [`ProcessingPool`] spawns (and keeps track of) one processing thread per trace, so that callers do not have to.
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use super::callback_data::CallbackData;
+use super::{TraceResult, TraceTrait};
+use crate::native::evntrace::{callback_data, process_trace, TraceHandle};
+
+/// Stops a trace owned by the pool
+type Stopper = Box<dyn FnOnce() -> TraceResult<()> + Send>;
+
+struct Worker {
+    trace_handle: TraceHandle,
+    /// `None` for handles whose trace is not owned by the pool
+    stopper: Option<Stopper>,
+    thread: JoinHandle<TraceResult<()>>,
+}
+
+/// How the processing of a trace of a [`ProcessingPool`] has ended
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ProcessingOutcome {
+    pub trace_handle: TraceHandle,
+    /// What `ProcessTrace` has returned
+    pub result: TraceResult<()>,
+}
+
+/// A set of processing threads, one per trace
+///
+/// ```no_run
+/// # use ferrisetw::trace::ProcessingPool;
+/// # use ferrisetw::FileTrace;
+/// # use ferrisetw::{EventRecord, SchemaLocator};
+/// # fn callback(_record: &EventRecord, _locator: &SchemaLocator) {}
+/// let mut pool = ProcessingPool::new();
+/// for path in ["first.etl", "second.etl"] {
+///     let (trace, _handle) = FileTrace::new(path.into(), callback).start().unwrap();
+///     pool.add(trace);
+/// }
+/// for outcome in pool.join_all() {
+///     println!("{:?} ended with {:?}", outcome.trace_handle, outcome.result);
+/// }
+/// ```
+///
+/// Dropping the pool stops the traces it owns, but does not wait for their threads.
+#[derive(Default)]
+pub struct ProcessingPool {
+    workers: Vec<Worker>,
+}
+
+impl ProcessingPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process `trace` on a new thread, and keep it until the pool is stopped or joined
+    ///
+    /// The hooks set with [`TraceBuilder::on_processing_start`](super::TraceBuilder::on_processing_start) and [`TraceBuilder::on_processing_end`](super::TraceBuilder::on_processing_end) are run on this thread.
+    pub fn add<T>(&mut self, trace: T) -> TraceHandle
+    where
+        T: TraceTrait + Send + 'static,
+    {
+        let trace_handle = trace.trace_handle();
+        let callback_data = Arc::clone(trace.callback_data());
+        let thread = std::thread::spawn(move || process(trace_handle, Some(callback_data)));
+        self.workers.push(Worker {
+            trace_handle,
+            stopper: Some(Box::new(move || trace.stop())),
+            thread,
+        });
+        trace_handle
+    }
+
+    /// Process a trace given its handle, on a new thread
+    ///
+    /// The trace is not owned by the pool, and cannot be stopped by [`ProcessingPool::stop_all`]: whoever owns it must stop it for its thread to return.<br/>
+    /// As with [`ProcessingPool::add`], the processing hooks of the trace (if it has been opened by this crate) are run on this thread.<br/>
+    /// This returns `false` (and spawns nothing) in case this handle is already processed by this pool.
+    pub fn add_handle(&mut self, trace_handle: TraceHandle) -> bool {
+        if self
+            .workers
+            .iter()
+            .any(|worker| worker.trace_handle == trace_handle)
+        {
+            log::warn!("Trace {:?} is already being processed", trace_handle);
+            return false;
+        }
+        let callback_data = callback_data(trace_handle);
+        let thread = std::thread::spawn(move || process(trace_handle, callback_data));
+        self.workers.push(Worker {
+            trace_handle,
+            stopper: None,
+            thread,
+        });
+        true
+    }
+
+    /// The number of traces in this pool
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Wait until every trace has stopped being processed
+    ///
+    /// For real-time traces, this only returns once their sessions have been stopped (e.g. by another thread, or with `logman stop -ets`).
+    /// File traces stop by themselves at the end of their files.<br/>
+    /// Traces owned by the pool are stopped once their thread has returned.
+    ///
+    /// # Panics
+    /// In case a processing thread has panicked (e.g. in a processing hook), its panic is resumed on the current thread, once every thread has been joined.
+    pub fn join_all(mut self) -> Vec<ProcessingOutcome> {
+        let workers = std::mem::take(&mut self.workers);
+        let mut first_panic = None;
+        let mut outcomes = Vec::with_capacity(workers.len());
+        for worker in workers {
+            let result = worker.thread.join();
+            if let Some(stopper) = worker.stopper {
+                // The session has usually already been stopped by whoever made `ProcessTrace` return
+                let _already_stopped = stopper();
+            }
+            match result {
+                Ok(result) => outcomes.push(ProcessingOutcome {
+                    trace_handle: worker.trace_handle,
+                    result,
+                }),
+                Err(payload) => {
+                    log::error!(
+                        "The processing thread of trace {:?} panicked",
+                        worker.trace_handle
+                    );
+                    first_panic.get_or_insert(payload);
+                }
+            }
+        }
+        if let Some(payload) = first_panic {
+            std::panic::resume_unwind(payload);
+        }
+        outcomes
+    }
+
+    /// Stop every trace owned by the pool, then wait for every thread to return
+    ///
+    /// See [`ProcessingPool::join_all`]. Threads of traces that have been added with [`ProcessingPool::add_handle`] must be stopped by their owners.
+    pub fn stop_all(mut self) -> Vec<ProcessingOutcome> {
+        let mut workers = std::mem::take(&mut self.workers);
+        for worker in &mut workers {
+            if let Some(stopper) = worker.stopper.take() {
+                if let Err(err) = stopper() {
+                    log::warn!("Unable to stop trace {:?}: {:?}", worker.trace_handle, err);
+                }
+            }
+        }
+        Self { workers }.join_all()
+    }
+}
+
+/// Process a trace, between its processing hooks (if any)
+fn process(trace_handle: TraceHandle, callback_data: Option<Arc<CallbackData>>) -> TraceResult<()> {
+    match callback_data {
+        Some(callback_data) => callback_data
+            .processing_hooks()
+            .run_around(|| process_trace(trace_handle)),
+        None => process_trace(trace_handle),
+    }
+    .map_err(|e| e.into())
+}
+
+impl Drop for ProcessingPool {
+    fn drop(&mut self) {
+        for worker in &mut self.workers {
+            if let Some(stopper) = worker.stopper.take() {
+                let _ignored_error_in_drop = stopper();
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for ProcessingPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProcessingPool")
+            .field(
+                "trace_handles",
+                &self
+                    .workers
+                    .iter()
+                    .map(|worker| worker.trace_handle)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
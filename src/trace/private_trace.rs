@@ -0,0 +1,300 @@
+//! Private logger sessions, that only collect events from the current process
+//!
+//! See [`PrivateTrace`]
+use std::ffi::OsString;
+
+use widestring::U16CString;
+use windows::core::GUID;
+
+use super::validation::{check_filters, ValidationIssue};
+use super::{
+    default_name, DumpFileLoggingMode, DumpFileParams, LoggingMode, SessionController,
+    SessionStats, TraceError, TraceProperties, TraceResult, KERNEL_LOGGER_NAME,
+};
+use crate::native::etw_types::{LOG_FILE_PATH_MAX_CHARS, TRACE_NAME_MAX_CHARS};
+use crate::native::evntrace::{provider_instances, start_private_trace};
+use crate::provider::Provider;
+
+/// File modes that private sessions do not support
+const UNSUPPORTED_FILE_MODES: DumpFileLoggingMode = DumpFileLoggingMode::from_bits_truncate(
+    DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_APPEND.bits()
+        | DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE.bits()
+        | DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_PREALLOCATE.bits(),
+);
+
+/// A private logger session, that logs the events of providers registered by the current process into an ETL file
+///
+/// Private sessions (`EVENT_TRACE_PRIVATE_LOGGER_MODE | EVENT_TRACE_PRIVATE_IN_PROC`) do not require any privilege, and their buffers live in the current process.
+/// In exchange:
+/// * only user-mode providers can be enabled, and only their instances registered by the current process log events into the session,
+/// * events cannot be consumed in real time. They are written to an ETL file, that can be read with a [`FileTrace`](crate::FileTrace) once the session is stopped.
+///
+/// ```no_run
+/// # use ferrisetw::provider::Provider;
+/// # use ferrisetw::trace::{DumpFileLoggingMode, DumpFileParams, PrivateTrace};
+/// let trace = PrivateTrace::new()
+///     .enable(Provider::by_guid("3c6f8f0e-5a9b-4d1e-9c2a-7f4b1e6d8a21").build())
+///     .set_etl_dump_file(DumpFileParams {
+///         file_path: "my-own-events.etl".into(),
+///         file_logging_mode: DumpFileLoggingMode::default(),
+///         max_size: None,
+///     })
+///     .start()
+///     .unwrap();
+/// // ...the current process logs some events...
+/// trace.stop().unwrap();
+/// ```
+///
+/// The session is stopped when this is dropped.
+#[derive(Debug)]
+pub struct PrivateTrace {
+    controller: SessionController,
+}
+
+/// Provides a way to create [`PrivateTrace`]s, see [`PrivateTrace::new`]
+pub struct PrivateTraceBuilder {
+    name: String,
+    providers: Vec<Provider>,
+    etl_dump_file: Option<DumpFileParams>,
+    properties: TraceProperties,
+}
+
+impl PrivateTrace {
+    /// Create a PrivateTrace builder
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> PrivateTraceBuilder {
+        PrivateTraceBuilder {
+            name: default_name(),
+            providers: Vec::new(),
+            etl_dump_file: None,
+            properties: TraceProperties::default(),
+        }
+    }
+
+    /// The name of the session
+    pub fn name(&self) -> OsString {
+        self.controller.name()
+    }
+
+    /// Enable another provider on this running session
+    ///
+    /// Unlike [`PrivateTraceBuilder::start`], this does not validate the provider.
+    pub fn enable_provider(&self, provider: &Provider) -> TraceResult<()> {
+        self.controller.enable_provider(provider)
+    }
+
+    /// Write the buffers of the session to its ETL file
+    pub fn flush(&self) -> TraceResult<()> {
+        self.controller.flush()
+    }
+
+    /// Query the current counters of this session
+    pub fn query_stats(&self) -> TraceResult<SessionStats> {
+        self.controller.query_stats()
+    }
+
+    /// Stops the session, so that its ETL file is complete
+    ///
+    /// The same result is achieved by dropping `Self`
+    pub fn stop(self) -> TraceResult<()> {
+        self.controller.stop()
+    }
+}
+
+impl PrivateTraceBuilder {
+    /// Define the session name
+    ///
+    /// Names only have to be unique within the current process. See [`TraceBuilder::named`](super::TraceBuilder::named).
+    pub fn named(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Enable a user-mode provider. Its callbacks (if any) are never invoked, since events are logged to a file
+    pub fn enable(mut self, provider: Provider) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// Set the ETL file the events are written to. This is mandatory
+    ///
+    /// `EVENT_TRACE_FILE_MODE_APPEND`, `EVENT_TRACE_FILE_MODE_NEWFILE` and `EVENT_TRACE_FILE_MODE_PREALLOCATE` are not supported by private sessions.
+    pub fn set_etl_dump_file(mut self, params: DumpFileParams) -> Self {
+        self.etl_dump_file = Some(params);
+        self
+    }
+
+    /// Set the properties of the session
+    ///
+    /// `EVENT_TRACE_PRIVATE_LOGGER_MODE` and `EVENT_TRACE_PRIVATE_IN_PROC` are always added to the logging mode, and `EVENT_TRACE_REAL_TIME_MODE` is always removed from it.
+    pub fn set_trace_properties(mut self, props: TraceProperties) -> Self {
+        self.properties = props;
+        self
+    }
+
+    /// Check the configuration of this session, without starting it
+    ///
+    /// On top of the usual [`ValidationIssue`]s, this reports the constraints that are specific to private sessions:
+    /// kernel providers, missing (or unsupported) ETL file, and providers that the current process has not registered (yet).
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+
+        if self.name.is_empty() {
+            issues.push(ValidationIssue::EmptyName);
+        } else if self.name.eq_ignore_ascii_case(KERNEL_LOGGER_NAME) {
+            issues.push(ValidationIssue::ReservedName);
+        }
+        if self.name.contains('\0') || self.name.encode_utf16().count() > TRACE_NAME_MAX_CHARS {
+            issues.push(ValidationIssue::NameTruncated);
+        }
+
+        if self.providers.is_empty() {
+            issues.push(ValidationIssue::NoProvider);
+        }
+        let pid = std::process::id();
+        for provider in &self.providers {
+            if provider.kernel_flags() != 0 || provider.kernel_group_mask() != 0 {
+                issues.push(ValidationIssue::KernelProviderInPrivateTrace(
+                    provider.guid(),
+                ));
+                continue;
+            }
+            match provider_instances(&provider.guid()) {
+                Ok(pids) if !pids.contains(&pid) => {
+                    issues.push(ValidationIssue::ProviderNotInProcess(provider.guid()))
+                }
+                Ok(_) => (),
+                Err(err) => log::debug!("Unable to list the instances of a provider: {:?}", err),
+            }
+            check_filters(provider.filters(), Some(provider.guid()), &mut issues);
+        }
+
+        match &self.etl_dump_file {
+            None => issues.push(ValidationIssue::NoEventDestination),
+            Some(dump_file) => {
+                if dump_file
+                    .file_logging_mode
+                    .intersects(UNSUPPORTED_FILE_MODES)
+                {
+                    issues.push(ValidationIssue::InvalidDumpFileMode(
+                        dump_file.file_logging_mode,
+                    ));
+                }
+            }
+        }
+
+        issues
+    }
+
+    /// Start the session, and enable its providers
+    ///
+    /// This fails with [`TraceError::InvalidPrivateTrace`] in case [`Self::validate`] finds any error.
+    pub fn start(self) -> TraceResult<PrivateTrace> {
+        let errors: Vec<ValidationIssue> = self
+            .validate()
+            .into_iter()
+            .filter(ValidationIssue::is_error)
+            .collect();
+        if !errors.is_empty() {
+            return Err(TraceError::InvalidPrivateTrace(errors));
+        }
+        let dump_file = match self.etl_dump_file {
+            Some(dump_file) => dump_file,
+            None => {
+                return Err(TraceError::InvalidPrivateTrace(vec![
+                    ValidationIssue::NoEventDestination,
+                ]))
+            }
+        };
+
+        let trace_wide_name = U16CString::from_str_truncate(self.name);
+        let mut trace_wide_vec = trace_wide_name.into_vec();
+        trace_wide_vec.truncate(TRACE_NAME_MAX_CHARS);
+        let trace_wide_name = U16CString::from_vec_truncate(trace_wide_vec);
+        let wide_path = U16CString::from_os_str_truncate(dump_file.file_path.as_os_str());
+        let mut wide_path_vec = wide_path.into_vec();
+        wide_path_vec.truncate(LOG_FILE_PATH_MAX_CHARS);
+        let wide_path = U16CString::from_vec_truncate(wide_path_vec);
+
+        let mut properties = self.properties;
+        properties.log_file_mode = private_logging_mode(properties.log_file_mode);
+        // Before Windows 10 1703, the GUID of a private session must be the GUID of its provider
+        let session_guid = match self.providers.first() {
+            Some(provider) => provider.guid(),
+            None => GUID::new().unwrap_or(GUID::zeroed()),
+        };
+        let (full_properties, control_handle) = start_private_trace(
+            &trace_wide_name,
+            (&wide_path, dump_file.file_logging_mode, dump_file.max_size),
+            &properties,
+            session_guid,
+        )?;
+
+        let trace = PrivateTrace {
+            controller: SessionController::new(full_properties, control_handle),
+        };
+        for provider in &self.providers {
+            trace.enable_provider(provider)?;
+        }
+        Ok(trace)
+    }
+}
+
+/// The logging mode of a private session, given the one that has been requested
+fn private_logging_mode(requested: LoggingMode) -> LoggingMode {
+    (requested - LoggingMode::EVENT_TRACE_REAL_TIME_MODE)
+        | LoggingMode::EVENT_TRACE_PRIVATE_LOGGER_MODE
+        | LoggingMode::EVENT_TRACE_PRIVATE_IN_PROC
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_private_logging_mode() {
+        let mode = private_logging_mode(TraceProperties::default().log_file_mode);
+        assert!(!mode.contains(LoggingMode::EVENT_TRACE_REAL_TIME_MODE));
+        assert!(mode.contains(
+            LoggingMode::EVENT_TRACE_PRIVATE_LOGGER_MODE | LoggingMode::EVENT_TRACE_PRIVATE_IN_PROC
+        ));
+        assert!(mode.contains(LoggingMode::EVENT_TRACE_NO_PER_PROCESSOR_BUFFERING));
+    }
+
+    #[cfg(feature = "kernel")]
+    #[test]
+    fn test_private_trace_kernel_provider() {
+        let issues = PrivateTrace::new()
+            .enable(Provider::kernel(&crate::provider::kernel_providers::PROCESS_PROVIDER).build())
+            .set_etl_dump_file(DumpFileParams {
+                file_path: "ferrisetw-private.etl".into(),
+                file_logging_mode: DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE,
+                max_size: Some(1),
+            })
+            .validate();
+        assert!(issues
+            .iter()
+            .any(|issue| matches!(issue, ValidationIssue::KernelProviderInPrivateTrace(_))));
+        assert!(issues.contains(&ValidationIssue::InvalidDumpFileMode(
+            DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE
+        )));
+    }
+
+    #[test]
+    fn test_validate_private_trace() {
+        let issues = PrivateTrace::new()
+            .enable(Provider::by_guid(GUID::from_u128(0x783)).build())
+            .validate();
+        assert!(issues.contains(&ValidationIssue::NoEventDestination));
+        // Nothing registers this provider
+        assert!(
+            issues.contains(&ValidationIssue::ProviderNotInProcess(GUID::from_u128(
+                0x783
+            )))
+        );
+        assert!(matches!(
+            PrivateTrace::new().start(),
+            Err(TraceError::InvalidPrivateTrace(_))
+        ));
+    }
+}
@@ -0,0 +1,200 @@
+//! Discover the ETW sessions that are running on this machine
+use std::path::PathBuf;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw;
+
+use super::TraceResult;
+use crate::native::etw_types::{EventTraceProperties, LoggingMode};
+use crate::native::evntrace::{self, ControlHandle};
+use crate::native::process;
+
+/// The properties of a running ETW session, see [`query_all_traces`]
+///
+/// See [EVENT_TRACE_PROPERTIES](https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties) for the meaning of each member
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SessionInfo {
+    pub name: String,
+    /// The identifier of the session, as used by e.g. `TraceSetInformation`
+    pub logger_id: u64,
+    pub guid: GUID,
+    /// The size of each buffer, in KB
+    pub buffer_size: u32,
+    pub minimum_buffers: u32,
+    pub maximum_buffers: u32,
+    pub number_of_buffers: u32,
+    pub free_buffers: u32,
+    pub buffers_written: u32,
+    pub events_lost: u32,
+    pub log_buffers_lost: u32,
+    pub real_time_buffers_lost: u32,
+    /// The file this session logs to, if any
+    pub log_file: Option<PathBuf>,
+    pub log_file_mode: LoggingMode,
+    pub enable_flags: u32,
+}
+
+impl From<&EventTraceProperties> for SessionInfo {
+    fn from(properties: &EventTraceProperties) -> Self {
+        let native = properties.native();
+        Self {
+            name: properties.name().to_string_lossy().into_owned(),
+            logger_id: unsafe {
+                // Safety: on output, Windows stores the logger ID in this member of the union
+                native.Wnode.Anonymous1.HistoricalContext
+            },
+            guid: native.Wnode.Guid,
+            buffer_size: native.BufferSize,
+            minimum_buffers: native.MinimumBuffers,
+            maximum_buffers: native.MaximumBuffers,
+            number_of_buffers: native.NumberOfBuffers,
+            free_buffers: native.FreeBuffers,
+            buffers_written: native.BuffersWritten,
+            events_lost: native.EventsLost,
+            log_buffers_lost: native.LogBuffersLost,
+            real_time_buffers_lost: native.RealTimeBuffersLost,
+            log_file: properties.log_file_name().map(PathBuf::from),
+            log_file_mode: LoggingMode::from_bits_truncate(native.LogFileMode),
+            enable_flags: native.EnableFlags.0,
+        }
+    }
+}
+
+/// The counters of a session, see [`UserTrace::query_stats`](crate::UserTrace::query_stats)
+///
+/// Comparing these across calls tells whether the consumer keeps up with the rate of events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct SessionStats {
+    /// Number of buffers allocated for the session
+    pub number_of_buffers: u32,
+    /// Number of allocated buffers that are currently unused
+    pub free_buffers: u32,
+    /// Number of buffers flushed so far
+    pub buffers_written: u32,
+    /// Number of events that have not been recorded (e.g. because no buffer was free)
+    pub events_lost: u32,
+    /// Number of buffers that could not be written to the log file
+    pub log_buffers_lost: u32,
+    /// Number of buffers that could not be delivered to the real-time consumer
+    pub real_time_buffers_lost: u32,
+}
+
+impl From<&EventTraceProperties> for SessionStats {
+    fn from(properties: &EventTraceProperties) -> Self {
+        let native = properties.native();
+        Self {
+            number_of_buffers: native.NumberOfBuffers,
+            free_buffers: native.FreeBuffers,
+            buffers_written: native.BuffersWritten,
+            events_lost: native.EventsLost,
+            log_buffers_lost: native.LogBuffersLost,
+            real_time_buffers_lost: native.RealTimeBuffersLost,
+        }
+    }
+}
+
+/// Query the current counters of a running session
+pub(crate) fn query_stats(
+    properties: &EventTraceProperties,
+    control_handle: ControlHandle,
+) -> TraceResult<SessionStats> {
+    let queried =
+        evntrace::control_trace_copy(properties, control_handle, Etw::EVENT_TRACE_CONTROL_QUERY)?;
+    Ok(SessionStats::from(&queried))
+}
+
+/// List the ETW sessions that are currently running on this machine (similar to `logman query -ets`)
+///
+/// This includes sessions that have not been started by this process. Querying some system sessions requires administrator privileges (they are silently omitted otherwise).
+///
+/// ```
+/// # use ferrisetw::trace::query_all_traces;
+/// for session in query_all_traces().unwrap() {
+///     println!("{} ({} events lost)", session.name, session.events_lost);
+/// }
+/// ```
+pub fn query_all_traces() -> TraceResult<Vec<SessionInfo>> {
+    let sessions = evntrace::query_all_traces()?;
+    Ok(sessions.iter().map(SessionInfo::from).collect())
+}
+
+/// Stop the sessions whose names start with `prefix`, and whose owner processes are no longer running
+///
+/// Crashed programs (and aborted tests) leave their sessions running, and Windows only allows a limited number of sessions at a time.<br/>
+/// The owner of a session is the PID that directly follows `prefix` in its name: only names shaped as `{prefix}-{pid}-...` or `{prefix}-{pid}` are considered.
+/// This is the case for the default session names (e.g. `n4r1b-trace-1234-k2Xo8CqfZ1`, for the [`DEFAULT_NAME_PREFIX`](super::DEFAULT_NAME_PREFIX) prefix),
+/// for the names built by [`TraceBuilder::name_prefix`](super::TraceBuilder::name_prefix) or after [`set_default_name_prefix`](super::set_default_name_prefix),
+/// and for names that use the `{pid}` placeholder (e.g. `my-agent-1234`, for the `my-agent` prefix, see [`TraceBuilder::named`](super::TraceBuilder::named)).
+/// Other sessions (e.g. `{prefix}-k2Xo8CqfZ1`, or `{prefix}er-1234`) are left untouched, since they cannot be attributed to any process.
+///
+/// This returns the names of the sessions that have been stopped.
+///
+/// ```no_run
+/// # use ferrisetw::trace::{cleanup_orphaned, DEFAULT_NAME_PREFIX};
+/// for name in cleanup_orphaned(DEFAULT_NAME_PREFIX).unwrap() {
+///     println!("Stopped {}", name);
+/// }
+/// ```
+pub fn cleanup_orphaned(prefix: &str) -> TraceResult<Vec<String>> {
+    let mut stopped = Vec::new();
+    for session in query_all_traces()? {
+        let pid = match owner_pid(&session.name, prefix) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if process::is_running(pid) {
+            continue;
+        }
+        match super::stop_trace_by_name(&session.name) {
+            Ok(()) => stopped.push(session.name),
+            Err(err) => log::warn!(
+                "Unable to stop orphaned session {}: {:?}",
+                session.name,
+                err
+            ),
+        }
+    }
+    Ok(stopped)
+}
+
+/// The PID that follows `prefix` in a session name (e.g. `1234` in `prefix-1234-k2Xo8CqfZ1` or in `prefix-1234`)
+fn owner_pid(name: &str, prefix: &str) -> Option<u32> {
+    let pid = name
+        .strip_prefix(prefix)?
+        .strip_prefix('-')?
+        .split('-')
+        .next()?;
+    if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    pid.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_owner_pid() {
+        assert_eq!(
+            owner_pid("n4r1b-trace-1234-k2Xo8CqfZ1", "n4r1b-trace"),
+            Some(1234)
+        );
+        assert_eq!(owner_pid("my-agent-42", "my-agent"), Some(42));
+        assert_eq!(owner_pid("n4r1b-trace-k2Xo8CqfZ1", "n4r1b-trace"), None);
+        assert_eq!(owner_pid("n4r1b-trace1234", "n4r1b-trace"), None);
+        assert_eq!(owner_pid("other-1234", "n4r1b-trace"), None);
+        assert_eq!(
+            owner_pid("n4r1b-trace-+1234-k2Xo8CqfZ1", "n4r1b-trace"),
+            None
+        );
+        assert_eq!(
+            owner_pid("n4r1b-trace-1234x-k2Xo8CqfZ1", "n4r1b-trace"),
+            None
+        );
+        assert_eq!(owner_pid("n4r1b-trace-99999999999", "n4r1b-trace"), None);
+        assert_eq!(owner_pid("n4r1b-tracer-1234", "n4r1b-trace"), None);
+    }
+}
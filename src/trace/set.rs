@@ -0,0 +1,150 @@
+//! Process several traces with a single `ProcessTrace` call
+//!
+//! Unlike a [`ProcessingPool`](super::ProcessingPool), that runs one thread per trace, a [`TraceSet`] hands every trace handle to the same `ProcessTrace` call.
+//! Windows then merges the events of these traces, and delivers them in chronological order (e.g. to correlate a kernel and a user session, or several ETL files).
+use std::sync::Arc;
+
+use super::{CallbackData, TraceError, TraceResult, TraceTrait};
+use crate::native::evntrace::{process_traces, TraceHandle};
+
+/// The maximum number of handles `ProcessTrace` accepts
+pub const MAX_TRACES: usize = 64;
+
+/// Stops a trace owned by the set
+type Stopper = Box<dyn FnOnce() -> TraceResult<()> + Send>;
+
+struct Member {
+    trace_handle: TraceHandle,
+    callback_data: Arc<CallbackData>,
+    stopper: Option<Stopper>,
+}
+
+/// Several traces, processed together
+///
+/// ```no_run
+/// # use ferrisetw::trace::TraceSet;
+/// # use ferrisetw::FileTrace;
+/// # use ferrisetw::{EventRecord, SchemaLocator};
+/// # fn callback(_record: &EventRecord, _locator: &SchemaLocator) {}
+/// let mut set = TraceSet::new();
+/// for path in ["first.etl", "second.etl"] {
+///     let (trace, _handle) = FileTrace::new(path.into(), callback).start().unwrap();
+///     set.add(trace).unwrap();
+/// }
+/// set.process().unwrap();
+/// for (handle, count) in set.events_handled() {
+///     println!("{:?} handled {} events", handle, count);
+/// }
+/// ```
+///
+/// Traces must either be all real-time traces (`UserTrace`s and `KernelTrace`s), or all `FileTrace`s.<br/>
+/// Dropping the set stops the traces it owns.
+#[derive(Default)]
+pub struct TraceSet {
+    members: Vec<Member>,
+    /// Whether the traces of this set are real-time traces. `None` as long as the set is empty.
+    real_time: Option<bool>,
+}
+
+impl TraceSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a trace to the set, that keeps it until the set is stopped or dropped
+    ///
+    /// This fails with [`TraceError::TooManyTraces`] in case the set already contains [`MAX_TRACES`] traces,
+    /// and with [`TraceError::MixedTraceKinds`] in case real-time and file traces are mixed.
+    pub fn add<T>(&mut self, trace: T) -> TraceResult<TraceHandle>
+    where
+        T: TraceTrait + Send + 'static,
+    {
+        if self.members.len() >= MAX_TRACES {
+            return Err(TraceError::TooManyTraces);
+        }
+        let real_time = trace.session().is_some();
+        if *self.real_time.get_or_insert(real_time) != real_time {
+            return Err(TraceError::MixedTraceKinds);
+        }
+
+        let trace_handle = trace.trace_handle();
+        self.members.push(Member {
+            trace_handle,
+            callback_data: Arc::clone(trace.callback_data()),
+            stopper: Some(Box::new(move || trace.stop())),
+        });
+        Ok(trace_handle)
+    }
+
+    /// The number of traces in this set
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// The handles of the traces of this set, in the order they have been added
+    pub fn trace_handles(&self) -> Vec<TraceHandle> {
+        self.members
+            .iter()
+            .map(|member| member.trace_handle)
+            .collect()
+    }
+
+    /// Process every trace of this set. This is blocking, see [`TraceTrait::process`].
+    ///
+    /// Events of all traces are delivered in chronological order, on the current thread.<br/>
+    /// For real-time traces, this only returns once every session has been stopped. File traces stop by themselves at the end of their files.
+    ///
+    /// Note: the processing hooks (see [`TraceBuilder::on_processing_start`](super::TraceBuilder::on_processing_start)) are not run by this function.
+    pub fn process(&mut self) -> TraceResult<()> {
+        process_traces(&self.trace_handles()).map_err(|e| e.into())
+    }
+
+    /// How many events each trace has handled so far, in the order they have been added
+    pub fn events_handled(&self) -> Vec<(TraceHandle, usize)> {
+        self.members
+            .iter()
+            .map(|member| (member.trace_handle, member.callback_data.events_handled()))
+            .collect()
+    }
+
+    /// Stop every trace of this set
+    ///
+    /// Every trace is stopped, even in case some of them fail to. The first error (if any) is returned.
+    pub fn stop(mut self) -> TraceResult<()> {
+        self.non_consuming_stop()
+    }
+
+    fn non_consuming_stop(&mut self) -> TraceResult<()> {
+        let mut result = Ok(());
+        for member in &mut self.members {
+            if let Some(stopper) = member.stopper.take() {
+                if let Err(err) = stopper() {
+                    log::warn!("Unable to stop trace {:?}: {:?}", member.trace_handle, err);
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for TraceSet {
+    fn drop(&mut self) {
+        let _ignored_error_in_drop = self.non_consuming_stop();
+    }
+}
+
+impl std::fmt::Debug for TraceSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraceSet")
+            .field("trace_handles", &self.trace_handles())
+            .field("real_time", &self.real_time)
+            .finish()
+    }
+}
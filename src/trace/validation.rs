@@ -0,0 +1,716 @@
+//! Check the configuration of a trace, without starting it
+//!
+//! See [`TraceBuilder::validate`]
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use windows::core::GUID;
+use windows::Win32::System::Diagnostics::Etw::MAX_EVENT_FILTERS_COUNT;
+
+use super::private::{PrivateRealTimeTraceTrait, TraceKind};
+use super::{
+    check_dump_file_mode, sessions, ClockType, Dispatch, DumpFileLoggingMode, LoggingMode,
+    RealTimeTraceTrait, TraceBuilder, TraceError, KERNEL_LOGGER_NAME,
+};
+use crate::capabilities::capabilities;
+use crate::native::etw_types::TRACE_NAME_MAX_CHARS;
+use crate::native::privileges;
+use crate::provider::{enumerate_registered_providers, protected, EventFilter, Provider};
+
+/// `StartTraceW` silently caps buffers to this size (in KB)
+const MAX_BUFFER_SIZE_KB: u32 = 1024;
+
+/// File modes that cannot be used without a maximum file size
+const SIZED_FILE_MODES: DumpFileLoggingMode = DumpFileLoggingMode::from_bits_truncate(
+    DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_CIRCULAR.bits()
+        | DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_NEWFILE.bits()
+        | DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_PREALLOCATE.bits(),
+);
+
+/// Whether a [`ValidationIssue`] prevents the trace from starting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// The trace can start, but probably does not behave as expected
+    Warning,
+    /// [`TraceBuilder::start`] would fail
+    Error,
+}
+
+/// A problem found by [`TraceBuilder::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationIssue {
+    /// The trace name is empty
+    EmptyName,
+    /// The trace name is reserved for the kernel logger, and cannot be used by user traces
+    ReservedName,
+    /// The trace name is too long, or contains a nul character, and will be truncated
+    NameTruncated,
+    /// A session with the same name is already running
+    SessionAlreadyRunning,
+    /// The current process is not allowed to start this kind of session
+    ///
+    /// Kernel traces require administrator privileges. User traces also accept members of the "Performance Log Users" group.
+    InsufficientPrivileges,
+    /// No provider is enabled on this trace
+    NoProvider,
+    /// This provider has no schema registered on this machine
+    ///
+    /// This may be a typo in its GUID. But this is also expected for providers that do not register their schema (e.g. TraceLogging providers), or that are not registered yet.
+    ProviderNotRegistered(GUID),
+    /// This provider can only be enabled by protected processes, and the current process is not one of them (see [`provider::protected`](crate::provider::protected))
+    ProtectedProvider(GUID),
+    /// This filter cannot be built. `provider` is `None` for session-level filters
+    ///
+    /// Invalid provider filters are ignored, but invalid session-level filters are errors.
+    InvalidFilter {
+        provider: Option<GUID>,
+        reason: String,
+    },
+    /// There are more filters than Windows supports
+    TooManyFilters {
+        provider: Option<GUID>,
+        count: usize,
+    },
+    /// See [`TraceError::InvalidDumpFileMode`]
+    InvalidDumpFileMode(DumpFileLoggingMode),
+    /// The session is not real-time, and has no ETL dump file, so that its events cannot go anywhere
+    NoEventDestination,
+    /// The minimum number of buffers is greater than their maximum number
+    InvalidBufferCount { min: u32, max: u32 },
+    /// Buffers larger than 1 MB are capped by Windows
+    BufferSizeTooLarge(u32),
+    /// [`TraceBuilder::write_chain_of_custody`] has no effect without an ETL dump file
+    ChainOfCustodyWithoutDumpFile,
+    /// Kernel providers cannot be enabled on a [`PrivateTrace`](super::PrivateTrace)
+    KernelProviderInPrivateTrace(GUID),
+    /// The current process does not register this provider (yet), so that a [`PrivateTrace`](super::PrivateTrace) receives none of its events
+    ///
+    /// This is expected in case the provider is registered after the session starts.
+    ProviderNotInProcess(GUID),
+    /// See [`TraceError::ConflictingProviders`]
+    ConflictingProviders(GUID),
+    /// Kernel providers cannot be enabled on a [`UserTrace`](super::UserTrace), they belong to a [`KernelTrace`](super::KernelTrace)
+    KernelProviderInUserTrace(GUID),
+    /// These logging modes cannot be used together, or cannot be used on this trace
+    ///
+    /// e.g. `EVENT_TRACE_BUFFERING_MODE` with `EVENT_TRACE_REAL_TIME_MODE` or with an ETL dump file, private modes outside of a [`PrivateTrace`](super::PrivateTrace),
+    /// or `EVENT_TRACE_USE_PAGED_MEMORY` on a kernel trace.
+    IncompatibleLoggingModes(LoggingMode),
+    /// These file modes require the `max_size` of the [`DumpFileParams`](super::DumpFileParams)
+    MissingMaxFileSize(DumpFileLoggingMode),
+    /// `EVENT_TRACE_FILE_MODE_APPEND` requires the [`ClockType::SystemTime`] clock, otherwise the timestamps of the file are inconsistent
+    AppendWithoutSystemTime,
+    /// A provider has a null GUID (e.g. `GUID::zeroed()`)
+    NullProviderGuid,
+    /// [`CallbackOrdering::Strict`](super::CallbackOrdering::Strict) cannot be honoured by a [`Dispatch::ThreadPool`](super::Dispatch::ThreadPool), whose workers run concurrently
+    StrictOrderingWithThreadPool,
+}
+
+impl ValidationIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            ValidationIssue::InvalidFilter { provider: None, .. } => Severity::Error,
+            ValidationIssue::EmptyName
+            | ValidationIssue::ReservedName
+            | ValidationIssue::SessionAlreadyRunning
+            | ValidationIssue::InsufficientPrivileges
+            | ValidationIssue::ProtectedProvider(_)
+            | ValidationIssue::InvalidDumpFileMode(_)
+            | ValidationIssue::NoEventDestination
+            | ValidationIssue::InvalidBufferCount { .. }
+            | ValidationIssue::KernelProviderInPrivateTrace(_)
+            | ValidationIssue::ConflictingProviders(_)
+            | ValidationIssue::KernelProviderInUserTrace(_)
+            | ValidationIssue::IncompatibleLoggingModes(_)
+            | ValidationIssue::MissingMaxFileSize(_)
+            | ValidationIssue::AppendWithoutSystemTime
+            | ValidationIssue::NullProviderGuid
+            | ValidationIssue::StrictOrderingWithThreadPool => Severity::Error,
+            ValidationIssue::NameTruncated
+            | ValidationIssue::NoProvider
+            | ValidationIssue::ProviderNotRegistered(_)
+            | ValidationIssue::InvalidFilter { .. }
+            | ValidationIssue::TooManyFilters { .. }
+            | ValidationIssue::BufferSizeTooLarge(_)
+            | ValidationIssue::ChainOfCustodyWithoutDumpFile
+            | ValidationIssue::ProviderNotInProcess(_) => Severity::Warning,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity() == Severity::Error
+    }
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let target = |provider: &Option<GUID>| match provider {
+            Some(guid) => format!("provider {:?}", guid),
+            None => String::from("session"),
+        };
+        match self {
+            Self::EmptyName => write!(f, "the trace name is empty"),
+            Self::ReservedName => write!(f, "the trace name is reserved for the kernel logger"),
+            Self::NameTruncated => write!(f, "the trace name will be truncated"),
+            Self::SessionAlreadyRunning => write!(f, "a session with this name is already running"),
+            Self::InsufficientPrivileges => write!(
+                f,
+                "the current process is not allowed to start this session"
+            ),
+            Self::NoProvider => write!(f, "no provider is enabled"),
+            Self::ProviderNotRegistered(guid) => {
+                write!(f, "provider {:?} has no registered schema", guid)
+            }
+            Self::ProtectedProvider(guid) => write!(
+                f,
+                "provider {:?} can only be enabled by a protected anti-malware process",
+                guid
+            ),
+            Self::InvalidFilter { provider, reason } => {
+                write!(f, "invalid filter for {}: {}", target(provider), reason)
+            }
+            Self::TooManyFilters { provider, count } => write!(
+                f,
+                "{} filters for {}, at most {} are supported",
+                count,
+                target(provider),
+                MAX_EVENT_FILTERS_COUNT
+            ),
+            Self::InvalidDumpFileMode(mode) => {
+                write!(f, "invalid dump file mode {:?}", mode)
+            }
+            Self::NoEventDestination => {
+                write!(f, "the session is neither real-time nor logged to a file")
+            }
+            Self::InvalidBufferCount { min, max } => write!(
+                f,
+                "minimum buffers ({}) exceed maximum buffers ({})",
+                min, max
+            ),
+            Self::BufferSizeTooLarge(size) => write!(
+                f,
+                "buffer size ({} KB) will be capped to {} KB",
+                size, MAX_BUFFER_SIZE_KB
+            ),
+            Self::ChainOfCustodyWithoutDumpFile => {
+                write!(f, "chain of custody requires an ETL dump file")
+            }
+            Self::KernelProviderInPrivateTrace(guid) => write!(
+                f,
+                "kernel provider {:?} cannot be enabled on a private session",
+                guid
+            ),
+            Self::ProviderNotInProcess(guid) => write!(
+                f,
+                "provider {:?} is not registered by the current process",
+                guid
+            ),
+            Self::ConflictingProviders(guid) => write!(
+                f,
+                "provider {:?} is enabled several times, with filters that cannot be merged",
+                guid
+            ),
+            Self::KernelProviderInUserTrace(guid) => write!(
+                f,
+                "kernel provider {:?} cannot be enabled on a user trace",
+                guid
+            ),
+            Self::IncompatibleLoggingModes(mode) => write!(
+                f,
+                "logging modes {:?} cannot be used together on this trace",
+                mode
+            ),
+            Self::MissingMaxFileSize(mode) => {
+                write!(f, "file modes {:?} require a maximum file size", mode)
+            }
+            Self::AppendWithoutSystemTime => {
+                write!(f, "appending to an ETL file requires the system time clock")
+            }
+            Self::NullProviderGuid => write!(f, "a provider has a null GUID"),
+            Self::StrictOrderingWithThreadPool => {
+                write!(
+                    f,
+                    "strict callback ordering cannot be used with a thread pool"
+                )
+            }
+        }
+    }
+}
+
+/// The logging modes that cannot be used together, or in this kind of trace
+fn incompatible_logging_modes(
+    log_file_mode: LoggingMode,
+    kernel: bool,
+    dump_file: bool,
+) -> LoggingMode {
+    let mut incompatible = log_file_mode
+        & (LoggingMode::EVENT_TRACE_PRIVATE_LOGGER_MODE | LoggingMode::EVENT_TRACE_PRIVATE_IN_PROC);
+    if log_file_mode.contains(LoggingMode::EVENT_TRACE_BUFFERING_MODE)
+        && (dump_file || log_file_mode.contains(LoggingMode::EVENT_TRACE_REAL_TIME_MODE))
+    {
+        incompatible |= log_file_mode
+            & (LoggingMode::EVENT_TRACE_BUFFERING_MODE | LoggingMode::EVENT_TRACE_REAL_TIME_MODE);
+    }
+    if kernel {
+        incompatible |= log_file_mode & LoggingMode::EVENT_TRACE_USE_PAGED_MEMORY;
+    }
+    incompatible
+}
+
+/// Check the filters of a provider (or of the session, when `provider` is `None`)
+pub(super) fn check_filters(
+    filters: &[EventFilter],
+    provider: Option<GUID>,
+    issues: &mut Vec<ValidationIssue>,
+) {
+    if filters.len() > MAX_EVENT_FILTERS_COUNT as usize {
+        issues.push(ValidationIssue::TooManyFilters {
+            provider,
+            count: filters.len(),
+        });
+    }
+    for filter in filters {
+        let descriptor = match &provider {
+            Some(guid) => filter.to_event_filter_descriptor_for(guid),
+            None => filter.to_event_filter_descriptor(),
+        };
+        if let Err(err) = descriptor {
+            issues.push(ValidationIssue::InvalidFilter {
+                provider,
+                reason: err.to_string(),
+            });
+        }
+    }
+}
+
+impl<T: RealTimeTraceTrait + PrivateRealTimeTraceTrait> TraceBuilder<T> {
+    /// The issues of the name of the trace, see [`TraceBuilder::try_named`](super::TraceBuilder::try_named)
+    pub(super) fn name_issues(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        if self.name.is_empty() {
+            issues.push(ValidationIssue::EmptyName);
+        } else if T::TRACE_KIND != TraceKind::Kernel
+            && self.name.eq_ignore_ascii_case(KERNEL_LOGGER_NAME)
+        {
+            issues.push(ValidationIssue::ReservedName);
+        }
+        if self.name.contains('\0') || self.name.encode_utf16().count() > TRACE_NAME_MAX_CHARS {
+            issues.push(ValidationIssue::NameTruncated);
+        }
+        issues
+    }
+
+    /// The issues of a provider, were it enabled on this trace, see [`TraceBuilder::try_enable`](super::TraceBuilder::try_enable)
+    pub(super) fn provider_issues(&self, provider: &Arc<Provider>) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        self.check_provider_kind(provider, &mut issues);
+        check_filters(provider.filters(), Some(provider.guid()), &mut issues);
+        if T::TRACE_KIND != TraceKind::Kernel {
+            let mut same_guid: Vec<_> = self
+                .rt_callback_data
+                .providers()
+                .into_iter()
+                .filter(|prov| prov.guid() == provider.guid())
+                .collect();
+            if !same_guid.is_empty() {
+                same_guid.push(Arc::clone(provider));
+                if Provider::merge(&same_guid).is_none() {
+                    issues.push(ValidationIssue::ConflictingProviders(provider.guid()));
+                }
+            }
+        }
+        issues
+    }
+
+    fn check_provider_kind(&self, provider: &Provider, issues: &mut Vec<ValidationIssue>) {
+        if provider.guid() == GUID::zeroed() {
+            issues.push(ValidationIssue::NullProviderGuid);
+        }
+        if T::TRACE_KIND != TraceKind::Kernel
+            && (provider.kernel_flags() != 0 || provider.kernel_group_mask() != 0)
+        {
+            issues.push(ValidationIssue::KernelProviderInUserTrace(provider.guid()));
+        }
+    }
+
+    /// The issues of the configuration itself (properties, file modes and kinds of providers), that do not depend on the state of the machine
+    ///
+    /// [`TraceBuilder::start`] refuses to start in case any of them is an error.
+    pub(super) fn configuration_issues(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let kernel = T::TRACE_KIND == TraceKind::Kernel;
+
+        for provider in self.rt_callback_data.providers() {
+            self.check_provider_kind(&provider, &mut issues);
+        }
+
+        let log_file_mode = self.properties.log_file_mode;
+        let incompatible =
+            incompatible_logging_modes(log_file_mode, kernel, self.etl_dump_file.is_some());
+        if !incompatible.is_empty() {
+            issues.push(ValidationIssue::IncompatibleLoggingModes(incompatible));
+        }
+
+        match &self.etl_dump_file {
+            Some(dump_file) => {
+                let file_mode = dump_file.file_logging_mode;
+                if let Err(TraceError::InvalidDumpFileMode(mode)) = check_dump_file_mode(
+                    file_mode,
+                    log_file_mode,
+                    kernel && !capabilities().named_kernel_traces(),
+                ) {
+                    issues.push(ValidationIssue::InvalidDumpFileMode(mode));
+                }
+                if file_mode.intersects(SIZED_FILE_MODES) && dump_file.max_size.unwrap_or(0) == 0 {
+                    issues.push(ValidationIssue::MissingMaxFileSize(
+                        file_mode & SIZED_FILE_MODES,
+                    ));
+                }
+                if file_mode.contains(DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_APPEND)
+                    && self.settings.clock_type != ClockType::SystemTime
+                {
+                    issues.push(ValidationIssue::AppendWithoutSystemTime);
+                }
+            }
+            None => {
+                if !log_file_mode.is_empty()
+                    && !log_file_mode.contains(LoggingMode::EVENT_TRACE_REAL_TIME_MODE)
+                {
+                    issues.push(ValidationIssue::NoEventDestination);
+                }
+                if self.chain_of_custody {
+                    issues.push(ValidationIssue::ChainOfCustodyWithoutDumpFile);
+                }
+            }
+        }
+        let (min, max) = (self.properties.min_buffer, self.properties.max_buffer);
+        if max != 0 && min > max {
+            issues.push(ValidationIssue::InvalidBufferCount { min, max });
+        }
+        if self.properties.buffer_size > MAX_BUFFER_SIZE_KB {
+            issues.push(ValidationIssue::BufferSizeTooLarge(
+                self.properties.buffer_size,
+            ));
+        }
+        if self.rt_callback_data.strict_ordering()
+            && matches!(
+                self.rt_callback_data.dispatch(),
+                Dispatch::ThreadPool { .. }
+            )
+        {
+            issues.push(ValidationIssue::StrictOrderingWithThreadPool);
+        }
+
+        issues
+    }
+
+    /// Check the configuration of this trace, without starting any session
+    ///
+    /// This looks for the most common reasons why [`TraceBuilder::start`] would fail, or why the trace would not receive the expected events:
+    /// invalid names, missing privileges, unknown providers, invalid filters and inconsistent properties.<br/>
+    /// Every issue found is returned, see [`ValidationIssue::severity`]. An empty list does not guarantee that the trace will successfully start.
+    ///
+    /// Checks that cannot be performed (e.g. because the running sessions cannot be listed) are silently skipped.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let kernel = T::TRACE_KIND == TraceKind::Kernel;
+
+        // Name
+        issues.extend(self.name_issues());
+        if let Ok(sessions) = sessions::query_all_traces() {
+            if sessions
+                .iter()
+                .any(|session| session.name.eq_ignore_ascii_case(&self.name))
+            {
+                issues.push(ValidationIssue::SessionAlreadyRunning);
+            }
+        }
+
+        // Privileges
+        let allowed = match privileges::is_elevated_administrator() {
+            Some(false) if !kernel => privileges::is_performance_log_user(),
+            allowed => allowed,
+        };
+        if allowed == Some(false) {
+            issues.push(ValidationIssue::InsufficientPrivileges);
+        }
+
+        // Providers and filters
+        let providers = self.rt_callback_data.providers();
+        if providers.is_empty() {
+            issues.push(ValidationIssue::NoProvider);
+        }
+        if !kernel && !providers.is_empty() {
+            match enumerate_registered_providers() {
+                Ok(registered) => {
+                    let registered: HashSet<GUID> = registered.iter().map(|p| p.guid).collect();
+                    for provider in &providers {
+                        if !registered.contains(&provider.guid()) {
+                            issues.push(ValidationIssue::ProviderNotRegistered(provider.guid()));
+                        }
+                    }
+                }
+                Err(err) => log::debug!("Unable to list the registered providers: {:?}", err),
+            }
+        }
+        if protected::can_enable_protected_providers() == Some(false) {
+            for provider in &providers {
+                if protected::is_protected(provider.guid()) {
+                    issues.push(ValidationIssue::ProtectedProvider(provider.guid()));
+                }
+            }
+        }
+        for provider in &providers {
+            check_filters(provider.filters(), Some(provider.guid()), &mut issues);
+        }
+        // Kernel providers are not enabled one by one, they are merged into the flags of the session
+        let mut seen = HashSet::new();
+        for provider in providers.iter().filter(|_| !kernel) {
+            if !seen.insert(provider.guid()) {
+                continue;
+            }
+            let same_guid: Vec<_> = providers
+                .iter()
+                .filter(|prov| prov.guid() == provider.guid())
+                .cloned()
+                .collect();
+            if same_guid.len() > 1 && Provider::merge(&same_guid).is_none() {
+                issues.push(ValidationIssue::ConflictingProviders(provider.guid()));
+            }
+        }
+        check_filters(&self.session_filters, None, &mut issues);
+
+        // Properties
+        issues.extend(self.configuration_issues());
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::{CallbackOrdering, DumpFileParams, Overflow, TraceProperties, UserTrace};
+    use std::time::Duration;
+
+    #[test]
+    fn test_validate_properties() {
+        let issues = UserTrace::new()
+            .named(String::from("ferrisetw-validate"))
+            .set_trace_properties(TraceProperties {
+                min_buffer: 8,
+                max_buffer: 4,
+                buffer_size: 4096,
+                log_file_mode: LoggingMode::EVENT_TRACE_BUFFERING_MODE,
+                ..Default::default()
+            })
+            .enable(
+                Provider::by_guid("22fb2cd6-0e7b-422b-a0c7-2fad1fd0e716")
+                    .add_filter(EventFilter::ByEventIds(Vec::new()))
+                    .build(),
+            )
+            .write_chain_of_custody()
+            .validate();
+
+        assert!(issues.contains(&ValidationIssue::InvalidBufferCount { min: 8, max: 4 }));
+        assert!(issues.contains(&ValidationIssue::BufferSizeTooLarge(4096)));
+        assert!(issues.contains(&ValidationIssue::NoEventDestination));
+        assert!(issues.contains(&ValidationIssue::ChainOfCustodyWithoutDumpFile));
+        assert!(issues
+            .iter()
+            .any(|issue| matches!(issue, ValidationIssue::InvalidFilter { .. })));
+        assert!(!issues.contains(&ValidationIssue::NoProvider));
+        assert!(!issues.contains(&ValidationIssue::EmptyName));
+    }
+
+    #[test]
+    fn test_validate_conflicting_providers() {
+        let guid = GUID::from_u128(0x784);
+        let issues = UserTrace::new()
+            .enable(Provider::by_guid(guid).any(0x1).build())
+            .enable(Provider::by_guid(guid).any(0x2).build())
+            .validate();
+        assert!(!issues.contains(&ValidationIssue::ConflictingProviders(guid)));
+
+        let issues = UserTrace::new()
+            .enable(Provider::by_guid(guid).build())
+            .enable(
+                Provider::by_guid(guid)
+                    .add_filter(EventFilter::ByEventIds(vec![1]))
+                    .build(),
+            )
+            .validate();
+        assert!(issues.contains(&ValidationIssue::ConflictingProviders(guid)));
+        assert!(ValidationIssue::ConflictingProviders(guid).is_error());
+    }
+
+    #[test]
+    fn test_configuration_issues() {
+        let rt = LoggingMode::EVENT_TRACE_REAL_TIME_MODE;
+        let buffering = LoggingMode::EVENT_TRACE_BUFFERING_MODE;
+        let paged = LoggingMode::EVENT_TRACE_USE_PAGED_MEMORY;
+        assert!(incompatible_logging_modes(rt | paged, false, true).is_empty());
+        assert_eq!(incompatible_logging_modes(rt | paged, true, false), paged);
+        assert_eq!(
+            incompatible_logging_modes(rt | buffering, false, false),
+            rt | buffering
+        );
+        assert_eq!(
+            incompatible_logging_modes(buffering, false, true),
+            buffering
+        );
+        assert_eq!(
+            incompatible_logging_modes(
+                rt | LoggingMode::EVENT_TRACE_PRIVATE_LOGGER_MODE,
+                false,
+                false
+            ),
+            LoggingMode::EVENT_TRACE_PRIVATE_LOGGER_MODE
+        );
+
+        let dump_file = |file_logging_mode, max_size| DumpFileParams {
+            file_path: "ferrisetw-configuration.etl".into(),
+            file_logging_mode,
+            max_size,
+        };
+        let issues = UserTrace::new()
+            .set_etl_dump_file(dump_file(
+                DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_CIRCULAR,
+                None,
+            ))
+            .configuration_issues();
+        assert_eq!(
+            issues,
+            [ValidationIssue::MissingMaxFileSize(
+                DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_CIRCULAR
+            )]
+        );
+
+        let append = UserTrace::new()
+            .set_trace_properties(TraceProperties {
+                log_file_mode: LoggingMode::EVENT_TRACE_NO_PER_PROCESSOR_BUFFERING,
+                ..Default::default()
+            })
+            .set_etl_dump_file(dump_file(
+                DumpFileLoggingMode::EVENT_TRACE_FILE_MODE_APPEND,
+                None,
+            ));
+        assert_eq!(
+            append.configuration_issues(),
+            [ValidationIssue::AppendWithoutSystemTime]
+        );
+        assert!(append
+            .clock_type(ClockType::SystemTime)
+            .configuration_issues()
+            .is_empty());
+
+        let strict = UserTrace::new()
+            .callback_ordering(CallbackOrdering::Strict {
+                max_pending: 16,
+                max_delay: Duration::from_secs(1),
+            })
+            .dispatch(Dispatch::ThreadPool {
+                workers: 2,
+                queue_len: 16,
+                overflow: Overflow::Block,
+            });
+        assert_eq!(
+            strict.configuration_issues(),
+            [ValidationIssue::StrictOrderingWithThreadPool]
+        );
+
+        let result = UserTrace::new()
+            .set_trace_properties(TraceProperties {
+                min_buffer: 8,
+                max_buffer: 4,
+                ..Default::default()
+            })
+            .start();
+        assert!(matches!(
+            result,
+            Err(TraceError::InvalidConfiguration(
+                ValidationIssue::InvalidBufferCount { min: 8, max: 4 }
+            ))
+        ));
+
+        let result = UserTrace::new()
+            .add_session_filter(EventFilter::ByPids(vec![1; 64]))
+            .start();
+        assert!(matches!(
+            result,
+            Err(TraceError::InvalidConfiguration(
+                ValidationIssue::InvalidFilter { provider: None, .. }
+            ))
+        ));
+    }
+
+    #[cfg(feature = "kernel")]
+    #[test]
+    fn test_kernel_provider_in_user_trace() {
+        let provider =
+            Provider::kernel(&crate::provider::kernel_providers::PROCESS_PROVIDER).build();
+        let guid = provider.guid();
+        let issues = UserTrace::new().enable(provider).configuration_issues();
+        assert!(issues.contains(&ValidationIssue::KernelProviderInUserTrace(guid)));
+        assert!(ValidationIssue::KernelProviderInUserTrace(guid).is_error());
+    }
+
+    #[test]
+    fn test_try_variants() {
+        assert!(matches!(
+            UserTrace::new().try_named(String::new()),
+            Err(TraceError::InvalidConfiguration(ValidationIssue::EmptyName))
+        ));
+        assert!(matches!(
+            UserTrace::new().try_named("a".repeat(500)),
+            Err(TraceError::InvalidConfiguration(
+                ValidationIssue::NameTruncated
+            ))
+        ));
+        let builder = UserTrace::new()
+            .try_named(String::from("ferrisetw-try-{pid}"))
+            .unwrap();
+        assert_eq!(
+            builder.name,
+            format!("ferrisetw-try-{}", std::process::id())
+        );
+
+        assert!(matches!(
+            builder.try_enable(Provider::by_guid(GUID::zeroed()).build()),
+            Err(TraceError::InvalidConfiguration(
+                ValidationIssue::NullProviderGuid
+            ))
+        ));
+
+        let guid = GUID::from_u128(0x789);
+        let builder = UserTrace::new()
+            .try_enable(Provider::by_guid(guid).build())
+            .unwrap();
+        let result = builder.try_enable(
+            Provider::by_guid(guid)
+                .add_filter(EventFilter::ByEventIds(vec![1]))
+                .build(),
+        );
+        assert!(matches!(
+            result,
+            Err(TraceError::InvalidConfiguration(
+                ValidationIssue::ConflictingProviders(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_name() {
+        let issues = UserTrace::new()
+            .named(String::from(KERNEL_LOGGER_NAME))
+            .validate();
+        assert!(issues.contains(&ValidationIssue::ReservedName));
+        assert!(issues.contains(&ValidationIssue::NoProvider));
+
+        let issues = UserTrace::new().named("a".repeat(500)).validate();
+        assert!(issues.contains(&ValidationIssue::NameTruncated));
+        assert_eq!(ValidationIssue::NameTruncated.severity(), Severity::Warning);
+    }
+}
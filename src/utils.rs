@@ -8,3 +8,21 @@ pub fn rand_string() -> String {
         .map(char::from)
         .collect()
 }
+
+/// Enter a `tracing` span at the trace level, that lasts until the returned guard is dropped
+///
+/// This expands to nothing unless the `tracing-internal` feature is enabled.
+macro_rules! internal_span {
+    ($name:literal $(, $($fields:tt)*)?) => {{
+        #[cfg(feature = "tracing-internal")]
+        let guard = tracing::trace_span!($name $(, $($fields)*)?).entered();
+        #[cfg(not(feature = "tracing-internal"))]
+        let guard = crate::utils::NoSpan;
+        guard
+    }};
+}
+pub(crate) use internal_span;
+
+/// What [`internal_span`] returns when the `tracing-internal` feature is disabled
+#[cfg(not(feature = "tracing-internal"))]
+pub(crate) struct NoSpan;
@@ -21,6 +21,8 @@ const EVENT_ID_DNS_QUERY_COMPLETED: u16 = 3008;
 
 #[test]
 fn dns_tests() {
+    utils::cleanup_orphaned_sessions();
+
     // These tests must be consecutive, as they share the same DNS provider
     simple_user_dns_trace();
     test_event_id_filter();
@@ -1,12 +1,16 @@
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use ferrisetw::etl::{EtlEvent, EtlWriter, Writer, MIN_BUFFER_SIZE};
 use ferrisetw::provider::Provider;
 use ferrisetw::schema_locator::SchemaLocator;
 use ferrisetw::trace::DumpFileParams;
+use ferrisetw::trace::ProcessingPool;
 use ferrisetw::trace::TraceTrait;
 use ferrisetw::EventRecord;
-use ferrisetw::{FileTrace, UserTrace};
+use ferrisetw::{FileTrace, UserTrace, GUID};
 
 #[test]
 fn etl_file() {
@@ -53,3 +57,141 @@ fn process_from_file(input_file: PathBuf) -> usize {
     println!("Read {} events from file", n_events);
     n_events
 }
+
+#[test]
+fn etl_writer_round_trip() {
+    let provider = GUID::from_u128(0x781);
+    let start_time = 133_000_000_000_000_000;
+    let path = PathBuf::from("etl-writer-round-trip.etl");
+
+    let mut writer = EtlWriter::create(&path)
+        .unwrap()
+        .with_buffer_size(MIN_BUFFER_SIZE)
+        .unwrap()
+        .with_start_time(start_time);
+    for index in 0..100u16 {
+        writer
+            .write_event(
+                &EtlEvent::new(provider, index)
+                    .processor(u32::from(index % 4))
+                    .at(Duration::from_millis(u64::from(index)))
+                    .payload(vec![0; 64]),
+            )
+            .unwrap();
+    }
+    writer.finish().unwrap();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = Arc::clone(&received);
+    let (_trace, handle) = FileTrace::new(
+        path,
+        move |record: &EventRecord, _schema_locator: &SchemaLocator| {
+            if record.provider_id() == provider {
+                received_clone.lock().unwrap().push((
+                    record.event_id(),
+                    record.processor_index(),
+                    record.raw_timestamp(),
+                ));
+            }
+        },
+    )
+    .start()
+    .unwrap();
+    FileTrace::process_from_handle(handle).unwrap();
+
+    let received = received.lock().unwrap();
+    assert_eq!(received.len(), 100);
+    for (index, (event_id, processor, timestamp)) in received.iter().enumerate() {
+        assert_eq!(*event_id as usize, index);
+        assert_eq!(*processor as usize, index % 4);
+        assert_eq!(*timestamp, start_time + index as i64 * 10_000);
+    }
+}
+
+#[test]
+fn etl_fixture_writer() {
+    let provider = GUID::from_u128(0x781);
+    let path = PathBuf::from("etl-fixture-writer.etl");
+
+    let mut writer = Writer::create(&path);
+    // Written out of order, the file is sorted by time
+    for index in (0..10u16).rev() {
+        writer.write(
+            EtlEvent::new(provider, index)
+                .process(1234, 5678)
+                .at(Duration::from_millis(u64::from(index) * 10)),
+        );
+    }
+    assert_eq!(writer.finish().unwrap(), 10);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received_clone = Arc::clone(&received);
+    let (_trace, handle) = FileTrace::new(
+        path,
+        move |record: &EventRecord, _schema_locator: &SchemaLocator| {
+            if record.provider_id() == provider {
+                received_clone
+                    .lock()
+                    .unwrap()
+                    .push((record.event_id(), record.process_id()));
+            }
+        },
+    )
+    .start()
+    .unwrap();
+    FileTrace::process_from_handle(handle).unwrap();
+
+    let expected: Vec<_> = (0..10u16).map(|index| (index, 1234)).collect();
+    assert_eq!(*received.lock().unwrap(), expected);
+}
+
+#[test]
+fn processing_pool() {
+    let provider = GUID::from_u128(0x766);
+    let path = PathBuf::from("processing-pool.etl");
+
+    let mut writer = Writer::create(&path);
+    for index in 0..10u16 {
+        writer.write(EtlEvent::new(provider, index).at(Duration::from_millis(u64::from(index))));
+    }
+    assert_eq!(writer.finish().unwrap(), 10);
+
+    let events_read = Arc::new(AtomicUsize::new(0));
+    let hooks_run = Arc::new(AtomicUsize::new(0));
+    let open = || {
+        let counter = Arc::clone(&events_read);
+        let on_start = Arc::clone(&hooks_run);
+        let on_end = Arc::clone(&hooks_run);
+        FileTrace::new(
+            path.clone(),
+            move |record: &EventRecord, _schema_locator: &SchemaLocator| {
+                if record.provider_id() == provider {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+        )
+        .on_processing_start(move || {
+            on_start.fetch_add(1, Ordering::Relaxed);
+        })
+        .on_processing_end(move || {
+            on_end.fetch_add(1, Ordering::Relaxed);
+        })
+        .start()
+        .unwrap()
+    };
+
+    let mut pool = ProcessingPool::new();
+    let (owned, _handle) = open();
+    pool.add(owned);
+    let (_not_owned, handle) = open();
+    assert!(pool.add_handle(handle));
+    assert!(!pool.add_handle(handle));
+    assert_eq!(pool.len(), 2);
+
+    for outcome in pool.join_all() {
+        assert!(outcome.result.is_ok());
+    }
+    assert_eq!(events_read.load(Ordering::Relaxed), 20);
+    // The hooks of traces added by handle are run as well
+    assert_eq!(hooks_run.load(Ordering::Relaxed), 4);
+}
@@ -1,13 +1,16 @@
 //! Use the DNS provider to test a few things regarding user traces
 
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use ferrisetw::parser::Parser;
 use ferrisetw::provider::kernel_providers;
 use ferrisetw::provider::{EventFilter, Provider};
 use ferrisetw::schema_locator::SchemaLocator;
-use ferrisetw::trace::KernelTrace;
-use ferrisetw::EventRecord;
+use ferrisetw::trace::{DumpFileParams, KernelTrace, TraceTrait};
+use ferrisetw::{EventRecord, FileTrace};
 
 use windows::core::HSTRING;
 use windows::Win32::Foundation::HANDLE;
@@ -17,9 +20,13 @@ mod utils;
 use utils::{Status, StatusNotifier, TestKind};
 
 const TEST_LIBRARY_NAME: &str = "crypt32.dll"; // this DLL is available on all Windows versions (so that the test can run everywhere)
+/// See https://learn.microsoft.com/en-us/windows/win32/etw/process-typegroup1
+const PROCESS_DC_START_OPCODE: u8 = 3;
 
 #[test]
 fn kernel_trace_tests() {
+    utils::cleanup_orphaned_sessions();
+
     let passed1 = Status::new(TestKind::ExpectSuccess);
     let notifier1 = passed1.notifier();
 
@@ -34,6 +41,56 @@ fn kernel_trace_tests() {
     println!("Test passed");
 }
 
+#[test]
+fn kernel_etl_file() {
+    let dump_file = PathBuf::from("kernel-dump-file.etl");
+
+    let provider = Provider::kernel(&kernel_providers::PROCESS_PROVIDER)
+        .add_callback(|_: &EventRecord, _: &SchemaLocator| {})
+        .build();
+    let trace = KernelTrace::new()
+        .enable(provider)
+        .set_etl_dump_file(DumpFileParams {
+            file_path: dump_file.clone(),
+            ..Default::default()
+        })
+        .start_and_process()
+        .unwrap();
+
+    // Spawn a process, so that the trace contains at least one "live" event
+    std::process::Command::new("cmd")
+        .args(["/C", "exit"])
+        .status()
+        .unwrap();
+    std::thread::sleep(Duration::from_secs(3));
+    trace.stop().unwrap();
+
+    // The kernel logger writes a rundown of the running processes at the start of the file, and we are one of them
+    let rundown_for_us = Arc::new(AtomicUsize::new(0));
+    let rundown_counter = Arc::clone(&rundown_for_us);
+    let (file_trace, handle) = FileTrace::new(
+        dump_file,
+        move |record: &EventRecord, schema_locator: &SchemaLocator| {
+            if record.provider_id() != kernel_providers::PROCESS_PROVIDER.guid
+                || record.opcode() != PROCESS_DC_START_OPCODE
+            {
+                return;
+            }
+            let schema = schema_locator.event_schema(record).unwrap();
+            let parser = Parser::create(record, &schema);
+            if parser.try_parse::<u32>("ProcessId").ok() == Some(std::process::id()) {
+                rundown_counter.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+    )
+    .start()
+    .unwrap();
+    FileTrace::process_from_handle(handle).unwrap();
+
+    assert!(file_trace.events_handled() > 0);
+    assert_eq!(rundown_for_us.load(Ordering::Relaxed), 1);
+}
+
 fn create_simple_kernel_trace_trace(notifier: StatusNotifier) -> KernelTrace {
     println!("We are process {}", std::process::id());
     let our_process_only = EventFilter::ByPids(vec![std::process::id() as _]);
@@ -267,3 +267,29 @@ fn serialize_flexbuffer() {
         SECONDS_TO_RUN,
     );
 }
+
+#[test]
+fn serialize_json_concurrent_enable() {
+    let name = "ferrisetw-json-concurrent";
+    if stop_trace_by_name(name).is_ok() {
+        println!("Trace was running, it has been stopped before starting it again.");
+    }
+
+    let stats = Arc::new(BenchmarkStatistics::new());
+
+    let mut trace_builder = UserTrace::new()
+        .named(name.to_string())
+        .enable_providers_concurrently(8);
+    for guid in BENCHMARK_PROVIDERS {
+        let s = stats.clone();
+        trace_builder = trace_builder.enable(
+            Provider::by_guid(*guid)
+                .add_callback(move |record, schema_locator| {
+                    s.json_callback(record, schema_locator, EventSerializerOptions::default())
+                })
+                .build(),
+        );
+    }
+
+    do_benchmark(name, stats, trace_builder, SECONDS_TO_RUN)
+}
@@ -20,6 +20,8 @@ tlg::define_provider!(FERRIS_PROVIDER, "ferrisETW.TraceLoggingTest");
 #[ignore]
 #[test]
 fn tlg_tests() {
+    utils::cleanup_orphaned_sessions();
+
     unsafe {
         FERRIS_PROVIDER.register();
     }
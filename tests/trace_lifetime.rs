@@ -7,6 +7,7 @@ use ferrisetw::schema_locator::SchemaLocator;
 use ferrisetw::trace::RealTimeTraceTrait;
 use ferrisetw::trace::TraceTrait;
 use ferrisetw::trace::UserTrace;
+use ferrisetw::trace::{Consumer, SessionController};
 use ferrisetw::EventRecord;
 
 #[derive(Clone, Copy, Debug)]
@@ -111,6 +112,42 @@ fn test_wordpad_trace(
     }
 }
 
+#[test]
+fn controller_and_consumers() {
+    const TRACE_NAME: &str = "ferrisetw-split-trace";
+    let _output = Command::new("logman")
+        .args(["stop", "-ets", TRACE_NAME])
+        .output()
+        .unwrap();
+
+    let (trace, _handle) = UserTrace::new()
+        .named(String::from(TRACE_NAME))
+        .start()
+        .unwrap();
+    let (controller, consumer) = trace.into_parts();
+    assert_trace_exists(TRACE_NAME, true);
+
+    // Another consumer can read the same session
+    let mut other_consumer = Consumer::open(TRACE_NAME, Vec::new()).unwrap();
+    other_consumer.add_callback(|_record: &EventRecord, _locator: &SchemaLocator| {});
+    std::thread::spawn(move || other_consumer.process());
+
+    // Closing consumers does not stop the session
+    drop(consumer);
+    assert_trace_exists(TRACE_NAME, true);
+
+    // Attached controllers do not stop the session when dropped...
+    let attached = SessionController::attach(TRACE_NAME).unwrap();
+    assert_eq!(attached.name(), controller.name());
+    assert!(attached.query_stats().is_ok());
+    drop(attached);
+    assert_trace_exists(TRACE_NAME, true);
+
+    // ...but the controller of the session does
+    drop(controller);
+    assert_trace_exists(TRACE_NAME, false);
+}
+
 /// Call `logman` and check if the expected trace is part of the output
 ///
 /// This is limited to the ASCII part of the trace name, because Windows really sucks when it comes to encodings from sub processes (codepage issues, etc.)
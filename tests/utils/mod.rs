@@ -2,6 +2,15 @@ use std::sync::mpsc;
 use std::sync::mpsc::{RecvTimeoutError, TrySendError};
 use std::time::Duration;
 
+/// Stop the sessions left running by previous test runs that crashed or have been aborted, so that they do not count against the limit of concurrent sessions
+pub fn cleanup_orphaned_sessions() {
+    match ferrisetw::trace::cleanup_orphaned(ferrisetw::trace::DEFAULT_NAME_PREFIX) {
+        Ok(stopped) if !stopped.is_empty() => println!("Stopped orphaned sessions {:?}", stopped),
+        Ok(_) => (),
+        Err(err) => println!("Unable to clean orphaned sessions up: {:?}", err),
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum TestKind {
     /// Test will pass if a success has been notified in the test duration